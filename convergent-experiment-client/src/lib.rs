@@ -0,0 +1,622 @@
+//! Client of the ws protocol of the convergent experiment, see [`CrdtClient`]
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use convergent_experiment_protocol::ReplicaId;
+use convergent_experiment_ws::causal::{CausalToken, Session};
+use convergent_experiment_ws::crdt::Wire;
+use convergent_experiment_ws::e2e::{Key, Plaintext, RoomKey};
+use convergent_experiment_ws::proto::{
+    ClientBound, ClientBoundDigest, ClientBoundRejected, ClientBoundSync, ClientBoundSyncDigest,
+    ClientBoundUpdate, RoomId, ServerBound, ServerBoundAck, ServerBoundSync, ServerBoundSyncDigest,
+    ServerBoundUpdate,
+};
+use convergent_experiment_ws::{Codec, DeltaCrdt, MsgPack};
+use futures_util::{SinkExt, StreamExt};
+use sypytkowski_convergent::delta_state::awormap::{AWORMap, Deltas};
+use sypytkowski_convergent::Value;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tungstenite::Message;
+
+/// How long to wait before connecting again after the connection dropped or connecting failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Keeps a replica of a room's [`AWORMap`] in sync with the server. Changes made here are sent
+/// as deltas as soon as the connection task gets to them, changes of others are merged in as
+/// they arrive.
+///
+/// The connection is restored in the background whenever it drops, exchanging digests with the
+/// server to catch up on what either side missed in between. Clients of end to end encrypted rooms
+/// send their whole state instead, see [`CrdtClient::connect_encrypted`].
+pub struct CrdtClient<K, V>
+where
+    K: Clone + PartialEq + Default + Debug + Ord + Value + Wire,
+    V: Value + Clone + PartialEq + Debug + Wire,
+{
+    shared: Arc<Shared<K, V>>,
+    task: JoinHandle<()>,
+}
+
+struct Shared<K, V>
+where
+    K: Clone + PartialEq + Default + Debug + Ord + Value + Wire,
+    V: Value + Clone + PartialEq + Debug + Wire,
+{
+    url: String,
+    replica: ReplicaId,
+    room: RoomId,
+    /// Set for end to end encrypted rooms
+    key: Option<RoomKey>,
+    local: Mutex<Local<K, V>>,
+    /// Woken when there are changes to send
+    changed: Notify,
+}
+
+struct Local<K, V>
+where
+    K: Clone + PartialEq + Default + Debug + Ord + Value + Wire,
+    V: Value + Clone + PartialEq + Debug + Wire,
+{
+    state: AWORMap<K, V>,
+    /// Changes made here that weren't sent yet. Kept apart from the delta of `state`, which would
+    /// otherwise pick up what's merged in from the server and send it right back.
+    pending: Option<Deltas<K, V>>,
+    /// What was read and written so far, handed to servers while there's no state to sync
+    session: Session<AWORMap<K, V>>,
+}
+
+impl<K, V> CrdtClient<K, V>
+where
+    K: Clone + PartialEq + Default + Debug + Ord + Value + Wire,
+    V: Value + Clone + PartialEq + Debug + Wire,
+{
+    /// Joins `room` on the server at `url` as `replica` and waits for the room's state. The
+    /// `token` query parameter goes in `url` for servers that require one.
+    pub async fn connect(
+        url: impl Into<String>,
+        replica: ReplicaId,
+        room: impl Into<RoomId>,
+    ) -> Result<Self> {
+        Self::start(url.into(), replica, room.into(), None, None).await
+    }
+
+    /// Joins `room` like [`CrdtClient::connect`], continuing the session `token` was taken from
+    /// with [`CrdtClient::session_token`], say by a client on another server. The server holds
+    /// the room's state back until it has seen everything that session read and wrote.
+    pub async fn connect_with_session(
+        url: impl Into<String>,
+        replica: ReplicaId,
+        room: impl Into<RoomId>,
+        token: CausalToken,
+    ) -> Result<Self> {
+        Self::start(url.into(), replica, room.into(), None, Some(token)).await
+    }
+
+    /// Joins `room` like [`CrdtClient::connect`], but seals every change with `key` so the server
+    /// only relays it. The server has to run the room end to end encrypted.
+    pub async fn connect_encrypted(
+        url: impl Into<String>,
+        replica: ReplicaId,
+        room: impl Into<RoomId>,
+        key: &Key,
+    ) -> Result<Self> {
+        let key = Some(RoomKey::new(key));
+        Self::start(url.into(), replica, room.into(), key, None).await
+    }
+
+    async fn start(
+        url: String,
+        replica: ReplicaId,
+        room: RoomId,
+        key: Option<RoomKey>,
+        token: Option<CausalToken>,
+    ) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            url,
+            replica,
+            room,
+            key,
+            local: Mutex::new(Local {
+                state: AWORMap::default(),
+                pending: None,
+                session: token.map(Session::resume).unwrap_or_default(),
+            }),
+            changed: Notify::new(),
+        });
+        let socket = shared.handshake().await?;
+        let task = tokio::spawn(shared.clone().run(socket));
+        Ok(Self { shared, task })
+    }
+
+    pub fn replica(&self) -> ReplicaId {
+        self.shared.replica
+    }
+
+    /// Everything this replica read from the server and sent to it, for
+    /// [`CrdtClient::connect_with_session`]
+    pub fn session_token(&self) -> CausalToken {
+        self.shared.local.lock().unwrap().session.session_token()
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let replica = self.shared.replica;
+        self.change(|state| state.insert(replica, key, value));
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.change(|state| state.remove(key));
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shared.local.lock().unwrap().state.get(key).cloned()
+    }
+
+    /// Copy of the local replica
+    pub fn state(&self) -> AWORMap<K, V> {
+        self.shared.local.lock().unwrap().state.clone()
+    }
+
+    fn change(&self, f: impl FnOnce(&mut AWORMap<K, V>)) {
+        let mut local = self.shared.local.lock().unwrap();
+        f(&mut local.state);
+        if let Some(delta) = local.state.split_mut() {
+            match &mut local.pending {
+                Some(pending) => pending.merge_in_place(&delta),
+                None => local.pending = Some(delta),
+            }
+        }
+        drop(local);
+        self.shared.changed.notify_one();
+    }
+}
+
+/// Stops the connection task, changes that weren't sent yet are lost
+impl<K, V> Drop for CrdtClient<K, V>
+where
+    K: Clone + PartialEq + Default + Debug + Ord + Value + Wire,
+    V: Value + Clone + PartialEq + Debug + Wire,
+{
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl<K, V> Shared<K, V>
+where
+    K: Clone + PartialEq + Default + Debug + Ord + Value + Wire,
+    V: Value + Clone + PartialEq + Debug + Wire,
+{
+    async fn run(self: Arc<Self>, mut socket: Socket) {
+        loop {
+            if let Err(e) = self.serve(&mut socket).await {
+                tracing::warn!(url = %self.url, error = ?e, "lost connection");
+            }
+            socket = loop {
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                match self.handshake().await {
+                    Ok(socket) => break socket,
+                    Err(e) => tracing::warn!(url = %self.url, error = ?e, "failed to reconnect"),
+                }
+            };
+        }
+    }
+
+    /// Connects and syncs, returns once the server's answer to the sync is merged. A replica
+    /// without any state asks for the server's, one that has state only exchanges the keys that
+    /// differ. In an end to end encrypted room the server has nothing to sync with, so the
+    /// replica sends its whole state once it joined instead.
+    ///
+    /// Only replicas without state ask the server to catch up with their session. One that has
+    /// state already holds everything it read and wrote, and sends the server what it's missing
+    /// of that after the digests were exchanged.
+    async fn handshake(&self) -> Result<Socket> {
+        let (mut socket, _) = tokio_tungstenite::connect_async(&self.url)
+            .await
+            .with_context(|| format!("Failed to connect to {}", self.url))?;
+
+        let msg: ServerBound<AWORMap<K, V>> = {
+            let local = self.local.lock().unwrap();
+            let empty = local.state.is_empty() && local.pending.is_none();
+            if empty || self.key.is_some() {
+                ServerBound::Sync(ServerBoundSync {
+                    replica_id: self.replica,
+                    room_id: self.room.clone(),
+                    state: Default::default(),
+                    subscription: None,
+                    causal: empty.then(|| local.session.session_token()),
+                    peer: false,
+                })
+            } else {
+                ServerBound::SyncDigest(ServerBoundSyncDigest {
+                    replica_id: self.replica,
+                    room_id: self.room.clone(),
+                    digest: local.state.digest(),
+                    causal: None,
+                })
+            }
+        };
+        send(&mut socket, &msg).await?;
+
+        loop {
+            let msg = recv(&mut socket).await?;
+            let synced = matches!(msg, ClientBound::Sync(_) | ClientBound::SyncDigest(_));
+            self.handle(&mut socket, msg).await?;
+            if synced {
+                break;
+            }
+        }
+
+        if let Some(key) = &self.key {
+            let state = {
+                let mut local = self.local.lock().unwrap();
+                local.pending = None;
+                local.state.clone()
+            };
+            if !state.is_empty() {
+                send(
+                    &mut socket,
+                    &key.seal_state::<_, MsgPack>(&self.room, state)?,
+                )
+                .await?;
+            }
+        }
+        Ok(socket)
+    }
+
+    async fn serve(&self, socket: &mut Socket) -> Result<()> {
+        loop {
+            tokio::select! {
+                msg = recv(socket) => self.handle(socket, msg?).await?,
+                _ = self.changed.notified() => self.flush(socket).await?,
+            }
+        }
+    }
+
+    /// Sends the changes made since the last flush
+    async fn flush(&self, socket: &mut Socket) -> Result<()> {
+        let deltas = {
+            let mut local = self.local.lock().unwrap();
+            let Some(deltas) = local.pending.take() else {
+                return Ok(());
+            };
+            local.session.wrote(&deltas);
+            deltas
+        };
+        let msg = match &self.key {
+            Some(key) => key.seal_deltas::<_, MsgPack>(&self.room, deltas)?,
+            None => ServerBound::Update(ServerBoundUpdate { deltas }),
+        };
+        send(socket, &msg).await
+    }
+
+    async fn handle(&self, socket: &mut Socket, msg: ClientBound<AWORMap<K, V>>) -> Result<()> {
+        let reply = {
+            let mut local = self.local.lock().unwrap();
+            let Local { state, session, .. } = &mut *local;
+            let reply = match msg {
+                ClientBound::Sync(ClientBoundSync { state: remote }) => {
+                    state.merge_in_place(&remote);
+                    session.read_state(&remote);
+                    None
+                }
+                ClientBound::SyncDigest(ClientBoundSyncDigest { digest, deltas }) => {
+                    // Taken before merging so the keys the server just sent aren't echoed back.
+                    // Everything pending is in there too.
+                    let divergent = state.divergent(&digest);
+                    state.merge_delta_in_place(&deltas);
+                    session.read_delta(&deltas);
+                    session.wrote(&divergent);
+                    local.pending = None;
+                    Some(ServerBound::Update(ServerBoundUpdate { deltas: divergent }))
+                }
+                ClientBound::Update(ClientBoundUpdate { seq, deltas }) => {
+                    state.merge_delta_in_place(&deltas);
+                    session.read_delta(&deltas);
+                    Some(ServerBound::Ack(ServerBoundAck { seq }))
+                }
+                // Only replicas that diverged sync
+                ClientBound::Digest(ClientBoundDigest { digest }) => {
+                    let ours = state.digest();
+                    (ours != digest).then(|| {
+                        ServerBound::SyncDigest(ServerBoundSyncDigest {
+                            replica_id: self.replica,
+                            room_id: self.room.clone(),
+                            digest: ours,
+                            causal: None,
+                        })
+                    })
+                }
+                ClientBound::Rejected(ClientBoundRejected { reason }) => {
+                    tracing::warn!(?reason, "server rejected update");
+                    None
+                }
+                // Payloads that don't open are skipped, they're from someone without the key
+                ClientBound::Encrypted(msg) => {
+                    let opened = match &self.key {
+                        Some(key) => key.open::<_, MsgPack>(&self.room, &msg),
+                        None => Err(anyhow!("Got encrypted update without a room key")),
+                    };
+                    match opened {
+                        Ok(Plaintext::Deltas { deltas }) => state.merge_delta_in_place(&deltas),
+                        Ok(Plaintext::State { state: remote }) => state.merge_in_place(&remote),
+                        Err(e) => tracing::warn!(error = ?e, "failed to open encrypted update"),
+                    }
+                    None
+                }
+                _ => None,
+            };
+            // What was merged from the server doesn't need to be sent anywhere
+            local.state.split_mut();
+            reply
+        };
+        match reply {
+            Some(reply) => send(socket, &reply).await,
+            None => Ok(()),
+        }
+    }
+}
+
+async fn send<K, V>(socket: &mut Socket, msg: &ServerBound<AWORMap<K, V>>) -> Result<()>
+where
+    K: Clone + PartialEq + Default + Debug + Ord + Value + Wire,
+    V: Value + Clone + PartialEq + Debug + Wire,
+{
+    socket.send(Message::Binary(MsgPack::encode(msg)?)).await?;
+    Ok(())
+}
+
+/// Next protocol message, pings are answered along the way
+async fn recv<K, V>(socket: &mut Socket) -> Result<ClientBound<AWORMap<K, V>>>
+where
+    K: Clone + PartialEq + Default + Debug + Ord + Value + Wire,
+    V: Value + Clone + PartialEq + Debug + Wire,
+{
+    loop {
+        let msg = socket
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("Server closed the connection"))??;
+        match msg {
+            Message::Binary(bytes) => return MsgPack::decode(&bytes),
+            Message::Close(frame) => {
+                return Err(anyhow!("Server closed the connection: {:?}", frame))
+            }
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::time::Duration;
+
+    use clap::Parser;
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use convergent_experiment_ws::e2e::RoomKey;
+    use convergent_experiment_ws::proto::{ServerBound, ServerBoundSync};
+    use convergent_experiment_ws::{Config, CrdtServer};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use super::{recv, send, CrdtClient};
+
+    async fn eventually(f: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if f() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Timed out");
+    }
+
+    fn free_addr() -> String {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string()
+    }
+
+    /// Starts a server with `args` on a free port and returns its URL
+    async fn serve(args: &[&str]) -> String {
+        serve_at(&free_addr(), args).await
+    }
+
+    async fn serve_at(addr: &str, args: &[&str]) -> String {
+        let config = Config::parse_from(["server", "--addr", addr].iter().chain(args));
+        let server = CrdtServer::<AWORMap<SquareId, Square>>::new(&config, Vec::new())
+            .await
+            .unwrap();
+        tokio::spawn(server.run());
+        format!("ws://{}", addr)
+    }
+
+    /// Retries until the server came up
+    async fn first<F: Future<Output = anyhow::Result<CrdtClient<SquareId, Square>>>>(
+        connect: impl Fn() -> F,
+    ) -> CrdtClient<SquareId, Square> {
+        for _ in 0..50 {
+            match connect().await {
+                Ok(client) => return client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+        panic!("Server didn't come up");
+    }
+
+    #[tokio::test]
+    async fn syncs_through_server() {
+        let url = serve(&[]).await;
+        let a = first(|| CrdtClient::connect(&url, ReplicaId::from(1), "room")).await;
+        a.insert(SquareId(1), Square::default());
+
+        // Gets what's already there when it joins, and changes after that
+        let b = CrdtClient::connect(&url, ReplicaId::from(2), "room")
+            .await
+            .unwrap();
+        eventually(|| b.get(&SquareId(1)).is_some()).await;
+        b.insert(SquareId(2), Square::default());
+        b.remove(&SquareId(1));
+        eventually(|| a.get(&SquareId(2)).is_some() && a.get(&SquareId(1)).is_none()).await;
+        assert_eq!(a.state().digest(), b.state().digest());
+
+        let other = CrdtClient::<SquareId, Square>::connect(&url, ReplicaId::from(3), "other")
+            .await
+            .unwrap();
+        assert!(other.state().is_empty());
+    }
+
+    #[tokio::test]
+    async fn syncs_end_to_end_encrypted() {
+        let url = serve(&["--e2e-room", "secret"]).await;
+        let key = RoomKey::generate();
+        let a =
+            first(|| CrdtClient::connect_encrypted(&url, ReplicaId::from(1), "secret", &key)).await;
+        a.insert(SquareId(1), Square::default());
+
+        let b = CrdtClient::connect_encrypted(&url, ReplicaId::from(2), "secret", &key)
+            .await
+            .unwrap();
+        eventually(|| b.get(&SquareId(1)).is_some()).await;
+        b.insert(SquareId(2), Square::default());
+        b.remove(&SquareId(1));
+        eventually(|| a.get(&SquareId(2)).is_some() && a.get(&SquareId(1)).is_none()).await;
+
+        // Joining later replays what the server relayed, which it can't read
+        let c = CrdtClient::<SquareId, Square>::connect_encrypted(
+            &url,
+            ReplicaId::from(3),
+            "secret",
+            &key,
+        )
+        .await
+        .unwrap();
+        eventually(|| c.get(&SquareId(2)).is_some() && c.get(&SquareId(1)).is_none()).await;
+        let plaintext = CrdtClient::<SquareId, Square>::connect(&url, ReplicaId::from(4), "secret")
+            .await
+            .unwrap();
+        let other_key = RoomKey::generate();
+        let wrong_key = CrdtClient::<SquareId, Square>::connect_encrypted(
+            &url,
+            ReplicaId::from(5),
+            "secret",
+            &other_key,
+        )
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(plaintext.state().is_empty() && wrong_key.state().is_empty());
+        assert_eq!(a.state().digest(), c.state().digest());
+    }
+
+    #[tokio::test]
+    async fn session_moves_between_servers() {
+        let first_url = serve(&[]).await;
+        let second_url = serve(&[]).await;
+        let a = first(|| CrdtClient::connect(&first_url, ReplicaId::from(1), "room")).await;
+        a.insert(SquareId(1), Square::default());
+        let b = first(|| CrdtClient::connect(&first_url, ReplicaId::from(2), "room")).await;
+        eventually(|| b.get(&SquareId(1)).is_some()).await;
+
+        // The second server hasn't seen a's write, so a's session waits for it there
+        let token = a.session_token();
+        let url = second_url.clone();
+        let moved = tokio::spawn(async move {
+            let connect = || {
+                CrdtClient::connect_with_session(&url, ReplicaId::from(3), "room", token.clone())
+            };
+            first(connect).await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!moved.is_finished());
+
+        // Clients that are held don't hold up anyone else joining the server
+        let other =
+            CrdtClient::<SquareId, Square>::connect(&second_url, ReplicaId::from(7), "other");
+        let other = tokio::time::timeout(Duration::from_secs(1), other).await;
+        assert!(other.is_ok_and(|joined| joined.is_ok()));
+        assert!(!moved.is_finished());
+
+        // Until someone brings it over
+        let (mut socket, _) = tokio_tungstenite::connect_async(&second_url).await.unwrap();
+        let msg = ServerBound::Sync(ServerBoundSync {
+            replica_id: ReplicaId::from(4),
+            room_id: "room".into(),
+            state: a.state(),
+            subscription: None,
+            causal: None,
+            peer: false,
+        });
+        send(&mut socket, &msg).await.unwrap();
+        recv::<SquareId, Square>(&mut socket).await.unwrap();
+        let c = moved.await.unwrap();
+        assert!(c.get(&SquareId(1)).is_some());
+
+        // Servers that don't catch up in time turn the client away
+        let impatient = serve(&["--causal-wait-ms", "50"]).await;
+        let _ = first(|| CrdtClient::connect(&impatient, ReplicaId::from(5), "other")).await;
+        let token = a.session_token();
+        let moved = CrdtClient::<SquareId, Square>::connect_with_session(
+            &impatient,
+            ReplicaId::from(6),
+            "room",
+            token,
+        );
+        assert!(moved.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn syncs_across_cluster() {
+        let (first_addr, second_addr) = (free_addr(), free_addr());
+        let (first_url, second_url) = (
+            format!("ws://{}", first_addr),
+            format!("ws://{}", second_addr),
+        );
+        let secret = ["--cluster-secret", "shh"];
+        serve_at(
+            &first_addr,
+            &[&["--peer", &second_url][..], &secret].concat(),
+        )
+        .await;
+        serve_at(
+            &second_addr,
+            &[&["--peer", &first_url][..], &secret].concat(),
+        )
+        .await;
+
+        // Each client joins a different server of the cluster
+        let a = first(|| CrdtClient::connect(&first_url, ReplicaId::from(1), "room")).await;
+        a.insert(SquareId(1), Square::default());
+        let b = first(|| CrdtClient::connect(&second_url, ReplicaId::from(2), "room")).await;
+        eventually(|| b.get(&SquareId(1)).is_some()).await;
+
+        b.insert(SquareId(2), Square::default());
+        b.remove(&SquareId(1));
+        eventually(|| a.get(&SquareId(2)).is_some() && a.get(&SquareId(1)).is_none()).await;
+        a.insert(SquareId(3), Square::default());
+        eventually(|| b.get(&SquareId(3)).is_some()).await;
+        assert_eq!(a.state().digest(), b.state().digest());
+
+        // Servers that don't know the secret can't link
+        let (mut socket, _) = tokio_tungstenite::connect_async(&first_url).await.unwrap();
+        let msg = ServerBound::Sync(ServerBoundSync {
+            replica_id: ReplicaId::from(3),
+            room_id: "room".into(),
+            state: AWORMap::<SquareId, Square>::default(),
+            subscription: None,
+            causal: None,
+            peer: true,
+        });
+        send(&mut socket, &msg).await.unwrap();
+        assert!(recv::<SquareId, Square>(&mut socket).await.is_err());
+    }
+}
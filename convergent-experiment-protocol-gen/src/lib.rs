@@ -1 +1,5 @@
-
+//! CRDTs that can cross the wasm boundary, the generated protocol crate re-exports these from
+//! sypytkowski-convergent instead of generating its own copies
+pub use sypytkowski_convergent::delta_state::{
+    awormap::AWORMap, gcounter::GCounter, gset::GSet, pncounter::PNCounter,
+};
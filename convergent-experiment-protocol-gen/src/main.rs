@@ -2,7 +2,13 @@ use fp_bindgen::prelude::*;
 use fp_bindgen::types::CargoDependency;
 use fp_bindgen::{prelude::Serializable, TsExtendedRuntimeConfig};
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use sypytkowski_convergent::delta_state::awormap::{AWORMap, Deltas};
+use sypytkowski_convergent::delta_state::counter_map::CounterMap;
+use sypytkowski_convergent::delta_state::digest::MapDigest;
+use sypytkowski_convergent::delta_state::dot::DotKernel;
+use sypytkowski_convergent::delta_state::mvreg::MVReg;
+use sypytkowski_convergent::delta_state::pncounter::PNCounter;
 use sypytkowski_convergent::{ReplicaId, Value};
 
 #[derive(Debug, Clone, PartialEq, Default, Serializable, Serialize, Deserialize)]
@@ -17,6 +23,48 @@ pub struct Square {
 )]
 pub struct SquareId(pub u32);
 
+/// How large the plugin's state grew, see `state_size`
+#[derive(Debug, Clone, PartialEq, Default, Serializable, Serialize, Deserialize)]
+pub struct SizeReport {
+    /// Keys in the squares map
+    pub entries: u32,
+    /// Dots the context couldn't fold into its clock yet
+    pub dot_cloud: u32,
+    /// Entries of deltas that weren't handed out or acked yet
+    pub delta_entries: u32,
+    /// Size of the state encoded as msgpack
+    pub bytes: u32,
+}
+
+/// Names a document hosted next to the default one, see `create_doc`
+#[derive(
+    Debug, Clone, PartialEq, Default, Serializable, Serialize, Deserialize, Eq, PartialOrd, Ord,
+)]
+pub struct DocId(pub String);
+
+/// Why an export couldn't do what it was asked, returned instead of panicking so JS can drop the
+/// offending message and carry on
+#[derive(Debug, Clone, PartialEq, Serializable, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum CrdtError {
+    /// Bytes that aren't a msgpack encoded state
+    Decode { message: String },
+    /// State that couldn't be encoded as msgpack
+    Encode { message: String },
+    /// State or delta holding an entry its context never saw, see `DotKernel::uncovered_dot`
+    Malformed { message: String },
+    /// No document with that id, see `create_doc`
+    UnknownDoc { doc: DocId },
+}
+
+/// Local deltas JS hasn't acked yet, see `ack_deltas`
+#[derive(Debug, Clone, PartialEq, Default, Serializable, Serialize, Deserialize)]
+pub struct PendingDeltas {
+    /// Acks these deltas and every earlier ones
+    pub seq: u32,
+    pub deltas: Deltas<SquareId, Square>,
+}
+
 impl Value for Square {}
 impl Value for SquareId {}
 
@@ -28,10 +76,56 @@ fp_export! {
     fn get() -> AWORMap<SquareId, Square>;
     fn set(replica: ReplicaId, id: SquareId, square: Square);
     fn del(replica: ReplicaId, id: SquareId);
-    fn merge_deltas(delta: Deltas<SquareId, Square>);
-    fn merge(other: AWORMap<SquareId, Square>) -> AWORMap<SquareId, Square>;
+    fn merge_deltas(delta: Deltas<SquareId, Square>) -> Result<(), CrdtError>;
+    fn merge(other: AWORMap<SquareId, Square>) -> Result<AWORMap<SquareId, Square>, CrdtError>;
     fn deltas() -> Deltas<SquareId, Square>;
-    fn replace(map: AWORMap<SquareId, Square>);
+    fn pending_deltas() -> PendingDeltas;
+    fn ack_deltas(seq: u32);
+    fn replace(map: AWORMap<SquareId, Square>) -> Result<(), CrdtError>;
+    fn undo(replica: ReplicaId) -> bool;
+    fn redo(replica: ReplicaId) -> bool;
+    fn start_undo_group();
+    fn end_undo_group();
+    fn digest() -> MapDigest;
+    fn diff(remote: MapDigest) -> Vec<SquareId>;
+    fn extract(keys: Vec<SquareId>) -> Deltas<SquareId, Square>;
+    fn create_doc(doc: DocId) -> bool;
+    fn drop_doc(doc: DocId) -> bool;
+    fn doc_get(doc: DocId) -> Option<AWORMap<SquareId, Square>>;
+    fn doc_set(doc: DocId, replica: ReplicaId, id: SquareId, square: Square) -> Result<(), CrdtError>;
+    fn doc_del(doc: DocId, replica: ReplicaId, id: SquareId) -> Result<(), CrdtError>;
+    fn doc_deltas(doc: DocId) -> Deltas<SquareId, Square>;
+    fn doc_merge_deltas(doc: DocId, delta: Deltas<SquareId, Square>) -> Result<(), CrdtError>;
+    fn counter_increment(replica: ReplicaId);
+    fn counter_decrement(replica: ReplicaId);
+    fn counter_value() -> i64;
+    fn counter_get() -> PNCounter;
+    fn counter_deltas() -> PNCounter;
+    fn counter_merge_deltas(delta: PNCounter);
+    fn register_set(replica: ReplicaId, value: String);
+    fn register_get() -> Vec<String>;
+    fn register_state() -> MVReg<String>;
+    fn register_deltas() -> DotKernel<String>;
+    fn register_merge_deltas(delta: DotKernel<String>) -> Result<(), CrdtError>;
+    fn get_encoded() -> Result<ByteBuf, CrdtError>;
+    fn merge_encoded(bytes: ByteBuf) -> Result<(), CrdtError>;
+    fn likes_increment(replica: ReplicaId, id: SquareId);
+    fn likes_decrement(replica: ReplicaId, id: SquareId);
+    fn likes_remove(id: SquareId);
+    fn likes_value(id: SquareId) -> i64;
+    fn likes_get() -> CounterMap<SquareId>;
+    fn likes_deltas() -> CounterMap<SquareId>;
+    fn likes_merge_deltas(delta: CounterMap<SquareId>);
+    fn table_insert_row(replica: ReplicaId, index: u32) -> bool;
+    fn table_remove_row(index: u32) -> bool;
+    fn table_insert_column(replica: ReplicaId, index: u32) -> bool;
+    fn table_remove_column(index: u32) -> bool;
+    fn table_set(replica: ReplicaId, row: u32, column: u32, value: String) -> bool;
+    fn table_get() -> Vec<Vec<Vec<String>>>;
+    fn table_deltas() -> Result<ByteBuf, CrdtError>;
+    fn table_merge_deltas(bytes: ByteBuf) -> Result<(), CrdtError>;
+    fn compact();
+    fn state_size() -> SizeReport;
 }
 
 fn main() {
@@ -2,23 +2,161 @@ use sypytkowski_convergent::delta_state::awormap::Deltas;
 
 use crate::types::*;
 
+#[fp_bindgen_support::fp_export_signature]
+pub fn ack_deltas(seq: u32);
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn compact();
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn counter_decrement(replica: ReplicaId);
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn counter_deltas() -> PNCounter;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn counter_get() -> PNCounter;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn counter_increment(replica: ReplicaId);
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn counter_merge_deltas(delta: PNCounter);
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn counter_value() -> i64;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn create_doc(doc: DocId) -> bool;
+
 #[fp_bindgen_support::fp_export_signature]
 pub fn del(replica: ReplicaId, id: SquareId);
 
 #[fp_bindgen_support::fp_export_signature]
 pub fn deltas() -> Deltas<SquareId, Square>;
 
+#[fp_bindgen_support::fp_export_signature]
+pub fn diff(remote: MapDigest) -> Vec<SquareId>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn digest() -> MapDigest;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn doc_del(doc: DocId, replica: ReplicaId, id: SquareId) -> Result<(), CrdtError>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn doc_deltas(doc: DocId) -> Deltas<SquareId, Square>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn doc_get(doc: DocId) -> Option<AWORMap<SquareId, Square>>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn doc_merge_deltas(doc: DocId, delta: Deltas<SquareId, Square>) -> Result<(), CrdtError>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn doc_set(doc: DocId, replica: ReplicaId, id: SquareId, square: Square) -> Result<(), CrdtError>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn drop_doc(doc: DocId) -> bool;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn end_undo_group();
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn extract(keys: Vec<SquareId>) -> Deltas<SquareId, Square>;
+
 #[fp_bindgen_support::fp_export_signature]
 pub fn get() -> AWORMap<SquareId, Square>;
 
 #[fp_bindgen_support::fp_export_signature]
-pub fn merge(other: AWORMap<SquareId, Square>) -> AWORMap<SquareId, Square>;
+pub fn get_encoded() -> Result<serde_bytes::ByteBuf, CrdtError>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn likes_decrement(replica: ReplicaId, id: SquareId);
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn likes_deltas() -> CounterMap<SquareId>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn likes_get() -> CounterMap<SquareId>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn likes_increment(replica: ReplicaId, id: SquareId);
 
 #[fp_bindgen_support::fp_export_signature]
-pub fn merge_deltas(delta: Deltas<SquareId, Square>);
+pub fn likes_merge_deltas(delta: CounterMap<SquareId>);
 
 #[fp_bindgen_support::fp_export_signature]
-pub fn replace(map: AWORMap<SquareId, Square>);
+pub fn likes_remove(id: SquareId);
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn likes_value(id: SquareId) -> i64;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn merge(other: AWORMap<SquareId, Square>) -> Result<AWORMap<SquareId, Square>, CrdtError>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn merge_deltas(delta: Deltas<SquareId, Square>) -> Result<(), CrdtError>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn merge_encoded(bytes: serde_bytes::ByteBuf) -> Result<(), CrdtError>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn pending_deltas() -> PendingDeltas;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn redo(replica: ReplicaId) -> bool;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn register_deltas() -> DotKernel<String>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn register_get() -> Vec<String>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn register_merge_deltas(delta: DotKernel<String>) -> Result<(), CrdtError>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn register_set(replica: ReplicaId, value: String);
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn register_state() -> MVReg<String>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn replace(map: AWORMap<SquareId, Square>) -> Result<(), CrdtError>;
 
 #[fp_bindgen_support::fp_export_signature]
 pub fn set(replica: ReplicaId, id: SquareId, square: Square);
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn start_undo_group();
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn state_size() -> SizeReport;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn table_deltas() -> Result<serde_bytes::ByteBuf, CrdtError>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn table_get() -> Vec<Vec<Vec<String>>>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn table_insert_column(replica: ReplicaId, index: u32) -> bool;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn table_insert_row(replica: ReplicaId, index: u32) -> bool;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn table_merge_deltas(bytes: serde_bytes::ByteBuf) -> Result<(), CrdtError>;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn table_remove_column(index: u32) -> bool;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn table_remove_row(index: u32) -> bool;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn table_set(replica: ReplicaId, row: u32, column: u32, value: String) -> bool;
+
+#[fp_bindgen_support::fp_export_signature]
+pub fn undo(replica: ReplicaId) -> bool;
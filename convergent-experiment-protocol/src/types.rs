@@ -2,32 +2,26 @@
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, collections::BTreeSet};
 use sypytkowski_convergent::Value;
+use sypytkowski_convergent::delta_state::awormap::Deltas;
 
 pub use sypytkowski_convergent::delta_state::awormap::AWORMap;
 pub use sypytkowski_convergent::delta_state::awormap::KeyVal;
+pub use sypytkowski_convergent::delta_state::aworset::AWORSet;
+pub use sypytkowski_convergent::delta_state::counter_map::CounterMap;
+pub use sypytkowski_convergent::delta_state::counter_map::KeyCounter;
+pub use sypytkowski_convergent::delta_state::digest::MapDigest;
 pub use sypytkowski_convergent::delta_state::dot::Dot;
+pub use sypytkowski_convergent::delta_state::dot::DotCloud;
+pub use sypytkowski_convergent::delta_state::dot::DotCtx;
+pub use sypytkowski_convergent::delta_state::dot::DotKernel;
+pub use sypytkowski_convergent::delta_state::dot::RetiredReplicas;
 pub use sypytkowski_convergent::delta_state::dot::VectorClock;
+pub use sypytkowski_convergent::delta_state::gcounter::GCounter;
+pub use sypytkowski_convergent::delta_state::gset::GSet;
+pub use sypytkowski_convergent::delta_state::mvreg::MVReg;
+pub use sypytkowski_convergent::delta_state::pncounter::PNCounter;
 pub use sypytkowski_convergent::ReplicaId;
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
-pub struct AWORSet<V: Clone + PartialEq + Default + Value> {
-    pub kernel: DotKernel<V>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub delta: Option<DotKernel<V>>,
-}
-
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
-pub struct DotCtx {
-    pub clock: VectorClock,
-    pub dot_cloud: BTreeSet<Dot>,
-}
-
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
-pub struct DotKernel<V: Clone + Value> {
-    pub ctx: DotCtx,
-    pub entries: BTreeMap<Dot, V>,
-}
-
 // stupid patch
 #[derive(
     Clone,
@@ -60,5 +54,79 @@ pub struct Square {
 )]
 pub struct SquareId(pub u32);
 
+/// How large the plugin's state grew, see `state_size`
+#[derive(
+    Clone,
+    Debug,
+    Deserialize,
+    PartialEq,
+    Serialize,
+    Default,
+    fp_bindgen::prelude::Serializable,
+)]
+pub struct SizeReport {
+    /// Keys in the squares map
+    pub entries: u32,
+    /// Dots the context couldn't fold into its clock yet
+    pub dot_cloud: u32,
+    /// Entries of deltas that weren't handed out or acked yet
+    pub delta_entries: u32,
+    /// Size of the state encoded as msgpack
+    pub bytes: u32,
+}
+
+/// Names a document hosted next to the default one, see `create_doc`
+#[derive(
+    Clone,
+    Debug,
+    Deserialize,
+    PartialEq,
+    Serialize,
+    Default,
+    Eq,
+    PartialOrd,
+    Ord,
+    fp_bindgen::prelude::Serializable,
+)]
+pub struct DocId(pub String);
+
+/// Why an export couldn't do what it was asked, returned instead of panicking so JS can drop the
+/// offending message and carry on
+#[derive(
+    Clone,
+    Debug,
+    Deserialize,
+    PartialEq,
+    Serialize,
+    fp_bindgen::prelude::Serializable,
+)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum CrdtError {
+    /// Bytes that aren't a msgpack encoded state
+    Decode { message: String },
+    /// State that couldn't be encoded as msgpack
+    Encode { message: String },
+    /// State or delta holding an entry its context never saw, see `DotKernel::uncovered_dot`
+    Malformed { message: String },
+    /// No document with that id, see `create_doc`
+    UnknownDoc { doc: DocId },
+}
+
+/// Local deltas JS hasn't acked yet, see `ack_deltas`
+#[derive(
+    Clone,
+    Debug,
+    Deserialize,
+    PartialEq,
+    Serialize,
+    Default,
+    fp_bindgen::prelude::Serializable,
+)]
+pub struct PendingDeltas {
+    /// Acks these deltas and every earlier ones
+    pub seq: u32,
+    pub deltas: Deltas<SquareId, Square>,
+}
+
 impl Value for Square {}
 impl Value for SquareId {}
@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Context, Result};
+use convergent_experiment_protocol::ReplicaId;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sypytkowski_convergent::delta_state::dot::{Dot, VectorClock};
+
+use crate::crdt::DeltaCrdt;
+use crate::proto::Rejection;
+
+/// How clients prove who they are. They send a token in the `token` query parameter of the
+/// websocket URL.
+pub enum Auth {
+    /// Anyone can connect and write as any replica
+    Open,
+    /// Every client knows the same secret. It only keeps strangers out, clients are trusted to
+    /// pick their own replica id.
+    Secret(String),
+    /// HS256 signed JWTs whose subject is the replica id the client may connect as
+    Jwt(DecodingKey),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    /// Expiry in seconds since the epoch, required
+    exp: u64,
+}
+
+impl Auth {
+    pub fn is_open(&self) -> bool {
+        matches!(self, Auth::Open)
+    }
+
+    /// Checks that `token` lets the client connect as `replica`
+    pub fn authorize(&self, token: Option<&str>, replica: ReplicaId) -> Result<()> {
+        let missing = || anyhow!("Client did not send a token");
+        match self {
+            Auth::Open => Ok(()),
+            Auth::Secret(secret) => {
+                let token = token.ok_or_else(missing)?;
+                if !constant_time_eq(secret.as_bytes(), token.as_bytes()) {
+                    return Err(anyhow!("Wrong secret"));
+                }
+                Ok(())
+            }
+            Auth::Jwt(key) => {
+                let token = token.ok_or_else(missing)?;
+                let claims =
+                    jsonwebtoken::decode::<Claims>(token, key, &Validation::new(Algorithm::HS256))
+                        .with_context(|| "Invalid JWT")?
+                        .claims;
+                if claims.sub != replica.to_string() {
+                    return Err(anyhow!(
+                        "JWT is for replica {} but client connected as {}",
+                        claims.sub,
+                        replica
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Compares every byte so the time taken doesn't tell how much of the secret was guessed
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Authenticated clients can only add entries as their own replica, the entries of others reach
+/// the server from the others themselves
+pub fn check_dots<K>(
+    replica: ReplicaId,
+    dots: impl IntoIterator<Item = Dot>,
+) -> Result<(), Rejection<K>> {
+    match dots.into_iter().find(|dot| dot.0 != replica) {
+        Some(Dot(claimed, _)) => Err(Rejection::ForeignDot { replica: claimed }),
+        None => Ok(()),
+    }
+}
+
+/// Of other replicas, authenticated clients can only claim to have seen what the room has seen
+/// (`known`). Merging a context claiming dots another replica hasn't written yet would take that
+/// replica's later entries for removed ones, and so would retiring it.
+pub fn check_context<K>(
+    replica: ReplicaId,
+    context: &VectorClock,
+    known: &VectorClock,
+) -> Result<(), Rejection<K>> {
+    let claimed = context
+        .iter()
+        .map(|(&id, &n)| Dot(id, n))
+        .find(|&dot| dot.0 != replica && !known.contains(dot));
+    if let Some(Dot(claimed, _)) = claimed {
+        return Err(Rejection::ForeignDot { replica: claimed });
+    }
+    match context.retired().first_missing_from(known.retired()) {
+        Some(claimed) => Err(Rejection::ForeignDot { replica: claimed }),
+        None => Ok(()),
+    }
+}
+
+/// `known` is the version of the room the deltas are for
+pub fn check_deltas<C: DeltaCrdt>(
+    replica: ReplicaId,
+    deltas: &C::Delta,
+    known: &VectorClock,
+) -> Result<(), Rejection<C::Key>> {
+    check_dots(replica, C::delta_dots(deltas))?;
+    check_context(replica, &C::delta_version(deltas), known)
+}
+
+/// `known` is the version of the room the state is for
+pub fn check_state<C: DeltaCrdt>(
+    replica: ReplicaId,
+    state: &C,
+    known: &VectorClock,
+) -> Result<(), Rejection<C::Key>> {
+    check_dots(replica, state.dots())?;
+    check_context(replica, &state.version(), known)
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use jsonwebtoken::{DecodingKey, EncodingKey, Header};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+    use sypytkowski_convergent::delta_state::dot::VectorClock;
+
+    use super::{check_deltas, Auth, Claims};
+    use crate::proto::Rejection;
+
+    #[test]
+    fn secret() {
+        let auth = Auth::Secret("hunter2".into());
+        let replica = ReplicaId::from(1);
+        assert!(auth.authorize(Some("hunter2"), replica).is_ok());
+        assert!(auth.authorize(Some("hunter3"), replica).is_err());
+        assert!(auth.authorize(None, replica).is_err());
+        assert!(Auth::Open.authorize(None, replica).is_ok());
+    }
+
+    #[test]
+    fn jwt_binds_replica() {
+        let secret = b"secret";
+        let auth = Auth::Jwt(DecodingKey::from_secret(secret));
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &Claims {
+                sub: "1".into(),
+                exp: u32::MAX as u64,
+            },
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap();
+
+        assert!(auth.authorize(Some(&token), ReplicaId::from(1)).is_ok());
+        assert!(auth.authorize(Some(&token), ReplicaId::from(2)).is_err());
+        assert!(auth.authorize(Some("garbage"), ReplicaId::from(1)).is_err());
+    }
+
+    #[test]
+    fn forged_dots() {
+        let alice = ReplicaId::from(1);
+        let mallory = ReplicaId::from(2);
+        let mut map = AWORMap::<SquareId, Square>::default();
+        map.insert(alice, SquareId(1), Square::default());
+        let deltas = map.split_mut().unwrap();
+
+        let known = VectorClock::default();
+        assert_eq!(
+            check_deltas::<AWORMap<_, _>>(alice, &deltas, &known),
+            Ok(())
+        );
+        assert_eq!(
+            check_deltas::<AWORMap<_, _>>(mallory, &deltas, &known),
+            Err(Rejection::ForeignDot { replica: alice })
+        );
+    }
+
+    #[test]
+    fn forged_context() {
+        let alice = ReplicaId::from(1);
+        let mallory = ReplicaId::from(2);
+        let mut room = AWORMap::<SquareId, Square>::default();
+        room.insert(alice, SquareId(1), Square::default());
+        room.split_mut();
+
+        // A removal whose context claims dots Alice has yet to write, merging it would take
+        // them for removed once she does
+        let mut forged = room.clone();
+        for _ in 0..4 {
+            forged.insert(alice, SquareId(1), Square::default());
+        }
+        forged.split_mut();
+        forged.remove(&SquareId(1));
+        let deltas = forged.split_mut().unwrap();
+
+        assert_eq!(
+            check_deltas::<AWORMap<_, _>>(mallory, &deltas, &room.version()),
+            Err(Rejection::ForeignDot { replica: alice })
+        );
+        // Claiming what the room has seen is fine
+        room.merge_in_place(&forged);
+        assert_eq!(
+            check_deltas::<AWORMap<_, _>>(mallory, &deltas, &room.version()),
+            Ok(())
+        );
+    }
+}
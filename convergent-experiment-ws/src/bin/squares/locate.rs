@@ -0,0 +1,28 @@
+use convergent_experiment_protocol::{Square, SquareId};
+use convergent_experiment_ws::proto::Region;
+use convergent_experiment_ws::Locate;
+
+use crate::Squares;
+
+/// Squares are in a region when they overlap it
+pub struct Positions;
+
+impl Locate<Squares> for Positions {
+    fn contains(&self, state: &Squares, key: &SquareId, region: &Region) -> bool {
+        let Some(Square {
+            x,
+            y,
+            width,
+            height,
+        }) = state.get(key).cloned()
+        else {
+            return false;
+        };
+        region.intersects(&Region {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
@@ -0,0 +1,26 @@
+mod locate;
+mod validate;
+
+use clap::Parser;
+use convergent_experiment_protocol::{Square, SquareId};
+use convergent_experiment_ws::{Config, CrdtServer};
+use locate::Positions;
+use sypytkowski_convergent::delta_state::awormap::AWORMap;
+use validate::default_validator;
+use tracing_subscriber::EnvFilter;
+
+/// The drawing the frontend syncs
+type Squares = AWORMap<SquareId, Square>;
+
+#[tokio::main]
+async fn main() {
+    // `RUST_LOG` picks what gets logged, `debug` for every message and replication round
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+    let config = Config::parse();
+    let server = CrdtServer::<Squares>::new(&config, default_validator())
+        .await
+        .expect("Failed to set up the server")
+        .with_locator(Positions);
+    server.run().await.expect("Failed to run the server");
+}
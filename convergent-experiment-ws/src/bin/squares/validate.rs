@@ -0,0 +1,138 @@
+use convergent_experiment_protocol::{Square, SquareId};
+use convergent_experiment_ws::proto::Rejection;
+use convergent_experiment_ws::Validator;
+use sypytkowski_convergent::delta_state::awormap::Deltas;
+
+use crate::Squares;
+
+/// Squares have to lie within the canvas, which starts at the origin
+pub struct Bounds {
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Validator<Squares> for Bounds {
+    fn validate(&self, deltas: &Deltas<SquareId, Square>) -> Result<(), Rejection<SquareId>> {
+        for (_, kv) in deltas.iter() {
+            let Square {
+                x,
+                y,
+                width,
+                height,
+            } = kv.val().clone();
+            let fits = x >= 0.0
+                && y >= 0.0
+                && width >= 0.0
+                && height >= 0.0
+                && x + width <= self.width
+                && y + height <= self.height;
+            if !fits {
+                return Err(Rejection::OutOfBounds {
+                    id: kv.key().clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Finite;
+
+impl Validator<Squares> for Finite {
+    fn validate(&self, deltas: &Deltas<SquareId, Square>) -> Result<(), Rejection<SquareId>> {
+        for (_, kv) in deltas.iter() {
+            let square = kv.val();
+            let finite = [square.x, square.y, square.width, square.height]
+                .iter()
+                .all(|n| n.is_finite());
+            if !finite {
+                return Err(Rejection::NotFinite {
+                    id: kv.key().clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Caps the number of squares a single update can add
+pub struct MaxEntries(pub usize);
+
+impl Validator<Squares> for MaxEntries {
+    fn validate(&self, deltas: &Deltas<SquareId, Square>) -> Result<(), Rejection<SquareId>> {
+        let entries = deltas.values().len();
+        if entries > self.0 {
+            return Err(Rejection::TooLarge {
+                entries,
+                max: self.0,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// What the frontend can produce: a 1000 by 1000 canvas, and updates of a handful of squares or
+/// a sync's worth of them
+pub fn default_validator() -> Vec<Box<dyn Validator<Squares>>> {
+    vec![
+        Box::new(MaxEntries(1024)),
+        Box::new(Finite),
+        Box::new(Bounds {
+            width: 1000.0,
+            height: 1000.0,
+        }),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use convergent_experiment_ws::proto::Rejection;
+    use convergent_experiment_ws::Validator;
+
+    use super::{default_validator, MaxEntries};
+
+    fn square(x: f32, y: f32) -> Square {
+        Square {
+            x,
+            y,
+            width: 100.0,
+            height: 100.0,
+        }
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let validator = default_validator();
+        let replica = ReplicaId::from(1);
+        let mut map = AWORMap::default();
+
+        map.insert(replica, SquareId(1), square(900.0, 0.0));
+        assert_eq!(validator.validate(&map.split_mut().unwrap()), Ok(()));
+
+        map.insert(replica, SquareId(2), square(901.0, 0.0));
+        assert_eq!(
+            validator.validate(&map.split_mut().unwrap()),
+            Err(Rejection::OutOfBounds { id: SquareId(2) })
+        );
+
+        map.insert(replica, SquareId(3), square(f32::NAN, 0.0));
+        assert_eq!(
+            validator.validate(&map.split_mut().unwrap()),
+            Err(Rejection::NotFinite { id: SquareId(3) })
+        );
+
+        // Removals don't carry squares
+        map.remove(&SquareId(3));
+        assert_eq!(validator.validate(&map.split_mut().unwrap()), Ok(()));
+
+        map.insert(replica, SquareId(4), square(0.0, 0.0));
+        map.insert(replica, SquareId(5), square(0.0, 0.0));
+        assert_eq!(
+            MaxEntries(1).validate(&map.split_mut().unwrap()),
+            Err(Rejection::TooLarge { entries: 2, max: 1 })
+        );
+    }
+}
@@ -0,0 +1,17 @@
+use clap::Parser;
+use convergent_experiment_ws::{Config, OpServer};
+use sypytkowski_commutative::rga::Rga;
+use tracing_subscriber::EnvFilter;
+
+/// Shared text, replicated with the op-based protocol
+type Text = Rga<char>;
+
+#[tokio::main]
+async fn main() {
+    // `RUST_LOG` picks what gets logged, `debug` for every message and replication round
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+    let config = Config::parse();
+    let server = OpServer::<Text>::new(&config, Rga::new).expect("Failed to set up the server");
+    server.run().await.expect("Failed to run the server");
+}
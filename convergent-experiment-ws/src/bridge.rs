@@ -0,0 +1,145 @@
+//! Rooms kept in sync between server instances through Redis pub/sub, see [`Bridge`].
+//!
+//! An alternative to linking every server of a cluster with every other one (see
+//! [`crate::cluster`]), so instances can come and go behind a load balancer. Every room has a
+//! channel, which the instances that have the room open subscribe to. An instance that opens a
+//! room publishes its state in a hello, which the others merge and answer with theirs. After that
+//! it publishes what its own clients change, and merges and passes on to its clients what the
+//! others publish. Nothing is kept in Redis, an instance only catches up with what it missed while
+//! a room wasn't open on it when the room is opened.
+use anyhow::{Context, Result};
+use convergent_experiment_protocol::ReplicaId;
+use futures_util::Stream;
+use redis::aio::MultiplexedConnection;
+use serde::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::crdt::DeltaCrdt;
+use crate::proto::RoomId;
+
+/// Connects the rooms of the server to Redis
+pub struct Bridge {
+    client: redis::Client,
+}
+
+impl Bridge {
+    /// Only checks the URL, connections are made as rooms open
+    pub fn open(url: &str) -> Result<Self> {
+        let client =
+            redis::Client::open(url).with_context(|| format!("Invalid Redis URL {:?}", url))?;
+        Ok(Self { client })
+    }
+
+    /// Subscribes to the room's channel, returns what's published to it along with the way to
+    /// publish to it
+    pub async fn join(
+        &self,
+        room: &RoomId,
+    ) -> Result<(RoomChannel, impl Stream<Item = Vec<u8>> + Send)> {
+        let name = channel(room);
+        let publisher = self
+            .client
+            .get_multiplexed_async_connection()
+            .await
+            .with_context(|| "Failed to connect to Redis")?;
+        let mut subscriber = self
+            .client
+            .get_async_pubsub()
+            .await
+            .with_context(|| "Failed to connect to Redis")?;
+        subscriber
+            .subscribe(&name)
+            .await
+            .with_context(|| format!("Failed to subscribe to {:?}", name))?;
+        let published = futures_util::StreamExt::map(subscriber.into_on_message(), |msg| {
+            msg.get_payload_bytes().to_vec()
+        });
+        Ok((RoomChannel { name, publisher }, published))
+    }
+}
+
+/// Channel of a room, with the server's codec. Redis channels are per database rather than per
+/// namespace, so they're prefixed.
+pub fn channel(room: &RoomId) -> String {
+    format!("convergent:room:{}", room)
+}
+
+pub struct RoomChannel {
+    name: String,
+    publisher: MultiplexedConnection,
+}
+
+impl RoomChannel {
+    pub async fn publish<C: DeltaCrdt, S: Codec>(&mut self, msg: &Bridged<C>) -> Result<()> {
+        redis::cmd("PUBLISH")
+            .arg(&self.name)
+            .arg(S::encode(msg)?)
+            .query_async::<()>(&mut self.publisher)
+            .await
+            .with_context(|| format!("Failed to publish to {:?}", self.name))
+    }
+}
+
+/// What the instances publish to a room's channel. Every instance gets what it published itself
+/// back, `server` is how it tells.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", bound = "")]
+#[serde(rename_all = "camelCase")]
+pub enum Bridged<C: DeltaCrdt> {
+    /// Published when the room opens, the others answer with a [`Bridged::State`]
+    Hello {
+        server: ReplicaId,
+        state: C,
+    },
+    State {
+        server: ReplicaId,
+        state: C,
+    },
+    Delta {
+        server: ReplicaId,
+        deltas: C::Delta,
+    },
+}
+
+impl<C: DeltaCrdt> Bridged<C> {
+    pub fn server(&self) -> ReplicaId {
+        match self {
+            Bridged::Hello { server, .. }
+            | Bridged::State { server, .. }
+            | Bridged::Delta { server, .. } => *server,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use crate::codec::{Codec, MsgPack};
+    use crate::crdt::DeltaCrdt;
+
+    use super::{channel, Bridged};
+
+    type Squares = AWORMap<SquareId, Square>;
+
+    #[test]
+    fn bridged_round_trip() {
+        let server = ReplicaId::from(1);
+        let mut state = Squares::default();
+        state.insert(ReplicaId::from(2), SquareId(1), Square::default());
+        let deltas = state.split_mut().unwrap();
+
+        let msg = Bridged::<Squares>::Delta { server, deltas };
+        let decoded: Bridged<Squares> = MsgPack::decode(&MsgPack::encode(&msg).unwrap()).unwrap();
+        assert_eq!(decoded.server(), server);
+        let Bridged::Delta { deltas, .. } = decoded else {
+            panic!("Expected a delta but got {:?}", decoded);
+        };
+        let mut merged = Squares::default();
+        DeltaCrdt::merge_delta_in_place(&mut merged, &deltas);
+        assert_eq!(merged.digest(), state.digest());
+
+        assert_eq!(channel(&"room".into()), "convergent:room:room");
+    }
+}
@@ -0,0 +1,148 @@
+//! Session guarantees for clients that move between servers, see [`Session`].
+//!
+//! Every server has its own replica of a room, and they catch up with each other at their own
+//! pace. A client that switches servers could otherwise join one that hasn't seen its own changes
+//! yet, or one that's behind what it already read from the last one. Clients hand the servers
+//! they join a [`CausalToken`], and servers hold their answer until their room caught up with it.
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+use sypytkowski_convergent::delta_state::dot::VectorClock;
+
+use crate::crdt::DeltaCrdt;
+
+/// What a client read and wrote, as versions of the room, see [`DeltaCrdt::version`]
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CausalToken {
+    pub read: VectorClock,
+    pub write: VectorClock,
+}
+
+impl CausalToken {
+    /// Version a room needs before it can answer the client: `write` for it to read its writes,
+    /// `read` for its reads to be monotonic
+    pub fn required(&self) -> VectorClock {
+        let mut required = self.read.clone();
+        required.merge_in_place(&self.write);
+        required
+    }
+
+    /// Whether a room at `version` can answer the client
+    pub fn is_covered_by(&self, version: &VectorClock) -> bool {
+        covers(version, &self.required())
+    }
+}
+
+/// Whether `version` is at least `required` for every replica
+pub fn covers(version: &VectorClock, required: &VectorClock) -> bool {
+    required
+        .iter()
+        .all(|(id, n)| version.get(id).is_some_and(|seen| seen >= n) || version.is_retired(*id))
+}
+
+/// Tracks what a client read and wrote across its connections, so it can ask the next server it
+/// joins for read-your-writes and monotonic reads.
+///
+/// Only deltas the client sent count as written, and only what it merged from a server as read.
+pub struct Session<C: DeltaCrdt> {
+    read: VectorClock,
+    write: VectorClock,
+    _crdt: PhantomData<fn() -> C>,
+}
+
+impl<C: DeltaCrdt> Session<C> {
+    pub fn new() -> Self {
+        Self::resume(CausalToken::default())
+    }
+
+    /// Picks up the session `token` was taken from, in another process say
+    pub fn resume(CausalToken { read, write }: CausalToken) -> Self {
+        Self {
+            read,
+            write,
+            _crdt: PhantomData,
+        }
+    }
+
+    /// To be called with every delta sent to a server
+    pub fn wrote(&mut self, delta: &C::Delta) {
+        self.write.merge_in_place(&C::delta_version(delta));
+    }
+
+    /// To be called with every delta merged from a server
+    pub fn read_delta(&mut self, delta: &C::Delta) {
+        self.read.merge_in_place(&C::delta_version(delta));
+    }
+
+    /// To be called with every state merged from a server
+    pub fn read_state(&mut self, state: &C) {
+        self.read.merge_in_place(&state.version());
+    }
+
+    /// Goes in the first message to the next server the client joins
+    pub fn session_token(&self) -> CausalToken {
+        CausalToken {
+            read: self.read.clone(),
+            write: self.write.clone(),
+        }
+    }
+}
+
+impl<C: DeltaCrdt> Default for Session<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: DeltaCrdt> fmt::Debug for Session<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("read", &self.read)
+            .field("write", &self.write)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use crate::crdt::DeltaCrdt;
+
+    use super::Session;
+
+    type Squares = AWORMap<SquareId, Square>;
+
+    #[test]
+    fn tokens_follow_reads_and_writes() {
+        let (a, b) = (ReplicaId::from(1), ReplicaId::from(2));
+        let mut server = Squares::default();
+        server.insert(b, SquareId(1), Square::default());
+        server.split_mut();
+
+        // The client reads the state of one server and writes to it
+        let mut session = Session::<Squares>::new();
+        let mut client = server.clone();
+        session.read_state(&client);
+        client.insert(a, SquareId(2), Square::default());
+        let delta = client.split_mut().unwrap();
+        session.wrote(&delta);
+
+        let token = session.session_token();
+        assert!(!token.is_covered_by(&Squares::default().version()));
+        assert!(!token.is_covered_by(&server.version()));
+        DeltaCrdt::merge_delta_in_place(&mut server, &delta);
+        assert!(token.is_covered_by(&server.version()));
+
+        // A server that only has the write is still behind what the client read
+        let mut other = Squares::default();
+        DeltaCrdt::merge_delta_in_place(&mut other, &delta);
+        assert!(!token.is_covered_by(&other.version()));
+
+        let resumed = Session::<Squares>::resume(token.clone());
+        assert_eq!(resumed.session_token(), token);
+    }
+}
@@ -0,0 +1,111 @@
+//! Several servers serving the same rooms, see [`Cluster`].
+//!
+//! Every server has its own replica id and its own copy of the rooms open on it. When a room is
+//! opened, the server dials each of its peers and links the room with theirs: it sends the peer
+//! its state in a sync flagged as `peer`, merges the state it's answered with, and from then on
+//! passes on the updates of its own clients to the peer as plain updates. What comes in from a
+//! peer isn't passed on again, so every server needs every other one as a peer. Clients can join
+//! any of them.
+//!
+//! Peers only link rooms that are open on both ends, a room still closes once its last client
+//! left and whoever joins it again syncs it back up. End to end encrypted rooms aren't linked.
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use convergent_experiment_protocol::ReplicaId;
+
+use crate::auth::constant_time_eq;
+
+/// How long a link waits before dialing a peer again after it failed or hung up
+pub const LINK_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Who the server is in the cluster and who else is in it
+#[derive(Clone, Debug)]
+pub struct Cluster {
+    /// Replica the server syncs with its peers as
+    pub replica: ReplicaId,
+    /// Websocket URLs of the other servers
+    pub peers: Vec<String>,
+    /// Goes in the `cluster` query parameter of the links, peers are let in with it rather than
+    /// with a client's token
+    secret: Option<String>,
+}
+
+impl Cluster {
+    pub fn new(replica: ReplicaId, peers: Vec<String>, secret: Option<String>) -> Self {
+        Self {
+            replica,
+            peers,
+            secret,
+        }
+    }
+
+    /// Checks that a server that connected with `secret` may link rooms with this one. Without a
+    /// cluster secret, peers are let in only if clients are let in without a token.
+    pub fn authorize(&self, secret: Option<&str>, open: bool) -> Result<()> {
+        match (&self.secret, secret) {
+            (Some(expected), Some(secret)) => {
+                if !constant_time_eq(expected.as_bytes(), secret.as_bytes()) {
+                    return Err(anyhow!("Wrong cluster secret"));
+                }
+                Ok(())
+            }
+            (Some(_), None) => Err(anyhow!("Peer did not send the cluster secret")),
+            (None, _) if open => Ok(()),
+            (None, _) => Err(anyhow!(
+                "Peers need a cluster secret when clients need a token"
+            )),
+        }
+    }
+
+    /// URL of the link to `peer`, with the cluster secret if there is one
+    pub fn link_url(&self, peer: &str) -> String {
+        let Some(secret) = &self.secret else {
+            return peer.to_string();
+        };
+        let separator = match peer.split_once("://").map_or(peer, |(_, rest)| rest) {
+            rest if rest.contains('?') => "&",
+            rest if rest.contains('/') => "?",
+            // The query needs a path in front of it
+            _ => "/?",
+        };
+        let secret: String = form_urlencoded::byte_serialize(secret.as_bytes()).collect();
+        format!("{}{}cluster={}", peer, separator, secret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::ReplicaId;
+    use tungstenite::http::Uri;
+
+    use crate::server::query_param;
+
+    use super::Cluster;
+
+    #[test]
+    fn links_carry_the_secret() {
+        let peers = vec!["ws://a:1".to_string(), "ws://b:2/?token=t".to_string()];
+        let open = Cluster::new(ReplicaId::from(1), peers.clone(), None);
+        assert_eq!(open.link_url(&peers[0]), "ws://a:1");
+        assert!(open.authorize(None, true).is_ok());
+        assert!(open.authorize(None, false).is_err());
+
+        let closed = Cluster::new(ReplicaId::from(1), peers.clone(), Some("s".into()));
+        assert_eq!(closed.link_url(&peers[0]), "ws://a:1/?cluster=s");
+        assert_eq!(closed.link_url(&peers[1]), "ws://b:2/?token=t&cluster=s");
+        assert!(closed.authorize(Some("s"), false).is_ok());
+        assert!(closed.authorize(Some("x"), true).is_err());
+        assert!(closed.authorize(None, true).is_err());
+    }
+
+    #[test]
+    fn secrets_with_reserved_characters() {
+        let secret = "a&b=c d%";
+        let cluster = Cluster::new(ReplicaId::from(1), vec![], Some(secret.into()));
+        let url = cluster.link_url("ws://a:1/?room=x%2Fy");
+        let uri: Uri = url.parse().unwrap();
+        assert_eq!(query_param(&uri, "cluster").as_deref(), Some(secret));
+        assert_eq!(query_param(&uri, "room").as_deref(), Some("x/y"));
+    }
+}
@@ -0,0 +1,29 @@
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// How messages and snapshots are turned into bytes. Clients have to speak the same codec as the
+/// server.
+pub trait Codec: Send + Sync + 'static {
+    /// Extension of the snapshots in the data dir
+    const EXTENSION: &'static str;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// Structs are encoded as maps with their field names, like the frontend expects
+pub struct MsgPack;
+
+impl Codec for MsgPack {
+    const EXTENSION: &'static str = "msgpack";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).with_context(|| "Failed to serialize to msgpack")
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(bytes).with_context(|| "Failed to deserialize msgpack")
+    }
+}
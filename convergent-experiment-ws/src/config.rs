@@ -0,0 +1,270 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use convergent_experiment_protocol::ReplicaId;
+use jsonwebtoken::DecodingKey;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tungstenite::protocol::WebSocketConfig;
+
+use crate::auth::Auth;
+#[cfg(feature = "redis")]
+use crate::bridge::Bridge;
+use crate::cluster::Cluster;
+use crate::limit::Rate;
+use crate::proto::RoomId;
+
+/// Every flag can be set through its environment variable as well
+#[derive(Debug, Parser)]
+#[command(about = "Server of the convergent experiment")]
+pub struct Config {
+    /// Address to listen on
+    #[arg(long, env = "CONVERGENT_ADDR", default_value = "127.0.0.1:6969")]
+    pub addr: String,
+
+    /// Keeps the rooms around across restarts
+    #[arg(long, env = "CONVERGENT_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Lets in the clients that know the secret
+    #[arg(long, env = "CONVERGENT_SECRET", conflicts_with = "jwt_secret")]
+    pub secret: Option<String>,
+
+    /// Lets in the clients with a JWT signed with the secret for their replica id
+    #[arg(long, env = "CONVERGENT_JWT_SECRET")]
+    pub jwt_secret: Option<String>,
+
+    /// Serves the metrics over HTTP on this address, for any path
+    #[arg(long, env = "CONVERGENT_METRICS")]
+    pub metrics: Option<String>,
+
+    /// PEM certificate chain, serves wss instead of ws along with `--tls-key`
+    #[arg(long, env = "CONVERGENT_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the certificate
+    #[arg(long, env = "CONVERGENT_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Connections past this many are turned away
+    #[arg(long, env = "CONVERGENT_MAX_CLIENTS")]
+    pub max_clients: Option<usize>,
+
+    /// Largest message a client may send, in bytes. Clients that send larger ones are
+    /// disconnected.
+    #[arg(long, env = "CONVERGENT_MAX_MESSAGE_SIZE", default_value_t = 16 << 20)]
+    pub max_message_size: usize,
+
+    /// Messages a client may send per second on average, clients that send more are
+    /// disconnected
+    #[arg(long, env = "CONVERGENT_MAX_MESSAGES_PER_SEC", default_value_t = 200.0)]
+    pub max_messages_per_sec: f64,
+
+    /// Messages a client may send at once on top of the average
+    #[arg(long, env = "CONVERGENT_MESSAGE_BURST", default_value_t = 400.0)]
+    pub message_burst: f64,
+
+    /// Seconds between the digests sent to the clients of a room so they can catch up on updates
+    /// they missed, 0 turns them off
+    #[arg(long, env = "CONVERGENT_ANTI_ENTROPY", default_value_t = 30)]
+    pub anti_entropy: u64,
+
+    /// `<room>=<seconds>` overrides `--anti-entropy` for one room, can be repeated
+    #[arg(long, value_parser = parse_room_anti_entropy)]
+    pub room_anti_entropy: Vec<(RoomId, u64)>,
+
+    /// Room whose clients encrypt their updates end to end, the server only relays them. Can be
+    /// repeated.
+    #[arg(long)]
+    pub e2e_room: Vec<RoomId>,
+
+    /// Milliseconds a joining client's answer is held for the room to catch up with the client's
+    /// session, see `causal`. Clients whose session the room doesn't catch up with in time are
+    /// disconnected.
+    #[arg(long, env = "CONVERGENT_CAUSAL_WAIT_MS", default_value_t = 5000)]
+    pub causal_wait_ms: u64,
+
+    /// Replica the server syncs with the other servers of its cluster as, random if not set
+    #[arg(long, env = "CONVERGENT_REPLICA_ID")]
+    pub replica_id: Option<ReplicaId>,
+
+    /// Websocket URL of another server of the cluster, see `cluster`. Can be repeated, and every
+    /// server needs every other one.
+    #[arg(long)]
+    pub peer: Vec<String>,
+
+    /// Lets in the servers of the cluster that know the secret, and is sent to the peers
+    #[arg(long, env = "CONVERGENT_CLUSTER_SECRET")]
+    pub cluster_secret: Option<String>,
+
+    /// Keeps the rooms in sync with the other servers on the same Redis through pub/sub instead
+    /// of linking them with peers, see `bridge`
+    #[cfg(feature = "redis")]
+    #[arg(long, env = "CONVERGENT_REDIS")]
+    pub redis: Option<String>,
+}
+
+/// How often the clients of each room get a digest, see [`Config::anti_entropy`]
+#[derive(Clone, Debug, Default)]
+pub struct AntiEntropy {
+    default: Option<Duration>,
+    rooms: HashMap<RoomId, Option<Duration>>,
+}
+
+impl AntiEntropy {
+    pub fn interval(&self, room: &RoomId) -> Option<Duration> {
+        *self.rooms.get(room).unwrap_or(&self.default)
+    }
+}
+
+/// Zero seconds means never
+fn anti_entropy_interval(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+fn parse_room_anti_entropy(arg: &str) -> Result<(RoomId, u64), String> {
+    let (room, secs) = arg
+        .rsplit_once('=')
+        .ok_or_else(|| format!("Expected <room>=<seconds> but got {:?}", arg))?;
+    let secs = secs
+        .parse()
+        .map_err(|e| format!("Invalid seconds {:?}: {}", secs, e))?;
+    Ok((room.to_string(), secs))
+}
+
+impl Config {
+    pub fn auth(&self) -> Auth {
+        if let Some(secret) = &self.jwt_secret {
+            return Auth::Jwt(DecodingKey::from_secret(secret.as_bytes()));
+        }
+        match &self.secret {
+            Some(secret) => Auth::Secret(secret.clone()),
+            None => Auth::Open,
+        }
+    }
+
+    pub fn tls(&self) -> Result<Option<TlsAcceptor>> {
+        let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) else {
+            return Ok(None);
+        };
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .with_context(|| "Invalid TLS certificate or key")?;
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    pub fn anti_entropy(&self) -> AntiEntropy {
+        AntiEntropy {
+            default: anti_entropy_interval(self.anti_entropy),
+            rooms: self
+                .room_anti_entropy
+                .iter()
+                .map(|(room, secs)| (room.clone(), anti_entropy_interval(*secs)))
+                .collect(),
+        }
+    }
+
+    pub fn e2e_rooms(&self) -> HashSet<RoomId> {
+        self.e2e_room.iter().cloned().collect()
+    }
+
+    pub fn causal_wait(&self) -> Duration {
+        Duration::from_millis(self.causal_wait_ms)
+    }
+
+    pub fn cluster(&self) -> Cluster {
+        Cluster::new(
+            self.replica_id.unwrap_or_else(ReplicaId::random),
+            self.peer.clone(),
+            self.cluster_secret.clone(),
+        )
+    }
+
+    #[cfg(feature = "redis")]
+    pub fn bridge(&self) -> Result<Option<Bridge>> {
+        self.redis.as_deref().map(Bridge::open).transpose()
+    }
+
+    pub fn rate(&self) -> Rate {
+        Rate {
+            per_sec: self.max_messages_per_sec,
+            burst: self.message_burst,
+        }
+    }
+
+    pub fn websocket(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: Some(self.max_message_size),
+            max_frame_size: Some(self.max_message_size),
+            ..Default::default()
+        }
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read certificates from {:?}", path))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates in {:?}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Takes the first PKCS#8, RSA or SEC1 key in the file
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("Failed to read key from {:?}", path))?;
+        match item {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(anyhow!("No private key in {:?}", path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use super::Config;
+
+    #[test]
+    fn room_anti_entropy() {
+        let config = Config::parse_from([
+            "server",
+            "--anti-entropy",
+            "10",
+            "--room-anti-entropy",
+            "quiet=0",
+            "--room-anti-entropy",
+            "a=b=5",
+        ]);
+        let anti_entropy = config.anti_entropy();
+        assert_eq!(
+            anti_entropy.interval(&"other".into()),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(anti_entropy.interval(&"quiet".into()), None);
+        assert_eq!(
+            anti_entropy.interval(&"a=b".into()),
+            Some(Duration::from_secs(5))
+        );
+        assert!(Config::try_parse_from(["server", "--room-anti-entropy", "nope"]).is_err());
+    }
+}
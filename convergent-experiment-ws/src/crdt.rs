@@ -0,0 +1,178 @@
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sypytkowski_convergent::delta_state::awormap::{AWORMap, Deltas};
+use sypytkowski_convergent::delta_state::digest::MapDigest;
+use sypytkowski_convergent::delta_state::dot::{Dot, VectorClock};
+use sypytkowski_convergent::Value;
+
+/// Bounds every type the server sends over the wire or shares between tasks needs
+pub trait Wire: Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static {}
+
+impl<T: Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static> Wire for T {}
+
+/// A delta state CRDT the server can keep rooms of. Clients send deltas as they change their
+/// replica and the server passes them on, full states are only exchanged when syncing.
+pub trait DeltaCrdt: Wire + Default {
+    type Delta: Wire + Default;
+    /// Identifies a part of the state, it's what clients lock and what rejections point at
+    type Key: Wire + Ord;
+    /// Summary of the state that's much smaller than it, so replicas can tell whether and where
+    /// they diverged without sending their state
+    type Digest: Wire + Default + PartialEq;
+
+    fn is_empty(&self) -> bool;
+
+    /// Entries in the state, for the metrics
+    fn len(&self) -> usize;
+
+    fn merge_in_place(&mut self, other: &Self);
+
+    fn merge_delta_in_place(&mut self, delta: &Self::Delta);
+
+    /// Combines two deltas into one that has the same effect as both, so queued deltas can be
+    /// sent as one
+    fn merge_deltas(delta: &mut Self::Delta, other: &Self::Delta);
+
+    fn digest(&self) -> Self::Digest;
+
+    /// What a replica whose digest is `remote` may be missing. Sending everything is correct,
+    /// just wasteful.
+    fn divergent(&self, remote: &Self::Digest) -> Self::Delta;
+
+    /// Dots of everything in the state, which tell which replica added it
+    fn dots(&self) -> Vec<Dot>;
+
+    /// Dots of the entries a delta adds. Its causal context is in [`DeltaCrdt::delta_version`],
+    /// and both need checking before merging a client's delta.
+    fn delta_dots(delta: &Self::Delta) -> Vec<Dot>;
+
+    /// Highest dot of each replica the state has seen, removed entries included. It's what
+    /// [`crate::causal`] compares sessions against.
+    fn version(&self) -> VectorClock;
+
+    /// Every dot a delta claims to have seen, its causal context included
+    fn delta_version(delta: &Self::Delta) -> VectorClock;
+
+    fn keys(&self) -> Vec<Self::Key>;
+
+    /// Keys of the state merging `delta` would change, removals included
+    fn affected_keys(&self, delta: &Self::Delta) -> Vec<Self::Key>;
+
+    /// The part of `delta` about `keys`, for clients that only subscribed to those. Removals
+    /// don't say what they removed, so they're all kept.
+    fn delta_for_keys(delta: &Self::Delta, keys: &[Self::Key]) -> Self::Delta;
+
+    /// The part of the state about `keys`, as a delta
+    fn extract(&self, keys: &[Self::Key]) -> Self::Delta;
+}
+
+impl<K, V> DeltaCrdt for AWORMap<K, V>
+where
+    K: Clone + PartialEq + Default + Debug + Ord + Value + Wire,
+    V: Value + Clone + PartialEq + Debug + Wire,
+{
+    type Delta = Deltas<K, V>;
+    type Key = K;
+    type Digest = MapDigest;
+
+    fn is_empty(&self) -> bool {
+        AWORMap::len(self) == 0
+    }
+
+    fn len(&self) -> usize {
+        AWORMap::len(self)
+    }
+
+    fn merge_in_place(&mut self, other: &Self) {
+        AWORMap::merge_in_place(self, other)
+    }
+
+    fn merge_delta_in_place(&mut self, delta: &Self::Delta) {
+        AWORMap::merge_delta_in_place(self, delta)
+    }
+
+    fn merge_deltas(delta: &mut Self::Delta, other: &Self::Delta) {
+        delta.merge_in_place(other)
+    }
+
+    fn digest(&self) -> MapDigest {
+        AWORMap::digest(self)
+    }
+
+    /// Only the keys in buckets whose digests differ
+    fn divergent(&self, remote: &MapDigest) -> Self::Delta {
+        if AWORMap::digest(self) == *remote {
+            return Deltas::default();
+        }
+        self.extract(&self.diff(remote))
+    }
+
+    fn dots(&self) -> Vec<Dot> {
+        self.iter()
+            .flat_map(|(key, _)| self.dots_for(key))
+            .collect()
+    }
+
+    fn delta_dots(delta: &Self::Delta) -> Vec<Dot> {
+        delta.iter().map(|(dot, _)| *dot).collect()
+    }
+
+    fn version(&self) -> VectorClock {
+        AWORMap::version(self)
+    }
+
+    fn delta_version(delta: &Self::Delta) -> VectorClock {
+        delta.version()
+    }
+
+    fn keys(&self) -> Vec<K> {
+        AWORMap::keys(self).cloned().collect()
+    }
+
+    fn affected_keys(&self, delta: &Self::Delta) -> Vec<K> {
+        AWORMap::affected_keys(self, delta).into_iter().collect()
+    }
+
+    fn delta_for_keys(delta: &Self::Delta, keys: &[K]) -> Self::Delta {
+        let keys: BTreeSet<&K> = keys.iter().collect();
+        delta.filtered(|_, kv| keys.contains(kv.key()))
+    }
+
+    fn extract(&self, keys: &[K]) -> Self::Delta {
+        AWORMap::extract(self, keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+    use sypytkowski_convergent::delta_state::dot::Dot;
+
+    use super::DeltaCrdt;
+
+    #[test]
+    fn awormap() {
+        let a = ReplicaId::from(1);
+        let mut server = AWORMap::default();
+        server.insert(a, SquareId(1), Square::default());
+        server.split_mut();
+        let mut client = server.clone();
+        server.insert(a, SquareId(2), Square::default());
+        let delta = server.split_mut().unwrap();
+        assert_eq!(
+            <AWORMap<SquareId, Square> as DeltaCrdt>::delta_dots(&delta),
+            [Dot(a, 2)]
+        );
+
+        assert_eq!(DeltaCrdt::len(&server), 2);
+        assert!(DeltaCrdt::divergent(&server, &DeltaCrdt::digest(&server)).is_empty());
+        let missing = DeltaCrdt::divergent(&server, &DeltaCrdt::digest(&client));
+        DeltaCrdt::merge_delta_in_place(&mut client, &missing);
+        assert_eq!(DeltaCrdt::digest(&client), DeltaCrdt::digest(&server));
+        assert_eq!(DeltaCrdt::dots(&client).len(), 2);
+    }
+}
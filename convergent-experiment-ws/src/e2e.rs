@@ -0,0 +1,288 @@
+//! End to end encrypted rooms, see [`RoomKey`].
+//!
+//! Clients of a room started with `--e2e-room` share a key the server never sees. They seal their
+//! deltas, or their whole state, with it and send them as [`ServerBound::Encrypted`]. The server
+//! can't merge what it can't read, so it keeps a log of what was sent instead and relays it to
+//! everyone else in the room, and to clients that join later. Presence, cursors and locks stay in
+//! plaintext.
+//!
+//! Payloads are sealed with XChaCha20-Poly1305 under a random 24 byte nonce that goes in front of
+//! the ciphertext. The room id is authenticated along with it, so the server can't replay one
+//! room's updates in another room that happens to use the same key.
+
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use convergent_experiment_protocol::ReplicaId;
+use serde::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::crdt::DeltaCrdt;
+use crate::proto::{ClientBoundEncrypted, RoomId, ServerBound, ServerBoundEncrypted};
+
+pub use chacha20poly1305::Key;
+
+const NONCE_LEN: usize = 24;
+
+/// What an encrypted payload holds once it's opened
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", bound = "")]
+#[serde(rename_all = "camelCase")]
+pub enum Plaintext<C: DeltaCrdt> {
+    Deltas {
+        deltas: C::Delta,
+    },
+    /// The sender's whole state, it replaces everything the sender relayed before
+    State {
+        state: C,
+    },
+}
+
+/// The key clients of an end to end encrypted room share. It has to get to them some other way
+/// than through the server, like the fragment of an invite link.
+pub struct RoomKey {
+    cipher: XChaCha20Poly1305,
+}
+
+impl RoomKey {
+    pub fn new(key: &Key) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key),
+        }
+    }
+
+    /// A random key for a new room
+    pub fn generate() -> Key {
+        XChaCha20Poly1305::generate_key(&mut OsRng)
+    }
+
+    /// Parses a key written by [`RoomKey::to_hex`]
+    pub fn from_hex(hex: &str) -> Result<Key> {
+        if hex.len() != 64 {
+            return Err(anyhow!("Expected 64 hex digits but got {}", hex.len()));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                let byte = hex.get(i..i + 2).unwrap_or_default();
+                u8::from_str_radix(byte, 16)
+                    .with_context(|| format!("Invalid hex digits {:?}", byte))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(*Key::from_slice(&bytes))
+    }
+
+    pub fn to_hex(key: &Key) -> String {
+        key.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Seals `deltas` into a message for the server to relay
+    pub fn seal_deltas<C: DeltaCrdt, S: Codec>(
+        &self,
+        room: &RoomId,
+        deltas: C::Delta,
+    ) -> Result<ServerBound<C>> {
+        self.seal::<C, S>(room, &Plaintext::Deltas { deltas })
+    }
+
+    /// Seals the whole `state`, which lets the server drop what was relayed from this client
+    /// before
+    pub fn seal_state<C: DeltaCrdt, S: Codec>(
+        &self,
+        room: &RoomId,
+        state: C,
+    ) -> Result<ServerBound<C>> {
+        self.seal::<C, S>(room, &Plaintext::State { state })
+    }
+
+    fn seal<C: DeltaCrdt, S: Codec>(
+        &self,
+        room: &RoomId,
+        plaintext: &Plaintext<C>,
+    ) -> Result<ServerBound<C>> {
+        let msg = S::encode(plaintext)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &msg,
+                    aad: room.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt payload"))?;
+        Ok(ServerBound::Encrypted(ServerBoundEncrypted {
+            payload: [nonce.as_slice(), &ciphertext].concat(),
+            snapshot: matches!(plaintext, Plaintext::State { .. }),
+        }))
+    }
+
+    /// Fails if the payload wasn't sealed with this key for `room`, or was tampered with on the
+    /// way
+    pub fn open<C: DeltaCrdt, S: Codec>(
+        &self,
+        room: &RoomId,
+        msg: &ClientBoundEncrypted,
+    ) -> Result<Plaintext<C>> {
+        if msg.payload.len() < NONCE_LEN {
+            return Err(anyhow!(
+                "Encrypted payload from {:?} is too short",
+                msg.from
+            ));
+        }
+        let (nonce, ciphertext) = msg.payload.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: room.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Encrypted payload from {:?} doesn't authenticate", msg.from))?;
+        S::decode(&plaintext)
+    }
+}
+
+/// What the server keeps of an end to end encrypted room in place of its state: everything
+/// clients sent, in order. A client's state covers everything it sent before, so sending it
+/// replaces the client's earlier entries.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Relay {
+    entries: Vec<ClientBoundEncrypted>,
+}
+
+impl Relay {
+    /// Returns the entry to pass on to the others in the room
+    pub fn push(&mut self, from: ReplicaId, msg: ServerBoundEncrypted) -> ClientBoundEncrypted {
+        if msg.snapshot {
+            self.entries.retain(|entry| entry.from != from);
+        }
+        let entry = ClientBoundEncrypted {
+            from,
+            payload: msg.payload,
+        };
+        self.entries.push(entry.clone());
+        entry
+    }
+
+    pub fn entries(&self) -> &[ClientBoundEncrypted] {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use super::{Key, Plaintext, Relay, RoomKey};
+    use crate::codec::MsgPack;
+    use crate::proto::{ClientBoundEncrypted, ServerBound, ServerBoundEncrypted};
+
+    type Map = AWORMap<SquareId, Square>;
+
+    fn sealed(msg: ServerBound<Map>) -> ServerBoundEncrypted {
+        match msg {
+            ServerBound::Encrypted(sealed) => sealed,
+            msg => panic!("Expected an encrypted message but got {:?}", msg),
+        }
+    }
+
+    fn relayed(sealed: &ServerBoundEncrypted) -> ClientBoundEncrypted {
+        ClientBoundEncrypted {
+            from: ReplicaId::from(1),
+            payload: sealed.payload.clone(),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let key = RoomKey::new(&RoomKey::generate());
+        let room = "room".to_string();
+        let mut map = Map::default();
+        map.insert(ReplicaId::from(1), SquareId(1), Square::default());
+        let deltas = map.split_mut().unwrap();
+
+        let msg = sealed(key.seal_deltas::<Map, MsgPack>(&room, deltas).unwrap());
+        assert!(!msg.snapshot);
+        let Plaintext::Deltas { deltas } = key.open::<Map, MsgPack>(&room, &relayed(&msg)).unwrap()
+        else {
+            panic!("Expected deltas");
+        };
+        let mut merged = Map::default();
+        merged.merge_delta_in_place(&deltas);
+        assert!(merged.contains_key(&SquareId(1)));
+
+        let msg = sealed(key.seal_state::<Map, MsgPack>(&room, map.clone()).unwrap());
+        assert!(msg.snapshot);
+        let Plaintext::State { state } = key.open::<Map, MsgPack>(&room, &relayed(&msg)).unwrap()
+        else {
+            panic!("Expected a state");
+        };
+        assert_eq!(state, map);
+    }
+
+    #[test]
+    fn only_opens_with_the_key_of_the_room() {
+        let key = RoomKey::generate();
+        let room = "room".to_string();
+        let msg = sealed(
+            RoomKey::new(&key)
+                .seal_state::<Map, MsgPack>(&room, Map::default())
+                .unwrap(),
+        );
+        let open = |key: &Key, room: &str, payload: Vec<u8>| {
+            let msg = ClientBoundEncrypted {
+                from: ReplicaId::from(1),
+                payload,
+            };
+            RoomKey::new(key)
+                .open::<Map, MsgPack>(&room.to_string(), &msg)
+                .is_ok()
+        };
+
+        assert!(open(&key, "room", msg.payload.clone()));
+        assert!(!open(&RoomKey::generate(), "room", msg.payload.clone()));
+        assert!(!open(&key, "other", msg.payload.clone()));
+        let mut tampered = msg.payload.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(!open(&key, "room", tampered));
+        assert!(!open(&key, "room", msg.payload[..10].to_vec()));
+    }
+
+    #[test]
+    fn hex() {
+        let key = RoomKey::generate();
+        let hex = RoomKey::to_hex(&key);
+        assert_eq!(RoomKey::from_hex(&hex).unwrap(), key);
+        assert!(RoomKey::from_hex(&hex[1..]).is_err());
+        assert!(RoomKey::from_hex(&"zz".repeat(32)).is_err());
+        assert!(RoomKey::from_hex(&"é".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn states_replace_what_their_sender_relayed() {
+        let (a, b) = (ReplicaId::from(1), ReplicaId::from(2));
+        let msg = |payload: u8, snapshot| ServerBoundEncrypted {
+            payload: vec![payload],
+            snapshot,
+        };
+        let mut relay = Relay::default();
+        relay.push(a, msg(1, false));
+        relay.push(b, msg(2, false));
+        relay.push(a, msg(3, false));
+        let entry = relay.push(a, msg(4, true));
+        assert_eq!(entry.from, a);
+        relay.push(a, msg(5, false));
+
+        let entries = relay
+            .entries()
+            .iter()
+            .map(|entry| (entry.from, entry.payload[0]))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![(b, 2), (a, 4), (a, 5)]);
+    }
+}
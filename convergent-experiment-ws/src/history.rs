@@ -0,0 +1,77 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::proto::HistoryEntry;
+
+/// Most updates kept per room, older ones are dropped as new ones come in
+const MAX_HISTORY: usize = 10_000;
+
+/// Milliseconds since the epoch
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Updates accepted in a room in the order they were merged, so clients can replay how the
+/// state came to be
+#[derive(Debug, Default)]
+pub struct History<D> {
+    entries: VecDeque<HistoryEntry<D>>,
+    /// Timestamp of the last entry dropped to stay under [`MAX_HISTORY`]
+    dropped_until: Option<u64>,
+}
+
+impl<D: Clone> History<D> {
+    /// Timestamps never go backwards even if the clock does, so the log stays sorted
+    pub fn push(&mut self, ts: u64, deltas: D) {
+        let ts = self.entries.back().map_or(ts, |last| last.ts.max(ts));
+        if self.entries.len() >= MAX_HISTORY {
+            self.dropped_until = self.entries.pop_front().map(|e| e.ts);
+        }
+        self.entries.push_back(HistoryEntry { ts, deltas });
+    }
+
+    /// Entries from `from_ts` up to and including `to_ts`, and whether entries in the range were
+    /// dropped already
+    pub fn range(&self, from_ts: u64, to_ts: u64) -> (Vec<HistoryEntry<D>>, bool) {
+        let start = self.entries.partition_point(|e| e.ts < from_ts);
+        let end = self.entries.partition_point(|e| e.ts <= to_ts);
+        let entries = self.entries.range(start..end.max(start)).cloned().collect();
+        let truncated = self.dropped_until.is_some_and(|ts| ts >= from_ts);
+        (entries, truncated)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{History, MAX_HISTORY};
+
+    #[test]
+    fn range_and_cap() {
+        let mut history = History::<()>::default();
+        history.push(10, ());
+        history.push(20, ());
+        // The clock went backwards
+        history.push(15, ());
+        history.push(30, ());
+
+        let ts = |(entries, _): (Vec<super::HistoryEntry<()>>, bool)| {
+            entries.iter().map(|e| e.ts).collect::<Vec<_>>()
+        };
+        assert_eq!(ts(history.range(0, u64::MAX)), [10, 20, 20, 30]);
+        assert_eq!(ts(history.range(11, 20)), [20, 20]);
+        assert!(ts(history.range(31, 40)).is_empty());
+        assert!(ts(history.range(20, 10)).is_empty());
+        assert!(!history.range(0, u64::MAX).1);
+
+        for _ in 0..MAX_HISTORY {
+            history.push(40, ());
+        }
+        let (entries, truncated) = history.range(0, u64::MAX);
+        assert_eq!(entries.len(), MAX_HISTORY);
+        assert!(truncated);
+        assert!(!history.range(40, 40).1);
+    }
+}
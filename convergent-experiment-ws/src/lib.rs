@@ -0,0 +1,37 @@
+//! Websocket server that keeps rooms of a delta state CRDT in sync between the clients in them,
+//! see [`CrdtServer`]. The squares binary serves the convergent experiment's frontend with it.
+
+mod auth;
+#[cfg(feature = "redis")]
+pub mod bridge;
+pub mod causal;
+pub mod cluster;
+pub mod codec;
+pub mod config;
+pub mod crdt;
+pub mod e2e;
+mod history;
+mod limit;
+pub mod locate;
+mod locks;
+mod metrics;
+pub mod ops;
+#[cfg(feature = "p2p")]
+pub mod p2p;
+mod outbox;
+mod persist;
+mod presence;
+pub mod proto;
+mod queue;
+pub mod server;
+mod session;
+mod subscriptions;
+pub mod validate;
+
+pub use codec::{Codec, MsgPack};
+pub use config::Config;
+pub use locate::Locate;
+pub use ops::OpServer;
+pub use crdt::DeltaCrdt;
+pub use server::CrdtServer;
+pub use validate::Validator;
@@ -0,0 +1,67 @@
+use std::time::Instant;
+
+/// How many messages a client may send
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    pub per_sec: f64,
+    /// Messages it may send at once after being quiet for a while
+    pub burst: f64,
+}
+
+/// Token bucket holding up to `burst` messages, refilled at `per_sec`
+#[derive(Debug)]
+pub struct RateLimit {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    pub fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst,
+            last: now,
+        }
+    }
+
+    /// Takes a token for a message, `false` if the client is over the limit
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_sec).min(self.rate.burst);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Rate, RateLimit};
+
+    #[test]
+    fn bursts_then_refills() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new(
+            Rate {
+                per_sec: 10.0,
+                burst: 3.0,
+            },
+            start,
+        );
+        assert!((0..3).all(|_| limit.allow(start)));
+        assert!(!limit.allow(start));
+
+        // One message worth of tokens every 100ms, and never more than the burst
+        assert!(limit.allow(start + Duration::from_millis(100)));
+        assert!(!limit.allow(start + Duration::from_millis(150)));
+        let later = start + Duration::from_secs(10);
+        assert!((0..3).all(|_| limit.allow(later)));
+        assert!(!limit.allow(later));
+    }
+}
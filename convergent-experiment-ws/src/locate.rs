@@ -0,0 +1,10 @@
+use crate::crdt::DeltaCrdt;
+use crate::proto::Region;
+
+/// Tells where the entries of the state are, for clients that subscribe to a
+/// [`Region`](crate::proto::Subscription::Region). Servers without one send those clients
+/// everything.
+pub trait Locate<C: DeltaCrdt>: Send + Sync {
+    /// Whether the entry at `key` lies within `region`, `false` if there is none
+    fn contains(&self, state: &C, key: &C::Key, region: &Region) -> bool;
+}
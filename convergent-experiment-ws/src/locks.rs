@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use convergent_experiment_protocol::ReplicaId;
+
+use crate::proto::Lock;
+
+/// Locks that aren't renewed by locking the key again are released after this long
+pub const LOCK_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct Held<K> {
+    lock: Lock<K>,
+    expires: Instant,
+}
+
+/// Advisory locks on the keys of a room's state. Nothing stops a client from changing a key
+/// someone else locked, the locks only tell the clients who's working on what.
+#[derive(Debug)]
+pub struct Locks<K> {
+    held: BTreeMap<K, Held<K>>,
+}
+
+impl<K> Default for Locks<K> {
+    fn default() -> Self {
+        Self {
+            held: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Clone + Ord> Locks<K> {
+    /// Conflicting requests are settled like in a LWW register: the later timestamp wins and ties
+    /// go to the higher replica id. Returns the lock if it was granted, the lock that's held
+    /// otherwise.
+    pub fn lock(
+        &mut self,
+        id: K,
+        owner: ReplicaId,
+        ts: u64,
+        now: Instant,
+    ) -> Result<Lock<K>, Lock<K>> {
+        if let Some(held) = self.held.get(&id) {
+            let renewing = held.lock.owner == owner;
+            if !renewing && (held.lock.ts, held.lock.owner) > (ts, owner) {
+                return Err(held.lock.clone());
+            }
+        }
+        let lock = Lock {
+            id: id.clone(),
+            owner,
+            ts,
+        };
+        self.held.insert(
+            id,
+            Held {
+                lock: lock.clone(),
+                expires: now + LOCK_TTL,
+            },
+        );
+        Ok(lock)
+    }
+
+    /// Only the owner can unlock a key
+    pub fn unlock(&mut self, id: &K, owner: ReplicaId) -> bool {
+        if !self
+            .held
+            .get(id)
+            .is_some_and(|held| held.lock.owner == owner)
+        {
+            return false;
+        }
+        self.held.remove(id);
+        true
+    }
+
+    /// Releases every lock of a client that left
+    pub fn release_all(&mut self, owner: ReplicaId) -> Vec<K> {
+        self.release(|held| held.lock.owner == owner)
+    }
+
+    pub fn expire(&mut self, now: Instant) -> Vec<K> {
+        self.release(|held| held.expires <= now)
+    }
+
+    fn release(&mut self, f: impl Fn(&Held<K>) -> bool) -> Vec<K> {
+        let released = self
+            .held
+            .iter()
+            .filter(|(_, held)| f(held))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in &released {
+            self.held.remove(id);
+        }
+        released
+    }
+
+    pub fn all(&self) -> Vec<Lock<K>> {
+        self.held.values().map(|held| held.lock.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use convergent_experiment_protocol::{ReplicaId, SquareId};
+
+    use super::{Locks, LOCK_TTL};
+
+    #[test]
+    fn lww_and_release() {
+        let a = ReplicaId::from(1);
+        let b = ReplicaId::from(2);
+        let square = SquareId(1);
+        let start = Instant::now();
+
+        let mut locks = Locks::default();
+        assert!(locks.lock(square.clone(), a, 10, start).is_ok());
+        // Earlier requests lose, later ones take over
+        assert_eq!(
+            locks.lock(square.clone(), b, 5, start).unwrap_err().owner,
+            a
+        );
+        assert!(locks.lock(square.clone(), b, 20, start).is_ok());
+        // Ties go to the higher replica id
+        assert!(locks.lock(square.clone(), a, 20, start).is_err());
+        assert!(!locks.unlock(&square, a));
+
+        // The owner renews its lock whatever its timestamp
+        let later = start + LOCK_TTL / 2;
+        assert!(locks.lock(square.clone(), b, 1, later).is_ok());
+        assert!(locks.expire(start + LOCK_TTL).is_empty());
+        assert_eq!(locks.expire(later + LOCK_TTL), [square]);
+
+        locks.lock(SquareId(2), a, 1, start).unwrap();
+        locks.lock(SquareId(3), b, 1, start).unwrap();
+        assert_eq!(locks.release_all(a), [SquareId(2)]);
+        assert!(locks.unlock(&SquareId(3), b));
+        assert!(locks.all().is_empty());
+    }
+}
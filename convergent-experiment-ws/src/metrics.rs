@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::crdt::DeltaCrdt;
+use crate::proto::{ClientBound, ServerBound};
+
+/// How often the update rate is measured
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counters updated as the server handles messages. The gauges that can be read off the rooms
+/// directly are passed in as [`Gauges`] when rendering instead.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Update messages received since the server started
+    updates: AtomicU64,
+    /// `updates` at the last sample
+    sampled_updates: AtomicU64,
+    updates_per_sec: AtomicU64,
+    /// Messages handed to the send tasks of the clients that haven't been written yet
+    queued: AtomicI64,
+    received: Mutex<BTreeMap<&'static str, u64>>,
+    sent: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Gauges {
+    pub clients: usize,
+    pub rooms: usize,
+    /// Entries in the state of every open room
+    pub entries: usize,
+}
+
+impl Metrics {
+    pub fn received<C: DeltaCrdt>(&self, msg: &ServerBound<C>) {
+        if let ServerBound::Update(_) = msg {
+            self.updates.fetch_add(1, Ordering::Relaxed);
+        }
+        *self.received.lock().unwrap().entry(msg.kind()).or_default() += 1;
+    }
+
+    pub fn sent<C: DeltaCrdt>(&self, msg: &ClientBound<C>) {
+        *self.sent.lock().unwrap().entry(msg.kind()).or_default() += 1;
+    }
+
+    pub fn queued(&self, count: i64) {
+        self.queued.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Called every [`SAMPLE_INTERVAL`]
+    pub fn sample(&self) {
+        let updates = self.updates.load(Ordering::Relaxed);
+        let last = self.sampled_updates.swap(updates, Ordering::Relaxed);
+        let rate = (updates - last) as f64 / SAMPLE_INTERVAL.as_secs_f64();
+        self.updates_per_sec
+            .store(rate.round() as u64, Ordering::Relaxed);
+    }
+
+    /// In the Prometheus text format
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let mut line = |name: &str, value: &dyn std::fmt::Display| {
+            writeln!(out, "convergent_{} {}", name, value).unwrap();
+        };
+        line("clients", &gauges.clients);
+        line("rooms", &gauges.rooms);
+        line("entries", &gauges.entries);
+        line("updates_total", &self.updates.load(Ordering::Relaxed));
+        line(
+            "updates_per_second",
+            &self.updates_per_sec.load(Ordering::Relaxed),
+        );
+        line("queued_messages", &self.queued.load(Ordering::Relaxed));
+        for (kind, count) in self.received.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "convergent_received_total{{type=\"{}\"}} {}",
+                kind, count
+            )
+            .unwrap();
+        }
+        for (kind, count) in self.sent.lock().unwrap().iter() {
+            writeln!(out, "convergent_sent_total{{type=\"{}\"}} {}", kind, count).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::{Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use super::{Gauges, Metrics};
+    use crate::proto::{
+        ClientBound, ClientBoundSync, ServerBound, ServerBoundAck, ServerBoundUpdate,
+    };
+
+    type Map = AWORMap<SquareId, Square>;
+
+    #[test]
+    fn counts() {
+        let metrics = Metrics::default();
+        for _ in 0..3 {
+            metrics.received(&ServerBound::<Map>::Update(ServerBoundUpdate {
+                deltas: Default::default(),
+            }));
+        }
+        metrics.received(&ServerBound::<Map>::Ack(ServerBoundAck { seq: 1 }));
+        metrics.sent(&ClientBound::<Map>::Sync(ClientBoundSync::default()));
+        metrics.queued(2);
+        metrics.queued(-1);
+        metrics.sample();
+
+        let out = metrics.render(&Gauges {
+            clients: 2,
+            rooms: 1,
+            entries: 5,
+        });
+        for expected in [
+            "convergent_clients 2",
+            "convergent_entries 5",
+            "convergent_updates_total 3",
+            "convergent_updates_per_second 3",
+            "convergent_queued_messages 1",
+            "convergent_received_total{type=\"update\"} 3",
+            "convergent_received_total{type=\"ack\"} 1",
+            "convergent_sent_total{type=\"sync\"} 1",
+        ] {
+            assert!(
+                out.lines().any(|l| l == expected),
+                "{} in\n{}",
+                expected,
+                out
+            );
+        }
+
+        // Nothing came in since the last sample
+        metrics.sample();
+        assert!(metrics
+            .render(&Gauges::default())
+            .contains("convergent_updates_per_second 0"));
+    }
+}
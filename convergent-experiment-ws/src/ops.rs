@@ -0,0 +1,493 @@
+//! Websocket server for the op-based CRDTs of `sypytkowski_commutative`, see [`OpServer`].
+//!
+//! Clients are replicas of their own and speak the replicator's [`Protocol`], one message per
+//! binary websocket message. Every room is a [`Replicator`] of the server running in a task of its
+//! own, what a client sends is handed to it and its answer goes back to the client unless it's a
+//! `Noop`. So clients pull the room's events with `Replicate` and get theirs pulled by sending
+//! `Connect`. Whenever the room picks up new events it sends every client in it a `Connect` of its
+//! own, which clients answer by pulling.
+//!
+//! The first message of a client has to be a `Connect` with its replica id, which is what its
+//! token is checked against. Clients pick their room with the `room` query parameter of the URL.
+//! Rooms only live in memory.
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Weak};
+use std::time::Instant;
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use sypytkowski_commutative::{
+    handle::ReplicatorWriter,
+    memdb::InMemoryDb,
+    protocol::{Connect, Protocol},
+    Crdt, ReplicaId, Replicator,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::WebSocketStream;
+use tracing::Instrument;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tungstenite::Message;
+
+use crate::auth::Auth;
+use crate::codec::{Codec, MsgPack};
+use crate::config::Config;
+use crate::crdt::Wire;
+use crate::limit::{Rate, RateLimit};
+use crate::proto::RoomId;
+use crate::server::{accept, decode, query_param, Stream};
+use convergent_experiment_protocol::ReplicaId as AuthId;
+
+type Msg<C> = Protocol<<C as Crdt>::Cmd, <C as Crdt>::EData>;
+
+/// Websocket server that keeps rooms of the op-based CRDT `C` in sync between their clients,
+/// speaking `S` on the wire
+pub struct OpServer<C: Crdt, S: Codec = MsgPack> {
+    addr: String,
+    auth: Auth,
+    /// Connections are wrapped in TLS first if set
+    tls: Option<TlsAcceptor>,
+    websocket: WebSocketConfig,
+    max_clients: Option<usize>,
+    rate: Rate,
+    /// Makes the CRDT of a new room for the server's replica in it
+    new_crdt: Box<dyn Fn(ReplicaId) -> C + Send + Sync>,
+    rooms: Mutex<HashMap<RoomId, Arc<Room<C>>>>,
+    codec: PhantomData<S>,
+}
+
+impl<C, S> OpServer<C, S>
+where
+    C: Crdt + 'static,
+    C::Cmd: Wire,
+    C::EData: Wire,
+    S: Codec,
+{
+    /// Loads the TLS certificate if the config asks for it, the data dir isn't used
+    pub fn new(
+        config: &Config,
+        new_crdt: impl Fn(ReplicaId) -> C + Send + Sync + 'static,
+    ) -> Result<Self> {
+        Ok(Self {
+            addr: config.addr.clone(),
+            auth: config.auth(),
+            tls: config.tls()?,
+            websocket: config.websocket(),
+            max_clients: config.max_clients,
+            rate: config.rate(),
+            new_crdt: Box::new(new_crdt),
+            rooms: Mutex::new(HashMap::new()),
+            codec: PhantomData,
+        })
+    }
+
+    /// Serves clients until the listener fails or the process gets Ctrl-C
+    pub async fn run(self) -> Result<()> {
+        let listener = TcpListener::bind(&self.addr)
+            .await
+            .with_context(|| format!("Failed to bind {}", self.addr))?;
+        tracing::info!(addr = %self.addr, tls = self.tls.is_some(), "listening");
+        Arc::new(self).serve(listener).await;
+        tracing::info!("shutting down");
+        Ok(())
+    }
+
+    async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => break,
+                },
+                _ = tokio::signal::ctrl_c() => break,
+            };
+
+            if let Some(max) = self.max_clients {
+                if self.client_count().await >= max {
+                    tracing::warn!(%addr, max, "turning away client");
+                    continue;
+                }
+            }
+
+            let server = self.clone();
+            let handled = async move {
+                if let Err(e) = server.handle(stream).await {
+                    tracing::warn!(error = ?e, "error handling client");
+                }
+            };
+            let span = tracing::info_span!(
+                "client",
+                %addr,
+                replica = tracing::field::Empty,
+                room = tracing::field::Empty
+            );
+            tokio::spawn(handled.instrument(span));
+        }
+    }
+
+    async fn client_count(&self) -> usize {
+        let mut count = 0;
+        for room in self.rooms.lock().await.values() {
+            count += room.clients.lock().await.len();
+        }
+        count
+    }
+
+    /// Gets the room, opening it with a fresh replica of the server if nobody was in it yet
+    async fn join(&self, id: &RoomId) -> Arc<Room<C>> {
+        let mut rooms = self.rooms.lock().await;
+        if let Some(room) = rooms.get(id) {
+            return room.clone();
+        }
+
+        let replica = ReplicaId::random();
+        tracing::info!(room = ?id, ?replica, "opening room");
+        let replicator = Replicator::new(replica, (self.new_crdt)(replica), InMemoryDb::default())
+            .await
+            .expect("in memory store never fails");
+        let (mut handle, writer) = replicator.spawn();
+        let room = Arc::new(Room {
+            replica,
+            writer,
+            clients: Mutex::new(HashMap::new()),
+        });
+
+        let weak = Arc::downgrade(&room);
+        tokio::spawn(async move {
+            while handle.changed().await {
+                let Some(room) = Weak::upgrade(&weak) else {
+                    break;
+                };
+                room.announce().await;
+            }
+        });
+
+        rooms.insert(id.clone(), room.clone());
+        room
+    }
+
+    async fn handle(&self, stream: TcpStream) -> Result<()> {
+        let (ws_stream, uri) = accept(stream, self.tls.as_ref(), self.websocket).await?;
+        let (w, mut r) = ws_stream.split();
+
+        let msg = r
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("Client did not send a message after connecting"))?
+            .with_context(|| "Error reading init message from client")?;
+        let Protocol::Connect(Connect { replica_id: id }) = decode::<S, Msg<C>>(msg)? else {
+            return Err(anyhow!(
+                "Client did not send a connect message after connecting"
+            ));
+        };
+        let token = query_param(&uri, "token");
+        self.auth
+            .authorize(token.as_deref(), AuthId::new(id.as_u128()))
+            .with_context(|| format!("Failed to authenticate client ({:?})", id))?;
+
+        let room_id = query_param(&uri, "room").unwrap_or_default();
+        let span = tracing::Span::current();
+        span.record("replica", tracing::field::debug(id));
+        span.record("room", tracing::field::debug(&room_id));
+        let room = self.join(&room_id).await;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(send_loop::<C, S>(id, w, rx));
+        let client = Client { id, tx };
+        room.clients.lock().await.insert(id, client.tx.clone());
+
+        let res = self.listen(&client, &room, &mut r).await;
+        room.leave(&client).await;
+        res
+    }
+
+    /// Pulls from the client and has it pull from the room right away, then answers whatever it
+    /// sends
+    async fn listen(
+        &self,
+        client: &Client<C>,
+        room: &Room<C>,
+        r: &mut futures_util::stream::SplitStream<WebSocketStream<Stream>>,
+    ) -> Result<()> {
+        let id = client.id;
+        room.forward(client, Protocol::Connect(Connect { replica_id: id }))
+            .await?;
+        client.send(Protocol::Connect(Connect {
+            replica_id: room.replica,
+        }));
+
+        let mut limit = RateLimit::new(self.rate, Instant::now());
+        while let Some(msg) = r.next().await {
+            let msg = match msg {
+                // Past the max message size of the websocket config
+                Err(tungstenite::Error::Capacity(e)) => {
+                    client.close(CloseCode::Size, "Message too large");
+                    return Err(e).with_context(|| "Client sent a message that's too large");
+                }
+                msg => msg?,
+            };
+            if !limit.allow(Instant::now()) {
+                client.close(CloseCode::Policy, "Too many messages");
+                return Err(anyhow!("Client sent too many messages"));
+            }
+            if msg.is_close() {
+                break;
+            }
+
+            let msg = decode::<S, Msg<C>>(msg)?;
+            // Clients may only speak for themselves
+            let speaks_for = match &msg {
+                Protocol::Command(_) => {
+                    client.close(CloseCode::Policy, "Commands are applied by clients");
+                    return Err(anyhow!("Client sent a command"));
+                }
+                Protocol::Connect(connect) => connect.replica_id,
+                Protocol::Replicate(replicate) => replicate.reply_to,
+                Protocol::Replicated(replicated) => replicated.from,
+                Protocol::Noop => continue,
+            };
+            if speaks_for != id {
+                client.close(CloseCode::Policy, "Wrong replica");
+                return Err(anyhow!("Client sent a message for {:?}", speaks_for));
+            }
+
+            room.forward(client, msg).await?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Room<C: Crdt> {
+    /// The server's replica in the room
+    replica: ReplicaId,
+    writer: ReplicatorWriter<C>,
+    clients: Mutex<HashMap<ReplicaId, mpsc::UnboundedSender<Outbound<C>>>>,
+}
+
+impl<C: Crdt> Room<C> {
+    /// Hands `msg` to the room's replicator and sends its answer to `client`
+    async fn forward(&self, client: &Client<C>, msg: Msg<C>) -> Result<()> {
+        let reply = self
+            .writer
+            .send(msg)
+            .await
+            .ok_or_else(|| anyhow!("The room's replicator stopped"))?;
+        if !matches!(reply, Protocol::Noop) {
+            client.send(reply);
+        }
+        Ok(())
+    }
+
+    /// Asks every client to pull from the room
+    async fn announce(&self) {
+        for tx in self.clients.lock().await.values() {
+            let _ = tx.send(Outbound::Msg(Protocol::Connect(Connect {
+                replica_id: self.replica,
+            })));
+        }
+    }
+
+    /// Only forgets the client if it didn't reconnect in the meantime
+    async fn leave(&self, client: &Client<C>) {
+        let mut clients = self.clients.lock().await;
+        if clients
+            .get(&client.id)
+            .is_some_and(|tx| tx.same_channel(&client.tx))
+        {
+            clients.remove(&client.id);
+        }
+    }
+}
+
+struct Client<C: Crdt> {
+    id: ReplicaId,
+    /// Feeds the client's send task, see [`send_loop`]
+    tx: mpsc::UnboundedSender<Outbound<C>>,
+}
+
+/// What the send task of a client is asked to do
+enum Outbound<C: Crdt> {
+    Msg(Msg<C>),
+    /// Closes the connection
+    Close(CloseFrame<'static>),
+}
+
+impl<C: Crdt> Client<C> {
+    fn send(&self, msg: Msg<C>) {
+        let _ = self.tx.send(Outbound::Msg(msg));
+    }
+
+    fn close(&self, code: CloseCode, reason: &'static str) {
+        let _ = self.tx.send(Outbound::Close(CloseFrame {
+            code,
+            reason: reason.into(),
+        }));
+    }
+}
+
+/// Owns the sending half of the socket until the client is closed or every handle to it is
+/// dropped
+async fn send_loop<C, S>(
+    id: ReplicaId,
+    mut write: SplitSink<WebSocketStream<Stream>, Message>,
+    mut rx: mpsc::UnboundedReceiver<Outbound<C>>,
+) where
+    C: Crdt,
+    C::Cmd: Wire,
+    C::EData: Wire,
+    S: Codec,
+{
+    while let Some(outbound) = rx.recv().await {
+        let msg = match outbound {
+            Outbound::Msg(msg) => msg,
+            Outbound::Close(frame) => {
+                if let Err(e) = write.send(Message::Close(Some(frame))).await {
+                    tracing::warn!(replica = ?id, error = %e, "error closing connection");
+                }
+                return;
+            }
+        };
+        let buf = match S::encode(&msg) {
+            Ok(buf) => buf,
+            Err(e) => {
+                tracing::warn!(replica = ?id, error = ?e, "error encoding message");
+                continue;
+            }
+        };
+        if let Err(e) = write.send(Message::Binary(buf)).await {
+            tracing::warn!(replica = ?id, error = %e, "error sending message");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use clap::Parser;
+    use futures_util::{SinkExt, StreamExt};
+    use sypytkowski_commutative::{
+        handle::ReplicatorHandle,
+        memdb::InMemoryDb,
+        protocol::{Connect, Protocol},
+        rga::{Command, Rga},
+        ReplicaId, Replicator,
+    };
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    use super::OpServer;
+    use crate::codec::{Codec, MsgPack};
+    use crate::config::Config;
+
+    type Text = Rga<char>;
+    type Msg = Protocol<Command<char>, <Text as sypytkowski_commutative::Crdt>::EData>;
+
+    /// A replica that applies the commands it gets locally and replicates through the server
+    async fn client(
+        addr: &str,
+        id: ReplicaId,
+    ) -> (mpsc::UnboundedSender<Command<char>>, ReplicatorHandle<Text>) {
+        let replicator = Replicator::new(id, Rga::new(id), InMemoryDb::default())
+            .await
+            .unwrap();
+        let (handle, writer) = replicator.spawn();
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?room=doc", addr))
+            .await
+            .unwrap();
+        let (mut w, mut r) = ws.split();
+        let (tx, mut commands) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let connect = || Msg::Connect(Connect { replica_id: id });
+            w.send(Message::Binary(MsgPack::encode(&connect()).unwrap()))
+                .await
+                .unwrap();
+            loop {
+                tokio::select! {
+                    Some(cmd) = commands.recv() => {
+                        writer.send(Protocol::Command(cmd)).await;
+                        w.send(Message::Binary(MsgPack::encode(&connect()).unwrap())).await.unwrap();
+                    }
+                    Some(Ok(msg)) = r.next() => {
+                        let msg: Msg = MsgPack::decode(&msg.into_data()).unwrap();
+                        let reply = writer.send(msg).await.unwrap();
+                        if !matches!(reply, Protocol::Noop) {
+                            w.send(Message::Binary(MsgPack::encode(&reply).unwrap())).await.unwrap();
+                        }
+                    }
+                    else => break,
+                }
+            }
+        });
+        (tx, handle)
+    }
+
+    async fn until(handle: &mut ReplicatorHandle<Text>, expected: &str) {
+        let expected: Vec<char> = expected.chars().collect();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handle.query() != expected {
+                assert!(handle.changed().await);
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("expected {:?} but got {:?}", expected, handle.query()));
+    }
+
+    #[tokio::test]
+    async fn replicates_between_clients() {
+        let config = Config::parse_from(["server"]);
+        let server = Arc::new(OpServer::<Text>::new(&config, Rga::new).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(server.serve(listener));
+
+        let (alice, mut alice_text) = client(&addr, ReplicaId::new(1)).await;
+        let (bob, mut bob_text) = client(&addr, ReplicaId::new(2)).await;
+
+        alice.send(Command::Insert(0, 'h')).unwrap();
+        alice.send(Command::Insert(1, 'i')).unwrap();
+        until(&mut bob_text, "hi").await;
+
+        bob.send(Command::RemoveAt(1)).unwrap();
+        bob.send(Command::Insert(1, 'o')).unwrap();
+        until(&mut alice_text, "ho").await;
+
+        // Late joiners catch up on everything
+        let (_carol, mut carol_text) = client(&addr, ReplicaId::new(3)).await;
+        until(&mut carol_text, "ho").await;
+    }
+
+    #[tokio::test]
+    async fn clients_only_speak_for_themselves() {
+        let config = Config::parse_from(["server"]);
+        let server = Arc::new(OpServer::<Text>::new(&config, Rga::new).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(server.serve(listener));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        for replica_id in [1, 2] {
+            let connect = Msg::Connect(Connect {
+                replica_id: ReplicaId::new(replica_id),
+            });
+            ws.send(Message::Binary(MsgPack::encode(&connect).unwrap()))
+                .await
+                .unwrap();
+        }
+
+        // The server pulls from replica 1, then hangs up on it for claiming to be 2
+        let mut closed = false;
+        while let Some(Ok(msg)) = ws.next().await {
+            closed |= msg.is_close();
+        }
+        assert!(closed);
+    }
+}
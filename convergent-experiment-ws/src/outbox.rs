@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+/// Most updates kept around for a client that doesn't ack them. A client that falls this far
+/// behind can't resume its session and syncs instead.
+const MAX_UNACKED: usize = 1024;
+
+/// Updates for one client that it hasn't acked yet, so they can be sent again when it reconnects
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Outbox<D> {
+    last_seq: u64,
+    /// Updates up to this one were dropped without being acked
+    dropped: u64,
+    unacked: VecDeque<(u64, D)>,
+}
+
+impl<D> Outbox<D> {
+    /// Numbers the update and keeps it until it is acked
+    pub fn push(&mut self, deltas: D) -> u64 {
+        self.last_seq += 1;
+        if self.unacked.len() >= MAX_UNACKED {
+            self.dropped = self.last_seq - 1;
+            self.unacked.clear();
+        }
+        self.unacked.push_back((self.last_seq, deltas));
+        self.last_seq
+    }
+
+    /// Acks are cumulative, acking `seq` acks everything before it as well
+    pub fn ack(&mut self, seq: u64) {
+        while self.unacked.front().is_some_and(|(s, _)| *s <= seq) {
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Whether a client that acked up to `seq` can catch up from the outbox alone
+    pub fn covers(&self, seq: u64) -> bool {
+        self.dropped <= seq && seq <= self.last_seq
+    }
+
+    pub fn unacked(&self) -> impl Iterator<Item = &(u64, D)> + '_ {
+        self.unacked.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Outbox, MAX_UNACKED};
+
+    #[test]
+    fn ack_and_overflow() {
+        let mut outbox = Outbox::default();
+        for _ in 0..3 {
+            outbox.push(());
+        }
+        outbox.ack(2);
+        assert!(outbox.covers(0));
+        assert!(!outbox.covers(4));
+        assert_eq!(outbox.unacked().map(|(s, _)| *s).collect::<Vec<_>>(), [3]);
+        // Stale acks don't do anything
+        outbox.ack(1);
+        assert_eq!(outbox.unacked().count(), 1);
+
+        for _ in 0..MAX_UNACKED {
+            outbox.push(());
+        }
+        // The buffer filled up and was dropped, sequence numbers keep counting though
+        assert_eq!(outbox.unacked().count(), 1);
+        assert!(!outbox.covers(2));
+        assert!(outbox.covers(MAX_UNACKED as u64 + 2));
+        assert_eq!(outbox.push(()), MAX_UNACKED as u64 + 4);
+        assert_eq!(outbox.unacked().count(), 2);
+    }
+}
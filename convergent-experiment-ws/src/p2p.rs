@@ -0,0 +1,565 @@
+//! Replicas that sync directly with each other over libp2p instead of through a server, see
+//! [`P2pNode`].
+//!
+//! Nodes share a gossipsub topic that every change of a local replica is published to, and when a
+//! peer joins the topic they run anti-entropy with it over request/response so whatever either
+//! missed while they weren't connected is caught up. Peers are dialed from the config and, if it's
+//! turned on, found on the local network with mDNS.
+//!
+//! What's gossiped and requested depends on the kind of CRDT:
+//!
+//! - [`DeltaReplica`] publishes the deltas of a [`DeltaCrdt`] and syncs by sending its digest and
+//!   getting back what it's missing
+//! - [`OpReplica`] publishes its replica id whenever a [`Replicator`] picks up new events and peers
+//!   pull them with the replicator's own [`Protocol`]. The events themselves aren't gossiped since
+//!   pulling is what keeps track of how far a replica got in a peer's log.
+//!
+//! Gossip is encoded with the node's [`Codec`], requests and responses with CBOR. Peers are
+//! trusted, anyone on the topic can change the replicas.
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use libp2p::identity::Keypair;
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::swarm::{behaviour::toggle::Toggle, dial_opts::DialOpts, NetworkBehaviour, SwarmEvent};
+use libp2p::{gossipsub, mdns, noise, tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sypytkowski_commutative::{
+    handle::{ReplicatorHandle, ReplicatorWriter},
+    protocol::{Connect, Protocol},
+    Crdt, ReplicaId, Replicator, Store,
+};
+use tokio::sync::{mpsc, watch};
+
+use crate::codec::{Codec, MsgPack};
+use crate::crdt::{DeltaCrdt, Wire};
+
+/// Protocol anti-entropy requests are sent with
+const SYNC_PROTOCOL: StreamProtocol = StreamProtocol::new("/sypytkowski/sync/1");
+
+/// How long connections without any streams are kept open
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Something a [`P2pNode`] keeps in sync with its peers
+pub trait Replica: Send + 'static {
+    /// Published to the topic on every local change
+    type Gossip: Serialize + DeserializeOwned + Send;
+    type Request: Serialize + DeserializeOwned + Send + 'static;
+    type Response: Serialize + DeserializeOwned + Send + 'static;
+
+    /// Gossip about local changes, the node stops when it ends
+    fn changes(&self) -> BoxStream<'static, Self::Gossip>;
+
+    /// Starts anti-entropy with a peer that joined the topic
+    fn hello(&self) -> Self::Request;
+
+    /// Takes in gossip from a peer, returns a request to send the peer that published it if
+    /// there's more to get from it
+    fn gossiped(
+        &mut self,
+        gossip: Self::Gossip,
+    ) -> impl Future<Output = Option<Self::Request>> + Send;
+
+    fn respond(&mut self, request: Self::Request) -> impl Future<Output = Self::Response> + Send;
+
+    /// Takes in a peer's response, returns the next request to send it if they aren't done
+    fn responded(
+        &mut self,
+        response: Self::Response,
+    ) -> impl Future<Output = Option<Self::Request>> + Send;
+}
+
+pub struct P2pConfig {
+    /// Addresses to listen on
+    pub listen: Vec<Multiaddr>,
+    /// Peers to dial when the node starts
+    pub peers: Vec<Multiaddr>,
+    /// Replicas of the same document have to share a topic
+    pub topic: String,
+    /// Dial peers found on the local network
+    pub mdns: bool,
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec!["/ip4/0.0.0.0/tcp/0".parse().unwrap()],
+            peers: vec![],
+            topic: "sypytkowski".into(),
+            mdns: true,
+        }
+    }
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour<Req, Resp>
+where
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Resp: Serialize + DeserializeOwned + Send + 'static,
+{
+    gossipsub: gossipsub::Behaviour,
+    sync: request_response::cbor::Behaviour<Req, Resp>,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+}
+
+/// A libp2p node that keeps `R` in sync with the replicas of the peers it finds, publishing
+/// gossip encoded with `S`
+pub struct P2pNode<R: Replica, S: Codec = MsgPack> {
+    swarm: Swarm<Behaviour<R::Request, R::Response>>,
+    topic: gossipsub::IdentTopic,
+    peers: Vec<Multiaddr>,
+    replica: R,
+    codec: PhantomData<S>,
+}
+
+impl<R: Replica, S: Codec> P2pNode<R, S> {
+    /// Starts listening but doesn't dial anyone until it runs
+    pub fn new(keypair: Keypair, config: P2pConfig, replica: R) -> Result<Self> {
+        let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )?
+            .with_behaviour(|key| {
+                let gossipsub = gossipsub::ConfigBuilder::default()
+                    .validation_mode(gossipsub::ValidationMode::Strict)
+                    .build()?;
+                let gossipsub = gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(key.clone()),
+                    gossipsub,
+                )?;
+                let sync = request_response::cbor::Behaviour::new(
+                    [(SYNC_PROTOCOL, ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                );
+                let mdns = match config.mdns {
+                    true => Some(mdns::tokio::Behaviour::new(
+                        mdns::Config::default(),
+                        key.public().to_peer_id(),
+                    )?),
+                    false => None,
+                };
+                Ok(Behaviour {
+                    gossipsub,
+                    sync,
+                    mdns: mdns.into(),
+                })
+            })
+            .map_err(|e| anyhow!("Failed to set up the node: {e}"))?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(IDLE_TIMEOUT))
+            .build();
+
+        let topic = gossipsub::IdentTopic::new(config.topic);
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&topic)
+            .with_context(|| format!("Failed to subscribe to {topic}"))?;
+        for addr in config.listen {
+            swarm
+                .listen_on(addr.clone())
+                .with_context(|| format!("Failed to listen on {addr}"))?;
+        }
+
+        Ok(Self {
+            swarm,
+            topic,
+            peers: config.peers,
+            replica,
+            codec: PhantomData,
+        })
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    /// Waits for the node to start listening and returns the address it's listening on
+    pub async fn listen_addr(&mut self) -> Result<Multiaddr> {
+        loop {
+            match self.swarm.select_next_some().await {
+                SwarmEvent::NewListenAddr { address, .. } => return Ok(address),
+                SwarmEvent::ListenerError { error, .. } => {
+                    return Err(error).context("Failed to listen")
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Syncs the replica until its changes end
+    pub async fn run(mut self) -> Result<()> {
+        for addr in std::mem::take(&mut self.peers) {
+            self.swarm
+                .dial(addr.clone())
+                .with_context(|| format!("Failed to dial {addr}"))?;
+        }
+
+        let mut changes = self.replica.changes();
+        loop {
+            tokio::select! {
+                gossip = changes.next() => match gossip {
+                    Some(gossip) => self.publish(&gossip),
+                    None => return Ok(()),
+                },
+                event = self.swarm.select_next_some() => self.handle(event).await,
+            }
+        }
+    }
+
+    fn publish(&mut self, gossip: &R::Gossip) {
+        let bytes = match S::encode(gossip) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::warn!(error = ?e, "failed to encode gossip");
+                return;
+            }
+        };
+        let gossipsub = &mut self.swarm.behaviour_mut().gossipsub;
+        match gossipsub.publish(self.topic.clone(), bytes) {
+            // Peers get it when they join the topic
+            Ok(_) | Err(gossipsub::PublishError::InsufficientPeers) => {}
+            Err(e) => tracing::warn!(error = %e, "failed to publish"),
+        }
+    }
+
+    async fn handle(&mut self, event: SwarmEvent<BehaviourEvent<R::Request, R::Response>>) {
+        let SwarmEvent::Behaviour(event) = event else {
+            return;
+        };
+        match event {
+            BehaviourEvent::Mdns(mdns::Event::Discovered(found)) => {
+                for (peer, addr) in found {
+                    if !self.swarm.is_connected(&peer) {
+                        let opts = DialOpts::peer_id(peer).addresses(vec![addr]).build();
+                        let _ = self.swarm.dial(opts);
+                    }
+                }
+            }
+            BehaviourEvent::Mdns(mdns::Event::Expired(_)) => {}
+            BehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })
+                if topic == self.topic.hash() =>
+            {
+                let hello = self.replica.hello();
+                self.swarm
+                    .behaviour_mut()
+                    .sync
+                    .send_request(&peer_id, hello);
+            }
+            BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message,
+                ..
+            }) => {
+                let gossip = match S::decode(&message.data) {
+                    Ok(gossip) => gossip,
+                    Err(e) => {
+                        tracing::warn!(%propagation_source, error = ?e, "invalid gossip");
+                        return;
+                    }
+                };
+                if let Some(request) = self.replica.gossiped(gossip).await {
+                    let peer = message.source.unwrap_or(propagation_source);
+                    self.swarm.behaviour_mut().sync.send_request(&peer, request);
+                }
+            }
+            BehaviourEvent::Gossipsub(_) => {}
+            BehaviourEvent::Sync(request_response::Event::Message { peer, message }) => {
+                match message {
+                    request_response::Message::Request {
+                        request, channel, ..
+                    } => {
+                        let response = self.replica.respond(request).await;
+                        // The peer went away
+                        let _ = self
+                            .swarm
+                            .behaviour_mut()
+                            .sync
+                            .send_response(channel, response);
+                    }
+                    request_response::Message::Response { response, .. } => {
+                        if let Some(request) = self.replica.responded(response).await {
+                            self.swarm.behaviour_mut().sync.send_request(&peer, request);
+                        }
+                    }
+                }
+            }
+            BehaviourEvent::Sync(request_response::Event::OutboundFailure {
+                peer, error, ..
+            }) => tracing::warn!(%peer, %error, "failed to sync"),
+            BehaviourEvent::Sync(_) => {}
+        }
+    }
+}
+
+/// State of a [`DeltaCrdt`] shared between a [`P2pNode`] and whoever changes it locally
+pub struct DeltaReplica<C: DeltaCrdt> {
+    state: Arc<Mutex<C>>,
+    /// Where the deltas of local changes go, one sender per node
+    local: Arc<Mutex<Vec<mpsc::UnboundedSender<C::Delta>>>>,
+    merged: watch::Sender<()>,
+}
+
+impl<C: DeltaCrdt> Clone for DeltaReplica<C> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            local: self.local.clone(),
+            merged: self.merged.clone(),
+        }
+    }
+}
+
+impl<C: DeltaCrdt> DeltaReplica<C> {
+    pub fn new(state: C) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            local: Default::default(),
+            merged: watch::Sender::new(()),
+        }
+    }
+
+    pub fn query(&self) -> C {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Merges the delta of a local change and publishes it
+    pub fn apply(&self, delta: C::Delta) {
+        self.state.lock().unwrap().merge_delta_in_place(&delta);
+        self.local
+            .lock()
+            .unwrap()
+            .retain(|node| node.send(delta.clone()).is_ok());
+        self.merged.send_replace(());
+    }
+
+    /// Changes whenever something is merged in, local or from a peer
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.merged.subscribe()
+    }
+
+    fn merge(&self, delta: &C::Delta) {
+        if C::delta_dots(delta).is_empty() {
+            return;
+        }
+        self.state.lock().unwrap().merge_delta_in_place(delta);
+        self.merged.send_replace(());
+    }
+}
+
+impl<C: DeltaCrdt> Replica for DeltaReplica<C> {
+    type Gossip = C::Delta;
+    type Request = C::Digest;
+    type Response = C::Delta;
+
+    fn changes(&self) -> BoxStream<'static, C::Delta> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.local.lock().unwrap().push(tx);
+        stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|d| (d, rx)) }).boxed()
+    }
+
+    fn hello(&self) -> C::Digest {
+        self.state.lock().unwrap().digest()
+    }
+
+    async fn gossiped(&mut self, delta: C::Delta) -> Option<C::Digest> {
+        self.merge(&delta);
+        None
+    }
+
+    async fn respond(&mut self, digest: C::Digest) -> C::Delta {
+        self.state.lock().unwrap().divergent(&digest)
+    }
+
+    async fn responded(&mut self, delta: C::Delta) -> Option<C::Digest> {
+        self.merge(&delta);
+        None
+    }
+}
+
+/// A spawned [`Replicator`] for a [`P2pNode`]
+pub struct OpReplica<C: Crdt> {
+    id: ReplicaId,
+    handle: ReplicatorHandle<C>,
+    writer: ReplicatorWriter<C>,
+}
+
+impl<C> OpReplica<C>
+where
+    C: Crdt + 'static,
+    C::Cmd: Send,
+{
+    pub fn spawn<Db: Store<C> + Send + 'static>(replicator: Replicator<C, Db>) -> Self {
+        let id = replicator.id();
+        let (handle, writer) = replicator.spawn();
+        Self { id, handle, writer }
+    }
+
+    pub fn handle(&self) -> ReplicatorHandle<C> {
+        self.handle.clone()
+    }
+
+    /// Commands sent here are replicated to the peers
+    pub fn writer(&self) -> ReplicatorWriter<C> {
+        self.writer.clone()
+    }
+
+    /// Hands `msg` to the replicator, `None` means there's nothing to send back
+    async fn send(&self, msg: Protocol<C::Cmd, C::EData>) -> Option<Protocol<C::Cmd, C::EData>> {
+        self.writer
+            .send(msg)
+            .await
+            .filter(|reply| !matches!(reply, Protocol::Noop))
+    }
+}
+
+impl<C> Replica for OpReplica<C>
+where
+    C: Crdt + 'static,
+    C::Cmd: Wire,
+    C::EData: Wire,
+{
+    /// The replica that picked up new events
+    type Gossip = ReplicaId;
+    type Request = Protocol<C::Cmd, C::EData>;
+    type Response = Protocol<C::Cmd, C::EData>;
+
+    fn changes(&self) -> BoxStream<'static, ReplicaId> {
+        let id = self.id;
+        stream::unfold(self.handle.clone(), move |mut handle| async move {
+            handle.changed().await.then_some((id, handle))
+        })
+        .boxed()
+    }
+
+    /// Gets the peer to pull from us, it does the same the other way around
+    fn hello(&self) -> Self::Request {
+        Protocol::Connect(Connect {
+            replica_id: self.id,
+        })
+    }
+
+    async fn gossiped(&mut self, replica_id: ReplicaId) -> Option<Self::Request> {
+        if replica_id == self.id {
+            return None;
+        }
+        self.send(Protocol::Connect(Connect { replica_id })).await
+    }
+
+    async fn respond(&mut self, request: Self::Request) -> Self::Response {
+        match request {
+            // Only local writes are commands
+            Protocol::Command(_) => Protocol::Noop,
+            request => self.send(request).await.unwrap_or(Protocol::Noop),
+        }
+    }
+
+    async fn responded(&mut self, response: Self::Response) -> Option<Self::Request> {
+        match response {
+            Protocol::Command(_) | Protocol::Noop => None,
+            response => self.send(response).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use libp2p::identity::Keypair;
+    use sypytkowski_commutative::{
+        counter::Counter, memdb::InMemoryDb, protocol::Protocol, ReplicaId as OpReplicaId,
+        Replicator,
+    };
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use super::{DeltaReplica, OpReplica, P2pConfig, P2pNode, Replica};
+
+    fn config() -> P2pConfig {
+        P2pConfig {
+            listen: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            mdns: false,
+            ..Default::default()
+        }
+    }
+
+    /// Runs a node for `a` and one for `b` that dials it
+    async fn connect<R: Replica>(a: R, b: R) {
+        let mut node = P2pNode::<R>::new(Keypair::generate_ed25519(), config(), a).unwrap();
+        let addr = node.listen_addr().await.unwrap();
+        tokio::spawn(node.run());
+
+        let config = P2pConfig {
+            peers: vec![addr],
+            ..config()
+        };
+        let node = P2pNode::<R>::new(Keypair::generate_ed25519(), config, b).unwrap();
+        tokio::spawn(node.run());
+    }
+
+    async fn eventually(what: &str, done: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for {what}"));
+    }
+
+    #[tokio::test]
+    async fn syncs_deltas() {
+        type Map = AWORMap<SquareId, Square>;
+        let (a, b) = (ReplicaId::from(1), ReplicaId::from(2));
+
+        let alice = DeltaReplica::new(Map::default());
+        let bob = DeltaReplica::new(Map::default());
+        let mut map = Map::default();
+        map.insert(a, SquareId(1), Square::default());
+        alice.apply(map.split_mut().unwrap());
+
+        connect(alice.clone(), bob.clone()).await;
+        eventually("anti-entropy", || bob.query().contains_key(&SquareId(1))).await;
+
+        let mut map = bob.query();
+        map.insert(b, SquareId(2), Square::default());
+        bob.apply(map.split_mut().unwrap());
+        eventually("gossip", || alice.query().contains_key(&SquareId(2))).await;
+    }
+
+    async fn counter(id: u128) -> OpReplica<Counter> {
+        let replicator = Replicator::new(
+            OpReplicaId::new(id),
+            Counter::default(),
+            InMemoryDb::default(),
+        )
+        .await
+        .unwrap();
+        OpReplica::spawn(replicator)
+    }
+
+    #[tokio::test]
+    async fn syncs_events() {
+        let alice = counter(1).await;
+        let bob = counter(2).await;
+        let (alice_handle, bob_handle) = (alice.handle(), bob.handle());
+        let (alice_writer, bob_writer) = (alice.writer(), bob.writer());
+
+        alice_writer.send(Protocol::Command(5)).await.unwrap();
+        connect(alice, bob).await;
+        eventually("anti-entropy", || bob_handle.query() == 5).await;
+
+        bob_writer.send(Protocol::Command(2)).await.unwrap();
+        eventually("alice to pull", || alice_handle.query() == 7).await;
+        alice_writer.send(Protocol::Command(-1)).await.unwrap();
+        eventually("bob to pull", || bob_handle.query() == 6).await;
+    }
+}
@@ -0,0 +1,130 @@
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::codec::Codec;
+use crate::proto::RoomId;
+
+/// Snapshots of the rooms' states, one file per room in the format of the codec. The snapshot
+/// includes the deltas the state hasn't handed out yet. The sessions of each room's clients are
+/// kept in a file next to it.
+pub struct Store<S> {
+    dir: PathBuf,
+    codec: PhantomData<S>,
+}
+
+impl<S: Codec> Store<S> {
+    pub async fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create data dir {:?}", dir))?;
+        Ok(Self {
+            dir,
+            codec: PhantomData,
+        })
+    }
+
+    /// Room ids can be anything, so they're hex encoded to make a file name
+    fn path(&self, kind: &str, room: &RoomId) -> PathBuf {
+        let name = room
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        self.dir.join(format!("{}-{}.{}", kind, name, S::EXTENSION))
+    }
+
+    pub async fn load<C: DeserializeOwned>(&self, room: &RoomId) -> Result<Option<C>> {
+        self.read(self.path("room", room)).await
+    }
+
+    pub async fn save<C: Serialize>(&self, room: &RoomId, state: &C) -> Result<()> {
+        self.write(self.path("room", room), state).await
+    }
+
+    pub async fn load_sessions<T: DeserializeOwned>(&self, room: &RoomId) -> Result<Option<T>> {
+        self.read(self.path("sessions", room)).await
+    }
+
+    pub async fn save_sessions<T: Serialize>(&self, room: &RoomId, sessions: &T) -> Result<()> {
+        self.write(self.path("sessions", room), sessions).await
+    }
+
+    async fn read<T: DeserializeOwned>(&self, path: PathBuf) -> Result<Option<T>> {
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+        };
+        let value =
+            S::decode(&bytes).with_context(|| format!("Failed to deserialize {:?}", path))?;
+        Ok(Some(value))
+    }
+
+    /// Writes to a temporary file first and renames it over the snapshot, so a crash leaves
+    /// either the old or the new snapshot behind but never half of one
+    async fn write<T: Serialize>(&self, path: PathBuf, value: &T) -> Result<()> {
+        let tmp = path.with_extension(format!("{}.tmp", S::EXTENSION));
+
+        let buf = S::encode(value)?;
+
+        let file = tokio::fs::File::create(&tmp)
+            .await
+            .with_context(|| format!("Failed to create {:?}", tmp))?;
+        let mut file = tokio::io::BufWriter::new(file);
+        tokio::io::AsyncWriteExt::write_all(&mut file, &buf).await?;
+        tokio::io::AsyncWriteExt::flush(&mut file).await?;
+        file.into_inner().sync_all().await?;
+
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .with_context(|| format!("Failed to move snapshot to {:?}", path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use super::Store;
+    use crate::codec::MsgPack;
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let dir = std::env::temp_dir().join(format!("ws-store-{}", ReplicaId::random()));
+        let store = Store::<MsgPack>::open(&dir).await.unwrap();
+        let room = "room/with ünicode".to_string();
+        assert!(store
+            .load::<AWORMap<SquareId, Square>>(&room)
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut state = AWORMap::default();
+        state.insert(
+            ReplicaId::from(1),
+            SquareId(1),
+            Square {
+                x: 1.0,
+                y: 2.0,
+                width: 3.0,
+                height: 4.0,
+            },
+        );
+        store.save(&room, &state).await.unwrap();
+        state.remove(&SquareId(1));
+        store.save(&room, &state).await.unwrap();
+
+        assert_eq!(store.load(&room).await.unwrap(), Some(state));
+        assert!(store
+            .load::<AWORMap<SquareId, Square>>(&"".to_string())
+            .await
+            .unwrap()
+            .is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use convergent_experiment_protocol::ReplicaId;
+
+use crate::proto::{CursorPos, User};
+
+/// Clients that didn't send anything for this long show up as idle
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Handed out to clients as they join, the least used one first
+const COLORS: [&str; 8] = [
+    "#e03131", "#1971c2", "#2f9e44", "#f08c00", "#9c36b5", "#0c8599", "#e8590c", "#5c940d",
+];
+
+#[derive(Debug)]
+struct Member {
+    user: User,
+    cursor: (f32, f32),
+    last_active: Instant,
+}
+
+/// Who is in a room. Clients are in the roster from connecting until they disconnect, they only
+/// go idle when they stop doing anything.
+#[derive(Debug, Default)]
+pub struct Roster {
+    members: HashMap<ReplicaId, Member>,
+}
+
+impl Roster {
+    /// Adds the client with a made up name until it sends its presence, `None` if it was already
+    /// in the roster
+    pub fn join(&mut self, id: ReplicaId, now: Instant) -> Option<User> {
+        if self.members.contains_key(&id) {
+            return None;
+        }
+        let digits = id.to_string();
+        let user = User {
+            id,
+            name: format!("Anonymous {}", &digits[digits.len().saturating_sub(4)..]),
+            color: self.pick_color().to_string(),
+            ..Default::default()
+        };
+        self.members.insert(
+            id,
+            Member {
+                user: user.clone(),
+                cursor: (0.0, 0.0),
+                last_active: now,
+            },
+        );
+        Some(user)
+    }
+
+    fn pick_color(&self) -> &'static str {
+        COLORS
+            .iter()
+            .min_by_key(|color| {
+                self.members
+                    .values()
+                    .filter(|m| m.user.color == **color)
+                    .count()
+            })
+            .unwrap()
+    }
+
+    pub fn leave(&mut self, id: ReplicaId) -> bool {
+        self.members.remove(&id).is_some()
+    }
+
+    pub fn set_presence(
+        &mut self,
+        id: ReplicaId,
+        name: String,
+        metadata: BTreeMap<String, String>,
+        now: Instant,
+    ) -> Option<User> {
+        let member = self.members.get_mut(&id)?;
+        // Clients without a name of their own keep the one they got when joining
+        if !name.is_empty() {
+            member.user.name = name;
+        }
+        member.user.metadata = metadata;
+        member.user.idle = false;
+        member.last_active = now;
+        Some(member.user.clone())
+    }
+
+    /// Records activity of the client, returns it if it was idle until now
+    pub fn touch(&mut self, id: ReplicaId, now: Instant) -> Option<User> {
+        let member = self.members.get_mut(&id)?;
+        member.last_active = now;
+        if !member.user.idle {
+            return None;
+        }
+        member.user.idle = false;
+        Some(member.user.clone())
+    }
+
+    pub fn set_cursor(&mut self, id: ReplicaId, cursor: (f32, f32)) -> Option<CursorPos> {
+        let member = self.members.get_mut(&id)?;
+        member.cursor = cursor;
+        Some(member.cursor_pos())
+    }
+
+    /// Marks the clients that have been inactive for longer than `timeout` as idle, returns the
+    /// ones that weren't idle already
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> Vec<User> {
+        self.members
+            .values_mut()
+            .filter(|m| !m.user.idle && now.saturating_duration_since(m.last_active) > timeout)
+            .map(|m| {
+                m.user.idle = true;
+                m.user.clone()
+            })
+            .collect()
+    }
+
+    pub fn users(&self) -> Vec<User> {
+        self.members.values().map(|m| m.user.clone()).collect()
+    }
+
+    pub fn cursors(&self) -> Vec<CursorPos> {
+        self.members.values().map(Member::cursor_pos).collect()
+    }
+}
+
+impl Member {
+    fn cursor_pos(&self) -> CursorPos {
+        CursorPos {
+            x: self.cursor.0,
+            y: self.cursor.1,
+            id: self.user.id,
+            name: self.user.name.clone(),
+            color: self.user.color.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use convergent_experiment_protocol::ReplicaId;
+
+    use super::Roster;
+
+    #[test]
+    fn join_idle_leave() {
+        let a = ReplicaId::from(1);
+        let b = ReplicaId::from(2);
+        let start = Instant::now();
+        let timeout = Duration::from_secs(10);
+
+        let mut roster = Roster::default();
+        let a_user = roster.join(a, start).unwrap();
+        let b_user = roster.join(b, start).unwrap();
+        assert!(roster.join(a, start).is_none());
+        assert_eq!(b_user.name, "Anonymous 2");
+        assert_ne!(a_user.color, b_user.color);
+        roster.set_presence(a, "alice".into(), Default::default(), start);
+        // Cursors come with the name and color
+        let pos = roster.set_cursor(a, (1.0, 2.0)).unwrap();
+        assert_eq!((pos.name.as_str(), pos.color), ("alice", a_user.color));
+        assert!(roster.set_cursor(ReplicaId::from(3), (1.0, 2.0)).is_none());
+        assert!(roster
+            .set_presence(b, "".into(), Default::default(), start)
+            .is_some_and(|b| b.name == "Anonymous 2"));
+
+        let later = start + Duration::from_secs(8);
+        assert!(roster.touch(b, later).is_none());
+
+        // Only a was inactive for long enough, and it's only reported once
+        let expired = roster.expire(start + Duration::from_secs(12), timeout);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].name, "alice");
+        assert!(expired[0].idle);
+        assert!(roster
+            .expire(start + Duration::from_secs(13), timeout)
+            .is_empty());
+
+        let back = roster.touch(a, start + Duration::from_secs(14)).unwrap();
+        assert!(!back.idle);
+
+        assert!(roster.leave(a));
+        assert!(!roster.leave(a));
+        assert_eq!(roster.users().len(), 1);
+    }
+}
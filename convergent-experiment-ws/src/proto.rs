@@ -1,28 +1,182 @@
-use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+use std::collections::BTreeMap;
+
+use convergent_experiment_protocol::ReplicaId;
 use serde::{Deserialize, Serialize};
-use sypytkowski_convergent::delta_state::awormap::{AWORMap, Deltas};
-use tungstenite::Message;
+
+use crate::causal::CausalToken;
+use crate::crdt::DeltaCrdt;
+
+/// Clients that join the same room share a drawing, clients that don't say which room they're
+/// joining end up in the unnamed one
+pub type RoomId = String;
+
+/// Handed out by [`ClientBoundSession`], lets a client resume where it left off after
+/// reconnecting
+pub type SessionToken = String;
+
+// Messages that carry state, deltas or digests are generic over the CRDT of the rooms, the ones
+// that only point at parts of the state over its key. `#[serde(bound = "")]` keeps serde from
+// asking more of the CRDT than `DeltaCrdt` already does.
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
+#[serde(tag = "type", bound = "")]
 #[serde(rename_all = "camelCase")]
-pub enum ServerBound {
-    Sync(ServerBoundSync),
-    Update(ServerBoundUpdate),
+pub enum ServerBound<C: DeltaCrdt> {
+    Sync(ServerBoundSync<C>),
+    SyncDigest(ServerBoundSyncDigest<C>),
+    Resume(ServerBoundResume<C>),
+    Update(ServerBoundUpdate<C>),
+    Ack(ServerBoundAck),
     Cursor(ServerBoundCursor),
+    Presence(ServerBoundPresence),
+    History(ServerBoundHistory),
+    Lock(ServerBoundLock<C::Key>),
+    Unlock(ServerBoundUnlock<C::Key>),
+    Encrypted(ServerBoundEncrypted),
+    Subscribe(ServerBoundSubscribe<C::Key>),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
-#[serde(rename_all = "camelCase")]
-pub struct ServerBoundSync {
+#[serde(rename_all = "camelCase", bound = "")]
+pub struct ServerBoundSync<C: DeltaCrdt> {
+    pub replica_id: ReplicaId,
+    #[serde(default)]
+    pub room_id: RoomId,
+    pub state: C,
+    /// Subscribes right away, so the answer is a [`ClientBoundSubscribed`] with only the part of
+    /// the state the subscription covers
+    #[serde(default)]
+    pub subscription: Option<Subscription<C::Key>>,
+    /// Holds the answer until the room caught up with the client's session, see [`crate::causal`]
+    #[serde(default)]
+    pub causal: Option<CausalToken>,
+    /// Sent by another server of the cluster rather than a client, see [`crate::cluster`]
+    #[serde(default)]
+    pub peer: bool,
+}
+
+/// Sent instead of [`ServerBoundSync`] by clients that already have state, so only the keys that
+/// differ are exchanged
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", bound = "")]
+pub struct ServerBoundSyncDigest<C: DeltaCrdt> {
+    pub replica_id: ReplicaId,
+    #[serde(default)]
+    pub room_id: RoomId,
+    pub digest: C::Digest,
+    #[serde(default)]
+    pub causal: Option<CausalToken>,
+}
+
+/// Sent instead of a sync by clients that had a session before. If the session can't be resumed
+/// the server answers as if `digest` came in a [`ServerBoundSyncDigest`].
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", bound = "")]
+pub struct ServerBoundResume<C: DeltaCrdt> {
     pub replica_id: ReplicaId,
-    pub state: AWORMap<SquareId, Square>,
+    #[serde(default)]
+    pub room_id: RoomId,
+    pub token: SessionToken,
+    /// Last [`ClientBoundUpdate`] the client acked
+    pub seq: u64,
+    pub digest: C::Digest,
+    #[serde(default)]
+    pub causal: Option<CausalToken>,
+}
+
+/// Asks for an advisory lock on a key, or renews one the client holds. `ts` settles
+/// conflicting requests, see [`crate::locks::Locks::lock`].
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerBoundLock<K> {
+    pub id: K,
+    pub ts: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerBoundUnlock<K> {
+    pub id: K,
+}
+
+/// Asks for the updates accepted in the room between the timestamps, in milliseconds since the
+/// epoch and inclusive
+/// Narrows the updates the client gets to part of the state, answered with a
+/// [`ClientBoundSubscribed`]. Sending it again replaces the subscription.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerBoundSubscribe<K> {
+    pub subscription: Subscription<K>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "camelCase")]
+pub enum Subscription<K> {
+    /// Everything, what clients that never subscribed get
+    All,
+    Keys {
+        keys: Vec<K>,
+    },
+    /// Entries within the region, and entries leaving it so the client sees them go. Servers
+    /// that can't tell where entries are treat it like [`Subscription::All`], see
+    /// [`crate::locate::Locate`].
+    Region {
+        region: Region,
+    },
+}
+
+/// Rectangle with its top left corner at `x`, `y`
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Region {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Region {
+    /// Whether the regions overlap, regions that only touch don't
+    pub fn intersects(&self, other: &Region) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerBoundHistory {
+    pub from_ts: u64,
+    pub to_ts: u64,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", bound = "")]
+pub struct ServerBoundUpdate<C: DeltaCrdt> {
+    pub deltas: C::Delta,
+}
+
+/// Deltas or a whole state encrypted with the room's key, see [`crate::e2e`]. Only taken by rooms
+/// the server runs end to end encrypted, which relay it to everyone else in the room without
+/// being able to read it.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerBoundEncrypted {
+    #[serde(with = "serde_bytes")]
+    pub payload: Vec<u8>,
+    /// The payload is the client's whole state, so what the client relayed before it can go
+    #[serde(default)]
+    pub snapshot: bool,
+}
+
+/// Acknowledges every [`ClientBoundUpdate`] up to and including `seq`
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
-pub struct ServerBoundUpdate {
-    pub deltas: Deltas<SquareId, Square>,
+pub struct ServerBoundAck {
+    pub seq: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -31,90 +185,287 @@ pub struct ServerBoundCursor {
     pub pos: (f32, f32),
 }
 
+/// Sets how the client shows up to others in the room, can be sent again to change it
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerBoundPresence {
+    pub name: String,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
+#[serde(tag = "type", bound = "")]
 #[serde(rename_all = "camelCase")]
-pub enum ClientBound {
-    Sync(ClientBoundSync),
-    Update(ClientBoundUpdate),
+pub enum ClientBound<C: DeltaCrdt> {
+    Sync(ClientBoundSync<C>),
+    SyncDigest(ClientBoundSyncDigest<C>),
+    Update(ClientBoundUpdate<C>),
     Cursor(ClientBoundCursor),
+    Roster(ClientBoundRoster),
+    Presence(ClientBoundPresence),
+    Session(ClientBoundSession),
+    Rejected(ClientBoundRejected<C::Key>),
+    Digest(ClientBoundDigest<C>),
+    History(ClientBoundHistory<C>),
+    Locks(ClientBoundLocks<C::Key>),
+    Encrypted(ClientBoundEncrypted),
+    Subscribed(ClientBoundSubscribed<C>),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", bound = "")]
+pub struct ClientBoundSync<C: DeltaCrdt> {
+    pub state: C,
+}
+
+/// Answer to [`ServerBoundSyncDigest`]: the server's keys where the client's digest differs, and
+/// the server's digest so the client can send back its own divergent keys as an update
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", bound = "")]
+pub struct ClientBoundSyncDigest<C: DeltaCrdt> {
+    pub digest: C::Digest,
+    pub deltas: C::Delta,
+}
+
+/// Answer to a [`ServerBoundSubscribe`]: the part of the state the subscription covers. Clients
+/// that subscribed get this instead of a [`ClientBoundSync`] when someone syncs their state.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", bound = "")]
+pub struct ClientBoundSubscribed<C: DeltaCrdt> {
+    pub deltas: C::Delta,
+}
+
+/// Answer to [`ServerBoundHistory`], oldest first. Only updates are in the history, state merged
+/// in by syncs isn't.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", bound = "")]
+pub struct ClientBoundHistory<C: DeltaCrdt> {
+    pub entries: Vec<HistoryEntry<C::Delta>>,
+    /// Set if the server dropped some of the updates in the range to keep its history short
+    pub truncated: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry<D> {
+    /// When the server accepted the update, in milliseconds since the epoch
+    pub ts: u64,
+    pub deltas: D,
+}
+
+/// Locks that were granted or released. Sent to everyone in the room, except for requests that
+/// lost to a lock that's already held, which only the requester hears about. A client that joins
+/// gets every lock that's held.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientBoundLocks<K> {
+    pub locked: Vec<Lock<K>>,
+    pub unlocked: Vec<K>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
-pub struct ClientBoundSync {
-    pub state: AWORMap<SquareId, Square>,
+pub struct Lock<K> {
+    pub id: K,
+    pub owner: ReplicaId,
+    pub ts: u64,
+}
+
+/// Sent to everyone in the room every so often, so clients that missed updates notice without
+/// reconnecting. Clients whose digest differs answer with a [`ServerBoundSyncDigest`].
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", bound = "")]
+pub struct ClientBoundDigest<C: DeltaCrdt> {
+    pub digest: C::Digest,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", bound = "")]
+pub struct ClientBoundUpdate<C: DeltaCrdt> {
+    /// Counts up per client, the client acks it with [`ServerBoundAck`]. Unacked updates are sent
+    /// again with the same `seq` when the client reconnects.
+    pub seq: u64,
+    pub deltas: C::Delta,
+}
+
+/// A [`ServerBoundEncrypted`] relayed as it came in. Clients joining an end to end encrypted room
+/// get everything the server kept this way, right after their [`ClientBoundSession`].
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
-pub struct ClientBoundUpdate {
-    pub deltas: Deltas<SquareId, Square>,
+pub struct ClientBoundEncrypted {
+    pub from: ReplicaId,
+    #[serde(with = "serde_bytes")]
+    pub payload: Vec<u8>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct ClientBoundCursor {
-    pub pos: Vec<(f32, f32, ReplicaId)>,
+    pub pos: Vec<CursorPos>,
 }
 
-impl ServerBound {
-    #[inline]
-    pub fn encode_msgpack(&self, buf: &mut Vec<u8>) {
-        rmp_serde::encode::write_named(buf, self).unwrap();
-    }
+/// Cursors carry who they belong to, so they can be drawn before the roster arrives
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CursorPos {
+    pub x: f32,
+    pub y: f32,
+    pub id: ReplicaId,
+    pub name: String,
+    pub color: String,
 }
-impl ClientBound {
-    #[inline]
-    pub fn encode_msgpack(&self, buf: &mut Vec<u8>) {
-        rmp_serde::encode::write_named(buf, self).unwrap();
-    }
+
+/// Sent after the answer to the client's first message. Sequence numbers of
+/// [`ClientBoundUpdate`] start over unless `resumed` is set, in which case the updates the client
+/// missed follow.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientBoundSession {
+    pub token: SessionToken,
+    pub resumed: bool,
 }
 
-impl TryFrom<Message> for ServerBound {
-    type Error = anyhow::Error;
+/// Answer to a [`ServerBoundUpdate`] that wasn't merged, nobody else got the update either
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientBoundRejected<K> {
+    pub reason: Rejection<K>,
+}
 
-    fn try_from(value: Message) -> Result<Self, Self::Error> {
-        if !value.is_binary() {
-            return Err(anyhow::anyhow!(
-                "Expected binary message but got: {:?}",
-                value
-            ));
-        }
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "camelCase")]
+pub enum Rejection<K> {
+    /// The value at the key is outside of what the application allows, like a square that
+    /// doesn't fit on the canvas
+    OutOfBounds {
+        id: K,
+    },
+    /// The value at the key has a NaN or infinite number
+    NotFinite {
+        id: K,
+    },
+    TooLarge {
+        entries: usize,
+        max: usize,
+    },
+    /// An entry claims to be added by `replica`, which isn't the client that sent it, or the
+    /// causal context claims dots of `replica` the room hasn't seen
+    ForeignDot {
+        replica: ReplicaId,
+    },
+    /// Plaintext deltas sent to an end to end encrypted room, or encrypted ones to a room that
+    /// isn't
+    Encryption {
+        room_encrypted: bool,
+    },
+}
 
-        let bytes = value.into_data();
+/// What other clients in the room see of a client
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    pub id: ReplicaId,
+    pub name: String,
+    /// CSS color the server picked when the client joined
+    pub color: String,
+    pub metadata: BTreeMap<String, String>,
+    pub idle: bool,
+}
 
-        ServerBound::deserialize(&mut rmp_serde::Deserializer::new(&bytes[..]))
-            .map_err(|e| anyhow::anyhow!("Failed to deserialize message: {}", e))
-    }
+/// Everyone in the room, sent to a client when it joins
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientBoundRoster {
+    pub users: Vec<User>,
 }
 
-impl TryFrom<Message> for ClientBound {
-    type Error = anyhow::Error;
+/// Changes to the roster since the last [`ClientBoundRoster`] or [`ClientBoundPresence`]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientBoundPresence {
+    /// Users that joined or changed
+    pub changed: Vec<User>,
+    pub left: Vec<ReplicaId>,
+}
+
+impl<C: DeltaCrdt> ServerBound<C> {
+    /// Replica the client says it is, for the messages that start a connection
+    pub fn replica_id(&self) -> Option<ReplicaId> {
+        match self {
+            ServerBound::Sync(ServerBoundSync { replica_id, .. })
+            | ServerBound::SyncDigest(ServerBoundSyncDigest { replica_id, .. })
+            | ServerBound::Resume(ServerBoundResume { replica_id, .. }) => Some(*replica_id),
+            _ => None,
+        }
+    }
 
-    fn try_from(value: Message) -> Result<Self, Self::Error> {
-        if !value.is_binary() {
-            return Err(anyhow::anyhow!("Expected binary message"));
+    /// Session the client asks the room to catch up with before answering, for the messages that
+    /// start a connection
+    pub fn causal(&self) -> Option<&CausalToken> {
+        match self {
+            ServerBound::Sync(ServerBoundSync { causal, .. })
+            | ServerBound::SyncDigest(ServerBoundSyncDigest { causal, .. })
+            | ServerBound::Resume(ServerBoundResume { causal, .. }) => causal.as_ref(),
+            _ => None,
         }
+    }
 
-        let bytes = value.into_data();
+    /// The `type` tag of the message
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerBound::Sync(_) => "sync",
+            ServerBound::SyncDigest(_) => "syncDigest",
+            ServerBound::Resume(_) => "resume",
+            ServerBound::Update(_) => "update",
+            ServerBound::Ack(_) => "ack",
+            ServerBound::Cursor(_) => "cursor",
+            ServerBound::Presence(_) => "presence",
+            ServerBound::History(_) => "history",
+            ServerBound::Lock(_) => "lock",
+            ServerBound::Unlock(_) => "unlock",
+            ServerBound::Encrypted(_) => "encrypted",
+            ServerBound::Subscribe(_) => "subscribe",
+        }
+    }
+}
 
-        ClientBound::deserialize(&mut rmp_serde::Deserializer::new(&bytes[..]))
-            .map_err(|e| anyhow::anyhow!("Failed to deserialize message: {}", e))
+impl<C: DeltaCrdt> ClientBound<C> {
+    /// The `type` tag of the message
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientBound::Sync(_) => "sync",
+            ClientBound::SyncDigest(_) => "syncDigest",
+            ClientBound::Update(_) => "update",
+            ClientBound::Cursor(_) => "cursor",
+            ClientBound::Roster(_) => "roster",
+            ClientBound::Presence(_) => "presence",
+            ClientBound::Session(_) => "session",
+            ClientBound::Rejected(_) => "rejected",
+            ClientBound::Digest(_) => "digest",
+            ClientBound::History(_) => "history",
+            ClientBound::Locks(_) => "locks",
+            ClientBound::Encrypted(_) => "encrypted",
+            ClientBound::Subscribed(_) => "subscribed",
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{ClientBound, ServerBound};
+    use convergent_experiment_protocol::{Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use super::ClientBound;
 
     #[test]
     fn noob() {
-        let state = ClientBound::Sync(super::ClientBoundSync {
+        let state = ClientBound::<AWORMap<SquareId, Square>>::Sync(super::ClientBoundSync {
             ..Default::default()
         });
         let mut buf = Vec::with_capacity(128);
         rmp_serde::encode::write_named(&mut buf, &state).unwrap();
-        std::fs::write("./state.bin", buf).unwrap()
+        std::fs::write(std::env::temp_dir().join("state.bin"), buf).unwrap()
     }
 }
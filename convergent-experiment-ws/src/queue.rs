@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use crate::crdt::DeltaCrdt;
+use crate::proto::{ClientBound, ClientBoundCursor, ClientBoundUpdate};
+
+/// How long messages for a client wait to be coalesced, about a frame
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Messages waiting to be sent to a client. Consecutive updates are merged into one, and only the
+/// latest position of each cursor is kept.
+#[derive(Debug, Default)]
+pub struct Outgoing<C: DeltaCrdt> {
+    msgs: Vec<ClientBound<C>>,
+}
+
+impl<C: DeltaCrdt> Outgoing<C> {
+    pub fn push(&mut self, msg: ClientBound<C>) {
+        match msg {
+            ClientBound::Update(update) => self.push_update(update),
+            ClientBound::Cursor(cursor) => self.push_cursor(cursor),
+            msg => self.msgs.push(msg),
+        }
+    }
+
+    /// Acks are cumulative, so the merged update takes the sequence number of the later one
+    fn push_update(&mut self, update: ClientBoundUpdate<C>) {
+        if let Some(ClientBound::Update(last)) = self.msgs.last_mut() {
+            C::merge_deltas(&mut last.deltas, &update.deltas);
+            last.seq = last.seq.max(update.seq);
+            return;
+        }
+        self.msgs.push(ClientBound::Update(update));
+    }
+
+    /// Cursors are positions rather than changes, so one message holds all of them no matter
+    /// what else was queued in between
+    fn push_cursor(&mut self, cursor: ClientBoundCursor) {
+        let queued = self.msgs.iter_mut().find_map(|msg| match msg {
+            ClientBound::Cursor(queued) => Some(queued),
+            _ => None,
+        });
+        let Some(queued) = queued else {
+            self.msgs.push(ClientBound::Cursor(cursor));
+            return;
+        };
+        for pos in cursor.pos {
+            match queued.pos.iter_mut().find(|queued| queued.id == pos.id) {
+                Some(queued) => *queued = pos,
+                None => queued.pos.push(pos),
+            }
+        }
+    }
+
+    pub fn take(&mut self) -> Vec<ClientBound<C>> {
+        std::mem::take(&mut self.msgs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use super::Outgoing;
+    use crate::proto::{
+        ClientBound, ClientBoundCursor, ClientBoundSync, ClientBoundUpdate, CursorPos,
+    };
+
+    #[test]
+    fn coalesces() {
+        let a = ReplicaId::from(1);
+        let b = ReplicaId::from(2);
+        let mut map = AWORMap::default();
+        let mut updates = vec![];
+        for i in 0..2 {
+            map.insert(a, SquareId(i), Square::default());
+            updates.push(map.split_mut().unwrap());
+        }
+
+        let cursor = |x, id| {
+            ClientBound::Cursor(ClientBoundCursor {
+                pos: vec![CursorPos {
+                    x,
+                    id,
+                    ..Default::default()
+                }],
+            })
+        };
+        let mut outgoing = Outgoing::<AWORMap<SquareId, Square>>::default();
+        outgoing.push(cursor(1.0, a));
+        for (seq, deltas) in updates.into_iter().enumerate() {
+            outgoing.push(ClientBound::Update(ClientBoundUpdate {
+                seq: seq as u64 + 1,
+                deltas,
+            }));
+        }
+        outgoing.push(ClientBound::Sync(ClientBoundSync::default()));
+        outgoing.push(cursor(2.0, b));
+        outgoing.push(cursor(3.0, a));
+
+        let msgs = outgoing.take();
+        assert!(outgoing.take().is_empty());
+        assert_eq!(msgs.len(), 3);
+        let ClientBound::Cursor(cursors) = &msgs[0] else {
+            panic!("Expected cursors first");
+        };
+        let pos = cursors.pos.iter().map(|p| (p.x, p.id)).collect::<Vec<_>>();
+        assert_eq!(pos, vec![(3.0, a), (2.0, b)]);
+        let ClientBound::Update(update) = &msgs[1] else {
+            panic!("Expected the updates to be merged");
+        };
+        assert_eq!(update.seq, 2);
+        let mut merged = AWORMap::default();
+        merged.merge_delta_in_place(&update.deltas);
+        assert!(merged.contains_key(&SquareId(0)) && merged.contains_key(&SquareId(1)));
+        assert!(matches!(msgs[2], ClientBound::Sync(_)));
+    }
+}
@@ -0,0 +1,2001 @@
+use anyhow::{anyhow, Context, Result};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use serde::de::DeserializeOwned;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::http::Uri;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tungstenite::Message;
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use crate::auth::{self, Auth};
+#[cfg(feature = "redis")]
+use crate::bridge::{Bridge, Bridged};
+use crate::causal::CausalToken;
+use crate::cluster::{Cluster, LINK_RETRY_DELAY};
+use crate::codec::{Codec, MsgPack};
+use crate::config::{AntiEntropy, Config};
+use crate::crdt::DeltaCrdt;
+use crate::e2e::Relay;
+use crate::history::{self, History};
+use crate::limit::{Rate, RateLimit};
+use crate::locate::Locate;
+use crate::locks::Locks;
+use crate::metrics::{self, Gauges, Metrics};
+use crate::persist::Store;
+use crate::presence::{Roster, IDLE_TIMEOUT};
+use crate::proto::{
+    ClientBound, ClientBoundCursor, ClientBoundDigest, ClientBoundHistory, ClientBoundLocks,
+    ClientBoundPresence, ClientBoundRejected, ClientBoundRoster, ClientBoundSession,
+    ClientBoundSubscribed, ClientBoundSync, ClientBoundSyncDigest, ClientBoundUpdate, Lock, Region,
+    Rejection, RoomId, ServerBound, ServerBoundAck, ServerBoundCursor, ServerBoundEncrypted,
+    ServerBoundHistory, ServerBoundLock, ServerBoundPresence, ServerBoundResume,
+    ServerBoundSubscribe, ServerBoundSync, ServerBoundSyncDigest, ServerBoundUnlock,
+    ServerBoundUpdate, SessionToken, Subscription,
+};
+use crate::queue::{Outgoing, FLUSH_INTERVAL};
+use crate::session::{Sessions, SESSION_TTL};
+use crate::subscriptions::Subscriptions;
+use crate::validate::Validator;
+use convergent_experiment_protocol::ReplicaId;
+use sypytkowski_convergent::delta_state::dot::VectorClock;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Mutex, Notify, RwLock},
+    task::JoinHandle,
+};
+use tracing::Instrument;
+
+/// Websocket server that keeps rooms of `C` in sync between their clients, speaking `S` on the
+/// wire and in the snapshots
+pub struct CrdtServer<C: DeltaCrdt, S: Codec = MsgPack> {
+    ctx: Arc<Ctx<C, S>>,
+    addr: String,
+    metrics: Option<String>,
+}
+
+impl<C: DeltaCrdt, S: Codec> CrdtServer<C, S> {
+    /// Opens the data dir and loads the TLS certificate if the config asks for them. Every update
+    /// of a client goes through `validator` before it's merged.
+    pub async fn new(config: &Config, validator: impl Validator<C> + 'static) -> Result<Self> {
+        let store = match &config.data_dir {
+            Some(dir) => Some(Store::open(dir).await?),
+            None => None,
+        };
+        Ok(Self {
+            ctx: Arc::new(Ctx::new(store, Arc::new(validator), config)?),
+            addr: config.addr.clone(),
+            metrics: config.metrics.clone(),
+        })
+    }
+
+    /// Lets clients subscribe to regions of the state, see [`Locate`]
+    pub fn with_locator(mut self, locator: impl Locate<C> + 'static) -> Self {
+        let ctx = Arc::get_mut(&mut self.ctx).expect("the context is only shared once running");
+        ctx.locator = Some(Arc::new(locator));
+        self
+    }
+
+    /// Serves clients until the listener fails or the process gets Ctrl-C, then writes a last
+    /// snapshot of every room
+    pub async fn run(self) -> Result<()> {
+        let ctx = self.ctx;
+        let listener = TcpListener::bind(&self.addr)
+            .await
+            .with_context(|| format!("Failed to bind {}", self.addr))?;
+        tracing::info!(addr = %self.addr, tls = ctx.tls.is_some(), "listening");
+
+        if ctx.store.is_some() {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    ctx.save_all().await;
+                }
+            });
+        }
+
+        if let Some(metrics_addr) = &self.metrics {
+            let metrics_listener = TcpListener::bind(&metrics_addr)
+                .await
+                .with_context(|| format!("Failed to bind metrics to {}", metrics_addr))?;
+            tracing::info!(addr = %metrics_addr, "serving metrics");
+            tokio::spawn(serve_metrics(ctx.clone(), metrics_listener));
+
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(metrics::SAMPLE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    ctx.metrics.sample();
+                }
+            });
+        }
+
+        {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    ctx.expire_idle().await;
+                    ctx.expire_sessions().await;
+                }
+            });
+        }
+
+        {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(LOCK_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    ctx.expire_locks().await;
+                }
+            });
+        }
+
+        {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(ANTI_ENTROPY_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    ctx.anti_entropy().await;
+                }
+            });
+        }
+
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => break,
+                },
+                _ = tokio::signal::ctrl_c() => break,
+            };
+            let ctx = ctx.clone();
+
+            if let Some(max) = ctx.max_clients {
+                if ctx.client_count().await >= max {
+                    tracing::warn!(%addr, max, "turning away client");
+                    continue;
+                }
+            }
+
+            // Handshakes and joins can take a while, a room holds clients until it caught up with
+            // their session for one, so they don't hold up the connections behind them
+            tokio::spawn(async move {
+                let (client, room, r) = match Client::new(stream, ctx.clone()).await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        tracing::warn!(%addr, error = ?e, "failed to create client");
+                        return;
+                    }
+                };
+
+                let span = tracing::info_span!(
+                    "client",
+                    replica = ?client.id,
+                    room = ?room.id,
+                    peer = client.peer
+                );
+                let handled = async move {
+                    let replica = client.id;
+                    if client.peer {
+                        room.add_peer(client.clone()).await;
+                    } else {
+                        room.add_connection(client.clone()).await;
+                        room.resend(&client).await;
+                        room.send_relayed(&client).await;
+                        room.join_presence(&client).await;
+                        room.send_locks(&client).await;
+                        room.broadcast_cursors(replica).await;
+                    }
+
+                    if let Err(e) = Client::listen::<S>(&client, r, room.clone()).await {
+                        tracing::warn!(error = ?e, "error handling client")
+                    }
+                    ctx.leave(room, &client).await;
+                };
+                handled.instrument(span).await;
+            });
+        }
+
+        tracing::info!("shutting down");
+        ctx.save_all().await;
+        Ok(())
+    }
+}
+
+/// How often the rooms that changed are written to the data dir
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often clients are checked for having gone idle, and sessions for having expired
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often locks are checked for having expired
+const LOCK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often rooms are checked for being due a digest, see [`AntiEntropy`]
+const ANTI_ENTROPY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Ctx<C: DeltaCrdt, S> {
+    rooms: Mutex<HashMap<RoomId, Arc<Room<C>>>>,
+    /// Rooms everyone left that may still be saving their last snapshot. Joining one takes it
+    /// back rather than loading a snapshot that misses what changed last.
+    closing: Mutex<HashMap<RoomId, Weak<Room<C>>>>,
+    id_counter: AtomicU64,
+    store: Option<Store<S>>,
+    /// Shared with the rooms, which log their updates to the sessions in them
+    sessions: Arc<Mutex<Sessions<C::Delta>>>,
+    validator: Arc<dyn Validator<C>>,
+    locator: Option<Arc<dyn Locate<C>>>,
+    auth: Auth,
+    metrics: Arc<Metrics>,
+    /// Connections are wrapped in TLS first if set
+    tls: Option<TlsAcceptor>,
+    websocket: WebSocketConfig,
+    max_clients: Option<usize>,
+    rate: Rate,
+    anti_entropy: AntiEntropy,
+    /// Rooms that only relay what their clients encrypted, see [`crate::e2e`]
+    e2e_rooms: HashSet<RoomId>,
+    /// How long a joining client waits for the room to catch up with its session
+    causal_wait: Duration,
+    /// Other servers the rooms are linked with, see [`crate::cluster`]
+    cluster: Cluster,
+    /// Other servers the rooms are kept in sync with through Redis, see [`crate::bridge`]
+    #[cfg(feature = "redis")]
+    bridge: Option<Arc<Bridge>>,
+}
+
+impl<C: DeltaCrdt, S: Codec> Ctx<C, S> {
+    fn new(
+        store: Option<Store<S>>,
+        validator: Arc<dyn Validator<C>>,
+        config: &Config,
+    ) -> Result<Self> {
+        Ok(Self {
+            rooms: Mutex::new(HashMap::new()),
+            closing: Mutex::new(HashMap::new()),
+            id_counter: 0.into(),
+            store,
+            sessions: Arc::new(Mutex::new(Sessions::default())),
+            validator,
+            locator: None,
+            auth: config.auth(),
+            metrics: Arc::new(Metrics::default()),
+            tls: config.tls()?,
+            websocket: config.websocket(),
+            max_clients: config.max_clients,
+            rate: config.rate(),
+            anti_entropy: config.anti_entropy(),
+            e2e_rooms: config.e2e_rooms(),
+            causal_wait: config.causal_wait(),
+            cluster: config.cluster(),
+            #[cfg(feature = "redis")]
+            bridge: config.bridge()?.map(Arc::new),
+        })
+    }
+
+    fn new_id(&self) -> u64 {
+        self.id_counter
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Gets the room, opening it if nobody is in it yet. Rooms are loaded from their snapshot
+    /// when they're opened rather than all at once at startup, since they're dropped again once
+    /// everyone left.
+    async fn join(&self, id: &RoomId) -> Arc<Room<C>> {
+        let mut rooms = self.rooms.lock().await;
+        let room = match rooms.get(id) {
+            Some(room) => room.clone(),
+            None => {
+                let closing = self.closing.lock().await.remove(id);
+                match closing.and_then(|room| room.upgrade()) {
+                    Some(room) => {
+                        tracing::info!(room = ?id, "reopening room");
+                        rooms.insert(id.clone(), room.clone());
+                        room
+                    }
+                    None => self.open(&mut rooms, id).await,
+                }
+            }
+        };
+        room.members.fetch_add(1, Ordering::SeqCst);
+        room
+    }
+
+    /// Loads the room and links it with the rest of the cluster
+    async fn open(&self, rooms: &mut HashMap<RoomId, Arc<Room<C>>>, id: &RoomId) -> Arc<Room<C>> {
+        tracing::info!(room = ?id, "opening room");
+        let (state, relay) = if self.e2e_rooms.contains(id) {
+            (None, Some(self.load(id).await.unwrap_or_default()))
+        } else {
+            (self.load(id).await, None)
+        };
+        self.restore_sessions(id).await;
+        let room = Arc::new(Room::new(
+            id.clone(),
+            state.unwrap_or_default(),
+            relay,
+            self.sessions.clone(),
+            self.validator.clone(),
+            self.locator.clone(),
+            self.anti_entropy.interval(id),
+        ));
+        rooms.insert(id.clone(), room.clone());
+        if room.relay.is_none() {
+            room.link::<S>(&self.cluster).await;
+            #[cfg(feature = "redis")]
+            if let Some(bridge) = &self.bridge {
+                room.bridge::<S>(bridge.clone(), self.cluster.replica).await;
+            }
+        }
+        room
+    }
+
+    /// Joins the room only if it's open, peers don't open rooms on their own
+    async fn open_room(&self, id: &RoomId) -> Option<Arc<Room<C>>> {
+        let rooms = self.rooms.lock().await;
+        let room = rooms.get(id)?;
+        room.members.fetch_add(1, Ordering::SeqCst);
+        Some(room.clone())
+    }
+
+    /// The snapshot of the room, or the relay log of an end to end encrypted one
+    async fn load<T: DeserializeOwned>(&self, id: &RoomId) -> Option<T> {
+        let store = self.store.as_ref()?;
+        store.load(id).await.unwrap_or_else(|e| {
+            tracing::warn!(room = ?id, error = ?e, "failed to load room, starting empty");
+            None
+        })
+    }
+
+    /// Picks up the sessions saved with the room, so clients that were in it before a restart
+    /// can resume them
+    async fn restore_sessions(&self, id: &RoomId) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let saved = store.load_sessions(id).await.unwrap_or_else(|e| {
+            tracing::warn!(room = ?id, error = ?e, "failed to load sessions");
+            None
+        });
+        if let Some(saved) = saved {
+            self.sessions.lock().await.restore(saved, Instant::now());
+        }
+    }
+
+    /// Picks the client's session back up, out of the room's snapshot if the room isn't open.
+    /// The room isn't opened for it, so a client with a bad token can't keep it open.
+    async fn resume(&self, resume: &ServerBoundResume<C>) -> bool {
+        let resumed = |sessions: &mut Sessions<C::Delta>| {
+            sessions.resume(
+                &resume.token,
+                resume.replica_id,
+                &resume.room_id,
+                resume.seq,
+            )
+        };
+        if resumed(&mut *self.sessions.lock().await) {
+            return true;
+        }
+        if self.rooms.lock().await.contains_key(&resume.room_id) {
+            return false;
+        }
+        self.restore_sessions(&resume.room_id).await;
+        resumed(&mut *self.sessions.lock().await)
+    }
+
+    /// Removes the client from the room, and the room along with its state once the last client
+    /// left. The client's session is kept for a while in case it comes back.
+    async fn leave(&self, room: Arc<Room<C>>, client: &Client<C>) {
+        self.sessions
+            .lock()
+            .await
+            .disconnect(&client.session, Instant::now());
+        if client.peer {
+            room.remove_peer(client).await;
+        } else {
+            room.remove_connection(client).await;
+            room.leave_presence(client.id).await;
+            room.release_locks(client.id).await;
+        }
+        let empty = room.connections.read().await.is_empty();
+        if !self.release(room.clone()).await && empty && !client.peer {
+            // The room closes once the peers linked with it hung up as well
+            room.close_peers().await;
+        }
+    }
+
+    /// Counts a member out of the room, and closes it if that was the last one. The links and
+    /// the background tasks hold on to the room too, only members keep it open. Returns whether
+    /// the room was closed.
+    async fn release(&self, room: Arc<Room<C>>) -> bool {
+        let mut rooms = self.rooms.lock().await;
+        if room.members.fetch_sub(1, Ordering::SeqCst) > 1 {
+            return false;
+        }
+        tracing::info!(room = ?room.id, "closing room");
+        rooms.remove(&room.id);
+        let mut closing = self.closing.lock().await;
+        closing.retain(|_, room| room.strong_count() > 0);
+        closing.insert(room.id.clone(), Arc::downgrade(&room));
+        drop(closing);
+        drop(rooms);
+        self.save(&room).await;
+        true
+    }
+
+    /// Writes a snapshot of the room and the sessions in it if either changed since the last one
+    async fn save(&self, room: &Room<C>) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        if !room.dirty.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let start = Instant::now();
+        // Taken before the state, so the snapshot has every update the sessions hand out again
+        let sessions = self.sessions.lock().await.in_room(&room.id);
+        let saved = match &room.relay {
+            Some(relay) => store.save(&room.id, &*relay.read().await).await,
+            None => store.save(&room.id, &room.get_state().await).await,
+        };
+        let saved = match saved {
+            Ok(()) => store.save_sessions(&room.id, &sessions).await,
+            Err(e) => Err(e),
+        };
+        let elapsed_us = start.elapsed().as_micros() as u64;
+        tracing::debug!(room = ?room.id, elapsed_us, ok = saved.is_ok(), "saved room");
+        if let Err(e) = saved {
+            tracing::warn!(room = ?room.id, error = ?e, "failed to save room");
+            room.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Holds a joining client until the room caught up with its session, see [`crate::causal`].
+    /// The session the client was given is let go if the room doesn't catch up in time.
+    async fn catch_up(
+        &self,
+        room: &Room<C>,
+        session: &SessionToken,
+        causal: Option<&CausalToken>,
+    ) -> Result<()> {
+        let Some(causal) = causal else {
+            return Ok(());
+        };
+        if room.catch_up(causal, self.causal_wait).await {
+            return Ok(());
+        }
+        self.sessions
+            .lock()
+            .await
+            .disconnect(session, Instant::now());
+        Err(anyhow!(
+            "Room {:?} didn't catch up with the client's session in time",
+            room.id
+        ))
+    }
+
+    async fn expire_idle(&self) {
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let now = Instant::now();
+        for room in rooms {
+            room.expire_idle(now).await;
+        }
+    }
+
+    async fn anti_entropy(&self) {
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let now = Instant::now();
+        for room in rooms {
+            room.anti_entropy(now).await;
+        }
+    }
+
+    async fn expire_locks(&self) {
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let now = Instant::now();
+        for room in rooms {
+            room.expire_locks(now).await;
+        }
+    }
+
+    async fn expire_sessions(&self) {
+        self.sessions
+            .lock()
+            .await
+            .expire(Instant::now(), SESSION_TTL);
+    }
+
+    async fn client_count(&self) -> usize {
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut count = 0;
+        for room in rooms {
+            count += room.connections.read().await.len();
+        }
+        count
+    }
+
+    async fn gauges(&self) -> Gauges {
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut gauges = Gauges {
+            rooms: rooms.len(),
+            ..Default::default()
+        };
+        for room in rooms {
+            gauges.clients += room.connections.read().await.len();
+            gauges.entries += room.state.read().await.len();
+        }
+        gauges
+    }
+
+    async fn save_all(&self) {
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for room in rooms {
+            self.save(&room).await;
+        }
+    }
+}
+
+/// Clients in the same room share a drawing and see each other's cursors, nothing is sent across
+/// rooms
+struct Room<C: DeltaCrdt> {
+    id: RoomId,
+    state: Arc<RwLock<C>>,
+    /// Set for end to end encrypted rooms, whose state stays empty since the server can't read
+    /// the updates
+    relay: Option<RwLock<Relay>>,
+    /// At most one connection per replica, a replica that connects again replaces its old one
+    connections: Arc<RwLock<HashMap<ReplicaId, Client<C>>>>,
+    /// Whether the state or the acks changed since the last snapshot
+    dirty: AtomicBool,
+    /// Clients and peers that joined and haven't left yet, only changed with [`Ctx::rooms`]
+    /// locked. The room closes once it's down to none.
+    members: AtomicUsize,
+    sessions: Arc<Mutex<Sessions<C::Delta>>>,
+    validator: Arc<dyn Validator<C>>,
+    locator: Option<Arc<dyn Locate<C>>>,
+    subscriptions: RwLock<Subscriptions<C::Key>>,
+    /// Who is in the room, along with their cursors
+    roster: RwLock<Roster>,
+    /// How often the clients get a digest of the state, if at all
+    anti_entropy: Option<Duration>,
+    next_digest: Mutex<Instant>,
+    history: RwLock<History<C::Delta>>,
+    locks: RwLock<Locks<C::Key>>,
+    /// Everything the state has seen, see [`DeltaCrdt::version`]
+    version: RwLock<VectorClock>,
+    /// Woken whenever `version` moves forward
+    advanced: Notify,
+    /// Other servers of the cluster that linked their room with this one. They're kept apart from
+    /// the clients since they only merge what they're sent, see [`crate::cluster`].
+    peers: RwLock<HashMap<ReplicaId, Client<C>>>,
+    /// This room's links to the other servers, which pass on what its own clients changed
+    links: Mutex<Vec<Link<C>>>,
+}
+
+impl<C: DeltaCrdt> Room<C> {
+    fn new(
+        id: RoomId,
+        state: C,
+        relay: Option<Relay>,
+        sessions: Arc<Mutex<Sessions<C::Delta>>>,
+        validator: Arc<dyn Validator<C>>,
+        locator: Option<Arc<dyn Locate<C>>>,
+        anti_entropy: Option<Duration>,
+    ) -> Self {
+        let version = state.version();
+        Self {
+            id,
+            state: Arc::new(RwLock::new(state)),
+            relay: relay.map(RwLock::new),
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            dirty: AtomicBool::new(false),
+            members: AtomicUsize::new(0),
+            sessions,
+            validator,
+            locator,
+            subscriptions: RwLock::new(Subscriptions::default()),
+            roster: RwLock::new(Roster::default()),
+            anti_entropy,
+            next_digest: Mutex::new(Instant::now() + anti_entropy.unwrap_or_default()),
+            history: RwLock::new(History::default()),
+            locks: RwLock::new(Locks::default()),
+            version: RwLock::new(version),
+            advanced: Notify::new(),
+            peers: RwLock::new(HashMap::new()),
+            links: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Dials every peer of the cluster, the links stop once the room is dropped
+    async fn link<S: Codec>(self: &Arc<Self>, cluster: &Cluster) {
+        let mut links = self.links.lock().await;
+        for peer in &cluster.peers {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let span = tracing::info_span!("link", room = ?self.id, %peer);
+            let task = link::<C, S>(
+                Arc::downgrade(self),
+                cluster.link_url(peer),
+                cluster.replica,
+                rx,
+            );
+            links.push(Link {
+                tx,
+                task: tokio::spawn(task.instrument(span)),
+            });
+        }
+    }
+
+    /// Subscribes to the room's channel on Redis, like a link to every server on it
+    #[cfg(feature = "redis")]
+    async fn bridge<S: Codec>(self: &Arc<Self>, bridge: Arc<Bridge>, server: ReplicaId) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let span = tracing::info_span!("bridge", room = ?self.id);
+        let task = bridge_loop::<C, S>(Arc::downgrade(self), bridge, server, rx);
+        self.links.lock().await.push(Link {
+            tx,
+            task: tokio::spawn(task.instrument(span)),
+        });
+    }
+
+    /// Passes on a change of one of the room's own clients to the peers
+    async fn replicate(&self, replicated: Replicated<C>) {
+        for link in self.links.lock().await.iter() {
+            let _ = link.tx.send(replicated.clone());
+        }
+    }
+
+    async fn add_peer(&self, peer: Client<C>) {
+        self.peers.write().await.insert(peer.id, peer);
+    }
+
+    /// Leaves the connection alone if the peer already linked again
+    async fn remove_peer(&self, peer: &Client<C>) {
+        let mut peers = self.peers.write().await;
+        if peers
+            .get(&peer.id)
+            .is_some_and(|p| p.session == peer.session)
+        {
+            peers.remove(&peer.id);
+        }
+    }
+
+    async fn close_peers(&self) {
+        for peer in self.peers.read().await.values() {
+            peer.close(CloseCode::Away, "Room closed");
+        }
+    }
+
+    async fn advance(&self, version: &VectorClock) {
+        self.version.write().await.merge_in_place(version);
+        self.advanced.notify_waiters();
+    }
+
+    /// Waits until the room has seen everything the session needs, returns `false` if it didn't
+    /// within `timeout`. End to end encrypted rooms don't know what they've seen, so they never
+    /// hold anyone.
+    async fn catch_up(&self, causal: &CausalToken, timeout: Duration) -> bool {
+        if self.relay.is_some() {
+            return true;
+        }
+        let caught_up = async {
+            loop {
+                // Taken before checking so an advance in between isn't missed
+                let advanced = self.advanced.notified();
+                if causal.is_covered_by(&*self.version.read().await) {
+                    return;
+                }
+                advanced.await;
+            }
+        };
+        tokio::time::timeout(timeout, caught_up).await.is_ok()
+    }
+
+    async fn add_connection(&self, client: Client<C>) {
+        self.connections.write().await.insert(client.id, client);
+    }
+
+    async fn broadcast_cursors(&self, replica: ReplicaId) {
+        let cursors = self.roster.read().await.cursors();
+
+        self.broadcast_msg(
+            ClientBound::Cursor(ClientBoundCursor { pos: cursors }),
+            self.connections.read().await.values(),
+        );
+    }
+
+    /// Leaves the connection alone if it's already been replaced by a newer one of the replica
+    async fn remove_connection(&self, client: &Client<C>) {
+        let mut connections = self.connections.write().await;
+        if connections
+            .get(&client.id)
+            .is_some_and(|c| c.session == client.session)
+        {
+            connections.remove(&client.id);
+        }
+    }
+
+    async fn get_state(&self) -> C {
+        self.state.read().await.clone()
+    }
+
+    /// Puts the client in the roster, sends it everyone that's already there and tells them
+    /// about it
+    async fn join_presence(&self, client: &Client<C>) {
+        let mut roster = self.roster.write().await;
+        let joined = roster.join(client.id, Instant::now());
+        let msg = ClientBound::Roster(ClientBoundRoster {
+            users: roster.users(),
+        });
+        client.send(msg);
+        drop(roster);
+
+        if let Some(user) = joined {
+            self.broadcast_presence(
+                client.id,
+                ClientBoundPresence {
+                    changed: vec![user],
+                    left: vec![],
+                },
+            )
+            .await;
+        }
+    }
+
+    async fn leave_presence(&self, id: ReplicaId) {
+        if self.roster.write().await.leave(id) {
+            self.broadcast_presence(
+                id,
+                ClientBoundPresence {
+                    changed: vec![],
+                    left: vec![id],
+                },
+            )
+            .await;
+        }
+    }
+
+    async fn handle_presence(
+        &self,
+        origin: ReplicaId,
+        ServerBoundPresence { name, metadata }: ServerBoundPresence,
+    ) {
+        let user = self
+            .roster
+            .write()
+            .await
+            .set_presence(origin, name, metadata, Instant::now());
+        if let Some(user) = user {
+            self.broadcast_presence(
+                origin,
+                ClientBoundPresence {
+                    changed: vec![user],
+                    left: vec![],
+                },
+            )
+            .await;
+        }
+    }
+
+    /// Called for every message of the client, so it stops being idle
+    async fn touch(&self, origin: ReplicaId) {
+        let user = self.roster.write().await.touch(origin, Instant::now());
+        if let Some(user) = user {
+            self.broadcast_presence(
+                origin,
+                ClientBoundPresence {
+                    changed: vec![user],
+                    left: vec![],
+                },
+            )
+            .await;
+        }
+    }
+
+    async fn expire_idle(&self, now: Instant) {
+        let changed = self.roster.write().await.expire(now, IDLE_TIMEOUT);
+        if changed.is_empty() {
+            return;
+        }
+        self.broadcast_msg(
+            ClientBound::Presence(ClientBoundPresence {
+                changed,
+                left: vec![],
+            }),
+            self.connections.read().await.values(),
+        );
+    }
+
+    /// Sends the client every lock that's held
+    async fn send_locks(&self, client: &Client<C>) {
+        client.send(ClientBound::Locks(ClientBoundLocks {
+            locked: self.locks.read().await.all(),
+            unlocked: vec![],
+        }));
+    }
+
+    async fn handle_lock(
+        &self,
+        origin: ReplicaId,
+        ServerBoundLock { id, ts }: ServerBoundLock<C::Key>,
+    ) {
+        let locked = self
+            .locks
+            .write()
+            .await
+            .lock(id, origin, ts, Instant::now());
+        match locked {
+            Ok(lock) => self.broadcast_locks(vec![lock], vec![]).await,
+            // Only the client that lost needs to hear who has the lock
+            Err(held) => {
+                let msg = ClientBound::Locks(ClientBoundLocks {
+                    locked: vec![held],
+                    unlocked: vec![],
+                });
+                self.send_to(origin, msg).await;
+            }
+        }
+    }
+
+    async fn handle_unlock(
+        &self,
+        origin: ReplicaId,
+        ServerBoundUnlock { id }: ServerBoundUnlock<C::Key>,
+    ) {
+        if self.locks.write().await.unlock(&id, origin) {
+            self.broadcast_locks(vec![], vec![id]).await;
+        }
+    }
+
+    async fn release_locks(&self, owner: ReplicaId) {
+        let released = self.locks.write().await.release_all(owner);
+        if !released.is_empty() {
+            self.broadcast_locks(vec![], released).await;
+        }
+    }
+
+    async fn expire_locks(&self, now: Instant) {
+        let expired = self.locks.write().await.expire(now);
+        if !expired.is_empty() {
+            self.broadcast_locks(vec![], expired).await;
+        }
+    }
+
+    async fn broadcast_locks(&self, locked: Vec<Lock<C::Key>>, unlocked: Vec<C::Key>) {
+        self.broadcast_msg(
+            ClientBound::Locks(ClientBoundLocks { locked, unlocked }),
+            self.connections.read().await.values(),
+        );
+    }
+
+    /// Sends everyone the digest of the state if it's been long enough since the last one.
+    /// Updates are fire and forget, this is how clients that missed some catch up.
+    async fn anti_entropy(&self, now: Instant) {
+        // The digest of an end to end encrypted room is always that of an empty state
+        let (Some(interval), None) = (self.anti_entropy, &self.relay) else {
+            return;
+        };
+        let mut next = self.next_digest.lock().await;
+        if now < *next {
+            return;
+        }
+        *next = now + interval;
+        drop(next);
+
+        let digest = self.state.read().await.digest();
+        // Subscribed clients only have part of the state, their digests never match
+        let subscriptions = self.subscriptions.read().await;
+        self.broadcast_msg(
+            ClientBound::Digest(ClientBoundDigest { digest }),
+            (self.connections.read().await.values()).filter(|c| !subscriptions.is_subscribed(c.id)),
+        );
+    }
+
+    /// Everyone but `origin` hears about presence changes of `origin`
+    async fn broadcast_presence(&self, origin: ReplicaId, presence: ClientBoundPresence) {
+        self.broadcast_msg(
+            ClientBound::Presence(presence),
+            self.connections
+                .read()
+                .await
+                .values()
+                .filter(|c| c.id != origin),
+        );
+    }
+
+    async fn handle_cursor(&self, origin: ReplicaId, (x, y): (f32, f32)) {
+        let Some(pos) = self.roster.write().await.set_cursor(origin, (x, y)) else {
+            return;
+        };
+
+        self.broadcast_msg(
+            ClientBound::Cursor(ClientBoundCursor { pos: vec![pos] }),
+            self.connections
+                .read()
+                .await
+                .values()
+                .filter(|c| c.id != origin),
+        );
+    }
+
+    /// Merges the deltas unless the validator rejects them, in which case nobody gets them
+    async fn handle_update(
+        &self,
+        origin: &SessionToken,
+        deltas: C::Delta,
+    ) -> Result<(), Rejection<C::Key>> {
+        self.validator.validate(&deltas)?;
+        let affected = {
+            let mut state = self.state.write().await;
+            let affected = state.affected_keys(&deltas);
+            state.merge_delta_in_place(&deltas);
+            affected
+        };
+        self.dirty.store(true, Ordering::SeqCst);
+        self.advance(&C::delta_version(&deltas)).await;
+        self.history
+            .write()
+            .await
+            .push(history::now_ms(), deltas.clone());
+        tracing::trace!(?deltas, "merged update");
+
+        // Sessions of clients that are gone get the update too, to catch up when they resume
+        let seqs = self.sessions.lock().await.push(&self.id, origin, &deltas);
+        let state = self.state.read().await;
+        let mut subscriptions = self.subscriptions.write().await;
+        for client in self.connections.read().await.values() {
+            let Some(seq) = seqs.get(&client.session).copied() else {
+                continue;
+            };
+            let inside = |key: &C::Key, region: &Region| self.inside(&state, key, region);
+            let deltas = match subscriptions.filter(client.id, &affected, inside) {
+                None => deltas.clone(),
+                // The session keeps the whole update all the same, it's what a resumed session
+                // gets sent again
+                Some(keys) if keys.is_empty() => continue,
+                Some(keys) => C::delta_for_keys(&deltas, &keys),
+            };
+            let msg = ClientBound::Update(ClientBoundUpdate { seq, deltas });
+            // Stays in the session, so it's sent again if it doesn't make it
+            client.send(msg);
+        }
+        Ok(())
+    }
+
+    /// Logs the encrypted update and passes it on to everyone else, the relay log is all that's
+    /// kept of it
+    async fn handle_encrypted(&self, origin: ReplicaId, msg: ServerBoundEncrypted) {
+        let Some(relay) = &self.relay else {
+            return;
+        };
+        let entry = relay.write().await.push(origin, msg);
+        self.dirty.store(true, Ordering::SeqCst);
+        self.broadcast_msg(
+            ClientBound::Encrypted(entry),
+            self.connections
+                .read()
+                .await
+                .values()
+                .filter(|c| c.id != origin),
+        );
+    }
+
+    /// Sends the client everything in the relay log of an end to end encrypted room. Merging is
+    /// idempotent, so clients that resumed may get what they already have again.
+    async fn send_relayed(&self, client: &Client<C>) {
+        let Some(relay) = &self.relay else {
+            return;
+        };
+        for entry in relay.read().await.entries() {
+            client.send(ClientBound::Encrypted(entry.clone()));
+        }
+    }
+
+    async fn handle_history(
+        &self,
+        ServerBoundHistory { from_ts, to_ts }: ServerBoundHistory,
+    ) -> ClientBoundHistory<C> {
+        let (entries, truncated) = self.history.read().await.range(from_ts, to_ts);
+        ClientBoundHistory { entries, truncated }
+    }
+
+    /// Acks are saved along with the state, see [`Ctx::save`]
+    async fn handle_ack(&self, origin: &SessionToken, seq: u64) {
+        self.sessions.lock().await.ack(origin, seq);
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Sends the updates `client` didn't ack before it reconnected again, in order
+    async fn resend(&self, client: &Client<C>) {
+        let sessions = self.sessions.lock().await;
+        let Some(session) = sessions.get(&client.session) else {
+            return;
+        };
+        for (seq, deltas) in session.outbox.unacked() {
+            let msg = ClientBound::Update(ClientBoundUpdate {
+                seq: *seq,
+                deltas: deltas.clone(),
+            });
+            client.send(msg);
+        }
+    }
+
+    /// Everyone gets the merged state, subscribed clients only the part they subscribed to
+    async fn handle_sync(&self, remote_state: C) {
+        let mut state = self.state.write().await;
+        state.merge_in_place(&remote_state);
+        self.dirty.store(true, Ordering::SeqCst);
+        self.advance(&remote_state.version()).await;
+        let state = state.downgrade();
+        let keys = state.keys();
+        let mut subscriptions = self.subscriptions.write().await;
+        for client in self.connections.read().await.values() {
+            let inside = |key: &C::Key, region: &Region| self.inside(&state, key, region);
+            let msg = match subscriptions.filter(client.id, &keys, inside) {
+                None => ClientBound::Sync(ClientBoundSync {
+                    state: state.clone(),
+                }),
+                Some(keys) => ClientBound::Subscribed(ClientBoundSubscribed {
+                    deltas: state.extract(&keys),
+                }),
+            };
+            client.send(msg);
+        }
+    }
+
+    /// Replaces what the client subscribed to, the answer is the part of the state the
+    /// subscription covers. Without a locator regions cover everything.
+    async fn subscribe(
+        &self,
+        replica: ReplicaId,
+        subscription: Subscription<C::Key>,
+    ) -> ClientBound<C> {
+        let subscription = match (subscription, &self.locator) {
+            (Subscription::Region { .. }, None) => Subscription::All,
+            (subscription, _) => subscription,
+        };
+        let state = self.state.read().await;
+        let mut subscriptions = self.subscriptions.write().await;
+        subscriptions.subscribe(replica, subscription);
+        let inside = |key: &C::Key, region: &Region| self.inside(&state, key, region);
+        match subscriptions.filter(replica, &state.keys(), inside) {
+            None => ClientBound::Sync(ClientBoundSync {
+                state: state.clone(),
+            }),
+            Some(keys) => ClientBound::Subscribed(ClientBoundSubscribed {
+                deltas: state.extract(&keys),
+            }),
+        }
+    }
+
+    async fn unsubscribe(&self, replica: ReplicaId) {
+        (self.subscriptions.write().await).subscribe(replica, Subscription::All);
+    }
+
+    fn inside(&self, state: &C, key: &C::Key, region: &Region) -> bool {
+        (self.locator.as_ref()).is_some_and(|locator| locator.contains(state, key, region))
+    }
+
+    /// Only answers the client that sent `remote_digest`, nothing changes here until it sends its
+    /// own divergent keys back as an update
+    async fn handle_sync_digest(&self, remote_digest: &C::Digest) -> ClientBoundSyncDigest<C> {
+        let state = self.state.read().await;
+        ClientBoundSyncDigest {
+            digest: state.digest(),
+            deltas: state.divergent(remote_digest),
+        }
+    }
+
+    async fn send_to(&self, replica: ReplicaId, msg: ClientBound<C>) {
+        if let Some(client) = self.connections.read().await.get(&replica) {
+            client.send(msg);
+        }
+    }
+
+    async fn reject(&self, replica: ReplicaId, reason: Rejection<C::Key>) {
+        tracing::info!(?replica, ?reason, "rejected message");
+        let msg = ClientBound::Rejected(ClientBoundRejected { reason });
+        self.send_to(replica, msg).await
+    }
+
+    /// Only hands the message to the clients' send tasks, so a slow client doesn't hold up the
+    /// others
+    fn broadcast_msg<'a>(&self, msg: ClientBound<C>, clients: impl Iterator<Item = &'a Client<C>>) {
+        for client in clients {
+            client.send(msg.clone());
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Client<C: DeltaCrdt> {
+    id: ReplicaId,
+    session: SessionToken,
+    /// Authenticated clients may only add entries with their own replica's dots
+    authenticated: bool,
+    /// Another server of the cluster rather than a client, see [`crate::cluster`]
+    peer: bool,
+    /// Feeds the client's send task, see [`Client::send_loop`]
+    tx: mpsc::UnboundedSender<Outbound<C>>,
+    metrics: Arc<Metrics>,
+    rate: Rate,
+}
+
+/// A room joined by a client that's still shaking hands. The client leaves it again, along with
+/// the session it was given, unless the handshake gets to [`Joined::keep`].
+struct Joined<C: DeltaCrdt, S: Codec> {
+    ctx: Arc<Ctx<C, S>>,
+    room: Option<Arc<Room<C>>>,
+    session: Option<SessionToken>,
+}
+
+impl<C: DeltaCrdt, S: Codec> Joined<C, S> {
+    fn new(ctx: Arc<Ctx<C, S>>, room: Arc<Room<C>>) -> Self {
+        Self {
+            ctx,
+            room: Some(room),
+            session: None,
+        }
+    }
+
+    /// The handshake went through, the client leaves the room like any other from here on
+    fn keep(mut self) -> Arc<Room<C>> {
+        self.room.take().expect("the room is only taken once")
+    }
+}
+
+impl<C: DeltaCrdt, S: Codec> Deref for Joined<C, S> {
+    type Target = Arc<Room<C>>;
+
+    fn deref(&self) -> &Self::Target {
+        self.room.as_ref().expect("the room is only taken once")
+    }
+}
+
+impl<C: DeltaCrdt, S: Codec> Drop for Joined<C, S> {
+    fn drop(&mut self) {
+        let Some(room) = self.room.take() else {
+            return;
+        };
+        let ctx = self.ctx.clone();
+        let session = self.session.take();
+        tokio::spawn(async move {
+            if let Some(session) = session {
+                ctx.sessions
+                    .lock()
+                    .await
+                    .disconnect(&session, Instant::now());
+            }
+            ctx.release(room).await;
+        });
+    }
+}
+
+/// What the send task of a client is asked to do
+enum Outbound<C: DeltaCrdt> {
+    Msg(ClientBound<C>),
+    /// Sends what's queued, then closes the connection
+    Close(CloseFrame<'static>),
+}
+
+impl<C: DeltaCrdt> Client<C> {
+    async fn new<S: Codec>(
+        stream: TcpStream,
+        ctx: Arc<Ctx<C, S>>,
+    ) -> Result<(Self, Arc<Room<C>>, SplitStream<WebSocketStream<Stream>>)> {
+        let (ws_stream, uri) = accept(stream, ctx.tls.as_ref(), ctx.websocket).await?;
+        let token = query_param(&uri, "token");
+
+        let (mut w, mut r) = ws_stream.split();
+
+        let msg = r
+            .next()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Client did not send a message after connecting"))?
+            .with_context(|| "Error reading init message from client")?;
+        let msg: ServerBound<C> =
+            decode::<S, _>(msg).with_context(|| "Error parsing init message from client")?;
+        ctx.metrics.received(&msg);
+
+        let replica = msg
+            .replica_id()
+            .ok_or_else(|| anyhow!("Client did not send a sync message after connecting"))?;
+        let peer = matches!(msg, ServerBound::Sync(ServerBoundSync { peer: true, .. }));
+        if peer {
+            let secret = query_param(&uri, "cluster");
+            ctx.cluster
+                .authorize(secret.as_deref(), ctx.auth.is_open())
+                .with_context(|| format!("Failed to authenticate peer ({:?})", replica))?;
+        } else {
+            ctx.auth
+                .authorize(token.as_deref(), replica)
+                .with_context(|| format!("Failed to authenticate client ({:?})", replica))?;
+        }
+        let authenticated = !peer && !ctx.auth.is_open();
+        let causal = msg.causal().cloned();
+
+        // A session that can't be resumed is answered like a digest sync
+        let msg = match msg {
+            ServerBound::Resume(resume) => {
+                if ctx.resume(&resume).await {
+                    ServerBound::Resume(resume)
+                } else {
+                    ServerBound::SyncDigest(ServerBoundSyncDigest {
+                        replica_id: resume.replica_id,
+                        room_id: resume.room_id,
+                        digest: resume.digest,
+                        causal: resume.causal,
+                    })
+                }
+            }
+            msg => msg,
+        };
+
+        // Sessions are opened before the state is read, so updates that come in after it was read
+        // are logged to the session and sent once the client is connected
+        let (id, room, session, resumed) = match msg {
+            // Peers are answered with the whole state and don't get a session, nothing is sent
+            // to them again
+            ServerBound::Sync(ServerBoundSync {
+                replica_id,
+                room_id,
+                state: remote_state,
+                peer: true,
+                ..
+            }) => {
+                let room = ctx.open_room(&room_id).await.ok_or_else(|| {
+                    anyhow!("Peer linked room {:?} that isn't open here", room_id)
+                })?;
+                let room = Joined::new(ctx.clone(), room);
+                if room.relay.is_some() {
+                    return Err(anyhow!(
+                        "Peer linked end to end encrypted room {:?}",
+                        room_id
+                    ));
+                }
+                if !remote_state.is_empty() {
+                    room.handle_sync(remote_state).await;
+                }
+                let msg = ClientBound::Sync(ClientBoundSync {
+                    state: room.get_state().await,
+                });
+                ctx.metrics.sent(&msg);
+                w.send(Message::Binary(S::encode(&msg)?)).await?;
+
+                let session = format!("peer-{:032x}", ReplicaId::random().as_u128());
+                (replica_id, room, session, false)
+            }
+            ServerBound::Sync(ServerBoundSync {
+                replica_id,
+                room_id,
+                state: remote_state,
+                subscription,
+                ..
+            }) => {
+                let mut room = Joined::new(ctx.clone(), ctx.join(&room_id).await);
+                if authenticated {
+                    let known = room.version.read().await;
+                    auth::check_state(replica_id, &remote_state, &known)
+                        .map_err(|reason| anyhow!("Client sent forged state: {:?}", reason))?;
+                }
+                if room.relay.is_some() && !remote_state.is_empty() {
+                    return Err(anyhow!(
+                        "Client sent plaintext state to end to end encrypted room {:?}",
+                        room_id
+                    ));
+                }
+                let session = ctx.sessions.lock().await.open(replica_id, room_id);
+                room.session = Some(session.clone());
+                if !remote_state.is_empty() {
+                    room.replicate(Replicated::State(remote_state.clone()))
+                        .await;
+                    room.handle_sync(remote_state).await;
+                }
+                ctx.catch_up(&room, &session, causal.as_ref()).await?;
+
+                let subscription = subscription.unwrap_or(Subscription::All);
+                let msg = room.subscribe(replica_id, subscription).await;
+                ctx.metrics.sent(&msg);
+                w.send(Message::Binary(S::encode(&msg)?)).await?;
+
+                (replica_id, room, session, false)
+            }
+            ServerBound::SyncDigest(ServerBoundSyncDigest {
+                replica_id,
+                room_id,
+                digest,
+                ..
+            }) => {
+                let mut room = Joined::new(ctx.clone(), ctx.join(&room_id).await);
+                if room.relay.is_some() {
+                    return Err(anyhow!(
+                        "Client synced digests with end to end encrypted room {:?}",
+                        room_id
+                    ));
+                }
+                let session = ctx.sessions.lock().await.open(replica_id, room_id);
+                room.session = Some(session.clone());
+                ctx.catch_up(&room, &session, causal.as_ref()).await?;
+                room.unsubscribe(replica_id).await;
+                let msg = ClientBound::SyncDigest(room.handle_sync_digest(&digest).await);
+                ctx.metrics.sent(&msg);
+                w.send(Message::Binary(S::encode(&msg)?)).await?;
+
+                (replica_id, room, session, false)
+            }
+            // The updates it missed are sent once the client is connected
+            ServerBound::Resume(ServerBoundResume {
+                replica_id,
+                room_id,
+                token,
+                ..
+            }) => {
+                let mut room = Joined::new(ctx.clone(), ctx.join(&room_id).await);
+                room.session = Some(token.clone());
+                ctx.catch_up(&room, &token, causal.as_ref()).await?;
+                (replica_id, room, token, true)
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Client did not send a sync message after connecting"
+                ))
+            }
+        };
+
+        let msg = ClientBound::<C>::Session(ClientBoundSession {
+            token: session.clone(),
+            resumed,
+        });
+        ctx.metrics.sent(&msg);
+        w.send(Message::Binary(S::encode(&msg)?)).await?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::send_loop::<S>(id, w, rx, ctx.metrics.clone()));
+        let client = Self {
+            id,
+            session,
+            authenticated,
+            peer,
+            tx,
+            metrics: ctx.metrics.clone(),
+            rate: ctx.rate,
+        };
+        Ok((client, room.keep(), r))
+    }
+
+    async fn listen<S: Codec>(
+        client: &Client<C>,
+        mut r: SplitStream<WebSocketStream<Stream>>,
+        room: Arc<Room<C>>,
+    ) -> Result<()> {
+        let replica = client.id;
+        let session = &client.session;
+        let mut limit = RateLimit::new(client.rate, Instant::now());
+        while let Some(msg) = r.next().await {
+            let msg = match msg {
+                // Past the max message size of the websocket config
+                Err(tungstenite::Error::Capacity(e)) => {
+                    client.close(CloseCode::Size, "Message too large");
+                    return Err(e).with_context(|| "Client sent a message that's too large");
+                }
+                msg => msg?,
+            };
+            if !limit.allow(Instant::now()) {
+                client.close(CloseCode::Policy, "Too many messages");
+                return Err(anyhow!("Client sent too many messages"));
+            }
+            let msg: ServerBound<C> = decode::<S, _>(msg)?;
+            client.metrics.received(&msg);
+            tracing::debug!(kind = msg.kind(), "received");
+            if client.peer && !matches!(msg, ServerBound::Sync(_) | ServerBound::Update(_)) {
+                return Err(anyhow!("Peers only send syncs and updates"));
+            }
+            room.touch(replica).await;
+            // Only presence, cursors and locks are plaintext in end to end encrypted rooms
+            let room_encrypted = room.relay.is_some();
+            let allowed = match &msg {
+                ServerBound::Sync(ServerBoundSync { state, .. }) => {
+                    !room_encrypted || state.is_empty()
+                }
+                ServerBound::SyncDigest(_) | ServerBound::Update(_) | ServerBound::Subscribe(_) => {
+                    !room_encrypted
+                }
+                ServerBound::Encrypted(_) => room_encrypted,
+                _ => true,
+            };
+            if !allowed {
+                room.reject(replica, Rejection::Encryption { room_encrypted })
+                    .await;
+                continue;
+            }
+            match msg {
+                // Clients stay in the room they joined with, and subscribe with a
+                // `ServerBoundSubscribe` once they're in it
+                ServerBound::Sync(ServerBoundSync { state, .. }) => {
+                    let checked = if client.authenticated {
+                        auth::check_state(replica, &state, &*room.version.read().await)
+                    } else {
+                        Ok(())
+                    };
+                    match checked {
+                        Ok(()) => {
+                            if !client.peer {
+                                room.replicate(Replicated::State(state.clone())).await;
+                            }
+                            room.handle_sync(state).await;
+                        }
+                        Err(reason) => room.reject(replica, reason).await,
+                    }
+                }
+                ServerBound::SyncDigest(ServerBoundSyncDigest { digest, .. }) => {
+                    let msg = ClientBound::SyncDigest(room.handle_sync_digest(&digest).await);
+                    room.send_to(replica, msg).await;
+                }
+                ServerBound::Resume(_) => {
+                    return Err(anyhow!("Sessions can only be resumed when connecting"));
+                }
+                ServerBound::Update(ServerBoundUpdate { deltas }) => {
+                    let checked = if client.authenticated {
+                        auth::check_deltas::<C>(replica, &deltas, &*room.version.read().await)
+                    } else {
+                        Ok(())
+                    };
+                    // What peers send isn't passed on again
+                    let replicated = (!client.peer).then(|| deltas.clone());
+                    if let Err(reason) = checked {
+                        room.reject(replica, reason).await;
+                    } else if let Err(reason) = room.handle_update(session, deltas).await {
+                        room.reject(replica, reason).await;
+                    } else if let Some(deltas) = replicated {
+                        room.replicate(Replicated::Delta(deltas)).await;
+                    }
+                }
+                ServerBound::Ack(ServerBoundAck { seq }) => {
+                    room.handle_ack(session, seq).await;
+                }
+                ServerBound::Cursor(ServerBoundCursor { pos }) => {
+                    room.handle_cursor(replica, pos).await;
+                }
+                ServerBound::Presence(presence) => {
+                    room.handle_presence(replica, presence).await;
+                }
+                ServerBound::Lock(lock) => {
+                    room.handle_lock(replica, lock).await;
+                }
+                ServerBound::Unlock(unlock) => {
+                    room.handle_unlock(replica, unlock).await;
+                }
+                ServerBound::History(history) => {
+                    let msg = ClientBound::History(room.handle_history(history).await);
+                    room.send_to(replica, msg).await;
+                }
+                ServerBound::Encrypted(msg) => {
+                    room.handle_encrypted(replica, msg).await;
+                }
+                ServerBound::Subscribe(ServerBoundSubscribe { subscription }) => {
+                    let msg = room.subscribe(replica, subscription).await;
+                    room.send_to(replica, msg).await;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the message with the next flush
+    fn send(&self, msg: ClientBound<C>) {
+        // The send task only stops once every handle to the client is dropped
+        if self.tx.send(Outbound::Msg(msg)).is_ok() {
+            self.metrics.queued(1);
+        }
+    }
+
+    /// Closes the connection once the queued messages are sent
+    fn close(&self, code: CloseCode, reason: &'static str) {
+        let _ = self.tx.send(Outbound::Close(CloseFrame {
+            code,
+            reason: reason.into(),
+        }));
+    }
+
+    /// Owns the sending half of the socket. Messages are coalesced as they come in and sent
+    /// every [`FLUSH_INTERVAL`], until the client is closed or every handle to it is dropped.
+    async fn send_loop<S: Codec>(
+        id: ReplicaId,
+        mut write: SplitSink<WebSocketStream<Stream>, Message>,
+        mut rx: mpsc::UnboundedReceiver<Outbound<C>>,
+        metrics: Arc<Metrics>,
+    ) {
+        let mut outgoing = Outgoing::default();
+        // Messages received since the last flush, before they were coalesced
+        let mut pending = 0;
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                msg = rx.recv() => match msg {
+                    Some(Outbound::Msg(msg)) => {
+                        outgoing.push(msg);
+                        pending += 1;
+                    }
+                    Some(Outbound::Close(frame)) => {
+                        metrics.queued(-pending);
+                        Self::write_msgs::<S>(id, &mut write, outgoing.take(), &metrics).await;
+                        if let Err(e) = write.send(Message::Close(Some(frame))).await {
+                            tracing::warn!(replica = ?id, error = %e, "error closing connection");
+                        }
+                        return;
+                    }
+                    None => {
+                        metrics.queued(-pending);
+                        return;
+                    }
+                },
+                _ = interval.tick() => {
+                    metrics.queued(-std::mem::take(&mut pending));
+                    Self::write_msgs::<S>(id, &mut write, outgoing.take(), &metrics).await;
+                }
+            }
+        }
+    }
+
+    async fn write_msgs<S: Codec>(
+        id: ReplicaId,
+        write: &mut SplitSink<WebSocketStream<Stream>, Message>,
+        msgs: Vec<ClientBound<C>>,
+        metrics: &Metrics,
+    ) {
+        for msg in msgs {
+            metrics.sent(&msg);
+            let buf = match S::encode(&msg) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    tracing::warn!(replica = ?id, error = ?e, "error encoding message");
+                    continue;
+                }
+            };
+            if let Err(e) = write.feed(Message::Binary(buf)).await {
+                tracing::warn!(replica = ?id, error = %e, "error sending message");
+            }
+        }
+        if let Err(e) = write.flush().await {
+            tracing::warn!(replica = ?id, error = %e, "error sending message");
+        }
+    }
+}
+
+/// What a room passes on to the peers it's linked with
+#[derive(Clone)]
+enum Replicated<C: DeltaCrdt> {
+    State(C),
+    Delta(C::Delta),
+}
+
+/// A room's link to one peer, the task stops when the room drops it
+struct Link<C: DeltaCrdt> {
+    tx: mpsc::UnboundedSender<Replicated<C>>,
+    task: JoinHandle<()>,
+}
+
+impl<C: DeltaCrdt> Drop for Link<C> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Keeps the room linked with the peer at `url`, dialing it again whenever it hangs up. Only
+/// holds on to the room while it's using it, so the room can still close.
+async fn link<C: DeltaCrdt, S: Codec>(
+    room: Weak<Room<C>>,
+    url: String,
+    replica: ReplicaId,
+    mut rx: mpsc::UnboundedReceiver<Replicated<C>>,
+) {
+    loop {
+        match linked::<C, S>(&room, &url, replica, &mut rx).await {
+            Ok(()) => return,
+            Err(e) => tracing::debug!(error = ?e, "link to peer failed"),
+        }
+        tokio::time::sleep(LINK_RETRY_DELAY).await;
+    }
+}
+
+/// Syncs the room with the peer and passes on what's sent to `rx` until either end goes away,
+/// returns `Ok` once the room closed
+async fn linked<C: DeltaCrdt, S: Codec>(
+    room: &Weak<Room<C>>,
+    url: &str,
+    replica: ReplicaId,
+    rx: &mut mpsc::UnboundedReceiver<Replicated<C>>,
+) -> Result<()> {
+    let (ws_stream, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .with_context(|| "Failed to connect to peer")?;
+    let (mut w, mut r) = ws_stream.split();
+
+    // The state covers whatever was waiting to be passed on
+    while rx.try_recv().is_ok() {}
+    let Some(linked_room) = room.upgrade() else {
+        return Ok(());
+    };
+    let msg = ServerBound::Sync(ServerBoundSync {
+        replica_id: replica,
+        room_id: linked_room.id.clone(),
+        state: linked_room.get_state().await,
+        subscription: None,
+        causal: None,
+        peer: true,
+    });
+    drop(linked_room);
+    w.send(Message::Binary(S::encode(&msg)?)).await?;
+    tracing::info!("linked with peer");
+
+    loop {
+        tokio::select! {
+            msg = r.next() => {
+                let msg = msg.ok_or_else(|| anyhow!("Peer hung up"))??;
+                if !msg.is_binary() {
+                    continue;
+                }
+                let Some(room) = room.upgrade() else {
+                    return Ok(());
+                };
+                // Peers answer with their state, then only send what they're sent to everyone
+                if let ClientBound::Sync(ClientBoundSync { state }) = decode::<S, _>(msg)? {
+                    room.handle_sync(state).await;
+                }
+            }
+            replicated = rx.recv() => {
+                let msg = match replicated {
+                    Some(Replicated::State(state)) => ServerBound::Sync(ServerBoundSync {
+                        replica_id: replica,
+                        room_id: Default::default(),
+                        state,
+                        subscription: None,
+                        causal: None,
+                        peer: true,
+                    }),
+                    Some(Replicated::Delta(deltas)) => {
+                        ServerBound::Update(ServerBoundUpdate { deltas })
+                    }
+                    None => return Ok(()),
+                };
+                w.send(Message::Binary(S::encode(&msg)?)).await?;
+            }
+        }
+    }
+}
+
+/// Keeps the room subscribed to its channel on Redis, subscribing again whenever the connection
+/// fails. Like [`link`], only holds on to the room while it's using it.
+#[cfg(feature = "redis")]
+async fn bridge_loop<C: DeltaCrdt, S: Codec>(
+    room: Weak<Room<C>>,
+    bridge: Arc<Bridge>,
+    server: ReplicaId,
+    mut rx: mpsc::UnboundedReceiver<Replicated<C>>,
+) {
+    loop {
+        match bridged::<C, S>(&room, &bridge, server, &mut rx).await {
+            Ok(()) => return,
+            Err(e) => tracing::warn!(error = ?e, "bridge to Redis failed"),
+        }
+        tokio::time::sleep(LINK_RETRY_DELAY).await;
+    }
+}
+
+/// Says hello on the room's channel, then merges what the other servers publish to it and
+/// publishes what's sent to `rx`. Returns `Ok` once the room closed.
+#[cfg(feature = "redis")]
+async fn bridged<C: DeltaCrdt, S: Codec>(
+    room: &Weak<Room<C>>,
+    bridge: &Bridge,
+    server: ReplicaId,
+    rx: &mut mpsc::UnboundedReceiver<Replicated<C>>,
+) -> Result<()> {
+    let Some(bridged_room) = room.upgrade() else {
+        return Ok(());
+    };
+    let (mut channel, published) = bridge.join(&bridged_room.id).await?;
+    futures_util::pin_mut!(published);
+
+    // The state covers whatever was waiting to be published
+    while rx.try_recv().is_ok() {}
+    let state = bridged_room.get_state().await;
+    drop(bridged_room);
+    channel
+        .publish::<C, S>(&Bridged::Hello { server, state })
+        .await?;
+
+    loop {
+        tokio::select! {
+            msg = published.next() => {
+                let msg = msg.ok_or_else(|| anyhow!("Redis hung up"))?;
+                let msg: Bridged<C> = S::decode(&msg)?;
+                if msg.server() == server {
+                    continue;
+                }
+                let Some(room) = room.upgrade() else {
+                    return Ok(());
+                };
+                match msg {
+                    Bridged::Hello { state: remote_state, .. } => {
+                        room.handle_sync(remote_state).await;
+                        let state = room.get_state().await;
+                        channel.publish::<C, S>(&Bridged::State { server, state }).await?;
+                    }
+                    Bridged::State { state, .. } => room.handle_sync(state).await,
+                    // Goes through the validator like the updates of this server's own clients
+                    Bridged::Delta { deltas, .. } => {
+                        if let Err(reason) = room.handle_update(&BRIDGE_SESSION.into(), deltas).await {
+                            tracing::warn!(?reason, "rejected bridged update");
+                        }
+                    }
+                }
+            }
+            replicated = rx.recv() => {
+                let msg = match replicated {
+                    Some(Replicated::State(state)) => Bridged::State { server, state },
+                    Some(Replicated::Delta(deltas)) => Bridged::Delta { server, deltas },
+                    None => return Ok(()),
+                };
+                channel.publish::<C, S>(&msg).await?;
+            }
+        }
+    }
+}
+
+/// Origin of the updates from other servers, which isn't anyone's session
+#[cfg(feature = "redis")]
+const BRIDGE_SESSION: &str = "bridge";
+
+/// Plain TCP or TLS, depending on how the server was started
+pub(crate) trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+pub(crate) type Stream = Box<dyn Io>;
+
+/// Wraps the connection in TLS if `tls` is set and does the websocket handshake, returns the
+/// websocket along with the URL the client asked for
+pub(crate) async fn accept(
+    stream: TcpStream,
+    tls: Option<&TlsAcceptor>,
+    websocket: WebSocketConfig,
+) -> Result<(WebSocketStream<Stream>, Uri)> {
+    let stream: Stream = match tls {
+        Some(tls) => Box::new(
+            tls.accept(stream)
+                .await
+                .with_context(|| "Error during the TLS handshake occurred")?,
+        ),
+        None => Box::new(stream),
+    };
+
+    let mut uri = Uri::default();
+    // The error type of the callback is tungstenite's
+    #[allow(clippy::result_large_err)]
+    let ws_stream = tokio_tungstenite::accept_hdr_async_with_config(
+        stream,
+        |req: &Request, res: Response| {
+            uri = req.uri().clone();
+            Ok(res)
+        },
+        Some(websocket),
+    )
+    .await
+    .with_context(|| "Error during the websocket handshake occurred")?;
+    Ok((ws_stream, uri))
+}
+
+/// Protocol messages only come in binary messages
+pub(crate) fn decode<S: Codec, T: DeserializeOwned>(msg: Message) -> Result<T> {
+    if !msg.is_binary() {
+        return Err(anyhow!("Expected binary message but got: {:?}", msg));
+    }
+    S::decode(&msg.into_data()).with_context(|| "Failed to deserialize message")
+}
+
+/// Value of the `name` query parameter of the websocket URL, percent-decoded
+pub(crate) fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    form_urlencoded::parse(uri.query()?.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Answers every request with the metrics, it's only meant to be scraped
+async fn serve_metrics<C: DeltaCrdt, S: Codec>(ctx: Arc<Ctx<C, S>>, listener: TcpListener) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!(error = ?e, "error accepting metrics connection");
+                continue;
+            }
+        };
+        // A scraper that's slow or never sends its request only holds up itself
+        tokio::spawn(answer_metrics(ctx.clone(), stream));
+    }
+}
+
+/// How long a metrics connection gets to send its request before it's answered anyway
+const METRICS_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn answer_metrics<C: DeltaCrdt, S: Codec>(ctx: Arc<Ctx<C, S>>, mut stream: TcpStream) {
+    // Whatever was asked for, the request is only read so closing doesn't reset the connection
+    // before the client read the response
+    let mut buf = [0; 1024];
+    let _ = tokio::time::timeout(METRICS_READ_TIMEOUT, stream.read(&mut buf)).await;
+    let body = ctx.metrics.render(&ctx.gauges().await);
+    let res = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(res.as_bytes()).await {
+        tracing::warn!(error = ?e, "error sending metrics");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use clap::Parser;
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+    use tokio::sync::mpsc;
+
+    use super::{Client, Ctx, Joined};
+    use crate::codec::MsgPack;
+    use crate::config::Config;
+    use crate::proto::{RoomId, ServerBoundResume};
+    use crate::validate::Validator;
+
+    type Map = AWORMap<SquareId, Square>;
+
+    fn ctx() -> Ctx<Map, MsgPack> {
+        let config = Config::parse_from(["server", "--anti-entropy", "1"]);
+        let validator: Arc<dyn Validator<Map>> = Arc::new(Vec::<Box<dyn Validator<Map>>>::new());
+        Ctx::new(None, validator, &config).unwrap()
+    }
+
+    async fn client(ctx: &Ctx<Map, MsgPack>, id: ReplicaId, room: &RoomId) -> Client<Map> {
+        let (tx, _) = mpsc::unbounded_channel();
+        Client {
+            id,
+            session: ctx.sessions.lock().await.open(id, room.clone()),
+            authenticated: false,
+            peer: false,
+            tx,
+            metrics: ctx.metrics.clone(),
+            rate: ctx.rate,
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_while_a_tick_holds_the_room() {
+        let ctx = ctx();
+        let id = RoomId::from("room");
+        let alice = client(&ctx, ReplicaId::from(1), &id).await;
+        let room = ctx.join(&id).await;
+        room.add_connection(alice.clone()).await;
+
+        // The digest is due, so the tick holds on to the room until it can read the state
+        let state = room.state.write().await;
+        let tick = tokio::spawn({
+            let room = room.clone();
+            async move {
+                room.anti_entropy(Instant::now() + Duration::from_secs(2))
+                    .await
+            }
+        });
+        tokio::task::yield_now().await;
+
+        ctx.leave(room.clone(), &alice).await;
+        assert!(ctx.rooms.lock().await.is_empty());
+
+        // Joining again while it's still around picks the same room back up
+        let again = ctx.join(&id).await;
+        assert!(Arc::ptr_eq(&room, &again));
+        drop(state);
+        tick.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_handshakes_leave_the_room() {
+        let ctx = Arc::new(ctx());
+        let id = RoomId::from("room");
+        let alice = ReplicaId::from(1);
+        let mut joined = Joined::new(ctx.clone(), ctx.join(&id).await);
+        let session = ctx.sessions.lock().await.open(alice, id.clone());
+        joined.session = Some(session.clone());
+        drop(joined);
+
+        // Leaving is spawned, since dropping can't wait for it
+        let closed = async {
+            while !ctx.rooms.lock().await.is_empty() {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), closed)
+            .await
+            .unwrap();
+        let mut sessions = ctx.sessions.lock().await;
+        sessions.expire(
+            Instant::now() + Duration::from_secs(60 * 60),
+            Duration::ZERO,
+        );
+        assert!(sessions.get(&session).is_none());
+    }
+
+    #[tokio::test]
+    async fn bad_tokens_dont_open_the_room() {
+        let ctx = ctx();
+        let resume = ServerBoundResume::<Map> {
+            replica_id: ReplicaId::from(1),
+            room_id: RoomId::from("room"),
+            token: "nope".into(),
+            seq: 0,
+            digest: Default::default(),
+            causal: None,
+        };
+        assert!(!ctx.resume(&resume).await);
+        assert!(ctx.rooms.lock().await.is_empty());
+    }
+}
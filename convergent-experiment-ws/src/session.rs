@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use convergent_experiment_protocol::ReplicaId;
+use serde::{Deserialize, Serialize};
+
+use crate::outbox::Outbox;
+use crate::proto::{RoomId, SessionToken};
+
+/// How long a disconnected client can take to come back and resume its session
+pub const SESSION_TTL: Duration = Duration::from_secs(2 * 60);
+
+/// Saved along with the snapshot of its room, see [`Sessions::restore`]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session<D> {
+    pub replica: ReplicaId,
+    pub room: RoomId,
+    /// Updates of the room the client hasn't acked, including the ones made while it was gone
+    pub outbox: Outbox<D>,
+    #[serde(skip)]
+    disconnected: Option<Instant>,
+}
+
+/// Sessions of the clients across all rooms. A session outlives its connection for
+/// [`SESSION_TTL`], so a client that reconnects in time only gets the updates it missed instead
+/// of syncing.
+#[derive(Debug, Default)]
+pub struct Sessions<D> {
+    sessions: HashMap<SessionToken, Session<D>>,
+}
+
+impl<D: Clone + Default> Sessions<D> {
+    pub fn open(&mut self, replica: ReplicaId, room: RoomId) -> SessionToken {
+        let token = format!("{:032x}", ReplicaId::random().as_u128());
+        self.sessions.insert(
+            token.clone(),
+            Session {
+                replica,
+                room,
+                outbox: Outbox::default(),
+                disconnected: None,
+            },
+        );
+        token
+    }
+
+    /// Picks the session back up if it belongs to the client, hasn't expired, and still has
+    /// every update after `seq`. The client acked everything up to `seq` in its last connection.
+    pub fn resume(
+        &mut self,
+        token: &SessionToken,
+        replica: ReplicaId,
+        room: &RoomId,
+        seq: u64,
+    ) -> bool {
+        let Some(session) = self.sessions.get_mut(token) else {
+            return false;
+        };
+        if session.replica != replica || session.room != *room || !session.outbox.covers(seq) {
+            return false;
+        }
+        session.outbox.ack(seq);
+        session.disconnected = None;
+        true
+    }
+
+    pub fn get(&self, token: &SessionToken) -> Option<&Session<D>> {
+        self.sessions.get(token)
+    }
+
+    pub fn ack(&mut self, token: &SessionToken, seq: u64) {
+        if let Some(session) = self.sessions.get_mut(token) {
+            session.outbox.ack(seq);
+        }
+    }
+
+    /// Keeps the update for every session in `room` but the one it came from, returns the
+    /// sequence number it got in each
+    pub fn push(
+        &mut self,
+        room: &RoomId,
+        origin: &SessionToken,
+        deltas: &D,
+    ) -> HashMap<SessionToken, u64> {
+        self.sessions
+            .iter_mut()
+            .filter(|(token, session)| session.room == *room && *token != origin)
+            .map(|(token, session)| (token.clone(), session.outbox.push(deltas.clone())))
+            .collect()
+    }
+
+    /// The sessions in `room` along with what their clients acked, to be saved with its snapshot
+    pub fn in_room(&self, room: &RoomId) -> HashMap<SessionToken, Session<D>> {
+        self.sessions
+            .iter()
+            .filter(|(_, session)| session.room == *room)
+            .map(|(token, session)| (token.clone(), session.clone()))
+            .collect()
+    }
+
+    /// Picks up the sessions saved with a room after a restart, so their clients can resume them
+    /// instead of syncing. They count as disconnected from `now`, and the ones still around are
+    /// newer than what was saved.
+    pub fn restore(&mut self, saved: HashMap<SessionToken, Session<D>>, now: Instant) {
+        for (token, session) in saved {
+            self.sessions.entry(token).or_insert(Session {
+                disconnected: Some(now),
+                ..session
+            });
+        }
+    }
+
+    pub fn disconnect(&mut self, token: &SessionToken, now: Instant) {
+        if let Some(session) = self.sessions.get_mut(token) {
+            session.disconnected = Some(now);
+        }
+    }
+
+    /// Drops the sessions whose clients have been gone for longer than `ttl`
+    pub fn expire(&mut self, now: Instant, ttl: Duration) {
+        self.sessions.retain(|_, session| {
+            session
+                .disconnected
+                .is_none_or(|at| now.saturating_duration_since(at) <= ttl)
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use convergent_experiment_protocol::ReplicaId;
+
+    use super::{Session, Sessions, SESSION_TTL};
+    use crate::codec::MsgPack;
+    use crate::persist::Store;
+
+    #[test]
+    fn resume_after_missing_updates() {
+        let a = ReplicaId::from(1);
+        let b = ReplicaId::from(2);
+        let room = "room".to_string();
+        let start = Instant::now();
+
+        let mut sessions = Sessions::default();
+        let a_token = sessions.open(a, room.clone());
+        let b_token = sessions.open(b, room.clone());
+        let other = sessions.open(b, "other".to_string());
+
+        let seqs = sessions.push(&room, &b_token, &());
+        assert_eq!(seqs.into_iter().collect::<Vec<_>>(), [(a_token.clone(), 1)]);
+        sessions.ack(&a_token, 1);
+
+        // a misses two updates while it's gone
+        sessions.disconnect(&a_token, start);
+        sessions.push(&room, &b_token, &());
+        sessions.push(&room, &b_token, &());
+
+        assert!(!sessions.resume(&a_token, b, &room, 1));
+        assert!(!sessions.resume(&a_token, a, &"other".to_string(), 1));
+        assert!(sessions.resume(&a_token, a, &room, 1));
+        let unacked = sessions.get(&a_token).unwrap().outbox.unacked();
+        assert_eq!(unacked.map(|(seq, _)| *seq).collect::<Vec<_>>(), [2, 3]);
+
+        sessions.disconnect(&other, start);
+        sessions.disconnect(&a_token, start + Duration::from_secs(5));
+        sessions.expire(start + Duration::from_secs(10), Duration::from_secs(8));
+        assert!(sessions.get(&other).is_none());
+        assert!(sessions.get(&a_token).is_some());
+        assert!(sessions.get(&b_token).is_some());
+    }
+
+    #[tokio::test]
+    async fn resume_after_restart() {
+        let dir = std::env::temp_dir().join(format!("ws-sessions-{}", ReplicaId::random()));
+        let store = Store::<MsgPack>::open(&dir).await.unwrap();
+        let (a, b) = (ReplicaId::from(1), ReplicaId::from(2));
+        let room = "room".to_string();
+
+        let mut sessions = Sessions::<u32>::default();
+        let a_token = sessions.open(a, room.clone());
+        let b_token = sessions.open(b, room.clone());
+        sessions.open(a, "other".to_string());
+        for deltas in 1..=3 {
+            sessions.push(&room, &b_token, &deltas);
+        }
+        sessions.ack(&a_token, 1);
+        store
+            .save_sessions(&room, &sessions.in_room(&room))
+            .await
+            .unwrap();
+
+        // The server restarts and opens the room again
+        let now = Instant::now();
+        let mut restarted = Sessions::default();
+        let saved: HashMap<_, Session<u32>> = store.load_sessions(&room).await.unwrap().unwrap();
+        assert_eq!(saved.len(), 2);
+        restarted.restore(saved, now);
+        assert!(!restarted.resume(&a_token, a, &room, 4));
+        assert!(restarted.resume(&a_token, a, &room, 1));
+        let unacked = restarted.get(&a_token).unwrap().outbox.unacked();
+        assert_eq!(unacked.cloned().collect::<Vec<_>>(), [(2, 2), (3, 3)]);
+        // Numbering carries on where it left off
+        assert_eq!(restarted.push(&room, &b_token, &4)[&a_token], 4);
+
+        // Sessions nobody resumed expire like any other
+        restarted.expire(now + SESSION_TTL * 2, SESSION_TTL);
+        assert!(restarted.get(&a_token).is_some());
+        assert!(restarted.get(&b_token).is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
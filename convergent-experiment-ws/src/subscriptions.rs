@@ -0,0 +1,119 @@
+use std::collections::{BTreeSet, HashMap};
+
+use convergent_experiment_protocol::ReplicaId;
+
+use crate::proto::{Region, Subscription};
+
+#[derive(Debug)]
+struct Subscribed<K> {
+    subscription: Subscription<K>,
+    /// Keys inside the region when the client last heard of them, so it hears about them
+    /// leaving it
+    visible: BTreeSet<K>,
+}
+
+/// What the clients of a room subscribed to, clients that didn't get everything. Subscriptions
+/// stay when a client leaves so a resumed session keeps its own, a client that syncs again starts
+/// over.
+#[derive(Debug)]
+pub struct Subscriptions<K> {
+    clients: HashMap<ReplicaId, Subscribed<K>>,
+}
+
+impl<K> Default for Subscriptions<K> {
+    fn default() -> Self {
+        Self {
+            clients: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Clone + Ord> Subscriptions<K> {
+    /// Replaces the client's subscription. What it covers of the state is up to [`Self::filter`]
+    /// of every key, which also notes the keys inside a region.
+    pub fn subscribe(&mut self, replica: ReplicaId, subscription: Subscription<K>) {
+        if subscription == Subscription::All {
+            self.clients.remove(&replica);
+            return;
+        }
+        let subscribed = Subscribed {
+            subscription,
+            visible: BTreeSet::new(),
+        };
+        self.clients.insert(replica, subscribed);
+    }
+
+    pub fn is_subscribed(&self, replica: ReplicaId) -> bool {
+        self.clients.contains_key(&replica)
+    }
+
+    /// Which of the keys an update `affected` the client hears about, `None` for all of them.
+    /// `inside` tells whether the entry at a key lies within a region after the update.
+    pub fn filter(
+        &mut self,
+        replica: ReplicaId,
+        affected: &[K],
+        inside: impl Fn(&K, &Region) -> bool,
+    ) -> Option<Vec<K>> {
+        let subscribed = self.clients.get_mut(&replica)?;
+        let keys = match &subscribed.subscription {
+            Subscription::All => return None,
+            Subscription::Keys { keys } => (affected.iter())
+                .filter(|key| keys.contains(key))
+                .cloned()
+                .collect(),
+            Subscription::Region { region } => (affected.iter())
+                .filter(|key| {
+                    let was_inside = subscribed.visible.remove(*key);
+                    let is_inside = inside(key, region);
+                    if is_inside {
+                        subscribed.visible.insert((*key).clone());
+                    }
+                    was_inside || is_inside
+                })
+                .cloned()
+                .collect(),
+        };
+        Some(keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::ReplicaId;
+
+    use crate::proto::{Region, Subscription};
+
+    use super::Subscriptions;
+
+    #[test]
+    fn region_follows_entries_out() {
+        let a = ReplicaId::from(1);
+        let b = ReplicaId::from(2);
+        let region = Region {
+            x: 0.0,
+            y: 0.0,
+            width: 10.0,
+            height: 10.0,
+        };
+
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.subscribe(a, Subscription::Region { region });
+        subscriptions.subscribe(b, Subscription::Keys { keys: vec![2] });
+        let inside = |key: &u32, _: &Region| *key == 1;
+        assert_eq!(subscriptions.filter(a, &[1, 2], inside), Some(vec![1]));
+
+        // 1 leaves the region and 2 enters it
+        let inside = |key: &u32, _: &Region| *key == 2;
+        assert_eq!(
+            subscriptions.filter(a, &[1, 2, 3], inside),
+            Some(vec![1, 2])
+        );
+        assert_eq!(subscriptions.filter(a, &[1, 2, 3], inside), Some(vec![2]));
+        assert_eq!(subscriptions.filter(b, &[1, 2, 3], inside), Some(vec![2]));
+
+        subscriptions.subscribe(b, Subscription::All);
+        assert!(!subscriptions.is_subscribed(b));
+        assert_eq!(subscriptions.filter(b, &[1], inside), None);
+    }
+}
@@ -0,0 +1,15 @@
+use crate::crdt::DeltaCrdt;
+use crate::proto::Rejection;
+
+/// Checks the deltas clients send before they're merged into a room's state
+pub trait Validator<C: DeltaCrdt>: Send + Sync {
+    fn validate(&self, deltas: &C::Delta) -> Result<(), Rejection<C::Key>>;
+}
+
+/// Runs every validator in order, the first rejection wins. An empty list lets everything
+/// through.
+impl<C: DeltaCrdt> Validator<C> for Vec<Box<dyn Validator<C>>> {
+    fn validate(&self, deltas: &C::Delta) -> Result<(), Rejection<C::Key>> {
+        self.iter().try_for_each(|v| v.validate(deltas))
+    }
+}
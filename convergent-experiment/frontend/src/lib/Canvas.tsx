@@ -1,4 +1,4 @@
-import { RefObject, useRef } from "react";
+import { RefObject, useEffect, useRef } from "react";
 import { useDrag } from "@use-gesture/react";
 import { Square, SquareId } from "./proto/types";
 import { useAppState } from "./state";
@@ -11,6 +11,21 @@ const clamp = (num: number, min: number, max: number) =>
 const Canvas = () => {
   const squares = useAppState((state) => state.squares);
   const ref = useRef<SVGSVGElement>(null);
+
+  useEffect(() => {
+    const onKeyDown = (e: KeyboardEvent) => {
+      if (!(e.ctrlKey || e.metaKey) || e.key.toLowerCase() !== "z") return;
+      e.preventDefault();
+      if (e.shiftKey) {
+        Interactions.redo();
+      } else {
+        Interactions.undo();
+      }
+    };
+    window.addEventListener("keydown", onKeyDown);
+    return () => window.removeEventListener("keydown", onKeyDown);
+  }, []);
+
   return (
     <svg
       onMouseMove={(e) => {
@@ -73,9 +88,12 @@ type Props = {
 
 const Square = ({ id, square, svg: svgRef }: Props) => {
   const cachedPoint = useRef<DOMPoint | undefined>(undefined);
-  const bind = useDrag(({ down, xy: [mx, my], event }) => {
+  const bind = useDrag(({ down, first, last, xy: [mx, my], event }) => {
     const svg = svgRef.current;
     if (svg === null) return;
+    // Undo a whole drag at once instead of every single move
+    if (first) useAppState.getState().local.startUndoGroup();
+    if (last) useAppState.getState().local.endUndoGroup();
     // event.stopPropagation();
     // event.preventDefault();
     let point = cachedPoint.current;
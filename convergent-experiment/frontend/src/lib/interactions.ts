@@ -32,3 +32,25 @@ export const removeSquare = (id: SquareId) => {
   const deltas = useAppState.getState().deltas();
   useWebsocketStore.actions.send(state, { type: "update", deltas });
 };
+
+const sendDeltas = () => {
+  const state = useWebsocketStore.getState();
+  if (state.kind !== "connected") {
+    console.error("Websocket not connected");
+    return;
+  }
+  const deltas = useAppState.getState().deltas();
+  useWebsocketStore.actions.send(state, { type: "update", deltas });
+};
+
+export const undo = () => {
+  if (useAppState.getState().local.undo()) {
+    sendDeltas();
+  }
+};
+
+export const redo = () => {
+  if (useAppState.getState().local.redo()) {
+    sendDeltas();
+  }
+};
@@ -16,13 +16,59 @@ export type Imports = {
 };
 
 export type Exports = {
+    ackDeltas?: (seq: number) => void;
+    compact?: () => void;
+    counterDecrement?: (replica: types.ReplicaId) => void;
+    counterDeltas?: () => types.PNCounter;
+    counterGet?: () => types.PNCounter;
+    counterIncrement?: (replica: types.ReplicaId) => void;
+    counterMergeDeltas?: (delta: types.PNCounter) => void;
+    counterValue?: () => bigint;
+    createDoc?: (doc: types.DocId) => boolean;
     del?: (replica: types.ReplicaId, id: types.SquareId) => void;
     deltas?: () => Deltas<SquareId, Square>;
+    diff?: (remote: types.MapDigest) => Array<types.SquareId>;
+    digest?: () => types.MapDigest;
+    docDel?: (doc: types.DocId, replica: types.ReplicaId, id: types.SquareId) => types.Result<void, types.CrdtError>;
+    docDeltas?: (doc: types.DocId) => Deltas<SquareId, Square>;
+    docGet?: (doc: types.DocId) => types.AWORMap<types.SquareId, types.Square> | null;
+    docMergeDeltas?: (doc: types.DocId, delta: Deltas<SquareId, Square>) => types.Result<void, types.CrdtError>;
+    docSet?: (doc: types.DocId, replica: types.ReplicaId, id: types.SquareId, square: types.Square) => types.Result<void, types.CrdtError>;
+    dropDoc?: (doc: types.DocId) => boolean;
+    endUndoGroup?: () => void;
+    extract?: (keys: Array<types.SquareId>) => Deltas<SquareId, Square>;
     get?: () => types.AWORMap<types.SquareId, types.Square>;
-    merge?: (other: types.AWORMap<types.SquareId, types.Square>) => types.AWORMap<types.SquareId, types.Square>;
-    mergeDeltas?: (delta: Deltas<SquareId, Square>) => void;
-    replace?: (map: types.AWORMap<types.SquareId, types.Square>) => void;
+    getEncoded?: () => types.Result<ArrayBuffer, types.CrdtError>;
+    likesDecrement?: (replica: types.ReplicaId, id: types.SquareId) => void;
+    likesDeltas?: () => types.CounterMap<types.SquareId>;
+    likesGet?: () => types.CounterMap<types.SquareId>;
+    likesIncrement?: (replica: types.ReplicaId, id: types.SquareId) => void;
+    likesMergeDeltas?: (delta: types.CounterMap<types.SquareId>) => void;
+    likesRemove?: (id: types.SquareId) => void;
+    likesValue?: (id: types.SquareId) => bigint;
+    merge?: (other: types.AWORMap<types.SquareId, types.Square>) => types.Result<types.AWORMap<types.SquareId, types.Square>, types.CrdtError>;
+    mergeDeltas?: (delta: Deltas<SquareId, Square>) => types.Result<void, types.CrdtError>;
+    mergeEncoded?: (bytes: ArrayBuffer) => types.Result<void, types.CrdtError>;
+    pendingDeltas?: () => types.PendingDeltas;
+    redo?: (replica: types.ReplicaId) => boolean;
+    registerDeltas?: () => types.DotKernel<string>;
+    registerGet?: () => Array<string>;
+    registerMergeDeltas?: (delta: types.DotKernel<string>) => types.Result<void, types.CrdtError>;
+    registerSet?: (replica: types.ReplicaId, value: string) => void;
+    registerState?: () => types.MVReg<string>;
+    replace?: (map: types.AWORMap<types.SquareId, types.Square>) => types.Result<void, types.CrdtError>;
     set?: (replica: types.ReplicaId, id: types.SquareId, square: types.Square) => void;
+    startUndoGroup?: () => void;
+    stateSize?: () => types.SizeReport;
+    tableDeltas?: () => types.Result<ArrayBuffer, types.CrdtError>;
+    tableGet?: () => Array<Array<Array<string>>>;
+    tableInsertColumn?: (replica: types.ReplicaId, index: number) => boolean;
+    tableInsertRow?: (replica: types.ReplicaId, index: number) => boolean;
+    tableMergeDeltas?: (bytes: ArrayBuffer) => types.Result<void, types.CrdtError>;
+    tableRemoveColumn?: (index: number) => boolean;
+    tableRemoveRow?: (index: number) => boolean;
+    tableSet?: (replica: types.ReplicaId, row: number, column: number, value: string) => boolean;
+    undo?: (replica: types.ReplicaId) => boolean;
 };
 
 /**
@@ -160,6 +206,72 @@ export async function createRuntime(
     const free = getExport<(ptr: FatPtr) => void>("__fp_free");
 
     return {
+        ackDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_ack_deltas as any;
+            if (!export_fn) return;
+
+            return (seq: number) => export_fn(seq);
+        })(),
+        compact: (() => {
+            const export_fn = instance.exports.__fp_gen_compact as any;
+            if (!export_fn) return;
+
+            return () => export_fn();
+        })(),
+        counterDecrement: (() => {
+            const export_fn = instance.exports.__fp_gen_counter_decrement as any;
+            if (!export_fn) return;
+
+            return (replica: types.ReplicaId) => {
+                const replica_ptr = serializeObject(replica);
+                export_fn(replica_ptr);
+            };
+        })(),
+        counterDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_counter_deltas as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.PNCounter>(export_fn());
+        })(),
+        counterGet: (() => {
+            const export_fn = instance.exports.__fp_gen_counter_get as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.PNCounter>(export_fn());
+        })(),
+        counterIncrement: (() => {
+            const export_fn = instance.exports.__fp_gen_counter_increment as any;
+            if (!export_fn) return;
+
+            return (replica: types.ReplicaId) => {
+                const replica_ptr = serializeObject(replica);
+                export_fn(replica_ptr);
+            };
+        })(),
+        counterMergeDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_counter_merge_deltas as any;
+            if (!export_fn) return;
+
+            return (delta: types.PNCounter) => {
+                const delta_ptr = serializeObject(delta);
+                export_fn(delta_ptr);
+            };
+        })(),
+        counterValue: (() => {
+            const export_fn = instance.exports.__fp_gen_counter_value as any;
+            if (!export_fn) return;
+
+            return () => interpretBigSign(export_fn(), 9223372036854775808n);
+        })(),
+        createDoc: (() => {
+            const export_fn = instance.exports.__fp_gen_create_doc as any;
+            if (!export_fn) return;
+
+            return (doc: types.DocId) => {
+                const doc_ptr = serializeObject(doc);
+                return !!export_fn(doc_ptr);
+            };
+        })(),
         del: (() => {
             const export_fn = instance.exports.__fp_gen_del as any;
             if (!export_fn) return;
@@ -176,19 +288,174 @@ export async function createRuntime(
 
             return () => parseObject<Deltas<SquareId, Square>>(export_fn());
         })(),
+        diff: (() => {
+            const export_fn = instance.exports.__fp_gen_diff as any;
+            if (!export_fn) return;
+
+            return (remote: types.MapDigest) => {
+                const remote_ptr = serializeObject(remote);
+                return parseObject<Array<types.SquareId>>(export_fn(remote_ptr));
+            };
+        })(),
+        digest: (() => {
+            const export_fn = instance.exports.__fp_gen_digest as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.MapDigest>(export_fn());
+        })(),
+        docDel: (() => {
+            const export_fn = instance.exports.__fp_gen_doc_del as any;
+            if (!export_fn) return;
+
+            return (doc: types.DocId, replica: types.ReplicaId, id: types.SquareId) => {
+                const doc_ptr = serializeObject(doc);
+                const replica_ptr = serializeObject(replica);
+                const id_ptr = serializeObject(id);
+                return parseObject<types.Result<void, types.CrdtError>>(export_fn(doc_ptr, replica_ptr, id_ptr));
+            };
+        })(),
+        docDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_doc_deltas as any;
+            if (!export_fn) return;
+
+            return (doc: types.DocId) => {
+                const doc_ptr = serializeObject(doc);
+                return parseObject<Deltas<SquareId, Square>>(export_fn(doc_ptr));
+            };
+        })(),
+        docGet: (() => {
+            const export_fn = instance.exports.__fp_gen_doc_get as any;
+            if (!export_fn) return;
+
+            return (doc: types.DocId) => {
+                const doc_ptr = serializeObject(doc);
+                return parseObject<types.AWORMap<types.SquareId, types.Square> | null>(export_fn(doc_ptr));
+            };
+        })(),
+        docMergeDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_doc_merge_deltas as any;
+            if (!export_fn) return;
+
+            return (doc: types.DocId, delta: Deltas<SquareId, Square>) => {
+                const doc_ptr = serializeObject(doc);
+                const delta_ptr = serializeObject(delta);
+                return parseObject<types.Result<void, types.CrdtError>>(export_fn(doc_ptr, delta_ptr));
+            };
+        })(),
+        docSet: (() => {
+            const export_fn = instance.exports.__fp_gen_doc_set as any;
+            if (!export_fn) return;
+
+            return (doc: types.DocId, replica: types.ReplicaId, id: types.SquareId, square: types.Square) => {
+                const doc_ptr = serializeObject(doc);
+                const replica_ptr = serializeObject(replica);
+                const id_ptr = serializeObject(id);
+                const square_ptr = serializeObject(square);
+                return parseObject<types.Result<void, types.CrdtError>>(export_fn(doc_ptr, replica_ptr, id_ptr, square_ptr));
+            };
+        })(),
+        dropDoc: (() => {
+            const export_fn = instance.exports.__fp_gen_drop_doc as any;
+            if (!export_fn) return;
+
+            return (doc: types.DocId) => {
+                const doc_ptr = serializeObject(doc);
+                return !!export_fn(doc_ptr);
+            };
+        })(),
+        endUndoGroup: (() => {
+            const export_fn = instance.exports.__fp_gen_end_undo_group as any;
+            if (!export_fn) return;
+
+            return () => export_fn();
+        })(),
+        extract: (() => {
+            const export_fn = instance.exports.__fp_gen_extract as any;
+            if (!export_fn) return;
+
+            return (keys: Array<types.SquareId>) => {
+                const keys_ptr = serializeObject(keys);
+                return parseObject<Deltas<SquareId, Square>>(export_fn(keys_ptr));
+            };
+        })(),
         get: (() => {
             const export_fn = instance.exports.__fp_gen_get as any;
             if (!export_fn) return;
 
             return () => parseObject<types.AWORMap<types.SquareId, types.Square>>(export_fn());
         })(),
+        getEncoded: (() => {
+            const export_fn = instance.exports.__fp_gen_get_encoded as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.Result<ArrayBuffer, types.CrdtError>>(export_fn());
+        })(),
+        likesDecrement: (() => {
+            const export_fn = instance.exports.__fp_gen_likes_decrement as any;
+            if (!export_fn) return;
+
+            return (replica: types.ReplicaId, id: types.SquareId) => {
+                const replica_ptr = serializeObject(replica);
+                const id_ptr = serializeObject(id);
+                export_fn(replica_ptr, id_ptr);
+            };
+        })(),
+        likesDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_likes_deltas as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.CounterMap<types.SquareId>>(export_fn());
+        })(),
+        likesGet: (() => {
+            const export_fn = instance.exports.__fp_gen_likes_get as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.CounterMap<types.SquareId>>(export_fn());
+        })(),
+        likesIncrement: (() => {
+            const export_fn = instance.exports.__fp_gen_likes_increment as any;
+            if (!export_fn) return;
+
+            return (replica: types.ReplicaId, id: types.SquareId) => {
+                const replica_ptr = serializeObject(replica);
+                const id_ptr = serializeObject(id);
+                export_fn(replica_ptr, id_ptr);
+            };
+        })(),
+        likesMergeDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_likes_merge_deltas as any;
+            if (!export_fn) return;
+
+            return (delta: types.CounterMap<types.SquareId>) => {
+                const delta_ptr = serializeObject(delta);
+                export_fn(delta_ptr);
+            };
+        })(),
+        likesRemove: (() => {
+            const export_fn = instance.exports.__fp_gen_likes_remove as any;
+            if (!export_fn) return;
+
+            return (id: types.SquareId) => {
+                const id_ptr = serializeObject(id);
+                export_fn(id_ptr);
+            };
+        })(),
+        likesValue: (() => {
+            const export_fn = instance.exports.__fp_gen_likes_value as any;
+            if (!export_fn) return;
+
+            return (id: types.SquareId) => {
+                const id_ptr = serializeObject(id);
+                return interpretBigSign(export_fn(id_ptr), 9223372036854775808n);
+            };
+        })(),
         merge: (() => {
             const export_fn = instance.exports.__fp_gen_merge as any;
             if (!export_fn) return;
 
             return (other: types.AWORMap<types.SquareId, types.Square>) => {
                 const other_ptr = serializeObject(other);
-                return parseObject<types.AWORMap<types.SquareId, types.Square>>(export_fn(other_ptr));
+                return parseObject<types.Result<types.AWORMap<types.SquareId, types.Square>, types.CrdtError>>(export_fn(other_ptr));
             };
         })(),
         mergeDeltas: (() => {
@@ -197,16 +464,77 @@ export async function createRuntime(
 
             return (delta: Deltas<SquareId, Square>) => {
                 const delta_ptr = serializeObject(delta);
-                export_fn(delta_ptr);
+                return parseObject<types.Result<void, types.CrdtError>>(export_fn(delta_ptr));
             };
         })(),
+        mergeEncoded: (() => {
+            const export_fn = instance.exports.__fp_gen_merge_encoded as any;
+            if (!export_fn) return;
+
+            return (bytes: ArrayBuffer) => {
+                const bytes_ptr = serializeObject(bytes);
+                return parseObject<types.Result<void, types.CrdtError>>(export_fn(bytes_ptr));
+            };
+        })(),
+        pendingDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_pending_deltas as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.PendingDeltas>(export_fn());
+        })(),
+        redo: (() => {
+            const export_fn = instance.exports.__fp_gen_redo as any;
+            if (!export_fn) return;
+
+            return (replica: types.ReplicaId) => {
+                const replica_ptr = serializeObject(replica);
+                return !!export_fn(replica_ptr);
+            };
+        })(),
+        registerDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_register_deltas as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.DotKernel<string>>(export_fn());
+        })(),
+        registerGet: (() => {
+            const export_fn = instance.exports.__fp_gen_register_get as any;
+            if (!export_fn) return;
+
+            return () => parseObject<Array<string>>(export_fn());
+        })(),
+        registerMergeDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_register_merge_deltas as any;
+            if (!export_fn) return;
+
+            return (delta: types.DotKernel<string>) => {
+                const delta_ptr = serializeObject(delta);
+                return parseObject<types.Result<void, types.CrdtError>>(export_fn(delta_ptr));
+            };
+        })(),
+        registerSet: (() => {
+            const export_fn = instance.exports.__fp_gen_register_set as any;
+            if (!export_fn) return;
+
+            return (replica: types.ReplicaId, value: string) => {
+                const replica_ptr = serializeObject(replica);
+                const value_ptr = serializeObject(value);
+                export_fn(replica_ptr, value_ptr);
+            };
+        })(),
+        registerState: (() => {
+            const export_fn = instance.exports.__fp_gen_register_state as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.MVReg<string>>(export_fn());
+        })(),
         replace: (() => {
             const export_fn = instance.exports.__fp_gen_replace as any;
             if (!export_fn) return;
 
             return (map: types.AWORMap<types.SquareId, types.Square>) => {
                 const map_ptr = serializeObject(map);
-                export_fn(map_ptr);
+                return parseObject<types.Result<void, types.CrdtError>>(export_fn(map_ptr));
             };
         })(),
         set: (() => {
@@ -220,6 +548,88 @@ export async function createRuntime(
                 export_fn(replica_ptr, id_ptr, square_ptr);
             };
         })(),
+        startUndoGroup: (() => {
+            const export_fn = instance.exports.__fp_gen_start_undo_group as any;
+            if (!export_fn) return;
+
+            return () => export_fn();
+        })(),
+        stateSize: (() => {
+            const export_fn = instance.exports.__fp_gen_state_size as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.SizeReport>(export_fn());
+        })(),
+        tableDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_table_deltas as any;
+            if (!export_fn) return;
+
+            return () => parseObject<types.Result<ArrayBuffer, types.CrdtError>>(export_fn());
+        })(),
+        tableGet: (() => {
+            const export_fn = instance.exports.__fp_gen_table_get as any;
+            if (!export_fn) return;
+
+            return () => parseObject<Array<Array<Array<string>>>>(export_fn());
+        })(),
+        tableInsertColumn: (() => {
+            const export_fn = instance.exports.__fp_gen_table_insert_column as any;
+            if (!export_fn) return;
+
+            return (replica: types.ReplicaId, index: number) => {
+                const replica_ptr = serializeObject(replica);
+                return !!export_fn(replica_ptr, index);
+            };
+        })(),
+        tableInsertRow: (() => {
+            const export_fn = instance.exports.__fp_gen_table_insert_row as any;
+            if (!export_fn) return;
+
+            return (replica: types.ReplicaId, index: number) => {
+                const replica_ptr = serializeObject(replica);
+                return !!export_fn(replica_ptr, index);
+            };
+        })(),
+        tableMergeDeltas: (() => {
+            const export_fn = instance.exports.__fp_gen_table_merge_deltas as any;
+            if (!export_fn) return;
+
+            return (bytes: ArrayBuffer) => {
+                const bytes_ptr = serializeObject(bytes);
+                return parseObject<types.Result<void, types.CrdtError>>(export_fn(bytes_ptr));
+            };
+        })(),
+        tableRemoveColumn: (() => {
+            const export_fn = instance.exports.__fp_gen_table_remove_column as any;
+            if (!export_fn) return;
+
+            return (index: number) => !!export_fn(index);
+        })(),
+        tableRemoveRow: (() => {
+            const export_fn = instance.exports.__fp_gen_table_remove_row as any;
+            if (!export_fn) return;
+
+            return (index: number) => !!export_fn(index);
+        })(),
+        tableSet: (() => {
+            const export_fn = instance.exports.__fp_gen_table_set as any;
+            if (!export_fn) return;
+
+            return (replica: types.ReplicaId, row: number, column: number, value: string) => {
+                const replica_ptr = serializeObject(replica);
+                const value_ptr = serializeObject(value);
+                return !!export_fn(replica_ptr, row, column, value_ptr);
+            };
+        })(),
+        undo: (() => {
+            const export_fn = instance.exports.__fp_gen_undo as any;
+            if (!export_fn) return;
+
+            return (replica: types.ReplicaId) => {
+                const replica_ptr = serializeObject(replica);
+                return !!export_fn(replica_ptr);
+            };
+        })(),
     };
 }
 
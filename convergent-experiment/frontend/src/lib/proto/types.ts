@@ -13,14 +13,67 @@ export type AWORSet<V> = {
     delta?: DotKernel<V>;
 };
 
+/**
+ * A [`PNCounter`] per key, for counting votes or likes of many items.
+ *
+ * Removing a key only takes back the counts the removing replica had seen: increments made
+ * concurrently with the removal are still counted afterwards, as if the key had been removed
+ * before them. Removed keys are kept around to tell what was taken back, so they still take up
+ * space.
+ */
+export type CounterMap<K> = {
+    entries: Record<K, KeyCounter>;
+    delta?: CounterMap<K>;
+};
+
+/**
+ * Why an export couldn't do what it was asked, returned instead of panicking so JS can drop the
+ * offending message and carry on
+ */
+export type CrdtError =
+    /**
+     * Bytes that aren't a msgpack encoded state
+     */
+    | { type: "decode"; message: string }
+    /**
+     * State that couldn't be encoded as msgpack
+     */
+    | { type: "encode"; message: string }
+    /**
+     * State or delta holding an entry its context never saw, see `DotKernel::uncovered_dot`
+     */
+    | { type: "malformed"; message: string }
+    /**
+     * No document with that id, see `create_doc`
+     */
+    | { type: "unknownDoc"; doc: DocId };
+
+/**
+ * Names a document hosted next to the default one, see `create_doc`
+ */
+export type DocId = string;
+
 export type Dot = {
     : ReplicaId;
     : number;
 };
 
+/**
+ * Dot cloud stored as runs of consecutive dots instead of individual dots.
+ *
+ * Dots of a replica are handed out sequentially, so even when they arrive out of order they
+ * mostly form long contiguous runs. Keeping them in a `BTreeSet<Dot>` costs a node per dot,
+ * here each replica maps to disjoint, non-adjacent runs `start -> end` (end exclusive), so a
+ * run of 100k dots is a single entry.
+ *
+ * Serializes as a list of [`DotRange`]s, and deserializing also accepts the old `BTreeSet<Dot>`
+ * form since a single dot is a valid [`DotRange`].
+ */
+export type DotCloud = Record<ReplicaId, Record<number, number>>;
+
 export type DotCtx = {
     clock: VectorClock;
-    dot_cloud: Array<Dot>;
+    dot_cloud: DotCloud;
 };
 
 export type DotKernel<V> = {
@@ -28,6 +81,29 @@ export type DotKernel<V> = {
     entries: Record<Dot, V>;
 };
 
+/**
+ * Note that the deltas are in a GCounter struct for composability reasons
+ */
+export type GCounter = {
+    values: Record<ReplicaId, number>;
+    delta?: GCounter;
+};
+
+/**
+ * Counter of a single key of a [`CounterMap`]
+ */
+export type KeyCounter = {
+    /**
+     * Everything ever counted for the key
+     */
+    counter: PNCounter;
+    /**
+     * The part of `counter` removals of the key saw. Replicas only count up, so this is never
+     * ahead of `counter` for any of them.
+     */
+    reset: PNCounter;
+};
+
 /**
  * Key-value pair so it can implement Serializable, note that
  * it also implements PartialEq but only compares keys
@@ -37,7 +113,74 @@ export type KeyVal<K, V> = {
     val: V;
 };
 
-export type ReplicaId = number;
+/**
+ * Hash tree of depth one over the entries of a map. Keys are spread over [`DIGEST_LEAVES`]
+ * buckets by hash and each leaf hashes the entries of its bucket, the root hashes the leaves.
+ *
+ * Hashes are 32 bits so they survive a trip through JavaScript numbers.
+ */
+export type MapDigest = {
+    root: number;
+    leaves: Array<number>;
+};
+
+export type MVReg<V> = {
+    core: DotKernel<V>;
+    delta?: DotKernel<V>;
+};
+
+export type PNCounter = {
+    inc: GCounter;
+    dec: GCounter;
+};
+
+/**
+ * Local deltas JS hasn't acked yet, see `ack_deltas`
+ */
+export type PendingDeltas = {
+    /**
+     * Acks these deltas and every earlier ones
+     */
+    seq: number;
+    deltas: Deltas<SquareId, Square>;
+};
+
+export type ReplicaId = string;
+
+/**
+ * A result that can be either successful (`Ok`) or represent an error (`Err`).
+ */
+export type Result<T, E> =
+    /**
+     * Represents a successful result.
+     */
+    | { Ok: T }
+    /**
+     * Represents an error.
+     */
+    | { Err: E };
+
+/**
+ * How large the plugin's state grew, see `state_size`
+ */
+export type SizeReport = {
+    /**
+     * Keys in the squares map
+     */
+    entries: number;
+    /**
+     * Dots the context couldn't fold into its clock yet
+     */
+    dot_cloud: number;
+    /**
+     * Entries of deltas that weren't handed out or acked yet
+     */
+    delta_entries: number;
+    /**
+     * Size of the state encoded as msgpack
+     */
+    bytes: number;
+};
 
 export type Square = {
     x: number;
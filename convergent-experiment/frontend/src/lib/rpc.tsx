@@ -1,19 +1,66 @@
 import { decode, encode } from "@msgpack/msgpack";
-import { AWORMap, Deltas, ReplicaId, Square, SquareId } from "./proto/types";
+import {
+  AWORMap,
+  Deltas,
+  MapDigest,
+  ReplicaId,
+  Square,
+  SquareId,
+} from "./proto/types";
 
 export type ServerBound =
   | {
       type: "sync";
-      replicaId: number;
+      replicaId: ReplicaId;
+      roomId?: string;
       state: AWORMap<SquareId, Square>;
     }
+  | {
+      type: "syncDigest";
+      replicaId: ReplicaId;
+      roomId?: string;
+      digest: MapDigest;
+    }
+  | {
+      type: "resume";
+      replicaId: ReplicaId;
+      roomId?: string;
+      token: string;
+      seq: number;
+      digest: MapDigest;
+    }
   | {
       type: "update";
       deltas: Deltas<SquareId, Square>;
     }
+  | {
+      type: "ack";
+      seq: number;
+    }
   | {
       type: "cursor";
       pos: [x: number, y: number];
+    }
+  | {
+      type: "presence";
+      name: string;
+      metadata?: Record<string, string>;
+    }
+  | {
+      type: "lock";
+      id: SquareId;
+      /** Later requests win conflicts, ties go to the higher replica id */
+      ts: number;
+    }
+  | {
+      type: "unlock";
+      id: SquareId;
+    }
+  | {
+      type: "history";
+      /** Milliseconds since the epoch, inclusive */
+      fromTs: number;
+      toTs: number;
     };
 
 export type ClientBound =
@@ -21,15 +68,87 @@ export type ClientBound =
       type: "sync";
       state: AWORMap<SquareId, Square>;
     }
+  | {
+      type: "syncDigest";
+      digest: MapDigest;
+      deltas: Deltas<SquareId, Square>;
+    }
   | {
       type: "update";
+      seq: number;
       deltas: Deltas<SquareId, Square>;
     }
   | {
       type: "cursor";
-      pos: [x: number, y: number, id: ReplicaId][];
+      pos: CursorPos[];
+    }
+  | {
+      type: "roster";
+      users: User[];
+    }
+  | {
+      type: "presence";
+      changed: User[];
+      left: ReplicaId[];
+    }
+  | {
+      type: "session";
+      token: string;
+      resumed: boolean;
+    }
+  | {
+      type: "rejected";
+      reason: Rejection;
+    }
+  | {
+      type: "digest";
+      digest: MapDigest;
+    }
+  | {
+      type: "history";
+      entries: HistoryEntry[];
+      truncated: boolean;
+    }
+  | {
+      type: "locks";
+      locked: SquareLock[];
+      unlocked: SquareId[];
     };
 
+export type SquareLock = {
+  id: SquareId;
+  owner: ReplicaId;
+  ts: number;
+};
+
+export type HistoryEntry = {
+  ts: number;
+  deltas: Deltas<SquareId, Square>;
+};
+
+export type Rejection =
+  | { kind: "outOfBounds"; id: SquareId }
+  | { kind: "notFinite"; id: SquareId }
+  | { kind: "tooLarge"; entries: number; max: number }
+  | { kind: "foreignDot"; replica: ReplicaId };
+
+export type CursorPos = {
+  x: number;
+  y: number;
+  id: ReplicaId;
+  name: string;
+  color: string;
+};
+
+export type User = {
+  id: ReplicaId;
+  name: string;
+  /** CSS color the server picked when the user joined */
+  color: string;
+  metadata: Record<string, string>;
+  idle: boolean;
+};
+
 export const encodeServerBound = (msg: ServerBound): Uint8Array => encode(msg);
 export const decodeClientBound = (
   msg: ArrayLike<number> | BufferSource
@@ -41,6 +160,26 @@ export const prettyClientBound = (
   switch (msg.type) {
     case "sync":
       return `Sync`;
+    case "syncDigest":
+      return `Sync digest`;
+    case "digest":
+      return `Digest`;
+    case "history":
+      return `History (${msg.entries.length} updates)`;
+    case "locks":
+      return `Locks (${msg.locked.length} locked, ${msg.unlocked.length} unlocked)`;
+    case "roster":
+      return `Roster (${msg.users.length} users)`;
+    case "presence":
+      return `Presence`;
+    case "session":
+      return msg.resumed ? `Resumed session` : `New session`;
+    case "rejected":
+      return (
+        <>
+          <b className="text-sm text-red-500">Rejected</b> {msg.reason.kind}
+        </>
+      );
     case "update":
       const entries: Record<string, [SquareId, Square]> = msg.deltas.entries;
       const str = Object.entries(entries)
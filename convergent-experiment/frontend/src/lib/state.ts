@@ -7,13 +7,25 @@ import type {
   SquareId,
 } from "./proto/types";
 import create from "zustand";
-import { ClientBound, ServerBound } from "./rpc";
+import {
+  ClientBound,
+  CursorPos,
+  HistoryEntry,
+  ServerBound,
+  SquareLock,
+  User,
+} from "./rpc";
 
 type Base = {
   squares: Record<SquareId, Square>;
-  cursors: Record<ReplicaId, [x: number, y: number]>;
+  cursors: Record<ReplicaId, CursorPos>;
+  users: Record<ReplicaId, User>;
+  /** Advisory, squares can still be changed by others */
+  locks: Record<SquareId, SquareLock>;
   prevClientMsg?: ClientBound;
   prevServerMsg?: ServerBound;
+  /** Updates of the room asked for with a `history` message, oldest first */
+  history?: { entries: HistoryEntry[]; truncated: boolean };
 };
 
 type Actions = {
@@ -24,11 +36,19 @@ type Actions = {
   local: {
     setSquare: (id: SquareId, square: Square) => void;
     deleteSquare: (id: SquareId) => void;
+    undo: () => boolean;
+    redo: () => boolean;
+    startUndoGroup: () => void;
+    endUndoGroup: () => void;
   };
   remote: {
     merge: (state: AWORMap<SquareId, Square>) => void;
     mergeDeltas: (crdt: Deltas<SquareId, Square>) => void;
-    setCursors: (cursors: [x: number, y: number, id: ReplicaId][]) => void;
+    setCursors: (cursors: CursorPos[]) => void;
+    setRoster: (users: User[]) => void;
+    updatePresence: (changed: User[], left: ReplicaId[]) => void;
+    setHistory: (entries: HistoryEntry[], truncated: boolean) => void;
+    updateLocks: (locked: SquareLock[], unlocked: SquareId[]) => void;
   };
 };
 
@@ -47,6 +67,8 @@ export const useAppState = create<AppState>((set, get) => ({
   ready: false,
   squares: {},
   cursors: {},
+  users: {},
+  locks: {},
   prevClientMsg: undefined,
   prevServerMsg: undefined,
   setPrevClientMsg(msg) {
@@ -101,13 +123,48 @@ export const useAppState = create<AppState>((set, get) => ({
 
       set({ squares: { ...state.squares } });
     },
+    undo() {
+      const state = get();
+      if (!state.ready) throw new Error("Not ready");
+
+      const undone = state.wasm.undo!(state.replicaId);
+      if (undone) {
+        set({ squares: awormapToRecord(state.wasm.get!()) });
+      }
+      return undone;
+    },
+    redo() {
+      const state = get();
+      if (!state.ready) throw new Error("Not ready");
+
+      const redone = state.wasm.redo!(state.replicaId);
+      if (redone) {
+        set({ squares: awormapToRecord(state.wasm.get!()) });
+      }
+      return redone;
+    },
+    startUndoGroup() {
+      const state = get();
+      if (!state.ready) return;
+      state.wasm.startUndoGroup!();
+    },
+    endUndoGroup() {
+      const state = get();
+      if (!state.ready) return;
+      state.wasm.endUndoGroup!();
+    },
   },
   remote: {
     mergeDeltas(deltas) {
       const state = get();
       if (!state.ready) throw new Error("Not ready");
 
-      state.wasm.mergeDeltas!(deltas);
+      const result = state.wasm.mergeDeltas!(deltas);
+      if ("Err" in result) {
+        // Dropping the delta is fine, the next digest exchange syncs what it carried
+        console.error("Failed to merge deltas", result.Err);
+        return;
+      }
       const squares = state.wasm.get!();
 
       set({
@@ -118,7 +175,12 @@ export const useAppState = create<AppState>((set, get) => ({
       const state = get();
       if (!state.ready) throw new Error("Not ready");
 
-      const squares = state.wasm.merge!(remoteState);
+      const result = state.wasm.merge!(remoteState);
+      if ("Err" in result) {
+        console.error("Failed to merge state", result.Err);
+        return;
+      }
+      const squares = result.Ok;
 
       set({
         squares: awormapToRecord(squares),
@@ -128,7 +190,7 @@ export const useAppState = create<AppState>((set, get) => ({
       const state = get();
       if (state.ready !== true) return;
       const newCursors: AppState["cursors"] = cursors.reduce(
-        (acc, [x, y, id]) => ({ ...acc, [id]: [x, y] }),
+        (acc, cursor) => ({ ...acc, [cursor.id]: cursor }),
         {}
       );
 
@@ -136,6 +198,38 @@ export const useAppState = create<AppState>((set, get) => ({
         cursors: { ...state.cursors, ...newCursors },
       });
     },
+    updateLocks(locked, unlocked) {
+      const locks = { ...get().locks };
+      for (const lock of locked) {
+        locks[lock.id] = lock;
+      }
+      for (const id of unlocked) {
+        delete locks[id];
+      }
+      set({ locks });
+    },
+    setHistory(entries, truncated) {
+      set({ history: { entries, truncated } });
+    },
+    setRoster(users) {
+      set({
+        users: users.reduce((acc, user) => ({ ...acc, [user.id]: user }), {}),
+      });
+    },
+    updatePresence(changed, left) {
+      const state = get();
+      const users = { ...state.users };
+      const cursors = { ...state.cursors };
+      for (const user of changed) {
+        users[user.id] = user;
+      }
+      // Cursors of users that left would otherwise stay where they were
+      for (const id of left) {
+        delete users[id];
+        delete cursors[id];
+      }
+      set({ users, cursors });
+    },
   },
 }));
 
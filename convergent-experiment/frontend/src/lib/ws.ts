@@ -11,6 +11,44 @@ import {
 import { ReplicaId } from "./proto/types";
 import { useAppState } from "./state";
 
+/**
+ * Random 128 bit replica id, so tabs don't have to coordinate to avoid
+ * colliding ids
+ */
+export const randomReplicaId = (): ReplicaId => {
+  const [hi, lo] = crypto.getRandomValues(new BigUint64Array(2));
+  return ((hi << BigInt(64)) | lo).toString();
+};
+
+/**
+ * Room to join, from the `?room=` query parameter. Everyone without one
+ * shares the unnamed room
+ */
+const roomFromLocation = (): string =>
+  new URLSearchParams(window.location.search).get("room") ?? "";
+
+/**
+ * Adds the `?token=` query parameter of the page to the websocket URL, for
+ * servers that require one
+ */
+const withToken = (addr: string): string => {
+  const token = new URLSearchParams(window.location.search).get("token");
+  return token === null ? addr : `${addr}?token=${encodeURIComponent(token)}`;
+};
+
+/**
+ * Name to show to others in the room, from the `?name=` query parameter.
+ * Without one the server keeps the name it made up when we joined
+ */
+const nameFromLocation = (): string =>
+  new URLSearchParams(window.location.search).get("name") ?? "";
+
+/**
+ * Session of the last connection and the last update acked in it, so a
+ * reconnect only gets the updates missed in between
+ */
+let session: { token: string; seq: number } | undefined;
+
 export type WebsocketMachine = StateMachineDef<
   {
     idle: { addr: string; runtime?: Runtime; replicaId?: ReplicaId };
@@ -75,7 +113,7 @@ export const useWebsocketStore = create<WebsocketMachine, Actions>(
       });
     },
     connect(idleState) {
-      const socket = new WebSocket(idleState.addr);
+      const socket = new WebSocket(withToken(idleState.addr));
 
       socket.addEventListener("error", (e) => {
         console.error("WS error", e);
@@ -92,12 +130,36 @@ export const useWebsocketStore = create<WebsocketMachine, Actions>(
       socket.addEventListener("open", () => {
         const currentState = get();
         if (currentState.kind === "connecting") {
-          const msg = encodeServerBound({
-            type: "sync",
-            state: currentState.runtime.get!(),
-            replicaId: idleState.replicaId,
-          });
+          const state = currentState.runtime.get!();
+          const roomId = roomFromLocation();
+          // Reconnecting clients only exchange the keys that differ, or
+          // nothing at all if their session can be resumed
+          const msg = encodeServerBound(
+            session !== undefined
+              ? {
+                  type: "resume",
+                  token: session.token,
+                  seq: session.seq,
+                  digest: currentState.runtime.digest!(),
+                  replicaId: idleState.replicaId,
+                  roomId,
+                }
+              : Object.keys(state.keys.kernel.entries).length === 0
+              ? { type: "sync", state, replicaId: idleState.replicaId, roomId }
+              : {
+                  type: "syncDigest",
+                  digest: currentState.runtime.digest!(),
+                  replicaId: idleState.replicaId,
+                  roomId,
+                }
+          );
           currentState.socket.send(msg);
+          currentState.socket.send(
+            encodeServerBound({
+              type: "presence",
+              name: nameFromLocation(),
+            })
+          );
           transition(currentState.kind, "connected", {
             socket,
             addr: currentState.addr,
@@ -113,7 +175,10 @@ export const useWebsocketStore = create<WebsocketMachine, Actions>(
           const arrayBuf = await e.data.arrayBuffer();
           const clientBound = decodeClientBound(arrayBuf);
 
-          if (clientBound.type !== "cursor") {
+          if (
+            clientBound.type !== "cursor" &&
+            clientBound.type !== "presence"
+          ) {
             console.log("Client bound message", clientBound);
             useAppState.getState().setPrevClientMsg(clientBound);
           }
@@ -123,14 +188,95 @@ export const useWebsocketStore = create<WebsocketMachine, Actions>(
               useAppState.getState().remote.merge(clientBound.state);
               break;
             }
+            case "syncDigest": {
+              const { runtime, socket } = currentState;
+              // Send back what the server is missing before merging, so the keys it just sent
+              // aren't echoed back to it
+              const keys = runtime.diff!(clientBound.digest);
+              const deltas = runtime.extract!(keys);
+              // Those cover every local change, if they're lost the next digest exchange sends
+              // them again
+              runtime.ackDeltas!(runtime.pendingDeltas!().seq);
+              useAppState.getState().remote.mergeDeltas(clientBound.deltas);
+              socket.send(encodeServerBound({ type: "update", deltas }));
+              break;
+            }
             case "update": {
               useAppState.getState().remote.mergeDeltas(clientBound.deltas);
+              if (session !== undefined) {
+                session.seq = clientBound.seq;
+              }
+              currentState.socket.send(
+                encodeServerBound({ type: "ack", seq: clientBound.seq })
+              );
+              break;
+            }
+            case "digest": {
+              // Only clients that diverged sync, the server answers with its side of the keys
+              // that differ
+              const { runtime, socket, replicaId } = currentState;
+              const digest = runtime.digest!();
+              if (digest.root !== clientBound.digest.root) {
+                socket.send(
+                  encodeServerBound({
+                    type: "syncDigest",
+                    digest,
+                    replicaId,
+                    roomId: roomFromLocation(),
+                  })
+                );
+              } else {
+                // The server has every local change, none of them need to be sent again
+                runtime.ackDeltas!(runtime.pendingDeltas!().seq);
+              }
+              break;
+            }
+            case "locks": {
+              useAppState
+                .getState()
+                .remote.updateLocks(clientBound.locked, clientBound.unlocked);
+              break;
+            }
+            case "history": {
+              useAppState
+                .getState()
+                .remote.setHistory(clientBound.entries, clientBound.truncated);
+              break;
+            }
+            case "rejected": {
+              console.error("Server rejected update", clientBound.reason);
+              break;
+            }
+            case "session": {
+              // Sequence numbers carry on in a resumed session
+              session = {
+                token: clientBound.token,
+                seq: clientBound.resumed ? session?.seq ?? 0 : 0,
+              };
+              if (clientBound.resumed) {
+                // Send what was changed here while disconnected, along with what was sent
+                // before but never acked
+                const deltas = currentState.runtime.deltas!();
+                currentState.socket.send(
+                  encodeServerBound({ type: "update", deltas })
+                );
+              }
               break;
             }
             case "cursor": {
               useAppState.getState().remote.setCursors(clientBound.pos);
               break;
             }
+            case "roster": {
+              useAppState.getState().remote.setRoster(clientBound.users);
+              break;
+            }
+            case "presence": {
+              useAppState
+                .getState()
+                .remote.updatePresence(clientBound.changed, clientBound.left);
+              break;
+            }
           }
         }
       });
@@ -5,15 +5,18 @@ import Link from "next/link";
 import { useEffect, useState } from "react";
 import Canvas from "../lib/Canvas";
 import Cursors from "../lib/Cursor";
-import { Square } from "../lib/proto/types";
+import { ReplicaId, Square } from "../lib/proto/types";
 import { useAppState } from "../lib/state";
 import { createRuntime } from "../lib/wasm";
-import { useWebsocketStore } from "../lib/ws";
+import { randomReplicaId, useWebsocketStore } from "../lib/ws";
 import { prettyClientBound } from "../lib/rpc";
 
+const COMPACT_INTERVAL_MS = 60_000;
+const COMPACT_AT = 1024;
+
 let ran = false;
 const Home: NextPage = () => {
-  const [replicaId, setReplicaId] = useState<number | undefined>(undefined);
+  const [replicaId, setReplicaId] = useState<ReplicaId | undefined>(undefined);
   const { state, actions } = useWebsocketStore();
   const { prevClientMsg, prevServerMsg } = useAppState(
     (state) => ({
@@ -49,6 +52,15 @@ const Home: NextPage = () => {
       });
       window.runtime = runtime;
       window.entries = () => Object.values(get().keys.kernel.entries);
+
+      // Tabs left open for hours pile up dots and unacked deltas, fold them
+      // away every now and then
+      setInterval(() => {
+        const size = runtime.stateSize?.();
+        if (size && (size.dot_cloud > 0 || size.delta_entries > COMPACT_AT)) {
+          runtime.compact?.();
+        }
+      }, COMPACT_INTERVAL_MS);
     };
 
     run();
@@ -63,12 +75,22 @@ const Home: NextPage = () => {
             className="ml-2 bg-inherit text-white focus:outline-none"
             value={replicaId}
             onChange={(e) => {
-              try {
-                const val = parseInt(e.target.value);
-                setReplicaId(val);
-              } catch (_) {}
+              // Ids are 128 bit integers sent as decimal strings
+              if (e.target.value === "") {
+                setReplicaId(undefined);
+              } else if (/^\d+$/.test(e.target.value)) {
+                setReplicaId(e.target.value);
+              }
             }}
           />
+          {replicaId === undefined && (
+            <button
+              className="ml-2 text-sm text-white"
+              onClick={(_) => setReplicaId(randomReplicaId())}
+            >
+              Random
+            </button>
+          )}
 
           <button
             className={
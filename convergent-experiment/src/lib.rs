@@ -1,14 +1,18 @@
-use std::{cell::RefCell, panic};
+mod outbox;
+mod state;
+
+use std::panic;
 
 use convergent_experiment_protocol::*;
-use once_cell::sync::Lazy;
+use serde_bytes::ByteBuf;
+use state::with_state;
 use sypytkowski_convergent::delta_state::awormap::{AWORMap, Deltas};
-
-// We maintain the global state in a mutable static so that we do not need to pass it from
-// JavaScript every time we call the reducer. This avoids significant serialization overhead we
-// would incur otherwise.
-static mut STATE: Lazy<RefCell<AWORMap<SquareId, Square>>> =
-    Lazy::new(|| RefCell::new(AWORMap::default()));
+use sypytkowski_convergent::delta_state::counter_map::CounterMap;
+use sypytkowski_convergent::delta_state::dot::{Dot, DotKernel};
+use sypytkowski_convergent::delta_state::grid::GridDelta;
+use sypytkowski_convergent::delta_state::mvreg::MVReg;
+use sypytkowski_convergent::delta_state::pncounter::PNCounter;
+use sypytkowski_convergent::delta_state::undo::UndoManager;
 
 fn panic_hook() {
     fn hook_impl(info: &panic::PanicInfo) {
@@ -44,43 +48,423 @@ fn panic_hook() {
 #[fp_export_impl(convergent_experiment_protocol)]
 fn get() -> AWORMap<SquareId, Square> {
     panic_hook();
-    unsafe { STATE.get_mut().clone() }
+    with_state(|s| s.squares.clone())
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn merge(map: AWORMap<SquareId, Square>) -> Result<AWORMap<SquareId, Square>, CrdtError> {
+    check_covered(map.uncovered_dot())?;
+    Ok(with_state(|s| {
+        s.squares.merge_in_place(&map);
+        s.squares.clone()
+    }))
 }
 
+/// State encoded as msgpack the way the ws server expects it, so it can be sent as is
 #[fp_export_impl(convergent_experiment_protocol)]
-fn merge(map: AWORMap<SquareId, Square>) -> AWORMap<SquareId, Square> {
-    let state = unsafe { STATE.get_mut() };
-    *state = state.merge(&map);
-    state.clone()
+fn get_encoded() -> Result<ByteBuf, CrdtError> {
+    let bytes = with_state(|s| rmp_serde::to_vec_named(&s.squares));
+    bytes.map(ByteBuf::from).map_err(|err| CrdtError::Encode {
+        message: err.to_string(),
+    })
 }
 
+/// Merges a state encoded like [`get_encoded`] encodes it, without decoding it in JS first
 #[fp_export_impl(convergent_experiment_protocol)]
-fn merge_deltas(delta: Deltas<SquareId, Square>) {
-    let state = unsafe { STATE.get_mut() };
-    state.merge_delta(delta);
+fn merge_encoded(bytes: ByteBuf) -> Result<(), CrdtError> {
+    let map: AWORMap<SquareId, Square> =
+        rmp_serde::from_slice(&bytes).map_err(|err| CrdtError::Decode {
+            message: err.to_string(),
+        })?;
+    check_covered(map.uncovered_dot())?;
+    with_state(|s| s.squares.merge_in_place(&map));
+    Ok(())
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn merge_deltas(delta: Deltas<SquareId, Square>) -> Result<(), CrdtError> {
+    check_covered(delta.uncovered_dot())?;
+    with_state(|s| s.squares.merge_delta_in_place(&delta));
+    Ok(())
+}
+
+/// Arguments are decoded before the exports run, whatever doesn't decode still panics. This
+/// catches what decodes fine but would corrupt the state once merged.
+fn check_covered(uncovered: Option<Dot>) -> Result<(), CrdtError> {
+    match uncovered {
+        Some(dot) => Err(CrdtError::Malformed {
+            message: format!("Entry at {dot:?} is missing from its context"),
+        }),
+        None => Ok(()),
+    }
 }
 
 #[fp_export_impl(convergent_experiment_protocol)]
 fn set(replica: sypytkowski_convergent::ReplicaId, id: SquareId, square: Square) {
-    let state = unsafe { STATE.get_mut() };
-    state.insert(replica, id, square);
+    with_state(|s| {
+        s.undo
+            .record(&mut s.squares, |state| state.insert(replica, id, square))
+    });
 }
 
 #[fp_export_impl(convergent_experiment_protocol)]
-fn del(replica: sypytkowski_convergent::ReplicaId, id: SquareId) {
-    let state = unsafe { STATE.get_mut() };
-    state.remove(replica, id)
+fn del(_replica: sypytkowski_convergent::ReplicaId, id: SquareId) {
+    with_state(|s| s.undo.record(&mut s.squares, |state| state.remove(&id)));
 }
 
+/// Changes since the last call merged with every earlier batch that wasn't acked yet, so deltas
+/// sent over a connection that dropped are sent again
 #[fp_export_impl(convergent_experiment_protocol)]
 fn deltas() -> Deltas<SquareId, Square> {
-    let state = unsafe { STATE.get_mut() };
-    let deltas = state.split_mut();
-    deltas.unwrap_or(Default::default())
+    pending_deltas().deltas
+}
+
+/// Like [`deltas`], along with the sequence number to pass to [`ack_deltas`] once the server has
+/// them
+#[fp_export_impl(convergent_experiment_protocol)]
+fn pending_deltas() -> PendingDeltas {
+    with_state(|s| {
+        if let Some(deltas) = s.squares.split_mut() {
+            s.outbox.push(deltas);
+        }
+        s.outbox.pending()
+    })
+}
+
+/// Stops sending the deltas up to `seq`, call it once the server is known to have them
+#[fp_export_impl(convergent_experiment_protocol)]
+fn ack_deltas(seq: u32) {
+    with_state(|s| s.outbox.ack(seq));
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn replace(map: AWORMap<SquareId, Square>) -> Result<(), CrdtError> {
+    check_covered(map.uncovered_dot())?;
+    with_state(|s| {
+        s.squares = map;
+        // The recorded dots may not exist in the new state
+        s.undo = UndoManager::with_limit(100);
+        // Neither are the queued deltas
+        s.outbox.clear();
+    });
+    Ok(())
+}
+
+/// Undo delta ends up in the state's deltas like any other local change, returns `false` if there
+/// was nothing to undo
+#[fp_export_impl(convergent_experiment_protocol)]
+fn undo(replica: sypytkowski_convergent::ReplicaId) -> bool {
+    with_state(|s| s.undo.undo(&mut s.squares, replica).is_some())
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn redo(replica: sypytkowski_convergent::ReplicaId) -> bool {
+    with_state(|s| s.undo.redo(&mut s.squares, replica).is_some())
+}
+
+/// Called when a drag starts so all of its moves are undone at once
+#[fp_export_impl(convergent_experiment_protocol)]
+fn start_undo_group() {
+    with_state(|s| s.undo.start_group());
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn end_undo_group() {
+    with_state(|s| s.undo.end_group());
+}
+
+/// Digest of the state to send when reconnecting, see [`AWORMap::digest`]
+#[fp_export_impl(convergent_experiment_protocol)]
+fn digest() -> MapDigest {
+    with_state(|s| s.squares.digest())
+}
+
+/// Our keys that may differ from the ones behind `remote`
+#[fp_export_impl(convergent_experiment_protocol)]
+fn diff(remote: MapDigest) -> Vec<SquareId> {
+    with_state(|s| s.squares.diff(&remote))
+}
+
+/// Partial state holding only `keys`, to be sent as an update
+#[fp_export_impl(convergent_experiment_protocol)]
+fn extract(keys: Vec<SquareId>) -> Deltas<SquareId, Square> {
+    with_state(|s| s.squares.extract(&keys))
+}
+
+/// Adds an empty document, returns `false` if there already is one named `doc`
+#[fp_export_impl(convergent_experiment_protocol)]
+fn create_doc(doc: DocId) -> bool {
+    with_state(|s| {
+        if s.docs.contains_key(&doc) {
+            return false;
+        }
+        s.docs.insert(doc, AWORMap::default());
+        true
+    })
+}
+
+/// Returns `false` if there was no document named `doc`
+#[fp_export_impl(convergent_experiment_protocol)]
+fn drop_doc(doc: DocId) -> bool {
+    with_state(|s| s.docs.remove(&doc).is_some())
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn doc_get(doc: DocId) -> Option<AWORMap<SquareId, Square>> {
+    with_state(|s| s.docs.get(&doc).cloned())
+}
+
+/// The `doc_*` functions that change a document fail with [`CrdtError::UnknownDoc`] if it doesn't
+/// exist
+#[fp_export_impl(convergent_experiment_protocol)]
+fn doc_set(
+    doc: DocId,
+    replica: sypytkowski_convergent::ReplicaId,
+    id: SquareId,
+    square: Square,
+) -> Result<(), CrdtError> {
+    with_doc(doc, |state| state.insert(replica, id, square))
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn doc_del(
+    doc: DocId,
+    _replica: sypytkowski_convergent::ReplicaId,
+    id: SquareId,
+) -> Result<(), CrdtError> {
+    with_doc(doc, |state| state.remove(&id))
+}
+
+/// Changes since the last call, empty if there is no document named `doc`
+#[fp_export_impl(convergent_experiment_protocol)]
+fn doc_deltas(doc: DocId) -> Deltas<SquareId, Square> {
+    with_state(|s| {
+        s.docs
+            .get_mut(&doc)
+            .and_then(|state| state.split_mut())
+            .unwrap_or_default()
+    })
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn doc_merge_deltas(doc: DocId, delta: Deltas<SquareId, Square>) -> Result<(), CrdtError> {
+    check_covered(delta.uncovered_dot())?;
+    with_doc(doc, |state| state.merge_delta_in_place(&delta))
+}
+
+fn with_doc(doc: DocId, f: impl FnOnce(&mut AWORMap<SquareId, Square>)) -> Result<(), CrdtError> {
+    with_state(|s| match s.docs.get_mut(&doc) {
+        Some(state) => {
+            f(state);
+            Ok(())
+        }
+        None => Err(CrdtError::UnknownDoc { doc }),
+    })
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn counter_increment(replica: sypytkowski_convergent::ReplicaId) {
+    with_state(|s| s.counter.increment(replica));
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn counter_decrement(replica: sypytkowski_convergent::ReplicaId) {
+    with_state(|s| s.counter.decrement(replica));
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn counter_value() -> i64 {
+    with_state(|s| s.counter.value())
+}
+
+/// Whole counter, to send when syncing
+#[fp_export_impl(convergent_experiment_protocol)]
+fn counter_get() -> PNCounter {
+    with_state(|s| s.counter.split().0)
+}
+
+/// Changes since the last call, a counter holding only those
+#[fp_export_impl(convergent_experiment_protocol)]
+fn counter_deltas() -> PNCounter {
+    with_state(|s| s.counter.split_mut().unwrap_or_default())
+}
+
+/// Takes both deltas and whole counters
+#[fp_export_impl(convergent_experiment_protocol)]
+fn counter_merge_deltas(delta: PNCounter) {
+    with_state(|s| s.counter.merge_delta_in_place(&delta));
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn register_set(replica: sypytkowski_convergent::ReplicaId, value: String) {
+    with_state(|s| s.register.set(replica, value));
+}
+
+/// Every value that was set concurrently, in order. Empty until the first set.
+#[fp_export_impl(convergent_experiment_protocol)]
+fn register_get() -> Vec<String> {
+    with_state(|s| s.register.value().into_iter().cloned().collect())
+}
+
+/// Whole register, to send when syncing
+#[fp_export_impl(convergent_experiment_protocol)]
+fn register_state() -> MVReg<String> {
+    with_state(|s| s.register.split().0)
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn register_deltas() -> DotKernel<String> {
+    with_state(|s| s.register.split_mut().unwrap_or_default())
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn register_merge_deltas(delta: DotKernel<String>) -> Result<(), CrdtError> {
+    check_covered(delta.uncovered_dot())?;
+    with_state(|s| s.register.merge_delta(delta));
+    Ok(())
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn likes_increment(replica: sypytkowski_convergent::ReplicaId, id: SquareId) {
+    with_state(|s| s.likes.increment(replica, id));
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn likes_decrement(replica: sypytkowski_convergent::ReplicaId, id: SquareId) {
+    with_state(|s| s.likes.decrement(replica, id));
+}
+
+/// Takes back the likes of `id` seen so far, likes given concurrently still count
+#[fp_export_impl(convergent_experiment_protocol)]
+fn likes_remove(id: SquareId) {
+    with_state(|s| s.likes.remove(&id));
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn likes_value(id: SquareId) -> i64 {
+    with_state(|s| s.likes.value(&id))
+}
+
+/// Whole map of likes, to send when syncing
+#[fp_export_impl(convergent_experiment_protocol)]
+fn likes_get() -> CounterMap<SquareId> {
+    with_state(|s| s.likes.split().0)
+}
+
+/// Changes since the last call, a map holding only the squares whose likes changed
+#[fp_export_impl(convergent_experiment_protocol)]
+fn likes_deltas() -> CounterMap<SquareId> {
+    with_state(|s| s.likes.split_mut().unwrap_or_default())
+}
+
+/// Takes both deltas and whole maps
+#[fp_export_impl(convergent_experiment_protocol)]
+fn likes_merge_deltas(delta: CounterMap<SquareId>) {
+    with_state(|s| s.likes.merge_delta_in_place(&delta));
+}
+
+/// Inserts an empty row at `index`, returns `false` if it is past the last row
+#[fp_export_impl(convergent_experiment_protocol)]
+fn table_insert_row(replica: sypytkowski_convergent::ReplicaId, index: u32) -> bool {
+    with_state(|s| {
+        let index = index as usize;
+        if index > s.table.rows() {
+            return false;
+        }
+        s.table.insert_row(replica, index);
+        true
+    })
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn table_remove_row(index: u32) -> bool {
+    with_state(|s| s.table.remove_row(index as usize))
+}
+
+/// Inserts an empty column at `index`, returns `false` if it is past the last column
+#[fp_export_impl(convergent_experiment_protocol)]
+fn table_insert_column(replica: sypytkowski_convergent::ReplicaId, index: u32) -> bool {
+    with_state(|s| {
+        let index = index as usize;
+        if index > s.table.columns() {
+            return false;
+        }
+        s.table.insert_column(replica, index);
+        true
+    })
+}
+
+#[fp_export_impl(convergent_experiment_protocol)]
+fn table_remove_column(index: u32) -> bool {
+    with_state(|s| s.table.remove_column(index as usize))
+}
+
+/// Returns `false` if the cell is out of the table
+#[fp_export_impl(convergent_experiment_protocol)]
+fn table_set(
+    replica: sypytkowski_convergent::ReplicaId,
+    row: u32,
+    column: u32,
+    value: String,
+) -> bool {
+    with_state(|s| s.table.set(replica, row as usize, column as usize, value))
+}
+
+/// Rows of cells, each with every value it was set to concurrently
+#[fp_export_impl(convergent_experiment_protocol)]
+fn table_get() -> Vec<Vec<Vec<String>>> {
+    with_state(|s| {
+        let rows = s.table.values().into_iter();
+        rows.map(|row| {
+            (row.into_iter())
+                .map(|cell| cell.into_iter().cloned().collect())
+                .collect()
+        })
+        .collect()
+    })
+}
+
+/// Changes since the last call encoded as msgpack, grids don't cross over to JS as they are
+#[fp_export_impl(convergent_experiment_protocol)]
+fn table_deltas() -> Result<ByteBuf, CrdtError> {
+    let bytes = with_state(|s| rmp_serde::to_vec_named(&s.table.split_mut().unwrap_or_default()));
+    bytes.map(ByteBuf::from).map_err(|err| CrdtError::Encode {
+        message: err.to_string(),
+    })
+}
+
+/// Merges deltas encoded like [`table_deltas`] encodes them
+#[fp_export_impl(convergent_experiment_protocol)]
+fn table_merge_deltas(bytes: ByteBuf) -> Result<(), CrdtError> {
+    let delta: GridDelta<String> =
+        rmp_serde::from_slice(&bytes).map_err(|err| CrdtError::Decode {
+            message: err.to_string(),
+        })?;
+    check_covered(delta.uncovered_dot())?;
+    with_state(|s| s.table.merge_delta(&delta));
+    Ok(())
+}
+
+/// Folds what it can of the dot contexts into their clocks and merges the queued batches, for
+/// tabs that have been open for hours. Nothing that wasn't sent yet is dropped.
+#[fp_export_impl(convergent_experiment_protocol)]
+fn compact() {
+    with_state(|s| {
+        s.squares.compact_ctx();
+        for doc in s.docs.values_mut() {
+            doc.compact_ctx();
+        }
+        s.outbox.compact();
+    });
 }
 
+/// Lets JS watch the state grow and decide when to [`compact`]
 #[fp_export_impl(convergent_experiment_protocol)]
-fn replace(map: AWORMap<SquareId, Square>) {
-    let state = unsafe { STATE.get_mut() };
-    *state = map;
+fn state_size() -> SizeReport {
+    with_state(|s| SizeReport {
+        entries: s.squares.len() as u32,
+        dot_cloud: s.squares.dot_cloud_len() as u32,
+        delta_entries: (s.squares.delta_len() + s.outbox.entries()) as u32,
+        bytes: rmp_serde::to_vec_named(&s.squares).map_or(0, |bytes| bytes.len()) as u32,
+    })
 }
@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+
+use convergent_experiment_protocol::{PendingDeltas, Square, SquareId};
+use sypytkowski_convergent::delta_state::awormap::Deltas;
+
+/// Most batches kept apart. Past this the two oldest are merged into one that takes the newer
+/// sequence number, acking the older one then just doesn't drop anything yet.
+const MAX_BATCHES: usize = 64;
+
+/// Deltas handed out to JS that it hasn't acked yet. Whatever JS does with them, they're handed
+/// out again with every later batch until acked, so deltas sent over a connection that dropped
+/// aren't lost.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    last_seq: u32,
+    unacked: VecDeque<(u32, Deltas<SquareId, Square>)>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, deltas: Deltas<SquareId, Square>) {
+        self.last_seq += 1;
+        self.unacked.push_back((self.last_seq, deltas));
+        if self.unacked.len() > MAX_BATCHES {
+            let (_, mut oldest) = self.unacked.pop_front().unwrap();
+            let (seq, next) = self.unacked.pop_front().unwrap();
+            oldest.merge_in_place(&next);
+            self.unacked.push_front((seq, oldest));
+        }
+    }
+
+    /// Acks are cumulative, acking `seq` acks every batch before it as well
+    pub fn ack(&mut self, seq: u32) {
+        while self.unacked.front().is_some_and(|(s, _)| *s <= seq) {
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Merges every batch into one under the latest sequence number, so acks of earlier ones
+    /// don't drop anything until that one is acked
+    pub fn compact(&mut self) {
+        if self.unacked.len() > 1 {
+            let pending = self.pending();
+            self.unacked.clear();
+            self.unacked.push_back((pending.seq, pending.deltas));
+        }
+    }
+
+    /// Entries across the unacked batches
+    pub fn entries(&self) -> usize {
+        self.unacked
+            .iter()
+            .map(|(_, batch)| batch.values().len())
+            .sum()
+    }
+
+    /// Drops every batch, sequence numbers carry on so acks handed out before don't drop later
+    /// batches
+    pub fn clear(&mut self) {
+        self.unacked.clear();
+    }
+
+    /// Every unacked batch merged into one, along with the sequence number that acks all of them
+    pub fn pending(&self) -> PendingDeltas {
+        let mut deltas = Deltas::default();
+        for (_, batch) in &self.unacked {
+            deltas.merge_in_place(batch);
+        }
+        PendingDeltas {
+            seq: self.last_seq,
+            deltas,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convergent_experiment_protocol::{ReplicaId, Square, SquareId};
+    use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+    use super::{Outbox, MAX_BATCHES};
+
+    #[test]
+    fn resends_until_acked() {
+        let replica = ReplicaId::from(1);
+        let mut map = AWORMap::default();
+        let mut outbox = Outbox::default();
+        for i in 0..2 {
+            map.insert(replica, SquareId(i), Square::default());
+            outbox.push(map.split_mut().unwrap());
+        }
+
+        let pending = outbox.pending();
+        assert_eq!(pending.seq, 2);
+        let mut remote = AWORMap::default();
+        remote.merge_delta_in_place(&pending.deltas);
+        assert_eq!(remote.len(), 2);
+
+        outbox.ack(1);
+        let mut remote = AWORMap::default();
+        remote.merge_delta_in_place(&outbox.pending().deltas);
+        assert!(remote.contains_key(&SquareId(1)) && !remote.contains_key(&SquareId(0)));
+        outbox.ack(2);
+        assert_eq!(outbox.pending().deltas, Default::default());
+        assert_eq!(outbox.entries(), 0);
+
+        // Batches past the cap are merged, nothing goes missing
+        for i in 0..MAX_BATCHES as u32 + 1 {
+            map.insert(replica, SquareId(i), Square::default());
+            outbox.push(map.split_mut().unwrap());
+        }
+        outbox.ack(3);
+        let mut remote = AWORMap::default();
+        remote.merge_delta_in_place(&outbox.pending().deltas);
+        assert_eq!(remote.len(), MAX_BATCHES + 1);
+
+        let entries = outbox.entries();
+        outbox.compact();
+        assert_eq!(outbox.entries(), entries);
+        outbox.ack(outbox.pending().seq - 1);
+        assert_eq!(outbox.entries(), entries);
+    }
+}
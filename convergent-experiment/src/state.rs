@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use convergent_experiment_protocol::{DocId, Square, SquareId};
+use once_cell::sync::Lazy;
+use sypytkowski_convergent::delta_state::awormap::AWORMap;
+use sypytkowski_convergent::delta_state::counter_map::CounterMap;
+use sypytkowski_convergent::delta_state::grid::Grid;
+use sypytkowski_convergent::delta_state::mvreg::MVReg;
+use sypytkowski_convergent::delta_state::pncounter::PNCounter;
+use sypytkowski_convergent::delta_state::undo::UndoManager;
+
+use crate::outbox::Outbox;
+
+// We maintain the global state in a static so that we do not need to pass it from JavaScript
+// every time we call the reducer. This avoids significant serialization overhead we would incur
+// otherwise.
+static STATE: Lazy<Mutex<PluginState>> = Lazy::new(Default::default);
+
+/// Everything the plugin keeps between calls
+pub struct PluginState {
+    pub squares: AWORMap<SquareId, Square>,
+    /// Only local `set`/`del` calls are recorded, so undoing never reverts another user's changes
+    pub undo: UndoManager<AWORMap<SquareId, Square>>,
+    pub outbox: Outbox,
+    /// Documents hosted next to `squares`, they don't take part in undo or the outbox
+    pub docs: BTreeMap<DocId, AWORMap<SquareId, Square>>,
+    pub counter: PNCounter,
+    pub register: MVReg<String>,
+    /// Cells of the table demo
+    pub table: Grid<String>,
+    /// Likes of each square, kept apart from `squares` so liking doesn't conflict with moving
+    pub likes: CounterMap<SquareId>,
+}
+
+impl Default for PluginState {
+    fn default() -> Self {
+        Self {
+            squares: AWORMap::default(),
+            undo: UndoManager::with_limit(100),
+            outbox: Outbox::default(),
+            docs: BTreeMap::new(),
+            counter: PNCounter::default(),
+            register: MVReg::default(),
+            table: Grid::default(),
+            likes: CounterMap::default(),
+        }
+    }
+}
+
+/// Runs `f` with the state locked. The lock isn't re-entrant, `f` must not call back into the
+/// exported functions.
+pub fn with_state<R>(f: impl FnOnce(&mut PluginState) -> R) -> R {
+    let mut state = STATE
+        .lock()
+        .expect("Plugin state poisoned by an earlier panic");
+    f(&mut state)
+}
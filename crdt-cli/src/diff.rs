@@ -0,0 +1,82 @@
+use serde_json::Value;
+
+/// Lines telling where `a` and `b` differ, as `path: a -> b`. Elements of arrays that only one side
+/// has are listed as `path[-] a` and `path[+] b` instead. Arrays holding the same elements in
+/// another order count as the same, hash sets come out of serde in any order.
+pub fn diff(a: &Value, b: &Value) -> Vec<String> {
+    let mut lines = vec![];
+    diff_at("", a, b, &mut lines);
+    lines
+}
+
+fn diff_at(path: &str, a: &Value, b: &Value, lines: &mut Vec<String>) {
+    match (a, b) {
+        _ if a == b => {}
+        (Value::Object(a), Value::Object(b)) => {
+            let mut keys = a.keys().chain(b.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                match (a.get(key), b.get(key)) {
+                    (Some(a), Some(b)) => diff_at(&path, a, b, lines),
+                    (a, b) => lines.push(format!("{path}: {} -> {}", show(a), show(b))),
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            let mut only_b = b.iter().collect::<Vec<_>>();
+            let mut only_a = vec![];
+            for value in a {
+                match only_b.iter().position(|other| *other == value) {
+                    Some(i) => {
+                        only_b.swap_remove(i);
+                    }
+                    None => only_a.push(value),
+                }
+            }
+            lines.extend(only_a.into_iter().map(|value| format!("{path}[-] {value}")));
+            lines.extend(only_b.into_iter().map(|value| format!("{path}[+] {value}")));
+        }
+        (a, b) => lines.push(format!("{path}: {a} -> {b}")),
+    }
+}
+
+fn show(value: Option<&Value>) -> String {
+    value.map_or("-".to_string(), Value::to_string)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::diff;
+
+    #[test]
+    fn lists_what_changed() {
+        let a = json!({"seq": 3, "version": {"1": 2}, "set": [1, 2, 3], "gone": true});
+        let b = json!({"seq": 4, "version": {"1": 2, "2": 1}, "set": [3, 4, 1]});
+        assert_eq!(
+            diff(&a, &b),
+            [
+                "gone: true -> -",
+                "seq: 3 -> 4",
+                "set[-] 2",
+                "set[+] 4",
+                "version.2: - -> 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_the_order_of_arrays() {
+        let a = json!({"set": [[1, {"a": 1}], [2, {"b": 2}]]});
+        let b = json!({"set": [[2, {"b": 2}], [1, {"a": 1}]]});
+        assert!(diff(&a, &b).is_empty());
+        assert_eq!(diff(&json!([1, 1]), &json!([1])), ["[-] 1"]);
+    }
+}
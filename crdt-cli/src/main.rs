@@ -0,0 +1,344 @@
+//! Inspects and fixes up the event logs replicas keep in their stores, for tracking down
+//! replication bugs in persisted data.
+//!
+//! Opens file stores, PostgreSQL and Redis. Replicas have to be stopped while the log is changed
+//! under them by `compact` or `import`.
+mod diff;
+mod store;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+use sypytkowski_commutative::{
+    counter::Counter, lseq::LSeq, lwwreg::LWWRegister, mvreg::MVRegister, orset::ORSet, tree::Tree,
+    Crdt, Event, ReplicaId, Replicator, Store, VTime,
+};
+
+use store::{Db, Location, Replica};
+
+#[derive(Debug, Parser)]
+#[command(name = "crdt-cli", about = "Inspects the event logs of replicas")]
+struct Cli {
+    /// Directory of a file store, or a postgres:// or redis:// URL
+    #[arg(long, short, env = "CRDT_STORE")]
+    store: Location,
+
+    /// What the replica replicates, values of sets, registers and sequences are strings
+    #[arg(long, value_enum, default_value_t = Kind::Counter)]
+    crdt: Kind,
+
+    /// Id of the replica, picks its log in PostgreSQL. Stores that have a snapshot know it
+    /// already.
+    #[arg(long)]
+    replica: Option<u128>,
+
+    /// Prefix of the replica's keys in Redis
+    #[arg(long)]
+    prefix: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Kind {
+    Counter,
+    Orset,
+    Lwwreg,
+    Mvreg,
+    Lseq,
+    Tree,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Lists the events along with their versions
+    Events {
+        #[arg(long, default_value_t = 1)]
+        from: u64,
+    },
+    /// Prints the state as of an event of the log, the last one if left out
+    State {
+        #[arg(long)]
+        at: Option<u64>,
+    },
+    /// Lists the differences with the replica in another store, both as they'd start up
+    Diff {
+        other: Location,
+        /// `--replica` of the other store, the same as this one's if left out
+        #[arg(long)]
+        other_replica: Option<u128>,
+        /// `--prefix` of the other store, the same as this one's if left out
+        #[arg(long)]
+        other_prefix: Option<String>,
+    },
+    /// Drops what every replica has seen from the snapshot
+    Compact,
+    /// Writes the events as JSON lines
+    Export {
+        #[arg(long, default_value_t = 1)]
+        from: u64,
+    },
+    /// Appends events written by `export` after the last one in the store, reads stdin if no
+    /// file is given
+    Import { file: Option<PathBuf> },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    match cli.crdt {
+        Kind::Counter => run(cli, |_| Counter::default()).await,
+        Kind::Orset => run(cli, |_| ORSet::<String>::new()).await,
+        Kind::Lwwreg => run(cli, LWWRegister::<String>::new).await,
+        Kind::Mvreg => run(cli, |_| MVRegister::<String>::new()).await,
+        Kind::Lseq => run(cli, LSeq::<String>::new).await,
+        Kind::Tree => run(cli, Tree::<String, String>::new).await,
+    }
+}
+
+async fn run<C>(cli: Cli, crdt: fn(ReplicaId) -> C) -> Result<()>
+where
+    C: Crdt + Serialize + DeserializeOwned + 'static,
+    C::EData: Serialize + DeserializeOwned + 'static,
+    C::State: Serialize,
+{
+    let replica = Replica {
+        id: cli.replica,
+        prefix: cli.prefix,
+    };
+    let id = ReplicaId::new(cli.replica.unwrap_or_default());
+    let create = matches!(cli.command, Command::Import { .. });
+    let mut db = Db::<C>::open(&cli.store, &replica, create).await?;
+
+    match cli.command {
+        Command::Events { from } => {
+            let mut events = db.load_events(from);
+            while let Some(event) = events.next().await {
+                let event = event?;
+                println!(
+                    "{:>6}  {}:{}  {}  {}",
+                    event.local_seq(),
+                    event.origin().as_u128(),
+                    event.origin_seq(),
+                    version(event.version()),
+                    serde_json::to_string(event.data())?
+                );
+            }
+        }
+        Command::State { at } => {
+            let crdt = match at {
+                Some(at) => state_at(&mut db, crdt(id), at).await?,
+                None => Replicator::new(id, crdt(id), db).await?.crdt().clone(),
+            };
+            println!("{}", serde_json::to_string_pretty(&crdt.query())?);
+        }
+        Command::Diff {
+            other,
+            other_replica,
+            other_prefix,
+        } => {
+            let other_replica = Replica {
+                id: other_replica.or(replica.id),
+                prefix: other_prefix.or(replica.prefix),
+            };
+            let other_id = ReplicaId::new(other_replica.id.unwrap_or_default());
+            let other = Db::<C>::open(&other, &other_replica, false).await?;
+            let a = Replicator::new(id, crdt(id), db).await?;
+            let b = Replicator::new(other_id, crdt(other_id), other).await?;
+            let lines = diff::diff(&describe(&a), &describe(&b));
+            if lines.is_empty() {
+                println!("No differences");
+            }
+            for line in lines {
+                println!("{line}");
+            }
+        }
+        Command::Compact => {
+            let mut replica = Replicator::new(id, crdt(id), db).await?;
+            let stable = replica.stable_version();
+            replica.compact().await;
+            println!("Compacted up to {}", version(&stable));
+        }
+        Command::Export { from } => export(&mut db, from, &mut io::stdout().lock()).await?,
+        Command::Import { file } => {
+            let imported = match file {
+                Some(path) => {
+                    let file = File::open(&path)
+                        .with_context(|| format!("Failed to open {}", path.display()))?;
+                    import(&mut db, BufReader::new(file)).await?
+                }
+                None => import(&mut db, io::stdin().lock()).await?,
+            };
+            eprintln!("Imported {imported} events");
+        }
+    }
+    Ok(())
+}
+
+/// `[replica:seq, ..]`
+fn version(version: &VTime) -> String {
+    let entries = version
+        .iter()
+        .map(|(replica, seq)| format!("{}:{seq}", replica.as_u128()))
+        .collect::<Vec<_>>();
+    format!("[{}]", entries.join(", "))
+}
+
+/// What [`Command::Diff`] compares, the state as queried along with everything the replica keeps
+fn describe<C, S>(replica: &Replicator<C, S>) -> serde_json::Value
+where
+    C: Crdt + Serialize,
+    C::State: Serialize,
+    S: Store<C>,
+{
+    serde_json::json!({
+        "state": replica.query(),
+        "replica": replica.snapshot(),
+    })
+}
+
+/// Replays the log up to `at`, on top of the snapshot unless the snapshot is past it already.
+/// The log is never truncated, so starting over from the beginning always works.
+async fn state_at<C: Crdt, S: Store<C>>(store: &mut S, mut crdt: C, at: u64) -> Result<C> {
+    let mut from = 1;
+    if let Some(snapshot) = store.load_snapshot().await {
+        if snapshot.seq() <= at {
+            crdt = snapshot.crdt().clone();
+            from = snapshot.seq() + 1;
+        }
+    }
+    let mut events = store.load_events(from);
+    while let Some(event) = events.next().await {
+        let event = event?;
+        if event.local_seq() > at {
+            break;
+        }
+        crdt.effect(event);
+    }
+    Ok(crdt)
+}
+
+async fn export<C, S>(store: &mut S, from: u64, out: &mut impl Write) -> Result<()>
+where
+    C: Crdt,
+    C::EData: Serialize,
+    S: Store<C>,
+{
+    let mut events = store.load_events(from);
+    while let Some(event) = events.next().await {
+        writeln!(out, "{}", serde_json::to_string(&event?)?)?;
+    }
+    Ok(())
+}
+
+/// Stores can't take events in the middle of their log, so every event has to come after the
+/// last one there and after the one before it. Nothing is saved unless all of them do.
+async fn import<C, S>(store: &mut S, input: impl BufRead) -> Result<usize>
+where
+    C: Crdt,
+    C::EData: DeserializeOwned,
+    S: Store<C>,
+{
+    let mut last = 0;
+    {
+        let mut events = store.load_events(1);
+        while let Some(event) = events.next().await {
+            last = event?.local_seq();
+        }
+    }
+
+    let mut events = vec![];
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: Event<C::EData> = serde_json::from_str(&line)
+            .with_context(|| format!("Line {} isn't an event", i + 1))?;
+        if event.local_seq() <= last {
+            return Err(anyhow!(
+                "Event {} on line {} doesn't come after {}",
+                event.local_seq(),
+                i + 1,
+                last
+            ));
+        }
+        last = event.local_seq();
+        events.push(event);
+    }
+    let imported = events.len();
+    store.save_events(events.into_iter()).await;
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use sypytkowski_commutative::{
+        counter::Counter, filedb::FileDb, protocol::Protocol, Crdt, ReplicaId, Replicator,
+    };
+
+    use super::{export, import, state_at};
+
+    fn dir() -> PathBuf {
+        std::env::temp_dir().join(format!("crdt-cli-{}", ReplicaId::random().as_u128()))
+    }
+
+    async fn replica(dir: &PathBuf) -> Replicator<Counter, FileDb<Counter>> {
+        let store = FileDb::open(dir).await.unwrap();
+        Replicator::new(ReplicaId::new(1), Counter::default(), store)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn replays_up_to_an_event() {
+        let dir = dir();
+        let mut replica = replica(&dir).await;
+        for i in 1..=4 {
+            replica.send(Protocol::Command(i)).await;
+        }
+        // The snapshot is at the end of the log, the ones before it start over
+        replica.compact().await;
+
+        let mut store = FileDb::open(&dir).await.unwrap();
+        for (at, expected) in [(0, 0), (2, 3), (4, 10), (9, 10)] {
+            let counter = state_at(&mut store, Counter::default(), at).await.unwrap();
+            assert_eq!(counter.query(), expected, "at {at}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn exports_and_imports() {
+        let (from, to) = (dir(), dir());
+        let mut source = replica(&from).await;
+        for i in 1..=3 {
+            source.send(Protocol::Command(i)).await;
+        }
+        let mut exported = vec![];
+        let mut store = FileDb::<Counter>::open(&from).await.unwrap();
+        export(&mut store, 2, &mut exported).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&exported).lines().count(), 2);
+
+        // Only what comes after the log
+        let mut store = FileDb::<Counter>::open(&to).await.unwrap();
+        replica(&to).await.send(Protocol::Command(10)).await;
+        let err = import(&mut store, &b"\n{}"[..]).await.unwrap_err();
+        assert_eq!(err.to_string(), "Line 2 isn't an event");
+        assert_eq!(import(&mut store, &exported[..]).await.unwrap(), 2);
+        assert_eq!(replica(&to).await.query(), 15);
+        let err = import(&mut store, &exported[..]).await.unwrap_err();
+        assert_eq!(err.to_string(), "Event 2 on line 1 doesn't come after 3");
+
+        std::fs::remove_dir_all(from).unwrap();
+        std::fs::remove_dir_all(to).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::PgPool;
+use sypytkowski_commutative::{
+    filedb::FileDb, pgdb::PgDb, redisdb::RedisDb, Crdt, Event, ReplicaId, ReplicationState, Store,
+    StoreError,
+};
+
+/// Where a store keeps its events
+#[derive(Debug, Clone)]
+pub enum Location {
+    File(PathBuf),
+    Postgres(String),
+    Redis(String),
+}
+
+/// URLs go to the database, anything else is the directory of a file store
+impl FromStr for Location {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(
+            if s.starts_with("postgres://") || s.starts_with("postgresql://") {
+                Location::Postgres(s.to_string())
+            } else if s.starts_with("redis://") || s.starts_with("rediss://") {
+                Location::Redis(s.to_string())
+            } else {
+                Location::File(s.into())
+            },
+        )
+    }
+}
+
+/// Which replica's log to open at a [`Location`], file stores hold a single one
+#[derive(Debug, Clone, Default)]
+pub struct Replica {
+    /// Picks the rows in PostgreSQL
+    pub id: Option<u128>,
+    /// Prefix of the keys in Redis
+    pub prefix: Option<String>,
+}
+
+/// Any of the stores the tool can open
+pub enum Db<C> {
+    File(FileDb<C>),
+    Postgres(PgDb<C>),
+    Redis(RedisDb<C>),
+}
+
+impl<C> Db<C> {
+    /// Only creates a file store if `create` is set, so a mistyped path doesn't read as an empty
+    /// log. PostgreSQL gets its tables created if they aren't there.
+    pub async fn open(location: &Location, replica: &Replica, create: bool) -> Result<Self> {
+        Ok(match location {
+            Location::File(dir) => {
+                if !create && !dir.is_dir() {
+                    return Err(anyhow!("No store at {}", dir.display()));
+                }
+                Db::File(FileDb::open(dir).await?)
+            }
+            Location::Postgres(url) => {
+                let id = replica
+                    .id
+                    .ok_or_else(|| anyhow!("PostgreSQL stores need --replica"))?;
+                let pool = PgPool::connect(url)
+                    .await
+                    .with_context(|| format!("Failed to connect to {url}"))?;
+                PgDb::<C>::migrate(&pool).await?;
+                Db::Postgres(PgDb::new(pool, ReplicaId::new(id)))
+            }
+            Location::Redis(url) => {
+                let prefix = replica
+                    .prefix
+                    .clone()
+                    .ok_or_else(|| anyhow!("Redis stores need --prefix"))?;
+                let client = redis::Client::open(url.as_str())?;
+                let db = RedisDb::connect(&client, prefix)
+                    .await
+                    .with_context(|| format!("Failed to connect to {url}"))?;
+                Db::Redis(db)
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl<C> Store<C> for Db<C>
+where
+    C: Crdt + Serialize + DeserializeOwned,
+    C::EData: Serialize + DeserializeOwned + 'static,
+{
+    async fn save_snapshot(&mut self, state: ReplicationState<C>) {
+        match self {
+            Db::File(db) => db.save_snapshot(state).await,
+            Db::Postgres(db) => db.save_snapshot(state).await,
+            Db::Redis(db) => db.save_snapshot(state).await,
+        }
+    }
+
+    async fn load_snapshot(&mut self) -> Option<ReplicationState<C>> {
+        match self {
+            Db::File(db) => db.load_snapshot().await,
+            Db::Postgres(db) => db.load_snapshot().await,
+            Db::Redis(db) => db.load_snapshot().await,
+        }
+    }
+
+    fn load_events(
+        &mut self,
+        start_seq: u64,
+    ) -> BoxStream<'_, Result<Event<C::EData>, StoreError>> {
+        match self {
+            Db::File(db) => db.load_events(start_seq),
+            Db::Postgres(db) => db.load_events(start_seq),
+            Db::Redis(db) => db.load_events(start_seq),
+        }
+    }
+
+    async fn save_events<I: Iterator<Item = Event<C::EData>> + Send>(&mut self, events: I) {
+        match self {
+            Db::File(db) => db.save_events(events).await,
+            Db::Postgres(db) => db.save_events(events).await,
+            Db::Redis(db) => db.save_events(events).await,
+        }
+    }
+}
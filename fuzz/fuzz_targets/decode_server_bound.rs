@@ -0,0 +1,21 @@
+//! Feeds arbitrary bytes to the decoder the server runs on every websocket message. Decoding may
+//! fail but never panic, and whatever decodes has to survive a round trip through the codec.
+#![no_main]
+
+use convergent_experiment_protocol::{Square, SquareId};
+use convergent_experiment_ws::{
+    codec::{Codec, MsgPack},
+    proto::ServerBound,
+};
+use libfuzzer_sys::fuzz_target;
+use sypytkowski_convergent::delta_state::awormap::AWORMap;
+
+type Squares = AWORMap<SquareId, Square>;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(msg) = MsgPack::decode::<ServerBound<Squares>>(data) else {
+        return;
+    };
+    let bytes = MsgPack::encode(&msg).expect("decoded message should encode");
+    MsgPack::decode::<ServerBound<Squares>>(&bytes).expect("encoded message should decode");
+});
@@ -0,0 +1,91 @@
+//! Replays fuzz input as adds, removes and merges on a few replicas' `DotKernel`s, then checks
+//! that merging the resulting states (and their clocks) behaves like a join: commutative,
+//! associative and idempotent, with `merge_in_place` agreeing with `merge`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sypytkowski_convergent::{
+    delta_state::dot::{Dot, DotKernel, VectorClock},
+    ReplicaId,
+};
+
+const REPLICAS: usize = 3;
+
+fuzz_target!(|data: &[u8]| {
+    let mut kernels = vec![DotKernel::<u8>::default(); REPLICAS];
+    let mut deltas = vec![DotKernel::<u8>::default(); REPLICAS];
+
+    for op in data.chunks_exact(2) {
+        let r = (op[0] as usize) % REPLICAS;
+        let value = op[1] % 16;
+        match op[0] / REPLICAS as u8 % 4 {
+            0 | 1 => {
+                let mut delta = DotKernel::default();
+                kernels[r].add(ReplicaId::from(r as u64), value, &mut delta);
+                deltas[r] = deltas[r].merge(&delta);
+            }
+            2 => {
+                let mut delta = DotKernel::default();
+                kernels[r].remove(&value, &mut delta);
+                deltas[r] = deltas[r].merge(&delta);
+            }
+            _ => {
+                // Ship either the full state or the accumulated delta of another replica
+                let from = (value as usize) % REPLICAS;
+                let other = if value >= 8 {
+                    std::mem::take(&mut deltas[from])
+                } else {
+                    kernels[from].clone()
+                };
+                kernels[r].merge_in_place(&other);
+            }
+        }
+    }
+
+    let [a, b, c] = [&kernels[0], &kernels[1], &kernels[2]];
+    assert_eq!(a.merge(b), b.merge(a), "merge is not commutative");
+    assert_eq!(
+        a.merge(b).merge(c),
+        a.merge(&b.merge(c)),
+        "merge is not associative"
+    );
+    let ab = a.merge(b);
+    assert_eq!(ab.merge(&ab), ab, "merge is not idempotent");
+    let mut in_place = a.clone();
+    in_place.merge_in_place(b);
+    assert_eq!(in_place, ab, "merge_in_place disagrees with merge");
+
+    let clocks = data
+        .chunks_exact(3)
+        .take(64)
+        .fold(vec![VectorClock::default(); REPLICAS], |mut clocks, op| {
+            let r = (op[0] as usize) % REPLICAS;
+            let replica = ReplicaId::from((op[1] % 8) as u64);
+            if op[2] == u8::MAX {
+                clocks[r].retire(replica);
+            } else {
+                let n = clocks[r].get(&replica).copied().unwrap_or(0) + op[2] as u64;
+                if !clocks[r].is_retired(replica) {
+                    clocks[r].insert(replica, n);
+                }
+            }
+            clocks
+        });
+    let merge = |a: &VectorClock, b: &VectorClock| {
+        let mut ret = a.clone();
+        ret.merge_in_place(b);
+        ret
+    };
+    let [a, b, c] = [&clocks[0], &clocks[1], &clocks[2]];
+    assert_eq!(merge(a, b), merge(b, a), "clock merge is not commutative");
+    assert_eq!(
+        merge(&merge(a, b), c),
+        merge(a, &merge(b, c)),
+        "clock merge is not associative"
+    );
+    assert_eq!(merge(a, a), *a, "clock merge is not idempotent");
+    let ab = merge(a, b);
+    for (&id, &n) in a.iter().chain(b.iter()) {
+        assert!(ab.contains(Dot(id, n)), "clock merge lost {:?}", Dot(id, n));
+    }
+});
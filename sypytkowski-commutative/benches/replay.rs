@@ -0,0 +1,49 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use futures::executor::block_on;
+use sypytkowski_commutative::{
+    counter::Counter, memdb::InMemoryDb, protocol::Protocol, ReplicaId, Replicator, VTime,
+};
+
+const EVENTS: u64 = 100_000;
+
+/// A counter replica that went through 100k increments, all of them in its event log
+fn setup() -> (
+    Replicator<Counter, InMemoryDb<Counter>>,
+    InMemoryDb<Counter>,
+) {
+    let db = InMemoryDb::default();
+    let mut replica = block_on(Replicator::new(
+        ReplicaId::random(),
+        Counter::default(),
+        db.clone(),
+    ))
+    .unwrap();
+    for i in 0..EVENTS {
+        block_on(replica.send(Protocol::Command(i as i64)));
+    }
+    (replica, db)
+}
+
+fn replay(c: &mut Criterion) {
+    let (mut replica, db) = setup();
+    let id = ReplicaId::random();
+
+    let mut group = c.benchmark_group("replicator_100k_events");
+    group.sample_size(10);
+    // Serving a peer that has seen nothing yet
+    group.bench_function("replay", |bench| {
+        bench.iter(|| black_box(block_on(replica.replay(id, VTime::default(), 1, EVENTS))))
+    });
+    // Starting up again from the event log
+    group.bench_function("recover", |bench| {
+        bench.iter_batched(
+            || db.clone(),
+            |db| block_on(Replicator::new(id, Counter::default(), db)).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, replay);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use futures::executor::block_on;
+use sypytkowski_commutative::{
+    memdb::InMemoryDb,
+    protocol::Protocol,
+    rga::{Command, Rga},
+    ReplicaId, Replicator,
+};
+
+type Doc = Replicator<Rga<char>, InMemoryDb<Rga<char>>>;
+
+const CHARS: u32 = 100_000;
+const EDITS: u32 = 1_000;
+
+fn doc() -> Doc {
+    let id = ReplicaId::random();
+    block_on(Replicator::new(id, Rga::new(id), InMemoryDb::default())).unwrap()
+}
+
+/// Types `chars` characters one after the other
+fn typed(chars: u32) -> Doc {
+    let mut doc = doc();
+    for i in 0..chars {
+        let c = char::from(b'a' + (i % 26) as u8);
+        block_on(doc.send(Protocol::Command(Command::Insert(i, c))));
+    }
+    doc
+}
+
+/// Positions spread over the whole document, the same on every run
+fn positions(len: u32) -> impl Iterator<Item = u32> {
+    (0..EDITS).map(move |i| (i.wrapping_mul(2_654_435_761) >> 7) % len)
+}
+
+fn rga(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rga_100k_chars");
+    group.sample_size(10);
+
+    group.bench_function("type", |bench| bench.iter(|| typed(CHARS)));
+
+    let base = typed(CHARS);
+    group.bench_function("insert_1k_random", |bench| {
+        bench.iter_batched(
+            || base.clone(),
+            |mut doc| {
+                for i in positions(CHARS) {
+                    block_on(doc.send(Protocol::Command(Command::Insert(i, '!'))));
+                }
+                doc
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("remove_1k_random", |bench| {
+        bench.iter_batched(
+            || base.clone(),
+            |mut doc| {
+                for i in positions(CHARS - EDITS) {
+                    block_on(doc.send(Protocol::Command(Command::RemoveAt(i))));
+                }
+                doc
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, rga);
+criterion_main!(benches);
@@ -0,0 +1,126 @@
+//! Checksums of what the CRDTs read as, so replicas can cheaply check they converged.
+//!
+//! Values are fed through [`Hash`] to FNV-1a rather than to
+//! [`DefaultHasher`](std::collections::hash_map::DefaultHasher), whose algorithm may change
+//! between Rust versions. Integers hash in native byte order, so this only holds between
+//! replicas of the same endianness.
+use std::hash::{Hash, Hasher};
+
+use crate::{
+    counter::Counter, lseq::LSeq, lwwreg::LWWRegister, mvreg::MVRegister, orset::ORSet, rga::Rga,
+    tree::Tree, Crdt,
+};
+
+/// 64 bit FNV-1a
+#[derive(Debug, Clone, Copy)]
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+pub fn stable_hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = StableHasher::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Stable hash of [`Crdt::query`]
+pub trait Checksum {
+    fn checksum(&self) -> u64;
+}
+
+impl Checksum for Counter {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.query())
+    }
+}
+
+impl<V: Hash + Sync + Send + Clone + std::fmt::Debug> Checksum for LSeq<V> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.query())
+    }
+}
+
+impl<V: Hash + Default + Clone + Send + Sync + std::fmt::Debug> Checksum for LWWRegister<V> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.query())
+    }
+}
+
+impl<V: Hash + Ord + Default + Clone + Send + Sync + std::fmt::Debug> Checksum for MVRegister<V> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.query())
+    }
+}
+
+impl<V: Eq + Hash + Clone + Send + Sync + std::fmt::Debug> Checksum for ORSet<V> {
+    /// The set has no order, so the hashes of the elements are summed up
+    fn checksum(&self) -> u64 {
+        self.query()
+            .iter()
+            .fold(0u64, |acc, val| acc.wrapping_add(stable_hash(val)))
+    }
+}
+
+impl<V: Hash + Sync + Send + Clone + std::fmt::Debug> Checksum for Rga<V> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.query())
+    }
+}
+
+impl<N, V> Checksum for Tree<N, V>
+where
+    N: Ord + Hash + Clone + Send + Sync + std::fmt::Debug,
+    V: Hash + Clone + Send + Sync + std::fmt::Debug,
+{
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.query())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        checksum::Checksum, memdb::InMemoryDb, orset, orset::ORSet, protocol::Protocol, replicate,
+        ReplicaId, Replicator,
+    };
+
+    #[tokio::test]
+    async fn replicas_converge() {
+        let alice_id = ReplicaId(0);
+        let bob_id = ReplicaId(1);
+        let mut alice =
+            Replicator::new(alice_id, ORSet::new(), InMemoryDb::<ORSet<u32>>::default())
+                .await
+                .unwrap();
+        let mut bob = Replicator::new(bob_id, ORSet::new(), InMemoryDb::<ORSet<u32>>::default())
+            .await
+            .unwrap();
+
+        let _ = alice.send(Protocol::Command(orset::Command::Add(1))).await;
+        let _ = bob.send(Protocol::Command(orset::Command::Add(2))).await;
+        assert_ne!(alice.checksum(), bob.checksum());
+
+        replicate(&mut alice, &mut bob).await;
+        replicate(&mut bob, &mut alice).await;
+
+        assert_eq!(alice.checksum(), bob.checksum());
+        assert_ne!(alice.checksum(), ORSet::<u32>::new().checksum());
+    }
+}
@@ -0,0 +1,49 @@
+//! Where replicas get the time from, see [`Clock`].
+//!
+//! Nothing reads the wall clock directly: a [`Replicator`](crate::Replicator) stamps the commands
+//! it prepares with a hybrid logical clock ([`HlcClock`]) over the [`Clock`] it was given, which
+//! is the system clock unless [`Replicator::with_clock`](crate::Replicator::with_clock) says
+//! otherwise. Tests hand it a [`ManualClock`] to decide what time it is.
+//!
+//! The clocks and timestamps are the ones of [`sypytkowski_convergent::hlc`], which the LWW types
+//! of the delta state CRDTs are ordered by as well.
+use std::sync::Arc;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use sypytkowski_convergent::hlc::SystemClock;
+pub use sypytkowski_convergent::hlc::{Clock, Hlc, ManualClock};
+
+use crate::ReplicaId;
+
+/// Hybrid logical clock of a replica, over whichever [`Clock`] it was given
+pub type HlcClock = sypytkowski_convergent::hlc::HlcClock<Arc<dyn Clock + Send + Sync>>;
+
+pub(crate) fn hlc_clock(replica: ReplicaId, clock: impl Clock + Send + Sync + 'static) -> HlcClock {
+    HlcClock::with_clock(replica.into(), Arc::new(clock))
+}
+
+/// Timestamps carry the replica ids of the delta state CRDTs, they are the same 128 bits
+impl From<ReplicaId> for sypytkowski_convergent::ReplicaId {
+    fn from(id: ReplicaId) -> Self {
+        Self::new(id.as_u128())
+    }
+}
+
+/// The time of the browser. Wasm builds without the `wasm` feature have no clock to read, their
+/// time stands still at 0 and timestamps only count up logically.
+#[cfg(target_arch = "wasm32")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[cfg(target_arch = "wasm32")]
+impl Clock for SystemClock {
+    #[cfg(feature = "wasm")]
+    fn now_millis(&self) -> u64 {
+        js_sys::Date::now() as u64
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    fn now_millis(&self) -> u64 {
+        0
+    }
+}
@@ -0,0 +1,236 @@
+//! Property tests running random commands on 2 to 4 [`Replicator`]s backed by [`InMemoryDb`],
+//! with replication messages delivered in random order or lost along the way, checking that every
+//! replica ends up with the same state once they have all pulled from each other.
+//!
+//! Unlike the [simulator](crate::simulator) the whole run is generated by proptest, so a failure
+//! shrinks down to the few commands and deliveries that cause it.
+use std::fmt::Debug;
+
+use futures::executor::block_on;
+use proptest::{collection::vec, prelude::*};
+
+use crate::checksum::Checksum;
+use crate::memdb::InMemoryDb;
+use crate::protocol::{self as proto, Protocol};
+use crate::{counter::Counter, lseq, lseq::LSeq, orset, orset::ORSet, rga, rga::Rga};
+use crate::{Crdt, ReplicaId, Replicator};
+
+#[derive(Debug, Clone)]
+enum Step {
+    /// `replica` runs a command, `kind` and `arg` are turned into one by the test based on what
+    /// the replica currently sees
+    Command { replica: usize, kind: u8, arg: u32 },
+    /// `replica` asks `from` for the events it is missing, the request is queued
+    Pull { replica: usize, from: usize },
+    /// Delivers a queued message, the reply is queued in turn
+    Deliver(usize),
+    /// Loses a queued message
+    Drop(usize),
+    /// `replica` drops whatever it considers stable
+    Compact(usize),
+}
+
+fn step_strategy() -> impl Strategy<Value = Step> {
+    // Replicas and queue positions are taken modulo how many there are, small numbers just shrink
+    // to something readable
+    prop_oneof![
+        3 => (0..4usize, any::<u8>(), any::<u32>())
+            .prop_map(|(replica, kind, arg)| Step::Command { replica, kind, arg }),
+        2 => (0..4usize, 0..4usize).prop_map(|(replica, from)| Step::Pull { replica, from }),
+        4 => (0..16usize).prop_map(Step::Deliver),
+        1 => (0..16usize).prop_map(Step::Drop),
+        1 => (0..4usize).prop_map(Step::Compact),
+    ]
+}
+
+fn run_strategy() -> impl Strategy<Value = (usize, Vec<Step>)> {
+    (2..=4usize, vec(step_strategy(), 0..80))
+}
+
+struct Net<C: Crdt> {
+    replicas: Vec<Replicator<C, InMemoryDb<C>>>,
+    /// Messages in flight along with the index of the replica they are for
+    queue: Vec<(usize, Protocol<C::Cmd, C::EData>)>,
+}
+
+impl<C: Crdt> Net<C> {
+    fn pull(&mut self, replica: usize, from: usize) {
+        let state = &self.replicas[replica].state;
+        let replicate = proto::Replicate {
+            seq_nr: state
+                .observed
+                .get(&ReplicaId(from as u128))
+                .copied()
+                .unwrap_or(0)
+                + 1,
+            max_count: 100,
+            filter: state.version.clone(),
+            reply_to: state.id,
+        };
+        self.queue.push((from, Protocol::Replicate(replicate)));
+    }
+
+    async fn deliver(&mut self, i: usize) {
+        let (to, msg) = self.queue.remove(i);
+        let reply_to = match &msg {
+            Protocol::Replicate(replicate) => replicate.reply_to,
+            Protocol::Replicated(replicated) => replicated.from,
+            _ => unreachable!("Only replication messages are queued"),
+        };
+        match self.replicas[to].send(msg).await {
+            reply @ (Protocol::Replicate(_) | Protocol::Replicated(_)) => {
+                self.queue.push((reply_to.0 as usize, reply))
+            }
+            _ => {}
+        }
+    }
+
+    async fn flush(&mut self) {
+        while !self.queue.is_empty() {
+            self.deliver(0).await;
+        }
+    }
+}
+
+/// Replays `steps` on `n` replicas made by `crdt`, `cmd` picks the command to run from what the
+/// replica currently sees. Returns the final state of every replica.
+fn converges<C>(
+    n: usize,
+    steps: Vec<Step>,
+    mut crdt: impl FnMut(ReplicaId) -> C,
+    cmd: impl Fn(&C::State, u8, u32) -> C::Cmd,
+) -> Result<Vec<C::State>, TestCaseError>
+where
+    C: Crdt + Checksum,
+    C::State: PartialEq + Debug,
+{
+    block_on(async {
+        let mut net = Net {
+            replicas: vec![],
+            queue: vec![],
+        };
+        for i in 0..n {
+            let id = ReplicaId(i as u128);
+            let replica = Replicator::new(id, crdt(id), InMemoryDb::default())
+                .await
+                .unwrap();
+            net.replicas.push(replica);
+        }
+        // Replicas compact based on who they know of, so everyone knows everyone from the start
+        for replica in 0..n {
+            for peer in (0..n).filter(|&peer| peer != replica) {
+                let connect = proto::Connect {
+                    replica_id: ReplicaId(peer as u128),
+                };
+                let replicate = net.replicas[replica].send(Protocol::Connect(connect)).await;
+                net.queue.push((peer, replicate));
+            }
+        }
+
+        for step in steps {
+            match step {
+                Step::Command { replica, kind, arg } => {
+                    let replica = &mut net.replicas[replica % n];
+                    let cmd = cmd(&replica.query(), kind, arg);
+                    replica.send(Protocol::Command(cmd)).await;
+                }
+                Step::Pull { replica, from } if replica % n != from % n => {
+                    net.pull(replica % n, from % n)
+                }
+                Step::Deliver(i) if !net.queue.is_empty() => {
+                    let i = i % net.queue.len();
+                    net.deliver(i).await
+                }
+                Step::Drop(i) if !net.queue.is_empty() => {
+                    let i = i % net.queue.len();
+                    net.queue.remove(i);
+                }
+                Step::Compact(replica) => net.replicas[replica % n].compact().await,
+                _ => {}
+            }
+        }
+
+        // Whatever is still in flight arrives, then everyone pulls from everyone
+        net.flush().await;
+        for replica in 0..n {
+            for from in (0..n).filter(|&from| from != replica) {
+                net.pull(replica, from);
+            }
+        }
+        net.flush().await;
+
+        let checksums = net
+            .replicas
+            .iter()
+            .map(|replica| replica.checksum())
+            .collect::<Vec<_>>();
+        let states = net
+            .replicas
+            .iter_mut()
+            .map(|replica| replica.query())
+            .collect::<Vec<_>>();
+        for (checksum, state) in checksums.iter().zip(states.iter()).skip(1) {
+            prop_assert_eq!(state, &states[0]);
+            prop_assert_eq!(*checksum, checksums[0]);
+        }
+        Ok(states)
+    })
+}
+
+/// Removes a third of the time when there is something to remove, otherwise inserts `arg`
+fn sequence_cmd<V>(seen: &[V], kind: u8, arg: u32) -> (bool, u32) {
+    if !seen.is_empty() && kind % 3 == 0 {
+        (true, arg % seen.len() as u32)
+    } else {
+        (false, arg % (seen.len() as u32 + 1))
+    }
+}
+
+proptest! {
+    #[test]
+    fn counter((n, steps) in run_strategy()) {
+        let total = steps
+            .iter()
+            .map(|step| match step {
+                Step::Command { arg, .. } => (*arg % 100) as i64,
+                _ => 0,
+            })
+            .sum::<i64>();
+        let states = converges(n, steps, |_| Counter::default(), |_, _, arg| {
+            (arg % 100) as i64
+        })?;
+        prop_assert_eq!(states[0], total);
+    }
+
+    #[test]
+    fn orset((n, steps) in run_strategy()) {
+        converges(n, steps, |_| ORSet::<u8>::new(), |_, kind, arg| {
+            let val = (arg % 8) as u8;
+            if kind % 3 == 0 {
+                orset::Command::Remove(val)
+            } else {
+                orset::Command::Add(val)
+            }
+        })?;
+    }
+
+    #[test]
+    fn rga((n, steps) in run_strategy()) {
+        converges(n, steps, Rga::new, |seen, kind, arg| {
+            match sequence_cmd(seen, kind, arg) {
+                (true, i) => rga::Command::RemoveAt(i),
+                (false, i) => rga::Command::Insert(i, arg),
+            }
+        })?;
+    }
+
+    #[test]
+    fn lseq((n, steps) in run_strategy()) {
+        converges(n, steps, LSeq::new, |seen, kind, arg| {
+            match sequence_cmd(seen, kind, arg) {
+                (true, i) => lseq::Command::RemoveAt(i),
+                (false, i) => lseq::Command::Insert(i, arg),
+            }
+        })?;
+    }
+}
@@ -1,6 +1,12 @@
-use crate::Crdt;
+use std::borrow::Cow;
+
+use crate::{
+    memory::{MemoryUsage, Usage},
+    Crdt,
+};
 
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Counter {
     val: i64,
 }
@@ -16,6 +22,10 @@ impl Crdt for Counter {
         self.val
     }
 
+    fn view(&self) -> Cow<'_, Self::State> {
+        Cow::Borrowed(&self.val)
+    }
+
     fn prepare(&self, op: Self::Cmd) -> Self::EData {
         op
     }
@@ -25,9 +35,18 @@ impl Crdt for Counter {
     }
 }
 
+/// Nothing on the heap
+impl MemoryUsage for Counter {
+    fn memory_usage(&self) -> Usage {
+        Usage::default()
+    }
+}
+
 #[cfg(test)]
 mod test {
 
+    use std::borrow::Cow;
+
     use crate::{
         counter::Counter, memdb::InMemoryDb, protocol::Protocol, replicate, ReplicaId, Replicator,
     };
@@ -41,9 +60,11 @@ mod test {
             Counter::default(),
             InMemoryDb::<Counter>::default(),
         )
-        .await;
-        let mut bob =
-            Replicator::new(bob_id, Counter::default(), InMemoryDb::<Counter>::default()).await;
+        .await
+        .unwrap();
+        let mut bob = Replicator::new(bob_id, Counter::default(), InMemoryDb::<Counter>::default())
+            .await
+            .unwrap();
 
         let _ = alice.send(Protocol::Command(34)).await;
         let _ = bob.send(Protocol::Command(35)).await;
@@ -55,7 +76,8 @@ mod test {
         let bob_value = bob.query();
 
         assert_eq!(alice_value, 69);
-        assert_eq!(alice_value, bob_value)
+        assert_eq!(alice_value, bob_value);
+        assert!(matches!(alice.view(), Cow::Borrowed(69)))
     }
 
     // use proptest::{collection::btree_map, prelude::*};
@@ -0,0 +1,288 @@
+//! Encryption at rest for any [`Store`], see [`EncryptedStore`].
+//!
+//! Events and snapshots are serialized to msgpack and sealed with XChaCha20-Poly1305 under a key
+//! the caller provides. Every seal gets a fresh random 24 byte nonce, which is long enough that
+//! picking them at random never repeats one in practice, written in front of the ciphertext.
+//!
+//! The inner store sees [`Sealed`] in place of the CRDT: events only keep their `local_seq` in the
+//! clear, which stores index them by, and the snapshot nothing at all. The `local_seq` is
+//! authenticated along with the ciphertext, so a store that swaps events around fails decryption
+//! just like one that flips a bit.
+use async_trait::async_trait;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use futures::{stream::BoxStream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Crdt, Event, ReplicationState, Store, StoreError};
+
+pub use chacha20poly1305::Key;
+
+const NONCE_LEN: usize = 24;
+const SNAPSHOT: &[u8] = b"snapshot";
+
+/// What an [`EncryptedStore`] hands its inner store instead of the CRDT and the data of its events.
+/// It doesn't replicate anything, it only carries the ciphertext.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sealed(pub Vec<u8>);
+
+impl Crdt for Sealed {
+    type State = Vec<u8>;
+    type Cmd = ();
+    type EData = Vec<u8>;
+
+    fn query(&self) -> Self::State {
+        self.0.clone()
+    }
+
+    fn prepare(&self, _op: Self::Cmd) -> Self::EData {
+        vec![]
+    }
+
+    fn effect(&mut self, _event: Event<Self::EData>) {}
+}
+
+/// Encrypts everything on its way into `S` and decrypts it on the way out.
+///
+/// Events that don't decrypt come out of [`Store::load_events`] as [`StoreError::Corrupt`]. A
+/// snapshot that doesn't decrypt panics like one that doesn't decode in the other stores, since
+/// [`Store::load_snapshot`] has no way to report it.
+pub struct EncryptedStore<S> {
+    inner: S,
+    cipher: XChaCha20Poly1305,
+}
+
+impl<S> EncryptedStore<S> {
+    pub fn new(inner: S, key: &Key) -> Self {
+        Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(key),
+        }
+    }
+
+    /// A random key to encrypt with
+    pub fn generate_key() -> Key {
+        XChaCha20Poly1305::generate_key(&mut OsRng)
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<C, S> Store<C> for EncryptedStore<S>
+where
+    C: Crdt + Serialize + DeserializeOwned + 'static,
+    C::EData: Serialize + DeserializeOwned,
+    S: Store<Sealed> + Send,
+{
+    async fn save_snapshot(&mut self, state: ReplicationState<C>) {
+        let sealed = ReplicationState {
+            crdt: Sealed(seal(&self.cipher, &state, SNAPSHOT)),
+            ..Default::default()
+        };
+        self.inner.save_snapshot(sealed).await;
+    }
+
+    async fn load_snapshot(&mut self) -> Option<ReplicationState<C>> {
+        let sealed = self.inner.load_snapshot().await?;
+        match open(&self.cipher, &sealed.crdt.0, SNAPSHOT) {
+            Ok(state) => Some(state),
+            Err(err) => panic!("snapshot doesn't decrypt: {err}"),
+        }
+    }
+
+    fn load_events(
+        &mut self,
+        start_seq: u64,
+    ) -> BoxStream<'_, Result<Event<C::EData>, StoreError>> {
+        let cipher = &self.cipher;
+        self.inner
+            .load_events(start_seq)
+            .map(move |sealed| {
+                let sealed = sealed?;
+                let event: Event<C::EData> =
+                    open(cipher, &sealed.data, &sealed.local_seq.to_be_bytes())?;
+                Ok(event)
+            })
+            .boxed()
+    }
+
+    async fn save_events<I: Iterator<Item = Event<C::EData>> + Send>(&mut self, events: I) {
+        let sealed = events
+            .map(|event| Event {
+                origin: Default::default(),
+                origin_seq: 0,
+                local_seq: event.local_seq,
+                version: Default::default(),
+                data: seal(&self.cipher, &event, &event.local_seq.to_be_bytes()),
+                signature: None,
+            })
+            .collect::<Vec<_>>();
+        self.inner.save_events(sealed.into_iter()).await;
+    }
+}
+
+/// `value` as msgpack encrypted under a new nonce, which goes first
+fn seal<T: Serialize>(cipher: &XChaCha20Poly1305, value: &T, aad: &[u8]) -> Vec<u8> {
+    let msg = rmp_serde::to_vec_named(value).expect("msgpack encoding doesn't fail for plain data");
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: &msg, aad })
+        .expect("encrypting fits in memory");
+    [nonce.as_slice(), &ciphertext].concat()
+}
+
+fn open<T: DeserializeOwned>(
+    cipher: &XChaCha20Poly1305,
+    sealed: &[u8],
+    aad: &[u8],
+) -> Result<T, StoreError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(StoreError::Corrupt("ciphertext is too short".into()));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let msg = cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| StoreError::Corrupt("ciphertext doesn't authenticate".into()))?;
+    rmp_serde::from_slice(&msg).map_err(|err| StoreError::Corrupt(err.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+
+    use crate::{
+        counter::Counter, memdb::InMemoryDb, protocol::Protocol, Event, ReplicaId, Replicator,
+        Store, StoreError,
+    };
+
+    use super::{EncryptedStore, Key, Sealed};
+
+    async fn start(
+        inner: &InMemoryDb<Sealed>,
+        key: &Key,
+    ) -> Replicator<Counter, EncryptedStore<InMemoryDb<Sealed>>> {
+        let store = EncryptedStore::new(inner.clone(), key);
+        Replicator::new(ReplicaId::new(1), Counter::default(), store)
+            .await
+            .unwrap()
+    }
+
+    async fn load(store: &mut EncryptedStore<InMemoryDb<Sealed>>) -> Vec<Result<i64, String>> {
+        Store::<Counter>::load_events(store, 0)
+            .map(|event| event.map(|event| event.data).map_err(|err| err.to_string()))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let key = EncryptedStore::<()>::generate_key();
+        let inner = InMemoryDb::<Sealed>::default();
+        let mut replica = start(&inner, &key).await;
+        for i in 1..=3 {
+            replica.send(Protocol::Command(i)).await;
+        }
+        replica.compact().await;
+        replica.send(Protocol::Command(4)).await;
+
+        // Only the sequence numbers are left in the clear
+        let events = inner.events.read().await;
+        assert_eq!(events.keys().copied().collect::<Vec<_>>(), [1, 2, 3, 4]);
+        assert!(events
+            .values()
+            .all(|event| event.origin == ReplicaId::default() && event.version.map.is_empty()));
+        drop(events);
+        let snapshot = inner.state.read().await.clone().unwrap();
+        assert_eq!(snapshot.seq, 0);
+
+        let restarted = start(&inner, &key).await;
+        assert_eq!(restarted.query(), 10);
+    }
+
+    #[tokio::test]
+    async fn uses_a_new_nonce_every_time() {
+        let key = EncryptedStore::<()>::generate_key();
+        let inner = InMemoryDb::<Sealed>::default();
+        let mut store = EncryptedStore::new(inner.clone(), &key);
+        let event = Event {
+            origin: ReplicaId::new(1),
+            origin_seq: 1,
+            local_seq: 1,
+            version: Default::default(),
+            data: 1,
+            signature: None,
+        };
+
+        Store::<Counter>::save_events(&mut store, std::iter::once(event.clone())).await;
+        let first = inner.events.read().await[&1].data.clone();
+        Store::<Counter>::save_events(&mut store, std::iter::once(event)).await;
+        let second = inner.events.read().await[&1].data.clone();
+        assert_ne!(first, second);
+        assert_eq!(load(&mut store).await, [Ok(1)]);
+    }
+
+    #[tokio::test]
+    async fn detects_tampering() {
+        let key = EncryptedStore::<()>::generate_key();
+        let inner = InMemoryDb::<Sealed>::default();
+        let mut replica = start(&inner, &key).await;
+        for i in 1..=3 {
+            replica.send(Protocol::Command(i)).await;
+        }
+        let corrupt =
+            Err(StoreError::Corrupt("ciphertext doesn't authenticate".into()).to_string());
+
+        // A flipped bit
+        let mut events = inner.events.write().await;
+        events.get_mut(&1).unwrap().data[30] ^= 1;
+        drop(events);
+        let mut store = EncryptedStore::new(inner.clone(), &key);
+        assert_eq!(load(&mut store).await, [corrupt.clone(), Ok(2), Ok(3)]);
+
+        // Events swapped around
+        let mut events = inner.events.write().await;
+        let second = events[&2].data.clone();
+        let third = std::mem::replace(&mut events.get_mut(&3).unwrap().data, second);
+        events.get_mut(&2).unwrap().data = third;
+        drop(events);
+        assert_eq!(
+            load(&mut store).await,
+            [corrupt.clone(), corrupt.clone(), corrupt.clone()]
+        );
+
+        // The wrong key
+        let inner = InMemoryDb::<Sealed>::default();
+        start(&inner, &key).await.send(Protocol::Command(1)).await;
+        let mut store = EncryptedStore::new(inner.clone(), &EncryptedStore::<()>::generate_key());
+        assert_eq!(load(&mut store).await, [corrupt]);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "snapshot doesn't decrypt")]
+    async fn refuses_tampered_snapshots() {
+        let key = EncryptedStore::<()>::generate_key();
+        let inner = InMemoryDb::<Sealed>::default();
+        let mut replica = start(&inner, &key).await;
+        replica.send(Protocol::Command(1)).await;
+        replica.compact().await;
+
+        inner.state.write().await.as_mut().unwrap().crdt.0[30] ^= 1;
+        start(&inner, &key).await;
+    }
+}
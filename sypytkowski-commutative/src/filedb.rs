@@ -0,0 +1,217 @@
+//! A [`Store`] in plain files, see [`FileDb`].
+//!
+//! Everything is JSON so the files can be read, grepped and fixed up by hand when debugging:
+//! `events.jsonl` holds an event per line in the order they were saved, `snapshot.json` the latest
+//! snapshot. Events are appended and synced before saving returns, the snapshot is written next to
+//! the old one and renamed over it so a crash never leaves half of one behind.
+use std::{
+    io::{self, ErrorKind},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::{Crdt, Event, ReplicationState, Store, StoreError};
+
+const EVENTS: &str = "events.jsonl";
+const SNAPSHOT: &str = "snapshot.json";
+
+/// Keeps one replica's events and snapshot in a directory of its own.
+///
+/// # Panics
+///
+/// [`Store`] has no way to report failed writes or unreadable snapshots, so saving panics when a
+/// file can't be written rather than losing events, and so does loading a snapshot that doesn't
+/// decode. Lines of the log that don't decode end up in the stream of [`Store::load_events`].
+pub struct FileDb<C> {
+    dir: PathBuf,
+    crdt: PhantomData<fn() -> C>,
+}
+
+impl<C> Clone for FileDb<C> {
+    fn clone(&self) -> Self {
+        Self {
+            dir: self.dir.clone(),
+            crdt: PhantomData,
+        }
+    }
+}
+
+impl<C> FileDb<C> {
+    /// Keeps the files in `dir`, creating it if it isn't there yet
+    pub async fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).await?;
+        Ok(Self {
+            dir,
+            crdt: PhantomData,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+#[async_trait]
+impl<C> Store<C> for FileDb<C>
+where
+    C: Crdt + Serialize + DeserializeOwned,
+    C::EData: Serialize + DeserializeOwned + 'static,
+{
+    async fn save_snapshot(&mut self, state: ReplicationState<C>) {
+        let path = self.dir.join(SNAPSHOT);
+        let written = path.with_extension("json.tmp");
+        let saved = async {
+            fs::write(&written, encode(&state)).await?;
+            fs::File::open(&written).await?.sync_all().await?;
+            fs::rename(&written, &path).await
+        };
+        if let Err(err) = saved.await {
+            panic!("saving the snapshot to {} failed: {err}", path.display());
+        }
+    }
+
+    async fn load_snapshot(&mut self) -> Option<ReplicationState<C>> {
+        let path = self.dir.join(SNAPSHOT);
+        let snapshot = match fs::read_to_string(&path).await {
+            Ok(snapshot) => snapshot,
+            Err(err) if err.kind() == ErrorKind::NotFound => return None,
+            Err(err) => panic!("loading the snapshot from {} failed: {err}", path.display()),
+        };
+        match decode(&snapshot) {
+            Ok(state) => Some(state),
+            Err(err) => panic!("snapshot in {} doesn't decode: {err}", path.display()),
+        }
+    }
+
+    /// Reads the whole log once the stream is first polled
+    fn load_events(
+        &mut self,
+        start_seq: u64,
+    ) -> BoxStream<'_, Result<Event<C::EData>, StoreError>> {
+        let path = self.dir.join(EVENTS);
+        stream::once(async move { fs::read_to_string(path).await })
+            .flat_map(move |log| {
+                let events = match log {
+                    Ok(log) => log
+                        .lines()
+                        .filter(|line| !line.trim().is_empty())
+                        .map(decode::<Event<C::EData>>)
+                        .filter(|event| !matches!(event, Ok(event) if event.local_seq < start_seq))
+                        .collect(),
+                    Err(err) if err.kind() == ErrorKind::NotFound => vec![],
+                    Err(err) => vec![Err(StoreError::Io(err))],
+                };
+                stream::iter(events)
+            })
+            .boxed()
+    }
+
+    async fn save_events<I: Iterator<Item = Event<C::EData>> + Send>(&mut self, events: I) {
+        let lines = events
+            .map(|event| encode(&event) + "\n")
+            .collect::<String>();
+        if lines.is_empty() {
+            return;
+        }
+        let path = self.dir.join(EVENTS);
+        let saved = async {
+            let mut log = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            log.write_all(lines.as_bytes()).await?;
+            log.sync_data().await
+        };
+        if let Err(err) = saved.await {
+            panic!("saving events to {} failed: {err}", path.display());
+        }
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("JSON encoding doesn't fail for plain data")
+}
+
+fn decode<T: DeserializeOwned>(json: &str) -> Result<T, StoreError> {
+    serde_json::from_str(json).map_err(|err| StoreError::Corrupt(err.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use futures::StreamExt;
+
+    use crate::{counter::Counter, protocol::Protocol, ReplicaId, Replicator, Store};
+
+    use super::{FileDb, EVENTS};
+
+    fn dir() -> PathBuf {
+        std::env::temp_dir().join(format!("filedb-{}", ReplicaId::random().as_u128()))
+    }
+
+    async fn start(dir: &PathBuf) -> Replicator<Counter, FileDb<Counter>> {
+        let store = FileDb::open(dir).await.unwrap();
+        Replicator::new(ReplicaId::new(1), Counter::default(), store)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn survives_restarts() {
+        let dir = dir();
+        let mut replica = start(&dir).await;
+        for i in 1..=3 {
+            replica.send(Protocol::Command(i)).await;
+        }
+        replica.compact().await;
+        replica.send(Protocol::Command(4)).await;
+
+        let mut restarted = start(&dir).await;
+        assert_eq!(restarted.query(), 10);
+        restarted.send(Protocol::Command(5)).await;
+        assert_eq!(start(&dir).await.query(), 15);
+
+        let mut store = FileDb::<Counter>::open(&dir).await.unwrap();
+        let seqs = store
+            .load_events(3)
+            .map(|event| event.unwrap().local_seq)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(seqs, [3, 4, 5]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_lines_that_dont_decode() {
+        let dir = dir();
+        let mut replica = start(&dir).await;
+        replica.send(Protocol::Command(1)).await;
+        let mut log = std::fs::read_to_string(dir.join(EVENTS)).unwrap();
+        log.push_str("{\"origin\":\n");
+        std::fs::write(dir.join(EVENTS), log).unwrap();
+
+        let mut store = FileDb::<Counter>::open(&dir).await.unwrap();
+        let events = Store::<Counter>::load_events(&mut store, 0)
+            .map(|event| event.map(|event| event.data))
+            .collect::<Vec<_>>()
+            .await;
+        assert!(matches!(events[..], [Ok(1), Err(_)]));
+        assert!(
+            Replicator::new(ReplicaId::new(1), Counter::default(), store)
+                .await
+                .is_err()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! Reading a [`Replicator`] while it keeps replicating.
+//!
+//! [`Replicator::spawn`] moves the replicator into a task of its own that handles one message at a
+//! time, [`ReplicatorWriter`]s hand it messages. [`ReplicatorHandle`]s read the copy of the CRDT
+//! the replicator publishes after every message that changed it, so readers never wait on
+//! replication or the store.
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::{protocol::Protocol, Crdt, Replicator, Store};
+
+/// Cheap to clone, read only view of a [`Replicator`]
+#[derive(Debug, Clone)]
+pub struct ReplicatorHandle<C: Crdt> {
+    crdt: watch::Receiver<Arc<C>>,
+}
+
+impl<C: Crdt> ReplicatorHandle<C> {
+    pub(crate) fn new(crdt: watch::Receiver<Arc<C>>) -> Self {
+        Self { crdt }
+    }
+
+    pub fn query(&self) -> C::State {
+        self.crdt.borrow().query()
+    }
+
+    /// The CRDT as last published, it is not going to change under the caller
+    pub fn crdt(&self) -> Arc<C> {
+        self.crdt.borrow().clone()
+    }
+
+    /// Waits for the replicator to publish a change, returns `false` once it is gone
+    pub async fn changed(&mut self) -> bool {
+        self.crdt.changed().await.is_ok()
+    }
+}
+
+type Request<C> = (
+    Protocol<<C as Crdt>::Cmd, <C as Crdt>::EData>,
+    oneshot::Sender<Protocol<<C as Crdt>::Cmd, <C as Crdt>::EData>>,
+);
+
+/// Sends messages to a [`Replicator`] running in its own task, see [`Replicator::spawn`]
+#[derive(Debug)]
+pub struct ReplicatorWriter<C: Crdt> {
+    requests: mpsc::Sender<Request<C>>,
+}
+
+impl<C: Crdt> Clone for ReplicatorWriter<C> {
+    fn clone(&self) -> Self {
+        Self {
+            requests: self.requests.clone(),
+        }
+    }
+}
+
+impl<C: Crdt> ReplicatorWriter<C> {
+    /// Same as [`Replicator::send`], `None` if the task is gone
+    pub async fn send(
+        &self,
+        msg: Protocol<C::Cmd, C::EData>,
+    ) -> Option<Protocol<C::Cmd, C::EData>> {
+        let (reply_to, reply) = oneshot::channel();
+        self.requests.send((msg, reply_to)).await.ok()?;
+        reply.await.ok()
+    }
+}
+
+impl<C, Db> Replicator<C, Db>
+where
+    C: Crdt + 'static,
+    C::Cmd: Send,
+    Db: Store<C> + Send + 'static,
+{
+    /// Runs the replicator in a task of its own, which stops once every writer is dropped
+    pub fn spawn(mut self) -> (ReplicatorHandle<C>, ReplicatorWriter<C>) {
+        let handle = self.handle();
+        let (requests, mut rx) = mpsc::channel::<Request<C>>(64);
+        tokio::spawn(async move {
+            while let Some((msg, reply_to)) = rx.recv().await {
+                let reply = self.send(msg).await;
+                let _ = reply_to.send(reply);
+            }
+        });
+
+        (handle, ReplicatorWriter { requests })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        memdb::InMemoryDb,
+        protocol::{Connect, Protocol},
+        rga::{Command, Rga},
+        ReplicaId, Replicator,
+    };
+
+    #[tokio::test]
+    async fn read_while_replicating() {
+        type Crdt = Rga<char>;
+
+        let alice_id = ReplicaId(0);
+        let bob_id = ReplicaId(1);
+        let alice = Replicator::new(alice_id, Crdt::new(alice_id), InMemoryDb::default())
+            .await
+            .unwrap();
+        let mut bob = Replicator::new(bob_id, Crdt::new(bob_id), InMemoryDb::default())
+            .await
+            .unwrap();
+
+        let (mut handle, writer) = alice.spawn();
+        let reader = handle.clone();
+        assert!(handle.query().is_empty());
+
+        writer
+            .send(Protocol::Command(Command::Insert(0, 'a')))
+            .await
+            .unwrap();
+        assert!(handle.changed().await);
+        assert_eq!(handle.query(), vec!['a']);
+
+        // Alice pulls from Bob through the writer, the handle picks up Bob's insert
+        let _ = bob.send(Protocol::Command(Command::Insert(0, 'b'))).await;
+        let connect = Protocol::Connect(Connect { replica_id: bob_id });
+        let mut msg = writer.send(connect).await.unwrap();
+        while !matches!(msg, Protocol::Noop) {
+            let reply = bob.send(msg).await;
+            msg = writer.send(reply).await.unwrap();
+        }
+
+        let crdt = reader.crdt();
+        assert_eq!(reader.query(), vec!['b', 'a']);
+
+        // What was read stays as it was
+        writer
+            .send(Protocol::Command(Command::RemoveAt(0)))
+            .await
+            .unwrap();
+        assert_eq!(reader.query(), vec!['a']);
+        assert_eq!(crate::Crdt::query(&*crdt), vec!['b', 'a']);
+
+        drop(writer);
+        while handle.changed().await {}
+    }
+}
@@ -0,0 +1,153 @@
+//! States of the log as it was, see [`Replicator::query_at`](crate::Replicator::query_at).
+//!
+//! The log of a replica is in causal order, so the events whose version is covered by a cutoff
+//! always include everything they depend on, and replaying just those in order gives the state as
+//! it was at the cutoff. Replaying from the first event every time gets slow as the log grows, so
+//! replays leave [`Checkpoints`] behind: the state after every event up to some point, along with
+//! their version. A later replay starts at the last checkpoint its cutoff covers.
+use std::collections::BTreeMap;
+
+use crate::{
+    memory::{MemoryUsage, Usage},
+    Crdt, VTime,
+};
+
+/// Events between two checkpoints to begin with
+const EVERY: u64 = 128;
+/// Checkpoints kept at most, past that every other one is dropped
+const MAX_CHECKPOINTS: usize = 32;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Checkpoints<C> {
+    /// By the last event applied, nothing after it. The one at 0 is where the log starts off.
+    states: BTreeMap<u64, (VTime, C)>,
+    /// Checkpoints are only taken at multiples of this, it doubles every time they are thinned
+    /// out so they stay spread over the whole log
+    every: u64,
+}
+
+impl<C: Crdt> Checkpoints<C> {
+    /// `crdt` is the state before the first event of the log, with `version` in it already
+    pub fn new(version: VTime, crdt: C) -> Self {
+        Self {
+            states: BTreeMap::from([(0, (version, crdt))]),
+            every: EVERY,
+        }
+    }
+
+    /// The last checkpoint `cutoff` covers, the start of the log if none does
+    pub fn before(&self, cutoff: &VTime) -> (u64, &VTime, &C) {
+        let mut states = self.states.iter();
+        let found = states.rfind(|(_, (version, _))| version <= cutoff);
+        let (seq, (version, crdt)) = found
+            .or_else(|| self.states.iter().next())
+            .expect("the start of the log is always there");
+        (*seq, version, crdt)
+    }
+
+    /// Whether the state after event `seq` should be kept
+    pub fn wants(&self, seq: u64) -> bool {
+        seq.is_multiple_of(self.every) && !self.states.contains_key(&seq)
+    }
+
+    pub fn insert(&mut self, seq: u64, version: VTime, crdt: C) {
+        self.states.insert(seq, (version, crdt));
+        if self.states.len() > MAX_CHECKPOINTS {
+            self.every *= 2;
+            let every = self.every;
+            self.states.retain(|seq, _| seq.is_multiple_of(every));
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+}
+
+impl<C: MemoryUsage> MemoryUsage for Checkpoints<C> {
+    fn memory_usage(&self) -> Usage {
+        let states = self.states.values();
+        let kept = states.map(|(version, crdt)| version.memory_usage() + crdt.memory_usage());
+        Usage::btree::<u64, (VTime, C)>(self.states.len()) + kept.sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        connect, counter::Counter, memdb::InMemoryDb, protocol::Protocol, ReplicaId, Replicator,
+        VTime,
+    };
+
+    type Replica = Replicator<Counter, InMemoryDb<Counter>>;
+
+    async fn replica(id: u128) -> Replica {
+        Replicator::new(ReplicaId::new(id), Counter::default(), InMemoryDb::default())
+            .await
+            .unwrap()
+    }
+
+    fn version(entries: &[(u128, u64)]) -> VTime {
+        VTime {
+            map: entries
+                .iter()
+                .map(|&(id, seq)| (ReplicaId::new(id), seq))
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn goes_back_to_any_version() {
+        let mut alice = replica(1).await;
+        let mut bob = replica(2).await;
+        alice.send(Protocol::Command(1)).await;
+        alice.send(Protocol::Command(2)).await;
+        bob.send(Protocol::Command(10)).await;
+        connect(&mut alice, &mut bob).await;
+        alice.send(Protocol::Command(4)).await;
+        assert_eq!(alice.query(), 17);
+
+        for (cutoff, expected) in [
+            (version(&[]), 0),
+            (version(&[(1, 1)]), 1),
+            (version(&[(1, 2)]), 3),
+            (version(&[(2, 1)]), 10),
+            (version(&[(1, 1), (2, 1)]), 11),
+            // Alice's last event depends on Bob's, it isn't there without it
+            (version(&[(1, 3)]), 3),
+            (version(&[(1, 3), (2, 1)]), 17),
+            (version(&[(1, 9), (2, 9), (3, 9)]), 17),
+        ] {
+            assert_eq!(alice.query_at(&cutoff).await.unwrap(), expected, "{cutoff:?}");
+        }
+        assert_eq!(alice.query(), 17);
+    }
+
+    #[tokio::test]
+    async fn replays_from_checkpoints() {
+        let mut alice = replica(1).await;
+        let mut bob = replica(2).await;
+        for _ in 0..1000 {
+            alice.send(Protocol::Command(1)).await;
+        }
+        bob.send(Protocol::Command(1000)).await;
+        connect(&mut alice, &mut bob).await;
+
+        // Bob's event comes last, so everything of Alice's before it is checkpointed
+        assert_eq!(alice.query_at(&version(&[(1, 1000)])).await.unwrap(), 1000);
+        let checkpoints = alice.checkpoints.len();
+        assert_eq!(checkpoints, 1 + 1000 / 128);
+        assert_eq!(alice.query_at(&version(&[(1, 500)])).await.unwrap(), 500);
+        assert_eq!(alice.checkpoints.len(), checkpoints);
+
+        // Thinned out once there are too many
+        for _ in 0..5000 {
+            alice.send(Protocol::Command(1)).await;
+        }
+        let cutoff = version(&[(1, 5999), (2, 1)]);
+        assert_eq!(alice.query_at(&cutoff).await.unwrap(), 6999);
+        assert!(alice.checkpoints.len() <= 32);
+        assert_eq!(alice.query_at(&version(&[(1, 10)])).await.unwrap(), 10);
+    }
+}
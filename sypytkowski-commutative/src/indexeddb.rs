@@ -0,0 +1,266 @@
+//! A [`Store`] on the browser's IndexedDB, see [`IndexedDb`].
+//!
+//! One database holds any number of documents: events go into the `events` object store keyed by
+//! `[document, local_seq]`, the latest snapshot of each document into `snapshots` keyed by the
+//! document. Both are msgpack with named fields in `Uint8Array`s. Every save waits for its
+//! transaction to commit, so once [`Replicator::send`](crate::Replicator::send) returns the edit
+//! survives a reload of the page even if it never made it to another replica.
+//!
+//! IndexedDB handles can't leave the thread that opened them while [`Store`] wants its futures to
+//! be [`Send`]. Browsers run wasm on a single thread, so everything touching IndexedDB is wrapped
+//! in a [`SendWrapper`], which panics should it ever be used from another thread.
+use std::{io, marker::PhantomData};
+
+use async_trait::async_trait;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use js_sys::{Array, Function, Promise, Uint8Array};
+use send_wrapper::SendWrapper;
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    IdbDatabase, IdbFactory, IdbKeyRange, IdbObjectStore, IdbOpenDbRequest, IdbRequest,
+    IdbTransaction, IdbTransactionMode, IdbVersionChangeEvent, WorkerGlobalScope,
+};
+
+use crate::{Crdt, Event, ReplicationState, Store, StoreError};
+
+const EVENTS: &str = "events";
+const SNAPSHOTS: &str = "snapshots";
+/// Bumped whenever the object stores change
+const VERSION: u32 = 1;
+
+/// How many events [`IndexedDb::load_events`](Store::load_events) asks for at a time
+const PAGE: u32 = 256;
+
+/// Keeps the events and snapshot of one document in an IndexedDB database.
+///
+/// # Panics
+///
+/// [`Store`] has no way to report failed writes or unreadable snapshots, so saving panics when
+/// IndexedDB refuses a write rather than losing events, and so does loading a snapshot that doesn't
+/// decode. Failed reads of events end up in the stream of [`Store::load_events`].
+pub struct IndexedDb<C> {
+    db: SendWrapper<IdbDatabase>,
+    document: String,
+    crdt: PhantomData<fn() -> C>,
+}
+
+impl<C> Clone for IndexedDb<C> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            document: self.document.clone(),
+            crdt: PhantomData,
+        }
+    }
+}
+
+impl<C> IndexedDb<C> {
+    /// Opens the database `name`, creating it if it isn't there yet, to keep `document` in
+    pub async fn open(name: &str, document: impl Into<String>) -> Result<Self, StoreError> {
+        let request = factory()?.open_with_u32(name, VERSION).map_err(js_error)?;
+        let upgrade = Closure::once_into_js(|event: IdbVersionChangeEvent| {
+            let Some(request) = event.target() else {
+                return;
+            };
+            let Ok(db) = request.unchecked_into::<IdbOpenDbRequest>().result() else {
+                return;
+            };
+            let db = db.unchecked_into::<IdbDatabase>();
+            for store in [EVENTS, SNAPSHOTS] {
+                if !db.object_store_names().contains(store) {
+                    let _ = db.create_object_store(store);
+                }
+            }
+        });
+        request.set_onupgradeneeded(Some(upgrade.unchecked_ref()));
+        let db = done(&request).await.map_err(js_error)?;
+        Ok(Self {
+            db: SendWrapper::new(db.unchecked_into()),
+            document: document.into(),
+            crdt: PhantomData,
+        })
+    }
+
+    pub fn document(&self) -> &str {
+        &self.document
+    }
+
+    fn store(
+        &self,
+        name: &str,
+        mode: IdbTransactionMode,
+    ) -> Result<(IdbTransaction, IdbObjectStore), JsValue> {
+        let tx = self.db.transaction_with_str_and_mode(name, mode)?;
+        let store = tx.object_store(name)?;
+        Ok((tx, store))
+    }
+
+    async fn put(&self, name: &str, values: Vec<(JsValue, Vec<u8>)>) -> Result<(), JsValue> {
+        let (tx, store) = self.store(name, IdbTransactionMode::Readwrite)?;
+        for (key, value) in values {
+            store.put_with_key(&Uint8Array::from(value.as_slice()), &key)?;
+        }
+        committed(&tx).await
+    }
+}
+
+#[async_trait]
+impl<C> Store<C> for IndexedDb<C>
+where
+    C: Crdt + Serialize + DeserializeOwned,
+    C::EData: Serialize + DeserializeOwned,
+{
+    async fn save_snapshot(&mut self, state: ReplicationState<C>) {
+        let snapshot = (JsValue::from_str(&self.document), encode(&state));
+        let saved = SendWrapper::new(self.put(SNAPSHOTS, vec![snapshot])).await;
+        if let Err(err) = saved {
+            panic!("saving the snapshot of {} failed: {err:?}", self.document);
+        }
+    }
+
+    async fn load_snapshot(&mut self) -> Option<ReplicationState<C>> {
+        let loaded = SendWrapper::new(async {
+            let (_, store) = self.store(SNAPSHOTS, IdbTransactionMode::Readonly)?;
+            let snapshot = done(&store.get(&JsValue::from_str(&self.document))?).await?;
+            Ok::<_, JsValue>(bytes(&snapshot))
+        })
+        .await;
+        let snapshot = match loaded {
+            Ok(snapshot) => snapshot?,
+            Err(err) => panic!("loading the snapshot of {} failed: {err:?}", self.document),
+        };
+        match decode(&snapshot) {
+            Ok(state) => Some(state),
+            Err(err) => panic!("snapshot of {} doesn't decode: {err}", self.document),
+        }
+    }
+
+    /// Reads the events a page at a time as they are polled
+    fn load_events(
+        &mut self,
+        start_seq: u64,
+    ) -> BoxStream<'_, Result<Event<C::EData>, StoreError>> {
+        let db = &*self;
+        let events = stream::unfold(Some(start_seq), move |next| async move {
+            let next = next?;
+            let page = async {
+                let (_, store) = db.store(EVENTS, IdbTransactionMode::Readonly)?;
+                let range = IdbKeyRange::bound(
+                    &event_key(&db.document, next as f64),
+                    &event_key(&db.document, f64::INFINITY),
+                )?;
+                let page = done(&store.get_all_with_key_and_limit(&range, PAGE)?).await?;
+                Ok::<_, JsValue>(page.unchecked_into::<Array>())
+            };
+            let page = match page.await {
+                Ok(page) => page,
+                Err(err) => return Some((vec![Err(js_error(err))], None)),
+            };
+            let events = page
+                .iter()
+                .map(|event| {
+                    let bytes = bytes(&event)
+                        .ok_or_else(|| StoreError::Corrupt(format!("{event:?} isn't an event")))?;
+                    decode::<Event<C::EData>>(&bytes)
+                })
+                .collect::<Vec<_>>();
+            let after = match events.last()? {
+                Ok(last) if page.length() == PAGE => Some(last.local_seq + 1),
+                _ => None,
+            };
+            Some((events, after))
+        })
+        .flat_map(stream::iter);
+        SendWrapper::new(events).boxed()
+    }
+
+    async fn save_events<I: Iterator<Item = Event<C::EData>> + Send>(&mut self, events: I) {
+        let events = events
+            .map(|event| (event.local_seq, encode(&event)))
+            .collect::<Vec<_>>();
+        if events.is_empty() {
+            return;
+        }
+        let saved = SendWrapper::new(async {
+            let events = events
+                .into_iter()
+                .map(|(seq, event)| (event_key(&self.document, seq as f64), event))
+                .collect();
+            self.put(EVENTS, events).await
+        })
+        .await;
+        if let Err(err) = saved {
+            panic!("saving events of {} failed: {err:?}", self.document);
+        }
+    }
+}
+
+/// `indexedDB` of the page or of the worker this runs in
+fn factory() -> Result<IdbFactory, StoreError> {
+    let global = js_sys::global();
+    let factory = match global.dyn_ref::<web_sys::Window>() {
+        Some(window) => window.indexed_db(),
+        None => global.unchecked_ref::<WorkerGlobalScope>().indexed_db(),
+    };
+    factory
+        .map_err(js_error)?
+        .ok_or_else(|| StoreError::Io(io::Error::other("IndexedDB isn't available")))
+}
+
+/// Resolves with the result of `request` once it succeeded
+async fn done(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve: Function, reject: Function| {
+        let target = request.clone();
+        let success = Closure::once_into_js(move || {
+            let _ = resolve.call1(&JsValue::NULL, &target.result().unwrap_or_default());
+        });
+        let error = Closure::once_into_js(move |event: JsValue| {
+            let _ = reject.call1(&JsValue::NULL, &event);
+        });
+        request.set_onsuccess(Some(success.unchecked_ref()));
+        request.set_onerror(Some(error.unchecked_ref()));
+    });
+    JsFuture::from(promise).await
+}
+
+/// Resolves once `tx` committed
+async fn committed(tx: &IdbTransaction) -> Result<(), JsValue> {
+    let promise = Promise::new(&mut |resolve: Function, reject: Function| {
+        let complete = Closure::once_into_js(move || {
+            let _ = resolve.call0(&JsValue::NULL);
+        });
+        let failed = Closure::<dyn FnMut(JsValue)>::new(move |event: JsValue| {
+            let _ = reject.call1(&JsValue::NULL, &event);
+        })
+        .into_js_value();
+        tx.set_oncomplete(Some(complete.unchecked_ref()));
+        tx.set_onerror(Some(failed.unchecked_ref()));
+        tx.set_onabort(Some(failed.unchecked_ref()));
+    });
+    JsFuture::from(promise).await.map(drop)
+}
+
+fn event_key(document: &str, seq: f64) -> JsValue {
+    Array::of2(&JsValue::from_str(document), &JsValue::from_f64(seq)).into()
+}
+
+fn bytes(value: &JsValue) -> Option<Vec<u8>> {
+    value.dyn_ref::<Uint8Array>().map(Uint8Array::to_vec)
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    rmp_serde::to_vec_named(value).expect("msgpack encoding doesn't fail for plain data")
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, StoreError> {
+    rmp_serde::from_slice(bytes).map_err(|err| StoreError::Corrupt(err.to_string()))
+}
+
+fn js_error(err: JsValue) -> StoreError {
+    StoreError::Io(io::Error::other(format!("{err:?}")))
+}
@@ -0,0 +1,237 @@
+//! Middleware around [`Replicator::send`](crate::Replicator::send), see [`Interceptor`].
+//!
+//! Interceptors run in the order they were added on the way in and the other way around on the
+//! way out, so the first one added wraps all the others. An interceptor that encrypts batches
+//! for the wire should be added first, one that validates what takes effect after whatever
+//! migrates events.
+use std::{fmt, sync::Arc};
+
+use crate::{protocol::Replicated, Crdt, Event, ReplicaId};
+
+/// Whether a batch is about to go to a peer or just came from one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Events this replica replays to a peer pulling from it
+    Sent,
+    /// Events a peer replayed to this replica
+    Received,
+}
+
+/// Hooks into the replication loop for what cuts across every CRDT: metrics, validation,
+/// logging, encryption or migrating events of an older schema. See
+/// [`Replicator::with_interceptor`](crate::Replicator::with_interceptor).
+pub trait Interceptor<C: Crdt>: fmt::Debug + Send + Sync {
+    /// Called before every event made or replicated here takes effect on `crdt`. An event it
+    /// rejects doesn't: a command is dropped, and a replicated batch stops there the same as at a
+    /// forged event, to be asked for again the next time the peer connects.
+    fn before_effect(&self, _event: &Event<C::EData>, _crdt: &C) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called once `event` took effect on `crdt`
+    fn after_effect(&self, _event: &Event<C::EData>, _crdt: &C) {}
+
+    /// Called with every batch of events on its way between replicas, before anything else looks
+    /// at it. Received events are verified and stored as rewritten here, so with
+    /// [`Replicator::with_auth`](crate::Replicator::with_auth) a rewrite of `Sent` events has to
+    /// be undone on `Received` ones.
+    fn on_replicate(&self, _replicated: &mut Replicated<C::EData>, _direction: Direction) {}
+}
+
+/// An event an [`Interceptor`] didn't let take effect
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejectedEvent {
+    /// Replica the event was replicated from, this one for commands
+    pub from: ReplicaId,
+    pub origin: ReplicaId,
+    pub origin_seq: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for RejectedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "event {} of {:?} from {:?} was rejected: {}",
+            self.origin_seq, self.origin, self.from, self.reason
+        )
+    }
+}
+
+impl std::error::Error for RejectedEvent {}
+
+/// Asks every interceptor in turn whether `event` replicated `from` a peer may take effect
+pub(crate) fn before_effect<C: Crdt>(
+    interceptors: &[Arc<dyn Interceptor<C>>],
+    from: ReplicaId,
+    event: &Event<C::EData>,
+    crdt: &C,
+) -> Result<(), RejectedEvent> {
+    for interceptor in interceptors {
+        interceptor
+            .before_effect(event, crdt)
+            .map_err(|reason| RejectedEvent {
+                from,
+                origin: event.origin,
+                origin_seq: event.origin_seq,
+                reason,
+            })?;
+    }
+    Ok(())
+}
+
+pub(crate) fn after_effect<C: Crdt>(
+    interceptors: &[Arc<dyn Interceptor<C>>],
+    event: &Event<C::EData>,
+    crdt: &C,
+) {
+    for interceptor in interceptors.iter().rev() {
+        interceptor.after_effect(event, crdt);
+    }
+}
+
+pub(crate) fn on_replicate<C: Crdt>(
+    interceptors: &[Arc<dyn Interceptor<C>>],
+    replicated: &mut Replicated<C::EData>,
+    direction: Direction,
+) {
+    match direction {
+        Direction::Received => {
+            for interceptor in interceptors {
+                interceptor.on_replicate(replicated, direction);
+            }
+        }
+        Direction::Sent => {
+            for interceptor in interceptors.iter().rev() {
+                interceptor.on_replicate(replicated, direction);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use crate::{
+        connect, counter::Counter, memdb::InMemoryDb, protocol::Protocol, ReplicaId, Replicator,
+    };
+
+    use super::*;
+
+    type Replica = Replicator<Counter, InMemoryDb<Counter>>;
+
+    async fn replica(id: u128) -> Replica {
+        Replicator::new(
+            ReplicaId::new(id),
+            Counter::default(),
+            InMemoryDb::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Nothing may take the counter below zero
+    #[derive(Debug)]
+    struct NonNegative;
+
+    impl Interceptor<Counter> for NonNegative {
+        fn before_effect(&self, event: &Event<i64>, crdt: &Counter) -> Result<(), String> {
+            match crdt.query() + event.data {
+                val if val < 0 => Err(format!("would go down to {val}")),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Notes down every call
+    #[derive(Debug)]
+    struct Log(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Interceptor<Counter> for Log {
+        fn before_effect(&self, event: &Event<i64>, _: &Counter) -> Result<(), String> {
+            let call = format!("{} before {}", self.0, event.data);
+            self.1.lock().unwrap().push(call);
+            Ok(())
+        }
+
+        fn after_effect(&self, event: &Event<i64>, _: &Counter) {
+            let call = format!("{} after {}", self.0, event.data);
+            self.1.lock().unwrap().push(call);
+        }
+
+        fn on_replicate(&self, replicated: &mut Replicated<i64>, direction: Direction) {
+            let call = format!("{} {direction:?} {}", self.0, replicated.events.len());
+            self.1.lock().unwrap().push(call);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_events() {
+        let mut alice = replica(1).await.with_interceptor(NonNegative);
+        let mut bob = replica(2).await;
+        alice.send(Protocol::Command(2)).await;
+        alice.send(Protocol::Command(-3)).await;
+        alice.send(Protocol::Command(-1)).await;
+        assert_eq!(alice.query(), 1);
+        assert_eq!(alice.snapshot().seq(), 2);
+        let rejected = alice.take_rejected();
+        assert_eq!(
+            rejected,
+            [RejectedEvent {
+                from: ReplicaId::new(1),
+                origin: ReplicaId::new(1),
+                origin_seq: 2,
+                reason: "would go down to -1".into(),
+            }]
+        );
+
+        // The batch stops at the rejected event
+        bob.send(Protocol::Command(-2)).await;
+        bob.send(Protocol::Command(5)).await;
+        connect(&mut alice, &mut bob).await;
+        assert_eq!(alice.query(), 1);
+        assert_eq!(alice.take_rejected().len(), 1);
+
+        // Bob's events go through once they no longer take the counter below zero
+        alice.send(Protocol::Command(1)).await;
+        connect(&mut alice, &mut bob).await;
+        assert_eq!(alice.query(), 5);
+        assert!(alice.take_rejected().is_empty());
+    }
+
+    #[tokio::test]
+    async fn nests_interceptors() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut alice = replica(1).await;
+        let mut bob = (replica(2).await)
+            .with_interceptor(Log("outer", calls.clone()))
+            .with_interceptor(Log("inner", calls.clone()));
+        bob.send(Protocol::Command(1)).await;
+        alice.send(Protocol::Command(2)).await;
+        connect(&mut bob, &mut alice).await;
+        connect(&mut alice, &mut bob).await;
+        assert_eq!(alice.query(), 3);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "outer before 1",
+                "inner before 1",
+                "inner after 1",
+                "outer after 1",
+                "outer Received 1",
+                "inner Received 1",
+                "outer before 2",
+                "inner before 2",
+                "inner after 2",
+                "outer after 2",
+                "outer Received 0",
+                "inner Received 0",
+                "inner Sent 1",
+                "outer Sent 1",
+                "inner Sent 0",
+                "outer Sent 0",
+            ]
+        );
+    }
+}
@@ -1,70 +1,353 @@
-#![feature(option_get_or_insert_default)]
-#![feature(btree_drain_filter)]
-#![feature(hash_drain_filter)]
-
 pub mod memdb;
 pub mod protocol;
 
+pub mod checksum;
+pub mod clock;
 pub mod counter;
+#[cfg(feature = "encryption")]
+pub mod encrypted;
+pub mod handle;
+mod history;
+#[cfg(feature = "file")]
+pub mod filedb;
+#[cfg(feature = "wasm")]
+pub mod indexeddb;
+pub mod interceptor;
 pub mod lseq;
 pub mod lwwreg;
+pub mod memory;
+#[cfg(feature = "migrate")]
+pub mod migrate;
 pub mod mvreg;
 pub mod orset;
+#[cfg(feature = "postgres")]
+pub mod pgdb;
+#[cfg(feature = "proto")]
+pub mod protobuf;
+#[cfg(feature = "quic")]
+pub mod quic;
+#[cfg(feature = "redis")]
+pub mod redisdb;
+pub mod registry;
 pub mod rga;
+#[cfg(feature = "signing")]
+pub mod signing;
+pub mod simulator;
+mod trace;
+pub mod tree;
+pub mod yjs;
+
+#[cfg(test)]
+mod convergence;
 
-use futures::{future::BoxFuture, stream::FuturesOrdered, StreamExt};
+use futures::{stream::BoxStream, StreamExt};
+use interceptor::{Direction, Interceptor, RejectedEvent};
 use protocol::{self as proto, Protocol};
 use std::{
+    borrow::Cow,
     cmp::Ordering,
     collections::{btree_map::Entry, BTreeMap},
+    fmt,
     ops::Deref,
+    sync::Arc,
 };
+use tokio::sync::watch;
 
 use async_trait::async_trait;
+use clock::{hlc_clock, Clock, Hlc, HlcClock, SystemClock};
 
 #[async_trait]
 pub trait Store<C: Crdt> {
     async fn save_snapshot(&mut self, state: ReplicationState<C>);
     async fn load_snapshot(&mut self) -> Option<ReplicationState<C>>;
-    // async fn load_events(&mut self, start_seq: u64) -> Vec<Event<C::EData>>;
-    async fn load_events<'a>(
-        &'a mut self,
+    /// Events from `start_seq` on in order, backends are free to page them in as the stream is
+    /// polled. A read that fails ends up in the stream rather than ending it silently.
+    fn load_events(
+        &mut self,
         start_seq: u64,
-    ) -> FuturesOrdered<BoxFuture<'a, Event<C::EData>>>;
+    ) -> BoxStream<'_, Result<Event<C::EData>, StoreError>>;
     async fn save_events<I: Iterator<Item = Event<C::EData>> + Send>(&mut self, events: I);
+    /// What the store keeps, for those that keep it in memory, see [`Replicator::stats`]
+    async fn usage(&mut self) -> Option<memory::Usage> {
+        None
+    }
+}
+
+/// Reading from a [`Store`] failed
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    /// What was read back isn't an event
+    Corrupt(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(err) => write!(f, "store io error: {err}"),
+            StoreError::Corrupt(reason) => write!(f, "corrupt event in store: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::Io(err) => Some(err),
+            StoreError::Corrupt(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Io(err)
+    }
 }
 
 pub trait EventData: Clone + Send + Sync + std::fmt::Debug {}
 impl<T: Clone + Send + Sync + std::fmt::Debug> EventData for T {}
 
+/// Signs the events a replica makes and checks the ones it replicates from others, so a peer
+/// can't pass off events as another replica's. See [`Replicator::with_auth`], the `signing`
+/// feature has an Ed25519 one.
+pub trait EventAuth<D: EventData>: fmt::Debug + Send + Sync {
+    /// Signature of `event` by its origin, `None` if this replica doesn't sign its events
+    fn sign(&self, event: &Event<D>) -> Option<Vec<u8>>;
+
+    /// Why `event` isn't signed by its origin, if it isn't
+    fn verify(&self, event: &Event<D>) -> Result<(), String>;
+}
+
+/// A replicated event that isn't signed by the replica it claims to come from
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForgedEvent {
+    /// Replica the event was replicated from
+    pub from: ReplicaId,
+    pub origin: ReplicaId,
+    pub origin_seq: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for ForgedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "event {} of {:?} replicated from {:?} is forged: {}",
+            self.origin_seq, self.origin, self.from, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ForgedEvent {}
+
+/// Two different events replicated under the same origin and sequence number, which an honest
+/// replica never makes, see [`Replicator::detect_equivocation`]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EquivocationDetected {
+    /// Replica the second event was replicated from
+    pub from: ReplicaId,
+    pub origin: ReplicaId,
+    pub origin_seq: u64,
+    /// Fingerprint of the event taken first
+    pub taken: u64,
+    /// Fingerprint of the one contradicting it
+    pub conflicting: u64,
+}
+
+impl fmt::Display for EquivocationDetected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} equivocated on event {}, {:?} replicated {:x} where {:x} was taken",
+            self.origin, self.origin_seq, self.from, self.conflicting, self.taken
+        )
+    }
+}
+
+impl std::error::Error for EquivocationDetected {}
+
 pub trait Crdt: Clone + Send + Sync {
-    type State;
+    type State: Clone;
     type Cmd: std::fmt::Debug;
     type EData: EventData;
 
     fn query(&self) -> Self::State;
     fn prepare(&self, op: Self::Cmd) -> Self::EData;
     fn effect(&mut self, event: Event<Self::EData>);
+
+    /// [`Crdt::prepare`] for a command made at `now` by the clock of the replica, for CRDTs that
+    /// order what they're told by time, see [`Replicator::with_clock`]
+    fn prepare_at(&self, op: Self::Cmd, _now: Hlc) -> Self::EData {
+        self.prepare(op)
+    }
+
+    /// Borrows the state when the CRDT keeps it as is, otherwise the same as [`Crdt::query`]
+    fn view(&self) -> Cow<'_, Self::State> {
+        Cow::Owned(self.query())
+    }
+
+    /// Drops metadata only needed for events not covered by `stable` yet. Every replica has seen
+    /// everything in `stable` and every event concurrent with it, see [`Replicator::compact`].
+    fn compact(&mut self, _stable: &VTime) {}
+
+    /// Called when a replica starts off another replica's snapshot, CRDTs that stamp their own id
+    /// on what they generate switch to `id`
+    fn rebind(&mut self, _id: ReplicaId) {}
+
+    /// What `event` changes, for activity feeds. Positions are as of this state, so `event`
+    /// should be described right before it takes effect.
+    fn describe(&self, event: &Event<Self::EData>) -> ChangeSummary {
+        ChangeSummary {
+            origin: event.origin,
+            change: Change::Unknown,
+        }
+    }
+}
+
+/// What an event changed, see [`Crdt::describe`]. Values are rendered with their `Debug` impl.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeSummary {
+    pub origin: ReplicaId,
+    pub change: Change,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added {
+        value: String,
+    },
+    /// Every copy of `value` the event's origin had seen
+    Removed {
+        value: String,
+    },
+    Inserted {
+        at: usize,
+        value: String,
+    },
+    /// `value` is `None` when it was removed already, concurrently with this event
+    RemovedAt {
+        at: usize,
+        value: Option<String>,
+    },
+    Set {
+        value: Option<String>,
+    },
+    /// The CRDT doesn't describe its events, or this one refers to something that's gone
+    Unknown,
+}
+
+impl fmt::Display for ChangeSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let origin = self.origin.0;
+        match &self.change {
+            Change::Added { value } => write!(f, "{origin} added {value}"),
+            Change::Removed { value } => write!(f, "{origin} removed {value}"),
+            Change::Inserted { at, value } => {
+                write!(f, "{origin} inserted {value} at position {at}")
+            }
+            Change::RemovedAt {
+                at,
+                value: Some(value),
+            } => write!(f, "{origin} removed {value} at position {at}"),
+            Change::RemovedAt { at, value: None } => {
+                write!(f, "{origin} removed what was at position {at}")
+            }
+            Change::Set { value: Some(value) } => write!(f, "{origin} set {value}"),
+            Change::Set { value: None } => write!(f, "{origin} cleared the value"),
+            Change::Unknown => write!(f, "{origin} made a change"),
+        }
+    }
+}
+
+/// 128 bits so replicas can pick their own id at random without coordinating
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
-pub struct ReplicaId(u64);
+pub struct ReplicaId(u128);
+
+impl ReplicaId {
+    pub fn new(id: u128) -> Self {
+        Self(id)
+    }
+
+    pub fn as_u128(&self) -> u128 {
+        self.0
+    }
+
+    /// A random (v4 UUID) id
+    pub fn random() -> Self {
+        uuid::Uuid::new_v4().into()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for ReplicaId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ReplicaId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        id.parse().map(Self).map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<uuid::Uuid> for ReplicaId {
+    fn from(uuid: uuid::Uuid) -> Self {
+        Self(uuid.as_u128())
+    }
+}
 
 #[derive(Debug, Clone, Default, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VTime {
     pub map: BTreeMap<ReplicaId, u64>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Event<D: EventData> {
     origin: ReplicaId,
     origin_seq: u64,
     local_seq: u64,
     version: VTime,
     data: D,
+    /// Signature of the origin over everything but `local_seq`, which changes as the event is
+    /// replicated, see [`EventAuth`]
+    #[cfg_attr(feature = "serde", serde(default))]
+    signature: Option<Vec<u8>>,
+}
+
+/// Read only, events are only made by [`Replicator::send`]
+impl<D: EventData> Event<D> {
+    pub fn origin(&self) -> ReplicaId {
+        self.origin
+    }
+
+    /// Where the event sits in the log of its origin
+    pub fn origin_seq(&self) -> u64 {
+        self.origin_seq
+    }
+
+    /// Where the event sits in the log it was loaded from
+    pub fn local_seq(&self) -> u64 {
+        self.local_seq
+    }
+
+    pub fn version(&self) -> &VTime {
+        &self.version
+    }
+
+    pub fn data(&self) -> &D {
+        &self.data
+    }
 }
 
 #[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ReplicationState<C>
 where
     C: Crdt,
@@ -73,7 +356,66 @@ where
     seq: u64,
     version: VTime,
     observed: BTreeMap<ReplicaId, u64>,
+    /// Versions other replicas sent along when pulling from us, see [`Replicator::stable_version`]
+    peers: BTreeMap<ReplicaId, PeerVersion>,
     crdt: C,
+    /// See [`Replicator::detect_equivocation`]
+    #[cfg_attr(feature = "serde", serde(default))]
+    fingerprints: Fingerprints,
+}
+
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct PeerVersion {
+    /// Latest version the peer reported
+    latest: VTime,
+    /// Latest reported version this replica had caught up with at the time it was looked at
+    stable: VTime,
+}
+
+/// What a replica remembers of the events it took to tell when an origin contradicts itself
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Fingerprints {
+    /// By origin and origin sequence number, the origin's entry in the event's version along with
+    /// the fingerprint so stable events can be dropped
+    events: BTreeMap<ReplicaId, BTreeMap<u64, (u64, u64)>>,
+    /// Every event in the log up to here has its fingerprint in `events`
+    seq: u64,
+    /// Origins caught equivocating, nothing of theirs is taken anymore
+    quarantined: BTreeMap<ReplicaId, EquivocationDetected>,
+}
+
+impl Fingerprints {
+    fn get(&self, origin: ReplicaId, origin_seq: u64) -> Option<u64> {
+        let (_, fingerprint) = self.events.get(&origin)?.get(&origin_seq)?;
+        Some(*fingerprint)
+    }
+
+    /// Keeps the fingerprint already there, if any
+    fn insert<D: EventData>(&mut self, event: &Event<D>, fingerprint: u64) {
+        let stamp = event.version.get(&event.origin).copied().unwrap_or_default();
+        let events = self.events.entry(event.origin).or_default();
+        events.entry(event.origin_seq).or_insert((stamp, fingerprint));
+        self.seq = self.seq.max(event.local_seq);
+    }
+
+    /// Forgets the events in `stable`
+    fn compact(&mut self, stable: &VTime) {
+        for (origin, events) in self.events.iter_mut() {
+            let stable = stable.get(origin).copied().unwrap_or_default();
+            events.retain(|_, (stamp, _)| *stamp > stable);
+        }
+        self.events.retain(|_, events| !events.is_empty());
+    }
+}
+
+type Fingerprinter<D> = fn(&Event<D>) -> u64;
+
+/// Hash of everything about the event its origin decided, that is everything but `local_seq` and
+/// the signature
+fn fingerprint<D: EventData + std::hash::Hash>(event: &Event<D>) -> u64 {
+    checksum::stable_hash(&(event.origin, event.origin_seq, &event.version, &event.data))
 }
 
 unsafe impl<C: Crdt> Send for ReplicationState<C> {}
@@ -82,7 +424,7 @@ pub struct ReplicationStatus {
     replica_id: ReplicaId,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct Replicator<C, Db>
 where
     C: Crdt,
@@ -90,6 +432,47 @@ where
 {
     store: Db,
     state: ReplicationState<C>,
+    /// Where [`handle::ReplicatorHandle`]s read from, only there once one was asked for
+    published: Option<watch::Sender<Arc<C>>>,
+    /// Signs and verifies events if set, see [`Replicator::with_auth`]
+    auth: Option<Arc<dyn EventAuth<C::EData>>>,
+    /// Replicated events that didn't verify since [`Replicator::take_forged`] was last called
+    forged: Vec<ForgedEvent>,
+    /// Fingerprints events if set, see [`Replicator::detect_equivocation`]
+    fingerprint: Option<Fingerprinter<C::EData>>,
+    /// Equivocations detected since [`Replicator::take_equivocations`] was last called
+    equivocations: Vec<EquivocationDetected>,
+    /// See [`Replicator::with_interceptor`]
+    interceptors: Vec<Arc<dyn Interceptor<C>>>,
+    /// Events the interceptors rejected since [`Replicator::take_rejected`] was last called
+    rejected: Vec<RejectedEvent>,
+    /// Where [`Replicator::query_at`] replays from
+    checkpoints: history::Checkpoints<C>,
+    /// Stamps commands, see [`Replicator::with_clock`]
+    clock: HlcClock,
+}
+
+/// The clone doesn't publish to the handles of the original
+impl<C, Db> Clone for Replicator<C, Db>
+where
+    C: Crdt,
+    Db: Store<C> + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            state: self.state.clone(),
+            published: None,
+            auth: self.auth.clone(),
+            forged: vec![],
+            fingerprint: self.fingerprint,
+            equivocations: vec![],
+            interceptors: self.interceptors.clone(),
+            rejected: vec![],
+            checkpoints: self.checkpoints.clone(),
+            clock: self.clock.clone(),
+        }
+    }
 }
 
 impl<C, Db> Replicator<C, Db>
@@ -97,56 +480,411 @@ where
     C: Crdt,
     Db: Store<C>,
 {
-    pub async fn new(id: ReplicaId, crdt: C, mut store: Db) -> Self {
-        let snapshot = store.load_snapshot().await;
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", name = "recover", skip_all, fields(replica = ?id))
+    )]
+    pub async fn new(id: ReplicaId, crdt: C, mut store: Db) -> Result<Self, StoreError> {
+        let checkpoints = history::Checkpoints::new(VTime::default(), crdt.clone());
+        let snapshot = trace::store_io("load_snapshot", store.load_snapshot()).await;
         let mut state = snapshot.unwrap_or(ReplicationState {
             id,
             crdt,
             seq: 0,
             version: Default::default(),
             observed: Default::default(),
+            peers: Default::default(),
+            fingerprints: Default::default(),
         });
 
-        while let Some(event) = store.load_events(state.seq + 1).await.next().await {
-            state.seq = state.seq.max(event.local_seq);
-            state.version.merge(&event.version);
-            state.observed.insert(event.origin, event.origin_seq);
-            state.crdt.effect(event);
+        // One pass over the log, loading it again for every event made recovery quadratic
+        {
+            let mut events = store.load_events(state.seq + 1);
+            while let Some(event) = events.next().await {
+                let event = event?;
+                state.seq = state.seq.max(event.local_seq);
+                state.version.merge(&event.version);
+                state.observed.insert(event.origin, event.origin_seq);
+                state.crdt.effect(event);
+            }
+        }
+        trace::debug!(seq = state.seq, "recovered");
+
+        Ok(Self {
+            clock: hlc_clock(state.id, SystemClock),
+            store,
+            state,
+            published: None,
+            auth: None,
+            forged: vec![],
+            fingerprint: None,
+            equivocations: vec![],
+            interceptors: vec![],
+            rejected: vec![],
+            checkpoints,
+        })
+    }
+
+    /// Starts replica `id` off `snapshot` of another replica instead of replaying the whole
+    /// history, which is the only way to join once the others have [compacted](Self::compact)
+    /// away what older events refer to
+    pub async fn from_snapshot(
+        id: ReplicaId,
+        snapshot: ReplicationState<C>,
+        mut store: Db,
+    ) -> Self {
+        let ReplicationState {
+            id: source,
+            seq,
+            version,
+            mut observed,
+            mut peers,
+            mut crdt,
+            mut fingerprints,
+        } = snapshot;
+        crdt.rebind(id);
+        observed.insert(source, seq);
+        peers.remove(&id);
+        peers.insert(
+            source,
+            PeerVersion {
+                latest: version.clone(),
+                stable: version.clone(),
+            },
+        );
+
+        // The events are the source's, this replica's log starts out empty
+        fingerprints.seq = 0;
+
+        let state = ReplicationState {
+            id,
+            seq: 0,
+            version,
+            observed,
+            peers,
+            crdt,
+            fingerprints,
+        };
+        trace::store_io("save_snapshot", store.save_snapshot(state.clone())).await;
+
+        Self {
+            store,
+            checkpoints: history::Checkpoints::new(state.version.clone(), state.crdt.clone()),
+            clock: hlc_clock(id, SystemClock),
+            state,
+            published: None,
+            auth: None,
+            forged: vec![],
+            fingerprint: None,
+            equivocations: vec![],
+            interceptors: vec![],
+            rejected: vec![],
         }
+    }
+
+    /// Signs the events made here with `auth` and only takes replicated events it verifies. A
+    /// batch stops at the first forged event, what came before it is kept and nothing after it,
+    /// so later events that may build on it never make it in. The forged events can be looked at
+    /// with [`Replicator::take_forged`].
+    ///
+    /// Events already in the store aren't checked again.
+    pub fn with_auth(mut self, auth: impl EventAuth<C::EData> + 'static) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
 
-        Self { store, state }
+    /// Reads the time from `clock` instead of the system clock. Commands are prepared with a
+    /// hybrid logical timestamp of it, see [`Crdt::prepare_at`] and [`clock`].
+    pub fn with_clock(mut self, clock: impl Clock + Send + Sync + 'static) -> Self {
+        self.clock = hlc_clock(self.state.id, clock);
+        self
+    }
+
+    /// The forged events replicated since the last call, oldest first
+    pub fn take_forged(&mut self) -> Vec<ForgedEvent> {
+        std::mem::take(&mut self.forged)
+    }
+
+    /// Remembers a fingerprint of every event taken from here on, and of those in the store that
+    /// don't have one yet, to catch origins replicating two different events under the same
+    /// sequence number. Once that happens the origin is quarantined for good: a batch stops at
+    /// the first event of a quarantined origin, the same as at a forged one, so nothing of theirs
+    /// or building on it is taken anymore. Detections can be looked at with
+    /// [`Replicator::take_equivocations`] and the quarantine with [`Replicator::quarantined`].
+    ///
+    /// Only events that actually get here are compared. Peers don't send events whose version
+    /// the puller has seen, so an equivocation with the same version as the event taken first
+    /// slips by, and so does one of an event that [`Replicator::compact`] has since forgotten.
+    pub async fn detect_equivocation(mut self) -> Result<Self, StoreError>
+    where
+        C::EData: std::hash::Hash,
+    {
+        {
+            let mut events = self.store.load_events(self.state.fingerprints.seq + 1);
+            while let Some(event) = events.next().await {
+                let event = event?;
+                self.state.fingerprints.insert(&event, fingerprint(&event));
+            }
+        }
+        self.fingerprint = Some(fingerprint);
+        Ok(self)
     }
 
-    pub fn query(&mut self) -> C::State {
+    /// Runs `interceptor` around every event taking effect here and every batch replicated to or
+    /// from here, inside the interceptors added before it, see [`interceptor`]. Events already
+    /// in the store aren't intercepted again.
+    pub fn with_interceptor(mut self, interceptor: impl Interceptor<C> + 'static) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// The events interceptors rejected since the last call, oldest first
+    pub fn take_rejected(&mut self) -> Vec<RejectedEvent> {
+        std::mem::take(&mut self.rejected)
+    }
+
+    /// The equivocations detected since the last call, oldest first
+    pub fn take_equivocations(&mut self) -> Vec<EquivocationDetected> {
+        std::mem::take(&mut self.equivocations)
+    }
+
+    /// The origins caught equivocating, with what they were caught at
+    pub fn quarantined(&self) -> impl Iterator<Item = &EquivocationDetected> {
+        self.state.fingerprints.quarantined.values()
+    }
+
+    pub fn snapshot(&self) -> ReplicationState<C> {
+        self.state.clone()
+    }
+
+    /// The version every known replica has seen, along with everything concurrent with it.
+    ///
+    /// A peer's version only counts once this replica has caught up with it, that way events the
+    /// peer made before seeing something stable have made it here too. Peers are the replicas
+    /// this one [connected](proto::Connect) to or that pulled from it, a replica nobody here has
+    /// heard of could still be sitting on concurrent events. So every replica has to connect to
+    /// every other one before anything is compacted.
+    pub fn stable_version(&mut self) -> VTime {
+        let ReplicationState {
+            id, version, peers, ..
+        } = &mut self.state;
+        for peer in peers.values_mut() {
+            if peer.latest <= *version {
+                peer.stable = peer.latest.clone();
+            }
+        }
+
+        // Whoever wrote something we've seen but never reported a version holds everything back
+        let mut writers = version.keys().filter(|&writer| writer != id);
+        if writers.any(|writer| !peers.contains_key(writer)) {
+            return VTime::default();
+        }
+
+        let mut stable = version.clone();
+        for peer in peers.values() {
+            for (replica, seq) in stable.map.iter_mut() {
+                *seq = (*seq).min(peer.stable.get(replica).copied().unwrap_or_default());
+            }
+        }
+        stable.map.retain(|_, seq| *seq > 0);
+        stable
+    }
+
+    /// Lets the CRDT drop what is no longer needed now that it is causally stable, see
+    /// [`Crdt::compact`]
+    pub async fn compact(&mut self) {
+        let stable = self.stable_version();
+        self.state.crdt.compact(&stable);
+        self.state.fingerprints.compact(&stable);
+        trace::debug!(replica = ?self.state.id, ?stable, "compacted");
+        let snapshot = self.state.clone();
+        trace::store_io("save_snapshot", self.store.save_snapshot(snapshot)).await;
+        self.publish();
+    }
+
+    /// A read only handle that sees the CRDT as of the last message this replicator handled
+    pub fn handle(&mut self) -> handle::ReplicatorHandle<C> {
+        let published = self
+            .published
+            .get_or_insert_with(|| watch::channel(Arc::new(self.state.crdt.clone())).0);
+        handle::ReplicatorHandle::new(published.subscribe())
+    }
+
+    /// Copies the CRDT out to the handles, unless there are none left
+    fn publish(&mut self) {
+        if let Some(published) = &self.published {
+            if published.receiver_count() == 0 {
+                self.published = None;
+            } else {
+                published.send_replace(Arc::new(self.state.crdt.clone()));
+            }
+        }
+    }
+
+    pub fn id(&self) -> ReplicaId {
+        self.state.id
+    }
+
+    pub fn query(&self) -> C::State {
         self.state.crdt.query()
     }
 
+    /// See [`Crdt::view`]
+    pub fn view(&self) -> Cow<'_, C::State> {
+        self.state.crdt.view()
+    }
+
+    /// The state as it was when this replica had seen `version`: every event in the log whose
+    /// version `version` covers, replayed in order. Versions of replicas this one never heard
+    /// from don't count.
+    ///
+    /// Replays leave checkpoints behind in memory so asking again, or for a later version, doesn't
+    /// go through the whole log again. A replica started [from a snapshot](Self::from_snapshot)
+    /// can't go back past it, before a restart it starts off the snapshot whatever `version`
+    /// says, after one it doesn't know about it at all.
+    pub async fn query_at(&mut self, version: &VTime) -> Result<C::State, StoreError> {
+        if self.state.version <= *version {
+            return Ok(self.state.crdt.query());
+        }
+
+        let (seq, covered, crdt) = self.checkpoints.before(version);
+        let (mut covered, mut crdt) = (covered.clone(), crdt.clone());
+        // Checkpoints only hold states with every event up to them
+        let mut whole = true;
+        let mut taken = vec![];
+        {
+            let mut events = self.store.load_events(seq + 1);
+            while let Some(event) = events.next().await {
+                let event = event?;
+                if event.version <= *version {
+                    let seq = event.local_seq;
+                    covered.merge(&event.version);
+                    crdt.effect(event);
+                    if whole && self.checkpoints.wants(seq) {
+                        taken.push((seq, covered.clone(), crdt.clone()));
+                    }
+                } else {
+                    whole = false;
+                }
+            }
+        }
+        for (seq, covered, checkpoint) in taken {
+            self.checkpoints.insert(seq, covered, checkpoint);
+        }
+        Ok(crdt.query())
+    }
+
+    /// How much this replica keeps in memory, and how much its store keeps if it tells
+    pub async fn stats(&mut self) -> memory::Stats
+    where
+        C: memory::MemoryUsage,
+        Db: Send,
+    {
+        use memory::{MemoryUsage, Usage};
+
+        let ReplicationState {
+            version,
+            observed,
+            peers,
+            fingerprints,
+            ..
+        } = &self.state;
+        let peer_versions = peers.values().map(|peer| {
+            Usage::btree::<ReplicaId, PeerVersion>(1)
+                + peer.latest.memory_usage()
+                + peer.stable.memory_usage()
+        });
+        let fingerprints = fingerprints.events.values().map(|events| {
+            Usage::btree::<ReplicaId, BTreeMap<u64, (u64, u64)>>(1)
+                + Usage::btree::<u64, (u64, u64)>(events.len())
+        });
+        let replication = version.memory_usage()
+            + Usage::btree::<ReplicaId, u64>(observed.len())
+            + peer_versions.sum()
+            + fingerprints.sum();
+
+        memory::Stats {
+            crdt: self.state.crdt.memory_usage(),
+            replication,
+            checkpoints: self.checkpoints.memory_usage(),
+            events: self.state.seq,
+            store: trace::store_io("usage", self.store.usage()).await,
+        }
+    }
+
+    /// Reads straight from the CRDT, for the iterators it has that don't copy anything
+    pub fn crdt(&self) -> &C {
+        &self.state.crdt
+    }
+
+    pub fn checksum(&self) -> u64
+    where
+        C: checksum::Checksum,
+    {
+        self.state.crdt.checksum()
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(replica = ?self.state.id))
+    )]
     pub async fn send(
         &mut self,
         msg: Protocol<C::Cmd, C::EData>,
         // replicating_nodes: &mut BTreeMap<ReplicaId, ReplicationStatus>,
     ) -> Protocol<C::Cmd, C::EData> {
+        let msg = match msg {
+            Protocol::Replicated(mut replicated) => {
+                let received = Direction::Received;
+                interceptor::on_replicate(&self.interceptors, &mut replicated, received);
+                Protocol::Replicated(replicated)
+            }
+            msg => msg,
+        };
         match msg {
             Protocol::Noop => Protocol::Noop,
             Protocol::Command(cmd) => {
-                self.state.seq += 1;
-                let seq = self.state.seq;
-                self.state.version.increment(self.state.id);
+                let seq = self.state.seq + 1;
+                let mut version = self.state.version.clone();
+                version.increment(self.state.id);
 
-                let data = self.state.crdt.prepare(cmd);
-                let event = Event {
+                let data = self.state.crdt.prepare_at(cmd, self.clock.now());
+                let mut event = Event {
                     origin: self.state.id,
                     origin_seq: seq,
                     local_seq: seq,
-                    version: self.state.version.clone(),
+                    version,
                     data,
+                    signature: None,
                 };
+                if let Some(auth) = &self.auth {
+                    event.signature = auth.sign(&event);
+                }
+                let (id, crdt) = (self.state.id, &self.state.crdt);
+                if let Err(rejected) =
+                    interceptor::before_effect(&self.interceptors, id, &event, crdt)
+                {
+                    trace::warning!(%rejected, "rejected command");
+                    self.rejected.push(rejected);
+                    return Protocol::Noop;
+                }
+                self.state.seq = seq;
+                self.state.version = event.version.clone();
+                if let Some(fingerprint) = self.fingerprint {
+                    self.state.fingerprints.insert(&event, fingerprint(&event));
+                }
 
-                self.store.save_events(std::iter::once(event.clone())).await;
-                self.state.crdt.effect(event);
+                trace::debug!(seq, "command");
+                let saved = self.store.save_events(std::iter::once(event.clone()));
+                trace::store_io("save_events", saved).await;
+                self.state.crdt.effect(event.clone());
+                interceptor::after_effect(&self.interceptors, &event, &self.state.crdt);
+                self.publish();
                 Protocol::Noop
             }
             Protocol::Connect(connect) => {
+                trace::debug!(peer = ?connect.replica_id, "connect");
+                self.state.peers.entry(connect.replica_id).or_default();
                 let seq_nr = self
                     .state
                     .observed
@@ -164,7 +902,11 @@ where
                 Protocol::Replicate(replicate)
             }
             Protocol::Replicate(replicate) => {
-                let replicated = self
+                if replicate.reply_to != self.state.id {
+                    let peer = self.state.peers.entry(replicate.reply_to).or_default();
+                    peer.latest.merge(&replicate.filter);
+                }
+                let mut replicated = self
                     .replay(
                         self.state.id,
                         replicate.filter,
@@ -172,6 +914,15 @@ where
                         replicate.max_count,
                     )
                     .await;
+                trace::debug!(
+                    peer = ?replicate.reply_to,
+                    seq_nr = replicate.seq_nr,
+                    to_seq_nr = replicated.to_seq_nr,
+                    events = replicated.events.len(),
+                    "replicate"
+                );
+                let sent = Direction::Sent;
+                interceptor::on_replicate(&self.interceptors, &mut replicated, sent);
                 Protocol::Replicated(replicated)
             }
             Protocol::Replicated(proto::Replicated {
@@ -181,9 +932,11 @@ where
             }) if events.is_empty() => {
                 // done replicating
                 let observed_seq_nr = self.state.observed.get(&from).copied().unwrap_or_default();
+                trace::debug!(peer = ?from, to_seq_nr, "caught up");
                 if to_seq_nr > observed_seq_nr {
                     self.state.observed.insert(from, to_seq_nr);
-                    self.store.save_snapshot(self.state.clone()).await;
+                    let snapshot = self.state.clone();
+                    trace::store_io("save_snapshot", self.store.save_snapshot(snapshot)).await;
                 }
                 Protocol::Noop
             }
@@ -196,10 +949,57 @@ where
                 let mut remote_seq_nr = new_state.observed.get(&from).copied().unwrap_or_default();
 
                 let mut to_save = vec![];
+                let mut forged = None;
+                let mut rejected = None;
+                let mut detected = false;
+                let mut quarantined = false;
 
                 // for all events not seen by the current node, rewrite them to use local sequence nr, update the state
                 // and save them in the database
-                for e in events.into_iter().filter(|e| self.state.is_unseen(from, e)) {
+                for e in events {
+                    let fingerprint = self.fingerprint.map(|fingerprint| fingerprint(&e));
+                    // Seen or not, an event contradicting one taken before gives its origin away
+                    let equivocation = fingerprint.and_then(|conflicting| {
+                        let taken = new_state.fingerprints.get(e.origin, e.origin_seq)?;
+                        (taken != conflicting).then_some(EquivocationDetected {
+                            from,
+                            origin: e.origin,
+                            origin_seq: e.origin_seq,
+                            taken,
+                            conflicting,
+                        })
+                    });
+                    if equivocation.is_none() && !self.state.is_unseen(from, &e) {
+                        continue;
+                    }
+                    // Checked first so forging a second event doesn't get the origin quarantined
+                    if let Some(Err(reason)) = self.auth.as_ref().map(|auth| auth.verify(&e)) {
+                        forged = Some(ForgedEvent {
+                            from,
+                            origin: e.origin,
+                            origin_seq: e.origin_seq,
+                            reason,
+                        });
+                        break;
+                    }
+                    if new_state.fingerprints.quarantined.contains_key(&e.origin) {
+                        quarantined = true;
+                        break;
+                    }
+                    if let Some(equivocation) = equivocation {
+                        let quarantine = &mut new_state.fingerprints.quarantined;
+                        quarantine.insert(e.origin, equivocation.clone());
+                        self.equivocations.push(equivocation);
+                        detected = true;
+                        break;
+                    }
+                    let interceptors = &self.interceptors;
+                    let crdt = &new_state.crdt;
+                    if let Err(rejection) = interceptor::before_effect(interceptors, from, &e, crdt)
+                    {
+                        rejected = Some(rejection);
+                        break;
+                    }
                     new_state.seq += 1;
                     new_state.version.merge(&e.version);
                     remote_seq_nr = remote_seq_nr.max(e.local_seq);
@@ -207,15 +1007,52 @@ where
                     let mut new_event = e.clone();
                     new_event.local_seq = new_state.seq;
 
+                    if let Some(fingerprint) = fingerprint {
+                        new_state.fingerprints.insert(&new_event, fingerprint);
+                    }
                     new_state.crdt.effect(e);
+                    interceptor::after_effect(&self.interceptors, &new_event, &new_state.crdt);
                     new_state.observed.insert(from, remote_seq_nr);
                     to_save.push(new_event);
                 }
                 self.state = new_state;
+                if !to_save.is_empty() {
+                    self.publish();
+                }
 
-                self.store.save_events(to_save.into_iter()).await;
+                trace::debug!(
+                    peer = ?from,
+                    to_seq_nr,
+                    first_seq = to_save.first().map(|e| e.local_seq),
+                    last_seq = to_save.last().map(|e| e.local_seq),
+                    "replicated"
+                );
+                trace::store_io("save_events", self.store.save_events(to_save.into_iter())).await;
                 // let target = replicating_nodes.get(&from);
 
+                // The peer gets asked again from where the forged event was the next time it
+                // connects
+                if let Some(forged) = forged {
+                    trace::warning!(peer = ?from, %forged, "forged event");
+                    self.forged.push(forged);
+                    return Protocol::Noop;
+                }
+                // Same for a rejected one
+                if let Some(rejected) = rejected {
+                    trace::warning!(peer = ?from, %rejected, "rejected event");
+                    self.rejected.push(rejected);
+                    return Protocol::Noop;
+                }
+                // The quarantine only lives in the snapshot
+                if detected {
+                    trace::warning!(peer = ?from, "equivocation detected");
+                    let snapshot = self.state.clone();
+                    trace::store_io("save_snapshot", self.store.save_snapshot(snapshot)).await;
+                }
+                if detected || quarantined {
+                    return Protocol::Noop;
+                }
+
                 // Keep replicating because we set `max_count` to 100 by default so there might
                 // be more events to replicate
                 Protocol::Replicate(proto::Replicate {
@@ -233,6 +1070,12 @@ where
         }
     }
 
+    /// Events past `seq_nr` that `filter` hasn't seen. A read failing ends the batch early, the
+    /// peer picks up from the last event it got the next time it pulls.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, replica_id, filter), fields(replica = ?replica_id))
+    )]
     pub async fn replay(
         &mut self,
         replica_id: ReplicaId,
@@ -244,20 +1087,9 @@ where
         let mut events = vec![];
         let mut last_seq_nr = 0;
 
-        // let foo = self.store.load_events(seq_nr).await.take(20);
+        let mut event_stream = self.store.load_events(seq_nr).take(count as usize);
 
-        println!(
-            "EVENTS LOL! {:?}",
-            self.store
-                .load_events(seq_nr)
-                .await
-                .collect::<Vec<_>>()
-                .await
-        );
-        let mut event_stream = self.store.load_events(seq_nr).await.take(count as usize);
-
-        while let Some(e) = event_stream.next().await {
-            // println!("NICE: {:?}", e);
+        while let Some(Ok(e)) = event_stream.next().await {
             last_seq_nr = last_seq_nr.max(e.local_seq);
             if matches!(
                 e.version.partial_cmp(&filter),
@@ -318,6 +1150,12 @@ impl VTime {
     }
 }
 
+impl memory::MemoryUsage for VTime {
+    fn memory_usage(&self) -> memory::Usage {
+        memory::Usage::btree::<ReplicaId, u64>(self.map.len())
+    }
+}
+
 impl PartialOrd for VTime {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Self::partial_ord_impl(&self, &other)
@@ -345,9 +1183,28 @@ impl<C> ReplicationState<C>
 where
     C: Crdt,
 {
+    pub fn id(&self) -> ReplicaId {
+        self.id
+    }
+
+    /// Last event in the log this state covers
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn version(&self) -> &VTime {
+        &self.version
+    }
+
+    pub fn crdt(&self) -> &C {
+        &self.crdt
+    }
+
     pub fn is_unseen(&self, node_id: ReplicaId, e: &Event<C::EData>) -> bool {
+        // `observed` holds sequence numbers of `node_id`'s log, compare against where the event
+        // sits in that log rather than in its origin's, they differ for relayed events
         match self.observed.get(&node_id) {
-            Some(&ver) if e.origin_seq <= ver => false,
+            Some(&ver) if e.local_seq <= ver => false,
             _ => {
                 matches!(
                     e.version.partial_cmp(&self.version),
@@ -1,29 +1,50 @@
 use std::{cmp::Ordering, fmt::Debug, io::Write};
 
-use crate::{Crdt, ReplicaId};
+use crate::{
+    memory::{MemoryUsage, Usage},
+    Crdt, ReplicaId,
+};
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LSeq<V> {
     values: Vec<Vertex<V>>,
     id: ReplicaId,
+    /// Inserts made here so far, see [`VPtr::n`]
+    inserts: u64,
 }
 
-#[derive(PartialEq, Clone, Debug)]
+/// Digits at the first level of a sequence are below `2^INITIAL_BASE_BITS`, every level below
+/// doubles that
+const INITIAL_BASE_BITS: u32 = 5;
+
+/// Furthest away from its neighbour a new digit is put, leaving the rest of the gap for whatever
+/// comes later on that side
+const BOUNDARY: u64 = 10;
+
+#[derive(PartialEq, Clone, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VPtr {
-    sequence: Vec<u8>,
+    sequence: Vec<u32>,
     id: ReplicaId,
+    /// Replicas generate the same sequence again when inserting into a gap whose value they removed,
+    /// this keeps the two positions apart
+    n: u64,
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Vertex<V>(VPtr, V);
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Command<V: Debug> {
     Insert(u32, V),
     RemoveAt(u32),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Operation<V: Debug> {
     Inserted(VPtr, V),
     Removed(VPtr),
@@ -31,7 +52,15 @@ pub enum Operation<V: Debug> {
 
 impl<V> LSeq<V> {
     pub fn new(id: ReplicaId) -> Self {
-        Self { values: vec![], id }
+        Self {
+            values: vec![],
+            id,
+            inserts: 0,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &V> + '_ {
+        self.values.iter().map(|v| &v.1)
     }
 }
 
@@ -62,12 +91,14 @@ impl<V: Sync + Send + Clone + Debug> Crdt for LSeq<V> {
                 };
 
                 let mut sequence = vec![];
-                VPtr::generate_seq(&mut sequence, lo, hi);
+                let seed = (self.id.0 as u64) ^ ((self.id.0 >> 64) as u64) ^ self.inserts;
+                VPtr::generate_seq(&mut sequence, lo, hi, seed);
 
                 Operation::Inserted(
                     VPtr {
                         sequence,
                         id: self.id,
+                        n: self.inserts + 1,
                     },
                     value,
                 )
@@ -83,6 +114,9 @@ impl<V: Sync + Send + Clone + Debug> Crdt for LSeq<V> {
     fn effect(&mut self, event: crate::Event<Self::EData>) {
         match event.data {
             Operation::Inserted(ptr, value) => {
+                if ptr.id == self.id {
+                    self.inserts = self.inserts.max(ptr.n);
+                }
                 let idx = self
                     .values
                     .binary_search_by(|Vertex(vptr, _)| VPtr::compare(vptr, &ptr))
@@ -106,6 +140,11 @@ impl<V: Sync + Send + Clone + Debug> Crdt for LSeq<V> {
             }
         }
     }
+
+    fn rebind(&mut self, id: ReplicaId) {
+        self.id = id;
+        self.inserts = 0;
+    }
 }
 
 impl VPtr {
@@ -126,31 +165,88 @@ impl VPtr {
         Ok(())
     }
 
+    /// Sequences compare digit by digit, the replica id and then `n` only break ties between equal
+    /// ones
     pub fn compare(a: &Self, b: &Self) -> Ordering {
-        match a.sequence.len().cmp(&b.sequence.len()) {
-            Ordering::Equal => return a.id.cmp(&b.id),
-            _ => (),
-        }
-        a.sequence.cmp(&b.sequence)
+        a.sequence
+            .cmp(&b.sequence)
+            .then_with(|| a.id.cmp(&b.id))
+            .then_with(|| a.n.cmp(&b.n))
     }
 
-    pub fn generate_seq(acc: &mut Vec<u8>, lo: &[u8], hi: &[u8]) {
-        let mut i = 0;
-        loop {
-            let min = lo.get(i).copied().unwrap_or(0);
-            let max = hi.get(i).copied().unwrap_or(u8::MAX);
+    /// LSEQ allocation: goes down the levels `lo` and `hi` share until there is room between them,
+    /// then picks a digit at most [`BOUNDARY`] away from one end of the gap. Which end depends on
+    /// the level, see [`Strategy`]. Since the base doubles with every level, sequences grow
+    /// logarithmically with the number of inserts instead of linearly when they keep landing on
+    /// the same side. `seed` picks the offset within the boundary.
+    pub fn generate_seq(acc: &mut Vec<u32>, lo: &[u32], hi: &[u32], seed: u64) {
+        // Whether `hi` still bounds the digits, once `acc` is below `hi`'s prefix it doesn't
+        let mut bounded = true;
+        for level in 0.. {
+            let base = base(level);
+            let min = lo.get(level).copied().map_or(0, u64::from);
+            let max = match hi.get(level) {
+                Some(&digit) if bounded => digit as u64,
+                _ => base,
+            };
 
             if min + 1 < max {
-                acc.push(min + 1);
+                let step = 1 + mix(seed, level as u64) % BOUNDARY.min(max - min - 1);
+                let digit = match Strategy::at(level) {
+                    Strategy::BoundaryPlus => min + step,
+                    Strategy::BoundaryMinus => max - step,
+                };
+                acc.push(digit as u32);
                 return;
             }
 
-            acc.push(min);
-            i += 1;
+            acc.push(min as u32);
+            bounded &= min == max;
+        }
+    }
+}
+
+/// Which end of a gap new digits are put next to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Right after the lower bound, which leaves room for appending
+    BoundaryPlus,
+    /// Right before the upper bound, which leaves room for prepending
+    BoundaryMinus,
+}
+
+impl Strategy {
+    /// Levels alternate, so whichever way a document is written every other level has room for it
+    pub fn at(level: usize) -> Self {
+        if level.is_multiple_of(2) {
+            Strategy::BoundaryPlus
+        } else {
+            Strategy::BoundaryMinus
         }
     }
 }
 
+fn base(level: usize) -> u64 {
+    1 << (INITIAL_BASE_BITS + level as u32).min(u32::BITS)
+}
+
+/// SplitMix64 of `seed` and `level`
+fn mix(seed: u64, level: u64) -> u64 {
+    let mut z = seed ^ level.rotate_left(32);
+    z = z.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Every vertex counts the digits of its position as well
+impl<V> MemoryUsage for LSeq<V> {
+    fn memory_usage(&self) -> Usage {
+        let sequences = self.values.iter().map(|Vertex(ptr, _)| ptr.sequence.len());
+        Usage::vec::<Vertex<V>>(self.values.len()) + Usage::vec::<u32>(sequences.sum())
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -158,7 +254,7 @@ mod test {
         lseq::{Command, LSeq},
         memdb::InMemoryDb,
         protocol::Protocol,
-        replicate, ReplicaId, Replicator,
+        replicate, Crdt, Event, ReplicaId, Replicator, VTime,
     };
 
     #[tokio::test]
@@ -168,9 +264,12 @@ mod test {
         let alice_id = ReplicaId(0);
         let bob_id = ReplicaId(1);
         let mut alice =
-            Replicator::new(alice_id, Crdt::new(alice_id), InMemoryDb::<Crdt>::default()).await;
-        let mut bob =
-            Replicator::new(bob_id, Crdt::new(bob_id), InMemoryDb::<Crdt>::default()).await;
+            Replicator::new(alice_id, Crdt::new(alice_id), InMemoryDb::<Crdt>::default())
+                .await
+                .unwrap();
+        let mut bob = Replicator::new(bob_id, Crdt::new(bob_id), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
 
         let _ = alice
             .send(Protocol::Command(Command::Insert(0, "nice")))
@@ -194,9 +293,12 @@ mod test {
         let alice_id = ReplicaId(0);
         let bob_id = ReplicaId(1);
         let mut alice =
-            Replicator::new(alice_id, Crdt::new(alice_id), InMemoryDb::<Crdt>::default()).await;
-        let mut bob =
-            Replicator::new(bob_id, Crdt::new(bob_id), InMemoryDb::<Crdt>::default()).await;
+            Replicator::new(alice_id, Crdt::new(alice_id), InMemoryDb::<Crdt>::default())
+                .await
+                .unwrap();
+        let mut bob = Replicator::new(bob_id, Crdt::new(bob_id), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
 
         let _ = alice
             .send(Protocol::Command(Command::Insert(0, "nice")))
@@ -217,4 +319,39 @@ mod test {
         assert_eq!(alice_value, vec!["nah"]);
         assert_eq!(alice_value, bob_value)
     }
+
+    /// Sequences stay short after 10k inserts at the same end or all over the place
+    #[test]
+    fn sequence_length() {
+        let run = |at: fn(u32) -> u32| {
+            let mut lseq = LSeq::new(ReplicaId(0));
+            for i in 0..10_000u32 {
+                let seq = i as u64 + 1;
+                let data = lseq.prepare(Command::Insert(at(i), i));
+                lseq.effect(Event {
+                    origin: ReplicaId(0),
+                    origin_seq: seq,
+                    local_seq: seq,
+                    version: VTime {
+                        map: [(ReplicaId(0), seq)].into(),
+                    },
+                    data,
+                    signature: None,
+                });
+            }
+            let longest = lseq.values.iter().map(|v| v.0.sequence.len()).max();
+            (lseq.query(), longest.unwrap())
+        };
+
+        let (appended, longest) = run(|i| i);
+        assert_eq!(appended, (0..10_000).collect::<Vec<_>>());
+        assert!(longest <= 16, "{longest}");
+
+        let (prepended, longest) = run(|_| 0);
+        assert_eq!(prepended, (0..10_000).rev().collect::<Vec<_>>());
+        assert!(longest <= 16, "{longest}");
+
+        let (_, longest) = run(|i| (i as u64 * 2654435761 % (i as u64 + 1)) as u32);
+        assert!(longest <= 16, "{longest}");
+    }
 }
@@ -1,8 +1,12 @@
-use std::cmp::Ordering;
+use std::{borrow::Cow, cmp::Ordering};
 
-use crate::{Crdt, ReplicaId, VTime};
+use crate::{
+    memory::{MemoryUsage, Usage},
+    Change, ChangeSummary, Crdt, ReplicaId, VTime,
+};
 
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LWWRegister<V> {
     id: ReplicaId,
     time: VTime,
@@ -30,6 +34,10 @@ impl<V: Default + Clone + Send + Sync + std::fmt::Debug> Crdt for LWWRegister<V>
         self.value.clone()
     }
 
+    fn view(&self) -> Cow<'_, Self::State> {
+        Cow::Borrowed(&self.value)
+    }
+
     fn prepare(&self, op: Self::Cmd) -> Self::EData {
         op
     }
@@ -60,14 +68,30 @@ impl<V: Default + Clone + Send + Sync + std::fmt::Debug> Crdt for LWWRegister<V>
             }
         }
     }
+
+    /// A concurrent write from a smaller replica id still wins over the one described
+    fn describe(&self, event: &crate::Event<Self::EData>) -> ChangeSummary {
+        ChangeSummary {
+            origin: event.origin,
+            change: Change::Set {
+                value: event.data.as_ref().map(|value| format!("{value:?}")),
+            },
+        }
+    }
+}
+
+impl<V> MemoryUsage for LWWRegister<V> {
+    fn memory_usage(&self) -> Usage {
+        self.time.memory_usage()
+    }
 }
 
 #[cfg(test)]
 mod test {
 
     use crate::{
-        lwwreg::LWWRegister, memdb::InMemoryDb, protocol::Protocol, replicate, ReplicaId,
-        Replicator,
+        lwwreg::LWWRegister, memdb::InMemoryDb, protocol::Protocol, replicate, Crdt as _,
+        ReplicaId, Replicator,
     };
 
     #[tokio::test]
@@ -76,9 +100,12 @@ mod test {
 
         let alice_id = ReplicaId(0);
         let bob_id = ReplicaId(1);
-        let mut alice =
-            Replicator::new(alice_id, LWW::new(alice_id), InMemoryDb::<LWW>::default()).await;
-        let mut bob = Replicator::new(bob_id, LWW::new(bob_id), InMemoryDb::<LWW>::default()).await;
+        let mut alice = Replicator::new(alice_id, LWW::new(alice_id), InMemoryDb::<LWW>::default())
+            .await
+            .unwrap();
+        let mut bob = Replicator::new(bob_id, LWW::new(bob_id), InMemoryDb::<LWW>::default())
+            .await
+            .unwrap();
 
         let _ = alice.send(Protocol::Command(Some("nice"))).await;
         let _ = bob.send(Protocol::Command(Some("nah"))).await;
@@ -92,4 +119,25 @@ mod test {
         assert_eq!(alice_value, Some("nice"));
         assert_eq!(alice_value, bob_value)
     }
+
+    #[tokio::test]
+    async fn describe() {
+        type LWW<'a> = LWWRegister<&'a str>;
+
+        let alice_id = ReplicaId(0);
+        let mut alice = Replicator::new(alice_id, LWW::new(alice_id), InMemoryDb::<LWW>::default())
+            .await
+            .unwrap();
+
+        let _ = alice.send(Protocol::Command(Some("nice"))).await;
+        let _ = alice.send(Protocol::Command(None)).await;
+
+        let replicated = alice.replay(alice_id, Default::default(), 1, 100).await;
+        let summaries = replicated
+            .events
+            .iter()
+            .map(|event| alice.crdt().describe(event).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(summaries, vec!["0 set \"nice\"", "0 cleared the value"]);
+    }
 }
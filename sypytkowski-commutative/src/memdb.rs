@@ -1,8 +1,14 @@
 use std::{collections::BTreeMap, sync::Arc};
 
-use crate::{Crdt, Event, ReplicationState, Store};
+use crate::{
+    memory::{MemoryUsage, Usage},
+    Crdt, Event, ReplicationState, Store, StoreError,
+};
 use async_trait::async_trait;
-use futures::{future::BoxFuture, FutureExt};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
 use tokio::sync::RwLock;
 
 #[derive(Clone)]
@@ -33,17 +39,19 @@ impl<C: Crdt> Store<C> for InMemoryDb<C> {
         self.state.read().await.clone()
     }
 
-    async fn load_events<'a>(
-        &'a mut self,
+    /// Looks up one event at a time instead of copying the rest of the log up front, so taking
+    /// a batch off the front costs as much as the batch
+    fn load_events(
+        &mut self,
         start_seq: u64,
-    ) -> futures::stream::FuturesOrdered<BoxFuture<'a, Event<C::EData>>> {
-        let events_map = self.events.read().await;
-        let events = events_map.range(start_seq..).map(|(_, event)| {
-            let new_event = event.clone();
-            async { new_event }.boxed()
-        });
-
-        futures::stream::FuturesOrdered::from_iter(events)
+    ) -> BoxStream<'_, Result<Event<C::EData>, StoreError>> {
+        let events = &self.events;
+        stream::unfold(start_seq, move |next| async move {
+            let events = events.read().await;
+            let (&seq, event) = events.range(next..).next()?;
+            Some((Ok(event.clone()), seq + 1))
+        })
+        .boxed()
     }
 
     async fn save_events<I: Iterator<Item = crate::Event<<C as Crdt>::EData>> + Send>(
@@ -55,4 +63,112 @@ impl<C: Crdt> Store<C> for InMemoryDb<C> {
             events_map.insert(event.local_seq, event);
         }
     }
+
+    /// The events and their versions, the snapshot isn't counted
+    async fn usage(&mut self) -> Option<Usage> {
+        let events = self.events.read().await;
+        let versions = events.values().map(|event| event.version.memory_usage());
+        Some(Usage::btree::<u64, Event<C::EData>>(events.len()) + versions.sum())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_trait::async_trait;
+    use futures::{
+        stream::{self, BoxStream},
+        StreamExt,
+    };
+
+    use crate::{
+        counter::Counter, protocol::Protocol, Crdt, Event, ReplicaId, ReplicationState, Replicator,
+        Store, StoreError,
+    };
+
+    use super::InMemoryDb;
+
+    #[tokio::test]
+    async fn load_events_from_the_middle() {
+        let id = ReplicaId(0);
+        let mut db = InMemoryDb::<Counter>::default();
+        let mut replica = Replicator::new(id, Counter::default(), db.clone())
+            .await
+            .unwrap();
+        for i in 1..=5 {
+            replica.send(Protocol::Command(i)).await;
+        }
+
+        let events = db.load_events(3).collect::<Vec<_>>().await;
+        let data = events
+            .into_iter()
+            .map(|event| event.unwrap().data)
+            .collect::<Vec<_>>();
+        assert_eq!(data, vec![3, 4, 5]);
+        assert_eq!(db.load_events(6).count().await, 0);
+    }
+
+    /// Hands out what its [`InMemoryDb`] has and then fails
+    struct Broken(InMemoryDb<Counter>);
+
+    #[async_trait]
+    impl Store<Counter> for Broken {
+        async fn save_snapshot(&mut self, state: ReplicationState<Counter>) {
+            self.0.save_snapshot(state).await
+        }
+
+        async fn load_snapshot(&mut self) -> Option<ReplicationState<Counter>> {
+            self.0.load_snapshot().await
+        }
+
+        fn load_events(
+            &mut self,
+            start_seq: u64,
+        ) -> BoxStream<'_, Result<Event<<Counter as Crdt>::EData>, StoreError>> {
+            let err = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "log cut short");
+            self.0
+                .load_events(start_seq)
+                .chain(stream::iter([Err(err.into())]))
+                .boxed()
+        }
+
+        async fn save_events<I: Iterator<Item = Event<<Counter as Crdt>::EData>> + Send>(
+            &mut self,
+            events: I,
+        ) {
+            self.0.save_events(events).await
+        }
+    }
+
+    #[tokio::test]
+    async fn store_errors() {
+        let id = ReplicaId(0);
+        let db = InMemoryDb::<Counter>::default();
+        let mut replica = Replicator::new(id, Counter::default(), db.clone())
+            .await
+            .unwrap();
+        for i in 1..=3 {
+            replica.send(Protocol::Command(i)).await;
+        }
+
+        // Serving a peer stops at the failure with what was read so far
+        let mut broken = Replicator {
+            store: Broken(db.clone()),
+            state: replica.state.clone(),
+            published: None,
+            auth: None,
+            forged: vec![],
+            fingerprint: None,
+            equivocations: vec![],
+            interceptors: vec![],
+            rejected: vec![],
+            checkpoints: replica.checkpoints.clone(),
+            clock: replica.clock.clone(),
+        };
+        let replicated = broken.replay(ReplicaId(1), Default::default(), 1, 10).await;
+        assert_eq!(replicated.events.len(), 3);
+
+        // Recovering doesn't carry on as if the log ended there
+        let recovered = Replicator::new(id, Counter::default(), Broken(db)).await;
+        assert!(matches!(recovered, Err(StoreError::Io(_))));
+    }
 }
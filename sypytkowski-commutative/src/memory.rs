@@ -0,0 +1,131 @@
+//! How much replicas hold on to, for deciding when [compacting](crate::Replicator::compact) is
+//! worth it, see [`Replicator::stats`](crate::Replicator::stats).
+//!
+//! Sizes are estimates: every entry counts its own size plus a share of what the collection
+//! spends on keeping it, about half again for a B-tree node and an eighth for a hash table slot.
+//! What values allocate themselves, like the contents of a `String`, isn't counted.
+use std::{
+    mem::size_of,
+    ops::{Add, AddAssign},
+};
+
+/// Entries kept and the heap they take up, see [`MemoryUsage`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub entries: usize,
+    pub heap_bytes: usize,
+}
+
+impl Usage {
+    /// `len` entries of a `BTreeMap<K, V>`, or of a `BTreeSet<K>` with `V = ()`
+    pub fn btree<K, V>(len: usize) -> Self {
+        Self {
+            entries: len,
+            heap_bytes: len * (size_of::<K>() + size_of::<V>()) * 3 / 2,
+        }
+    }
+
+    /// `len` entries of a `HashMap<K, V>`, or of a `HashSet<K>` with `V = ()`
+    pub fn hash<K, V>(len: usize) -> Self {
+        Self {
+            entries: len,
+            heap_bytes: len * (size_of::<K>() + size_of::<V>() + 1) * 9 / 8,
+        }
+    }
+
+    /// `len` entries of a `Vec<T>`, the spare capacity isn't counted
+    pub fn vec<T>(len: usize) -> Self {
+        Self {
+            entries: len,
+            heap_bytes: len * size_of::<T>(),
+        }
+    }
+}
+
+impl Add for Usage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            entries: self.entries + other.entries,
+            heap_bytes: self.heap_bytes + other.heap_bytes,
+        }
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl std::iter::Sum for Usage {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+/// What a CRDT keeps, tombstones and other metadata included
+pub trait MemoryUsage {
+    fn memory_usage(&self) -> Usage;
+}
+
+/// What a replicator keeps, see [`Replicator::stats`](crate::Replicator::stats)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub crdt: Usage,
+    /// Versions of this replica and its peers, and the fingerprints of
+    /// [equivocation detection](crate::Replicator::detect_equivocation)
+    pub replication: Usage,
+    /// The states kept for [`Replicator::query_at`](crate::Replicator::query_at)
+    pub checkpoints: Usage,
+    /// Events in the log
+    pub events: u64,
+    /// `None` when the store doesn't tell, see [`Store::usage`](crate::Store::usage)
+    pub store: Option<Usage>,
+}
+
+impl Stats {
+    /// Everything kept in memory, the store only counts when it's in memory itself
+    pub fn total(&self) -> Usage {
+        self.crdt + self.replication + self.checkpoints
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        memdb::InMemoryDb,
+        protocol::Protocol,
+        rga::{Command, Rga},
+        ReplicaId, Replicator,
+    };
+
+    #[tokio::test]
+    async fn compacting_drops_tombstones() {
+        type Crdt = Rga<char>;
+
+        let id = ReplicaId::new(1);
+        let mut replica = Replicator::new(id, Crdt::new(id), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
+        for (i, c) in "abc".chars().enumerate() {
+            let insert = Command::Insert(i as u32, c);
+            let _ = replica.send(Protocol::Command(insert)).await;
+        }
+        let _ = replica.send(Protocol::Command(Command::RemoveAt(0))).await;
+
+        let before = replica.stats().await;
+        assert_eq!(before.events, 4);
+        // Every event and the one entry of its version
+        assert_eq!(before.store.unwrap().entries, 8);
+        assert!(before.replication.entries > 0);
+
+        // A replica without peers has everything stable
+        replica.compact().await;
+        let after = replica.stats().await;
+        assert!(after.crdt.entries < before.crdt.entries);
+        assert!(after.crdt.heap_bytes < before.crdt.heap_bytes);
+        assert_eq!(after.store, before.store);
+    }
+}
@@ -0,0 +1,375 @@
+//! Event data that outlives its layout, see [`MigratingStore`].
+//!
+//! Events are kept forever, so a log written by an older release holds event data in whatever
+//! layout that release had. A [`MigratingStore`] writes the data of every event in an
+//! [`Envelope`] along with the version of its layout, and upgrades older data one version at a
+//! time with a [`Migrator`] as the events are loaded. Data is kept as JSON values in between, so
+//! migrations can move fields around without the old types still being in the code.
+//!
+//! Logs written before the envelope are read as version 0. Only the events are migrated, the
+//! snapshot is kept as the CRDT serializes it.
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Crdt, Event, EventData, ReplicationState, Store, StoreError};
+
+/// Upgrades event data written by older releases
+pub trait Migrator: Send + Sync {
+    /// Version of the layout events are written with now
+    fn version(&self) -> u32;
+
+    /// `data` of layout `from` in the layout of version `from + 1`
+    fn migrate(&self, from: u32, data: Value) -> Result<Value, String>;
+}
+
+/// What a [`MigratingStore`] keeps in place of the data of an event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Stored")]
+pub struct Envelope {
+    pub version: u32,
+    pub data: Value,
+}
+
+/// Data written before the envelope is anything that isn't one
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    Versioned(Versioned),
+    Unversioned(Value),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Versioned {
+    version: u32,
+    data: Value,
+}
+
+impl From<Stored> for Envelope {
+    fn from(stored: Stored) -> Self {
+        match stored {
+            Stored::Versioned(Versioned { version, data }) => Envelope { version, data },
+            Stored::Unversioned(data) => Envelope { version: 0, data },
+        }
+    }
+}
+
+/// What a [`MigratingStore`] hands its inner store instead of the CRDT. The snapshot is the
+/// CRDT's own, it only swaps out the data of the events.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Enveloped<C>(pub C);
+
+impl<C: Crdt> Crdt for Enveloped<C> {
+    type State = C::State;
+    type Cmd = ();
+    type EData = Envelope;
+
+    fn query(&self) -> Self::State {
+        self.0.query()
+    }
+
+    fn prepare(&self, _op: Self::Cmd) -> Self::EData {
+        Envelope {
+            version: 0,
+            data: Value::Null,
+        }
+    }
+
+    fn effect(&mut self, _event: Event<Self::EData>) {}
+}
+
+/// Writes event data into `S` in an [`Envelope`] and migrates it with `M` on the way out.
+///
+/// Migrations run every time the events are loaded, the store keeps them in the layout they were
+/// written in. Events that don't migrate, or that a newer release wrote, come out of
+/// [`Store::load_events`] as [`StoreError::Corrupt`].
+pub struct MigratingStore<S, M> {
+    inner: S,
+    migrator: M,
+}
+
+impl<S, M: Migrator> MigratingStore<S, M> {
+    pub fn new(inner: S, migrator: M) -> Self {
+        Self { inner, migrator }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<C, S, M> Store<C> for MigratingStore<S, M>
+where
+    C: Crdt + 'static,
+    C::EData: Serialize + DeserializeOwned,
+    S: Store<Enveloped<C>> + Send,
+    M: Migrator,
+{
+    async fn save_snapshot(&mut self, state: ReplicationState<C>) {
+        self.inner.save_snapshot(with_crdt(state, Enveloped)).await;
+    }
+
+    async fn load_snapshot(&mut self) -> Option<ReplicationState<C>> {
+        let state = self.inner.load_snapshot().await?;
+        Some(with_crdt(state, |Enveloped(crdt)| crdt))
+    }
+
+    fn load_events(
+        &mut self,
+        start_seq: u64,
+    ) -> BoxStream<'_, Result<Event<C::EData>, StoreError>> {
+        let migrator = &self.migrator;
+        self.inner
+            .load_events(start_seq)
+            .map(move |event| upgrade(migrator, event?))
+            .boxed()
+    }
+
+    async fn save_events<I: Iterator<Item = Event<C::EData>> + Send>(&mut self, events: I) {
+        let version = self.migrator.version();
+        let enveloped = events
+            .map(|event| Event {
+                data: Envelope {
+                    version,
+                    data: serde_json::to_value(&event.data)
+                        .expect("JSON encoding doesn't fail for plain data"),
+                },
+                origin: event.origin,
+                origin_seq: event.origin_seq,
+                local_seq: event.local_seq,
+                version: event.version,
+                signature: event.signature,
+            })
+            .collect::<Vec<_>>();
+        self.inner.save_events(enveloped.into_iter()).await;
+    }
+}
+
+/// The data of `event` migrated up to the current version
+fn upgrade<D: EventData + DeserializeOwned>(
+    migrator: &impl Migrator,
+    event: Event<Envelope>,
+) -> Result<Event<D>, StoreError> {
+    let Envelope {
+        mut version,
+        mut data,
+    } = event.data;
+    let current = migrator.version();
+    if version > current {
+        return Err(StoreError::Corrupt(format!(
+            "event {} is of version {version}, this release only knows up to {current}",
+            event.local_seq
+        )));
+    }
+    while version < current {
+        data = migrator.migrate(version, data).map_err(|reason| {
+            let seq = event.local_seq;
+            StoreError::Corrupt(format!(
+                "event {seq} doesn't migrate from version {version}: {reason}"
+            ))
+        })?;
+        version += 1;
+    }
+    Ok(Event {
+        data: serde_json::from_value(data).map_err(|err| StoreError::Corrupt(err.to_string()))?,
+        origin: event.origin,
+        origin_seq: event.origin_seq,
+        local_seq: event.local_seq,
+        version: event.version,
+        signature: event.signature,
+    })
+}
+
+fn with_crdt<A: Crdt, B: Crdt>(
+    state: ReplicationState<A>,
+    f: impl FnOnce(A) -> B,
+) -> ReplicationState<B> {
+    let ReplicationState {
+        id,
+        seq,
+        version,
+        observed,
+        peers,
+        crdt,
+        fingerprints,
+    } = state;
+    ReplicationState {
+        id,
+        seq,
+        version,
+        observed,
+        peers,
+        crdt: f(crdt),
+        fingerprints,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use futures::StreamExt;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+
+    use crate::{memdb::InMemoryDb, protocol::Protocol, Crdt, Event, ReplicaId, Replicator, Store};
+
+    use super::{Envelope, Enveloped, MigratingStore, Migrator};
+
+    /// Votes per name
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    struct Tally(BTreeMap<String, i64>);
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Vote {
+        name: String,
+        weight: i64,
+    }
+
+    impl Crdt for Tally {
+        type State = BTreeMap<String, i64>;
+        type Cmd = Vote;
+        type EData = Vote;
+
+        fn query(&self) -> Self::State {
+            self.0.clone()
+        }
+
+        fn prepare(&self, op: Self::Cmd) -> Self::EData {
+            op
+        }
+
+        fn effect(&mut self, event: Event<Self::EData>) {
+            let Vote { name, weight } = event.data;
+            *self.0.entry(name).or_default() += weight;
+        }
+    }
+
+    /// Votes used to be bare names, then names in a struct, and only later got a weight
+    struct Votes;
+
+    impl Migrator for Votes {
+        fn version(&self) -> u32 {
+            2
+        }
+
+        fn migrate(&self, from: u32, data: Value) -> Result<Value, String> {
+            match (from, data) {
+                (0, name) => Ok(json!({ "name": name })),
+                (1, Value::Object(mut vote)) => {
+                    vote.insert("weight".into(), json!(1));
+                    Ok(Value::Object(vote))
+                }
+                (1, _) => Err("vote isn't an object".into()),
+                _ => Err(format!("there was no version {from}")),
+            }
+        }
+    }
+
+    /// Two votes of replica 1, one from before the envelope and one from version 1
+    const OLD_LOG: &str = r#"
+{"origin":"1","origin_seq":1,"local_seq":1,"version":{"map":{"1":1}},"data":"carol"}
+{"origin":"1","origin_seq":2,"local_seq":2,"version":{"map":{"1":2}},"data":{"version":1,"data":{"name":"dave"}}}
+"#;
+
+    type Inner = InMemoryDb<Enveloped<Tally>>;
+
+    async fn inner(log: &str) -> Inner {
+        let inner = Inner::default();
+        let mut events = inner.events.write().await;
+        for line in log.lines().filter(|line| !line.is_empty()) {
+            let event: Event<Envelope> = serde_json::from_str(line).unwrap();
+            events.insert(event.local_seq, event);
+        }
+        drop(events);
+        inner
+    }
+
+    async fn start(inner: &Inner) -> Replicator<Tally, MigratingStore<Inner, Votes>> {
+        let store = MigratingStore::new(inner.clone(), Votes);
+        Replicator::new(ReplicaId::new(1), Tally::default(), store)
+            .await
+            .unwrap()
+    }
+
+    fn tally(votes: &[(&str, i64)]) -> BTreeMap<String, i64> {
+        (votes.iter())
+            .map(|(name, votes)| (name.to_string(), *votes))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn migrates_old_events() {
+        let inner = inner(OLD_LOG).await;
+        let mut replica = start(&inner).await;
+        assert_eq!(replica.query(), tally(&[("carol", 1), ("dave", 1)]));
+
+        let vote = Vote {
+            name: "carol".into(),
+            weight: 2,
+        };
+        replica.send(Protocol::Command(vote)).await;
+        replica.compact().await;
+        assert_eq!(
+            start(&inner).await.query(),
+            tally(&[("carol", 3), ("dave", 1)])
+        );
+
+        // New events are written with the current version, old ones stay as they were
+        let events = inner.events.read().await;
+        let versions = (events.values())
+            .map(|event| event.data.version)
+            .collect::<Vec<_>>();
+        assert_eq!(versions, [0, 1, 2]);
+        assert_eq!(
+            events[&3].data.data,
+            json!({ "name": "carol", "weight": 2 })
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_events_that_dont_migrate() {
+        let inner = inner(
+            r#"
+{"origin":"1","origin_seq":1,"local_seq":1,"version":{"map":{"1":1}},"data":{"version":1,"data":"erin"}}
+{"origin":"1","origin_seq":2,"local_seq":2,"version":{"map":{"1":2}},"data":{"version":2,"data":{"name":"frank"}}}
+{"origin":"1","origin_seq":3,"local_seq":3,"version":{"map":{"1":3}},"data":{"version":3,"data":{}}}
+{"origin":"1","origin_seq":4,"local_seq":4,"version":{"map":{"1":4}},"data":"grace"}
+"#,
+        )
+        .await;
+        let mut store = MigratingStore::new(inner, Votes);
+        let events = Store::<Tally>::load_events(&mut store, 0)
+            .map(|event| event.map(|event| event.data).map_err(|err| err.to_string()))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            [
+                Err(
+                    "corrupt event in store: event 1 doesn't migrate from version 1: vote isn't \
+                     an object"
+                        .into()
+                ),
+                Err("corrupt event in store: missing field `weight`".into()),
+                Err(
+                    "corrupt event in store: event 3 is of version 3, this release only knows \
+                     up to 2"
+                        .into()
+                ),
+                Ok(Vote {
+                    name: "grace".into(),
+                    weight: 1,
+                }),
+            ]
+        );
+    }
+}
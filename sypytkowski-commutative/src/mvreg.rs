@@ -1,8 +1,12 @@
 use std::{cmp::Ordering, collections::BTreeSet};
 
-use crate::{Crdt, VTime};
+use crate::{
+    memory::{MemoryUsage, Usage},
+    Crdt, VTime,
+};
 
 #[derive(Clone, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MVRegister<V> {
     values: Vec<(VTime, Option<V>)>,
 }
@@ -40,6 +44,13 @@ impl<V: Ord + Default + Clone + Send + Sync + std::fmt::Debug> Crdt for MVRegist
     }
 }
 
+impl<V> MemoryUsage for MVRegister<V> {
+    fn memory_usage(&self) -> Usage {
+        let versions = self.values.iter().map(|(version, _)| version.memory_usage());
+        Usage::vec::<(VTime, Option<V>)>(self.values.len()) + versions.sum()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::BTreeSet;
@@ -54,8 +65,12 @@ mod test {
 
         let alice_id = ReplicaId(0);
         let bob_id = ReplicaId(1);
-        let mut alice = Replicator::new(alice_id, Crdt::new(), InMemoryDb::<Crdt>::default()).await;
-        let mut bob = Replicator::new(bob_id, Crdt::new(), InMemoryDb::<Crdt>::default()).await;
+        let mut alice = Replicator::new(alice_id, Crdt::new(), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
+        let mut bob = Replicator::new(bob_id, Crdt::new(), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
 
         let _ = alice.send(Protocol::Command(Some("nice"))).await;
         let _ = bob.send(Protocol::Command(Some("nah"))).await;
@@ -1,19 +1,33 @@
-use std::{collections::HashSet, hash::Hash};
+use std::{
+    collections::HashSet,
+    hash::{Hash, Hasher},
+};
 
-use crate::{Crdt, VTime};
+use crate::{
+    checksum::stable_hash,
+    memory::{MemoryUsage, Usage},
+    Change, ChangeSummary, Crdt, VTime,
+};
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(deserialize = "V: Eq + serde::Deserialize<'de>"))
+)]
 pub struct ORSet<V: Hash> {
     values: HashSet<(V, ClockWrapper)>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Command<V: Hash> {
     Add(V),
     Remove(V),
 }
 
 #[derive(Debug, Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ClockWrapper(VTime);
 impl Eq for ClockWrapper {
     fn assert_receiver_is_total_eq(&self) {}
@@ -25,11 +39,30 @@ impl PartialEq for ClockWrapper {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Op<V: Hash> {
     Added(V),
     Removed(HashSet<ClockWrapper>),
 }
 
+/// Doesn't depend on the order the removed clocks sit in the set
+impl<V: Hash> Hash for Op<V> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Op::Added(value) => {
+                state.write_u8(0);
+                value.hash(state);
+            }
+            Op::Removed(clocks) => {
+                state.write_u8(1);
+                state.write_usize(clocks.len());
+                let clocks = clocks.iter().map(stable_hash);
+                state.write_u64(clocks.fold(0, u64::wrapping_add));
+            }
+        }
+    }
+}
+
 impl<V: Hash> ORSet<V> {
     pub fn new() -> Self {
         Self {
@@ -38,6 +71,21 @@ impl<V: Hash> ORSet<V> {
     }
 }
 
+impl<V: Eq + Hash> ORSet<V> {
+    /// Every value once, however many times it was added concurrently
+    pub fn iter(&self) -> impl Iterator<Item = &V> + '_ {
+        let mut seen = HashSet::new();
+        self.values
+            .iter()
+            .map(|(v, _)| v)
+            .filter(move |v| seen.insert(*v))
+    }
+
+    pub fn contains(&self, value: &V) -> bool {
+        self.values.iter().any(|(v, _)| v == value)
+    }
+}
+
 impl<V: Eq + Hash + Clone + Send + Sync + std::fmt::Debug> Crdt for ORSet<V> {
     type State = HashSet<V>;
 
@@ -67,11 +115,37 @@ impl<V: Eq + Hash + Clone + Send + Sync + std::fmt::Debug> Crdt for ORSet<V> {
                 self.values.insert((val, ClockWrapper(event.version)));
             }
             Op::Removed(removed) => {
-                self.values
-                    .drain_filter(|(_, clock)| removed.contains(clock));
+                self.values.retain(|(_, clock)| !removed.contains(clock));
             }
         }
     }
+
+    fn describe(&self, event: &crate::Event<Self::EData>) -> ChangeSummary {
+        let change = match &event.data {
+            Op::Added(val) => Change::Added {
+                value: format!("{val:?}"),
+            },
+            // Removes only ever name copies of one value
+            Op::Removed(removed) => self
+                .values
+                .iter()
+                .find(|(_, clock)| removed.contains(clock))
+                .map_or(Change::Unknown, |(val, _)| Change::Removed {
+                    value: format!("{val:?}"),
+                }),
+        };
+        ChangeSummary {
+            origin: event.origin,
+            change,
+        }
+    }
+}
+
+impl<V: Hash> MemoryUsage for ORSet<V> {
+    fn memory_usage(&self) -> Usage {
+        let values = Usage::hash::<(V, ClockWrapper), ()>(self.values.len());
+        self.values.iter().map(|(_, clock)| clock.0.memory_usage()).sum::<Usage>() + values
+    }
 }
 
 #[cfg(test)]
@@ -83,7 +157,7 @@ mod test {
         memdb::InMemoryDb,
         orset::{Command, ORSet},
         protocol::Protocol,
-        replicate, ReplicaId, Replicator,
+        replicate, Change, ChangeSummary, Crdt as _, ReplicaId, Replicator,
     };
 
     #[tokio::test]
@@ -92,8 +166,12 @@ mod test {
 
         let alice_id = ReplicaId(0);
         let bob_id = ReplicaId(1);
-        let mut alice = Replicator::new(alice_id, Crdt::new(), InMemoryDb::<Crdt>::default()).await;
-        let mut bob = Replicator::new(bob_id, Crdt::new(), InMemoryDb::<Crdt>::default()).await;
+        let mut alice = Replicator::new(alice_id, Crdt::new(), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
+        let mut bob = Replicator::new(bob_id, Crdt::new(), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
 
         let _ = alice.send(Protocol::Command(Command::Add("nice"))).await;
         let _ = bob.send(Protocol::Command(Command::Add("nah"))).await;
@@ -108,14 +186,44 @@ mod test {
         assert_eq!(alice_value, bob_value)
     }
 
+    #[tokio::test]
+    async fn iter() {
+        type Crdt<'a> = ORSet<&'a str>;
+
+        let alice_id = ReplicaId(0);
+        let bob_id = ReplicaId(1);
+        let mut alice = Replicator::new(alice_id, Crdt::new(), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
+        let mut bob = Replicator::new(bob_id, Crdt::new(), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
+
+        // Both add "nice" concurrently, it's still there once
+        let _ = alice.send(Protocol::Command(Command::Add("nice"))).await;
+        let _ = bob.send(Protocol::Command(Command::Add("nice"))).await;
+        let _ = bob.send(Protocol::Command(Command::Add("nah"))).await;
+        replicate(&mut alice, &mut bob).await;
+
+        let mut values = alice.crdt().iter().copied().collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(values, vec!["nah", "nice"]);
+        assert!(alice.crdt().contains(&"nah"));
+        assert!(!alice.crdt().contains(&"meh"));
+    }
+
     #[tokio::test]
     async fn remove() {
         type Crdt<'a> = ORSet<&'a str>;
 
         let alice_id = ReplicaId(0);
         let bob_id = ReplicaId(1);
-        let mut alice = Replicator::new(alice_id, Crdt::new(), InMemoryDb::<Crdt>::default()).await;
-        let mut bob = Replicator::new(bob_id, Crdt::new(), InMemoryDb::<Crdt>::default()).await;
+        let mut alice = Replicator::new(alice_id, Crdt::new(), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
+        let mut bob = Replicator::new(bob_id, Crdt::new(), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
 
         let _ = alice.send(Protocol::Command(Command::Add("nice"))).await;
         let _ = bob.send(Protocol::Command(Command::Add("nah"))).await;
@@ -133,4 +241,44 @@ mod test {
         assert_eq!(alice_value, HashSet::from_iter(["nice"]));
         assert_eq!(alice_value, bob_value)
     }
+
+    #[tokio::test]
+    async fn describe() {
+        type Crdt<'a> = ORSet<&'a str>;
+
+        let alice_id = ReplicaId(0);
+        let mut alice = Replicator::new(alice_id, Crdt::new(), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
+
+        let _ = alice.send(Protocol::Command(Command::Add("nice"))).await;
+        let _ = alice.send(Protocol::Command(Command::Remove("nice"))).await;
+
+        // Described the way a replica taking them in order sees them
+        let replicated = alice.replay(alice_id, Default::default(), 1, 100).await;
+        let mut set = Crdt::new();
+        let mut summaries = vec![];
+        for event in replicated.events {
+            summaries.push(set.describe(&event));
+            set.effect(event);
+        }
+        assert_eq!(
+            summaries,
+            vec![
+                ChangeSummary {
+                    origin: alice_id,
+                    change: Change::Added {
+                        value: "\"nice\"".into()
+                    },
+                },
+                ChangeSummary {
+                    origin: alice_id,
+                    change: Change::Removed {
+                        value: "\"nice\"".into()
+                    },
+                },
+            ]
+        );
+        assert_eq!(summaries[1].to_string(), "0 removed \"nice\"");
+    }
 }
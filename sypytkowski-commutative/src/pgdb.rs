@@ -0,0 +1,434 @@
+//! A [`Store`] on PostgreSQL, see [`PgDb`], and replication between replicators sharing the
+//! database, see [`PgEventListener`].
+//!
+//! Every replica's log lives in the `events` table keyed by replica id and `local_seq`, its latest
+//! snapshot in a row of `snapshots` with a column per part of the [`ReplicationState`]. Events and
+//! the parts of snapshots are msgpack with named fields, replica ids decimal strings.
+//!
+//! Saving events notifies [`CHANNEL`] with the id of the replica that saved them, in the same
+//! transaction so listeners only hear of committed events. A [`PgEventListener`] pulls from the
+//! log of whichever replica that was into its own replicator the same way it would pull from a
+//! peer over the network, so processes sharing a database replicate without talking to each other.
+use std::cmp::Ordering;
+
+use async_trait::async_trait;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{postgres::PgListener, PgPool, Row};
+
+use crate::{
+    handle::ReplicatorWriter,
+    protocol::{Connect, Protocol, Replicate, Replicated},
+    trace, Crdt, Event, EventData, ReplicaId, ReplicationState, Store, StoreError, VTime,
+};
+
+/// The channel saved events are announced on
+pub const CHANNEL: &str = "replicated_events";
+
+/// How many events [`PgDb::load_events`](Store::load_events) asks for at a time
+const PAGE: i64 = 256;
+
+/// Keeps one replica's events and snapshot in PostgreSQL, see [`PgDb::migrate`] for the tables.
+///
+/// # Panics
+///
+/// [`Store`] has no way to report failed writes or unreadable snapshots, so saving panics when
+/// the database can't be reached rather than losing events, and so does loading a snapshot that
+/// doesn't decode. Failed reads of events end up in the stream of [`Store::load_events`].
+pub struct PgDb<C> {
+    pool: PgPool,
+    replica: ReplicaId,
+    crdt: std::marker::PhantomData<fn() -> C>,
+}
+
+impl<C> Clone for PgDb<C> {
+    fn clone(&self) -> Self {
+        Self::new(self.pool.clone(), self.replica)
+    }
+}
+
+impl<C> PgDb<C> {
+    /// The log and snapshot of `replica`, which has to be the id of the replicator using it
+    pub fn new(pool: PgPool, replica: ReplicaId) -> Self {
+        Self {
+            pool,
+            replica,
+            crdt: std::marker::PhantomData,
+        }
+    }
+
+    /// Creates the tables unless they are there already
+    pub async fn migrate(pool: &PgPool) -> sqlx::Result<()> {
+        sqlx::raw_sql(
+            "CREATE TABLE IF NOT EXISTS events (
+                replica_id TEXT NOT NULL,
+                local_seq BIGINT NOT NULL,
+                event BYTEA NOT NULL,
+                PRIMARY KEY (replica_id, local_seq)
+            );
+            CREATE TABLE IF NOT EXISTS snapshots (
+                replica_id TEXT PRIMARY KEY,
+                seq BIGINT NOT NULL,
+                version BYTEA NOT NULL,
+                observed BYTEA NOT NULL,
+                peers BYTEA NOT NULL,
+                crdt BYTEA NOT NULL
+            );
+            ALTER TABLE snapshots ADD COLUMN IF NOT EXISTS fingerprints BYTEA;",
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<C> Store<C> for PgDb<C>
+where
+    C: Crdt + Serialize + DeserializeOwned,
+    C::EData: Serialize + DeserializeOwned + 'static,
+{
+    async fn save_snapshot(&mut self, state: ReplicationState<C>) {
+        let saved = sqlx::query(
+            "INSERT INTO snapshots (replica_id, seq, version, observed, peers, crdt, fingerprints)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (replica_id) DO UPDATE SET
+                seq = EXCLUDED.seq,
+                version = EXCLUDED.version,
+                observed = EXCLUDED.observed,
+                peers = EXCLUDED.peers,
+                crdt = EXCLUDED.crdt,
+                fingerprints = EXCLUDED.fingerprints",
+        )
+        .bind(replica_key(self.replica))
+        .bind(state.seq as i64)
+        .bind(encode(&state.version))
+        .bind(encode(&state.observed))
+        .bind(encode(&state.peers))
+        .bind(encode(&state.crdt))
+        .bind(encode(&state.fingerprints))
+        .execute(&self.pool)
+        .await;
+        if let Err(err) = saved {
+            panic!("saving the snapshot of {:?} failed: {err}", self.replica);
+        }
+    }
+
+    async fn load_snapshot(&mut self) -> Option<ReplicationState<C>> {
+        let row = sqlx::query(
+            "SELECT seq, version, observed, peers, crdt, fingerprints FROM snapshots
+            WHERE replica_id = $1",
+        )
+        .bind(replica_key(self.replica))
+        .fetch_optional(&self.pool)
+        .await;
+        let row = match row {
+            Ok(row) => row?,
+            Err(err) => panic!("loading the snapshot of {:?} failed: {err}", self.replica),
+        };
+        let state = (|| {
+            Ok::<_, StoreError>(ReplicationState {
+                id: self.replica,
+                seq: row.try_get::<i64, _>("seq").map_err(corrupt)? as u64,
+                version: decode(row.try_get("version").map_err(corrupt)?)?,
+                observed: decode(row.try_get("observed").map_err(corrupt)?)?,
+                peers: decode(row.try_get("peers").map_err(corrupt)?)?,
+                crdt: decode(row.try_get("crdt").map_err(corrupt)?)?,
+                // Null in snapshots saved before there were fingerprints
+                fingerprints: match row.try_get("fingerprints").map_err(corrupt)? {
+                    Some(fingerprints) => decode(fingerprints)?,
+                    None => Default::default(),
+                },
+            })
+        })();
+        match state {
+            Ok(state) => Some(state),
+            Err(err) => panic!("snapshot of {:?} doesn't decode: {err}", self.replica),
+        }
+    }
+
+    /// Reads the log a page at a time as the events are polled
+    fn load_events(
+        &mut self,
+        start_seq: u64,
+    ) -> BoxStream<'_, Result<Event<C::EData>, StoreError>> {
+        load_events(&self.pool, self.replica, start_seq)
+    }
+
+    async fn save_events<I: Iterator<Item = Event<C::EData>> + Send>(&mut self, events: I) {
+        let events = events
+            .map(|event| (event.local_seq as i64, encode(&event)))
+            .collect::<Vec<_>>();
+        if events.is_empty() {
+            return;
+        }
+        if let Err(err) = self.insert(events).await {
+            panic!("saving events of {:?} failed: {err}", self.replica);
+        }
+    }
+}
+
+impl<C> PgDb<C> {
+    async fn insert(&self, events: Vec<(i64, Vec<u8>)>) -> sqlx::Result<()> {
+        let replica = replica_key(self.replica);
+        let mut tx = self.pool.begin().await?;
+        for (seq, event) in events {
+            sqlx::query("INSERT INTO events (replica_id, local_seq, event) VALUES ($1, $2, $3)")
+                .bind(&replica)
+                .bind(seq)
+                .bind(event)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CHANNEL)
+            .bind(&replica)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+}
+
+/// Pulls events other replicas save into the same database into a replicator.
+///
+/// Each [`CHANNEL`] notification about another replica makes the listener ask the replicator
+/// where it left off with that replica and answer the [`Replicate`]s it gets back from the
+/// replica's log until it has caught up. It catches up with every replica in the database when
+/// it starts, which also covers what was saved while it wasn't listening.
+pub struct PgEventListener<C: Crdt> {
+    pool: PgPool,
+    listener: PgListener,
+    id: ReplicaId,
+    writer: ReplicatorWriter<C>,
+}
+
+impl<C> PgEventListener<C>
+where
+    C: Crdt,
+    C::EData: DeserializeOwned + 'static,
+{
+    /// Listens for events for the replicator with id `id` behind `writer`
+    pub async fn new(
+        pool: PgPool,
+        id: ReplicaId,
+        writer: ReplicatorWriter<C>,
+    ) -> sqlx::Result<Self> {
+        let mut listener = PgListener::connect_with(&pool).await?;
+        listener.listen(CHANNEL).await?;
+        Ok(Self {
+            pool,
+            listener,
+            id,
+            writer,
+        })
+    }
+
+    /// Replicates until the connection to the database is lost for good or the replicator is
+    /// gone
+    pub async fn run(mut self) -> sqlx::Result<()> {
+        let replicas = sqlx::query("SELECT DISTINCT replica_id FROM events")
+            .fetch_all(&self.pool)
+            .await?;
+        for row in replicas {
+            if let Some(replica) = parse_replica(row.try_get("replica_id")?) {
+                if !self.pull(replica).await? {
+                    return Ok(());
+                }
+            }
+        }
+
+        loop {
+            let notification = self.listener.recv().await?;
+            let Some(replica) = parse_replica(notification.payload()) else {
+                trace::warning!(payload = notification.payload(), "ignoring notification");
+                continue;
+            };
+            if !self.pull(replica).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Catches up with `replica`, `false` once the replicator is gone
+    async fn pull(&self, replica: ReplicaId) -> sqlx::Result<bool> {
+        if replica == self.id {
+            return Ok(true);
+        }
+        let connect = Protocol::Connect(Connect {
+            replica_id: replica,
+        });
+        let Some(mut reply) = self.writer.send(connect).await else {
+            return Ok(false);
+        };
+        while let Protocol::Replicate(replicate) = reply {
+            let replicated = self.replicated(replica, replicate).await?;
+            let Some(next) = self.writer.send(Protocol::Replicated(replicated)).await else {
+                return Ok(false);
+            };
+            reply = next;
+        }
+        Ok(true)
+    }
+
+    /// What [`Replicator::replay`](crate::Replicator::replay) would answer on `replica`
+    async fn replicated(
+        &self,
+        replica: ReplicaId,
+        replicate: Replicate,
+    ) -> sqlx::Result<Replicated<C::EData>> {
+        let mut events = vec![];
+        let mut last_seq_nr = 0;
+        let mut log =
+            load_events(&self.pool, replica, replicate.seq_nr).take(replicate.max_count as usize);
+        while let Some(event) = log.next().await {
+            let event = event.map_err(|err| sqlx::Error::Decode(err.into()))?;
+            last_seq_nr = last_seq_nr.max(event.local_seq);
+            if is_new(&event.version, &replicate.filter) {
+                events.push(event);
+            }
+        }
+        Ok(Replicated {
+            from: replica,
+            to_seq_nr: last_seq_nr,
+            events,
+        })
+    }
+}
+
+fn is_new(version: &VTime, filter: &VTime) -> bool {
+    matches!(version.partial_cmp(filter), Some(Ordering::Greater) | None)
+}
+
+fn load_events<D: EventData + DeserializeOwned + 'static>(
+    pool: &PgPool,
+    replica: ReplicaId,
+    start_seq: u64,
+) -> BoxStream<'static, Result<Event<D>, StoreError>> {
+    let pool = pool.clone();
+    let replica = replica_key(replica);
+    stream::unfold(Some(start_seq as i64), move |next| {
+        let pool = pool.clone();
+        let replica = replica.clone();
+        async move {
+            let next = next?;
+            let rows = sqlx::query(
+                "SELECT local_seq, event FROM events
+                WHERE replica_id = $1 AND local_seq >= $2
+                ORDER BY local_seq LIMIT $3",
+            )
+            .bind(replica)
+            .bind(next)
+            .bind(PAGE)
+            .fetch_all(&pool)
+            .await;
+            let rows = match rows {
+                Ok(rows) => rows,
+                Err(err) => return Some((vec![Err(io_error(err))], None)),
+            };
+            let last = rows.last()?.try_get::<i64, _>("local_seq");
+            let after = match last {
+                Ok(seq) if rows.len() as i64 == PAGE => Some(seq + 1),
+                Ok(_) => None,
+                Err(err) => return Some((vec![Err(corrupt(err))], None)),
+            };
+            let events = rows
+                .iter()
+                .map(|row| decode(row.try_get("event").map_err(corrupt)?))
+                .collect::<Vec<_>>();
+            Some((events, after))
+        }
+    })
+    .flat_map(stream::iter)
+    .boxed()
+}
+
+fn replica_key(replica: ReplicaId) -> String {
+    replica.as_u128().to_string()
+}
+
+fn parse_replica(key: &str) -> Option<ReplicaId> {
+    key.parse().ok().map(ReplicaId::new)
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    rmp_serde::to_vec_named(value).expect("msgpack encoding doesn't fail for plain data")
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, StoreError> {
+    rmp_serde::from_slice(bytes).map_err(|err| StoreError::Corrupt(err.to_string()))
+}
+
+fn corrupt(err: sqlx::Error) -> StoreError {
+    StoreError::Corrupt(err.to_string())
+}
+
+fn io_error(err: sqlx::Error) -> StoreError {
+    StoreError::Io(std::io::Error::other(err))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use sqlx::PgPool;
+
+    use crate::{counter::Counter, protocol::Protocol, ReplicaId, Replicator};
+
+    use super::{PgDb, PgEventListener};
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database at DATABASE_URL"]
+    async fn replicates_through_the_database() {
+        let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
+            .await
+            .unwrap();
+        PgDb::<Counter>::migrate(&pool).await.unwrap();
+
+        let a = ReplicaId::random();
+        let b = ReplicaId::random();
+        let (_, writer_a) = Replicator::new(a, Counter::default(), PgDb::new(pool.clone(), a))
+            .await
+            .unwrap()
+            .spawn();
+        writer_a.send(Protocol::Command(1)).await.unwrap();
+
+        // `b` catches up with what `a` saved before it started and then follows along
+        let (mut handle_b, writer_b) =
+            Replicator::new(b, Counter::default(), PgDb::new(pool.clone(), b))
+                .await
+                .unwrap()
+                .spawn();
+        let listener = PgEventListener::new(pool.clone(), b, writer_b.clone())
+            .await
+            .unwrap();
+        tokio::spawn(listener.run());
+        for i in 2..=4 {
+            writer_a.send(Protocol::Command(i)).await.unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handle_b.query() != 10 {
+                assert!(handle_b.changed().await);
+            }
+        })
+        .await
+        .unwrap();
+
+        // Restarting `b` off its own log, which now holds `a`'s events too
+        let b_again = Replicator::new(b, Counter::default(), PgDb::new(pool.clone(), b))
+            .await
+            .unwrap();
+        assert_eq!(b_again.query(), 10);
+
+        for table in ["events", "snapshots"] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE replica_id = ANY($1)"))
+                .bind([super::replica_key(a), super::replica_key(b)])
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+    }
+}
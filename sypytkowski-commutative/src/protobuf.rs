@@ -0,0 +1,373 @@
+//! Protobuf messages for the replication protocol, so services that aren't written in Rust can
+//! replicate with a [`Replicator`](crate::Replicator).
+//!
+//! The schema is `proto/replication.proto`. The types in [`messages`] are what `prost-build`
+//! generates for it, they are kept in the crate so building doesn't need `protoc` and have to be
+//! updated along with the schema.
+//!
+//! Commands and event data travel as the bytes of the CRDT's own protobuf messages, so they have to
+//! implement [`prost::Message`] (which the primitive types like `i64` already do).
+use std::fmt;
+
+use prost::Message;
+
+use crate::{
+    protocol::{Connect, Protocol, Replicate, Replicated},
+    Event, EventData, ReplicaId, VTime,
+};
+
+pub mod messages {
+    #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+    pub struct ReplicaId {
+        #[prost(fixed64, tag = "1")]
+        pub hi: u64,
+        #[prost(fixed64, tag = "2")]
+        pub lo: u64,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct VTimeEntry {
+        #[prost(message, optional, tag = "1")]
+        pub replica: Option<ReplicaId>,
+        #[prost(uint64, tag = "2")]
+        pub seq: u64,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct VTime {
+        #[prost(message, repeated, tag = "1")]
+        pub entries: Vec<VTimeEntry>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Event {
+        #[prost(message, optional, tag = "1")]
+        pub origin: Option<ReplicaId>,
+        #[prost(uint64, tag = "2")]
+        pub origin_seq: u64,
+        #[prost(uint64, tag = "3")]
+        pub local_seq: u64,
+        #[prost(message, optional, tag = "4")]
+        pub version: Option<VTime>,
+        #[prost(bytes = "vec", tag = "5")]
+        pub data: Vec<u8>,
+        #[prost(bytes = "vec", tag = "6")]
+        pub signature: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Connect {
+        #[prost(message, optional, tag = "1")]
+        pub replica_id: Option<ReplicaId>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Replicate {
+        #[prost(uint64, tag = "1")]
+        pub seq_nr: u64,
+        #[prost(uint64, tag = "2")]
+        pub max_count: u64,
+        #[prost(message, optional, tag = "3")]
+        pub filter: Option<VTime>,
+        #[prost(message, optional, tag = "4")]
+        pub reply_to: Option<ReplicaId>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Replicated {
+        #[prost(message, optional, tag = "1")]
+        pub from: Option<ReplicaId>,
+        #[prost(uint64, tag = "2")]
+        pub to_seq_nr: u64,
+        #[prost(message, repeated, tag = "3")]
+        pub events: Vec<Event>,
+    }
+
+    #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+    pub struct Noop {}
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Protocol {
+        #[prost(oneof = "protocol::Msg", tags = "1, 2, 3, 4, 5")]
+        pub msg: Option<protocol::Msg>,
+    }
+
+    pub mod protocol {
+        #[derive(Clone, PartialEq, ::prost::Oneof)]
+        pub enum Msg {
+            #[prost(bytes, tag = "1")]
+            Command(Vec<u8>),
+            #[prost(message, tag = "2")]
+            Connect(super::Connect),
+            #[prost(message, tag = "3")]
+            Replicate(super::Replicate),
+            #[prost(message, tag = "4")]
+            Replicated(super::Replicated),
+            #[prost(message, tag = "5")]
+            Noop(super::Noop),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtoError {
+    Decode(prost::DecodeError),
+    /// A field the protocol can't do without wasn't set
+    Missing(&'static str),
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtoError::Decode(err) => write!(f, "invalid protobuf: {err}"),
+            ProtoError::Missing(field) => write!(f, "missing field `{field}`"),
+        }
+    }
+}
+
+impl std::error::Error for ProtoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtoError::Decode(err) => Some(err),
+            ProtoError::Missing(_) => None,
+        }
+    }
+}
+
+impl From<prost::DecodeError> for ProtoError {
+    fn from(err: prost::DecodeError) -> Self {
+        ProtoError::Decode(err)
+    }
+}
+
+/// Encodes `msg` as a [`messages::Protocol`]
+pub fn encode<Cmd, EData>(msg: &Protocol<Cmd, EData>) -> Vec<u8>
+where
+    Cmd: fmt::Debug + Message,
+    EData: EventData + Message,
+{
+    messages::Protocol::from(msg).encode_to_vec()
+}
+
+/// Decodes what [`encode`] (or any other protobuf implementation) wrote
+pub fn decode<Cmd, EData>(bytes: &[u8]) -> Result<Protocol<Cmd, EData>, ProtoError>
+where
+    Cmd: fmt::Debug + Message + Default,
+    EData: EventData + Message + Default,
+{
+    messages::Protocol::decode(bytes)?.try_into()
+}
+
+impl From<ReplicaId> for messages::ReplicaId {
+    fn from(ReplicaId(id): ReplicaId) -> Self {
+        Self {
+            hi: (id >> 64) as u64,
+            lo: id as u64,
+        }
+    }
+}
+
+impl From<messages::ReplicaId> for ReplicaId {
+    fn from(id: messages::ReplicaId) -> Self {
+        ReplicaId((id.hi as u128) << 64 | id.lo as u128)
+    }
+}
+
+fn replica_id(
+    id: Option<messages::ReplicaId>,
+    field: &'static str,
+) -> Result<ReplicaId, ProtoError> {
+    id.map(ReplicaId::from).ok_or(ProtoError::Missing(field))
+}
+
+impl From<&VTime> for messages::VTime {
+    fn from(time: &VTime) -> Self {
+        Self {
+            entries: time
+                .iter()
+                .map(|(&replica, &seq)| messages::VTimeEntry {
+                    replica: Some(replica.into()),
+                    seq,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<messages::VTime> for VTime {
+    type Error = ProtoError;
+
+    fn try_from(time: messages::VTime) -> Result<Self, Self::Error> {
+        let mut map = std::collections::BTreeMap::new();
+        for entry in time.entries {
+            let replica = replica_id(entry.replica, "VTimeEntry.replica")?;
+            let seq = map.entry(replica).or_insert(entry.seq);
+            *seq = entry.seq.max(*seq);
+        }
+        Ok(VTime { map })
+    }
+}
+
+impl<D: EventData + Message> From<&Event<D>> for messages::Event {
+    fn from(event: &Event<D>) -> Self {
+        Self {
+            origin: Some(event.origin.into()),
+            origin_seq: event.origin_seq,
+            local_seq: event.local_seq,
+            version: Some((&event.version).into()),
+            data: event.data.encode_to_vec(),
+            signature: event.signature.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl<D: EventData + Message + Default> TryFrom<messages::Event> for Event<D> {
+    type Error = ProtoError;
+
+    fn try_from(event: messages::Event) -> Result<Self, Self::Error> {
+        Ok(Event {
+            origin: replica_id(event.origin, "Event.origin")?,
+            origin_seq: event.origin_seq,
+            local_seq: event.local_seq,
+            version: event.version.unwrap_or_default().try_into()?,
+            data: D::decode(event.data.as_slice())?,
+            signature: (!event.signature.is_empty()).then_some(event.signature),
+        })
+    }
+}
+
+impl<Cmd, EData> From<&Protocol<Cmd, EData>> for messages::Protocol
+where
+    Cmd: fmt::Debug + Message,
+    EData: EventData + Message,
+{
+    fn from(msg: &Protocol<Cmd, EData>) -> Self {
+        use messages::protocol::Msg;
+
+        let msg = match msg {
+            Protocol::Command(cmd) => Msg::Command(cmd.encode_to_vec()),
+            Protocol::Connect(Connect { replica_id }) => Msg::Connect(messages::Connect {
+                replica_id: Some((*replica_id).into()),
+            }),
+            Protocol::Replicate(replicate) => Msg::Replicate(messages::Replicate {
+                seq_nr: replicate.seq_nr,
+                max_count: replicate.max_count,
+                filter: Some((&replicate.filter).into()),
+                reply_to: Some(replicate.reply_to.into()),
+            }),
+            Protocol::Replicated(replicated) => Msg::Replicated(messages::Replicated {
+                from: Some(replicated.from.into()),
+                to_seq_nr: replicated.to_seq_nr,
+                events: replicated.events.iter().map(Into::into).collect(),
+            }),
+            Protocol::Noop => Msg::Noop(messages::Noop {}),
+        };
+        Self { msg: Some(msg) }
+    }
+}
+
+impl<Cmd, EData> TryFrom<messages::Protocol> for Protocol<Cmd, EData>
+where
+    Cmd: fmt::Debug + Message + Default,
+    EData: EventData + Message + Default,
+{
+    type Error = ProtoError;
+
+    fn try_from(msg: messages::Protocol) -> Result<Self, Self::Error> {
+        use messages::protocol::Msg;
+
+        Ok(match msg.msg.ok_or(ProtoError::Missing("Protocol.msg"))? {
+            Msg::Command(cmd) => Protocol::Command(Cmd::decode(cmd.as_slice())?),
+            Msg::Connect(connect) => Protocol::Connect(Connect {
+                replica_id: replica_id(connect.replica_id, "Connect.replica_id")?,
+            }),
+            Msg::Replicate(replicate) => Protocol::Replicate(Replicate {
+                seq_nr: replicate.seq_nr,
+                max_count: replicate.max_count,
+                filter: replicate.filter.unwrap_or_default().try_into()?,
+                reply_to: replica_id(replicate.reply_to, "Replicate.reply_to")?,
+            }),
+            Msg::Replicated(replicated) => Protocol::Replicated(Replicated {
+                from: replica_id(replicated.from, "Replicated.from")?,
+                to_seq_nr: replicated.to_seq_nr,
+                events: replicated
+                    .events
+                    .into_iter()
+                    .map(Event::try_from)
+                    .collect::<Result<_, _>>()?,
+            }),
+            Msg::Noop(_) => Protocol::Noop,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prost::Message;
+
+    use crate::{
+        counter::Counter,
+        memdb::InMemoryDb,
+        protocol::{Connect, Protocol},
+        ReplicaId, Replicator,
+    };
+
+    use super::{decode, encode, messages, ProtoError};
+
+    /// Alice and Bob only ever see each other's messages as protobuf bytes
+    #[tokio::test]
+    async fn replicate_over_protobuf() {
+        let alice_id = ReplicaId(1 << 100 | 1);
+        let bob_id = ReplicaId(2);
+        let mut alice = Replicator::new(alice_id, Counter::default(), InMemoryDb::default())
+            .await
+            .unwrap();
+        let mut bob = Replicator::new(bob_id, Counter::default(), InMemoryDb::default())
+            .await
+            .unwrap();
+
+        for n in [5, -2] {
+            let cmd = encode::<i64, i64>(&Protocol::Command(n));
+            alice.send(decode(&cmd).unwrap()).await;
+        }
+        let _ = bob.send(Protocol::Command(10)).await;
+
+        let connect = Protocol::Connect(Connect {
+            replica_id: alice_id,
+        });
+        let mut msg = bob.send(connect).await;
+        while !matches!(msg, Protocol::Noop) {
+            let reply = alice.send(decode(&encode(&msg)).unwrap()).await;
+            msg = bob.send(decode(&encode(&reply)).unwrap()).await;
+        }
+
+        assert_eq!(bob.query(), 13);
+    }
+
+    #[test]
+    fn replica_ids_keep_all_bits() {
+        let id = ReplicaId(u128::MAX - 7);
+        assert_eq!(ReplicaId::from(messages::ReplicaId::from(id)), id);
+    }
+
+    #[test]
+    fn missing_fields() {
+        let msg = messages::Protocol {
+            msg: Some(messages::protocol::Msg::Connect(messages::Connect {
+                replica_id: None,
+            })),
+        };
+        assert!(matches!(
+            decode::<i64, i64>(&msg.encode_to_vec()),
+            Err(ProtoError::Missing("Connect.replica_id"))
+        ));
+        assert!(matches!(
+            decode::<i64, i64>(&[]),
+            Err(ProtoError::Missing("Protocol.msg"))
+        ));
+        assert!(matches!(
+            decode::<i64, i64>(&[0xff]),
+            Err(ProtoError::Decode(_))
+        ));
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{Event, EventData, ReplicaId, VTime};
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Protocol<Cmd: std::fmt::Debug, EData: EventData> {
     // Query,
     // QueryResponse(State),
@@ -12,11 +13,13 @@ pub enum Protocol<Cmd: std::fmt::Debug, EData: EventData> {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Connect {
     pub replica_id: ReplicaId,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Replicate {
     pub seq_nr: u64,
     pub max_count: u64,
@@ -24,7 +27,8 @@ pub struct Replicate {
     pub reply_to: ReplicaId,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Replicated<D: EventData> {
     pub from: ReplicaId,
     pub to_seq_nr: u64,
@@ -0,0 +1,401 @@
+//! Replication between replicas over QUIC, see [`QuicReplicator`].
+//!
+//! Every pair of connected replicas multiplexes the protocol over streams of one connection:
+//!
+//! - each side opens a unidirectional stream it sends a `Connect` with its id down whenever it has
+//!   new events, starting with one right away
+//! - each side opens a bidirectional stream for pulling, on every `Connect` it gets it sends
+//!   `Replicate`s down its stream and hands the `Replicated`s it gets back to its replicator until
+//!   it's caught up
+//!
+//! Messages are the protobuf messages of [`crate::protobuf`], each prefixed with its length as a
+//! big endian `u32`.
+//!
+//! QUIC connections are identified by their id rather than by addresses, so when a replica's
+//! network changes it only has to [`QuicReplicator::rebind`] its socket and its connections carry
+//! on, the peers pick up the new address with the next packet. Connections that are lost anyway
+//! are dialed again by the side that dialed them.
+use std::{fmt, io, net::SocketAddr, time::Duration};
+
+use prost::Message;
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    handle::{ReplicatorHandle, ReplicatorWriter},
+    protobuf::{self, ProtoError},
+    protocol::{Connect, Protocol},
+    Crdt, ReplicaId, Replicator, Store,
+};
+
+/// Largest message accepted from a peer
+const MAX_FRAME_LEN: u32 = 16 << 20;
+
+/// How long to wait before dialing a lost connection again
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+type Msg<C> = Protocol<<C as Crdt>::Cmd, <C as Crdt>::EData>;
+
+#[derive(Debug)]
+pub enum QuicError {
+    Connect(quinn::ConnectError),
+    Connection(quinn::ConnectionError),
+    Io(io::Error),
+    Decode(ProtoError),
+    /// A peer announced a message larger than [`MAX_FRAME_LEN`]
+    FrameTooLarge(u32),
+    /// The replicator task is gone
+    Stopped,
+}
+
+impl fmt::Display for QuicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuicError::Connect(err) => write!(f, "failed to connect: {err}"),
+            QuicError::Connection(err) => write!(f, "connection failed: {err}"),
+            QuicError::Io(err) => write!(f, "stream failed: {err}"),
+            QuicError::Decode(err) => write!(f, "invalid message: {err}"),
+            QuicError::FrameTooLarge(len) => write!(f, "message of {len} bytes is too large"),
+            QuicError::Stopped => write!(f, "the replicator stopped"),
+        }
+    }
+}
+
+impl std::error::Error for QuicError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            QuicError::Connect(err) => Some(err),
+            QuicError::Connection(err) => Some(err),
+            QuicError::Io(err) => Some(err),
+            QuicError::Decode(err) => Some(err),
+            QuicError::FrameTooLarge(_) | QuicError::Stopped => None,
+        }
+    }
+}
+
+impl From<quinn::ConnectError> for QuicError {
+    fn from(err: quinn::ConnectError) -> Self {
+        QuicError::Connect(err)
+    }
+}
+
+impl From<quinn::ConnectionError> for QuicError {
+    fn from(err: quinn::ConnectionError) -> Self {
+        QuicError::Connection(err)
+    }
+}
+
+impl From<io::Error> for QuicError {
+    fn from(err: io::Error) -> Self {
+        QuicError::Io(err)
+    }
+}
+
+impl From<quinn::WriteError> for QuicError {
+    fn from(err: quinn::WriteError) -> Self {
+        QuicError::Io(err.into())
+    }
+}
+
+impl From<ProtoError> for QuicError {
+    fn from(err: ProtoError) -> Self {
+        QuicError::Decode(err)
+    }
+}
+
+/// A [`Replicator`] running in its own task that replicates with the peers it is connected to
+/// over QUIC. Connections the endpoint accepts are served as long as the endpoint is open, which it
+/// is until this is dropped.
+pub struct QuicReplicator<C: Crdt> {
+    id: ReplicaId,
+    endpoint: Endpoint,
+    handle: ReplicatorHandle<C>,
+    writer: ReplicatorWriter<C>,
+}
+
+impl<C> QuicReplicator<C>
+where
+    C: Crdt + 'static,
+    C::Cmd: Message + Default + Send,
+    C::EData: Message + Default,
+{
+    /// Spawns `replicator` and starts accepting connections on `endpoint`. Replicas that dial
+    /// others need a default client config on their endpoint.
+    pub fn new<Db: Store<C> + Send + 'static>(
+        replicator: Replicator<C, Db>,
+        endpoint: Endpoint,
+    ) -> Self {
+        let id = replicator.id();
+        let (handle, writer) = replicator.spawn();
+        let this = Self {
+            id,
+            endpoint,
+            handle,
+            writer,
+        };
+
+        let (endpoint, peer) = (this.endpoint.clone(), this.peer());
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let peer = peer.clone();
+                tokio::spawn(async move {
+                    if let Ok(conn) = incoming.await {
+                        let _ = peer.run(conn).await;
+                    }
+                });
+            }
+        });
+        this
+    }
+
+    /// Connects to the replica at `addr` and keeps replicating with it, dialing it again whenever
+    /// the connection is lost until the endpoint is closed
+    pub async fn connect(&self, addr: SocketAddr, server_name: &str) -> Result<(), QuicError> {
+        let conn = self.endpoint.connect(addr, server_name)?.await?;
+
+        let (endpoint, peer, server_name) =
+            (self.endpoint.clone(), self.peer(), server_name.to_owned());
+        tokio::spawn(async move {
+            let mut conn = conn;
+            loop {
+                let _ = peer.clone().run(conn).await;
+                conn = loop {
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    match endpoint.connect(addr, &server_name) {
+                        // The endpoint was closed
+                        Err(_) => return,
+                        Ok(connecting) => match connecting.await {
+                            Ok(conn) => break conn,
+                            Err(_) => continue,
+                        },
+                    }
+                };
+            }
+        });
+        Ok(())
+    }
+
+    /// Moves the endpoint over to `socket`, for when the network changed. Connections migrate to
+    /// the new address instead of being dropped.
+    pub fn rebind(&self, socket: std::net::UdpSocket) -> io::Result<()> {
+        self.endpoint.rebind(socket)
+    }
+
+    pub fn id(&self) -> ReplicaId {
+        self.id
+    }
+
+    pub fn handle(&self) -> ReplicatorHandle<C> {
+        self.handle.clone()
+    }
+
+    pub fn writer(&self) -> ReplicatorWriter<C> {
+        self.writer.clone()
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    fn peer(&self) -> Peer<C> {
+        Peer {
+            id: self.id,
+            handle: self.handle.clone(),
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+impl<C: Crdt> Drop for QuicReplicator<C> {
+    fn drop(&mut self) {
+        self.endpoint.close(0u32.into(), b"");
+    }
+}
+
+/// What a connection needs of the replicator
+struct Peer<C: Crdt> {
+    id: ReplicaId,
+    handle: ReplicatorHandle<C>,
+    writer: ReplicatorWriter<C>,
+}
+
+impl<C: Crdt> Clone for Peer<C> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            handle: self.handle.clone(),
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+impl<C> Peer<C>
+where
+    C: Crdt,
+    C::Cmd: Message + Default,
+    C::EData: Message + Default,
+{
+    /// Replicates both ways over `conn` until it fails
+    async fn run(self, conn: Connection) -> Result<(), QuicError> {
+        let Peer { id, handle, writer } = self;
+        tokio::try_join!(
+            Self::announce(id, handle, &conn),
+            Self::pull(&writer, &conn),
+            Self::serve(&writer, &conn),
+        )?;
+        Ok(())
+    }
+
+    /// Tells the peer to pull whenever there are new events
+    async fn announce(
+        id: ReplicaId,
+        mut handle: ReplicatorHandle<C>,
+        conn: &Connection,
+    ) -> Result<(), QuicError> {
+        let mut stream = conn.open_uni().await?;
+        let connect = Protocol::Connect(Connect { replica_id: id });
+        loop {
+            write_frame::<C>(&mut stream, &connect).await?;
+            if !handle.changed().await {
+                return Err(QuicError::Stopped);
+            }
+        }
+    }
+
+    /// Pulls from the peer whenever it tells us to
+    async fn pull(writer: &ReplicatorWriter<C>, conn: &Connection) -> Result<(), QuicError> {
+        let mut announcements = conn.accept_uni().await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        loop {
+            let mut msg = read_frame::<C>(&mut announcements).await?;
+            loop {
+                match writer.send(msg).await.ok_or(QuicError::Stopped)? {
+                    Protocol::Noop => break,
+                    req => write_frame::<C>(&mut send, &req).await?,
+                }
+                msg = read_frame::<C>(&mut recv).await?;
+            }
+        }
+    }
+
+    /// Answers the peer's pulls
+    async fn serve(writer: &ReplicatorWriter<C>, conn: &Connection) -> Result<(), QuicError> {
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        loop {
+            let req = read_frame::<C>(&mut recv).await?;
+            let reply = writer.send(req).await.ok_or(QuicError::Stopped)?;
+            write_frame::<C>(&mut send, &reply).await?;
+        }
+    }
+}
+
+async fn write_frame<C>(stream: &mut SendStream, msg: &Msg<C>) -> Result<(), QuicError>
+where
+    C: Crdt,
+    C::Cmd: Message,
+    C::EData: Message,
+{
+    let bytes = protobuf::encode(msg);
+    stream
+        .write_all(&(bytes.len() as u32).to_be_bytes())
+        .await?;
+    stream.write_all(&bytes).await?;
+    Ok(())
+}
+
+async fn read_frame<C>(stream: &mut RecvStream) -> Result<Msg<C>, QuicError>
+where
+    C: Crdt,
+    C::Cmd: Message + Default,
+    C::EData: Message + Default,
+{
+    let len = stream.read_u32().await?;
+    if len > MAX_FRAME_LEN {
+        return Err(QuicError::FrameTooLarge(len));
+    }
+    let mut bytes = vec![0; len as usize];
+    AsyncReadExt::read_exact(stream, &mut bytes).await?;
+    Ok(protobuf::decode(&bytes)?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use quinn::{
+        rustls::{
+            pki_types::{CertificateDer, PrivatePkcs8KeyDer},
+            RootCertStore,
+        },
+        ClientConfig, Endpoint, ServerConfig,
+    };
+
+    use crate::{
+        counter::Counter, handle::ReplicatorHandle, memdb::InMemoryDb, protocol::Protocol,
+        ReplicaId, Replicator,
+    };
+
+    use super::QuicReplicator;
+
+    /// Endpoints that accept connections for `localhost` and trust each other
+    fn endpoints() -> (Endpoint, Endpoint) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let der = CertificateDer::from(cert.cert);
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let mut roots = RootCertStore::empty();
+        roots.add(der.clone()).unwrap();
+        let client = ClientConfig::with_root_certificates(Arc::new(roots)).unwrap();
+        let server = ServerConfig::with_single_cert(vec![der], key.into()).unwrap();
+
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut a = Endpoint::server(server.clone(), localhost).unwrap();
+        let mut b = Endpoint::server(server, localhost).unwrap();
+        a.set_default_client_config(client.clone());
+        b.set_default_client_config(client);
+        (a, b)
+    }
+
+    async fn replica(id: u128, endpoint: Endpoint) -> QuicReplicator<Counter> {
+        let id = ReplicaId::new(id);
+        let replicator = Replicator::new(id, Counter::default(), InMemoryDb::default())
+            .await
+            .unwrap();
+        QuicReplicator::new(replicator, endpoint)
+    }
+
+    async fn until(mut handle: ReplicatorHandle<Counter>, expected: i64) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handle.query() != expected {
+                assert!(handle.changed().await);
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("expected {} but got {}", expected, handle.query()));
+    }
+
+    #[tokio::test]
+    async fn replicates_both_ways() {
+        let (a, b) = endpoints();
+        let alice = replica(1, a).await;
+        let bob = replica(2, b).await;
+
+        alice.writer().send(Protocol::Command(5)).await.unwrap();
+        let addr = alice.endpoint().local_addr().unwrap();
+        bob.connect(addr, "localhost").await.unwrap();
+        until(bob.handle(), 5).await;
+
+        bob.writer().send(Protocol::Command(2)).await.unwrap();
+        until(alice.handle(), 7).await;
+
+        // Bob's network changes, the connection moves over to the new socket
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let before = bob.endpoint().local_addr().unwrap();
+        bob.rebind(socket).unwrap();
+        assert_ne!(bob.endpoint().local_addr().unwrap(), before);
+
+        alice.writer().send(Protocol::Command(-1)).await.unwrap();
+        until(bob.handle(), 6).await;
+        bob.writer().send(Protocol::Command(10)).await.unwrap();
+        until(alice.handle(), 16).await;
+    }
+}
@@ -0,0 +1,297 @@
+//! A [`Store`] on Redis, see [`RedisDb`].
+//!
+//! Replicators sharing a Redis share a durable log: events go into the stream `<prefix>:events`
+//! with entry ids `<local_seq>-0` so they can be read back from any sequence number, the latest
+//! snapshot into the hash `<prefix>:snapshot` with a field per part of the
+//! [`ReplicationState`]. Both hold msgpack with named fields.
+//!
+//! A [`RedisDb`] made with [`RedisDb::publish_to`] also publishes every event it saves on a pub/sub
+//! channel in the same transaction, so other services can follow along with [`subscribe`] without
+//! polling the stream.
+use std::{collections::HashMap, io, marker::PhantomData};
+
+use async_trait::async_trait;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use redis::{
+    aio::MultiplexedConnection, streams::StreamRangeReply, AsyncCommands, Client, RedisError,
+    RedisResult,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Crdt, Event, EventData, ReplicationState, Store, StoreError};
+
+/// How many events [`RedisDb::load_events`](Store::load_events) asks for at a time
+const PAGE: usize = 256;
+
+/// Keeps a replica's events and snapshot in Redis under a key prefix.
+///
+/// # Panics
+///
+/// [`Store`] has no way to report failed writes or unreadable snapshots, so saving panics when
+/// Redis can't be reached rather than losing events, and so does loading a snapshot that doesn't
+/// decode. Failed reads of events end up in the stream of [`Store::load_events`].
+pub struct RedisDb<C> {
+    conn: MultiplexedConnection,
+    prefix: String,
+    channel: Option<String>,
+    crdt: PhantomData<fn() -> C>,
+}
+
+impl<C> Clone for RedisDb<C> {
+    fn clone(&self) -> Self {
+        Self {
+            conn: self.conn.clone(),
+            prefix: self.prefix.clone(),
+            channel: self.channel.clone(),
+            crdt: PhantomData,
+        }
+    }
+}
+
+impl<C> RedisDb<C> {
+    pub fn new(conn: MultiplexedConnection, prefix: impl Into<String>) -> Self {
+        Self {
+            conn,
+            prefix: prefix.into(),
+            channel: None,
+            crdt: PhantomData,
+        }
+    }
+
+    pub async fn connect(client: &Client, prefix: impl Into<String>) -> RedisResult<Self> {
+        let conn = client.get_multiplexed_async_connection().await?;
+        Ok(Self::new(conn, prefix))
+    }
+
+    /// Publishes every saved event on `channel` as well, see [`subscribe`]
+    pub fn publish_to(mut self, channel: impl Into<String>) -> Self {
+        self.channel = Some(channel.into());
+        self
+    }
+
+    pub fn events_key(&self) -> String {
+        format!("{}:events", self.prefix)
+    }
+
+    pub fn snapshot_key(&self) -> String {
+        format!("{}:snapshot", self.prefix)
+    }
+}
+
+#[async_trait]
+impl<C> Store<C> for RedisDb<C>
+where
+    C: Crdt + Serialize + DeserializeOwned,
+    C::EData: Serialize + DeserializeOwned,
+{
+    async fn save_snapshot(&mut self, state: ReplicationState<C>) {
+        let key = self.snapshot_key();
+        let fields = [
+            ("id", encode(&state.id)),
+            ("seq", encode(&state.seq)),
+            ("version", encode(&state.version)),
+            ("observed", encode(&state.observed)),
+            ("peers", encode(&state.peers)),
+            ("crdt", encode(&state.crdt)),
+            ("fingerprints", encode(&state.fingerprints)),
+        ];
+        let saved: RedisResult<()> = self.conn.hset_multiple(&key, &fields).await;
+        if let Err(err) = saved {
+            panic!("saving the snapshot to {key} failed: {err}");
+        }
+    }
+
+    async fn load_snapshot(&mut self) -> Option<ReplicationState<C>> {
+        let key = self.snapshot_key();
+        let fields: HashMap<String, Vec<u8>> = match self.conn.hgetall(&key).await {
+            Ok(fields) => fields,
+            Err(err) => panic!("loading the snapshot from {key} failed: {err}"),
+        };
+        if fields.is_empty() {
+            return None;
+        }
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .map(Vec::as_slice)
+                .unwrap_or_else(|| panic!("snapshot in {key} has no {name}"))
+        };
+        let state = (|| {
+            Ok::<_, StoreError>(ReplicationState {
+                id: decode(field("id"))?,
+                seq: decode(field("seq"))?,
+                version: decode(field("version"))?,
+                observed: decode(field("observed"))?,
+                peers: decode(field("peers"))?,
+                crdt: decode(field("crdt"))?,
+                // Not in snapshots saved before there were fingerprints
+                fingerprints: match fields.get("fingerprints") {
+                    Some(fingerprints) => decode(fingerprints)?,
+                    None => Default::default(),
+                },
+            })
+        })();
+        match state {
+            Ok(state) => Some(state),
+            Err(err) => panic!("snapshot in {key} doesn't decode: {err}"),
+        }
+    }
+
+    /// Reads the stream a page at a time as the events are polled
+    fn load_events(
+        &mut self,
+        start_seq: u64,
+    ) -> BoxStream<'_, Result<Event<C::EData>, StoreError>> {
+        let key = self.events_key();
+        let conn = self.conn.clone();
+        stream::unfold(Some(start_seq), move |next| {
+            let key = key.clone();
+            let mut conn = conn.clone();
+            async move {
+                let next = next?;
+                let page: StreamRangeReply = match conn
+                    .xrange_count(&key, format!("{next}-0"), "+", PAGE)
+                    .await
+                {
+                    Ok(page) => page,
+                    Err(err) => return Some((vec![Err(io_error(err))], None)),
+                };
+                let last = page.ids.last()?.id.clone();
+                let after = match entry_seq(&last) {
+                    Some(seq) if page.ids.len() == PAGE => Some(seq + 1),
+                    Some(_) => None,
+                    None => {
+                        let err = StoreError::Corrupt(format!("stream entry id {last}"));
+                        return Some((vec![Err(err)], None));
+                    }
+                };
+                let events = page
+                    .ids
+                    .iter()
+                    .map(|entry| {
+                        let bytes: Vec<u8> = entry.get("event").ok_or_else(|| {
+                            StoreError::Corrupt(format!("stream entry {} has no event", entry.id))
+                        })?;
+                        decode(&bytes)
+                    })
+                    .collect::<Vec<_>>();
+                Some((events, after))
+            }
+        })
+        .flat_map(stream::iter)
+        .boxed()
+    }
+
+    async fn save_events<I: Iterator<Item = Event<C::EData>> + Send>(&mut self, events: I) {
+        let key = self.events_key();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for event in events {
+            let bytes = encode(&event);
+            pipe.xadd(&key, format!("{}-0", event.local_seq), &[("event", &bytes)])
+                .ignore();
+            if let Some(channel) = &self.channel {
+                pipe.publish(channel, &bytes).ignore();
+            }
+        }
+        let saved: RedisResult<()> = pipe.query_async(&mut self.conn).await;
+        if let Err(err) = saved {
+            panic!("saving events to {key} failed: {err}");
+        }
+    }
+}
+
+/// Events published on `channel` by [`RedisDb`]s from now on
+pub async fn subscribe<D>(
+    client: &Client,
+    channel: &str,
+) -> RedisResult<BoxStream<'static, Result<Event<D>, StoreError>>>
+where
+    D: EventData + DeserializeOwned + 'static,
+{
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    Ok(pubsub
+        .into_on_message()
+        .map(|msg| decode(msg.get_payload_bytes()))
+        .boxed())
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    rmp_serde::to_vec_named(value).expect("msgpack encoding doesn't fail for plain data")
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, StoreError> {
+    rmp_serde::from_slice(bytes).map_err(|err| StoreError::Corrupt(err.to_string()))
+}
+
+fn io_error(err: RedisError) -> StoreError {
+    StoreError::Io(io::Error::other(err))
+}
+
+/// The `local_seq` of the stream entry `<local_seq>-0`
+fn entry_seq(id: &str) -> Option<u64> {
+    id.split_once('-')?.0.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+    use redis::{AsyncCommands, Client};
+
+    use crate::{counter::Counter, protocol::Protocol, ReplicaId, Replicator, Store};
+
+    use super::{subscribe, RedisDb};
+
+    fn client() -> Client {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".into());
+        Client::open(url).unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server, set REDIS_URL if it isn't on localhost"]
+    async fn shares_the_log() {
+        let client = client();
+        let prefix = format!("replica-{}", uuid::Uuid::new_v4());
+        let channel = format!("{prefix}:published");
+        let db = RedisDb::<Counter>::connect(&client, prefix.clone())
+            .await
+            .unwrap()
+            .publish_to(channel.clone());
+        let mut published = subscribe::<i64>(&client, &channel).await.unwrap();
+
+        let mut replica = Replicator::new(ReplicaId::new(1), Counter::default(), db.clone())
+            .await
+            .unwrap();
+        for i in 1..=3 {
+            replica.send(Protocol::Command(i)).await;
+        }
+        replica.compact().await;
+        for i in 4..=300 {
+            replica.send(Protocol::Command(i)).await;
+        }
+
+        for i in 1..=300 {
+            assert_eq!(published.next().await.unwrap().unwrap().data, i);
+        }
+
+        // Another process picks up where this one left off, past the snapshot and over more than
+        // a page of events
+        let mut other = db.clone();
+        assert_eq!(other.load_snapshot().await.unwrap().seq, 3);
+        assert_eq!(other.load_events(299).count().await, 2);
+        let replica = Replicator::new(ReplicaId::new(1), Counter::default(), other)
+            .await
+            .unwrap();
+        assert_eq!(replica.query(), (1..=300).sum::<i64>());
+
+        let mut conn = client.get_multiplexed_async_connection().await.unwrap();
+        let _: () = conn
+            .del(&[db.events_key(), db.snapshot_key()])
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+//! Many documents replicated as one, see [`CrdtRegistry`].
+//!
+//! A [`Replicator`](crate::Replicator) keeps one CRDT, so an app with a replicator per document
+//! opens a replication session and a store for each. A registry is a CRDT holding any number of
+//! documents of the same CRDT by id, with every event tagged with the document it is for, so one
+//! replicator, one log and one session carry all of them.
+//!
+//! Events keep the version of the whole log, which orders the events of a document the same as
+//! a version of its own would: events one of them saw are still seen, concurrent ones still
+//! concurrent.
+use std::{collections::BTreeMap, hash::Hash};
+
+use crate::{
+    checksum::{stable_hash, Checksum},
+    clock::Hlc,
+    memory::{MemoryUsage, Usage},
+    ChangeSummary, Crdt, Event, EventData, ReplicaId, VTime,
+};
+
+/// A command or event for document `doc`
+#[derive(Debug, Clone, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tagged<K, T> {
+    pub doc: K,
+    pub value: T,
+}
+
+impl<K, T> Tagged<K, T> {
+    pub fn new(doc: K, value: T) -> Self {
+        Self { doc, value }
+    }
+}
+
+/// Documents of `C` by id. Documents start out as a copy of the template the registry was made
+/// with the first time an event is for them, and are never dropped.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "K: serde::Serialize, C: serde::Serialize",
+        deserialize = "K: Ord + serde::Deserialize<'de>, C: serde::Deserialize<'de>"
+    ))
+)]
+pub struct CrdtRegistry<K, C> {
+    template: C,
+    docs: BTreeMap<K, C>,
+    /// What each document has seen
+    versions: BTreeMap<K, VTime>,
+}
+
+impl<K: Ord, C> CrdtRegistry<K, C> {
+    /// New documents are copies of `template`
+    pub fn new(template: C) -> Self {
+        Self {
+            template,
+            docs: BTreeMap::new(),
+            versions: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, doc: &K) -> Option<&C> {
+        self.docs.get(doc)
+    }
+
+    pub fn docs(&self) -> impl Iterator<Item = (&K, &C)> + '_ {
+        self.docs.iter()
+    }
+
+    /// The events of `doc` this replica has seen, `None` before the first one
+    pub fn version(&self, doc: &K) -> Option<&VTime> {
+        self.versions.get(doc)
+    }
+}
+
+impl<K: Ord, C: Default> Default for CrdtRegistry<K, C> {
+    fn default() -> Self {
+        Self::new(C::default())
+    }
+}
+
+/// The document `event` is for and the event as that document takes it
+fn untagged<K: EventData, D: EventData>(event: Event<Tagged<K, D>>) -> (K, Event<D>) {
+    let Tagged { doc, value } = event.data;
+    let event = Event {
+        origin: event.origin,
+        origin_seq: event.origin_seq,
+        local_seq: event.local_seq,
+        version: event.version,
+        data: value,
+        signature: event.signature,
+    };
+    (doc, event)
+}
+
+impl<K, C> Crdt for CrdtRegistry<K, C>
+where
+    K: EventData + Ord,
+    C: Crdt,
+{
+    type State = BTreeMap<K, C::State>;
+    type Cmd = Tagged<K, C::Cmd>;
+    type EData = Tagged<K, C::EData>;
+
+    fn query(&self) -> Self::State {
+        (self.docs.iter())
+            .map(|(doc, crdt)| (doc.clone(), crdt.query()))
+            .collect()
+    }
+
+    fn prepare(&self, op: Self::Cmd) -> Self::EData {
+        let crdt = self.docs.get(&op.doc).unwrap_or(&self.template);
+        let value = crdt.prepare(op.value);
+        Tagged { doc: op.doc, value }
+    }
+
+    fn prepare_at(&self, op: Self::Cmd, now: Hlc) -> Self::EData {
+        let crdt = self.docs.get(&op.doc).unwrap_or(&self.template);
+        let value = crdt.prepare_at(op.value, now);
+        Tagged { doc: op.doc, value }
+    }
+
+    fn effect(&mut self, event: Event<Self::EData>) {
+        let (doc, event) = untagged(event);
+        let version = self.versions.entry(doc.clone()).or_default();
+        version.merge(&event.version);
+        let crdt = (self.docs.entry(doc)).or_insert_with(|| self.template.clone());
+        crdt.effect(event);
+    }
+
+    fn compact(&mut self, stable: &VTime) {
+        for crdt in self.docs.values_mut() {
+            crdt.compact(stable);
+        }
+    }
+
+    fn rebind(&mut self, id: ReplicaId) {
+        self.template.rebind(id);
+        for crdt in self.docs.values_mut() {
+            crdt.rebind(id);
+        }
+    }
+
+    fn describe(&self, event: &Event<Self::EData>) -> ChangeSummary {
+        let (doc, event) = untagged(event.clone());
+        self.docs
+            .get(&doc)
+            .unwrap_or(&self.template)
+            .describe(&event)
+    }
+}
+
+/// Over the checksums of the documents along with their ids
+impl<K, C> Checksum for CrdtRegistry<K, C>
+where
+    K: Hash,
+    C: Checksum,
+{
+    fn checksum(&self) -> u64 {
+        let docs = self.docs.iter().map(|(doc, crdt)| (doc, crdt.checksum()));
+        stable_hash(&docs.collect::<Vec<_>>())
+    }
+}
+
+impl<K, C: MemoryUsage> MemoryUsage for CrdtRegistry<K, C> {
+    fn memory_usage(&self) -> Usage {
+        let docs = self.docs.values().map(MemoryUsage::memory_usage);
+        let versions = self.versions.values().map(MemoryUsage::memory_usage);
+        Usage::btree::<K, C>(self.docs.len())
+            + Usage::btree::<K, VTime>(self.versions.len())
+            + docs.sum()
+            + versions.sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        checksum::Checksum,
+        connect,
+        counter::Counter,
+        memdb::InMemoryDb,
+        protocol::Protocol,
+        rga::{Command, Rga},
+        Crdt, ReplicaId, Replicator,
+    };
+
+    use super::{CrdtRegistry, Tagged};
+
+    type Docs = CrdtRegistry<&'static str, Rga<char>>;
+    type Replica = Replicator<Docs, InMemoryDb<Docs>>;
+
+    async fn replica(id: u128) -> Replica {
+        let id = ReplicaId::new(id);
+        Replicator::new(id, CrdtRegistry::new(Rga::new(id)), InMemoryDb::default())
+            .await
+            .unwrap()
+    }
+
+    async fn insert(replica: &mut Replica, doc: &'static str, at: u32, c: char) {
+        let cmd = Tagged::new(doc, Command::Insert(at, c));
+        replica.send(Protocol::Command(cmd)).await;
+    }
+
+    fn text(replica: &Replica, doc: &str) -> Option<String> {
+        let rga = replica.crdt().get(&doc)?;
+        Some(rga.query().into_iter().collect())
+    }
+
+    #[tokio::test]
+    async fn replicates_documents_together() {
+        let mut alice = replica(1).await;
+        let mut bob = replica(2).await;
+        insert(&mut alice, "greeting", 0, 'h').await;
+        insert(&mut alice, "greeting", 1, 'i').await;
+        insert(&mut bob, "name", 0, 'b').await;
+        insert(&mut bob, "greeting", 0, '!').await;
+
+        connect(&mut alice, &mut bob).await;
+        connect(&mut bob, &mut alice).await;
+        for replica in [&alice, &bob] {
+            assert_eq!(text(replica, "greeting"), text(&alice, "greeting"));
+            assert_eq!(text(replica, "greeting").unwrap().len(), 3);
+            assert_eq!(text(replica, "name").as_deref(), Some("b"));
+            assert_eq!(text(replica, "missing"), None);
+            assert_eq!(replica.snapshot().seq(), 4);
+        }
+        assert_eq!(alice.checksum(), bob.checksum());
+
+        // Each document only saw its own events
+        let name = alice.crdt().version(&"name").unwrap();
+        assert_eq!(name.map, [(ReplicaId::new(2), 1)].into());
+        assert_eq!(alice.crdt().version(&"missing"), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_documents_apart() {
+        let counters = CrdtRegistry::<u32, Counter>::default();
+        let store = InMemoryDb::default();
+        let mut replica = Replicator::new(ReplicaId::new(1), counters, store.clone())
+            .await
+            .unwrap();
+        for (doc, n) in [(1, 2), (2, 5), (1, 3)] {
+            replica.send(Protocol::Command(Tagged::new(doc, n))).await;
+        }
+        assert_eq!(replica.query(), [(1, 5), (2, 5)].into());
+
+        let restarted = Replicator::new(ReplicaId::new(1), Default::default(), store)
+            .await
+            .unwrap();
+        assert_eq!(restarted.query(), replica.query());
+        let empty = CrdtRegistry::<u32, Counter>::default();
+        assert_ne!(replica.crdt().checksum(), empty.checksum());
+    }
+}
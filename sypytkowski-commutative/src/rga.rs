@@ -1,26 +1,44 @@
-use crate::{Crdt, ReplicaId};
+use crate::{
+    memory::{MemoryUsage, Usage},
+    Change, ChangeSummary, Crdt, ReplicaId, VTime,
+};
 
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug};
+
+use tree::VertexTree;
+
+mod tree;
 
 #[derive(Clone)]
 pub struct Rga<V> {
-    values: Vec<Vertex<V>>,
+    values: VertexTree<V>,
     sequencer: VPtr,
+    /// Tombstones still in `values` along with the event that removed them
+    removed: HashMap<VPtr, Stamp>,
 }
 
-#[derive(Clone, Debug, PartialEq, PartialOrd, Copy)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VPtr(u64, ReplicaId);
 
+/// The vertex's value is `None` once removed, the [`Stamp`] is the event that inserted it
 #[derive(Clone, Debug)]
-pub struct Vertex<V>(VPtr, Option<V>);
+pub struct Vertex<V>(VPtr, Option<V>, Stamp);
+
+/// An event by its origin and the origin's entry in the event's version, which is all it takes to
+/// tell whether a stable version covers it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Stamp(ReplicaId, u64);
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Command<V> {
     Insert(u32, V),
     RemoveAt(u32),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Operation<V> {
     Inserted {
         predecessor: VPtr,
@@ -34,88 +52,65 @@ pub enum Operation<V> {
 
 impl<V: Sync + Send + Clone + Debug> Rga<V> {
     pub fn new(replica_id: ReplicaId) -> Self {
+        let mut values = VertexTree::new();
+        values.insert(
+            0,
+            Vertex(
+                VPtr(0, ReplicaId(u128::MAX)),
+                None,
+                Stamp(ReplicaId(u128::MAX), 0),
+            ),
+        );
         Self {
-            values: vec![Vertex(VPtr(0, ReplicaId(u64::MAX)), None)],
+            values,
             sequencer: VPtr(0, replica_id),
+            removed: HashMap::new(),
         }
     }
 
+    /// Skips the vertices after `offset` that were inserted concurrently with `ptr` and won
     fn shift(&self, offset: usize, ptr: VPtr) -> usize {
-        if offset >= self.values.len() {
-            return offset;
-        }
-
-        let Vertex(successor, _) = self.values[offset];
-        if successor < ptr {
-            return offset;
-        }
-
-        self.shift(offset + 1, ptr)
+        offset
+            + self
+                .values
+                .iter_from(offset)
+                .take_while(|Vertex(successor, ..)| *successor >= ptr)
+                .count()
     }
 
     fn index_of_vptr(&self, ptr: VPtr) -> usize {
-        match self
-            .values
-            .iter()
-            .enumerate()
-            .find(|(_, Vertex(vptr, _))| vptr == &ptr)
-        {
-            Some((i, _)) => i,
+        match self.values.position(ptr) {
+            Some(i) => i,
             None => {
-                panic!(
-                    "COULDN'T FIND VPTR {:?} {:?} {:?}",
-                    self.sequencer.1, ptr, self.values
-                );
+                panic!("COULDN'T FIND VPTR {:?} {:?}", self.sequencer.1, ptr);
             }
         }
-        // .map(|v| v.0)
     }
 
-    fn apply_inserted(&mut self, predecessor: VPtr, ptr: VPtr, value: V) {
-        // In the case that we didn't find the vptr then just
-        let predecessor_idx = self.index_of_vptr(predecessor); //.unwrap_or(self.values.len());
-
-        println!("PREDECESSOR {}", predecessor_idx);
+    fn apply_inserted(&mut self, predecessor: VPtr, ptr: VPtr, value: V, stamp: Stamp) {
+        let predecessor_idx = self.index_of_vptr(predecessor);
         let insert_idx = self.shift(predecessor_idx + 1, ptr);
-        println!("SHIFT!! {} {:?}", insert_idx, self.sequencer.1);
 
         let VPtr(seq, id) = self.sequencer.incr();
         let next_seq = VPtr(seq.max(ptr.0), id);
 
-        println!(
-            "INSERTING {} {:?} {:?} {:?}",
-            insert_idx, self.sequencer.1, value, self.values
-        );
-        self.values.insert(insert_idx, Vertex(ptr, Some(value)));
+        self.values
+            .insert(insert_idx, Vertex(ptr, Some(value), stamp));
         self.sequencer = next_seq;
-        println!("FINAL INSERT {:?}: {:?}", self.sequencer.1, self.values);
     }
 
-    fn apply_removed(&mut self, pos: VPtr) {
-        // let index = match self.index_of_vptr(pos) {
-        //     Some(idx) => idx,
-        //     // either was already deleted or never existed
-        //     None => return,
-        // };
-        let index = self.index_of_vptr(pos);
-        self.values[index].1 = None;
-        println!("FINAL REMOVE {:?}: {:?}", self.sequencer.1, self.values);
+    /// The vertex might have been purged already, when an event removing it concurrently with the
+    /// one that got it purged is delivered again
+    fn apply_removed(&mut self, pos: VPtr, stamp: Stamp) {
+        if self.values.tombstone(pos) {
+            self.removed.entry(pos).or_insert(stamp);
+        }
     }
 
-    fn index_including_tombstones(&self, mut i: u32) -> usize {
-        let mut offset = 1;
-        for vertex in self.values.iter().skip(1) {
-            if i == 0 {
-                return offset;
-            }
-
-            if !vertex.is_tombstone() {
-                i -= 1;
-            }
-
-            offset += 1
-        }
-        return offset + i as usize;
+    /// Tombstones in front of the `i`th value are skipped, removing at `i` would otherwise remove
+    /// one of them instead
+    fn index_including_tombstones(&self, i: u32) -> usize {
+        self.values.live_position(i as usize)
     }
 }
 
@@ -133,8 +128,14 @@ impl<V: Sync + Send + Clone + Debug> Crdt for Rga<V> {
     fn prepare(&self, op: Self::Cmd) -> Self::EData {
         match op {
             Command::Insert(i, val) => {
-                let index = self.index_including_tombstones(i);
-                let predecessor = self.values[index - 1].0;
+                // Inserting right after the previous live vertex rather than after the tombstones
+                // that follow it ends up at the same place, the new vertex comes before them since
+                // its pointer is the greatest. It also means inserts never refer to tombstones,
+                // which may be purged by the time the insert gets anywhere.
+                let predecessor = match i {
+                    0 => self.values.get(0).0,
+                    i => self.values.get(self.index_including_tombstones(i - 1)).0,
+                };
                 let at = self.sequencer.incr();
 
                 Operation::Inserted {
@@ -145,26 +146,115 @@ impl<V: Sync + Send + Clone + Debug> Crdt for Rga<V> {
             }
             Command::RemoveAt(i) => {
                 let index = self.index_including_tombstones(i);
-                let pos = self.values[index].0;
+                let pos = self.values.get(index).0;
                 Operation::Removed { pos }
             }
         }
     }
 
     fn effect(&mut self, event: crate::Event<Self::EData>) {
+        let stamp = Stamp(
+            event.origin,
+            event
+                .version
+                .get(&event.origin)
+                .copied()
+                .unwrap_or_default(),
+        );
         match event.data {
             Operation::Inserted {
                 predecessor,
                 ptr,
                 val,
             } => {
-                self.apply_inserted(predecessor, ptr, val);
+                self.apply_inserted(predecessor, ptr, val, stamp);
             }
-            Operation::Removed { pos } => self.apply_removed(pos),
+            Operation::Removed { pos } => self.apply_removed(pos, stamp),
+        }
+    }
+
+    /// Purges tombstones whose removal is stable. Nothing can refer to them anymore, but they still
+    /// stop [`Rga::shift`] from skipping past them, which matters when what follows was inserted
+    /// after them by an event that isn't stable yet: a later insert with a smaller pointer would
+    /// land on one side of it here and on the other side wherever the tombstone is gone. So a
+    /// tombstone also waits for whatever comes right after it to be stable, every vertex
+    /// inserted from then on has a greater pointer than any stable one.
+    fn compact(&mut self, stable: &VTime) {
+        let is_stable =
+            |Stamp(origin, n): Stamp| stable.get(&origin).copied().unwrap_or_default() >= n;
+
+        // Back to front, so a run of tombstones goes in one pass and positions of those still to
+        // look at don't move
+        let mut purgeable = self
+            .removed
+            .iter()
+            .filter(|(_, &removal)| is_stable(removal))
+            .map(|(&ptr, _)| (self.index_of_vptr(ptr), ptr))
+            .collect::<Vec<_>>();
+        purgeable.sort_unstable_by(|a, b| b.cmp(a));
+
+        for (i, ptr) in purgeable {
+            let next_stable = i + 1 == self.values.len() || is_stable(self.values.get(i + 1).2);
+            if next_stable {
+                self.values.remove(ptr);
+                self.removed.remove(&ptr);
+            }
+        }
+    }
+
+    fn rebind(&mut self, id: ReplicaId) {
+        self.sequencer.1 = id;
+    }
+
+    /// Positions count live values only, the same as [`Command`]'s
+    fn describe(&self, event: &crate::Event<Self::EData>) -> ChangeSummary {
+        let change = match &event.data {
+            Operation::Inserted {
+                predecessor,
+                ptr,
+                val,
+            } => self.values.live_before(*predecessor).map(|live| {
+                let predecessor_idx = self.index_of_vptr(*predecessor);
+                let own = !self.values.get(predecessor_idx).is_tombstone() as usize;
+                // The concurrent inserts `ptr` ends up after, see `Rga::shift`
+                let skipped = self
+                    .values
+                    .iter_from(predecessor_idx + 1)
+                    .take_while(|Vertex(successor, ..)| successor >= ptr)
+                    .filter(|vertex| !vertex.is_tombstone())
+                    .count();
+                Change::Inserted {
+                    at: live + own + skipped,
+                    value: format!("{val:?}"),
+                }
+            }),
+            Operation::Removed { pos } => self.values.live_before(*pos).map(|at| {
+                let vertex = self.values.get(self.index_of_vptr(*pos));
+                Change::RemovedAt {
+                    at,
+                    value: vertex.1.as_ref().map(|val| format!("{val:?}")),
+                }
+            }),
+        };
+        ChangeSummary {
+            origin: event.origin,
+            change: change.unwrap_or(Change::Unknown),
         }
     }
 }
 
+impl<V> Rga<V> {
+    /// Values in order, without the removed ones
+    pub fn iter(&self) -> impl Iterator<Item = &V> + '_ {
+        self.values.iter().filter_map(|vertex| vertex.1.as_ref())
+    }
+
+    /// Number of vertices kept, tombstones and the head included
+    pub fn vertices(&self) -> usize {
+        self.values.len()
+    }
+}
+
 impl<V> Vertex<V> {
     fn is_tombstone(&self) -> bool {
         self.1.is_none()
@@ -177,6 +267,13 @@ impl VPtr {
     }
 }
 
+/// Tombstones count until [compacted](Crdt::compact) away
+impl<V> MemoryUsage for Rga<V> {
+    fn memory_usage(&self) -> Usage {
+        self.values.memory_usage() + Usage::hash::<VPtr, Stamp>(self.removed.len())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::{
@@ -184,7 +281,7 @@ mod test {
         protocol::Protocol,
         replicate,
         rga::{Command, Rga},
-        ReplicaId, Replicator,
+        Change, Crdt as _, ReplicaId, Replicator,
     };
 
     use super::VPtr;
@@ -204,9 +301,12 @@ mod test {
         let alice_id = ReplicaId(0);
         let bob_id = ReplicaId(1);
         let mut alice =
-            Replicator::new(alice_id, Crdt::new(alice_id), InMemoryDb::<Crdt>::default()).await;
-        let mut bob =
-            Replicator::new(bob_id, Crdt::new(bob_id), InMemoryDb::<Crdt>::default()).await;
+            Replicator::new(alice_id, Crdt::new(alice_id), InMemoryDb::<Crdt>::default())
+                .await
+                .unwrap();
+        let mut bob = Replicator::new(bob_id, Crdt::new(bob_id), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
 
         let _ = alice
             .send(Protocol::Command(Command::Insert(0, "nice")))
@@ -220,7 +320,8 @@ mod test {
         let bob_value = bob.query();
 
         assert_eq!(alice_value, vec!["nah", "nice"]);
-        assert_eq!(alice_value, bob_value)
+        assert_eq!(alice_value, bob_value);
+        assert!(alice.crdt().iter().eq(&alice_value))
     }
 
     #[tokio::test]
@@ -230,9 +331,12 @@ mod test {
         let alice_id = ReplicaId(0);
         let bob_id = ReplicaId(1);
         let mut alice =
-            Replicator::new(alice_id, Crdt::new(alice_id), InMemoryDb::<Crdt>::default()).await;
-        let mut bob =
-            Replicator::new(bob_id, Crdt::new(bob_id), InMemoryDb::<Crdt>::default()).await;
+            Replicator::new(alice_id, Crdt::new(alice_id), InMemoryDb::<Crdt>::default())
+                .await
+                .unwrap();
+        let mut bob = Replicator::new(bob_id, Crdt::new(bob_id), InMemoryDb::<Crdt>::default())
+            .await
+            .unwrap();
 
         let _ = alice
             .send(Protocol::Command(Command::Insert(0, "nice")))
@@ -255,4 +359,200 @@ mod test {
         assert_eq!(alice_value, vec!["nice"]);
         assert_eq!(alice_value, bob_value)
     }
+
+    #[tokio::test]
+    async fn describe() {
+        let [mut alice, mut bob] = replicas().await;
+
+        // Both insert after "b" concurrently, Bob's ends up first
+        let _ = alice.send(Protocol::Command(Command::Insert(2, "x"))).await;
+        let _ = bob.send(Protocol::Command(Command::Insert(2, "y"))).await;
+        let _ = bob.send(Protocol::Command(Command::RemoveAt(0))).await;
+
+        let replicated = bob
+            .replay(bob.id(), alice.state.version.clone(), 1, 100)
+            .await;
+        let summaries = replicated
+            .events
+            .iter()
+            .map(|event| {
+                let summary = alice.crdt().describe(event);
+                alice.state.crdt.effect(event.clone());
+                summary
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|s| s.change.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Change::Inserted {
+                    at: 2,
+                    value: "\"y\"".into()
+                },
+                Change::RemovedAt {
+                    at: 0,
+                    value: Some("\"a\"".into())
+                },
+            ]
+        );
+        assert_eq!(alice.query(), vec!["b", "y", "x", "c", "d"]);
+        assert_eq!(summaries[0].to_string(), "1 inserted \"y\" at position 2");
+    }
+
+    async fn replicas() -> [Replicator<Rga<&'static str>, InMemoryDb<Rga<&'static str>>>; 2] {
+        let alice_id = ReplicaId(0);
+        let bob_id = ReplicaId(1);
+        let mut alice = Replicator::new(alice_id, Rga::new(alice_id), InMemoryDb::default())
+            .await
+            .unwrap();
+        let mut bob = Replicator::new(bob_id, Rga::new(bob_id), InMemoryDb::default())
+            .await
+            .unwrap();
+
+        for (i, val) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            let _ = alice
+                .send(Protocol::Command(Command::Insert(i as u32, val)))
+                .await;
+        }
+        replicate(&mut bob, &mut alice).await;
+        replicate(&mut alice, &mut bob).await;
+
+        [alice, bob]
+    }
+
+    #[tokio::test]
+    async fn compact() {
+        let [mut alice, mut bob] = replicas().await;
+
+        let _ = bob.send(Protocol::Command(Command::RemoveAt(1))).await;
+        let _ = bob.send(Protocol::Command(Command::RemoveAt(1))).await;
+        replicate(&mut alice, &mut bob).await;
+        replicate(&mut bob, &mut alice).await;
+
+        alice.compact().await;
+        bob.compact().await;
+        assert_eq!(alice.state.crdt.vertices(), 3);
+        assert_eq!(bob.state.crdt.vertices(), 3);
+
+        // Concurrent inserts where the tombstones used to be still agree
+        let _ = alice.send(Protocol::Command(Command::Insert(1, "x"))).await;
+        let _ = bob.send(Protocol::Command(Command::Insert(1, "y"))).await;
+        replicate(&mut alice, &mut bob).await;
+        replicate(&mut bob, &mut alice).await;
+
+        assert_eq!(alice.query(), vec!["a", "y", "x", "d"]);
+        assert_eq!(alice.query(), bob.query());
+    }
+
+    #[tokio::test]
+    async fn compact_waits_for_every_replica() {
+        let [mut alice, mut bob] = replicas().await;
+
+        // Bob hasn't seen the removal yet
+        let _ = alice.send(Protocol::Command(Command::RemoveAt(1))).await;
+        alice.compact().await;
+        assert_eq!(alice.state.crdt.vertices(), 5);
+
+        // Bob has, but Alice hasn't seen the insert Bob made before that
+        let _ = bob.send(Protocol::Command(Command::Insert(2, "y"))).await;
+        replicate(&mut bob, &mut alice).await;
+        alice.compact().await;
+        assert_eq!(alice.state.crdt.vertices(), 5);
+
+        // Now "b" goes, while "y" took its place in the meantime
+        replicate(&mut alice, &mut bob).await;
+        replicate(&mut bob, &mut alice).await;
+        alice.compact().await;
+        assert_eq!(alice.state.crdt.vertices(), 5);
+        assert_eq!(alice.query(), vec!["a", "y", "c", "d"]);
+        assert_eq!(alice.query(), bob.query());
+    }
+
+    #[tokio::test]
+    async fn late_joiner_from_snapshot() {
+        let [mut alice, mut bob] = replicas().await;
+
+        let _ = alice.send(Protocol::Command(Command::RemoveAt(0))).await;
+        let _ = bob.send(Protocol::Command(Command::RemoveAt(3))).await;
+        replicate(&mut alice, &mut bob).await;
+        replicate(&mut bob, &mut alice).await;
+        alice.compact().await;
+        assert_eq!(alice.state.crdt.vertices(), 3);
+
+        let carol_id = ReplicaId(2);
+        let mut carol =
+            Replicator::from_snapshot(carol_id, alice.snapshot(), InMemoryDb::default()).await;
+        assert_eq!(carol.query(), vec!["b", "c"]);
+
+        let _ = alice.send(Protocol::Command(Command::Insert(0, "x"))).await;
+        let _ = bob.send(Protocol::Command(Command::Insert(2, "y"))).await;
+        let _ = carol.send(Protocol::Command(Command::Insert(1, "z"))).await;
+        let _ = carol.send(Protocol::Command(Command::RemoveAt(0))).await;
+
+        for _ in 0..2 {
+            replicate(&mut alice, &mut bob).await;
+            replicate(&mut alice, &mut carol).await;
+            replicate(&mut bob, &mut alice).await;
+            replicate(&mut bob, &mut carol).await;
+            replicate(&mut carol, &mut alice).await;
+            replicate(&mut carol, &mut bob).await;
+        }
+        assert_eq!(alice.query(), vec!["x", "z", "c", "y"]);
+        assert_eq!(alice.query(), bob.query());
+        assert_eq!(alice.query(), carol.query());
+
+        // Everyone pulled from everyone, so Carol's removal is stable everywhere
+        for replica in [&mut alice, &mut bob] {
+            replica.compact().await;
+            assert_eq!(replica.state.crdt.vertices(), 5);
+        }
+        carol.compact().await;
+        assert_eq!(carol.state.crdt.vertices(), 5);
+    }
+
+    #[tokio::test]
+    async fn compact_keeps_tombstones_followed_by_unstable_inserts() {
+        let [mut alice, mut bob] = replicas().await;
+        let carol_id = ReplicaId(2);
+        let mut carol = Replicator::new(carol_id, Rga::new(carol_id), InMemoryDb::default())
+            .await
+            .unwrap();
+        replicate(&mut carol, &mut alice).await;
+        replicate(&mut alice, &mut carol).await;
+
+        // Bob gets his sequencer ahead, then puts "z" right after "b"
+        for _ in 0..4 {
+            let _ = bob.send(Protocol::Command(Command::Insert(4, "q"))).await;
+        }
+        let _ = bob.send(Protocol::Command(Command::Insert(2, "z"))).await;
+
+        // Alice removes "b", everyone sees it but only Alice and Bob see "z"
+        let _ = alice.send(Protocol::Command(Command::RemoveAt(1))).await;
+        replicate(&mut carol, &mut alice).await;
+        replicate(&mut bob, &mut alice).await;
+        replicate(&mut alice, &mut bob).await;
+
+        // The removal is stable but "z" isn't, without "b" Carol's insert would end up after "z"
+        // here while it goes before it on Carol's side
+        alice.compact().await;
+        assert_eq!(alice.state.crdt.vertices(), 10);
+        let _ = carol.send(Protocol::Command(Command::Insert(1, "x"))).await;
+
+        for _ in 0..2 {
+            replicate(&mut alice, &mut carol).await;
+            replicate(&mut bob, &mut carol).await;
+            replicate(&mut carol, &mut alice).await;
+            replicate(&mut carol, &mut bob).await;
+            replicate(&mut alice, &mut bob).await;
+            replicate(&mut bob, &mut alice).await;
+        }
+        assert_eq!(
+            alice.query(),
+            vec!["a", "x", "z", "c", "d", "q", "q", "q", "q"]
+        );
+        assert_eq!(alice.query(), bob.query());
+        assert_eq!(alice.query(), carol.query());
+    }
 }
@@ -0,0 +1,398 @@
+//! Sequence of [`Vertex`]es kept in an implicit treap, so looking up a position, finding where a
+//! [`VPtr`] sits and inserting all take `O(log n)` instead of scanning a `Vec`.
+//!
+//! Nodes live in an arena and point at each other by index. Every node knows how many vertices
+//! and how many live (non tombstone) vertices its subtree holds, which is what positions are
+//! derived from. Priorities come from hashing the vertex's `VPtr`, so the shape of the tree is the
+//! same on every replica without needing any randomness. Slots of removed vertices are reused by
+//! the next inserts.
+use std::collections::HashMap;
+
+use super::{VPtr, Vertex};
+use crate::memory::{MemoryUsage, Usage};
+
+const NIL: usize = usize::MAX;
+
+#[derive(Clone, Debug)]
+struct Node<V> {
+    vertex: Vertex<V>,
+    priority: u64,
+    left: usize,
+    right: usize,
+    parent: usize,
+    /// Vertices in the subtree, tombstones included
+    size: usize,
+    /// Live vertices in the subtree
+    live: usize,
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct VertexTree<V> {
+    nodes: Vec<Node<V>>,
+    root: usize,
+    by_ptr: HashMap<VPtr, usize>,
+    /// Arena slots left behind by removed vertices
+    free: Vec<usize>,
+}
+
+impl<V> VertexTree<V> {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![],
+            root: NIL,
+            by_ptr: HashMap::new(),
+            free: vec![],
+        }
+    }
+
+    /// Number of vertices, tombstones included
+    pub fn len(&self) -> usize {
+        self.size(self.root)
+    }
+
+    /// The vertex at position `i`, tombstones included
+    pub fn get(&self, i: usize) -> &Vertex<V> {
+        &self.nodes[self.node_at(i)].vertex
+    }
+
+    /// Position of the vertex with `ptr`, tombstones included
+    pub fn position(&self, ptr: VPtr) -> Option<usize> {
+        let mut node = *self.by_ptr.get(&ptr)?;
+        let mut pos = self.size(self.nodes[node].left);
+        while self.nodes[node].parent != NIL {
+            let parent = self.nodes[node].parent;
+            if self.nodes[parent].right == node {
+                pos += self.size(self.nodes[parent].left) + 1;
+            }
+            node = parent;
+        }
+        Some(pos)
+    }
+
+    /// Number of live vertices in front of the vertex with `ptr`
+    pub fn live_before(&self, ptr: VPtr) -> Option<usize> {
+        let mut node = *self.by_ptr.get(&ptr)?;
+        let mut live = self.live(self.nodes[node].left);
+        while self.nodes[node].parent != NIL {
+            let parent = self.nodes[node].parent;
+            if self.nodes[parent].right == node {
+                let own = !self.nodes[parent].vertex.is_tombstone() as usize;
+                live += self.live(self.nodes[parent].left) + own;
+            }
+            node = parent;
+        }
+        Some(live)
+    }
+
+    /// Position of the `i`th live vertex. Past the last one this keeps counting from the end, so
+    /// `i` live vertices further than the last live one is `len + i - live`.
+    pub fn live_position(&self, mut i: usize) -> usize {
+        let live = self.live(self.root);
+        if i >= live {
+            return self.len() + i - live;
+        }
+
+        let (mut node, mut pos) = (self.root, 0);
+        loop {
+            let Node {
+                left,
+                right,
+                vertex,
+                ..
+            } = &self.nodes[node];
+            let left_live = self.live(*left);
+            if i < left_live {
+                node = *left;
+                continue;
+            }
+            i -= left_live;
+            pos += self.size(*left);
+            if !vertex.is_tombstone() {
+                if i == 0 {
+                    return pos;
+                }
+                i -= 1;
+            }
+            pos += 1;
+            node = *right;
+        }
+    }
+
+    pub fn insert(&mut self, i: usize, vertex: Vertex<V>) {
+        let ptr = vertex.0;
+        let new = Node {
+            priority: priority(ptr),
+            live: !vertex.is_tombstone() as usize,
+            vertex,
+            left: NIL,
+            right: NIL,
+            parent: NIL,
+            size: 1,
+        };
+        let node = match self.free.pop() {
+            Some(node) => {
+                self.nodes[node] = new;
+                node
+            }
+            None => {
+                self.nodes.push(new);
+                self.nodes.len() - 1
+            }
+        };
+        self.by_ptr.insert(ptr, node);
+
+        let (left, right) = self.split(self.root, i);
+        let left = self.merge(left, node);
+        self.root = self.merge(left, right);
+        self.nodes[self.root].parent = NIL;
+    }
+
+    /// Turns the vertex with `ptr` into a tombstone, returns `false` if there is no such vertex
+    pub fn tombstone(&mut self, ptr: VPtr) -> bool {
+        let Some(&node) = self.by_ptr.get(&ptr) else {
+            return false;
+        };
+        if self.nodes[node].vertex.1.take().is_some() {
+            let mut node = node;
+            while node != NIL {
+                self.nodes[node].live -= 1;
+                node = self.nodes[node].parent;
+            }
+        }
+        true
+    }
+
+    /// Takes the vertex with `ptr` out of the sequence altogether, returns `false` if there is no
+    /// such vertex
+    pub fn remove(&mut self, ptr: VPtr) -> bool {
+        let Some(i) = self.position(ptr) else {
+            return false;
+        };
+        let (left, right) = self.split(self.root, i);
+        let (node, right) = self.split(right, 1);
+        self.root = self.merge(left, right);
+        if self.root != NIL {
+            self.nodes[self.root].parent = NIL;
+        }
+
+        self.by_ptr.remove(&ptr);
+        self.nodes[node].vertex.1 = None;
+        self.free.push(node);
+        true
+    }
+
+    /// Vertices in order starting at position `i`
+    pub fn iter_from(&self, i: usize) -> Iter<'_, V> {
+        let mut stack = vec![];
+        let (mut node, mut i) = (self.root, i);
+        while node != NIL {
+            let left = self.size(self.nodes[node].left);
+            if i < left {
+                stack.push(node);
+                node = self.nodes[node].left;
+            } else if i == left {
+                stack.push(node);
+                break;
+            } else {
+                i -= left + 1;
+                node = self.nodes[node].right;
+            }
+        }
+        Iter { tree: self, stack }
+    }
+
+    pub fn iter(&self) -> Iter<'_, V> {
+        self.iter_from(0)
+    }
+
+    fn node_at(&self, mut i: usize) -> usize {
+        let mut node = self.root;
+        loop {
+            let left = self.size(self.nodes[node].left);
+            if i < left {
+                node = self.nodes[node].left;
+            } else if i == left {
+                return node;
+            } else {
+                i -= left + 1;
+                node = self.nodes[node].right;
+            }
+        }
+    }
+
+    fn size(&self, node: usize) -> usize {
+        if node == NIL {
+            0
+        } else {
+            self.nodes[node].size
+        }
+    }
+
+    fn live(&self, node: usize) -> usize {
+        if node == NIL {
+            0
+        } else {
+            self.nodes[node].live
+        }
+    }
+
+    fn update(&mut self, node: usize) {
+        let Node { left, right, .. } = self.nodes[node];
+        let own = !self.nodes[node].vertex.is_tombstone() as usize;
+        self.nodes[node].size = self.size(left) + self.size(right) + 1;
+        self.nodes[node].live = self.live(left) + self.live(right) + own;
+        for child in [left, right] {
+            if child != NIL {
+                self.nodes[child].parent = node;
+            }
+        }
+    }
+
+    /// Splits `node`'s subtree into its first `i` vertices and the rest. The recursion follows a
+    /// single path down the tree, so it is only as deep as the tree.
+    fn split(&mut self, node: usize, i: usize) -> (usize, usize) {
+        if node == NIL {
+            return (NIL, NIL);
+        }
+        let left = self.nodes[node].left;
+        if i <= self.size(left) {
+            let (l, r) = self.split(left, i);
+            self.nodes[node].left = r;
+            self.update(node);
+            if l != NIL {
+                self.nodes[l].parent = NIL;
+            }
+            (l, node)
+        } else {
+            let right = self.nodes[node].right;
+            let (l, r) = self.split(right, i - self.size(left) - 1);
+            self.nodes[node].right = l;
+            self.update(node);
+            if r != NIL {
+                self.nodes[r].parent = NIL;
+            }
+            (node, r)
+        }
+    }
+
+    /// Joins two subtrees, every vertex of `left` ending up before those of `right`
+    fn merge(&mut self, left: usize, right: usize) -> usize {
+        if left == NIL {
+            return right;
+        }
+        if right == NIL {
+            return left;
+        }
+        if self.nodes[left].priority > self.nodes[right].priority {
+            let merged = self.merge(self.nodes[left].right, right);
+            self.nodes[left].right = merged;
+            self.update(left);
+            left
+        } else {
+            let merged = self.merge(left, self.nodes[right].left);
+            self.nodes[right].left = merged;
+            self.update(right);
+            right
+        }
+    }
+}
+
+/// Free slots still hold their node, so they count as well
+impl<V> MemoryUsage for VertexTree<V> {
+    fn memory_usage(&self) -> Usage {
+        Usage::vec::<Node<V>>(self.nodes.len())
+            + Usage::hash::<VPtr, usize>(self.by_ptr.len())
+            + Usage::vec::<usize>(self.free.len())
+    }
+}
+
+/// In order walk over a [`VertexTree`], the stack holds the nodes still to visit along with
+/// their right subtrees
+pub(super) struct Iter<'a, V> {
+    tree: &'a VertexTree<V>,
+    stack: Vec<usize>,
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = &'a Vertex<V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        let mut next = self.tree.nodes[node].right;
+        while next != NIL {
+            self.stack.push(next);
+            next = self.tree.nodes[next].left;
+        }
+        Some(&self.tree.nodes[node].vertex)
+    }
+}
+
+/// SplitMix64 of the pointer
+fn priority(VPtr(seq, id): VPtr) -> u64 {
+    let mut z = seq ^ (id.0 as u64) ^ ((id.0 >> 64) as u64).rotate_left(32);
+    z = z.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rga::{Stamp, VPtr, Vertex};
+    use crate::ReplicaId;
+
+    use super::VertexTree;
+
+    /// Checks every query against a plain `Vec` after each change
+    #[test]
+    fn matches_vec() {
+        let mut tree = VertexTree::new();
+        let mut vec: Vec<Vertex<u64>> = vec![];
+        let mut x = 7u64;
+        let stamp = Stamp(ReplicaId(0), 0);
+        for n in 0..2_000u64 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let ptr = VPtr(n, ReplicaId(0));
+            if x % 4 == 0 && !vec.is_empty() {
+                let i = (x >> 33) as usize % vec.len();
+                let ptr = vec[i].0;
+                vec[i].1 = None;
+                assert!(tree.tombstone(ptr));
+            } else if x % 8 == 1 && vec.len() > 1 {
+                let i = (x >> 33) as usize % vec.len();
+                let ptr = vec.remove(i).0;
+                assert!(tree.remove(ptr));
+                assert!(!tree.remove(ptr));
+                assert_eq!(tree.position(ptr), None);
+            } else {
+                let i = (x >> 33) as usize % (vec.len() + 1);
+                vec.insert(i, Vertex(ptr, Some(n), stamp));
+                tree.insert(i, Vertex(ptr, Some(n), stamp));
+            }
+
+            assert_eq!(tree.len(), vec.len());
+            let i = (x >> 17) as usize % vec.len();
+            assert_eq!(tree.position(vec[i].0), Some(i));
+            assert_eq!(tree.get(i).0, vec[i].0);
+            let live = vec.iter().filter(|v| v.1.is_some()).count();
+            let nth = (x >> 40) as usize % (live + 2);
+            let expected = vec
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| v.1.is_some())
+                .nth(nth)
+                .map_or(vec.len() + nth - live, |(i, _)| i);
+            assert_eq!(tree.live_position(nth), expected);
+        }
+
+        let ptrs =
+            |v: &mut dyn Iterator<Item = &Vertex<u64>>| v.map(|v| (v.0, v.1)).collect::<Vec<_>>();
+        assert_eq!(ptrs(&mut tree.iter()), ptrs(&mut vec.iter()));
+        assert_eq!(
+            ptrs(&mut tree.iter_from(500)),
+            ptrs(&mut vec.iter().skip(500))
+        );
+    }
+}
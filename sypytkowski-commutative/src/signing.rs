@@ -0,0 +1,264 @@
+//! Ed25519 signed events, see [`Signer`].
+//!
+//! Every replica signs the events it makes with its own key. What's signed is the origin, its
+//! sequence number, the version and the data as msgpack, everything but the `local_seq` that each
+//! replica rewrites, so signatures survive being relayed through any number of replicas. Event
+//! data has to serialize the same way every time for that, which rules out hash maps.
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use ed25519_dalek::{Signature, Signer as _};
+use serde::Serialize;
+
+use crate::{Event, EventAuth, EventData, ReplicaId};
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// Keeps signatures of events from being mistaken for signatures of anything else
+const CONTEXT: &[u8] = b"sypytkowski-commutative event v1";
+
+/// Which replica signs with which key. Clones share the keys, so replicas already running pick up
+/// keys added later.
+#[derive(Debug, Clone, Default)]
+pub struct KeyRegistry {
+    keys: Arc<RwLock<BTreeMap<ReplicaId, VerifyingKey>>>,
+}
+
+impl KeyRegistry {
+    pub fn insert(&self, replica: ReplicaId, key: VerifyingKey) -> Option<VerifyingKey> {
+        self.keys.write().unwrap().insert(replica, key)
+    }
+
+    /// Events of `replica` stop verifying from now on
+    pub fn remove(&self, replica: ReplicaId) -> Option<VerifyingKey> {
+        self.keys.write().unwrap().remove(&replica)
+    }
+
+    pub fn get(&self, replica: ReplicaId) -> Option<VerifyingKey> {
+        self.keys.read().unwrap().get(&replica).copied()
+    }
+}
+
+impl FromIterator<(ReplicaId, VerifyingKey)> for KeyRegistry {
+    fn from_iter<I: IntoIterator<Item = (ReplicaId, VerifyingKey)>>(iter: I) -> Self {
+        Self {
+            keys: Arc::new(RwLock::new(iter.into_iter().collect())),
+        }
+    }
+}
+
+/// Signs events with the replica's key and verifies replicated ones against the keys in a
+/// [`KeyRegistry`]. Events of replicas that aren't in the registry don't verify, and neither do
+/// unsigned ones.
+#[derive(Debug)]
+pub struct Signer {
+    key: Option<SigningKey>,
+    keys: KeyRegistry,
+}
+
+impl Signer {
+    /// The registry should have the verifying key of `key` too, or this replica's own events won't
+    /// verify when they come back around through others
+    pub fn new(key: SigningKey, keys: KeyRegistry) -> Self {
+        Self {
+            key: Some(key),
+            keys,
+        }
+    }
+
+    /// Only verifies, events made here go out unsigned
+    pub fn verifier(keys: KeyRegistry) -> Self {
+        Self { key: None, keys }
+    }
+}
+
+impl<D: EventData + Serialize> EventAuth<D> for Signer {
+    fn sign(&self, event: &Event<D>) -> Option<Vec<u8>> {
+        let key = self.key.as_ref()?;
+        Some(key.sign(&message(event)).to_vec())
+    }
+
+    fn verify(&self, event: &Event<D>) -> Result<(), String> {
+        let key = self
+            .keys
+            .get(event.origin)
+            .ok_or("origin has no key in the registry")?;
+        let signature = event.signature.as_deref().ok_or("event isn't signed")?;
+        let signature = Signature::from_slice(signature).map_err(|_| "malformed signature")?;
+        key.verify_strict(&message(event), &signature)
+            .map_err(|_| "signature doesn't match".to_string())
+    }
+}
+
+/// What the origin signs
+fn message<D: EventData + Serialize>(event: &Event<D>) -> Vec<u8> {
+    let mut msg = CONTEXT.to_vec();
+    msg.extend(event.origin.as_u128().to_be_bytes());
+    msg.extend(event.origin_seq.to_be_bytes());
+    msg.extend((event.version.len() as u64).to_be_bytes());
+    for (replica, seq) in event.version.iter() {
+        msg.extend(replica.as_u128().to_be_bytes());
+        msg.extend(seq.to_be_bytes());
+    }
+    let data =
+        rmp_serde::to_vec(&event.data).expect("msgpack encoding doesn't fail for plain data");
+    msg.extend(data);
+    msg
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        connect, counter::Counter, memdb::InMemoryDb, protocol::Protocol, ForgedEvent, ReplicaId,
+        Replicator,
+    };
+
+    use super::{KeyRegistry, Signer, SigningKey};
+
+    type Replica = Replicator<Counter, InMemoryDb<Counter>>;
+
+    fn key(n: u8) -> SigningKey {
+        SigningKey::from_bytes(&[n; 32])
+    }
+
+    /// Replicas 1 to 3 with their own keys, all in the registry
+    fn registry() -> KeyRegistry {
+        (1..=3)
+            .map(|n| (ReplicaId::new(n as u128), key(n).verifying_key()))
+            .collect()
+    }
+
+    async fn replica(id: u128, key: SigningKey, keys: &KeyRegistry) -> Replica {
+        Replicator::new(
+            ReplicaId::new(id),
+            Counter::default(),
+            InMemoryDb::default(),
+        )
+        .await
+        .unwrap()
+        .with_auth(Signer::new(key, keys.clone()))
+    }
+
+    #[tokio::test]
+    async fn replicates_signed_events() {
+        let keys = registry();
+        let mut alice = replica(1, key(1), &keys).await;
+        let mut bob = replica(2, key(2), &keys).await;
+        let mut carol = replica(3, key(3), &keys).await;
+        alice.send(Protocol::Command(1)).await;
+        alice.send(Protocol::Command(2)).await;
+        bob.send(Protocol::Command(3)).await;
+
+        // Alice's events reach Carol through Bob, still signed by Alice
+        connect(&mut bob, &mut alice).await;
+        connect(&mut carol, &mut bob).await;
+        connect(&mut alice, &mut bob).await;
+        for replica in [&mut alice, &mut bob, &mut carol] {
+            assert_eq!(replica.query(), 6);
+            assert!(replica.take_forged().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_impersonation() {
+        let keys = registry();
+        let mut bob = replica(2, key(2), &keys).await;
+        // Signs as Alice with a key that isn't hers
+        let mut mallory = replica(1, key(9), &keys).await;
+        mallory.send(Protocol::Command(1)).await;
+
+        connect(&mut bob, &mut mallory).await;
+        assert_eq!(bob.query(), 0);
+        assert!(bob.store.events.read().await.is_empty());
+        let forged = bob.take_forged();
+        assert_eq!(
+            forged,
+            [ForgedEvent {
+                from: ReplicaId::new(1),
+                origin: ReplicaId::new(1),
+                origin_seq: 1,
+                reason: "signature doesn't match".into(),
+            }]
+        );
+        assert!(bob.take_forged().is_empty());
+
+        // Unsigned events and events of replicas nobody knows the key of
+        let plain = Replicator::new(ReplicaId::new(1), Counter::default(), InMemoryDb::default());
+        let mut plain = plain.await.unwrap();
+        plain.send(Protocol::Command(1)).await;
+        connect(&mut bob, &mut plain).await;
+        let mut stranger = replica(4, key(4), &keys).await;
+        stranger.send(Protocol::Command(1)).await;
+        connect(&mut bob, &mut stranger).await;
+        let reasons = bob
+            .take_forged()
+            .into_iter()
+            .map(|forged| forged.reason)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            ["event isn't signed", "origin has no key in the registry"]
+        );
+        assert_eq!(bob.query(), 0);
+
+        // Keys added later count right away
+        keys.insert(ReplicaId::new(4), key(4).verifying_key());
+        connect(&mut bob, &mut stranger).await;
+        assert_eq!(bob.query(), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_at_tampered_events() {
+        let keys = registry();
+        let mut alice = replica(1, key(1), &keys).await;
+        let mut bob = replica(2, key(2), &keys).await;
+        for i in 1..=3 {
+            alice.send(Protocol::Command(i)).await;
+        }
+
+        let Protocol::Replicate(replicate) = bob
+            .send(Protocol::Connect(crate::protocol::Connect {
+                replica_id: alice.id(),
+            }))
+            .await
+        else {
+            panic!("expected a replicate");
+        };
+        let Protocol::Replicated(mut replicated) = alice.send(Protocol::Replicate(replicate)).await
+        else {
+            panic!("expected replicated events");
+        };
+        replicated.events[1].data = 20;
+        let reply = bob.send(Protocol::Replicated(replicated)).await;
+
+        // Nothing past the tampered event is taken, even though the one after it is fine
+        assert!(matches!(reply, Protocol::Noop));
+        assert_eq!(bob.query(), 1);
+        let forged = bob.take_forged();
+        assert_eq!(forged.len(), 1);
+        assert_eq!(forged[0].origin_seq, 2);
+
+        // Pulling again picks up where it stopped
+        connect(&mut bob, &mut alice).await;
+        assert_eq!(bob.query(), 6);
+    }
+
+    #[tokio::test]
+    async fn verifiers_dont_sign() {
+        let keys = registry();
+        let mut alice = replica(1, key(1), &keys).await;
+        let verifier =
+            Replicator::new(ReplicaId::new(2), Counter::default(), InMemoryDb::default());
+        let mut verifier = verifier.await.unwrap().with_auth(Signer::verifier(keys));
+        alice.send(Protocol::Command(1)).await;
+        verifier.send(Protocol::Command(2)).await;
+
+        connect(&mut verifier, &mut alice).await;
+        connect(&mut alice, &mut verifier).await;
+        assert_eq!(verifier.query(), 3);
+        assert_eq!(alice.query(), 1);
+        assert_eq!(alice.take_forged()[0].reason, "event isn't signed");
+    }
+}
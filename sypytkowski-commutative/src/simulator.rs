@@ -0,0 +1,710 @@
+//! Deterministic network simulation for [`Replicator`]s.
+//!
+//! Replicas only talk through a simulated network that delays, reorders, duplicates and drops
+//! [`Replicate`](proto::Replicate)/[`Replicated`](proto::Replicated) messages. Every random choice
+//! comes from one seeded [`SimRng`], so a failing run is replayed by running the same seed again.
+//!
+//! Lost messages end the pull they belonged to, so every [`NetConfig::sync_every`] ticks each
+//! replica starts pulling from every peer again, the way a real deployment would retry.
+//!
+//! [`Simulation::partition`] splits the replicas into groups that can't reach each other until
+//! [`Simulation::heal`], messages already in flight across the split are lost as well.
+//!
+//! [`Simulation::equivocate`] has a replica tell one peer something else than everyone else.
+//!
+//! Replicas read the time off a [`ManualClock`] that moves a millisecond every tick.
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::ops::Range;
+
+use crate::checksum::Checksum;
+use crate::clock::ManualClock;
+use crate::memdb::InMemoryDb;
+use crate::protocol::{self as proto, Protocol};
+use crate::{Crdt, ReplicaId, Replicator};
+
+/// SplitMix64, enough for picking faults and keeps runs reproducible from the seed alone
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `range`, `range.start` if it is empty
+    pub fn range(&mut self, range: Range<u64>) -> u64 {
+        if range.is_empty() {
+            return range.start;
+        }
+        range.start + self.next_u64() % (range.end - range.start)
+    }
+
+    /// `true` with probability `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.range(0..items.len() as u64) as usize]
+    }
+}
+
+/// How badly the simulated network behaves
+#[derive(Debug, Clone)]
+pub struct NetConfig {
+    /// Ticks a message spends in flight, picked uniformly
+    pub delay: Range<u64>,
+    /// Probability a message is held back for another `delay.end` ticks, so messages sent after it
+    /// overtake it
+    pub reorder: f64,
+    /// Probability a message is delivered twice
+    pub duplicate: f64,
+    /// Probability a message is lost
+    pub drop: f64,
+    /// Ticks between two rounds of every replica pulling from every peer
+    pub sync_every: u64,
+}
+
+impl NetConfig {
+    /// Delivers everything in order on the next tick
+    pub fn reliable() -> Self {
+        Self {
+            delay: 1..2,
+            reorder: 0.0,
+            duplicate: 0.0,
+            drop: 0.0,
+            sync_every: 10,
+        }
+    }
+
+    pub fn lossy() -> Self {
+        Self {
+            delay: 1..8,
+            reorder: 0.2,
+            duplicate: 0.1,
+            drop: 0.1,
+            sync_every: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Message<D: crate::EventData> {
+    Replicate(proto::Replicate),
+    Replicated(proto::Replicated<D>),
+}
+
+#[derive(Debug, Clone)]
+struct Envelope<D: crate::EventData> {
+    from: ReplicaId,
+    to: ReplicaId,
+    msg: Message<D>,
+}
+
+/// `N` replicas of `C` and the network between them
+pub struct Simulation<C: Crdt> {
+    replicas: BTreeMap<ReplicaId, Replicator<C, InMemoryDb<C>>>,
+    /// Keyed by delivery tick and then by send order, so delivery doesn't depend on anything but
+    /// the seed
+    in_flight: BTreeMap<(u64, u64), Envelope<C::EData>>,
+    config: NetConfig,
+    /// Group of every replica while partitioned, replicas only reach those in the same group
+    groups: Option<BTreeMap<ReplicaId, usize>>,
+    rng: SimRng,
+    now: u64,
+    clock: ManualClock,
+    sent: u64,
+    seed: u64,
+}
+
+impl<C: Crdt> Simulation<C> {
+    /// `replicas` replicas starting from `crdt`, with ids `0..replicas`
+    pub async fn new(crdt: C, replicas: usize, seed: u64, config: NetConfig) -> Self {
+        Self::with(replicas, seed, config, |_| crdt.clone()).await
+    }
+
+    /// Like [`Self::new`] for CRDTs that need to know which replica they are on
+    pub async fn with(
+        replicas: usize,
+        seed: u64,
+        config: NetConfig,
+        mut crdt: impl FnMut(ReplicaId) -> C,
+    ) -> Self {
+        let clock = ManualClock::default();
+        let mut map = BTreeMap::new();
+        for i in 0..replicas {
+            let id = ReplicaId(i as u128);
+            let replicator = Replicator::new(id, crdt(id), InMemoryDb::default())
+                .await
+                .expect("in memory store never fails");
+            map.insert(id, replicator.with_clock(clock.clone()));
+        }
+
+        Self {
+            replicas: map,
+            in_flight: BTreeMap::new(),
+            config,
+            groups: None,
+            rng: SimRng::new(seed),
+            now: 0,
+            clock,
+            sent: 0,
+            seed,
+        }
+    }
+
+    pub fn ids(&self) -> Vec<ReplicaId> {
+        self.replicas.keys().copied().collect()
+    }
+
+    /// Same generator the network uses, for picking workloads that replay with the seed
+    pub fn rng(&mut self) -> &mut SimRng {
+        &mut self.rng
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn replica(&mut self, id: ReplicaId) -> &mut Replicator<C, InMemoryDb<C>> {
+        self.replicas.get_mut(&id).expect("Unknown replica")
+    }
+
+    pub async fn command(&mut self, id: ReplicaId, cmd: C::Cmd) {
+        self.replica(id).send(Protocol::Command(cmd)).await;
+    }
+
+    /// Turns on [`Replicator::detect_equivocation`] on every replica
+    pub async fn detect_equivocation(&mut self)
+    where
+        C::EData: Hash,
+    {
+        for (id, replica) in std::mem::take(&mut self.replicas) {
+            let replica = replica.detect_equivocation().await;
+            let replica = replica.expect("in memory store never fails");
+            self.replicas.insert(id, replica);
+        }
+    }
+
+    /// Makes `id` equivocate: `cmds` run on a copy of `id` forked off its current state, and what
+    /// they make is sent to `to` as if it came from `id`. `id` itself carries on as if nothing
+    /// happened, so its next events go out under the same sequence numbers.
+    pub async fn equivocate(
+        &mut self,
+        id: ReplicaId,
+        cmds: impl IntoIterator<Item = C::Cmd>,
+        to: ReplicaId,
+    ) {
+        let replica = self.replica(id);
+        let forked_at = replica.state.seq;
+        let mut twin = Replicator {
+            store: InMemoryDb::default(),
+            state: replica.state.clone(),
+            published: None,
+            auth: replica.auth.clone(),
+            forged: vec![],
+            fingerprint: None,
+            equivocations: vec![],
+            interceptors: replica.interceptors.clone(),
+            rejected: vec![],
+            checkpoints: replica.checkpoints.clone(),
+            clock: replica.clock.clone(),
+        };
+        for cmd in cmds {
+            twin.send(Protocol::Command(cmd)).await;
+        }
+        let replicated = twin
+            .replay(id, Default::default(), forked_at + 1, u64::MAX)
+            .await;
+        self.transmit(id, to, Message::Replicated(replicated));
+    }
+
+    /// Starts `replica` pulling whatever `from` has that it doesn't
+    pub fn pull(&mut self, replica: ReplicaId, from: ReplicaId) {
+        let state = &self.replica(replica).state;
+        let replicate = proto::Replicate {
+            seq_nr: state.observed.get(&from).copied().unwrap_or(0) + 1,
+            max_count: 100,
+            filter: state.version.clone(),
+            reply_to: replica,
+        };
+        self.transmit(replica, from, Message::Replicate(replicate));
+    }
+
+    /// Splits the replicas into `groups`, replicas left out of every group end up alone in a group
+    /// of their own
+    pub fn partition(&mut self, groups: &[&[ReplicaId]]) {
+        let mut map = BTreeMap::new();
+        for (group, ids) in groups.iter().enumerate() {
+            for &id in ids.iter() {
+                map.insert(id, group);
+            }
+        }
+        for id in self.ids() {
+            let next = map.len() + groups.len();
+            map.entry(id).or_insert(next);
+        }
+        self.groups = Some(map);
+    }
+
+    pub fn heal(&mut self) {
+        self.groups = None;
+    }
+
+    pub fn reachable(&self, from: ReplicaId, to: ReplicaId) -> bool {
+        self.groups
+            .as_ref()
+            .is_none_or(|groups| groups.get(&from) == groups.get(&to))
+    }
+
+    /// Every replica pulls from every peer
+    pub fn sync_all(&mut self) {
+        let ids = self.ids();
+        for &replica in ids.iter() {
+            for &from in ids.iter().filter(|&&from| from != replica) {
+                self.pull(replica, from);
+            }
+        }
+    }
+
+    /// Advances the clock by one tick, delivering whatever is due
+    pub async fn step(&mut self) {
+        self.now += 1;
+        self.clock.set(self.now);
+        if self.config.sync_every > 0 && self.now.is_multiple_of(self.config.sync_every) {
+            self.sync_all();
+        }
+
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.key().0 > self.now {
+                break;
+            }
+            let envelope = entry.remove();
+            if self.reachable(envelope.from, envelope.to) {
+                self.deliver(envelope).await;
+            }
+        }
+    }
+
+    pub async fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.step().await;
+        }
+    }
+
+    /// Heals any partition, turns the faults off and keeps syncing until the network is empty and
+    /// the replicas agree, panics if they still don't after `rounds` rounds of [`Self::sync_all`]
+    pub async fn settle(&mut self, rounds: usize)
+    where
+        C: Checksum,
+    {
+        self.heal();
+        self.config = NetConfig {
+            sync_every: 0,
+            ..NetConfig::reliable()
+        };
+
+        for _ in 0..rounds {
+            while !self.in_flight.is_empty() {
+                self.step().await;
+            }
+            if self.converged() {
+                return;
+            }
+            self.sync_all();
+        }
+        self.assert_converged();
+    }
+
+    pub fn converged(&self) -> bool
+    where
+        C: Checksum,
+    {
+        let mut checksums = self.replicas.values().map(|replica| replica.checksum());
+        let first = checksums.next();
+        checksums.all(|checksum| Some(checksum) == first)
+    }
+
+    pub fn assert_converged(&self)
+    where
+        C: Checksum,
+    {
+        assert!(
+            self.converged(),
+            "Replicas diverged with seed {}: {:?}",
+            self.seed,
+            self.replicas
+                .iter()
+                .map(|(id, replica)| (*id, replica.checksum()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    fn transmit(&mut self, from: ReplicaId, to: ReplicaId, msg: Message<C::EData>) {
+        if !self.reachable(from, to) || self.rng.chance(self.config.drop) {
+            return;
+        }
+        let copies = if self.rng.chance(self.config.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut deliver_at = self.now + self.rng.range(self.config.delay.clone()).max(1);
+            if self.rng.chance(self.config.reorder) {
+                deliver_at += self.config.delay.end;
+            }
+            self.sent += 1;
+            self.in_flight.insert(
+                (deliver_at, self.sent),
+                Envelope {
+                    from,
+                    to,
+                    msg: msg.clone(),
+                },
+            );
+        }
+    }
+
+    async fn deliver(&mut self, envelope: Envelope<C::EData>) {
+        let (reply_to, msg) = match envelope.msg {
+            Message::Replicate(replicate) => (replicate.reply_to, Protocol::Replicate(replicate)),
+            Message::Replicated(replicated) => (replicated.from, Protocol::Replicated(replicated)),
+        };
+
+        match self.replica(envelope.to).send(msg).await {
+            Protocol::Replicate(replicate) => {
+                self.transmit(envelope.to, reply_to, Message::Replicate(replicate))
+            }
+            Protocol::Replicated(replicated) => {
+                self.transmit(envelope.to, reply_to, Message::Replicated(replicated))
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use super::{NetConfig, Simulation};
+    use crate::checksum::Checksum;
+    use crate::{counter::Counter, lseq, lseq::LSeq, orset, orset::ORSet, rga, rga::Rga};
+    use crate::{Crdt, ReplicaId, Replicator};
+
+    #[tokio::test]
+    async fn counter_converges_over_lossy_network() {
+        for seed in 0..20 {
+            let mut sim = Simulation::new(Counter::default(), 4, seed, NetConfig::lossy()).await;
+            let ids = sim.ids();
+
+            let mut total = 0;
+            for _ in 0..50 {
+                let id = *sim.rng().pick(&ids);
+                let n = sim.rng().range(0..10) as i64;
+                total += n;
+                sim.command(id, n).await;
+                let ticks = sim.rng().range(0..5);
+                sim.run(ticks).await;
+            }
+
+            sim.settle(10).await;
+            for id in ids {
+                assert_eq!(sim.replica(id).query(), total, "seed {seed}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn orset_converges_over_lossy_network() {
+        for seed in 0..20 {
+            let mut sim = Simulation::new(ORSet::<u8>::new(), 3, seed, NetConfig::lossy()).await;
+            let ids = sim.ids();
+
+            for _ in 0..50 {
+                let id = *sim.rng().pick(&ids);
+                let val = sim.rng().range(0..8) as u8;
+                let cmd = if sim.rng().chance(0.3) {
+                    orset::Command::Remove(val)
+                } else {
+                    orset::Command::Add(val)
+                };
+                sim.command(id, cmd).await;
+                let ticks = sim.rng().range(0..5);
+                sim.run(ticks).await;
+            }
+
+            sim.settle(10).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn detecting_equivocation_doesnt_get_in_the_way() {
+        for seed in 0..20 {
+            let mut sim = Simulation::new(ORSet::<u8>::new(), 3, seed, NetConfig::lossy()).await;
+            sim.detect_equivocation().await;
+            let ids = sim.ids();
+
+            for _ in 0..50 {
+                let id = *sim.rng().pick(&ids);
+                let val = sim.rng().range(0..8) as u8;
+                let cmd = if sim.rng().chance(0.3) {
+                    orset::Command::Remove(val)
+                } else {
+                    orset::Command::Add(val)
+                };
+                sim.command(id, cmd).await;
+                let ticks = sim.rng().range(0..5);
+                sim.run(ticks).await;
+                if sim.rng().chance(0.1) {
+                    sim.replica(id).compact().await;
+                }
+            }
+
+            sim.settle(10).await;
+            for id in ids {
+                let replica = sim.replica(id);
+                assert!(replica.take_equivocations().is_empty(), "seed {seed}");
+                assert_eq!(replica.quarantined().count(), 0, "seed {seed}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn quarantines_equivocating_replicas() {
+        let config = NetConfig {
+            sync_every: 0,
+            ..NetConfig::reliable()
+        };
+        let mut sim = Simulation::new(Counter::default(), 3, 0, config).await;
+        let [liar, victim, other] = [0, 1, 2].map(ReplicaId);
+        async fn drain(sim: &mut Simulation<Counter>) {
+            while sim.in_flight() > 0 {
+                sim.step().await;
+            }
+        }
+
+        // The victim is told about two events of the liar before anyone checks anything, the
+        // store gets fingerprinted when detection is turned on
+        sim.equivocate(liar, [10, 10], victim).await;
+        drain(&mut sim).await;
+        assert_eq!(sim.replica(victim).query(), 20);
+        sim.detect_equivocation().await;
+
+        // Everyone else sees the liar make a single event after one of the other's, under the
+        // same sequence number as the second event the victim got
+        sim.command(other, 1).await;
+        sim.pull(liar, other);
+        drain(&mut sim).await;
+        sim.command(liar, 1).await;
+        sim.pull(other, liar);
+        drain(&mut sim).await;
+        // The liar has nothing past what the victim got from its twin, it takes a relay
+        sim.pull(victim, other);
+        drain(&mut sim).await;
+
+        let equivocations = sim.replica(victim).take_equivocations();
+        assert_eq!(equivocations.len(), 1);
+        let equivocation = &equivocations[0];
+        assert_eq!(
+            (
+                equivocation.from,
+                equivocation.origin,
+                equivocation.origin_seq
+            ),
+            (other, liar, 2)
+        );
+        assert_ne!(equivocation.taken, equivocation.conflicting);
+        // What came before it in the batch is still taken
+        assert_eq!(sim.replica(victim).query(), 21);
+
+        // Nothing more of the liar's gets in, however it comes, and it isn't reported again
+        sim.command(liar, 100).await;
+        sim.pull(other, liar);
+        drain(&mut sim).await;
+        for _ in 0..3 {
+            sim.sync_all();
+            drain(&mut sim).await;
+        }
+        assert_eq!(sim.replica(other).query(), 102);
+        assert_eq!(sim.replica(victim).query(), 21);
+        assert!(sim.replica(victim).take_equivocations().is_empty());
+        for id in [liar, other] {
+            assert!(sim.replica(id).take_equivocations().is_empty());
+        }
+
+        // The quarantine outlives restarts
+        let store = sim.replica(victim).store.clone();
+        let restarted = Replicator::new(victim, Counter::default(), store).await;
+        let restarted = restarted.unwrap().detect_equivocation().await.unwrap();
+        assert_eq!(restarted.quarantined().collect::<Vec<_>>(), [equivocation]);
+        assert_eq!(restarted.query(), 21);
+    }
+
+    #[tokio::test]
+    async fn same_seed_same_run() {
+        let mut checksums = vec![];
+        for _ in 0..2 {
+            let mut sim = Simulation::new(Counter::default(), 3, 7, NetConfig::lossy()).await;
+            let ids = sim.ids();
+            for i in 0..20 {
+                let id = *sim.rng().pick(&ids);
+                sim.command(id, i).await;
+                sim.run(3).await;
+            }
+            checksums.push(
+                ids.iter()
+                    .map(|&id| sim.replica(id).checksum())
+                    .collect::<Vec<_>>(),
+            );
+        }
+        assert_eq!(checksums[0], checksums[1]);
+    }
+
+    /// Splits the replicas in two at random, both sides keep changing things on their own, then
+    /// the partition heals while changes keep coming in
+    async fn partition_and_heal<C: Crdt + Checksum>(
+        sim: &mut Simulation<C>,
+        mut next_cmd: impl FnMut(&mut Simulation<C>, ReplicaId) -> C::Cmd,
+    ) {
+        let ids = sim.ids();
+        let split = sim.rng().range(1..ids.len() as u64) as usize;
+        let (left, right) = ids.split_at(split);
+        sim.partition(&[left, right]);
+        assert!(!sim.reachable(left[0], right[0]));
+
+        for i in 0..60 {
+            if i == 40 {
+                sim.heal();
+            }
+            let id = *sim.rng().pick(&ids);
+            let cmd = next_cmd(sim, id);
+            sim.command(id, cmd).await;
+            let ticks = sim.rng().range(0..5);
+            sim.run(ticks).await;
+        }
+
+        sim.settle(10).await;
+    }
+
+    /// Values are unique and only ever removed after being seen, so whatever was added and not
+    /// removed must be there on every replica once healed
+    fn assert_no_lost_update(
+        seed: u64,
+        values: BTreeMap<ReplicaId, Vec<u32>>,
+        added: &BTreeSet<u32>,
+        removed: &BTreeSet<u32>,
+    ) {
+        let expected = added.difference(removed).copied().collect::<BTreeSet<_>>();
+        for (id, values) in values {
+            let unique = values.iter().copied().collect::<BTreeSet<_>>();
+            assert_eq!(
+                unique.len(),
+                values.len(),
+                "Duplicates on {id:?} with seed {seed}"
+            );
+            assert_eq!(unique, expected, "Lost updates on {id:?} with seed {seed}");
+        }
+    }
+
+    fn values<C: Crdt, I: IntoIterator<Item = u32>>(
+        sim: &mut Simulation<C>,
+        query: impl Fn(C::State) -> I,
+    ) -> BTreeMap<ReplicaId, Vec<u32>> {
+        sim.ids()
+            .into_iter()
+            .map(|id| (id, query(sim.replica(id).query()).into_iter().collect()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn orset_partition() {
+        for seed in 0..20 {
+            let mut sim = Simulation::new(ORSet::<u32>::new(), 3, seed, NetConfig::lossy()).await;
+            let (mut added, mut removed) = (BTreeSet::new(), BTreeSet::new());
+
+            partition_and_heal(&mut sim, |sim, id| {
+                let seen = sim.replica(id).query().into_iter().collect::<Vec<_>>();
+                if !seen.is_empty() && sim.rng().chance(0.3) {
+                    let val = *sim.rng().pick(&seen);
+                    removed.insert(val);
+                    orset::Command::Remove(val)
+                } else {
+                    let val = added.len() as u32;
+                    added.insert(val);
+                    orset::Command::Add(val)
+                }
+            })
+            .await;
+
+            let values = values(&mut sim, |set| set);
+            assert_no_lost_update(seed, values, &added, &removed);
+        }
+    }
+
+    #[tokio::test]
+    async fn rga_partition() {
+        for seed in 0..20 {
+            let mut sim = Simulation::with(3, seed, NetConfig::lossy(), Rga::new).await;
+            let (mut added, mut removed) = (BTreeSet::new(), BTreeSet::new());
+
+            partition_and_heal(&mut sim, |sim, id| {
+                let seen = sim.replica(id).query();
+                if !seen.is_empty() && sim.rng().chance(0.3) {
+                    let i = sim.rng().range(0..seen.len() as u64) as usize;
+                    removed.insert(seen[i]);
+                    rga::Command::RemoveAt(i as u32)
+                } else {
+                    let i = sim.rng().range(0..seen.len() as u64 + 1) as u32;
+                    let val = added.len() as u32;
+                    added.insert(val);
+                    rga::Command::Insert(i, val)
+                }
+            })
+            .await;
+
+            let values = values(&mut sim, |seq| seq);
+            assert_no_lost_update(seed, values, &added, &removed);
+        }
+    }
+
+    #[tokio::test]
+    async fn lseq_partition() {
+        for seed in 0..20 {
+            let mut sim = Simulation::with(3, seed, NetConfig::lossy(), LSeq::new).await;
+            let (mut added, mut removed) = (BTreeSet::new(), BTreeSet::new());
+
+            partition_and_heal(&mut sim, |sim, id| {
+                let seen = sim.replica(id).query();
+                if !seen.is_empty() && sim.rng().chance(0.3) {
+                    let i = sim.rng().range(0..seen.len() as u64) as usize;
+                    removed.insert(seen[i]);
+                    lseq::Command::RemoveAt(i as u32)
+                } else {
+                    let i = sim.rng().range(0..seen.len() as u64 + 1) as u32;
+                    let val = added.len() as u32;
+                    added.insert(val);
+                    lseq::Command::Insert(i, val)
+                }
+            })
+            .await;
+
+            let values = values(&mut sim, |seq| seq);
+            assert_no_lost_update(seed, values, &added, &removed);
+        }
+    }
+}
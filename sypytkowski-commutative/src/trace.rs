@@ -0,0 +1,34 @@
+//! What the `tracing` feature logs, everything here does nothing without it so the code logging
+//! doesn't need a `cfg` of its own
+use std::future::Future;
+
+/// A `tracing::debug!` event when the feature is on
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    }};
+}
+
+/// A `tracing::warn!` event when the feature is on
+macro_rules! warning {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+    }};
+}
+
+pub(crate) use {debug, warning};
+
+/// Awaits `io` on the store and logs how long it took. Not timed on wasm, which has no clock
+/// `std` can read.
+pub(crate) async fn store_io<T>(op: &'static str, io: impl Future<Output = T>) -> T {
+    #[cfg(all(feature = "tracing", not(target_arch = "wasm32")))]
+    let start = std::time::Instant::now();
+    let out = io.await;
+    #[cfg(all(feature = "tracing", not(target_arch = "wasm32")))]
+    tracing::debug!(op, elapsed_us = start.elapsed().as_micros() as u64, "store io");
+    #[cfg(not(all(feature = "tracing", not(target_arch = "wasm32"))))]
+    let _ = op;
+    out
+}
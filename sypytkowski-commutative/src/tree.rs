@@ -0,0 +1,426 @@
+//! Tree whose nodes can be moved around, see [`Tree`].
+//!
+//! Moves are the algorithm of "A highly-available move operation for replicated trees"
+//! (https://martin.kleppmann.com/papers/move-op.pdf): every move is stamped with an [`Hlc`]
+//! timestamp and applied in timestamp order, so a move replicated after moves stamped later than
+//! it undoes those, takes effect and does them again. A move that would put a node under itself
+//! is skipped, which of two moves forming a cycle concurrently loses is then the same everywhere.
+use std::collections::BTreeMap;
+
+use crate::{
+    clock::Hlc,
+    memory::{MemoryUsage, Usage},
+    Change, ChangeSummary, Crdt, Event, ReplicaId, VTime,
+};
+
+/// Where a node hangs
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Parent<N> {
+    Root,
+    Node(N),
+    /// Deleted nodes are kept here along with their children, for moves concurrent with the
+    /// deletion
+    Trash,
+}
+
+/// A node of the tree as read, `parent` is `None` right under the root
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TreeNode<N, V> {
+    pub parent: Option<N>,
+    pub value: V,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Command<N, V> {
+    /// Adds `node` under `parent`, `None` for the root. Creating a node that is there already
+    /// moves it and sets its value.
+    Create {
+        node: N,
+        parent: Option<N>,
+        value: V,
+    },
+    /// Moves `node` under `parent`, `None` for the root
+    Move { node: N, parent: Option<N> },
+    /// Removes `node` along with everything under it
+    Delete(N),
+}
+
+/// Every command is a move of `node` under `parent`
+#[derive(Debug, Clone, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Move<N, V> {
+    pub timestamp: Hlc,
+    pub node: N,
+    pub parent: Parent<N>,
+    /// Value the node is created with, `None` keeps the one it has. Moves of nodes that aren't
+    /// there without one do nothing.
+    pub value: Option<V>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct LogEntry<N, V> {
+    op: Move<N, V>,
+    origin: ReplicaId,
+    /// Events of `origin` up to this one, as counted in versions
+    seq: u64,
+    /// Where the node was before, `None` if it wasn't there
+    before: Option<(Parent<N>, V)>,
+}
+
+/// Replicated tree of nodes with ids `N` holding a `V` each.
+///
+/// Nodes under a parent that isn't there, because its creation didn't replicate yet or it was
+/// never created, are left out of [`Crdt::query`] until it turns up.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "N: serde::Serialize, V: serde::Serialize",
+        deserialize = "N: Ord + serde::Deserialize<'de>, V: serde::Deserialize<'de>"
+    ))
+)]
+pub struct Tree<N, V> {
+    id: ReplicaId,
+    nodes: BTreeMap<N, (Parent<N>, V)>,
+    /// Moves not known to be stable yet, by timestamp
+    log: Vec<LogEntry<N, V>>,
+    /// Latest timestamp seen
+    timestamp: Option<Hlc>,
+}
+
+impl<N: Ord, V> Tree<N, V> {
+    pub fn new(id: ReplicaId) -> Self {
+        Self {
+            id,
+            nodes: BTreeMap::new(),
+            log: Vec::new(),
+            timestamp: None,
+        }
+    }
+
+    /// Whether `ancestor` is above `node`, trashed nodes included
+    pub fn is_ancestor(&self, ancestor: &N, node: &N) -> bool {
+        let mut node = node;
+        while let Some((Parent::Node(parent), _)) = self.nodes.get(node) {
+            if parent == ancestor {
+                return true;
+            }
+            node = parent;
+        }
+        false
+    }
+
+    /// Whether `node` is in the tree, and not deleted or under a parent that isn't there
+    pub fn contains(&self, node: &N) -> bool {
+        let mut node = node;
+        loop {
+            match self.nodes.get(node) {
+                Some((Parent::Root, _)) => return true,
+                Some((Parent::Node(parent), _)) => node = parent,
+                Some((Parent::Trash, _)) | None => return false,
+            }
+        }
+    }
+
+    pub fn get(&self, node: &N) -> Option<&V> {
+        let (_, value) = self.nodes.get(node)?;
+        self.contains(node).then_some(value)
+    }
+
+    /// Nodes right under `parent`, `None` for the root
+    pub fn children<'a>(&'a self, parent: Option<&'a N>) -> impl Iterator<Item = &'a N> + 'a {
+        let parent = parent.map_or(Parent::Root, Parent::Node);
+        (self.nodes.iter())
+            .filter(move |(node, (at, _))| at.as_ref() == parent && self.contains(node))
+            .map(|(node, _)| node)
+    }
+
+    /// Puts the node of `entry` back where it was before
+    fn undo(&mut self, entry: &LogEntry<N, V>)
+    where
+        N: Clone,
+        V: Clone,
+    {
+        match &entry.before {
+            Some(before) => self.nodes.insert(entry.op.node.clone(), before.clone()),
+            None => self.nodes.remove(&entry.op.node),
+        };
+    }
+
+    /// Moves the node of `entry` unless that makes a cycle, and logs it
+    fn apply(&mut self, mut entry: LogEntry<N, V>)
+    where
+        N: Clone,
+        V: Clone,
+    {
+        entry.before = self.nodes.get(&entry.op.node).cloned();
+        let op = &entry.op;
+        let cycle = match &op.parent {
+            Parent::Node(parent) => parent == &op.node || self.is_ancestor(&op.node, parent),
+            Parent::Root | Parent::Trash => false,
+        };
+        let value = (op.value.clone()).or_else(|| entry.before.clone().map(|(_, value)| value));
+        if let (false, Some(value)) = (cycle, value) {
+            self.nodes
+                .insert(op.node.clone(), (op.parent.clone(), value));
+        }
+        self.log.push(entry);
+    }
+}
+
+impl<N> Parent<N> {
+    fn as_ref(&self) -> Parent<&N> {
+        match self {
+            Parent::Root => Parent::Root,
+            Parent::Node(node) => Parent::Node(node),
+            Parent::Trash => Parent::Trash,
+        }
+    }
+}
+
+impl<N, V> Crdt for Tree<N, V>
+where
+    N: Ord + Clone + Send + Sync + std::fmt::Debug,
+    V: Clone + Send + Sync + std::fmt::Debug,
+{
+    type State = BTreeMap<N, TreeNode<N, V>>;
+    type Cmd = Command<N, V>;
+    type EData = Move<N, V>;
+
+    fn query(&self) -> Self::State {
+        (self.nodes.iter())
+            .filter(|(node, _)| self.contains(node))
+            .map(|(node, (parent, value))| {
+                let parent = match parent {
+                    Parent::Node(parent) => Some(parent.clone()),
+                    Parent::Root | Parent::Trash => None,
+                };
+                let value = value.clone();
+                (node.clone(), TreeNode { parent, value })
+            })
+            .collect()
+    }
+
+    /// Without a clock moves only count up from the last one
+    fn prepare(&self, op: Self::Cmd) -> Self::EData {
+        self.prepare_at(op, Hlc::zero(self.id.into()))
+    }
+
+    /// Moves are stamped after every move seen, so they are applied after the ones they saw
+    fn prepare_at(&self, op: Self::Cmd, now: Hlc) -> Self::EData {
+        let after = self.timestamp.map(|last| last.tick(now.replica));
+        let timestamp = after.map_or(now, |after| after.max(now));
+        let parent = |parent: Option<N>| parent.map_or(Parent::Root, Parent::Node);
+        let (node, parent, value) = match op {
+            Command::Create {
+                node,
+                parent: at,
+                value,
+            } => (node, parent(at), Some(value)),
+            Command::Move { node, parent: at } => (node, parent(at), None),
+            Command::Delete(node) => (node, Parent::Trash, None),
+        };
+        Move {
+            timestamp,
+            node,
+            parent,
+            value,
+        }
+    }
+
+    fn effect(&mut self, event: Event<Self::EData>) {
+        let entry = LogEntry {
+            origin: event.origin,
+            seq: event
+                .version
+                .get(&event.origin)
+                .copied()
+                .unwrap_or_default(),
+            op: event.data,
+            before: None,
+        };
+        let timestamp = entry.op.timestamp;
+        self.timestamp = self.timestamp.max(Some(timestamp));
+
+        let at = self.log.partition_point(|e| e.op.timestamp < timestamp);
+        let later = self.log.split_off(at);
+        for entry in later.iter().rev() {
+            self.undo(entry);
+        }
+        self.apply(entry);
+        for entry in later {
+            self.apply(entry);
+        }
+    }
+
+    /// Moves replicas make from now on are stamped after the stable ones, nothing before the
+    /// last of those is undone anymore
+    fn compact(&mut self, stable: &VTime) {
+        let is_stable = |entry: &LogEntry<N, V>| {
+            stable.get(&entry.origin).copied().unwrap_or_default() >= entry.seq
+        };
+        if let Some(last) = self.log.iter().rposition(is_stable) {
+            self.log.drain(..=last);
+        }
+    }
+
+    fn rebind(&mut self, id: ReplicaId) {
+        self.id = id;
+    }
+
+    fn describe(&self, event: &Event<Self::EData>) -> ChangeSummary {
+        let Move {
+            node,
+            parent,
+            value,
+            ..
+        } = &event.data;
+        let change = match (parent, value) {
+            (Parent::Trash, _) => Change::Removed {
+                value: format!("{node:?}"),
+            },
+            (_, Some(_)) if !self.nodes.contains_key(node) => Change::Added {
+                value: format!("{node:?}"),
+            },
+            _ => Change::Unknown,
+        };
+        ChangeSummary {
+            origin: event.origin,
+            change,
+        }
+    }
+}
+
+impl<N, V> MemoryUsage for Tree<N, V> {
+    fn memory_usage(&self) -> Usage {
+        Usage::btree::<N, (Parent<N>, V)>(self.nodes.len())
+            + Usage::vec::<LogEntry<N, V>>(self.log.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        clock::ManualClock, memdb::InMemoryDb, protocol::Protocol, replicate, ReplicaId, Replicator,
+    };
+
+    use super::{Command, Tree};
+
+    type Replica = Replicator<Tree<&'static str, u32>, InMemoryDb<Tree<&'static str, u32>>>;
+
+    async fn replica(id: u128, time: &ManualClock) -> Replica {
+        let id = ReplicaId::new(id);
+        Replicator::new(id, Tree::new(id), InMemoryDb::default())
+            .await
+            .unwrap()
+            .with_clock(time.clone())
+    }
+
+    async fn create(replica: &mut Replica, node: &'static str, parent: Option<&'static str>) {
+        let value = replica.query().len() as u32;
+        let cmd = Command::Create {
+            node,
+            parent,
+            value,
+        };
+        replica.send(Protocol::Command(cmd)).await;
+    }
+
+    async fn move_to(replica: &mut Replica, node: &'static str, parent: Option<&'static str>) {
+        let cmd = Command::Move { node, parent };
+        replica.send(Protocol::Command(cmd)).await;
+    }
+
+    fn parent(replica: &Replica, node: &str) -> Option<Option<&'static str>> {
+        replica.query().get(node).map(|node| node.parent)
+    }
+
+    #[tokio::test]
+    async fn moves_nodes_under_each_other() {
+        let time = ManualClock::new(10);
+        let mut alice = replica(1, &time).await;
+        let mut bob = replica(2, &time).await;
+        create(&mut alice, "a", None).await;
+        create(&mut alice, "b", None).await;
+        replicate(&mut bob, &mut alice).await;
+
+        // Alice moves a under b while Bob moves b under a, the later move would make a cycle
+        move_to(&mut alice, "a", Some("b")).await;
+        time.advance(1);
+        move_to(&mut bob, "b", Some("a")).await;
+        replicate(&mut alice, &mut bob).await;
+        replicate(&mut bob, &mut alice).await;
+
+        for replica in [&alice, &bob] {
+            assert_eq!(parent(replica, "a"), Some(Some("b")));
+            assert_eq!(parent(replica, "b"), Some(None));
+            assert!(replica.crdt().is_ancestor(&"b", &"a"));
+        }
+        assert_eq!(alice.checksum(), bob.checksum());
+    }
+
+    #[tokio::test]
+    async fn applies_moves_in_timestamp_order() {
+        let time = ManualClock::new(10);
+        let mut alice = replica(1, &time).await;
+        let mut bob = replica(2, &time).await;
+        for node in ["a", "b", "c"] {
+            create(&mut alice, node, None).await;
+        }
+        replicate(&mut bob, &mut alice).await;
+
+        // Alice's move is earlier but reaches Bob after his own, which he has to undo
+        move_to(&mut alice, "a", Some("b")).await;
+        time.advance(1);
+        move_to(&mut bob, "b", Some("c")).await;
+        move_to(&mut bob, "c", Some("a")).await;
+        replicate(&mut alice, &mut bob).await;
+        replicate(&mut bob, &mut alice).await;
+
+        for replica in [&alice, &bob] {
+            assert_eq!(parent(replica, "a"), Some(Some("b")));
+            assert_eq!(parent(replica, "b"), Some(Some("c")));
+            assert_eq!(parent(replica, "c"), Some(None));
+        }
+    }
+
+    #[tokio::test]
+    async fn deletes_subtrees() {
+        let time = ManualClock::new(10);
+        let mut alice = replica(1, &time).await;
+        let mut bob = replica(2, &time).await;
+        create(&mut alice, "docs", None).await;
+        create(&mut alice, "notes", Some("docs")).await;
+        replicate(&mut bob, &mut alice).await;
+
+        // Bob adds to the folder Alice deletes
+        let delete = Command::Delete("docs");
+        alice.send(Protocol::Command(delete)).await;
+        create(&mut bob, "todo", Some("notes")).await;
+        replicate(&mut alice, &mut bob).await;
+        replicate(&mut bob, &mut alice).await;
+        for replica in [&alice, &bob] {
+            assert!(replica.query().is_empty());
+            assert!(!replica.crdt().contains(&"todo"));
+        }
+
+        // Moving a node out of the trash brings it back with what's under it
+        move_to(&mut bob, "notes", None).await;
+        replicate(&mut alice, &mut bob).await;
+        let children = alice.crdt().children(Some(&"notes")).collect::<Vec<_>>();
+        assert_eq!(children, [&"todo"]);
+        assert_eq!(alice.crdt().get(&"todo"), Some(&2));
+        assert_eq!(alice.query().len(), 2);
+
+        replicate(&mut bob, &mut alice).await;
+        alice.compact().await;
+        bob.compact().await;
+        assert!(alice.crdt().log.is_empty() && bob.crdt().log.is_empty());
+        assert_eq!(alice.query(), bob.query());
+    }
+}
@@ -0,0 +1,985 @@
+//! Collaborating on text with [Yjs](https://yjs.dev) through its v1 update format.
+//!
+//! [`Update`] encodes and decodes Yjs updates: the structs each client inserted, identified by the
+//! client and a clock counting UTF-16 code units, and a delete set of the clock ranges that were
+//! removed. [`YText`] keeps the items of one root `Y.Text` the way Yjs does and places the ones
+//! it gets with the same YATA rules, so it ends up in the same order as every Yjs peer. It
+//! translates between the two sides:
+//!
+//! - [`YText::apply_update`] integrates an update from Yjs and returns the [`Edit`]s that make an
+//!   [`Rga`](crate::rga::Rga) or [`LSeq`](crate::lseq::LSeq) replica's text match it, to be sent
+//!   as commands
+//! - [`YText::update_from`] takes the replica's text after it changed and returns a Yjs update
+//!   with the inserts and deletes that turn the Yjs text into it
+//!
+//! Edits coming from other replicas of this crate reach Yjs the same way as local ones, as a diff
+//! of the replica's text, so only one replica should talk to Yjs for a document. Formatting and
+//! embeds in the text are kept in place but don't show up in the replica. Structs of other shared
+//! types of the Yjs document are only kept track of so their clocks line up.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use crate::{lseq, rga};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum YjsError {
+    UnexpectedEnd,
+    InvalidUtf8,
+    /// A content type this module can't skip over, like nested shared types
+    Unsupported(u8),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for YjsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            YjsError::UnexpectedEnd => write!(f, "update ended early"),
+            YjsError::InvalidUtf8 => write!(f, "string isn't valid UTF-8"),
+            YjsError::Unsupported(content) => write!(f, "unsupported content type {content}"),
+            YjsError::Invalid(what) => write!(f, "invalid update: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for YjsError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Id {
+    pub client: u64,
+    pub clock: u64,
+}
+
+impl Id {
+    pub fn new(client: u64, clock: u64) -> Self {
+        Self { client, clock }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Parent {
+    /// A shared type of the document by its name
+    Root(String),
+    /// The shared type held by an item
+    Item(Id),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Content {
+    /// Removed content that was garbage collected, by its length
+    Deleted(u64),
+    Json(Vec<String>),
+    Binary(Vec<u8>),
+    String(String),
+    /// JSON of an embed in text
+    Embed(String),
+    /// Formatting attribute of text, the value is JSON
+    Format {
+        key: String,
+        value: String,
+    },
+}
+
+impl Content {
+    /// Clock units it takes up, strings count UTF-16 code units like JS does
+    pub fn len(&self) -> u64 {
+        match self {
+            Content::Deleted(len) => *len,
+            Content::Json(vals) => vals.len() as u64,
+            Content::String(s) => s.encode_utf16().count() as u64,
+            Content::Binary(_) | Content::Embed(_) | Content::Format { .. } => 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Content::Deleted(_) => 1,
+            Content::Json(_) => 2,
+            Content::Binary(_) => 3,
+            Content::String(_) => 4,
+            Content::Embed(_) => 5,
+            Content::Format { .. } => 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub id: Id,
+    /// Last unit of what was left of the item when it was inserted
+    pub origin: Option<Id>,
+    /// What was right of it when it was inserted
+    pub right_origin: Option<Id>,
+    /// Only encoded when there's no origin to take it from
+    pub parent: Option<Parent>,
+    /// Key of the item in a map
+    pub parent_sub: Option<String>,
+    pub content: Content,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Struct {
+    Item(Item),
+    /// Garbage collected items
+    Gc {
+        id: Id,
+        len: u64,
+    },
+    /// A gap in an update that has no structs for it
+    Skip {
+        id: Id,
+        len: u64,
+    },
+}
+
+impl Struct {
+    pub fn id(&self) -> Id {
+        match self {
+            Struct::Item(item) => item.id,
+            Struct::Gc { id, .. } | Struct::Skip { id, .. } => *id,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        match self {
+            Struct::Item(item) => item.content.len(),
+            Struct::Gc { len, .. } | Struct::Skip { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A Yjs v1 update
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Update {
+    /// Each client's structs have consecutive clocks
+    pub structs: Vec<Struct>,
+    /// Removed `(clock, len)` ranges of each client
+    pub deletes: BTreeMap<u64, Vec<(u64, u64)>>,
+}
+
+impl Update {
+    pub fn decode(bytes: &[u8]) -> Result<Self, YjsError> {
+        let mut input = Reader(bytes);
+        let mut structs = vec![];
+        for _ in 0..input.varint()? {
+            let count = input.varint()?;
+            let client = input.varint()?;
+            let mut clock = input.varint()?;
+            for _ in 0..count {
+                let s = input.read_struct(Id::new(client, clock))?;
+                clock += s.len();
+                structs.push(s);
+            }
+        }
+
+        let mut deletes = BTreeMap::new();
+        for _ in 0..input.varint()? {
+            let client = input.varint()?;
+            let ranges = (0..input.varint()?)
+                .map(|_| Ok((input.varint()?, input.varint()?)))
+                .collect::<Result<Vec<_>, YjsError>>()?;
+            deletes.insert(client, ranges);
+        }
+        if !input.0.is_empty() {
+            return Err(YjsError::Invalid("trailing bytes"));
+        }
+        Ok(Self { structs, deletes })
+    }
+
+    /// Clients are written from the highest id down like Yjs does
+    pub fn encode(&self) -> Vec<u8> {
+        let mut clients: BTreeMap<u64, Vec<&Struct>> = BTreeMap::new();
+        for s in self.structs.iter() {
+            clients.entry(s.id().client).or_default().push(s);
+        }
+
+        let mut out = vec![];
+        write_varint(&mut out, clients.len() as u64);
+        for (client, structs) in clients.iter_mut().rev() {
+            structs.sort_by_key(|s| s.id().clock);
+            // Clocks only count up from the first struct, gaps between structs need skips
+            let mut run = vec![];
+            let mut clock = structs[0].id().clock;
+            for s in structs.iter() {
+                if s.id().clock > clock {
+                    run.push(Struct::Skip {
+                        id: Id::new(*client, clock),
+                        len: s.id().clock - clock,
+                    });
+                }
+                run.push((*s).clone());
+                clock = s.id().clock + s.len();
+            }
+            write_varint(&mut out, run.len() as u64);
+            write_varint(&mut out, *client);
+            write_varint(&mut out, structs[0].id().clock);
+            for s in run.iter() {
+                write_struct(&mut out, s);
+            }
+        }
+
+        write_varint(&mut out, self.deletes.len() as u64);
+        for (client, ranges) in self.deletes.iter().rev() {
+            write_varint(&mut out, *client);
+            write_varint(&mut out, ranges.len() as u64);
+            for (clock, len) in ranges {
+                write_varint(&mut out, *clock);
+                write_varint(&mut out, *len);
+            }
+        }
+        out
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, YjsError> {
+        let (&byte, rest) = self.0.split_first().ok_or(YjsError::UnexpectedEnd)?;
+        self.0 = rest;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, YjsError> {
+        let mut val = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            val |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err(YjsError::Invalid("varint too long"))
+    }
+
+    fn bytes(&mut self) -> Result<&[u8], YjsError> {
+        let len = self.varint()? as usize;
+        if len > self.0.len() {
+            return Err(YjsError::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, YjsError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| YjsError::InvalidUtf8)
+    }
+
+    fn id(&mut self) -> Result<Id, YjsError> {
+        Ok(Id::new(self.varint()?, self.varint()?))
+    }
+
+    fn read_struct(&mut self, id: Id) -> Result<Struct, YjsError> {
+        let info = self.byte()?;
+        match info & 0x1f {
+            0 => {
+                return Ok(Struct::Gc {
+                    id,
+                    len: self.varint()?,
+                })
+            }
+            10 => {
+                return Ok(Struct::Skip {
+                    id,
+                    len: self.varint()?,
+                })
+            }
+            _ => {}
+        }
+
+        let origin = (info & 0x80 != 0).then(|| self.id()).transpose()?;
+        let right_origin = (info & 0x40 != 0).then(|| self.id()).transpose()?;
+        let (mut parent, mut parent_sub) = (None, None);
+        if origin.is_none() && right_origin.is_none() {
+            parent = Some(match self.varint()? {
+                1 => Parent::Root(self.string()?),
+                _ => Parent::Item(self.id()?),
+            });
+            if info & 0x20 != 0 {
+                parent_sub = Some(self.string()?);
+            }
+        }
+
+        let content = match info & 0x1f {
+            1 => Content::Deleted(self.varint()?),
+            2 => Content::Json(
+                (0..self.varint()?)
+                    .map(|_| self.string())
+                    .collect::<Result<_, _>>()?,
+            ),
+            3 => Content::Binary(self.bytes()?.to_vec()),
+            4 => Content::String(self.string()?),
+            5 => Content::Embed(self.string()?),
+            6 => Content::Format {
+                key: self.string()?,
+                value: self.string()?,
+            },
+            other => return Err(YjsError::Unsupported(other)),
+        };
+        Ok(Struct::Item(Item {
+            id,
+            origin,
+            right_origin,
+            parent,
+            parent_sub,
+            content,
+        }))
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut val: u64) {
+    while val >= 0x80 {
+        out.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_id(out: &mut Vec<u8>, id: Id) {
+    write_varint(out, id.client);
+    write_varint(out, id.clock);
+}
+
+fn write_struct(out: &mut Vec<u8>, s: &Struct) {
+    let item = match s {
+        Struct::Gc { len, .. } => {
+            out.push(0);
+            return write_varint(out, *len);
+        }
+        Struct::Skip { len, .. } => {
+            out.push(10);
+            return write_varint(out, *len);
+        }
+        Struct::Item(item) => item,
+    };
+
+    let has_parent = item.origin.is_none() && item.right_origin.is_none();
+    let mut info = item.content.tag();
+    if item.origin.is_some() {
+        info |= 0x80;
+    }
+    if item.right_origin.is_some() {
+        info |= 0x40;
+    }
+    if has_parent && item.parent_sub.is_some() {
+        info |= 0x20;
+    }
+    out.push(info);
+
+    if let Some(origin) = item.origin {
+        write_id(out, origin);
+    }
+    if let Some(right_origin) = item.right_origin {
+        write_id(out, right_origin);
+    }
+    if has_parent {
+        match &item.parent {
+            Some(Parent::Root(name)) => {
+                write_varint(out, 1);
+                write_bytes(out, name.as_bytes());
+            }
+            Some(Parent::Item(id)) => {
+                write_varint(out, 0);
+                write_id(out, *id);
+            }
+            None => {
+                write_varint(out, 1);
+                write_bytes(out, b"");
+            }
+        }
+        if let Some(sub) = &item.parent_sub {
+            write_bytes(out, sub.as_bytes());
+        }
+    }
+
+    match &item.content {
+        Content::Deleted(len) => write_varint(out, *len),
+        Content::Json(vals) => {
+            write_varint(out, vals.len() as u64);
+            for val in vals {
+                write_bytes(out, val.as_bytes());
+            }
+        }
+        Content::Binary(bytes) => write_bytes(out, bytes),
+        Content::String(s) | Content::Embed(s) => write_bytes(out, s.as_bytes()),
+        Content::Format { key, value } => {
+            write_bytes(out, key.as_bytes());
+            write_bytes(out, value.as_bytes());
+        }
+    }
+}
+
+/// A change to the replica's text, by index into its values
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit<V> {
+    Insert(u32, V),
+    Remove(u32),
+}
+
+impl<V> From<Edit<V>> for rga::Command<V> {
+    fn from(edit: Edit<V>) -> Self {
+        match edit {
+            Edit::Insert(i, val) => rga::Command::Insert(i, val),
+            Edit::Remove(i) => rga::Command::RemoveAt(i),
+        }
+    }
+}
+
+impl<V: fmt::Debug> From<Edit<V>> for lseq::Command<V> {
+    fn from(edit: Edit<V>) -> Self {
+        match edit {
+            Edit::Insert(i, val) => lseq::Command::Insert(i, val),
+            Edit::Remove(i) => lseq::Command::RemoveAt(i),
+        }
+    }
+}
+
+/// One unit of the text: a character, or formatting or an embed that takes up a position without
+/// being part of the text
+#[derive(Debug, Clone)]
+struct Block {
+    id: Id,
+    origin: Option<Id>,
+    right_origin: Option<Id>,
+    /// A single character or a single unit of other content
+    content: Content,
+    deleted: bool,
+}
+
+impl Block {
+    fn char(&self) -> Option<char> {
+        match &self.content {
+            Content::String(s) if !self.deleted => s.chars().next(),
+            _ => None,
+        }
+    }
+
+    fn last(&self) -> Id {
+        Id::new(self.id.client, self.id.clock + self.content.len() - 1)
+    }
+}
+
+/// A root `Y.Text` of a Yjs document kept in step with a replica's text, see the
+/// [module docs](self)
+#[derive(Debug, Clone)]
+pub struct YText {
+    /// The Yjs client id of the replica's edits
+    client: u64,
+    name: String,
+    /// Removed blocks stay, later inserts may be placed relative to them
+    blocks: Vec<Block>,
+    /// Next clock of every client
+    state: BTreeMap<u64, u64>,
+    /// Clock ranges of structs that aren't part of the text, by their start
+    foreign: BTreeMap<Id, u64>,
+    /// Items waiting for what they refer to
+    pending: Vec<Item>,
+    /// Deletes of clocks that haven't arrived yet
+    pending_deletes: Vec<(Id, u64)>,
+}
+
+impl YText {
+    /// `client` is the id the replica's edits are made under on the Yjs side, Yjs picks random
+    /// 32 bit ones. `name` is the name of the text in the Yjs document, as in `doc.getText(name)`.
+    pub fn new(client: u64, name: &str) -> Self {
+        Self {
+            client,
+            name: name.to_owned(),
+            blocks: vec![],
+            state: BTreeMap::new(),
+            foreign: BTreeMap::new(),
+            pending: vec![],
+            pending_deletes: vec![],
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.blocks.iter().filter_map(Block::char).collect()
+    }
+
+    /// Next clock of every client, Yjs' state vector
+    pub fn state_vector(&self) -> &BTreeMap<u64, u64> {
+        &self.state
+    }
+
+    /// Integrates a Yjs update, returning what to apply to the replica in order. Structs whose
+    /// dependencies haven't arrived yet are kept until they do.
+    pub fn apply_update(&mut self, update: &[u8]) -> Result<Vec<Edit<char>>, YjsError> {
+        let update = Update::decode(update)?;
+        let mut edits = vec![];
+
+        for s in update.structs {
+            match s {
+                Struct::Item(item) => self.pending.push(item),
+                Struct::Gc { id, len } if self.clock(id.client) == id.clock => {
+                    self.foreign.insert(id, len);
+                    self.state.insert(id.client, id.clock + len);
+                }
+                Struct::Gc { id, len } => self.pending.push(Item {
+                    id,
+                    origin: None,
+                    right_origin: None,
+                    parent: Some(Parent::Item(id)),
+                    parent_sub: None,
+                    content: Content::Deleted(len),
+                }),
+                Struct::Skip { .. } => {}
+            }
+        }
+        self.pending.sort_by_key(|item| item.id);
+        while let Some(i) = self.pending.iter().position(|item| self.is_ready(item)) {
+            let item = self.pending.remove(i);
+            self.integrate(item, &mut edits);
+        }
+
+        for (client, ranges) in update.deletes {
+            for (clock, len) in ranges {
+                self.pending_deletes.push((Id::new(client, clock), len));
+            }
+        }
+        for (start, len) in std::mem::take(&mut self.pending_deletes) {
+            self.delete(start, len, &mut edits);
+        }
+        Ok(edits)
+    }
+
+    /// Turns the Yjs text into `text`, the replica's values after it changed, and returns the
+    /// update that does the same to Yjs peers. `None` if the text didn't change.
+    pub fn update_from(&mut self, text: &[char]) -> Option<Vec<u8>> {
+        let live = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, block)| block.char().map(|c| (i, c)))
+            .collect::<Vec<_>>();
+        let prefix = live
+            .iter()
+            .zip(text)
+            .take_while(|((_, a), b)| a == *b)
+            .count();
+        let suffix = live[prefix..]
+            .iter()
+            .rev()
+            .zip(text[prefix..].iter().rev())
+            .take_while(|((_, a), b)| a == *b)
+            .count();
+        let removed = &live[prefix..live.len() - suffix];
+        let inserted = &text[prefix..text.len() - suffix];
+        if removed.is_empty() && inserted.is_empty() {
+            return None;
+        }
+
+        let mut update = Update::default();
+        for &(i, _) in removed {
+            self.blocks[i].deleted = true;
+            let Id { client, clock } = self.blocks[i].id;
+            push_range(update.deletes.entry(client).or_default(), clock, 1);
+        }
+
+        if !inserted.is_empty() {
+            // Right after the last value that stays in front, tombstones after it go after the
+            // new values
+            let mut at = match prefix {
+                0 => 0,
+                prefix => live[prefix - 1].0 + 1,
+            };
+            let mut origin = at.checked_sub(1).map(|i| self.blocks[i].last());
+            let right_origin = self.blocks.get(at).map(|block| block.id);
+            let start = Id::new(self.client, self.clock(self.client));
+
+            for &c in inserted {
+                let block = Block {
+                    id: Id::new(self.client, self.clock(self.client)),
+                    origin,
+                    right_origin,
+                    content: Content::String(c.to_string()),
+                    deleted: false,
+                };
+                origin = Some(block.last());
+                self.state
+                    .insert(self.client, block.id.clock + block.content.len());
+                self.blocks.insert(at, block);
+                at += 1;
+            }
+
+            let first = at - inserted.len();
+            update.structs.push(Struct::Item(Item {
+                id: start,
+                origin: self.blocks[first].origin,
+                right_origin,
+                parent: Some(Parent::Root(self.name.clone())),
+                parent_sub: None,
+                content: Content::String(inserted.iter().collect()),
+            }));
+        }
+        Some(update.encode())
+    }
+
+    /// Everything as one update, for a Yjs document to start from. Blocks that were inserted
+    /// together are written as one struct like Yjs does, removed ones as deleted content.
+    pub fn encode_state_as_update(&self) -> Vec<u8> {
+        let position = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.id, i))
+            .collect::<HashMap<_, _>>();
+        let mut ids = position.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+
+        let mut update = Update::default();
+        let mut last: Option<(usize, &Block)> = None;
+        for id in ids {
+            let i = position[&id];
+            let block = &self.blocks[i];
+            if block.deleted {
+                push_range(
+                    update.deletes.entry(id.client).or_default(),
+                    id.clock,
+                    block.content.len(),
+                );
+            }
+
+            let content = match (&block.content, block.deleted) {
+                (content, true) => Content::Deleted(content.len()),
+                (content, false) => content.clone(),
+            };
+            let merges = last.is_some_and(|(j, prev)| {
+                j + 1 == i
+                    && prev.last().client == id.client
+                    && prev.last().clock + 1 == id.clock
+                    && block.origin == Some(prev.last())
+                    && block.right_origin == prev.right_origin
+            });
+            match (update.structs.last_mut(), merges, content) {
+                (
+                    Some(Struct::Item(Item {
+                        content: Content::String(s),
+                        ..
+                    })),
+                    true,
+                    Content::String(c),
+                ) => s.push_str(&c),
+                (
+                    Some(Struct::Item(Item {
+                        content: Content::Deleted(len),
+                        ..
+                    })),
+                    true,
+                    Content::Deleted(n),
+                ) => *len += n,
+                (_, _, content) => update.structs.push(Struct::Item(Item {
+                    id,
+                    origin: block.origin,
+                    right_origin: block.right_origin,
+                    parent: Some(Parent::Root(self.name.clone())),
+                    parent_sub: None,
+                    content,
+                })),
+            }
+            last = Some((i, block));
+        }
+
+        for (&id, &len) in self.foreign.iter() {
+            update.structs.push(Struct::Gc { id, len });
+        }
+        update.encode()
+    }
+
+    fn clock(&self, client: u64) -> u64 {
+        self.state.get(&client).copied().unwrap_or_default()
+    }
+
+    fn has(&self, id: Id) -> bool {
+        id.clock < self.clock(id.client)
+    }
+
+    fn is_foreign(&self, id: Id) -> bool {
+        self.foreign
+            .range(..=id)
+            .next_back()
+            .is_some_and(|(start, len)| start.client == id.client && id.clock < start.clock + len)
+    }
+
+    /// Yjs sends items whose clocks this already has again, a struct is only integrated once it
+    /// continues its client's clocks and whatever it's placed next to is here
+    fn is_ready(&self, item: &Item) -> bool {
+        item.id.clock <= self.clock(item.id.client)
+            && item.origin.is_none_or(|id| self.has(id))
+            && item.right_origin.is_none_or(|id| self.has(id))
+            && match &item.parent {
+                Some(Parent::Item(id)) if *id != item.id => self.has(*id),
+                _ => true,
+            }
+    }
+
+    fn index_of(&self, id: Id) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|block| block.id.client == id.client && block.id.clock == id.clock)
+    }
+
+    /// Places the units of `item` this doesn't have yet
+    fn integrate(&mut self, item: Item, edits: &mut Vec<Edit<char>>) {
+        let known = self.clock(item.id.client) - item.id.clock;
+        let len = item.content.len();
+        if known >= len {
+            return;
+        }
+        self.state.insert(item.id.client, item.id.clock + len);
+
+        let ours = match (&item.origin, &item.right_origin, &item.parent) {
+            (None, None, Some(Parent::Root(name))) => {
+                *name == self.name && item.parent_sub.is_none()
+            }
+            (None, None, _) => false,
+            (origin, right_origin, _) => origin
+                .or(*right_origin)
+                .is_some_and(|id| !self.is_foreign(id)),
+        };
+        if !ours {
+            self.foreign.insert(item.id, len);
+            return;
+        }
+
+        let units: Vec<Content> = match &item.content {
+            Content::String(s) => s.chars().map(|c| Content::String(c.to_string())).collect(),
+            Content::Deleted(len) => (0..*len).map(|_| Content::Deleted(1)).collect(),
+            Content::Json(vals) => vals
+                .iter()
+                .map(|v| Content::Json(vec![v.clone()]))
+                .collect(),
+            content => vec![content.clone()],
+        };
+        let mut clock = item.id.clock;
+        let mut origin = item.origin;
+        for content in units {
+            let block = Block {
+                id: Id::new(item.id.client, clock),
+                origin,
+                right_origin: item.right_origin,
+                deleted: matches!(content, Content::Deleted(_)),
+                content,
+            };
+            clock += block.content.len();
+            origin = Some(block.last());
+            if block.id.clock + block.content.len() <= item.id.clock + known {
+                continue;
+            }
+            self.place(block, edits);
+        }
+    }
+
+    /// YATA: between the block's origin and its right origin, skip over blocks that go before it
+    /// and stop at the first one that goes after it
+    fn place(&mut self, block: Block, edits: &mut Vec<Edit<char>>) {
+        let mut left = block.origin.and_then(|id| self.index_of_unit(id));
+        let end = block
+            .right_origin
+            .and_then(|id| self.index_of_unit(id))
+            .unwrap_or(self.blocks.len());
+
+        let mut before_origin = HashSet::new();
+        let mut conflicting = HashSet::new();
+        let mut o = left.map_or(0, |l| l + 1);
+        while o < end {
+            let other = &self.blocks[o];
+            before_origin.insert(other.id);
+            conflicting.insert(other.id);
+            if other.origin == block.origin {
+                if other.id.client < block.id.client {
+                    left = Some(o);
+                    conflicting.clear();
+                } else if other.right_origin == block.right_origin {
+                    break;
+                }
+            } else if let Some(other_origin) = other
+                .origin
+                .and_then(|id| self.index_of_unit(id))
+                .map(|i| self.blocks[i].id)
+                .filter(|id| before_origin.contains(id))
+            {
+                if !conflicting.contains(&other_origin) {
+                    left = Some(o);
+                    conflicting.clear();
+                }
+            } else {
+                break;
+            }
+            o += 1;
+        }
+
+        let at = left.map_or(0, |l| l + 1);
+        if let Some(c) = block.char() {
+            edits.push(Edit::Insert(self.live_before(at), c));
+        }
+        self.blocks.insert(at, block);
+    }
+
+    /// Index of the block holding the unit `id`
+    fn index_of_unit(&self, id: Id) -> Option<usize> {
+        self.index_of(id).or_else(|| {
+            // The second unit of a character outside of the BMP
+            self.index_of(Id::new(id.client, id.clock.checked_sub(1)?))
+        })
+    }
+
+    fn live_before(&self, at: usize) -> u32 {
+        self.blocks[..at]
+            .iter()
+            .filter(|block| block.char().is_some())
+            .count() as u32
+    }
+
+    fn delete(&mut self, start: Id, len: u64, edits: &mut Vec<Edit<char>>) {
+        let end = start.clock + len;
+        let known = self.clock(start.client).min(end);
+        if known < end {
+            self.pending_deletes.push((
+                Id::new(start.client, known.max(start.clock)),
+                end - known.max(start.clock),
+            ));
+        }
+        for i in 0..self.blocks.len() {
+            let block = &self.blocks[i];
+            let in_range = block.id.client == start.client
+                && block.id.clock < known
+                && block.id.clock + block.content.len() > start.clock;
+            if in_range && !block.deleted {
+                if block.char().is_some() {
+                    edits.push(Edit::Remove(self.live_before(i)));
+                }
+                self.blocks[i].deleted = true;
+            }
+        }
+    }
+}
+
+/// Adds a range to a client's delete set, merging it with the last one if they touch
+fn push_range(ranges: &mut Vec<(u64, u64)>, clock: u64, len: u64) {
+    match ranges.last_mut() {
+        Some((start, n)) if *start + *n == clock => *n += len,
+        _ => ranges.push((clock, len)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Content, Edit, Id, Item, Parent, Struct, Update, YText};
+
+    /// Client 1 typing "abc" into `doc.getText("text")`
+    const ABC: &[u8] = &[
+        1, 1, 1, 0, 4, 1, 4, b't', b'e', b'x', b't', 3, b'a', b'b', b'c', 0,
+    ];
+
+    #[test]
+    fn decodes_updates() {
+        let update = Update::decode(ABC).unwrap();
+        assert_eq!(
+            update.structs,
+            vec![Struct::Item(Item {
+                id: Id::new(1, 0),
+                origin: None,
+                right_origin: None,
+                parent: Some(Parent::Root("text".into())),
+                parent_sub: None,
+                content: Content::String("abc".into()),
+            })]
+        );
+        assert!(update.deletes.is_empty());
+        assert_eq!(update.encode(), ABC);
+    }
+
+    #[test]
+    fn utf16_clocks() {
+        let mut text = YText::new(1, "text");
+        text.update_from(&['a', '😀', 'b']).unwrap();
+        assert_eq!(text.state_vector()[&1], 4);
+
+        let mut peer = YText::new(2, "text");
+        peer.apply_update(&text.encode_state_as_update()).unwrap();
+        assert_eq!(peer.text(), "a😀b");
+        let update = text.update_from(&['a', '😀', 'c', 'b']).unwrap();
+        assert_eq!(
+            peer.apply_update(&update).unwrap(),
+            vec![Edit::Insert(2, 'c')]
+        );
+        assert_eq!(peer.text(), "a😀cb");
+    }
+
+    #[test]
+    fn concurrent_inserts_order_by_client() {
+        let mut base = YText::new(1, "text");
+        let base_update = {
+            base.update_from(&['a', 'b']).unwrap();
+            base.encode_state_as_update()
+        };
+
+        let mut updates = vec![];
+        for (client, c) in [(5, 'Y'), (4, 'Z')] {
+            let mut text = YText::new(client, "text");
+            text.apply_update(&base_update).unwrap();
+            updates.push(text.update_from(&['a', c, 'b']).unwrap());
+        }
+
+        for order in [[0, 1], [1, 0]] {
+            let mut text = YText::new(9, "text");
+            text.apply_update(&base_update).unwrap();
+            for i in order {
+                text.apply_update(&updates[i]).unwrap();
+            }
+            assert_eq!(text.text(), "aZYb");
+        }
+    }
+
+    #[test]
+    fn waits_for_missing_structs() {
+        let mut a = YText::new(1, "text");
+        let first = a.update_from(&['a']).unwrap();
+        let second = a.update_from(&['a', 'b']).unwrap();
+        let removal = a.update_from(&['b']).unwrap();
+
+        let mut b = YText::new(2, "text");
+        assert!(b.apply_update(&removal).unwrap().is_empty());
+        assert!(b.apply_update(&second).unwrap().is_empty());
+        assert_eq!(
+            b.apply_update(&first).unwrap(),
+            vec![Edit::Insert(0, 'a'), Edit::Insert(1, 'b'), Edit::Remove(0)]
+        );
+        assert_eq!(b.text(), "b");
+    }
+
+    #[test]
+    fn skips_other_types() {
+        // A struct of `doc.getMap("meta")` from client 7 in front of the text
+        let mut update = Update::decode(ABC).unwrap();
+        update.structs.push(Struct::Item(Item {
+            id: Id::new(7, 0),
+            origin: None,
+            right_origin: None,
+            parent: Some(Parent::Root("meta".into())),
+            parent_sub: Some("title".into()),
+            content: Content::Json(vec!["\"squares\"".into()]),
+        }));
+        let bytes = update.encode();
+        assert_eq!(Update::decode(&bytes).unwrap().encode(), bytes);
+
+        let mut text = YText::new(2, "text");
+        text.apply_update(&bytes).unwrap();
+        assert_eq!(text.text(), "abc");
+        assert_eq!(text.state_vector()[&7], 1);
+    }
+}
@@ -0,0 +1,83 @@
+//! A replica of this crate collaborating with Yjs peers through Yjs v1 updates.
+//!
+//! The updates are laid out the way Yjs 13 writes them with `Y.encodeStateAsUpdate` and in its
+//! `update` events for a `doc.getText("text")`.
+use std::fmt::Debug;
+
+use sypytkowski_commutative::{
+    lseq::LSeq,
+    memdb::InMemoryDb,
+    protocol::Protocol,
+    rga::Rga,
+    yjs::{Update, YText},
+    Crdt, ReplicaId, Replicator,
+};
+
+/// Client 1 types "abc"
+const ABC: &[u8] = &[
+    1, 1, 1, 0, 4, 1, 4, b't', b'e', b'x', b't', 3, b'a', b'b', b'c', 0,
+];
+
+/// Client 2 types "X" between "b" and "c" and removes "a" in one transaction
+const X_REMOVE_A: &[u8] = &[1, 1, 2, 0, 0xc4, 1, 1, 1, 2, 1, b'X', 1, 1, 1, 0, 1];
+
+/// Client 3, the Rust replica, appends "!"
+const BANG: &[u8] = &[1, 1, 3, 0, 0x84, 1, 2, 1, b'!', 0];
+
+/// The whole document afterwards: the removed "a" as deleted content, "b" and "c" split apart by
+/// the "X" between them
+const STATE: &[u8] = &[
+    3, //
+    1, 3, 0, 0x84, 1, 2, 1, b'!', //
+    1, 2, 0, 0xc4, 1, 1, 1, 2, 1, b'X', //
+    3, 1, 0, 1, 1, 4, b't', b'e', b'x', b't', 1, 0x84, 1, 0, 1, b'b', 0x84, 1, 1, 1, b'c', //
+    1, 1, 1, 0, 1,
+];
+
+async fn round_trip<C>(crdt: C)
+where
+    C: Crdt<State = Vec<char>>,
+    C::Cmd: From<sypytkowski_commutative::yjs::Edit<char>> + Debug,
+{
+    let mut replica = Replicator::new(ReplicaId::new(3), crdt, InMemoryDb::default())
+        .await
+        .unwrap();
+    let mut ytext = YText::new(3, "text");
+
+    for update in [ABC, X_REMOVE_A] {
+        for edit in ytext.apply_update(update).unwrap() {
+            replica.send(Protocol::Command(edit.into())).await;
+        }
+    }
+    assert_eq!(replica.query().iter().collect::<String>(), "bXc");
+    assert_eq!(ytext.text(), "bXc");
+
+    let mut text = replica.query();
+    text.push('!');
+    assert_eq!(ytext.update_from(&text).unwrap(), BANG);
+    assert_eq!(ytext.update_from(&text), None);
+    assert_eq!(ytext.encode_state_as_update(), STATE);
+
+    // A Yjs peer loading the document gets the same text
+    let mut browser = YText::new(2, "text");
+    browser.apply_update(STATE).unwrap();
+    assert_eq!(browser.text(), "bXc!");
+    assert_eq!(browser.encode_state_as_update(), STATE);
+}
+
+#[tokio::test]
+async fn rga() {
+    round_trip(Rga::new(ReplicaId::new(3))).await;
+}
+
+#[tokio::test]
+async fn lseq() {
+    round_trip(LSeq::new(ReplicaId::new(3))).await;
+}
+
+#[test]
+fn updates_re_encode_as_is() {
+    for update in [ABC, X_REMOVE_A, BANG, STATE] {
+        assert_eq!(Update::decode(update).unwrap().encode(), update);
+    }
+}
@@ -0,0 +1,77 @@
+//! `#[derive(Convergent)]` for structs whose fields are all CRDTs.
+//!
+//! Merging a product of CRDTs field by field is itself a CRDT, so this lets you compose
+//! something like:
+//!
+//! ```ignore
+//! #[derive(Clone, Convergent)]
+//! struct Profile {
+//!     name: MVReg<String>,
+//!     score: PNCounter,
+//! }
+//! ```
+//!
+//! without hand-writing `merge`. Every type parameter gets a `Convergent` bound.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Index};
+
+#[proc_macro_derive(Convergent)]
+pub fn derive_convergent(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let convergent = quote!(::sypytkowski_convergent::delta_state::convergent::Convergent);
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        Data::Enum(_) | Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "Convergent can only be derived for structs",
+            ))
+        }
+    };
+
+    let body = match fields {
+        Fields::Named(fields) => {
+            let names = fields.named.iter().map(|field| &field.ident);
+            quote! {
+                Self {
+                    #(#names: #convergent::merge(&self.#names, &other.#names),)*
+                }
+            }
+        }
+        Fields::Unnamed(fields) => {
+            let indices = (0..fields.unnamed.len()).map(Index::from);
+            quote! {
+                Self(#(#convergent::merge(&self.#indices, &other.#indices),)*)
+            }
+        }
+        Fields::Unit => quote!(Self),
+    };
+
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#convergent));
+    }
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    // Unit structs don't look at `other`
+    let other = match fields {
+        Fields::Unit => format_ident!("_other"),
+        _ => format_ident!("other"),
+    };
+
+    Ok(quote! {
+        impl #impl_generics #convergent for #name #ty_generics #where_clause {
+            fn merge(&self, #other: &Self) -> Self {
+                #body
+            }
+        }
+    })
+}
@@ -0,0 +1,55 @@
+use sypytkowski_convergent::{
+    delta_state::{
+        convergent::Convergent, gcounter::GCounter, mvreg::MVReg, pncounter::PNCounter,
+    },
+    ReplicaGenerator,
+};
+
+#[derive(Debug, Clone, PartialEq, Default, Convergent)]
+struct Profile {
+    name: MVReg<String>,
+    score: PNCounter,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Convergent)]
+struct Pair<A, B>(A, B);
+
+#[derive(Debug, Clone, PartialEq, Convergent)]
+struct Unit;
+
+#[test]
+fn merges_fields() {
+    let mut gen = ReplicaGenerator::new();
+    let a_id = gen.gen();
+    let b_id = gen.gen();
+
+    let mut a = Profile::default();
+    let mut b = Profile::default();
+    a.name.set(a_id, "zack".into());
+    a.score.increment(a_id);
+    b.score.increment(b_id);
+    b.score.increment(b_id);
+
+    let ab = a.merge(&b);
+    assert_eq!(ab, b.merge(&a));
+    assert_eq!(ab, ab.merge(&ab));
+    assert_eq!(ab.score.value(), 3);
+    assert_eq!(ab.name.value().into_iter().collect::<Vec<_>>(), vec!["zack"]);
+}
+
+#[test]
+fn merges_generic_tuple_structs() {
+    let mut gen = ReplicaGenerator::new();
+    let a_id = gen.gen();
+    let b_id = gen.gen();
+
+    let mut a = Pair(GCounter::default(), 1u64);
+    let mut b = Pair(GCounter::default(), 2u64);
+    a.0.increment(a_id);
+    b.0.increment(b_id);
+
+    let ab = a.merge(&b);
+    assert_eq!(ab.0.value(), 2);
+    assert_eq!(ab.1, 2);
+    assert_eq!(Unit.merge(&Unit), Unit);
+}
@@ -0,0 +1,103 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use sypytkowski_convergent::{delta_state::awormap::AWORMap, ReplicaGenerator};
+
+const SIZES: [u64; 3] = [1_000, 10_000, 100_000];
+/// Share of the keys the other replica overwrites, in percent
+const OVERLAP: u64 = 10;
+
+/// Two replicas of an `entries` entry map, the second one overwrote every 10th key and still
+/// holds the delta of those writes
+fn setup(entries: u64) -> (AWORMap<u64, u64>, AWORMap<u64, u64>) {
+    let mut gen = ReplicaGenerator::new();
+    let a_id = gen.gen();
+    let b_id = gen.gen();
+
+    let mut a = AWORMap::<u64, u64>::default();
+    for i in 0..entries {
+        a.insert(a_id, i, i);
+    }
+    a.split_mut();
+
+    let mut b = a.clone();
+    for i in (0..entries).step_by((100 / OVERLAP) as usize) {
+        b.insert(b_id, i, i + 1);
+    }
+
+    (a, b)
+}
+
+fn merge(c: &mut Criterion) {
+    let mut group = c.benchmark_group("awormap_merge");
+    for entries in SIZES {
+        let (a, mut b) = setup(entries);
+        let delta = b.split_mut().expect("delta should be defined");
+
+        group.bench_with_input(
+            BenchmarkId::new("merge_in_place", entries),
+            &b,
+            |bench, b| {
+                bench.iter_batched(
+                    || a.clone(),
+                    |mut a| {
+                        a.merge_in_place(b);
+                        a
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("merge_delta_in_place", entries),
+            &delta,
+            |bench, delta| {
+                bench.iter_batched(
+                    || a.clone(),
+                    |mut a| {
+                        a.merge_delta_in_place(delta);
+                        a
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Anti-entropy path: find the keys that differ, extract them on one side and apply them on the
+/// other
+fn extract(c: &mut Criterion) {
+    let mut group = c.benchmark_group("awormap_extract");
+    for entries in SIZES {
+        let (a, mut b) = setup(entries);
+        b.split_mut();
+        let keys = b.diff(&a.digest());
+
+        group.bench_with_input(BenchmarkId::new("diff", entries), &b, |bench, b| {
+            bench.iter(|| black_box(b.diff(&a.digest())))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("extract", entries),
+            &keys,
+            |bench, keys| bench.iter(|| black_box(b.extract(keys))),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("extract_apply", entries),
+            &keys,
+            |bench, keys| {
+                bench.iter_batched(
+                    || a.clone(),
+                    |mut a| {
+                        a.merge_delta(b.extract(keys));
+                        a
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, merge, extract);
+criterion_main!(benches);
@@ -0,0 +1,76 @@
+use std::collections::BTreeSet;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use sypytkowski_convergent::{
+    delta_state::dot::{Dot, DotCloud},
+    ReplicaId,
+};
+
+const DOTS: u64 = 100_000;
+const REPLICAS: u64 = 10;
+
+/// 100k dots spread over 10 replicas, every replica is missing its first dot (so nothing can be
+/// compacted into the clock) and every 100th dot after that
+fn sparse_dots() -> Vec<Dot> {
+    (0..REPLICAS)
+        .flat_map(|id| {
+            (2..)
+                .filter(|n| n % 100 != 0)
+                .take((DOTS / REPLICAS) as usize)
+                .map(move |n| Dot(ReplicaId::from(id), n))
+        })
+        .collect()
+}
+
+/// Rough heap usage: a B-tree node per entry is a good enough approximation to compare the two
+fn report_memory(dots: &[Dot]) {
+    let set = dots.iter().copied().collect::<BTreeSet<_>>();
+    let cloud = dots.iter().copied().collect::<DotCloud>();
+    let set_bytes = set.len() * std::mem::size_of::<Dot>();
+    let cloud_bytes = cloud.run_count() * 2 * std::mem::size_of::<u64>();
+
+    println!(
+        "{} sparse dots: BTreeSet<Dot> ~{} bytes, DotCloud {} runs ~{} bytes ({:.1}x smaller)",
+        cloud.len(),
+        set_bytes,
+        cloud.run_count(),
+        cloud_bytes,
+        set_bytes as f64 / cloud_bytes as f64
+    );
+}
+
+fn dot_cloud(c: &mut Criterion) {
+    let dots = sparse_dots();
+    report_memory(&dots);
+
+    let mut group = c.benchmark_group("dot_cloud_100k_sparse");
+    group.bench_function("btreeset_insert", |b| {
+        b.iter(|| black_box(dots.iter().copied().collect::<BTreeSet<_>>()))
+    });
+    group.bench_function("dotcloud_insert", |b| {
+        b.iter(|| black_box(dots.iter().copied().collect::<DotCloud>()))
+    });
+
+    let set = dots.iter().copied().collect::<BTreeSet<_>>();
+    let cloud = dots.iter().copied().collect::<DotCloud>();
+    group.bench_function("btreeset_contains", |b| {
+        b.iter(|| dots.iter().filter(|dot| set.contains(dot)).count())
+    });
+    group.bench_function("dotcloud_contains", |b| {
+        b.iter(|| dots.iter().filter(|dot| cloud.contains(**dot)).count())
+    });
+    group.bench_function("dotcloud_extend", |b| {
+        b.iter_batched(
+            || cloud.clone(),
+            |mut other| {
+                other.extend(cloud.ranges());
+                other
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, dot_cloud);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use sypytkowski_convergent::{
+    delta_state::dot::{Dot, DotCtx},
+    ReplicaId,
+};
+
+const REPLICAS: u64 = 10;
+const DOTS: [u64; 2] = [10_000, 100_000];
+
+/// `dots` dots spread over 10 replicas sitting in the cloud. When `gapless` every run starts
+/// right after the clock so compacting folds all of them in, otherwise every 100th dot is missing
+/// and only the first run of each replica can be folded.
+fn ctx(dots: u64, gapless: bool) -> DotCtx {
+    let mut ctx = DotCtx::default();
+    for id in 0..REPLICAS {
+        (1..)
+            .filter(|n| gapless || n % 100 != 0)
+            .take((dots / REPLICAS) as usize)
+            .for_each(|n| ctx.add(Dot(ReplicaId::from(id), n)));
+    }
+    ctx
+}
+
+fn compact(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dot_ctx_compact");
+    for dots in DOTS {
+        for (name, gapless) in [("gapless", true), ("sparse", false)] {
+            let ctx = ctx(dots, gapless);
+            group.bench_with_input(BenchmarkId::new(name, dots), &ctx, |bench, ctx| {
+                bench.iter_batched(
+                    || ctx.clone(),
+                    |mut ctx| {
+                        ctx.compact();
+                        ctx
+                    },
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, compact);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use sypytkowski_convergent::{delta_state::awormap::AWORMap, ReplicaGenerator};
+
+const ENTRIES: u64 = 50_000;
+const DELTA_ENTRIES: u64 = 10;
+
+/// A 50k entry map, plus a 10 entry delta (and the full map that produced it) from another replica
+fn setup() -> (AWORMap<u64, u64>, AWORMap<u64, u64>) {
+    let mut gen = ReplicaGenerator::new();
+    let a_id = gen.gen();
+    let b_id = gen.gen();
+
+    let mut a = AWORMap::<u64, u64>::default();
+    for i in 0..ENTRIES {
+        a.insert(a_id, i, i);
+    }
+    a.split_mut();
+
+    let mut b = AWORMap::<u64, u64>::default();
+    for i in 0..DELTA_ENTRIES {
+        b.insert(b_id, ENTRIES + i, i);
+    }
+
+    (a, b)
+}
+
+fn merge(c: &mut Criterion) {
+    let (a, mut b) = setup();
+    let delta = b.clone().split_mut().expect("delta should be defined");
+    b.split_mut();
+
+    let mut group = c.benchmark_group("awormap_50k_merge_10");
+    group.bench_function("merge", |bench| {
+        bench.iter(|| black_box(a.merge(&b)))
+    });
+    group.bench_function("merge_in_place", |bench| {
+        bench.iter_batched(
+            || a.clone(),
+            |mut a| {
+                a.merge_in_place(&b);
+                a
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("merge_delta", |bench| {
+        bench.iter_batched(
+            || a.clone(),
+            |mut a| {
+                a.merge_delta(delta.clone());
+                a
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("merge_delta_in_place", |bench| {
+        bench.iter_batched(
+            || a.clone(),
+            |mut a| {
+                a.merge_delta_in_place(&delta);
+                a
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, merge);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use sypytkowski_convergent::state::{grow_counter::GrowCounter, vector_clock::VectorClock};
+
+const REPLICAS: [u64; 3] = [10, 100, 1_000];
+
+fn clock(replicas: u64, f: impl Fn(u64) -> u64) -> VectorClock {
+    VectorClock::new(GrowCounter::from_iter(
+        (0..replicas).map(|id| (id.into(), f(id))),
+    ))
+}
+
+/// Equal clocks and clocks where only the last replica differs have to look at every entry,
+/// concurrent ones where the very first entries disagree could stop early
+fn partial_cmp(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vector_clock_partial_cmp");
+    for replicas in REPLICAS {
+        let a = clock(replicas, |id| id + 1);
+        let cases = [
+            ("equal", clock(replicas, |id| id + 1)),
+            (
+                "less",
+                clock(replicas, |id| id + 1 + (id == replicas - 1) as u64),
+            ),
+            (
+                "concurrent",
+                clock(replicas, |id| if id == 0 { 2 } else { id }),
+            ),
+        ];
+        for (name, b) in cases {
+            group.bench_with_input(BenchmarkId::new(name, replicas), &b, |bench, b| {
+                bench.iter(|| black_box(a.partial_cmp(b)))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, partial_cmp);
+criterion_main!(benches);
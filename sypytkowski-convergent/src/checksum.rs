@@ -0,0 +1,396 @@
+//! Checksums of the materialized values of the CRDTs, so replicas can cheaply check they
+//! converged without exchanging their state.
+//!
+//! Checksums have to come out the same on every replica, including plugins compiled to wasm, so
+//! values are hashed through their serialized form with FNV-1a rather than with `core::hash`,
+//! whose output may change between Rust versions. Only the value is hashed: two replicas that
+//! read the same agree even if one of them still holds metadata the other compacted away.
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+use alloc::{vec, vec::Vec};
+use core::hash::Hasher;
+#[cfg(feature = "std")]
+use std::io;
+
+use serde::Serialize;
+
+use crate::delta_state::{
+    awormap::AWORMap,
+    aworset::AWORSet,
+    counter_map::CounterMap,
+    document::{Crdt, Document},
+    gcounter::GCounter,
+    graph::{MonotoneDag, TwoPTwoPGraph},
+    grid::Grid,
+    gset::GSet,
+    json::Json,
+    lww::{HlcLwwMap, HlcLwwRegister},
+    mvreg::MVReg,
+    orarray::ORArray,
+    pncounter::PNCounter,
+    rich_text::RichText,
+    text::Text,
+};
+use crate::state;
+use crate::{ReplicaId, Value};
+
+/// 64 bit FNV-1a
+#[derive(Debug, Clone, Copy)]
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl io::Write for StableHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Hasher::write(self, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StableHasher {
+    /// Feeds the JSON encoding of `value` to the hasher
+    #[cfg(feature = "std")]
+    pub fn write_serialized<T: Serialize + ?Sized>(&mut self, value: &T) {
+        serde_json::to_writer(&mut *self, value).expect("value should serialize to JSON")
+    }
+
+    /// Without `std` there is no writer to stream into, the encoding is buffered instead
+    #[cfg(not(feature = "std"))]
+    pub fn write_serialized<T: Serialize + ?Sized>(&mut self, value: &T) {
+        let json = serde_json::to_vec(value).expect("value should serialize to JSON");
+        Hasher::write(self, &json)
+    }
+}
+
+/// Stable hash of the serialized form of `value`
+pub fn stable_hash<T: Serialize + ?Sized>(value: &T) -> u64 {
+    let mut hasher = StableHasher::default();
+    hasher.write_serialized(value);
+    hasher.finish()
+}
+
+/// Stable hash of the value a CRDT currently reads as
+pub trait Checksum {
+    fn checksum(&self) -> u64;
+}
+
+impl Checksum for GCounter {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.value())
+    }
+}
+
+impl Checksum for PNCounter {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.value())
+    }
+}
+
+impl<T: Clone + Ord + Value + Serialize> Checksum for GSet<T> {
+    fn checksum(&self) -> u64 {
+        stable_hash(self.value())
+    }
+}
+
+impl<K: Clone + Ord + Value + Serialize> Checksum for CounterMap<K> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.iter().collect::<Vec<_>>())
+    }
+}
+
+impl<V: Clone + Ord + Value + Serialize> Checksum for MonotoneDag<V> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&(self.vertices(), self.edges()))
+    }
+}
+
+/// Edges missing a vertex aren't part of the value
+impl<V: Clone + Ord + Value + Serialize> Checksum for TwoPTwoPGraph<V> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&(self.vertices(), self.edges()))
+    }
+}
+
+impl<V> Checksum for AWORSet<V>
+where
+    V: Clone + PartialEq + Ord + Value + Serialize,
+{
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.values_ref())
+    }
+}
+
+impl<K, V> Checksum for AWORMap<K, V>
+where
+    K: Clone + PartialEq + Ord + Value + Serialize,
+    V: Value + Clone + Serialize,
+{
+    /// Concurrent values of a key are all part of the value
+    fn checksum(&self) -> u64 {
+        let entries = self
+            .iter()
+            .map(|(key, _)| (key, self.get_all(key).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        stable_hash(&entries)
+    }
+}
+
+impl<V> Checksum for MVReg<V>
+where
+    V: Clone + PartialEq + Ord + Value + Serialize,
+{
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.value())
+    }
+}
+
+impl<V> Checksum for ORArray<V>
+where
+    V: Clone + PartialEq + Value + Serialize,
+{
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.iter().collect::<Vec<_>>())
+    }
+}
+
+impl<V: Clone + Ord + Value + Serialize> Checksum for Grid<V> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.values())
+    }
+}
+
+impl Checksum for Text {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.to_string())
+    }
+}
+
+impl Checksum for RichText {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.spans())
+    }
+}
+
+impl Checksum for Json {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.to_json())
+    }
+}
+
+impl<V: Value + Clone + Serialize> Checksum for HlcLwwRegister<V> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.value())
+    }
+}
+
+impl<K, V> Checksum for HlcLwwMap<K, V>
+where
+    K: Ord + Clone + Value + Serialize,
+    V: Clone + Value + Serialize,
+{
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.iter().collect::<Vec<_>>())
+    }
+}
+
+impl<K, V> Checksum for Crdt<K, V>
+where
+    K: Clone + PartialEq + Ord + Value + Serialize,
+    V: Clone + PartialEq + Ord + Value + Serialize,
+{
+    /// Tagged with the kind, so an empty set and an empty list don't look the same
+    fn checksum(&self) -> u64 {
+        stable_hash(&match self {
+            Crdt::Counter(crdt) => (0, crdt.checksum()),
+            Crdt::Register(crdt) => (1, crdt.checksum()),
+            Crdt::Set(crdt) => (2, crdt.checksum()),
+            Crdt::Map(crdt) => (3, crdt.checksum()),
+            Crdt::List(crdt) => (4, crdt.checksum()),
+            Crdt::Grid(crdt) => (5, crdt.checksum()),
+        })
+    }
+}
+
+impl<K, V> Checksum for Document<K, V>
+where
+    K: Clone + PartialEq + Ord + Value + Serialize,
+    V: Clone + PartialEq + Ord + Value + Serialize,
+{
+    fn checksum(&self) -> u64 {
+        let fields = self
+            .fields()
+            .map(|(name, crdt)| (name, crdt.checksum()))
+            .collect::<Vec<_>>();
+        stable_hash(&fields)
+    }
+}
+
+impl<N: state::grow_counter::Count + Serialize> Checksum for state::grow_counter::GrowCounter<N> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.value())
+    }
+}
+
+impl Checksum for state::pn_counter::PNCounter {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.value())
+    }
+}
+
+impl Checksum for state::vector_clock::VectorClock {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.iter().collect::<Vec<_>>())
+    }
+}
+
+impl<T: Ord + Clone + Serialize> Checksum for state::or_set::ORSet<T> {
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.value().keys().collect::<Vec<_>>())
+    }
+}
+
+impl<K, V> Checksum for state::awormap::AWORMap<K, V>
+where
+    K: Clone + PartialEq + Ord + Value + Serialize,
+    V: crate::delta_state::convergent::Convergent + Clone + Value + Serialize,
+{
+    fn checksum(&self) -> u64 {
+        stable_hash(&self.value().iter().collect::<Vec<_>>())
+    }
+}
+
+/// Collects the checksums replicas report, to tell whether they all read the same
+#[derive(Debug, Clone, Default)]
+pub struct ConvergenceChecker {
+    checksums: BTreeMap<ReplicaId, u64>,
+}
+
+impl ConvergenceChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces what `replica` reported before
+    pub fn record(&mut self, replica: ReplicaId, checksum: u64) {
+        self.checksums.insert(replica, checksum);
+    }
+
+    pub fn record_crdt<T: Checksum>(&mut self, replica: ReplicaId, crdt: &T) {
+        self.record(replica, crdt.checksum())
+    }
+
+    /// Stops waiting on `replica`, e.g. once it disconnected
+    pub fn forget(&mut self, replica: ReplicaId) {
+        self.checksums.remove(&replica);
+    }
+
+    /// Whether every replica reported the same checksum, trivially so with less than two
+    pub fn converged(&self) -> bool {
+        let mut checksums = self.checksums.values();
+        match checksums.next() {
+            Some(first) => checksums.all(|checksum| checksum == first),
+            None => true,
+        }
+    }
+
+    /// Replicas that disagree with the checksum most of them reported, ties go to the smaller
+    /// checksum
+    pub fn divergent(&self) -> Vec<ReplicaId> {
+        let mut counts = BTreeMap::<u64, usize>::new();
+        for checksum in self.checksums.values() {
+            *counts.entry(*checksum).or_default() += 1;
+        }
+        let Some(majority) = counts
+            .iter()
+            .max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then(b.cmp(a)))
+            .map(|(checksum, _)| *checksum)
+        else {
+            return vec![];
+        };
+
+        self.checksums
+            .iter()
+            .filter(|(_, checksum)| **checksum != majority)
+            .map(|(replica, _)| *replica)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::delta_state::{awormap::AWORMap, text::Text};
+    use crate::ReplicaId;
+
+    use super::{Checksum, ConvergenceChecker};
+
+    const A: ReplicaId = ReplicaId(1);
+    const B: ReplicaId = ReplicaId(2);
+    const C: ReplicaId = ReplicaId(3);
+
+    #[test]
+    fn replicas_converge() {
+        let mut a = AWORMap::<u32, String>::default();
+        let mut b = AWORMap::<u32, String>::default();
+        a.insert(A, 1, "a".into());
+        b.insert(B, 1, "b".into());
+        b.insert(B, 2, "b".into());
+
+        let mut checker = ConvergenceChecker::new();
+        checker.record_crdt(A, &a);
+        checker.record_crdt(B, &b);
+        assert!(!checker.converged());
+
+        let a_delta = a.split_mut().unwrap();
+        a.merge_delta(b.split_mut().unwrap());
+        b.merge_delta(a_delta);
+        checker.record_crdt(A, &a);
+        checker.record_crdt(B, &b);
+        assert!(checker.converged());
+        assert!(checker.divergent().is_empty());
+
+        // Both concurrent values of 1 are part of the value
+        let mut c = AWORMap::<u32, String>::default();
+        c.insert(C, 1, "b".into());
+        c.insert(C, 2, "b".into());
+        assert_ne!(c.checksum(), a.checksum());
+    }
+
+    #[test]
+    fn divergent_replicas() {
+        let mut text = Text::default();
+        text.insert(A, 0, "hello");
+        let mut other = text.clone();
+        other.insert(B, 5, "!");
+
+        let mut checker = ConvergenceChecker::new();
+        checker.record_crdt(A, &text);
+        checker.record_crdt(B, &text);
+        checker.record_crdt(C, &other);
+        assert_eq!(checker.divergent(), vec![C]);
+
+        checker.forget(C);
+        assert!(checker.converged());
+    }
+}
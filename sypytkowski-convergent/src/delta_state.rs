@@ -1,8 +1,20 @@
 pub mod awormap;
 pub mod aworset;
 pub mod convergent;
+pub mod counter_map;
+pub mod digest;
+pub mod document;
 pub mod dot;
 pub mod gcounter;
+pub mod graph;
+pub mod grid;
 pub mod gset;
+pub mod json;
+pub mod lww;
 pub mod mvreg;
+pub mod orarray;
 pub mod pncounter;
+pub mod product;
+pub mod rich_text;
+pub mod text;
+pub mod undo;
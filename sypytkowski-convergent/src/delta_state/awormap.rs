@@ -1,168 +1,870 @@
-use std::fmt::Debug;
-use std::hash::Hash;
-use std::{cmp::Ord, collections::HashMap};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::{vec, vec::Vec};
+use core::hash::{Hash, Hasher};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
 
-use serde::de::Visitor;
-use serde::ser::SerializeTuple;
+use serde::ser::{SerializeStruct, SerializeTuple};
 use serde::{Deserialize, Serialize};
 
+use crate::checksum::{stable_hash, StableHasher};
+use crate::encoding::{Decode, DecodeError, Encode, Reader, Writer};
+use crate::memory::{MemoryUsage, Usage};
 use crate::{ReplicaId, Value};
 
 use super::aworset::AWORSet;
-use super::dot::DotKernel;
+use super::convergent::Convergent;
+use super::digest::{fold, MapDigest, DIGEST_LEAVES};
+use super::dot::{DeltaPolicy, Dot, DotCtx, DotKernel, DotRange, VectorClock};
 
+/// Deltas carry every entry as a [`KeyVal`], the way the map used to keep them, so they stay
+/// readable by replicas that haven't moved on
 pub type Deltas<K, V> = DotKernel<KeyVal<K, V>>;
 
-#[derive(
-    Default,
-    Clone,
-    Debug,
-    PartialEq,
-    serde_derive::Serialize,
-    fp_bindgen::prelude::Serializable,
-    serde_derive::Deserialize,
+/// Add-wins observed remove map. The keys live in an [`AWORSet`], each value sits beside it
+/// under the dot of its key.
+///
+/// When replicas concurrently insert the same key neither write wins, the key keeps every
+/// concurrent value (like an [`MVReg`](super::mvreg::MVReg) per key) until a later insert
+/// observes and replaces all of them. Use [`AWORMap::get_all`] to read them, or
+/// [`AWORMap::get_merged`] when the values are [`Convergent`]. A map can also be given a
+/// [`ConflictPolicy`] deciding how [`AWORMap::get_resolved`] reads them.
+///
+/// Alongside the set we keep an index from each key to the dots holding it, so lookups and
+/// removals don't need to scan every entry of the kernel. The index is derived state, it is not
+/// serialized and gets rebuilt when deserializing.
+///
+/// Serialized and encoded, keys and values are put back together into [`KeyVal`]s like in
+/// [`Deltas`], so states written before they were kept apart still read.
+///
+/// With the `history` feature the map also remembers every value that was replaced or removed,
+/// see [`AWORMap::history`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::awormap")
 )]
-#[fp(rust_plugin_module = "sypytkowski_convergent::delta_state::awormap")]
-pub struct AWORMap<
-    K: Clone + PartialEq + Default + Debug + Ord + Value,
-    V: Value + Clone + Default + Debug,
-> {
-    pub keys: AWORSet<KeyVal<K, V>>,
+pub struct AWORMap<K: Clone + PartialEq + Ord + Value, V: Value + Clone> {
+    pub(crate) keys: AWORSet<K>,
+    /// Value of every entry of the set's kernel, by dot
+    values: BTreeMap<Dot, V>,
+    /// Value of every entry of the set's delta buffer, by dot
+    delta_values: BTreeMap<Dot, V>,
+    index: BTreeMap<K, BTreeSet<Dot>>,
+    policy: Policy<V>,
+    #[cfg(feature = "history")]
+    history: History<K, V>,
 }
 
+impl<K, V> Default for AWORMap<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Value + Clone,
+{
+    fn default() -> Self {
+        Self {
+            keys: AWORSet::default(),
+            values: BTreeMap::new(),
+            delta_values: BTreeMap::new(),
+            index: BTreeMap::new(),
+            policy: Policy::default(),
+            #[cfg(feature = "history")]
+            history: History(BTreeMap::new()),
+        }
+    }
+}
+
+/// How [`AWORMap::get_resolved`] reads a key holding concurrent values. Only reads follow it,
+/// merging keeps every concurrent value whatever the policy, so replicas set up with different
+/// policies still converge. The policy is local to a map, it isn't serialized or encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::awormap")
+)]
+pub enum ConflictPolicy {
+    /// The value with the greatest dot wins, like [`AWORMap::get`]. Dots of concurrent inserts
+    /// are ordered by replica first, so it's the same replica winning on every replica rather
+    /// than the one that wrote last.
+    #[default]
+    LastWriterWins,
+    /// The values are merged together, if they are [`Convergent`] and the map was told so with
+    /// [`AWORMap::set_merge_policy`]
+    MergeConvergent,
+    /// Every value is kept, like an [`MVReg`](super::mvreg::MVReg)
+    KeepBoth,
+}
+
+/// The [`ConflictPolicy`] of a map, and how to merge its values once the map knows they are
+/// [`Convergent`]. Like [`History`] it is local to the map and never makes two maps unequal.
+#[derive(Clone, Debug)]
+struct Policy<V> {
+    kind: ConflictPolicy,
+    merge: Option<fn(&V, &V) -> V>,
+}
+
+impl<V> Default for Policy<V> {
+    fn default() -> Self {
+        Self {
+            kind: ConflictPolicy::default(),
+            merge: None,
+        }
+    }
+}
+
+impl<V> PartialEq for Policy<V> {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl<V: Value> fp_bindgen::prelude::Serializable for Policy<V> {
+    fn ident() -> fp_bindgen::prelude::TypeIdent {
+        fp_bindgen::prelude::TypeIdent::new(
+            "Policy",
+            vec![(fp_bindgen::prelude::TypeIdent::from("V"), vec![])],
+        )
+    }
+
+    /// Merge functions don't cross over, to plugins it is only the [`ConflictPolicy`]
+    fn ty() -> fp_bindgen::prelude::Type {
+        fp_bindgen::prelude::Type::from_item(
+            r#"#[fp(rust_plugin_module = "sypytkowski_convergent::delta_state::awormap")] pub struct Policy<V> { kind: ConflictPolicy, merge: Option<V> }"#,
+        )
+    }
+
+    fn collect_types(types: &mut fp_bindgen::prelude::TypeMap) {
+        types.entry(Self::ident()).or_insert_with(Self::ty);
+        ConflictPolicy::collect_types(types);
+    }
+}
+
+/// The value of a key as read by [`AWORMap::get_resolved`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolved<'a, V> {
+    /// The only value of the key, or the one that won with [`ConflictPolicy::LastWriterWins`]
+    Value(&'a V),
+    /// The concurrent values merged with [`ConflictPolicy::MergeConvergent`]
+    Merged(V),
+    /// Every value of the key ordered by dot, even a single one, with [`ConflictPolicy::KeepBoth`]
+    Concurrent(Vec<&'a V>),
+}
+
+/// A value that is no longer in the map, along with the dot it was held with
+#[cfg(feature = "history")]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::awormap")
+)]
+pub struct Superseded<V: Value> {
+    pub dot: Dot,
+    pub val: V,
+}
+
+/// Superseded values of each key, oldest first. This is local debugging information rather than
+/// part of the CRDT: it isn't serialized and never makes two maps unequal.
+#[cfg(feature = "history")]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::awormap")
+)]
+struct History<K: Value, V: Value>(BTreeMap<K, Vec<Superseded<V>>>);
+
+#[cfg(feature = "history")]
+impl<K: Value, V: Value> PartialEq for History<K, V> {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "history")]
+impl<K, V> History<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Value + Clone,
+{
+    fn record(&mut self, dot: Dot, key: &K, val: V) {
+        let superseded = self.0.entry(key.clone()).or_default();
+        superseded.push(Superseded { dot, val });
+    }
+}
+
+#[cfg(feature = "std")]
 impl<K, V> AWORMap<K, V>
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value + Hash,
-    V: Value + Clone + Default + Debug + Hash,
+    K: Clone + PartialEq + Ord + Value + Hash,
+    V: Value + Clone + Hash,
 {
     pub fn values_owned(&self) -> HashMap<K, V> {
-        self.keys
-            .values_iter()
-            .map(|kv| (kv.key.clone(), kv.val.clone()))
+        self.entries()
+            .map(|(_, key, val)| (key.clone(), val.clone()))
             .collect()
     }
     pub fn values(&self) -> HashMap<&K, &V> {
-        self.keys
-            .values_iter()
-            .map(|kv| (&kv.key, &kv.val))
-            .collect()
+        self.entries().map(|(_, key, val)| (key, val)).collect()
     }
 }
 
 impl<K, V> AWORMap<K, V>
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value,
-    V: Value + Clone + Default + Debug,
+    K: Clone + PartialEq + Ord + Value,
+    V: Value + Clone,
 {
+    /// Number of keys, a key holding concurrent values is counted once
     pub fn len(&self) -> usize {
-        self.keys.len()
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// An empty map reading conflicts with `policy`. [`ConflictPolicy::MergeConvergent`] only
+    /// merges once the map knows its values are [`Convergent`], see
+    /// [`AWORMap::with_merge_policy`].
+    pub fn with_policy(policy: ConflictPolicy) -> Self {
+        let mut map = Self::default();
+        map.set_policy(policy);
+        map
+    }
+
+    pub fn policy(&self) -> ConflictPolicy {
+        self.policy.kind
+    }
+
+    pub fn set_policy(&mut self, policy: ConflictPolicy) {
+        self.policy.kind = policy;
+    }
+
+    /// Value of `key` the way the map's [`ConflictPolicy`] reads it. With
+    /// [`ConflictPolicy::MergeConvergent`] concurrent values are only merged if the map was set
+    /// up with [`AWORMap::with_merge_policy`] or [`AWORMap::set_merge_policy`], otherwise they
+    /// are all read like with [`ConflictPolicy::KeepBoth`].
+    pub fn get_resolved(&self, key: &K) -> Option<Resolved<'_, V>> {
+        let resolved = match (self.policy.kind, self.policy.merge) {
+            (ConflictPolicy::MergeConvergent, Some(merge)) if self.is_conflicted(key) => {
+                let mut values = self.get_all(key);
+                let first = values.next()?.clone();
+                Resolved::Merged(values.fold(first, |acc, val| merge(&acc, val)))
+            }
+            (ConflictPolicy::MergeConvergent, None) if self.is_conflicted(key) => {
+                Resolved::Concurrent(self.get_all(key).collect())
+            }
+            (ConflictPolicy::LastWriterWins | ConflictPolicy::MergeConvergent, _) => {
+                Resolved::Value(self.get(key)?)
+            }
+            (ConflictPolicy::KeepBoth, _) if self.contains_key(key) => {
+                Resolved::Concurrent(self.get_all(key).collect())
+            }
+            (ConflictPolicy::KeepBoth, _) => return None,
+        };
+        Some(resolved)
+    }
+
+    /// Iterate entries in key order, read the way [`AWORMap::get_resolved`] does
+    pub fn iter_resolved(&self) -> impl Iterator<Item = (&K, Resolved<'_, V>)> + '_ {
+        self.index
+            .keys()
+            .filter_map(|key| self.get_resolved(key).map(|val| (key, val)))
     }
 
     pub fn insert(&mut self, replica: ReplicaId, key: K, value: V) {
-        self.keys.add(replica, KeyVal { key, val: value });
+        // Remove the previous value(s) of the key
+        self.remove(&key);
+        let delta = self.keys.delta.get_or_insert_with(Default::default);
+        let dot = self.keys.kernel.add(replica, key.clone(), delta);
+        self.values.insert(dot, value.clone());
+        self.delta_values.insert(dot, value);
+        self.index.insert(key, BTreeSet::from([dot]));
     }
 
-    pub fn remove(&mut self, replica: ReplicaId, key: K) {
-        self.keys.remove(&KeyVal {
-            key,
-            val: Default::default(),
-        });
+    pub fn remove(&mut self, key: &K) {
+        if let Some(dots) = self.index.remove(key) {
+            let delta = self.keys.delta.get_or_insert_with(Default::default);
+            self.keys.kernel.remove_dots(dots.iter().copied(), delta);
+            for dot in dots {
+                self.delta_values.remove(&dot);
+                self.forget(dot, key);
+            }
+        }
+    }
+
+    /// If the key was inserted concurrently by multiple replicas it holds multiple values, in
+    /// that case the value with the greatest dot is returned
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let dot = self.index.get(key)?.iter().next_back()?;
+        self.values.get(dot)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Iterate entries in key order, see [`AWORMap::get`] for which value is picked for a key
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.index.iter().filter_map(|(key, dots)| {
+            let dot = dots.iter().next_back()?;
+            self.values.get(dot).map(|val| (key, val))
+        })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Every value concurrently assigned to `key`, ordered by dot
+    pub fn get_all(&self, key: &K) -> impl Iterator<Item = &V> + '_ {
+        self.index
+            .get(key)
+            .into_iter()
+            .flatten()
+            .filter_map(|dot| self.values.get(dot))
+    }
+
+    /// Whether `key` holds more than one value because of concurrent inserts
+    pub fn is_conflicted(&self, key: &K) -> bool {
+        self.index.get(key).is_some_and(|dots| dots.len() > 1)
+    }
+
+    /// Dots holding the current value(s) of `key`, their replicas are the ones that wrote them
+    pub fn dots_for(&self, key: &K) -> Vec<Dot> {
+        self.index
+            .get(key)
+            .map(|dots| dots.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Keys merging `delta` would change: the ones it has entries for, and the ones whose entries
+    /// its context covers without it carrying them, which it removes
+    pub fn affected_keys(&self, delta: &Deltas<K, V>) -> BTreeSet<K> {
+        let mut keys = (delta.entries.values())
+            .map(|kv| kv.key.clone())
+            .collect::<BTreeSet<_>>();
+        for DotRange(id, range) in delta.ctx.ranges() {
+            let ours = (self.keys.kernel.entries).range(Dot(id, range.start)..Dot(id, range.end));
+            for (dot, key) in ours {
+                if !delta.entries.contains_key(dot) {
+                    keys.insert(key.clone());
+                }
+            }
+        }
+        keys
+    }
+
+    /// Values `key` held before, oldest first. Values are recorded when a local insert or remove
+    /// replaces them, or when merging removes them, so a replica only knows about the ones it has
+    /// seen.
+    #[cfg(feature = "history")]
+    pub fn history(&self, key: &K) -> &[Superseded<V>] {
+        self.history.0.get(key).map_or(&[], Vec::as_slice)
+    }
+
+    #[cfg(feature = "history")]
+    pub fn clear_history(&mut self) {
+        self.history.0.clear();
     }
 
     pub fn merge_delta(&mut self, delta: Deltas<K, V>) {
-        self.keys.merge_delta(delta);
+        let (keys, values) = Self::unfuse(delta);
+        self.merge_kernel_in_place(&keys.ctx, &values, &values, |set| {
+            set.merge_delta_in_place(&keys)
+        });
+    }
+
+    /// Takes the delta apart into keys and values, so it costs a copy of `delta`
+    pub fn merge_delta_in_place(&mut self, delta: &Deltas<K, V>) {
+        self.merge_delta(delta.clone());
     }
 
     pub fn merge(&self, other: &Self) -> Self {
-        Self {
-            keys: self.keys.merge(&other.keys),
+        let mut ret = self.clone();
+        ret.merge_in_place(other);
+        ret
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        let ctx = &other.keys.kernel.ctx;
+        self.merge_kernel_in_place(ctx, &other.values, &other.delta_values, |keys| {
+            keys.merge_in_place(&other.keys)
+        });
+    }
+
+    /// See [`AWORSet::compact_deltas`], the delta isn't indexed so this leaves the index alone
+    pub fn compact_deltas(&mut self, acked: &VectorClock) {
+        self.keys.compact_deltas(acked);
+        self.prune_delta_values();
+    }
+
+    /// See [`AWORSet::compact_deltas_with`]
+    #[must_use]
+    pub fn compact_deltas_with(&mut self, acked: &VectorClock, policy: DeltaPolicy) -> bool {
+        let resync = self.keys.compact_deltas_with(acked, policy);
+        self.prune_delta_values();
+        resync
+    }
+
+    /// Dots of the context that aren't covered by its clock yet, removals and adds that arrived
+    /// out of order
+    pub fn dot_cloud_len(&self) -> usize {
+        self.keys.kernel.ctx.dot_cloud.len()
+    }
+
+    /// Entries in the delta buffer, see [`Self::split_mut`]
+    pub fn delta_len(&self) -> usize {
+        self.keys
+            .delta
+            .as_ref()
+            .map_or(0, |delta| delta.entries.len())
+    }
+
+    /// Folds whatever dots it can into the clocks of the state and of the delta buffer, see
+    /// [`DotCtx::compact`]
+    pub fn compact_ctx(&mut self) {
+        self.keys.kernel.ctx.compact();
+        if let Some(delta) = &mut self.keys.delta {
+            delta.ctx.compact();
         }
     }
 
+    /// See [`DotKernel::uncovered_dot`]
+    pub fn uncovered_dot(&self) -> Option<Dot> {
+        self.keys.kernel.uncovered_dot()
+    }
+
+    /// See [`DotCtx::version`], deltas have theirs through
+    /// [`DotKernel::version`]
+    pub fn version(&self) -> VectorClock {
+        self.keys.kernel.version()
+    }
+
     pub fn split_mut(&mut self) -> Option<Deltas<K, V>> {
-        self.keys.split_mut()
+        let values = core::mem::take(&mut self.delta_values);
+        let delta = self.keys.split_mut()?;
+        Some(Self::fuse(&delta, &values))
     }
 
-    pub fn split(self) -> (Self, Option<Deltas<K, V>>) {
-        let (keys, delta) = self.keys.split();
-        (Self { keys }, delta)
+    /// See [`AWORSet::split_for_keys`]
+    pub fn split_for_keys(&mut self, keys: &[K]) -> Option<Deltas<K, V>> {
+        let split = self.keys.split_for_keys(keys)?;
+        let split = Self::fuse(&split, &self.delta_values);
+        self.prune_delta_values();
+        Some(split)
+    }
+
+    /// See [`AWORSet::delta_since`]
+    pub fn delta_since(&self, clock: &VectorClock) -> Deltas<K, V> {
+        Self::fuse(&self.keys.delta_since(clock), &self.values)
+    }
+
+    pub fn split(mut self) -> (Self, Option<Deltas<K, V>>) {
+        let delta = self.split_mut();
+        (self, delta)
     }
 
     pub fn split_expect_deltas(self) -> (Self, Deltas<K, V>) {
-        let (keys, delta) = self.keys.split_expect_deltas();
-        (Self { keys }, delta)
+        let (map, delta) = self.split();
+        (map, delta.expect("Deltas should be defined."))
+    }
+
+    /// The kernel with keys and values together, the way [`Deltas`] carry them
+    pub(crate) fn fused_kernel(&self) -> Deltas<K, V> {
+        Self::fuse(&self.keys.kernel, &self.values)
+    }
+
+    /// The map with its values and index rebuilt from the set
+    #[cfg(test)]
+    fn reindexed(&self) -> Self {
+        Self::from_fused(self.fused())
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&Dot, &K, &V)> + '_ {
+        let entries = self.keys.kernel.entries.iter();
+        entries.map(|(dot, key)| (dot, key, self.value(dot)))
+    }
+
+    fn value(&self, dot: &Dot) -> &V {
+        self.values.get(dot).expect("every entry has a value")
+    }
+
+    fn fuse(keys: &DotKernel<K>, values: &BTreeMap<Dot, V>) -> Deltas<K, V> {
+        let entries = keys.entries.iter().map(|(dot, key)| {
+            let val = values.get(dot).expect("every entry has a value");
+            let kv = KeyVal {
+                key: key.clone(),
+                val: val.clone(),
+            };
+            (*dot, kv)
+        });
+        DotKernel {
+            ctx: keys.ctx.clone(),
+            entries: entries.collect(),
+        }
+    }
+
+    fn unfuse(fused: Deltas<K, V>) -> (DotKernel<K>, BTreeMap<Dot, V>) {
+        let mut values = BTreeMap::new();
+        let entries = fused.entries.into_iter().map(|(dot, KeyVal { key, val })| {
+            values.insert(dot, val);
+            (dot, key)
+        });
+        let keys = DotKernel {
+            entries: entries.collect(),
+            ctx: fused.ctx,
+        };
+        (keys, values)
+    }
+
+    fn from_fused(fused: Fused<K, V>) -> Self {
+        let (kernel, values) = Self::unfuse(fused.kernel);
+        let (delta, delta_values) = match fused.delta.map(Self::unfuse) {
+            Some((delta, values)) => (Some(delta), values),
+            None => (None, BTreeMap::new()),
+        };
+        let mut index = BTreeMap::<K, BTreeSet<Dot>>::new();
+        for (dot, key) in kernel.entries.iter() {
+            index.entry(key.clone()).or_default().insert(*dot);
+        }
+        Self {
+            keys: AWORSet { kernel, delta },
+            values,
+            delta_values,
+            index,
+            policy: Policy::default(),
+            #[cfg(feature = "history")]
+            history: History(BTreeMap::new()),
+        }
+    }
+
+    fn fused(&self) -> Fused<K, V> {
+        Fused {
+            kernel: self.fused_kernel(),
+            delta: (self.keys.delta.as_ref()).map(|delta| Self::fuse(delta, &self.delta_values)),
+        }
+    }
+
+    /// Drops the value of an entry that left the kernel, into the history with the `history`
+    /// feature
+    fn forget(&mut self, dot: Dot, key: &K) {
+        let val = self.values.remove(&dot);
+        #[cfg(feature = "history")]
+        if let Some(val) = val {
+            self.history.record(dot, key, val);
+        }
+        #[cfg(not(feature = "history"))]
+        let _ = (val, key);
+    }
+
+    /// Values of entries that left the delta buffer
+    fn prune_delta_values(&mut self) {
+        match &self.keys.delta {
+            Some(delta) => (self.delta_values).retain(|dot, _| delta.entries.contains_key(dot)),
+            None => self.delta_values.clear(),
+        }
+    }
+
+    /// Runs `merge` on the set of keys and then patches the values and the index, only the dots
+    /// `ctx` knows about can have been added or removed by merging. New entries of the set take
+    /// their value from `values`, new entries of its delta buffer from `delta_values`.
+    fn merge_kernel_in_place<F: FnOnce(&mut AWORSet<K>)>(
+        &mut self,
+        ctx: &DotCtx,
+        values: &BTreeMap<Dot, V>,
+        delta_values: &BTreeMap<Dot, V>,
+        merge: F,
+    ) {
+        let touched = ctx
+            .ranges()
+            .flat_map(|DotRange(id, range)| {
+                self.keys
+                    .kernel
+                    .entries
+                    .range(Dot(id, range.start)..Dot(id, range.end))
+                    .map(|(dot, key)| (*dot, key.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        merge(&mut self.keys);
+
+        for (dot, key) in touched {
+            if self.keys.kernel.entries.contains_key(&dot) {
+                continue;
+            }
+            self.forget(dot, &key);
+            if let Some(dots) = self.index.get_mut(&key) {
+                dots.remove(&dot);
+                if dots.is_empty() {
+                    self.index.remove(&key);
+                }
+            }
+        }
+        for (dot, val) in values {
+            if let Some(key) = self.keys.kernel.entries.get(dot) {
+                self.index.entry(key.clone()).or_default().insert(*dot);
+                self.values.entry(*dot).or_insert_with(|| val.clone());
+            }
+        }
+
+        self.prune_delta_values();
+        if let Some(delta) = &self.keys.delta {
+            for (dot, val) in delta_values {
+                if delta.entries.contains_key(dot) {
+                    self.delta_values.entry(*dot).or_insert_with(|| val.clone());
+                }
+            }
+        }
     }
 }
 
-/// Key-value pair so it can implement Serializable, note that
-/// it also implements PartialEq but only compares keys
-#[derive(Clone, Debug, Default, fp_bindgen::prelude::Serializable)]
-#[fp(rust_plugin_module = "sypytkowski_convergent::delta_state::awormap")]
-pub struct KeyVal<K: Clone + PartialEq + Default + Debug + Ord + Value, V: Value + Default + Debug>
+impl<K, V> AWORMap<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Value + Clone + Convergent,
 {
-    key: K,
-    val: V,
+    /// Value of `key` with concurrent values merged together
+    pub fn get_merged(&self, key: &K) -> Option<V> {
+        let mut values = self.get_all(key);
+        let first = values.next()?.clone();
+        Some(values.fold(first, |acc, val| acc.merge(val)))
+    }
+
+    /// Iterate entries in key order with concurrent values merged together
+    pub fn iter_merged(&self) -> impl Iterator<Item = (&K, V)> + '_ {
+        self.index
+            .keys()
+            .filter_map(|key| self.get_merged(key).map(|val| (key, val)))
+    }
+
+    /// An empty map merging concurrent values with [`ConflictPolicy::MergeConvergent`]
+    pub fn with_merge_policy() -> Self {
+        let mut map = Self::default();
+        map.set_merge_policy();
+        map
+    }
+
+    /// Reads conflicts with [`ConflictPolicy::MergeConvergent`], merging concurrent values.
+    /// The map keeps knowing how to merge them if its policy is changed again.
+    pub fn set_merge_policy(&mut self) {
+        self.policy = Policy {
+            kind: ConflictPolicy::MergeConvergent,
+            merge: Some(<V as Convergent>::merge),
+        };
+    }
+
+    /// Replaces the concurrent values of `key` with their merge, so later readers see a single
+    /// value. Does nothing if the key isn't conflicted.
+    pub fn resolve(&mut self, replica: ReplicaId, key: &K) {
+        if !self.is_conflicted(key) {
+            return;
+        }
+        if let Some(val) = self.get_merged(key) {
+            self.insert(replica, key.clone(), val);
+        }
+    }
+}
+
+/// Digest sync: a replica sends its [`AWORMap::digest`], the other side answers with
+/// [`AWORMap::extract`] of the keys [`AWORMap::diff`] finds, and the same the other way around.
+impl<K, V> AWORMap<K, V>
+where
+    K: Clone + PartialEq + Ord + Value + serde::Serialize,
+    V: Value + Clone + serde::Serialize,
+{
+    /// Hash tree over the entries, concurrent values of a key and the dots holding them included
+    pub fn digest(&self) -> MapDigest {
+        let mut leaves = vec![0u64; DIGEST_LEAVES];
+        for (dot, key, val) in self.entries() {
+            let mut hasher = StableHasher::default();
+            // Hashed the same as the `KeyVal` it used to be
+            hasher.write_serialized(&(dot, (key, val)));
+            // Summing keeps the leaf independent of the order entries are visited in
+            let leaf = &mut leaves[MapDigest::bucket(stable_hash(key))];
+            *leaf = leaf.wrapping_add(hasher.finish());
+        }
+        MapDigest::from_leaves(leaves.into_iter().map(fold).collect())
+    }
+
+    /// Keys of this map that fall into a bucket where `remote` differs. Keys only the remote has
+    /// can't be known from here, the remote finds those by diffing against our digest.
+    ///
+    /// A key removed here but still present on the remote makes its bucket differ without
+    /// showing up in the diff, [`AWORMap::extract`] carries the removal regardless of the keys.
+    pub fn diff(&self, remote: &MapDigest) -> Vec<K> {
+        let divergent = self.digest().divergent_buckets(remote);
+        self.index
+            .keys()
+            .filter(|key| divergent[MapDigest::bucket(stable_hash(key))])
+            .cloned()
+            .collect()
+    }
+
+    /// Partial state holding only `keys`, to be merged with [`AWORMap::merge_delta`].
+    ///
+    /// The context is ours minus the dots of the entries left out, so merging it can't remove
+    /// them on the other side while every removal we know of still applies. Entries whose dots
+    /// can't be taken out of the context, those of retired replicas, are always included.
+    pub fn extract(&self, keys: &[K]) -> Deltas<K, V> {
+        let keys = keys.iter().collect::<BTreeSet<_>>();
+        let mut ctx = self.keys.kernel.ctx.clone();
+        let mut entries = BTreeMap::new();
+        for (dot, key, val) in self.entries() {
+            if !keys.contains(key) {
+                ctx.remove(*dot);
+                if !ctx.contains(*dot) {
+                    continue;
+                }
+            }
+            let kv = KeyVal {
+                key: key.clone(),
+                val: val.clone(),
+            };
+            entries.insert(*dot, kv);
+        }
+        DotKernel { entries, ctx }
+    }
 }
 
-impl<K, V> Eq for KeyVal<K, V>
+impl<K, V> Serialize for AWORMap<K, V>
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value,
-    V: Value + Default + Debug,
+    K: Clone + PartialEq + Ord + Value + serde::Serialize,
+    V: Value + Clone + serde::Serialize,
 {
-    fn assert_receiver_is_total_eq(&self) {}
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut map = serializer.serialize_struct("AWORMap", 1)?;
+        map.serialize_field("keys", &self.fused())?;
+        map.end()
+    }
 }
-impl<K, V> PartialOrd for KeyVal<K, V>
+
+impl<'de, K, V> Deserialize<'de> for AWORMap<K, V>
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value,
-    V: Value + Default + Debug,
+    K: Clone + PartialEq + Ord + Value + serde::Deserialize<'de>,
+    V: Value + Clone + serde::Deserialize<'de>,
 {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.key.cmp(&other.key))
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        AWORMapDeserializer::<K, V>::deserialize(deserializer).map(|map| Self::from_fused(map.keys))
     }
 }
 
-impl<K, V> Ord for KeyVal<K, V>
+/// Encoded like the `AWORSet` of [`KeyVal`]s it used to be, the index is rebuilt when decoding
+impl<K, V> Encode for AWORMap<K, V>
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value,
-    V: Value + Default + Debug,
+    K: Clone + PartialEq + Ord + Value + Encode,
+    V: Value + Clone + Encode,
 {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        unsafe { self.partial_cmp(&other).unwrap_unchecked() }
+    fn encode(&self, out: &mut Writer) {
+        let fused = self.fused();
+        fused.kernel.encode(out);
+        fused.delta.encode(out);
     }
 }
 
-impl<K, V> Hash for KeyVal<K, V>
+impl<K, V> Decode for AWORMap<K, V>
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value + Hash,
-    V: Value + Clone + Default + Debug + Hash,
+    K: Clone + PartialEq + Ord + Value + Decode,
+    V: Value + Clone + Decode,
 {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.key.hash(state);
-        self.val.hash(state);
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let fused = Fused {
+            kernel: DotKernel::decode(input)?,
+            delta: Option::decode(input)?,
+        };
+        Ok(Self::from_fused(fused))
     }
 }
 
-impl<K, V> PartialEq for KeyVal<K, V>
+/// The values and the index count too, and so does the history with the `history` feature
+impl<K, V> MemoryUsage for AWORMap<K, V>
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value,
-    V: Value + Default + Debug,
+    K: Clone + PartialEq + Ord + Value,
+    V: Value + Clone,
 {
-    fn eq(&self, other: &Self) -> bool {
-        self.key == other.key
+    fn memory_usage(&self) -> Usage {
+        let dots = self.index.values().map(|dots| Usage::btree::<Dot, ()>(dots.len()));
+        let usage = self.keys.memory_usage()
+            + Usage::btree::<Dot, V>(self.values.len() + self.delta_values.len())
+            + Usage::btree::<K, BTreeSet<Dot>>(self.index.len())
+            + dots.sum();
+        #[cfg(feature = "history")]
+        let usage = usage
+            + Usage::btree::<K, Vec<Superseded<V>>>(self.history.0.len())
+            + (self.history.0.values())
+                .map(|superseded| Usage::vec::<Superseded<V>>(superseded.len()))
+                .sum();
+        usage
+    }
+}
+
+#[derive(serde_derive::Deserialize)]
+struct AWORMapDeserializer<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Value + Clone,
+{
+    keys: Fused<K, V>,
+}
+
+/// Shaped like the `AWORSet` of [`KeyVal`]s the map used to keep, which is how it's still written
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename = "AWORSet")]
+struct Fused<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Value + Clone,
+{
+    kernel: Deltas<K, V>,
+    delta: Option<Deltas<K, V>>,
+}
+
+/// A key along with its value, the entries of [`Deltas`]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::awormap")
+)]
+pub struct KeyVal<K: Clone + PartialEq + Ord + Value, V: Value> {
+    key: K,
+    val: V,
+}
+
+impl<K, V> KeyVal<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Value,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn val(&self) -> &V {
+        &self.val
     }
 }
 
 impl<K, V> Value for KeyVal<K, V>
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value + Value,
-    V: Value + Default + Debug,
+    K: Clone + PartialEq + Ord + Value,
+    V: Value,
 {
 }
 
 // impl<K, V> fp_bindgen::prelude::Serializable for KeyVal<K, V>
 // where
 //     K: Clone + PartialEq + Default + Debug + Ord + Value + Value,
-//     V: Value + Default + Debug,
+//     V: Value + Debug,
 // {
 //     fn ident() -> fp_bindgen::types::TypeIdent {
 //         fp_bindgen::types::TypeIdent::new(
@@ -179,8 +881,8 @@ where
 
 impl<K, V> Serialize for KeyVal<K, V>
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value + Value + serde::Serialize,
-    V: Value + Default + Debug + serde::Serialize,
+    K: Clone + PartialEq + Ord + Value + Value + serde::Serialize,
+    V: Value + serde::Serialize,
 {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -195,8 +897,8 @@ where
 
 impl<'de, K, V> Deserialize<'de> for KeyVal<K, V>
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value + Value + serde::Deserialize<'de>,
-    V: Value + Default + Debug + serde::Deserialize<'de>,
+    K: Clone + PartialEq + Ord + Value + Value + serde::Deserialize<'de>,
+    V: Value + serde::Deserialize<'de>,
 {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -209,11 +911,35 @@ where
     }
 }
 
+impl<K, V> Encode for KeyVal<K, V>
+where
+    K: Clone + PartialEq + Ord + Value + Encode,
+    V: Value + Encode,
+{
+    fn encode(&self, out: &mut Writer) {
+        self.key.encode(out);
+        self.val.encode(out);
+    }
+}
+
+impl<K, V> Decode for KeyVal<K, V>
+where
+    K: Clone + PartialEq + Ord + Value + Decode,
+    V: Value + Decode,
+{
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(KeyVal {
+            key: K::decode(input)?,
+            val: V::decode(input)?,
+        })
+    }
+}
+
 #[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 struct KeyValVisitor<K, V>(K, V)
 where
-    K: Clone + PartialEq + Default + Debug + Ord + Value + Value,
-    V: Value + Default + Debug;
+    K: Clone + PartialEq + Ord + Value + Value,
+    V: Value;
 
 #[cfg(test)]
 mod test {
@@ -221,7 +947,7 @@ mod test {
 
     use crate::ReplicaGenerator;
 
-    use super::AWORMap;
+    use super::{AWORMap, ConflictPolicy, Deltas, Dot, Resolved};
 
     #[test]
     fn test3() {
@@ -243,7 +969,7 @@ mod test {
         let mut a = AWORMap::<u64, u64>::default();
         a.insert(a_id, 1, 420);
         let mut b = a.clone();
-        b.remove(b_id, 1);
+        b.remove(&1);
         b.insert(b_id, 420, 69);
         let (a, a_deltas) = a.split_expect_deltas();
         b.merge_delta(a_deltas);
@@ -267,7 +993,7 @@ mod test {
         b.insert(a_id, 1, 421);
 
         let mut c = AWORMap::default();
-        c.merge_delta(b.keys.delta.clone().unwrap());
+        c.merge_delta(b.clone().split_mut().unwrap());
         println!("B: {:#?}\n\n{:#?}", b, b.values());
         println!("C: {:#?}\n\n{:#?}", c, c.values());
     }
@@ -295,53 +1021,435 @@ mod test {
         println!("A: {:#?}\n\nB: {:#?}", a.values(), b.values());
     }
 
-    mod properties {
-        use proptest::prelude::*;
-        use std::fmt::Debug;
-
-        use crate::{
-            delta_state::{
-                awormap::{AWORMap, KeyVal},
-                aworset::{self, test::properties::aworset_strategy_impl},
-            },
-            Value,
+    #[test]
+    fn get_and_remove() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 420);
+        a.insert(a_id, 2, 69);
+        a.insert(a_id, 1, 421);
+
+        assert_eq!(a.get(&1), Some(&421));
+        assert_eq!(a.get(&3), None);
+        assert!(a.contains_key(&2));
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![(&1, &421), (&2, &69)]);
+        assert_eq!(a.keys().collect::<Vec<_>>(), vec![&1, &2]);
+
+        let mut b = AWORMap::<u64, u64>::default();
+        b.merge_delta(a.split_mut().unwrap());
+        b.remove(&1);
+        assert!(!b.contains_key(&1));
+        assert_eq!(b.len(), 1);
+
+        a.merge_delta(b.split_mut().unwrap());
+        assert_eq!(a.get(&1), None);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![(&2, &69)]);
+
+        b.insert(b_id, 1, 0);
+        a.merge_delta(b.split_mut().unwrap());
+        assert_eq!(a.get(&1), Some(&0));
+    }
+
+    #[test]
+    fn concurrent_inserts_keep_all_values() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        let mut b = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 420);
+        b.insert(b_id, 1, 69);
+
+        let a_deltas = a.split_mut().unwrap();
+        let b_deltas = b.split_mut().unwrap();
+        a.merge_delta(b_deltas);
+        b.merge_delta(a_deltas);
+
+        assert_eq!(a.keys.kernel, b.keys.kernel);
+        assert_eq!(a.len(), 1);
+        assert!(a.is_conflicted(&1));
+        assert_eq!(a.get_all(&1).copied().collect::<Vec<_>>(), vec![420, 69]);
+        assert_eq!(a.get_merged(&1), Some(420));
+        assert_eq!(a.iter_merged().collect::<Vec<_>>(), vec![(&1, 420)]);
+
+        // An insert that observed both values replaces them
+        b.insert(b_id, 1, 0);
+        a.merge_delta(b.split_mut().unwrap());
+        assert!(!a.is_conflicted(&1));
+        assert_eq!(a.get_all(&1).copied().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn resolve_collapses_concurrent_values() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        let mut b = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 1);
+        b.insert(b_id, 1, 2);
+        a.merge_delta(b.split_mut().unwrap());
+        b.merge_delta(a.split_mut().unwrap());
+
+        a.resolve(a_id, &1);
+        assert!(!a.is_conflicted(&1));
+        assert_eq!(a.get(&1), Some(&2));
+
+        b.merge_delta(a.split_mut().unwrap());
+        assert_eq!(b.get_all(&1).copied().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn conflict_policies() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::with_policy(ConflictPolicy::KeepBoth);
+        let mut b = AWORMap::<u64, u64>::with_merge_policy();
+        a.insert(a_id, 1, 3);
+        a.insert(a_id, 2, 1);
+        b.insert(b_id, 1, 2);
+        a.merge_delta(b.split_mut().unwrap());
+        b.merge_delta(a.split_mut().unwrap());
+
+        assert_eq!(a.get_resolved(&1), Some(Resolved::Concurrent(vec![&3, &2])));
+        assert_eq!(a.get_resolved(&2), Some(Resolved::Concurrent(vec![&1])));
+        assert_eq!(b.get_resolved(&1), Some(Resolved::Merged(3)));
+        assert_eq!(b.get_resolved(&2), Some(Resolved::Value(&1)));
+        assert_eq!(b.get_resolved(&3), None);
+
+        // Policies only change reads, both replicas hold the same entries
+        b.set_policy(ConflictPolicy::LastWriterWins);
+        assert_eq!(b.get_resolved(&1), Some(Resolved::Value(&2)));
+        assert_eq!(
+            b.iter_resolved().collect::<Vec<_>>(),
+            vec![(&1, Resolved::Value(&2)), (&2, Resolved::Value(&1))]
+        );
+        assert_eq!(a.fused_kernel(), b.fused_kernel());
+        // Nor does it take part in comparing maps
+        assert_eq!(
+            AWORMap::<u64, u64>::with_policy(ConflictPolicy::KeepBoth),
+            AWORMap::with_merge_policy()
+        );
+        // Still merges once the map knows how to
+        b.set_policy(ConflictPolicy::MergeConvergent);
+        assert_eq!(b.get_resolved(&1), Some(Resolved::Merged(3)));
+    }
+
+    #[test]
+    fn conflict_policies_without_merge() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = AWORMap::<u64, String>::with_policy(ConflictPolicy::KeepBoth);
+        let mut b = AWORMap::<u64, String>::with_policy(ConflictPolicy::MergeConvergent);
+        a.insert(a_id, 1, "a".to_string());
+        b.insert(b_id, 1, "b".to_string());
+        a.merge_delta(b.split_mut().unwrap());
+        b.merge_delta(a.split_mut().unwrap());
+
+        let (val_a, val_b) = ("a".to_string(), "b".to_string());
+        let both = Some(Resolved::Concurrent(vec![&val_a, &val_b]));
+        assert_eq!(a.get_resolved(&1), both);
+        // Strings don't merge, their concurrent values are all read
+        assert_eq!(b.get_resolved(&1), both);
+        b.set_policy(ConflictPolicy::LastWriterWins);
+        assert_eq!(b.get_resolved(&1), Some(Resolved::Value(&val_b)));
+    }
+
+    #[test]
+    fn split_for_keys() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        let mut b = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 10);
+        a.insert(a_id, 2, 20);
+        a.insert(a_id, 3, 30);
+
+        b.merge_delta(a.split_for_keys(&[1, 3]).unwrap());
+        assert_eq!(b.iter().collect::<Vec<_>>(), vec![(&1, &10), (&3, &30)]);
+        assert_eq!(a.delta_len(), 1);
+
+        b.merge_delta(a.split_mut().unwrap());
+        assert_eq!(b.iter().collect::<Vec<_>>(), a.iter().collect::<Vec<_>>());
+        assert_eq!(b.reindexed(), b);
+    }
+
+    #[test]
+    fn delta_since() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        let mut b = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 10);
+        a.insert(a_id, 2, 20);
+        b.merge_delta(a.split_mut().unwrap());
+        let seen = b.keys.kernel.ctx.clock.clone();
+
+        a.insert(a_id, 1, 11);
+        a.remove(&2);
+        let delta = a.delta_since(&seen);
+        assert_eq!(delta.entries.len(), 1);
+        b.merge_delta(delta);
+        assert_eq!(b.iter().collect::<Vec<_>>(), vec![(&1, &11)]);
+    }
+
+    #[test]
+    fn version() {
+        let mut gen = ReplicaGenerator::new();
+        let (a_id, b_id) = (gen.gen(), gen.gen());
+
+        let mut a = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 10);
+        a.insert(a_id, 2, 20);
+        let mut b = AWORMap::<u64, u64>::default();
+        b.insert(b_id, 3, 30);
+        b.split_mut();
+        a.split_mut();
+
+        // Removed entries still count, their dots stay in the context
+        a.remove(&1);
+        a.insert(a_id, 4, 40);
+        let delta = a.split_mut().unwrap();
+        assert_eq!(delta.version().get(&a_id), Some(&3));
+        b.merge_delta(delta);
+        assert_eq!(b.version().get(&a_id), Some(&3));
+        assert_eq!(b.version().get(&b_id), Some(&1));
+        assert_eq!(a.version().get(&b_id), None);
+    }
+
+    #[test]
+    fn affected_keys() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 10);
+        a.insert(a_id, 2, 20);
+        a.insert(a_id, 3, 30);
+        let b = a.clone();
+        let _ = a.split_mut();
+
+        a.insert(a_id, 1, 11);
+        a.remove(&2);
+        let delta = a.split_mut().unwrap();
+        assert_eq!(b.affected_keys(&delta), [1, 2].into());
+        assert!(a.affected_keys(&Deltas::default()).is_empty());
+    }
+
+    #[test]
+    fn dots_for() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        let mut b = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 1);
+        a.insert(a_id, 1, 2);
+        b.insert(b_id, 1, 3);
+        a.merge_delta(b.split_mut().unwrap());
+
+        assert_eq!(a.dots_for(&1), vec![Dot(a_id, 2), Dot(b_id, 1)]);
+        assert!(a.dots_for(&2).is_empty());
+    }
+
+    #[cfg(feature = "history")]
+    #[test]
+    fn history() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        let mut b = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 1);
+        b.merge_delta(a.split_mut().unwrap());
+        b.insert(b_id, 1, 2);
+        b.remove(&1);
+        a.merge_delta(b.split_mut().unwrap());
+
+        let history = |map: &AWORMap<u64, u64>| {
+            map.history(&1)
+                .iter()
+                .map(|superseded| (superseded.dot, superseded.val))
+                .collect::<Vec<_>>()
         };
+        assert_eq!(history(&b), vec![(Dot(a_id, 1), 1), (Dot(b_id, 1), 2)]);
+        // A never saw 2, only that its own value went away
+        assert_eq!(history(&a), vec![(Dot(a_id, 1), 1)]);
+        // History doesn't take part in comparing maps
+        let mut cleared = a.clone();
+        cleared.clear_history();
+        assert!(cleared.history(&1).is_empty());
+        assert_eq!(a, cleared);
+    }
 
-        fn keyval_strategy<
-            K: Clone + PartialEq + Default + Debug + Value + Ord,
-            V: Value + Default + Debug + Clone,
-        >(
-            key_strat: impl Strategy<Value = K>,
-            value_strat: impl Strategy<Value = V>,
-        ) -> impl Strategy<Value = KeyVal<K, V>> {
-            (key_strat, value_strat).prop_map(|(key, val)| KeyVal { key, val })
-        }
+    #[test]
+    fn values_without_default() {
+        #[derive(Clone, Debug, PartialEq)]
+        #[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+        struct Color(u8, u8, u8);
+        impl crate::Value for Color {}
+
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
 
-        fn awormap_strategy_impl<
-            K: Clone + PartialEq + Default + Debug + Value + Ord,
-            V: Value + Default + Debug + Clone,
-        >(
-            key_strat: impl Strategy<Value = K> + 'static,
-            value_strat: impl Strategy<Value = V> + 'static,
-        ) -> impl Strategy<Value = AWORMap<K, V>> {
-            aworset_strategy_impl(keyval_strategy(key_strat, value_strat))
-                .prop_map(|keys| AWORMap { keys })
+        let mut a = AWORMap::<u64, Color>::default();
+        let mut b = AWORMap::<u64, Color>::default();
+        a.insert(a_id, 1, Color(255, 0, 0));
+        let delta = a.split_mut().unwrap();
+        b.merge_delta_in_place(&delta);
+        assert_eq!(b.iter().collect::<Vec<_>>(), vec![(&1, &Color(255, 0, 0))]);
+
+        // Entries of deltas compare their values too, not just the keys
+        let mut changed = delta.clone();
+        changed.entries.get_mut(&Dot(a_id, 1)).unwrap().val = Color(0, 0, 255);
+        assert_ne!(delta, changed);
+    }
+
+    #[test]
+    fn index_survives_serde() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 420);
+        a.insert(a_id, 2, 69);
+        let json = serde_json::to_string(&a).unwrap();
+        let b: AWORMap<u64, u64> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(a, b);
+        assert_eq!(b.get(&2), Some(&69));
+    }
+
+    #[test]
+    fn digest_sync() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        for key in 0..100 {
+            a.insert(a_id, key, key);
         }
+        let mut b = a.clone();
+        assert_eq!(a.digest(), b.digest());
+        assert!(b.diff(&a.digest()).is_empty());
+
+        a.insert(a_id, 1, 420);
+        a.remove(&2);
+        b.insert(b_id, 200, 69);
+
+        // B answers A's digest with its keys in buckets that differ, A ignores the stale value of 1
+        let b_digest = b.digest();
+        let keys = b.diff(&a.digest());
+        assert!(keys.contains(&1) && keys.contains(&200));
+        let partial = b.extract(&keys);
+        assert!(partial.entries.len() < 10);
+        a.merge_delta(partial);
+
+        // A does the same for B, which also carries the removal of 2 in A's context
+        let keys = a.diff(&b_digest);
+        b.merge_delta(a.extract(&keys));
+
+        assert_eq!(a.digest(), b.digest());
+        assert_eq!(a.keys.kernel.entries, b.keys.kernel.entries);
+        assert_eq!(b.get_all(&1).copied().collect::<Vec<_>>(), vec![420]);
+        assert_eq!(b.get(&2), None);
+        assert_eq!(a.get(&200), Some(&69));
+    }
+
+    #[test]
+    fn sizes() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 420);
+        assert_eq!(a.delta_len(), 1);
+        let first = a.split_mut().unwrap();
+        assert_eq!(a.delta_len(), 0);
+        a.insert(a_id, 2, 69);
+        let second = a.split_mut().unwrap();
+
+        // The second add arrives first, its dot waits in the cloud for the first one
+        let mut b = AWORMap::<u64, u64>::default();
+        b.merge_delta(second);
+        assert_eq!(b.dot_cloud_len(), 1);
+        b.merge_delta(first);
+        b.compact_ctx();
+        assert_eq!(b.dot_cloud_len(), 0);
+        assert_eq!(b.len(), 2);
+    }
+
+    #[test]
+    fn uncovered_dot() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+
+        let mut a = AWORMap::<u64, u64>::default();
+        a.insert(a_id, 1, 420);
+        assert_eq!(a.uncovered_dot(), None);
+        let mut delta = a.split_mut().unwrap();
+        assert_eq!(delta.uncovered_dot(), None);
+
+        // Context lost on the way, nobody saw the dot of the entry
+        delta.ctx = Default::default();
+        assert_eq!(delta.uncovered_dot(), Some(Dot(a_id, 1)));
+    }
+
+    mod properties {
+        use proptest::prelude::*;
+
+        use crate::delta_state::{
+            awormap::{AWORMap, Fused, KeyVal},
+            aworset::{self, test::properties::aworset_strategy_impl},
+            dot::DotKernel,
+        };
+
+        /// Values come from the dot, so an entry has the same value in every generated map
         fn awormap_strategy() -> impl Strategy<Value = AWORMap<u16, u16>> {
-            awormap_strategy_impl(any::<u16>(), any::<u16>())
+            aworset_strategy_impl(any::<u16>()).prop_map(|keys| {
+                let entries = keys.kernel.entries.iter().map(|(dot, key)| {
+                    let kv = KeyVal {
+                        key: *key,
+                        val: dot.1 as u16,
+                    };
+                    (*dot, kv)
+                });
+                let kernel = DotKernel {
+                    ctx: keys.kernel.ctx,
+                    entries: entries.collect(),
+                };
+                AWORMap::from_fused(Fused {
+                    kernel,
+                    delta: None,
+                })
+            })
         }
 
-        fn patch<
-            K: Clone + PartialEq + Default + Debug + Value + Ord,
-            V: Value + Default + Debug + Clone,
-        >(
-            awormaps: &mut [&mut AWORMap<K, V>],
-        ) {
+        fn patch(awormaps: &mut [&mut AWORMap<u16, u16>]) {
             let mut aworsets = awormaps
                 .iter_mut()
                 .map(|awormap| &mut awormap.keys)
                 .collect::<Vec<_>>();
             aworset::test::properties::patch(&mut aworsets);
+
+            // Patching removes entries behind the index's back
+            for map in awormaps.iter_mut() {
+                **map = map.reindexed();
+            }
         }
 
         proptest! {
@@ -379,47 +1487,166 @@ mod test {
 
                 assert_eq!(aa, a);
             }
-        }
-
-        // TODO: finish
-        // mod delta {
-        //     use proptest::prelude::*;
 
-        //     use crate::delta_state::awormap::test::properties::{awormap_strategy, patch};
+            /// Exchanging digests and extracting the divergent keys both ways ends up with the
+            /// same entries as merging the full states
+            #[test]
+            fn digest_sync(mut a in awormap_strategy(), mut b in awormap_strategy()) {
+                patch(&mut [&mut a, &mut b]);
+                let merged = a.merge(&b);
 
-        //     proptest! {
-        //         // #![proptest_config(ProptestConfig{ cases: 1, ..Default::default()})]
-        //         #![proptest_config(ProptestConfig{ ..Default::default()})]
+                let b_digest = b.digest();
+                a.merge_delta(b.extract(&b.diff(&a.digest())));
+                b.merge_delta(a.extract(&a.diff(&b_digest)));
 
-        //         #[test]
-        //         fn commutativity(mut a in awormap_strategy(), mut b in awormap_strategy()) {
-        //             patch(&mut [&mut a, &mut b]);
+                assert_eq!(&a.keys.kernel.entries, &merged.keys.kernel.entries);
+                assert_eq!(&b.keys.kernel.entries, &merged.keys.kernel.entries);
+            }
 
-        //             let (mut a, a_deltas) = a.split_expect_deltas();
-        //             let (mut b, b_deltas) = b.split_expect_deltas();
+            /// The incrementally maintained values and index must match ones rebuilt from scratch
+            #[test]
+            fn index_consistency(mut a in awormap_strategy(), mut b in awormap_strategy()) {
+                patch(&mut [&mut a, &mut b]);
 
-        //             let a = a.merge_delta(b_deltas);
-        //             let b = b.merge_delta(a_deltas);
+                let mut merged = a.clone();
+                merged.merge_in_place(&b);
+                assert_eq!(merged, merged.reindexed());
 
-        //             assert_eq!(a, b);
-        //         }
+                let mut merged = a.clone();
+                merged.merge_delta_in_place(&b.fused_kernel());
+                assert_eq!(merged, merged.reindexed());
+            }
+        }
 
-        //         #[test]
-        //         fn associativity(mut a in awormap_strategy(), mut b in awormap_strategy(), mut c in awormap_strategy()) {
-        //             patch(&mut [&mut a, &mut b, &mut c]);
-        //             let ab_c = a.merge(&b).merge(&c);
-        //             let a_bc = a.merge(&b.merge(&c));
+        mod delta {
+            use proptest::prelude::*;
+
+            use crate::{
+                delta_state::{
+                    awormap::{AWORMap, Deltas},
+                    aworset::test::properties::{history_strategy, Op},
+                },
+                ReplicaId,
+            };
+
+            /// Same as replaying on sets, adds insert a key and removes pick one of the keys
+            fn replay(
+                replicas: usize,
+                history: Vec<(usize, Op<(u16, u16)>)>,
+            ) -> Vec<AWORMap<u16, u16>> {
+                let mut maps = vec![AWORMap::default(); replicas];
+                for (i, op) in history {
+                    match op {
+                        Op::Add((key, val)) => maps[i].insert(ReplicaId::from(i as u64), key, val),
+                        Op::Remove(n) => {
+                            let keys = maps[i].iter().map(|(k, _)| *k).collect::<Vec<_>>();
+                            if !keys.is_empty() {
+                                maps[i].remove(&keys[n % keys.len()]);
+                            }
+                        }
+                        Op::MergeDelta(from) => {
+                            if let Some(delta) = maps[from].clone().split_mut() {
+                                maps[i].merge_delta(delta);
+                            }
+                        }
+                    }
+                }
+                maps
+            }
 
-        //             assert_eq!(ab_c, a_bc);
-        //         }
+            fn replicas_strategy() -> impl Strategy<Value = Vec<AWORMap<u16, u16>>> {
+                history_strategy((0..16u16, any::<u16>()), 3).prop_map(|history| replay(3, history))
+            }
 
-        //         #[test]
-        //         fn idempotency(a in awormap_strategy()) {
-        //             let aa = a.merge(&a);
+            fn delta(mut map: AWORMap<u16, u16>) -> Deltas<u16, u16> {
+                map.split_mut().unwrap_or_default()
+            }
 
-        //             assert_eq!(aa, a);
-        //         }
-        //     }
-        // }
+            proptest! {
+                #![proptest_config(ProptestConfig{ ..Default::default()})]
+
+                #[test]
+                fn commutativity(maps in replicas_strategy()) {
+                    let [a, b, c] = <[_; 3]>::try_from(maps).unwrap();
+                    let (db, dc) = (delta(b), delta(c));
+
+                    let mut bc = a.clone();
+                    bc.merge_delta(db.clone());
+                    bc.merge_delta(dc.clone());
+                    let mut cb = a;
+                    cb.merge_delta(dc);
+                    cb.merge_delta(db);
+
+                    assert_eq!(bc, cb);
+                }
+
+                #[test]
+                fn associativity(maps in replicas_strategy()) {
+                    let [a, b, c] = <[_; 3]>::try_from(maps).unwrap();
+                    let (db, dc) = (delta(b), delta(c));
+
+                    let mut ab_c = a.clone();
+                    ab_c.merge_delta(db.clone());
+                    ab_c.merge_delta(dc.clone());
+                    let mut a_bc = a;
+                    a_bc.merge_delta(db.merge(&dc));
+
+                    assert_eq!(ab_c, a_bc);
+                }
+
+                #[test]
+                fn idempotency(maps in replicas_strategy()) {
+                    let [a, b, _] = <[_; 3]>::try_from(maps).unwrap();
+                    let db = delta(b);
+
+                    let mut once = a;
+                    once.merge_delta(db.clone());
+                    let mut twice = once.clone();
+                    twice.merge_delta(db);
+
+                    assert_eq!(once, twice);
+                }
+
+                /// Partial states from [`AWORMap::extract`] are deltas too, applying them in any
+                /// order or twice changes nothing
+                #[test]
+                fn extract(maps in replicas_strategy(), keys in proptest::collection::vec(0..16u16, 0..8)) {
+                    let [a, b, c] = <[_; 3]>::try_from(maps).unwrap();
+                    let (db, dc) = (b.extract(&keys), c.extract(&keys));
+
+                    let mut bc = a.clone();
+                    bc.merge_delta(db.clone());
+                    bc.merge_delta(dc.clone());
+                    bc.merge_delta(db.clone());
+                    let mut cb = a;
+                    cb.merge_delta(dc);
+                    cb.merge_delta(db);
+
+                    assert_eq!(bc, cb);
+                    assert_eq!(bc, bc.reindexed());
+                }
+
+                /// Every replica merging the buffers of all the others ends up with the same
+                /// entries, and an index matching them
+                #[test]
+                fn converge(mut maps in replicas_strategy()) {
+                    let deltas = maps.iter().map(|map| map.clone().split_mut()).collect::<Vec<_>>();
+                    for (i, map) in maps.iter_mut().enumerate() {
+                        for (_, delta) in deltas.iter().enumerate().filter(|(j, _)| *j != i) {
+                            if let Some(delta) = delta {
+                                map.merge_delta_in_place(delta);
+                            }
+                        }
+                    }
+
+                    for map in maps.iter() {
+                        assert_eq!(map.keys.kernel, maps[0].keys.kernel);
+                        assert_eq!(map.values, maps[0].values);
+                        assert_eq!(map.index, maps[0].index);
+                        assert_eq!(map.index, map.reindexed().index);
+                    }
+                }
+            }
+        }
     }
 }
@@ -1,25 +1,28 @@
-use std::collections::{BTreeMap, BTreeSet, HashSet};
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::collections::HashSet;
 
+use crate::encoding::{Decode, DecodeError, Encode, Reader, Writer};
+use crate::memory::{MemoryUsage, Usage};
 use crate::{ReplicaId, Value};
 
-use super::dot::DotKernel;
+use super::dot::{compact_delta_buffer, DeltaPolicy, DotKernel, VectorClock};
 
-#[derive(
-    Debug,
-    Clone,
-    PartialEq,
-    fp_bindgen::prelude::Serializable,
-    serde_derive::Serialize,
-    serde_derive::Deserialize,
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::aworset")
 )]
-pub struct AWORSet<V: Clone + PartialEq + Default + Value> {
+pub struct AWORSet<V: Clone + PartialEq + Value> {
     pub kernel: DotKernel<V>,
     pub delta: Option<DotKernel<V>>,
 }
 
 impl<V> Default for AWORSet<V>
 where
-    V: Clone + PartialEq + Default + Value,
+    V: Clone + PartialEq + Value,
 {
     fn default() -> Self {
         Self {
@@ -29,9 +32,31 @@ where
     }
 }
 
+impl<V: Clone + PartialEq + Value + Encode> Encode for AWORSet<V> {
+    fn encode(&self, out: &mut Writer) {
+        self.kernel.encode(out);
+        self.delta.encode(out);
+    }
+}
+
+impl<V: Clone + PartialEq + Value + Decode> Decode for AWORSet<V> {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(AWORSet {
+            kernel: DotKernel::decode(input)?,
+            delta: Option::decode(input)?,
+        })
+    }
+}
+
+impl<V: Clone + PartialEq + Value> MemoryUsage for AWORSet<V> {
+    fn memory_usage(&self) -> Usage {
+        self.kernel.memory_usage() + self.delta.memory_usage()
+    }
+}
+
 impl<V> AWORSet<V>
 where
-    V: Clone + PartialEq + Default + std::fmt::Debug + Value,
+    V: Clone + PartialEq + Value,
 {
     pub fn new(kernel: DotKernel<V>) -> Self {
         Self {
@@ -45,7 +70,7 @@ where
     }
 
     pub fn add(&mut self, replica: ReplicaId, value: V) {
-        let deltas = self.delta.get_or_insert_default();
+        let deltas = self.delta.get_or_insert_with(Default::default);
         // Remove duplicates
         self.kernel.remove(&value, deltas);
         self.kernel.add(replica, value, deltas);
@@ -53,7 +78,7 @@ where
 
     pub fn remove(&mut self, value: &V) {
         self.kernel
-            .remove(value, self.delta.get_or_insert_default());
+            .remove(value, self.delta.get_or_insert_with(Default::default));
     }
 
     pub fn merge(&self, other: &Self) -> Self {
@@ -79,6 +104,41 @@ where
         self.delta = Some(new_deltas);
     }
 
+    /// Same as [`AWORSet::merge`] but merges `other` into `self`, reusing the existing allocations
+    pub fn merge_in_place(&mut self, other: &Self) {
+        match (&mut self.delta, &other.delta) {
+            (Some(a), Some(b)) => a.merge_in_place(b),
+            (None, Some(b)) => self.delta = Some(b.clone()),
+            (_, None) => (),
+        }
+
+        self.kernel.merge_in_place(&other.kernel);
+    }
+
+    /// Same as [`AWORSet::merge_delta`] but without cloning the whole kernel, so the cost is
+    /// proportional to the size of `delta` rather than the size of the set
+    pub fn merge_delta_in_place(&mut self, delta: &DotKernel<V>) {
+        match &mut self.delta {
+            Some(a) => a.merge_in_place(delta),
+            None => self.delta = Some(delta.clone()),
+        }
+
+        self.kernel.merge_in_place(delta);
+    }
+
+    /// Drops the parts of the accumulated delta already acknowledged with `acked`. When the delta
+    /// is shared by several peers `acked` has to be the lowest clock all of them acknowledged.
+    pub fn compact_deltas(&mut self, acked: &VectorClock) {
+        compact_delta_buffer(&mut self.delta, acked, None);
+    }
+
+    /// Like [`AWORSet::compact_deltas`] but returns `true` if the delta is still bigger than
+    /// `policy` allows, in that case the delta is dropped and peers need the full state
+    #[must_use]
+    pub fn compact_deltas_with(&mut self, acked: &VectorClock, policy: DeltaPolicy) -> bool {
+        compact_delta_buffer(&mut self.delta, acked, Some(policy))
+    }
+
     pub fn split_mut(&mut self) -> Option<DotKernel<V>> {
         let delta = self.delta.take();
         delta
@@ -95,7 +155,7 @@ where
 
 impl<V> AWORSet<V>
 where
-    V: Clone + PartialEq + Default + Ord + std::fmt::Debug + Value,
+    V: Clone + PartialEq + Ord + Value,
 {
     pub fn value(&self) -> BTreeSet<V> {
         self.kernel.values().cloned().collect()
@@ -103,20 +163,60 @@ where
     pub fn values_ref(&self) -> BTreeSet<&V> {
         self.kernel.values().collect()
     }
+
+    /// Every value once, however many replicas added it concurrently, without copying any
+    pub fn iter(&self) -> impl Iterator<Item = &V> + '_ {
+        let mut seen = BTreeSet::new();
+        self.kernel.values().filter(move |val| seen.insert(*val))
+    }
+
+    pub fn contains(&self, value: &V) -> bool {
+        self.kernel.values().any(|val| val == value)
+    }
+
+    /// Takes the part of the delta buffer about `values` out of it, for peers that only follow
+    /// some of the values, see [`DotKernel::filtered`]. The rest stays for [`Self::split_mut`].
+    /// Removals are in both, the buffer doesn't know which value they removed.
+    pub fn split_for_keys(&mut self, values: &[V]) -> Option<DotKernel<V>> {
+        let values = values.iter().collect::<BTreeSet<_>>();
+        let delta = self.delta.as_ref()?;
+        let split = delta.filtered(|_, val| values.contains(val));
+        let rest = delta.filtered(|_, val| !values.contains(val));
+        self.delta = (!rest.is_empty()).then_some(rest);
+        Some(split)
+    }
+
+    /// What a peer that has seen the dots of `clock` is missing from the state: the entries added
+    /// since and every removal we know of. Unlike [`Self::split_mut`] it leaves the delta buffer
+    /// alone, so it works for peers joining late or coming back after the buffer was compacted.
+    pub fn delta_since(&self, clock: &VectorClock) -> DotKernel<V> {
+        self.kernel.filtered(|dot, _| !clock.contains(*dot))
+    }
 }
 
 impl<V> AWORSet<V>
 where
-    V: Clone + PartialEq + Default + std::fmt::Debug + Value,
+    V: Clone + PartialEq + Value,
 {
-    pub fn values_iter(&self) -> std::collections::btree_map::Values<super::dot::Dot, V> {
+    pub fn values_iter(&self) -> alloc::collections::btree_map::Values<super::dot::Dot, V> {
         self.kernel.values()
     }
+
+    /// Dots `value` is held with, more than one if several replicas added it concurrently
+    pub fn dots_for(&self, value: &V) -> Vec<super::dot::Dot> {
+        self.kernel
+            .entries
+            .iter()
+            .filter(|(_, val)| *val == value)
+            .map(|(dot, _)| *dot)
+            .collect()
+    }
 }
 
+#[cfg(feature = "std")]
 impl<V> AWORSet<V>
 where
-    V: Clone + PartialEq + Default + Eq + std::hash::Hash + std::fmt::Debug + Value,
+    V: Clone + PartialEq + Eq + core::hash::Hash + Value,
 {
     pub fn value_hashset(&self) -> HashSet<V> {
         self.kernel.values().cloned().collect()
@@ -125,7 +225,10 @@ where
 
 #[cfg(test)]
 pub mod test {
-    use crate::ReplicaGenerator;
+    use crate::{
+        delta_state::dot::{DeltaPolicy, Dot, VectorClock},
+        ReplicaGenerator,
+    };
 
     use super::AWORSet;
 
@@ -145,6 +248,141 @@ pub mod test {
         assert_eq!(a, b)
     }
 
+    #[test]
+    fn dots_for() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+        let mut a = AWORSet::<u64>::default();
+        let mut b = AWORSet::<u64>::default();
+
+        a.add(a_id, 1);
+        b.add(b_id, 1);
+        b.add(b_id, 2);
+        a.merge_delta(b.split_mut().unwrap());
+
+        assert_eq!(a.dots_for(&1), vec![Dot(a_id, 1), Dot(b_id, 1)]);
+        assert_eq!(a.dots_for(&2), vec![Dot(b_id, 2)]);
+        assert!(a.dots_for(&3).is_empty());
+
+        // 1 is held twice but iterated once
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![&1, &2]);
+        assert!(a.contains(&2) && !a.contains(&3));
+    }
+
+    #[test]
+    fn split_for_keys() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let mut a = AWORSet::<u64>::default();
+        let mut b = AWORSet::<u64>::default();
+
+        a.add(a_id, 1);
+        a.add(a_id, 2);
+        b.merge_delta(a.split_mut().unwrap());
+        a.remove(&1);
+        a.add(a_id, 3);
+
+        // Only 2 and 3 are followed, the removal of 1 still comes along
+        b.merge_delta(a.split_for_keys(&[2, 3]).unwrap());
+        assert_eq!(b.value(), [2, 3].into());
+
+        // Nothing about 3 is left for the rest
+        let mut c = AWORSet::<u64>::default();
+        c.merge_delta(a.split_mut().unwrap());
+        assert!(c.value().is_empty());
+        assert!(a.split_for_keys(&[2]).is_none());
+    }
+
+    #[test]
+    fn delta_since() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let mut a = AWORSet::<u64>::default();
+        let mut b = AWORSet::<u64>::default();
+
+        a.add(a_id, 1);
+        a.add(a_id, 2);
+        b.merge_delta(a.split_mut().unwrap());
+        let seen = b.kernel.ctx.clock.clone();
+
+        a.add(a_id, 3);
+        a.remove(&1);
+        // Shipped to other peers while b was away
+        let _ = a.split_mut();
+
+        let delta = a.delta_since(&seen);
+        assert_eq!(delta.values().copied().collect::<Vec<_>>(), vec![3]);
+        b.merge_delta(delta);
+        assert_eq!(b.value(), a.value());
+    }
+
+    #[test]
+    fn values_without_default_or_debug() {
+        #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+        #[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+        struct Tag(String);
+        impl crate::Value for Tag {}
+        let urgent = || Tag("urgent".to_string());
+
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let mut a = AWORSet::<Tag>::default();
+        let mut b = AWORSet::<Tag>::default();
+
+        a.add(a_id, urgent());
+        b.merge_delta(a.split_mut().unwrap());
+        assert!(b.contains(&urgent()));
+        b.remove(&urgent());
+        assert_eq!(b.len(), 0);
+    }
+
+    #[test]
+    fn compact_deltas() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let mut a = AWORSet::<u64>::default();
+        let mut b = AWORSet::<u64>::default();
+
+        a.add(a_id, 1);
+        a.add(a_id, 2);
+        b.merge_delta(a.delta.clone().unwrap());
+
+        // b has seen everything so far, only what comes after needs to be shipped
+        a.remove(&1);
+        a.add(a_id, 3);
+        a.compact_deltas(&b.kernel.ctx.clock);
+        let delta = a.split_mut().unwrap();
+        assert_eq!(delta.entries.len(), 1);
+
+        b.merge_delta(delta);
+        assert_eq!(a.kernel, b.kernel);
+
+        // Nothing left once the peer acknowledged everything
+        a.add(a_id, 4);
+        a.compact_deltas(&a.kernel.ctx.clock.clone());
+        assert_eq!(a.delta, None);
+    }
+
+    #[test]
+    fn compact_deltas_resync() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let mut a = AWORSet::<u64>::default();
+        let policy = DeltaPolicy {
+            max_delta_entries: 2,
+        };
+
+        a.add(a_id, 1);
+        a.add(a_id, 2);
+        assert!(!a.compact_deltas_with(&VectorClock::default(), policy));
+        assert!(a.delta.is_some());
+
+        a.add(a_id, 3);
+        assert!(a.compact_deltas_with(&VectorClock::default(), policy));
+        assert_eq!(a.delta, None);
+    }
+
     pub mod properties {
         use std::fmt::Debug;
 
@@ -156,9 +394,9 @@ pub mod test {
                     DotKernel,
                 },
             },
-            Value,
+            ReplicaId, Value,
         };
-        use proptest::prelude::*;
+        use proptest::{collection::vec, prelude::*};
 
         pub fn aworset_strategy_impl<V: Debug + Clone + Value + Default + PartialEq>(
             value_strat: impl Strategy<Value = V> + 'static,
@@ -177,6 +415,61 @@ pub mod test {
             patch_kernels(kernels.as_mut_slice())
         }
 
+        /// Something one of the replicas of [`history_strategy`] does
+        #[derive(Debug, Clone)]
+        pub enum Op<V> {
+            Add(V),
+            /// Removes one of the values the replica currently has, picked by index
+            Remove(usize),
+            /// Merges the delta buffer of another replica, picked by index
+            MergeDelta(usize),
+        }
+
+        /// Operations done by `replicas` replicas, one after the other. Unlike kernels generated
+        /// directly, states replayed from these are always consistent with each other and come
+        /// with the deltas that produced them.
+        pub fn history_strategy<V: Debug + Clone + 'static>(
+            value_strat: impl Strategy<Value = V> + 'static,
+            replicas: usize,
+        ) -> impl Strategy<Value = Vec<(usize, Op<V>)>> {
+            let op = prop_oneof![
+                3 => value_strat.prop_map(Op::Add),
+                1 => any::<usize>().prop_map(Op::Remove),
+                1 => (0..replicas).prop_map(Op::MergeDelta),
+            ];
+            vec((0..replicas, op), 0..50)
+        }
+
+        /// Replays `history` on `replicas` empty sets, each one keeps every delta it made or
+        /// received in its buffer
+        pub fn replay<V: Clone + PartialEq + Default + Debug + Value>(
+            replicas: usize,
+            history: Vec<(usize, Op<V>)>,
+        ) -> Vec<AWORSet<V>> {
+            let mut sets = vec![AWORSet::default(); replicas];
+            for (i, op) in history {
+                match op {
+                    Op::Add(value) => sets[i].add(ReplicaId::from(i as u64), value),
+                    Op::Remove(n) => {
+                        let values = sets[i].kernel.values().cloned().collect::<Vec<_>>();
+                        if !values.is_empty() {
+                            sets[i].remove(&values[n % values.len()]);
+                        }
+                    }
+                    Op::MergeDelta(from) => {
+                        if let Some(delta) = sets[from].delta.clone() {
+                            sets[i].merge_delta(delta);
+                        }
+                    }
+                }
+            }
+            sets
+        }
+
+        fn replicas_strategy() -> impl Strategy<Value = Vec<AWORSet<u16>>> {
+            history_strategy(0..32u16, 3).prop_map(|history| replay(3, history))
+        }
+
         proptest! {
             // #![proptest_config(ProptestConfig{ cases: 1, ..Default::default()})]
             #![proptest_config(ProptestConfig{ ..Default::default()})]
@@ -211,6 +504,88 @@ pub mod test {
 
                 assert_eq!(aa, a);
             }
+
+            #[test]
+            fn merge_in_place(mut a in aworset_strategy(), mut b in aworset_strategy()) {
+                patch(&mut [&mut a, &mut b]);
+
+                let ab = a.merge(&b);
+                let mut ab_in_place = a.clone();
+                ab_in_place.merge_in_place(&b);
+
+                assert_eq!(ab, ab_in_place);
+            }
+
+            #[test]
+            fn merge_delta_in_place(mut a in aworset_strategy(), mut b in aworset_strategy()) {
+                patch(&mut [&mut a, &mut b]);
+
+                let mut merged = a.clone();
+                merged.merge_delta(b.kernel.clone());
+                let mut merged_in_place = a.clone();
+                merged_in_place.merge_delta_in_place(&b.kernel);
+
+                assert_eq!(merged, merged_in_place);
+            }
+
+            #[test]
+            fn delta_commutativity(sets in replicas_strategy()) {
+                let [a, b, c] = <[_; 3]>::try_from(sets).unwrap();
+                let (db, dc) = (b.delta.unwrap_or_default(), c.delta.unwrap_or_default());
+
+                let mut bc = a.clone();
+                bc.merge_delta(db.clone());
+                bc.merge_delta(dc.clone());
+                let mut cb = a;
+                cb.merge_delta(dc);
+                cb.merge_delta(db);
+
+                assert_eq!(bc, cb);
+            }
+
+            #[test]
+            fn delta_associativity(sets in replicas_strategy()) {
+                let [a, b, c] = <[_; 3]>::try_from(sets).unwrap();
+                let (db, dc) = (b.delta.unwrap_or_default(), c.delta.unwrap_or_default());
+
+                let mut ab_c = a.clone();
+                ab_c.merge_delta(db.clone());
+                ab_c.merge_delta(dc.clone());
+                let mut a_bc = a;
+                a_bc.merge_delta(db.merge(&dc));
+
+                assert_eq!(ab_c, a_bc);
+            }
+
+            #[test]
+            fn delta_idempotency(sets in replicas_strategy()) {
+                let [a, b, _] = <[_; 3]>::try_from(sets).unwrap();
+                let db = b.delta.unwrap_or_default();
+
+                let mut once = a;
+                once.merge_delta(db.clone());
+                let mut twice = once.clone();
+                twice.merge_delta(db);
+
+                assert_eq!(once, twice);
+            }
+
+            /// Every replica merging the buffers of all the others ends up in the same state
+            #[test]
+            fn deltas_converge(mut sets in replicas_strategy()) {
+                let deltas = sets.iter().map(|set| set.delta.clone()).collect::<Vec<_>>();
+                for (i, set) in sets.iter_mut().enumerate() {
+                    for (_, delta) in deltas.iter().enumerate().filter(|(j, _)| *j != i) {
+                        if let Some(delta) = delta {
+                            set.merge_delta_in_place(delta);
+                        }
+                    }
+                }
+
+                for set in sets.iter().skip(1) {
+                    assert_eq!(set.kernel, sets[0].kernel);
+                }
+            }
         }
     }
 }
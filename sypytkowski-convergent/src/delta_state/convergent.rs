@@ -1,7 +1,30 @@
+use crate::Value;
+
+use super::{
+    awormap::AWORMap,
+    aworset::AWORSet,
+    counter_map::CounterMap,
+    gcounter::GCounter,
+    graph::{MonotoneDag, TwoPTwoPGraph},
+    grid::Grid,
+    gset::GSet,
+    lww::{HlcLwwMap, HlcLwwRegister},
+    mvreg::MVReg,
+    pncounter::PNCounter,
+};
+
+/// State that can be merged with any other replica of itself, the merge has to be commutative,
+/// associative and idempotent.
+///
+/// With the `derive` feature `#[derive(Convergent)]` implements this for structs by merging each
+/// field, which makes it easy to build product CRDTs out of the ones in this module.
 pub trait Convergent {
     fn merge(&self, other: &Self) -> Self;
 }
 
+#[cfg(feature = "derive")]
+pub use sypytkowski_convergent_derive::Convergent;
+
 macro_rules! impl_convergent_num {
     ($($t:ty),*) => ($(
         impl Convergent for $t {
@@ -13,3 +36,91 @@ macro_rules! impl_convergent_num {
 }
 
 impl_convergent_num!(u16, u32, u64, i16, i32, i64, f32, f64);
+
+// The CRDTs already have an inherent `merge`, these just forward to it
+
+impl Convergent for GCounter {
+    fn merge(&self, other: &Self) -> Self {
+        GCounter::merge(self, other)
+    }
+}
+
+impl Convergent for PNCounter {
+    fn merge(&self, other: &Self) -> Self {
+        PNCounter::merge(self, other)
+    }
+}
+
+impl<T: Clone + Ord + Value> Convergent for GSet<T> {
+    fn merge(&self, other: &Self) -> Self {
+        GSet::merge(self, other)
+    }
+}
+
+impl<K: Clone + Ord + Value> Convergent for CounterMap<K> {
+    fn merge(&self, other: &Self) -> Self {
+        CounterMap::merge(self, other)
+    }
+}
+
+impl<V: Clone + Ord + Value> Convergent for MonotoneDag<V> {
+    fn merge(&self, other: &Self) -> Self {
+        MonotoneDag::merge(self, other)
+    }
+}
+
+impl<V: Clone + Ord + Value> Convergent for TwoPTwoPGraph<V> {
+    fn merge(&self, other: &Self) -> Self {
+        TwoPTwoPGraph::merge(self, other)
+    }
+}
+
+impl<V: Clone + Ord + Value> Convergent for Grid<V> {
+    fn merge(&self, other: &Self) -> Self {
+        Grid::merge(self, other)
+    }
+}
+
+impl<V> Convergent for AWORSet<V>
+where
+    V: Clone + PartialEq + Value,
+{
+    fn merge(&self, other: &Self) -> Self {
+        AWORSet::merge(self, other)
+    }
+}
+
+impl<K, V> Convergent for AWORMap<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Value + Clone,
+{
+    fn merge(&self, other: &Self) -> Self {
+        AWORMap::merge(self, other)
+    }
+}
+
+impl<V> Convergent for MVReg<V>
+where
+    V: Clone + PartialEq + Ord + Value,
+{
+    fn merge(&self, other: &Self) -> Self {
+        MVReg::merge(self, other)
+    }
+}
+
+impl<V: Clone + Value> Convergent for HlcLwwRegister<V> {
+    fn merge(&self, other: &Self) -> Self {
+        HlcLwwRegister::merge(self, other)
+    }
+}
+
+impl<K, V> Convergent for HlcLwwMap<K, V>
+where
+    K: Ord + Clone + Value,
+    V: Clone + Value,
+{
+    fn merge(&self, other: &Self) -> Self {
+        HlcLwwMap::merge(self, other)
+    }
+}
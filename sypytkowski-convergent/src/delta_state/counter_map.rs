@@ -0,0 +1,280 @@
+//! Counters by key, see [`CounterMap`].
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+
+use crate::memory::{MemoryUsage, Usage};
+use crate::{ReplicaId, Value};
+
+use super::pncounter::PNCounter;
+
+/// Deltas are in a `CounterMap` holding only the keys that changed, like [`PNCounter`]'s
+pub type Deltas<K> = CounterMap<K>;
+
+/// Counter of a single key of a [`CounterMap`]
+#[derive(Debug, Clone, PartialEq, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::counter_map")
+)]
+pub struct KeyCounter {
+    /// Everything ever counted for the key
+    pub counter: PNCounter,
+    /// The part of `counter` removals of the key saw. Replicas only count up, so this is never
+    /// ahead of `counter` for any of them.
+    pub reset: PNCounter,
+}
+
+impl KeyCounter {
+    fn value(&self) -> i64 {
+        let value = self.counter.value() as i128 - self.reset.value() as i128;
+        value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    fn is_removed(&self) -> bool {
+        self.counter == self.reset
+    }
+
+    fn merge_in_place(&mut self, other: &Self) {
+        self.counter.merge_in_place(&other.counter);
+        self.reset.merge_in_place(&other.reset);
+    }
+}
+
+/// A [`PNCounter`] per key, for counting votes or likes of many items.
+///
+/// Removing a key only takes back the counts the removing replica had seen: increments made
+/// concurrently with the removal are still counted afterwards, as if the key had been removed
+/// before them. Removed keys are kept around to tell what was taken back, so they still take up
+/// space.
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::counter_map")
+)]
+pub struct CounterMap<K: Ord + Value> {
+    entries: BTreeMap<K, KeyCounter>,
+    #[serde(default = "Option::default", skip_serializing_if = "Option::is_none")]
+    delta: Option<Box<CounterMap<K>>>,
+}
+
+impl<K: Ord + Value> Default for CounterMap<K> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            delta: None,
+        }
+    }
+}
+
+impl<K: Ord + Value> MemoryUsage for CounterMap<K> {
+    fn memory_usage(&self) -> Usage {
+        let entries = (self.entries.values())
+            .map(|entry| entry.counter.memory_usage() + entry.reset.memory_usage());
+        Usage::btree::<K, KeyCounter>(self.entries.len())
+            + entries.sum()
+            + self.delta.memory_usage()
+    }
+}
+
+impl<K: Clone + Ord + Value> CounterMap<K> {
+    /// Count of `key`, 0 if it was never counted or removed since
+    pub fn value(&self, key: &K) -> i64 {
+        self.entries.get(key).map_or(0, KeyCounter::value)
+    }
+
+    /// Whether `key` was counted since it was last removed
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries
+            .get(key)
+            .is_some_and(|entry| !entry.is_removed())
+    }
+
+    /// Keys that weren't removed along with their counts
+    pub fn iter(&self) -> impl Iterator<Item = (&K, i64)> + '_ {
+        (self.entries.iter())
+            .filter(|(_, entry)| !entry.is_removed())
+            .map(|(key, entry)| (key, entry.value()))
+    }
+
+    pub fn increment(&mut self, replica: ReplicaId, key: K) {
+        self.count(key, |counter| counter.increment(replica))
+    }
+
+    pub fn decrement(&mut self, replica: ReplicaId, key: K) {
+        self.count(key, |counter| counter.decrement(replica))
+    }
+
+    fn count(&mut self, key: K, f: impl FnOnce(&mut PNCounter)) {
+        let entry = self.entries.entry(key.clone()).or_default();
+        f(&mut entry.counter);
+        // Counters of the entries never hold on to their deltas, ours are kept by key
+        if let Some(delta) = entry.counter.split_mut() {
+            self.delta_entry(key).counter.merge_in_place(&delta);
+        }
+    }
+
+    /// Takes back every count of `key` seen so far
+    pub fn remove(&mut self, key: &K) {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return;
+        };
+        entry.reset.merge_in_place(&entry.counter);
+        // The counts go along so `reset` isn't ahead of the counter on the other side
+        let entry = entry.clone();
+        self.delta_entry(key.clone()).merge_in_place(&entry);
+    }
+
+    fn delta_entry(&mut self, key: K) -> &mut KeyCounter {
+        let delta = self.delta.get_or_insert_with(Default::default);
+        delta.entries.entry(key).or_default()
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut ret = self.clone();
+        ret.merge_in_place(other);
+        ret
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        for (key, entry) in other.entries.iter() {
+            match self.entries.get_mut(key) {
+                Some(ours) => ours.merge_in_place(entry),
+                None => {
+                    self.entries.insert(key.clone(), entry.clone());
+                }
+            }
+        }
+        match (&mut self.delta, &other.delta) {
+            (Some(a), Some(b)) => a.merge_in_place(b),
+            (None, Some(b)) => self.delta = Some(b.clone()),
+            (_, None) => (),
+        }
+    }
+
+    pub fn merge_delta_in_place(&mut self, delta: &Deltas<K>) {
+        self.merge_in_place(delta)
+    }
+
+    pub fn split_mut(&mut self) -> Option<Deltas<K>> {
+        self.delta.take().map(|delta| *delta)
+    }
+
+    pub fn split(&self) -> (Self, Option<Deltas<K>>) {
+        let mut state = self.clone();
+        let delta = state.split_mut();
+        (state, delta)
+    }
+
+    pub fn split_expect(&self) -> (Self, Deltas<K>) {
+        let (map, deltas) = self.split();
+        (map, deltas.expect("Expected deltas."))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use crate::ReplicaId;
+
+    use super::CounterMap;
+
+    const A: ReplicaId = ReplicaId(1);
+    const B: ReplicaId = ReplicaId(2);
+    const C: ReplicaId = ReplicaId(3);
+
+    const POST: u8 = 1;
+    const OTHER: u8 = 2;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Increment(u8),
+        Decrement(u8),
+        Remove(u8),
+    }
+
+    fn op_strategy() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0..4u8).prop_map(Op::Increment),
+            (0..4u8).prop_map(Op::Decrement),
+            (0..4u8).prop_map(Op::Remove),
+        ]
+    }
+
+    /// Counter map of a replica that went through `ops`
+    fn counter_map_strategy(replica: ReplicaId) -> impl Strategy<Value = CounterMap<u8>> {
+        prop::collection::vec(op_strategy(), 0..20).prop_map(move |ops| {
+            let mut map = CounterMap::default();
+            for op in ops {
+                match op {
+                    Op::Increment(key) => map.increment(replica, key),
+                    Op::Decrement(key) => map.decrement(replica, key),
+                    Op::Remove(key) => map.remove(&key),
+                }
+            }
+            map
+        })
+    }
+
+    #[test]
+    fn removes_observed_counts() {
+        let mut a = CounterMap::default();
+        a.increment(A, POST);
+        a.increment(A, POST);
+        a.increment(A, OTHER);
+        let mut b = CounterMap::default();
+        b.merge_delta_in_place(&a.split_mut().unwrap());
+
+        // B takes back the two likes it saw while A likes it again
+        b.remove(&POST);
+        assert!(!b.contains_key(&POST));
+        a.increment(A, POST);
+        a.decrement(A, OTHER);
+        let a_delta = a.split_mut().unwrap();
+        a.merge_delta_in_place(&b.split_mut().unwrap());
+        b.merge_delta_in_place(&a_delta);
+
+        for map in [&a, &b] {
+            assert_eq!(map.value(&POST), 1);
+            assert_eq!(map.value(&OTHER), 0);
+            assert_eq!(map.value(&0), 0);
+            assert_eq!(map.iter().collect::<Vec<_>>(), [(&POST, 1), (&OTHER, 0)]);
+        }
+        assert!(a.split_mut().is_none());
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig{ ..Default::default()})]
+
+        #[test]
+        fn commutativity(a in counter_map_strategy(A), b in counter_map_strategy(B)) {
+            assert_eq!(a.merge(&b), b.merge(&a))
+        }
+
+        #[test]
+        fn associativity(a in counter_map_strategy(A), b in counter_map_strategy(B), c in counter_map_strategy(C)) {
+            assert_eq!(a.merge(&b).merge(&c), a.merge(&b.merge(&c)))
+        }
+
+        #[test]
+        fn idempotency(a in counter_map_strategy(A)) {
+            assert_eq!(a, a.merge(&a))
+        }
+
+        #[test]
+        fn deltas_match_states(a in counter_map_strategy(A), b in counter_map_strategy(B)) {
+            let (a_state, a_deltas) = a.split();
+            let (b_state, b_deltas) = b.split();
+
+            let mut ab = a_state.clone();
+            ab.merge_delta_in_place(&b_deltas.unwrap_or_default());
+            let mut ba = b_state.clone();
+            ba.merge_delta_in_place(&a_deltas.unwrap_or_default());
+
+            assert_eq!(ab, a_state.merge(&b_state));
+            assert_eq!(ab, ba);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Hash tree digests, so replicas can find out which parts of their state differ without sending
+//! the state itself.
+//!
+//! Hashes are [stable](crate::checksum::stable_hash), so replicas built for different targets
+//! agree on them.
+use alloc::{vec, vec::Vec};
+
+use crate::checksum::stable_hash;
+
+/// Number of leaves of a [`MapDigest`]
+pub const DIGEST_LEAVES: usize = 256;
+
+/// Hash tree of depth one over the entries of a map. Keys are spread over [`DIGEST_LEAVES`]
+/// buckets by hash and each leaf hashes the entries of its bucket, the root hashes the leaves.
+///
+/// Hashes are 32 bits so they survive a trip through JavaScript numbers.
+#[derive(Debug, Clone, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::digest")
+)]
+pub struct MapDigest {
+    pub root: u32,
+    pub leaves: Vec<u32>,
+}
+
+impl Default for MapDigest {
+    fn default() -> Self {
+        Self::from_leaves(vec![0; DIGEST_LEAVES])
+    }
+}
+
+impl MapDigest {
+    pub(crate) fn from_leaves(leaves: Vec<u32>) -> Self {
+        Self {
+            root: fold(stable_hash(&leaves)),
+            leaves,
+        }
+    }
+
+    /// Leaf that the key with hash `key_hash` falls into
+    pub fn bucket(key_hash: u64) -> usize {
+        (key_hash % DIGEST_LEAVES as u64) as usize
+    }
+
+    /// Buckets whose leaves differ, every bucket if `other` has a different shape
+    pub fn divergent_buckets(&self, other: &Self) -> Vec<bool> {
+        if self.root == other.root && self.leaves.len() == other.leaves.len() {
+            return vec![false; self.leaves.len()];
+        }
+        if self.leaves.len() != other.leaves.len() {
+            return vec![true; self.leaves.len()];
+        }
+        self.leaves
+            .iter()
+            .zip(other.leaves.iter())
+            .map(|(a, b)| a != b)
+            .collect()
+    }
+}
+
+/// Folds a 64 bit hash into 32 bits
+pub(crate) fn fold(hash: u64) -> u32 {
+    (hash ^ (hash >> 32)) as u32
+}
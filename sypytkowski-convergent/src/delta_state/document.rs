@@ -0,0 +1,381 @@
+//! A document of named CRDTs that is synced as a whole.
+//!
+//! Each field holds one of the delta CRDTs in this module and keeps its own delta buffer, so
+//! [`Document::split_mut`] just collects the deltas of every field that changed.
+//!
+//! Fields are never removed. If two replicas concurrently create the same field with different
+//! kinds the one declared later in [`Crdt`] wins (a grid beats a list, a list beats a map...), which
+//! keeps merging deterministic without having to merge across kinds.
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+
+use crate::Value;
+
+use super::{
+    awormap::{self, AWORMap},
+    aworset::AWORSet,
+    dot::DotKernel,
+    grid::{Grid, GridDelta},
+    mvreg::MVReg,
+    orarray::{self, ORArray},
+    pncounter::PNCounter,
+};
+
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(bound(
+    serialize = "K: serde::Serialize, V: serde::Serialize",
+    deserialize = "K: serde::Deserialize<'de>, V: serde::Deserialize<'de>"
+))]
+pub enum Crdt<K: Clone + PartialEq + Ord + Value, V: Clone + PartialEq + Ord + Value> {
+    Counter(PNCounter),
+    Register(MVReg<V>),
+    Set(AWORSet<V>),
+    Map(AWORMap<K, V>),
+    List(ORArray<V>),
+    Grid(Grid<V>),
+}
+
+/// Delta of a single [`Crdt`], tagged with the kind so the receiver can create the field
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(bound(
+    serialize = "K: serde::Serialize, V: serde::Serialize",
+    deserialize = "K: serde::Deserialize<'de>, V: serde::Deserialize<'de>"
+))]
+pub enum CrdtDelta<K: Clone + PartialEq + Ord + Value, V: Clone + PartialEq + Ord + Value> {
+    Counter(PNCounter),
+    Register(DotKernel<V>),
+    Set(DotKernel<V>),
+    Map(awormap::Deltas<K, V>),
+    List(orarray::Deltas<V>),
+    Grid(GridDelta<V>),
+}
+
+impl<K, V> Crdt<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Clone + PartialEq + Ord + Value,
+{
+    /// Order of the kinds when resolving a field created concurrently with different kinds
+    fn kind(&self) -> u8 {
+        match self {
+            Crdt::Counter(_) => 0,
+            Crdt::Register(_) => 1,
+            Crdt::Set(_) => 2,
+            Crdt::Map(_) => 3,
+            Crdt::List(_) => 4,
+            Crdt::Grid(_) => 5,
+        }
+    }
+
+    /// Empty CRDT of the same kind as `delta`
+    fn for_delta(delta: &CrdtDelta<K, V>) -> Self {
+        match delta {
+            CrdtDelta::Counter(_) => Crdt::Counter(Default::default()),
+            CrdtDelta::Register(_) => Crdt::Register(Default::default()),
+            CrdtDelta::Set(_) => Crdt::Set(Default::default()),
+            CrdtDelta::Map(_) => Crdt::Map(Default::default()),
+            CrdtDelta::List(_) => Crdt::List(Default::default()),
+            CrdtDelta::Grid(_) => Crdt::Grid(Default::default()),
+        }
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut ret = self.clone();
+        ret.merge_in_place(other);
+        ret
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        match (&mut *self, other) {
+            (Crdt::Counter(a), Crdt::Counter(b)) => a.merge_in_place(b),
+            (Crdt::Register(a), Crdt::Register(b)) => *a = a.merge(b),
+            (Crdt::Set(a), Crdt::Set(b)) => a.merge_in_place(b),
+            (Crdt::Map(a), Crdt::Map(b)) => a.merge_in_place(b),
+            (Crdt::List(a), Crdt::List(b)) => a.merge_in_place(b),
+            (Crdt::Grid(a), Crdt::Grid(b)) => a.merge_in_place(b),
+            (a, b) if b.kind() > a.kind() => *a = b.clone(),
+            _ => (),
+        }
+    }
+
+    pub fn merge_delta(&mut self, delta: &CrdtDelta<K, V>) {
+        if Self::for_delta(delta).kind() > self.kind() {
+            *self = Self::for_delta(delta);
+        }
+
+        match (self, delta) {
+            (Crdt::Counter(a), CrdtDelta::Counter(b)) => a.merge_delta_in_place(b),
+            (Crdt::Register(a), CrdtDelta::Register(b)) => a.merge_delta(b.clone()),
+            (Crdt::Set(a), CrdtDelta::Set(b)) => a.merge_delta_in_place(b),
+            (Crdt::Map(a), CrdtDelta::Map(b)) => a.merge_delta_in_place(b),
+            (Crdt::List(a), CrdtDelta::List(b)) => a.merge_delta_in_place(b),
+            (Crdt::Grid(a), CrdtDelta::Grid(b)) => a.merge_delta(b),
+            // The delta lost against the kind we already have
+            _ => (),
+        }
+    }
+
+    pub fn split_mut(&mut self) -> Option<CrdtDelta<K, V>> {
+        match self {
+            Crdt::Counter(a) => a.split_mut().map(CrdtDelta::Counter),
+            Crdt::Register(a) => a.split_mut().map(CrdtDelta::Register),
+            Crdt::Set(a) => a.split_mut().map(CrdtDelta::Set),
+            Crdt::Map(a) => a.split_mut().map(CrdtDelta::Map),
+            Crdt::List(a) => a.split_mut().map(CrdtDelta::List),
+            Crdt::Grid(a) => a.split_mut().map(CrdtDelta::Grid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(bound(
+    serialize = "K: serde::Serialize, V: serde::Serialize",
+    deserialize = "K: serde::Deserialize<'de>, V: serde::Deserialize<'de>"
+))]
+pub struct Document<K: Clone + PartialEq + Ord + Value, V: Clone + PartialEq + Ord + Value> {
+    fields: BTreeMap<String, Crdt<K, V>>,
+}
+
+/// Deltas of every field that changed since the last [`Document::split_mut`]
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(bound(
+    serialize = "K: serde::Serialize, V: serde::Serialize",
+    deserialize = "K: serde::Deserialize<'de>, V: serde::Deserialize<'de>"
+))]
+pub struct DocumentDelta<K: Clone + PartialEq + Ord + Value, V: Clone + PartialEq + Ord + Value> {
+    pub fields: BTreeMap<String, CrdtDelta<K, V>>,
+}
+
+impl<K, V> Default for Document<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Clone + PartialEq + Ord + Value,
+{
+    fn default() -> Self {
+        Self {
+            fields: Default::default(),
+        }
+    }
+}
+
+/// Generates the accessor that gets a field of a given kind, creating it if it doesn't exist
+macro_rules! field_accessor {
+    ($(#[$doc:meta])* $name:ident, $variant:ident, $ty:ty) => {
+        $(#[$doc])*
+        ///
+        /// Returns `None` if the field already holds another kind of CRDT.
+        pub fn $name(&mut self, name: &str) -> Option<&mut $ty> {
+            let field = self
+                .fields
+                .entry(name.to_owned())
+                .or_insert_with(|| Crdt::$variant(Default::default()));
+            match field {
+                Crdt::$variant(crdt) => Some(crdt),
+                _ => None,
+            }
+        }
+    };
+}
+
+impl<K, V> Document<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Clone + PartialEq + Ord + Value,
+{
+    pub fn get(&self, name: &str) -> Option<&Crdt<K, V>> {
+        self.fields.get(name)
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &Crdt<K, V>)> + '_ {
+        self.fields.iter().map(|(name, crdt)| (name.as_str(), crdt))
+    }
+
+    field_accessor!(
+        /// Counter stored under `name`
+        counter,
+        Counter,
+        PNCounter
+    );
+    field_accessor!(
+        /// Register stored under `name`
+        register,
+        Register,
+        MVReg<V>
+    );
+    field_accessor!(
+        /// Set stored under `name`
+        set,
+        Set,
+        AWORSet<V>
+    );
+    field_accessor!(
+        /// Map stored under `name`
+        map,
+        Map,
+        AWORMap<K, V>
+    );
+    field_accessor!(
+        /// List stored under `name`
+        list,
+        List,
+        ORArray<V>
+    );
+    field_accessor!(
+        /// Grid stored under `name`
+        grid,
+        Grid,
+        Grid<V>
+    );
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut ret = self.clone();
+        ret.merge_in_place(other);
+        ret
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        for (name, crdt) in other.fields.iter() {
+            match self.fields.get_mut(name) {
+                Some(field) => field.merge_in_place(crdt),
+                None => {
+                    self.fields.insert(name.clone(), crdt.clone());
+                }
+            }
+        }
+    }
+
+    pub fn merge_delta(&mut self, delta: &DocumentDelta<K, V>) {
+        for (name, delta) in delta.fields.iter() {
+            self.fields
+                .entry(name.clone())
+                .or_insert_with(|| Crdt::for_delta(delta))
+                .merge_delta(delta);
+        }
+    }
+
+    pub fn split_mut(&mut self) -> Option<DocumentDelta<K, V>> {
+        let fields = self
+            .fields
+            .iter_mut()
+            .filter_map(|(name, crdt)| crdt.split_mut().map(|delta| (name.clone(), delta)))
+            .collect::<BTreeMap<_, _>>();
+
+        if fields.is_empty() {
+            None
+        } else {
+            Some(DocumentDelta { fields })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ReplicaGenerator;
+
+    use super::{Crdt, Document};
+
+    type Doc = Document<String, String>;
+
+    fn values(doc: &Doc) -> Vec<String> {
+        doc.fields()
+            .map(|(name, crdt)| match crdt {
+                Crdt::Counter(c) => format!("{name}: {}", c.value()),
+                Crdt::Register(r) => format!("{name}: {:?}", r.value()),
+                Crdt::Set(s) => format!("{name}: {:?}", s.value()),
+                Crdt::Map(m) => format!("{name}: {:?}", m.iter().collect::<Vec<_>>()),
+                Crdt::List(l) => format!("{name}: {:?}", l.values()),
+                Crdt::Grid(g) => format!("{name}: {:?}", g.values()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn syncs_with_deltas() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = Doc::default();
+        let mut b = Doc::default();
+        a.counter("likes").unwrap().increment(a_id);
+        a.register("title").unwrap().set(a_id, "crdts".into());
+        a.list("todo").unwrap().push(a_id, "write tests".into());
+        b.counter("likes").unwrap().increment(b_id);
+        b.map("tags")
+            .unwrap()
+            .insert(b_id, "lang".into(), "rust".into());
+        b.set("authors").unwrap().add(b_id, "zack".into());
+        let table = b.grid("table").unwrap();
+        table.insert_row(b_id, 0);
+        table.insert_column(b_id, 0);
+        table.set(b_id, 0, 0, "cell".into());
+
+        let a_delta = a.split_mut().unwrap();
+        let b_delta = b.split_mut().unwrap();
+        a.merge_delta(&b_delta);
+        b.merge_delta(&a_delta);
+
+        assert_eq!(values(&a), values(&b));
+        assert_eq!(values(&a), values(&a.merge(&b)));
+        assert_eq!(
+            values(&a),
+            vec![
+                "authors: {\"zack\"}",
+                "likes: 2",
+                "table: [[{\"cell\"}]]",
+                "tags: [(\"lang\", \"rust\")]",
+                "title: {\"crdts\"}",
+                "todo: [\"write tests\"]",
+            ]
+        );
+        assert!(a.split_mut().is_some());
+        assert!(a.split_mut().is_none());
+    }
+
+    #[test]
+    fn conflicting_kinds() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = Doc::default();
+        let mut b = Doc::default();
+        a.counter("field").unwrap().increment(a_id);
+        b.list("field").unwrap().push(b_id, "x".into());
+        assert!(a.list("field").is_none());
+
+        let a_delta = a.split_mut().unwrap();
+        let b_delta = b.split_mut().unwrap();
+        let merged = a.merge(&b);
+        a.merge_delta(&b_delta);
+        b.merge_delta(&a_delta);
+
+        assert_eq!(values(&a), vec!["field: [\"x\"]"]);
+        assert_eq!(values(&a), values(&b));
+        assert_eq!(values(&a), values(&merged));
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+
+        let mut a = Doc::default();
+        a.counter("likes").unwrap().increment(a_id);
+        a.list("todo").unwrap().push(a_id, "write tests".into());
+        a.map("tags")
+            .unwrap()
+            .insert(a_id, "lang".into(), "rust".into());
+
+        let json = serde_json::to_string(&a).unwrap();
+        let b: Doc = serde_json::from_str(&json).unwrap();
+        assert_eq!(a, b);
+
+        let delta = a.split_mut().unwrap();
+        let json = serde_json::to_string(&delta).unwrap();
+        let mut c = Doc::default();
+        c.merge_delta(&serde_json::from_str(&json).unwrap());
+        assert_eq!(values(&a), values(&c));
+    }
+}
@@ -17,26 +17,98 @@
 //!
 //! In addition, DVVs are also more space efficient. For example in the original ORSet implementation (see or_set.rs) we store a
 //! vector clock for each element, we need to track every client that has added or removed the element. This is not necessary with DVVs.
-use std::{
+use alloc::{
     collections::{BTreeMap, BTreeSet},
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
     fmt::Write,
-    ops::{Deref, DerefMut},
+    ops::{
+        Bound::{Excluded, Unbounded},
+        Deref, DerefMut, Range,
+    },
 };
 
 use serde::{de::Visitor, Deserialize, Serialize};
 
+use crate::encoding::{Decode, DecodeError, Encode, Reader, Writer};
+use crate::memory::{MemoryUsage, Usage};
 use crate::{ReplicaId, Value};
 
 #[derive(serde_derive::Deserialize)]
 pub struct VectorClockDeserializer(BTreeMap<String, u64>);
 
-#[derive(Debug, Clone, PartialEq, fp_bindgen::prelude::Serializable)]
-#[fp(rust_plugin_module = "sypytkowski_convergent::delta_state::dot")]
-pub struct VectorClock(pub BTreeMap<ReplicaId, u64>);
+/// Highest sequence number seen from each replica.
+///
+/// Every replica that ever wrote keeps an entry, which adds up when replicas are short lived (every
+/// browser tab of the demo is a new replica). Once a replica is gone for good it can be
+/// [retired](VectorClock::retire): its entry is dropped and it is remembered in a run-length
+/// encoded set instead. Runs only form out of consecutive ids, so the set stays tiny when ids
+/// come from a [`ReplicaGenerator`](crate::ReplicaGenerator). Random ids (see
+/// [`ReplicaId::random`]) each take a run of their own, so retiring them doesn't save space.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::dot")
+)]
+pub struct VectorClock {
+    clock: BTreeMap<ReplicaId, u64>,
+    retired: RetiredReplicas,
+}
 
-impl Default for VectorClock {
-    fn default() -> Self {
-        Self(BTreeMap::new())
+impl VectorClock {
+    /// Drops `replica`'s entry, from now on every dot of `replica` counts as seen.
+    ///
+    /// Only retire a replica that will never write again and whose dots have all been seen here.
+    /// The retirement spreads to other replicas by merging, it is fine for them to learn about it
+    /// at different times as long as the state carrying it has seen all of `replica`'s dots.
+    pub fn retire(&mut self, replica: ReplicaId) {
+        self.clock.remove(&replica);
+        self.retired.insert(replica);
+    }
+
+    pub fn is_retired(&self, replica: ReplicaId) -> bool {
+        self.retired.contains(replica)
+    }
+
+    pub fn retired(&self) -> &RetiredReplicas {
+        &self.retired
+    }
+
+    /// Whether `dot` is covered by the clock, which is always the case for retired replicas
+    pub fn contains(&self, Dot(id, n): Dot) -> bool {
+        self.clock.get(&id).is_some_and(|found| *found >= n) || self.retired.contains(id)
+    }
+
+    /// Takes the max of both clocks and the union of their retired replicas
+    pub fn merge_in_place(&mut self, other: &Self) {
+        for (&key, &new_val) in other.clock.iter() {
+            self.clock
+                .entry(key)
+                .and_modify(|val| {
+                    *val = new_val.max(*val);
+                })
+                .or_insert(new_val);
+        }
+        if !other.retired.is_empty() {
+            self.retired.extend(&other.retired);
+        }
+        if !self.retired.is_empty() {
+            let retired = &self.retired;
+            self.clock.retain(|id, _| !retired.contains(*id));
+        }
+    }
+}
+
+impl From<BTreeMap<ReplicaId, u64>> for VectorClock {
+    fn from(clock: BTreeMap<ReplicaId, u64>) -> Self {
+        Self {
+            clock,
+            retired: Default::default(),
+        }
     }
 }
 
@@ -44,23 +116,25 @@ impl Deref for VectorClock {
     type Target = BTreeMap<ReplicaId, u64>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.clock
     }
 }
 
 impl DerefMut for VectorClock {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.clock
     }
 }
 
 impl Serialize for VectorClock {
+    /// Retired replicas are serialized in the same map as `start..end` keys (end exclusive) with
+    /// a value of 0, so a clock without any is encoded exactly like before
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         use serde::ser::SerializeMap;
-        let mut map = serializer.serialize_map(Some(self.len()))?;
+        let mut map = serializer.serialize_map(Some(self.len() + self.retired.0.len()))?;
         let mut str_buf = String::new();
         for (&replica_id, &value) in self.iter() {
             let start = str_buf.len();
@@ -70,6 +144,9 @@ impl Serialize for VectorClock {
             // let fuck = replica_id.0.to_string();
             // map.serialize_entry("hi", &value)?;
         }
+        for (start, end) in self.retired.0.iter() {
+            map.serialize_entry(&format!("{}..{}", start, end), &0)?;
+        }
         map.end()
     }
 }
@@ -79,18 +156,136 @@ impl<'de> Deserialize<'de> for VectorClock {
     where
         D: serde::Deserializer<'de>,
     {
-        let mut str_map = VectorClockDeserializer::deserialize(deserializer)?.0;
-        let mut map = BTreeMap::new();
+        let str_map = VectorClockDeserializer::deserialize(deserializer)?.0;
+        let parse = |k: &str| k.parse::<ReplicaId>().map_err(serde::de::Error::custom);
+        let mut clock = VectorClock::default();
         for (k, v) in str_map {
-            let id = ReplicaId(k.parse().map_err(|e| serde::de::Error::custom(e))?);
-            map.insert(id, v);
+            match k.split_once("..") {
+                Some((start, end)) => {
+                    insert_run(&mut clock.retired.0, parse(start)?..parse(end)?);
+                }
+                // Runs over the clock end at `n + 1`
+                None if v == u64::MAX => {
+                    return Err(serde::de::Error::custom("sequence number out of range"));
+                }
+                None => {
+                    clock.clock.insert(parse(&k)?, v);
+                }
+            }
+        }
+        let retired = &clock.retired;
+        clock.clock.retain(|id, _| !retired.contains(*id));
+        Ok(clock)
+    }
+}
+
+/// Clock entries followed by the retired runs as their first id and how many ids they hold
+impl Encode for VectorClock {
+    fn encode(&self, out: &mut Writer) {
+        out.length(self.clock.len());
+        for (id, n) in self.clock.iter() {
+            id.encode(out);
+            n.encode(out);
+        }
+        out.length(self.retired.0.len());
+        for (start, end) in self.retired.0.iter() {
+            start.encode(out);
+            (end.0 - start.0).encode(out);
+        }
+    }
+}
+
+impl Decode for VectorClock {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let mut clock = VectorClock::default();
+        for _ in 0..input.length()? {
+            let id = ReplicaId::decode(input)?;
+            // Runs over the clock end at `n + 1`
+            match u64::decode(input)? {
+                u64::MAX => return Err(DecodeError::Invalid("sequence number out of range")),
+                n => clock.clock.insert(id, n),
+            };
+        }
+        for _ in 0..input.length()? {
+            let start = ReplicaId::decode(input)?;
+            let end = match u128::decode(input)? {
+                0 => return Err(DecodeError::Invalid("empty replica range")),
+                len => start
+                    .0
+                    .checked_add(len)
+                    .ok_or(DecodeError::Invalid("replica range out of range"))?,
+            };
+            insert_run(&mut clock.retired.0, start..ReplicaId(end));
+        }
+        let retired = &clock.retired;
+        clock.clock.retain(|id, _| !retired.contains(*id));
+        Ok(clock)
+    }
+}
+
+/// Retired replicas count a run each
+impl MemoryUsage for VectorClock {
+    fn memory_usage(&self) -> Usage {
+        Usage::btree::<ReplicaId, u64>(self.clock.len())
+            + Usage::btree::<ReplicaId, ReplicaId>(self.retired.0.len())
+    }
+}
+
+/// Replicas retired from a [`VectorClock`], stored as runs of consecutive ids `start -> end`
+/// (end exclusive). Only sequential ids make runs longer than one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::dot")
+)]
+pub struct RetiredReplicas(BTreeMap<ReplicaId, ReplicaId>);
+
+impl RetiredReplicas {
+    pub fn insert(&mut self, id: ReplicaId) {
+        insert_run(&mut self.0, id..ReplicaId(id.0 + 1))
+    }
+
+    pub fn contains(&self, id: ReplicaId) -> bool {
+        run_contains(&self.0, id)
+    }
+
+    pub fn extend(&mut self, other: &Self) {
+        for (&start, &end) in other.0.iter() {
+            insert_run(&mut self.0, start..end);
         }
-        Ok(VectorClock(map))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ReplicaId> + '_ {
+        self.0
+            .iter()
+            .flat_map(|(start, end)| (start.0..end.0).map(ReplicaId))
+    }
+
+    /// First replica retired here that isn't retired in `other`. Goes run by run, so even huge
+    /// runs are quick to check.
+    pub fn first_missing_from(&self, other: &Self) -> Option<ReplicaId> {
+        self.0.iter().find_map(|(&start, &end)| {
+            // Runs are coalesced, so the end of the run covering `start` isn't retired in `other`
+            match other.0.range(..=start).next_back() {
+                Some((_, &covered)) if covered >= end => None,
+                Some((_, &covered)) if covered > start => Some(covered),
+                _ => Some(start),
+            }
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, fp_bindgen::prelude::Serializable)]
-#[fp(rust_plugin_module = "sypytkowski_convergent::delta_state::dot")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::dot")
+)]
 pub struct Dot(pub ReplicaId, pub u64);
 
 impl serde::Serialize for Dot {
@@ -102,7 +297,7 @@ struct DotDeserializer;
 impl<'de> Visitor<'de> for DotDeserializer {
     type Value = Dot;
 
-    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(formatter, "Dot")
     }
 
@@ -121,7 +316,7 @@ impl<'de> Visitor<'de> for DotDeserializer {
         Ok(Dot(
             ReplicaId(
                 replica
-                    .parse::<u64>()
+                    .parse::<u128>()
                     .map_err(|e| serde::de::Error::custom(e.to_string()))?,
             ),
             sequence_number
@@ -139,33 +334,93 @@ impl<'de> serde::Deserialize<'de> for Dot {
     }
 }
 
-#[derive(
-    Debug,
-    Clone,
-    PartialEq,
-    fp_bindgen::prelude::Serializable,
-    serde_derive::Serialize,
-    serde_derive::Deserialize,
+impl Encode for Dot {
+    fn encode(&self, out: &mut Writer) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+}
+
+impl Decode for Dot {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(Dot(ReplicaId::decode(input)?, u64::decode(input)?))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::dot")
 )]
 pub struct DotKernel<V: Clone + Value> {
     pub(crate) ctx: DotCtx,
     pub(crate) entries: BTreeMap<Dot, V>,
 }
 
-#[derive(
-    Debug,
-    Clone,
-    PartialEq,
-    fp_bindgen::prelude::Serializable,
-    serde_derive::Serialize,
-    serde_derive::Deserialize,
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::dot")
 )]
 pub struct DotCtx {
     pub(crate) clock: VectorClock,
-    pub(crate) dot_cloud: BTreeSet<Dot>,
+    pub(crate) dot_cloud: DotCloud,
+}
+
+impl<V: Clone + Value + Encode> Encode for DotKernel<V> {
+    fn encode(&self, out: &mut Writer) {
+        self.ctx.encode(out);
+        out.length(self.entries.len());
+        for (dot, value) in self.entries.iter() {
+            dot.encode(out);
+            value.encode(out);
+        }
+    }
+}
+
+impl<V: Clone + Value + Decode> Decode for DotKernel<V> {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let ctx = DotCtx::decode(input)?;
+        let mut entries = BTreeMap::new();
+        for _ in 0..input.length()? {
+            let dot = Dot::decode(input)?;
+            entries.insert(dot, V::decode(input)?);
+        }
+        Ok(DotKernel { ctx, entries })
+    }
+}
+
+impl Encode for DotCtx {
+    fn encode(&self, out: &mut Writer) {
+        self.clock.encode(out);
+        self.dot_cloud.encode(out);
+    }
+}
+
+impl Decode for DotCtx {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(DotCtx {
+            clock: VectorClock::decode(input)?,
+            dot_cloud: DotCloud::decode(input)?,
+        })
+    }
+}
+
+impl<V: Clone + Value> MemoryUsage for DotKernel<V> {
+    fn memory_usage(&self) -> Usage {
+        self.ctx.memory_usage() + Usage::btree::<Dot, V>(self.entries.len())
+    }
 }
 
-impl<V: Clone + PartialEq + Default + Value> Default for DotKernel<V> {
+impl MemoryUsage for DotCtx {
+    fn memory_usage(&self) -> Usage {
+        self.clock.memory_usage() + self.dot_cloud.memory_usage()
+    }
+}
+
+impl<V: Clone + Value> Default for DotKernel<V> {
     fn default() -> Self {
         Self {
             ctx: Default::default(),
@@ -174,11 +429,16 @@ impl<V: Clone + PartialEq + Default + Value> Default for DotKernel<V> {
     }
 }
 
-impl<V: Clone + PartialEq + std::fmt::Debug + Value> DotKernel<V> {
-    pub fn values(&self) -> std::collections::btree_map::Values<Dot, V> {
+impl<V: Clone + PartialEq + Value> DotKernel<V> {
+    pub fn values(&self) -> alloc::collections::btree_map::Values<Dot, V> {
         self.entries.values()
     }
 
+    /// Entries along with the dots they were added with
+    pub fn iter(&self) -> alloc::collections::btree_map::Iter<Dot, V> {
+        self.entries.iter()
+    }
+
     pub fn merge(&self, other: &Self) -> Self {
         // Initialize entries from `self`
         let mut entries = self.entries.clone();
@@ -215,12 +475,56 @@ impl<V: Clone + PartialEq + std::fmt::Debug + Value> DotKernel<V> {
         }
     }
 
-    pub fn add(&mut self, replica: ReplicaId, value: V, delta: &mut Self) {
+    /// Same as [`DotKernel::merge`] but merges `other` into `self`, reusing its allocations.
+    ///
+    /// Instead of walking all of our entries to find the ones `other` removed, only the dots
+    /// covered by `other`'s context are looked up, so merging a small delta into a big kernel
+    /// doesn't pay for the size of the kernel.
+    pub fn merge_in_place(&mut self, other: &Self) {
+        // Dots `other` has seen but no longer has in its entries were removed by `other`
+        let removed = other
+            .ctx
+            .ranges()
+            .flat_map(|DotRange(id, range)| {
+                self.entries
+                    .range(Dot(id, range.start)..Dot(id, range.end))
+                    .map(|(dot, _)| *dot)
+            })
+            .filter(|dot| !other.entries.contains_key(dot))
+            .collect::<Vec<_>>();
+        for dot in removed {
+            self.entries.remove(&dot);
+        }
+
+        // Add unseen items from `other`
+        for (dot, val) in other.entries.iter() {
+            if !(self.entries.contains_key(dot) || self.ctx.contains(*dot)) {
+                self.entries.insert(*dot, val.clone());
+            }
+        }
+
+        self.ctx.merge_in_place(&other.ctx);
+    }
+
+    pub fn add(&mut self, replica: ReplicaId, value: V, delta: &mut Self) -> Dot {
         let dot = self.ctx.next_dot(replica);
         self.entries.insert(dot, value.clone());
         delta.entries.insert(dot, value);
         delta.ctx.add(dot);
         delta.ctx.compact();
+        dot
+    }
+
+    /// Like [`DotKernel::remove`] but for when the caller already knows which dots hold the
+    /// value, so we don't need to scan all of the entries
+    pub fn remove_dots<I: IntoIterator<Item = Dot>>(&mut self, dots: I, delta: &mut Self) {
+        for dot in dots {
+            if self.entries.remove(&dot).is_some() {
+                delta.ctx.add(dot);
+                delta.entries.remove(&dot);
+            }
+        }
+        delta.ctx.compact()
     }
 
     pub fn remove(&mut self, value: &V, delta: &mut Self) {
@@ -230,7 +534,10 @@ impl<V: Clone + PartialEq + std::fmt::Debug + Value> DotKernel<V> {
         // }
         // delta.ctx.compact()
 
-        for (dot, _) in self.entries.drain_filter(|_, val| val == value) {
+        self.entries.retain(|&dot, val| {
+            if val != value {
+                return true;
+            }
             delta.ctx.add(dot);
             // The F# code from the blog post keeps the value in the delta.entries map, this
             // causes my delta state awormap to keep the deleted key when merging with deltas which is
@@ -238,16 +545,110 @@ impl<V: Clone + PartialEq + std::fmt::Debug + Value> DotKernel<V> {
             //
             // This should be fine but just noting this here in case it does cause problems
             delta.entries.remove(&dot);
-        }
+            false
+        });
         delta.ctx.compact()
     }
 
     pub fn remove_all(&mut self) {
-        for (k, _) in self.entries.drain_filter(|_, _| true) {
+        for k in core::mem::take(&mut self.entries).into_keys() {
             self.ctx.add(k);
         }
         self.ctx.compact();
     }
+
+    /// Drops the entries covered by `acked`, along with their dots from the context. Meant for
+    /// delta kernels: whoever acknowledged `acked` has already seen those entries, so shipping them
+    /// again is wasted bandwidth.
+    ///
+    /// Dots in the context without an entry are removals and are kept, acknowledging a dot only
+    /// says the peer saw it being added, not that it saw it being removed.
+    pub fn compact_acked(&mut self, acked: &VectorClock) {
+        let covered = self
+            .entries
+            .keys()
+            .filter(|dot| acked.contains(**dot))
+            .copied()
+            .collect::<Vec<_>>();
+        for dot in covered {
+            self.entries.remove(&dot);
+            self.ctx.remove(dot);
+        }
+    }
+
+    /// The entries `keep` picks, with the context minus the dots of the entries left out so
+    /// merging the result can't remove those on the other side. Every removal in the context still
+    /// applies. Entries whose dots can't be taken out of the context, those of retired replicas,
+    /// are kept whatever `keep` says.
+    pub fn filtered<F: FnMut(&Dot, &V) -> bool>(&self, mut keep: F) -> Self {
+        let mut ctx = self.ctx.clone();
+        let entries = (self.entries.iter())
+            .filter(|(dot, val)| {
+                if keep(dot, val) {
+                    return true;
+                }
+                ctx.remove(**dot);
+                ctx.contains(**dot)
+            })
+            .map(|(dot, val)| (*dot, val.clone()))
+            .collect();
+        Self { ctx, entries }
+    }
+
+    /// See [`DotCtx::retire`]. The entries `replica` added stay, they are still identified by
+    /// their dots.
+    ///
+    /// Only retire on a full state, a delta that claims to have seen all of `replica`'s dots
+    /// would remove every entry of `replica` it doesn't carry.
+    pub fn retire(&mut self, replica: ReplicaId) {
+        self.ctx.retire(replica)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.ctx.is_empty()
+    }
+
+    /// First entry whose dot isn't in the context. Kernels only changed through their methods never
+    /// have one, a kernel that does was made up or corrupted on the way and merging it would keep
+    /// entries around that were already removed.
+    pub fn uncovered_dot(&self) -> Option<Dot> {
+        self.entries
+            .keys()
+            .find(|dot| !self.ctx.contains(**dot))
+            .copied()
+    }
+
+    /// See [`DotCtx::version`]
+    pub fn version(&self) -> VectorClock {
+        self.ctx.version()
+    }
+}
+
+/// Bounds how large an accumulated delta may grow before sending the full state to a peer is
+/// preferable to sending the delta
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaPolicy {
+    pub max_delta_entries: usize,
+}
+
+/// Compacts a CRDT's delta buffer against `acked`, returning `true` when the remaining delta
+/// exceeds `policy`. The delta is dropped in that case and the caller should resync its peers with
+/// the full state.
+pub(crate) fn compact_delta_buffer<V: Clone + PartialEq + Value>(
+    delta: &mut Option<DotKernel<V>>,
+    acked: &VectorClock,
+    policy: Option<DeltaPolicy>,
+) -> bool {
+    let Some(kernel) = delta else {
+        return false;
+    };
+    kernel.compact_acked(acked);
+
+    let resync = policy.is_some_and(|policy| kernel.entries.len() > policy.max_delta_entries);
+    if resync || kernel.is_empty() {
+        *delta = None;
+    }
+    resync
 }
 
 impl Default for DotCtx {
@@ -264,14 +665,16 @@ impl DotCtx {
         self.dot_cloud.insert(dot);
     }
 
-    pub fn contains(&self, dot @ Dot(id, n): Dot) -> bool {
-        match self.clock.get(&id) {
-            Some(found) if *found >= n => true,
-            _ => self.dot_cloud.contains(&dot),
-        }
+    pub fn contains(&self, dot: Dot) -> bool {
+        self.clock.contains(dot) || self.dot_cloud.contains(dot)
     }
 
     pub fn next_dot(&mut self, replica: ReplicaId) -> Dot {
+        debug_assert!(
+            !self.clock.is_retired(replica),
+            "{:?} is retired and can't make new dots",
+            replica
+        );
         let val = self
             .clock
             .entry(replica)
@@ -284,42 +687,413 @@ impl DotCtx {
     }
 
     pub fn merge(&self, other: &Self) -> Self {
-        let clock = self
-            .clock
-            .iter()
-            .fold(other.clock.clone(), |mut acc, (&key, &new_val)| {
-                acc.entry(key)
-                    .and_modify(|val| {
-                        *val = new_val.max(*val);
-                    })
-                    .or_insert(new_val);
-                acc
-            });
+        let mut clock = self.clock.clone();
+        clock.merge_in_place(&other.clock);
 
         let mut dot_cloud = self.dot_cloud.clone();
-        dot_cloud.extend(other.dot_cloud.iter());
+        dot_cloud.extend(other.dot_cloud.ranges());
 
         let mut ret = Self { clock, dot_cloud };
         ret.compact();
         ret
     }
 
+    /// Same as [`DotCtx::merge`] but merges `other` into `self`
+    pub fn merge_in_place(&mut self, other: &Self) {
+        self.clock.merge_in_place(&other.clock);
+        self.dot_cloud.extend(other.dot_cloud.ranges());
+        self.compact();
+    }
+
+    /// Removes a single dot, splitting the clock's run if it falls inside of it. The dots after
+    /// it move to the cloud, so this is the one operation that leaves the context uncompacted.
+    pub fn remove(&mut self, dot @ Dot(id, n): Dot) {
+        match self.clock.get(&id).copied() {
+            Some(c) if n <= c => {
+                if n > 1 {
+                    self.clock.insert(id, n - 1);
+                } else {
+                    self.clock.remove(&id);
+                }
+                self.dot_cloud.insert_range(DotRange(id, n + 1..c + 1));
+            }
+            _ => self.dot_cloud.remove(dot),
+        }
+    }
+
+    /// See [`VectorClock::retire`], also drops `replica`'s dots from the cloud
+    pub fn retire(&mut self, replica: ReplicaId) {
+        self.clock.retire(replica);
+        self.dot_cloud.0.remove(&replica);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clock.is_empty() && self.clock.retired().is_empty() && self.dot_cloud.is_empty()
+    }
+
+    /// Highest dot of each replica in the context, whether it is in the clock or the cloud.
+    /// Replicas send their deltas in order, so a replica that got one of their dots has usually
+    /// seen every dot before it too.
+    pub fn version(&self) -> VectorClock {
+        let mut version = self.clock.clone();
+        for DotRange(id, range) in self.dot_cloud.ranges() {
+            let n = version.entry(id).or_default();
+            *n = (*n).max(range.end - 1);
+        }
+        version
+    }
+
+    /// Every dot in the context as runs, the clock contributing everything up to `n` for each
+    /// replica and every possible dot for retired ones
+    pub fn ranges(&self) -> impl Iterator<Item = DotRange> + '_ {
+        self.clock
+            .iter()
+            .map(|(&id, &n)| DotRange(id, 0..n + 1))
+            .chain(
+                self.clock
+                    .retired()
+                    .iter()
+                    .map(|id| DotRange(id, 0..u64::MAX)),
+            )
+            .chain(self.dot_cloud.ranges())
+    }
+
     /// - If dot for replica in the cloud is 1 greater than the value in clock, update the clock with it and remove from cloud
     /// - If dot for replica in the cloud is less than or equal to the value in clock, remove from cloud
     /// - Otherwise do nothing
+    ///
+    /// Since the cloud stores runs of dots this works on a whole run at a time. Dots of retired
+    /// replicas are dropped altogether.
     pub fn compact(&mut self) {
-        drop(self.dot_cloud.drain_filter(|&Dot(id, n)| {
-            let n2 = self.clock.get(&id).copied().unwrap_or(0);
+        for (id, runs) in self.dot_cloud.0.iter_mut() {
+            if self.clock.is_retired(*id) {
+                runs.clear();
+                continue;
+            }
+            let current = self.clock.get(id).copied().unwrap_or(0);
+            let mut n = current;
+
+            // Runs are sorted by their start so only the first ones can ever touch the clock
+            while let Some((&start, &end)) = runs.iter().next() {
+                if start > n + 1 {
+                    break;
+                }
+                runs.remove(&start);
+                n = n.max(end - 1);
+            }
+
+            if n > current {
+                self.clock.insert(*id, n);
+            }
+        }
+        self.dot_cloud.0.retain(|_, runs| !runs.is_empty());
+    }
+}
+
+/// A run of consecutive dots `start..end` (end is exclusive) from a single replica
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DotRange(pub ReplicaId, pub Range<u64>);
+
+impl DotRange {
+    pub fn dots(&self) -> impl Iterator<Item = Dot> {
+        let id = self.0;
+        self.1.clone().map(move |n| Dot(id, n))
+    }
+}
+
+impl From<Dot> for DotRange {
+    fn from(Dot(id, n): Dot) -> Self {
+        DotRange(id, n..n + 1)
+    }
+}
+
+impl serde::Serialize for DotRange {
+    /// Single dots are serialized in the same `replica:n` form as [`Dot`] so a cloud that
+    /// only contains isolated dots is encoded exactly like the old `BTreeSet<Dot>` was
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let DotRange(id, range) = self;
+        if range.end == range.start + 1 {
+            serializer.serialize_str(&format!("{:?}:{:?}", id.0, range.start))
+        } else {
+            serializer.serialize_str(&format!("{:?}:{:?}..{:?}", id.0, range.start, range.end))
+        }
+    }
+}
+struct DotRangeDeserializer;
+impl<'de> Visitor<'de> for DotRangeDeserializer {
+    type Value = DotRange;
+
+    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(formatter, "Dot or DotRange")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        let (replica, seq) = v
+            .split_once(':')
+            .ok_or(serde::de::Error::missing_field("sequence number"))?;
+        let replica = ReplicaId(
+            replica
+                .parse::<u128>()
+                .map_err(|e| serde::de::Error::custom(e.to_string()))?,
+        );
+        let parse_seq = |n: &str| {
+            n.parse::<u64>()
+                .map_err(|e| serde::de::Error::custom(e.to_string()))
+        };
+
+        match seq.split_once("..") {
+            Some((start, end)) => {
+                let (start, end) = (parse_seq(start)?, parse_seq(end)?);
+                if start >= end {
+                    return Err(serde::de::Error::custom(format!(
+                        "empty dot range: {}..{}",
+                        start, end
+                    )));
+                }
+                Ok(DotRange(replica, start..end))
+            }
+            // `u64::MAX` can't start a run since the end is exclusive
+            None => match parse_seq(seq)? {
+                u64::MAX => Err(serde::de::Error::custom("sequence number out of range")),
+                n => Ok(Dot(replica, n).into()),
+            },
+        }
+    }
+}
+impl<'de> serde::Deserialize<'de> for DotRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(DotRangeDeserializer)
+    }
+}
+
+/// Dot cloud stored as runs of consecutive dots instead of individual dots.
+///
+/// Dots of a replica are handed out sequentially, so even when they arrive out of order they
+/// mostly form long contiguous runs. Keeping them in a `BTreeSet<Dot>` costs a node per dot,
+/// here each replica maps to disjoint, non-adjacent runs `start -> end` (end exclusive), so a
+/// run of 100k dots is a single entry.
+///
+/// Serializes as a list of [`DotRange`]s, and deserializing also accepts the old `BTreeSet<Dot>`
+/// form since a single dot is a valid [`DotRange`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::dot")
+)]
+pub struct DotCloud(BTreeMap<ReplicaId, BTreeMap<u64, u64>>);
+
+impl DotCloud {
+    pub fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    pub fn insert(&mut self, dot: Dot) {
+        self.insert_range(dot.into())
+    }
+
+    pub fn insert_range(&mut self, DotRange(id, range): DotRange) {
+        if range.is_empty() {
+            return;
+        }
+        insert_run(self.0.entry(id).or_default(), range)
+    }
+
+    pub fn remove(&mut self, Dot(id, n): Dot) {
+        let Some(runs) = self.0.get_mut(&id) else {
+            return;
+        };
+        let Some((&start, &end)) = runs.range(..=n).next_back() else {
+            return;
+        };
+        if n >= end {
+            return;
+        }
+
+        runs.remove(&start);
+        if start < n {
+            runs.insert(start, n);
+        }
+        if n + 1 < end {
+            runs.insert(n + 1, end);
+        }
+        if runs.is_empty() {
+            self.0.remove(&id);
+        }
+    }
+
+    pub fn contains(&self, Dot(id, n): Dot) -> bool {
+        self.0.get(&id).is_some_and(|runs| run_contains(runs, n))
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = DotRange> + '_ {
+        self.0.iter().flat_map(|(&id, runs)| {
+            runs.iter()
+                .map(move |(&start, &end)| DotRange(id, start..end))
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Dot> + '_ {
+        self.ranges().flat_map(|range| range.dots())
+    }
+
+    /// Number of dots in the cloud
+    pub fn len(&self) -> usize {
+        self.0
+            .values()
+            .flat_map(|runs| runs.iter())
+            .map(|(start, end)| (end - start) as usize)
+            .sum()
+    }
+
+    /// Number of runs actually stored
+    pub fn run_count(&self) -> usize {
+        self.0.values().map(|runs| runs.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Adds `range` to the disjoint, non-adjacent runs `start -> end` (end exclusive) in `runs`
+fn insert_run<T: Ord + Copy>(runs: &mut BTreeMap<T, T>, range: Range<T>) {
+    if range.is_empty() {
+        return;
+    }
+    let (mut start, mut end) = (range.start, range.end);
+
+    // Coalesce with a preceding run that overlaps or is adjacent
+    if let Some((&s, &e)) = runs.range(..=start).next_back() {
+        if e >= end {
+            return;
+        }
+        if e >= start {
+            start = s;
+        }
+    }
+
+    // Swallow every following run that overlaps or is adjacent
+    while let Some((&s, &e)) = runs.range((Excluded(start), Unbounded)).next() {
+        if s > end {
+            break;
+        }
+        end = end.max(e);
+        runs.remove(&s);
+    }
+
+    runs.insert(start, end);
+}
 
-            if n == n2 + 1 {
-                self.clock.insert(id, n);
-                true
-            } else if n <= n2 {
-                true
-            } else {
-                false
+fn run_contains<T: Ord + Copy>(runs: &BTreeMap<T, T>, n: T) -> bool {
+    runs.range(..=n)
+        .next_back()
+        .is_some_and(|(_, &end)| n < end)
+}
+
+/// Every replica and every run of its dots counts as an entry, however many dots the run holds
+impl MemoryUsage for DotCloud {
+    fn memory_usage(&self) -> Usage {
+        let runs = self.0.values().map(|runs| Usage::btree::<u64, u64>(runs.len()));
+        Usage::btree::<ReplicaId, BTreeMap<u64, u64>>(self.0.len()) + runs.sum()
+    }
+}
+
+impl Extend<DotRange> for DotCloud {
+    fn extend<T: IntoIterator<Item = DotRange>>(&mut self, iter: T) {
+        for range in iter {
+            self.insert_range(range);
+        }
+    }
+}
+
+impl Extend<Dot> for DotCloud {
+    fn extend<T: IntoIterator<Item = Dot>>(&mut self, iter: T) {
+        for dot in iter {
+            self.insert(dot);
+        }
+    }
+}
+
+impl FromIterator<Dot> for DotCloud {
+    fn from_iter<T: IntoIterator<Item = Dot>>(iter: T) -> Self {
+        let mut cloud = Self::new();
+        cloud.extend(iter);
+        cloud
+    }
+}
+
+impl From<BTreeSet<Dot>> for DotCloud {
+    fn from(set: BTreeSet<Dot>) -> Self {
+        set.into_iter().collect()
+    }
+}
+
+impl Serialize for DotCloud {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_seq(self.ranges())
+    }
+}
+
+impl<'de> Deserialize<'de> for DotCloud {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let ranges = Vec::<DotRange>::deserialize(deserializer)?;
+        let mut cloud = DotCloud::new();
+        cloud.extend(ranges);
+        Ok(cloud)
+    }
+}
+
+/// Every replica's runs as how far they start after the end of the previous run and how many
+/// dots they hold, which keeps the numbers small
+impl Encode for DotCloud {
+    fn encode(&self, out: &mut Writer) {
+        out.length(self.0.len());
+        for (id, runs) in self.0.iter() {
+            id.encode(out);
+            out.length(runs.len());
+            let mut prev = 0;
+            for (&start, &end) in runs.iter() {
+                (start - prev).encode(out);
+                (end - start).encode(out);
+                prev = end;
+            }
+        }
+    }
+}
+
+impl Decode for DotCloud {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let out_of_range = DecodeError::Invalid("dot range out of range");
+        let mut cloud = DotCloud::new();
+        for _ in 0..input.length()? {
+            let id = ReplicaId::decode(input)?;
+            let mut prev = 0u64;
+            for _ in 0..input.length()? {
+                let start = prev
+                    .checked_add(u64::decode(input)?)
+                    .ok_or(out_of_range.clone())?;
+                let end = match u64::decode(input)? {
+                    0 => return Err(DecodeError::Invalid("empty dot range")),
+                    len => start.checked_add(len).ok_or(out_of_range.clone())?,
+                };
+                cloud.insert_range(DotRange(id, start..end));
+                prev = end;
             }
-        }));
+        }
+        Ok(cloud)
     }
 }
 
@@ -346,15 +1120,18 @@ pub mod test {
     }
     fn vector_clock_strategy() -> impl Strategy<Value = VectorClock> {
         btree_map(
-            (5..MAX_VALUES).prop_map(ReplicaId),
+            (5..MAX_VALUES).prop_map(ReplicaId::from),
             0..MAX_VALUES,
             0..(MAX_VALUES as usize),
         )
-        .prop_map(VectorClock)
+        .prop_map(VectorClock::from)
     }
     fn dotctx_strategy() -> impl Strategy<Value = DotCtx> {
         (dotcloud_strategy(), vector_clock_strategy()).prop_map(|(dot_cloud, clock)| {
-            let mut ctx = DotCtx { dot_cloud, clock };
+            let mut ctx = DotCtx {
+                dot_cloud: dot_cloud.into(),
+                clock,
+            };
             ctx.compact();
             ctx
         })
@@ -455,9 +1232,13 @@ pub mod test {
     mod kernel {
         use proptest::prelude::*;
 
+        use std::collections::BTreeSet;
+
         use crate::delta_state::dot::test::{
             dotkernel_strategy as dotkernel_strategy_impl, patch_kernels,
         };
+        use crate::delta_state::dot::DotRange;
+        use crate::ReplicaId;
 
         fn dotkernel_strategy() -> impl Strategy<Value = super::DotKernel<u16>> {
             // change as needed
@@ -498,13 +1279,143 @@ pub mod test {
 
                 assert_eq!(aa, a);
             }
+
+            #[test]
+            fn merge_in_place(mut a in dotkernel_strategy(), mut b in dotkernel_strategy()) {
+                patch_kernels(&mut [&mut a, &mut b]);
+
+                let ab = a.merge(&b);
+                let mut ab_in_place = a.clone();
+                ab_in_place.merge_in_place(&b);
+
+                assert_eq!(ab, ab_in_place);
+            }
+
+            /// A peer that acknowledged `acked` ends up in the same state whether it receives the
+            /// full delta or the compacted one
+            #[test]
+            fn compact_acked(mut peer in dotkernel_strategy(), mut delta in dotkernel_strategy()) {
+                patch_kernels(&mut [&mut peer, &mut delta]);
+                // Dots start at 1, the clock can't represent removing dot 0
+                delta.entries.retain(|dot, _| dot.1 > 0);
+                let mut acked = super::VectorClock::default();
+                for (&id, &n) in delta.ctx.clock.iter() {
+                    acked.insert(id, n / 2);
+                }
+
+                // The peer has seen the entries it acknowledged
+                let mut seen = super::DotKernel::<u16>::default();
+                for (&dot, &val) in delta.entries.iter() {
+                    if acked.get(&dot.0).is_some_and(|m| dot.1 <= *m) {
+                        seen.ctx.add(dot);
+                        seen.entries.insert(dot, val);
+                    }
+                }
+                seen.ctx.compact();
+                let peer = peer.merge(&seen);
+
+                let mut compacted = delta.clone();
+                compacted.compact_acked(&acked);
+                for dot in compacted.entries.keys() {
+                    assert!(acked.get(&dot.0).is_none_or(|m| dot.1 > *m));
+                    assert!(compacted.ctx.contains(*dot));
+                }
+
+                assert_eq!(peer.merge(&delta), peer.merge(&compacted));
+            }
+
+            /// Retiring a replica once all of its dots were seen doesn't change the outcome of
+            /// any merge, no matter which replicas have learned about the retirement yet
+            #[test]
+            fn retire(mut a in dotkernel_strategy(), mut b in dotkernel_strategy(), mut c in dotkernel_strategy(), pick in any::<prop::sample::Index>()) {
+                patch_kernels(&mut [&mut a, &mut b, &mut c]);
+                let all = a.merge(&b).merge(&c);
+                let ids = all.ctx.ranges().map(|DotRange(id, _)| id).collect::<BTreeSet<_>>();
+                prop_assume!(!ids.is_empty());
+                let replica = *ids.iter().nth(pick.index(ids.len())).unwrap();
+
+                // `b` catches up and keeps editing before it hears about the retirement
+                let mut b = b.merge(&all);
+                let removed = b.entries.keys().step_by(3).copied().collect::<Vec<_>>();
+                b.remove_dots(removed, &mut Default::default());
+                b.add(ReplicaId(0), 0, &mut Default::default());
+
+                let mut retired = all.clone();
+                retired.retire(replica);
+                for other in [&a, &b, &c] {
+                    let expected = all.merge(other).entries;
+                    assert_eq!(retired.merge(other).entries, expected);
+                    assert_eq!(other.merge(&retired).entries, expected);
+
+                    let mut in_place = other.clone();
+                    in_place.merge_in_place(&retired);
+                    assert_eq!(in_place.entries, expected);
+                    let mut in_place = retired.clone();
+                    in_place.merge_in_place(other);
+                    assert_eq!(in_place.entries, expected);
+                }
+
+                // `b` retires on its own later, everyone still converges
+                let mut b_retired = b.clone();
+                b_retired.retire(replica);
+                let mut expected = all.merge(&b);
+                expected.retire(replica);
+                assert_eq!(retired.merge(&b_retired), expected);
+                assert_eq!(b_retired.merge(&a).merge(&retired).merge(&c), expected);
+                assert_eq!(b.merge(&retired).merge(&b_retired), expected);
+                assert!(!expected.ctx.clock.contains_key(&replica));
+            }
         }
     }
 
     mod ctx {
+        use std::collections::BTreeMap;
+
         use proptest::prelude::*;
 
         use crate::delta_state::dot::test::dotctx_strategy;
+        use crate::delta_state::dot::{Dot, DotRange, VectorClock};
+        use crate::ReplicaId;
+
+        #[test]
+        fn retired_serde() {
+            let mut clock =
+                VectorClock::from(BTreeMap::from([(ReplicaId(1), 4), (ReplicaId(7), 2)]));
+            for id in [3, 4, 5, 1, 9] {
+                clock.retire(ReplicaId(id));
+            }
+            let json = serde_json::to_string(&clock).unwrap();
+
+            assert_eq!(json, r#"{"7":2,"1..2":0,"3..6":0,"9..10":0}"#);
+            assert_eq!(serde_json::from_str::<VectorClock>(&json).unwrap(), clock);
+            assert!(clock.contains(Dot(ReplicaId(4), 1000)));
+            assert!(!clock.contains(Dot(ReplicaId(7), 3)));
+        }
+
+        #[test]
+        fn retired_subset() {
+            let mut clock = VectorClock::default();
+            for id in [3, 4, 5, 9] {
+                clock.retire(ReplicaId(id));
+            }
+            let mut fewer = VectorClock::default();
+            fewer.retire(ReplicaId(4));
+            fewer.retire(ReplicaId(5));
+            assert_eq!(fewer.retired().first_missing_from(clock.retired()), None);
+            assert_eq!(
+                clock.retired().first_missing_from(fewer.retired()),
+                Some(ReplicaId(3))
+            );
+
+            // Bridging the gap between two runs isn't covered by either of them
+            fewer.retire(ReplicaId(6));
+            assert_eq!(
+                fewer.retired().first_missing_from(clock.retired()),
+                Some(ReplicaId(6))
+            );
+            let none = VectorClock::default();
+            assert_eq!(none.retired().first_missing_from(fewer.retired()), None);
+        }
 
         proptest! {
             // #![proptest_config(ProptestConfig{ cases: 5, ..Default::default()})]
@@ -532,6 +1443,236 @@ pub mod test {
 
                 assert_eq!(aa, a);
             }
+
+            #[test]
+            fn merge_in_place(a in dotctx_strategy(), b in dotctx_strategy()) {
+                let ab = a.merge(&b);
+                let mut ab_in_place = a.clone();
+                ab_in_place.merge_in_place(&b);
+
+                assert_eq!(ab, ab_in_place);
+            }
+
+            /// Contexts that retired different replicas still merge into the same context
+            #[test]
+            fn retire(mut a in dotctx_strategy(), mut b in dotctx_strategy(), c in dotctx_strategy(), pick_a in any::<prop::sample::Index>(), pick_b in any::<prop::sample::Index>()) {
+                let ids = a.ranges().chain(b.ranges()).map(|DotRange(id, _)| id).collect::<Vec<_>>();
+                prop_assume!(!ids.is_empty());
+                let (retired_a, retired_b) = (ids[pick_a.index(ids.len())], ids[pick_b.index(ids.len())]);
+                a.retire(retired_a);
+                b.retire(retired_b);
+
+                let ab = a.merge(&b);
+                assert_eq!(ab, b.merge(&a));
+                assert_eq!(ab.merge(&c), a.merge(&b.merge(&c)));
+                let mut ab_in_place = a.clone();
+                ab_in_place.merge_in_place(&b);
+                assert_eq!(ab, ab_in_place);
+
+                for id in [retired_a, retired_b] {
+                    assert!(ab.contains(Dot(id, u64::MAX - 1)));
+                    assert!(!ab.clock.contains_key(&id));
+                    assert!(ab.dot_cloud.ranges().all(|DotRange(other, _)| other != id));
+                }
+            }
+        }
+    }
+
+    /// Checks the run-length encoded cloud against the plain `BTreeSet<Dot>` representation it
+    /// replaced
+    mod cloud {
+        use std::collections::{BTreeMap, BTreeSet};
+
+        use proptest::{collection::btree_set, prelude::*};
+
+        use crate::{
+            delta_state::dot::{test::dotcloud_strategy, Dot, DotCloud, DotCtx, VectorClock},
+            ReplicaId,
+        };
+
+        /// The set based context that used to back `DotCtx`
+        #[derive(Debug, Clone, PartialEq)]
+        struct SetCtx {
+            clock: BTreeMap<ReplicaId, u64>,
+            dot_cloud: BTreeSet<Dot>,
+        }
+
+        impl SetCtx {
+            fn contains(&self, dot @ Dot(id, n): Dot) -> bool {
+                match self.clock.get(&id) {
+                    Some(found) if *found >= n => true,
+                    _ => self.dot_cloud.contains(&dot),
+                }
+            }
+
+            fn merge(&self, other: &Self) -> Self {
+                let mut clock = other.clock.clone();
+                for (&id, &n) in self.clock.iter() {
+                    let val = clock.entry(id).or_insert(n);
+                    *val = n.max(*val);
+                }
+                let mut dot_cloud = self.dot_cloud.clone();
+                dot_cloud.extend(other.dot_cloud.iter());
+
+                let mut ret = Self { clock, dot_cloud };
+                ret.compact();
+                ret
+            }
+
+            fn compact(&mut self) {
+                let clock = &mut self.clock;
+                let dots = std::mem::take(&mut self.dot_cloud);
+                for dot @ Dot(id, n) in dots {
+                    let n2 = clock.get(&id).copied().unwrap_or(0);
+                    if n == n2 + 1 {
+                        clock.insert(id, n);
+                    } else if n > n2 {
+                        self.dot_cloud.insert(dot);
+                    }
+                }
+            }
+
+            fn to_ctx(&self) -> DotCtx {
+                DotCtx {
+                    clock: self.clock.clone().into(),
+                    dot_cloud: self.dot_cloud.clone().into(),
+                }
+            }
+        }
+
+        /// Dense runs of dots with a few holes, which is what real dot clouds look like
+        fn sparse_set_strategy() -> impl Strategy<Value = BTreeSet<Dot>> {
+            btree_set((0..4u64, 0..200u64), 0..150)
+                .prop_map(|dots| dots.into_iter().map(|(id, n)| Dot(id.into(), n)).collect())
+        }
+
+        fn setctx_strategy() -> impl Strategy<Value = SetCtx> {
+            (
+                prop_oneof![sparse_set_strategy(), dotcloud_strategy()],
+                proptest::collection::btree_map(
+                    (0..4u64).prop_map(ReplicaId::from),
+                    0..20u64,
+                    0..4,
+                ),
+            )
+                .prop_map(|(dot_cloud, clock)| {
+                    let mut ctx = SetCtx { clock, dot_cloud };
+                    ctx.compact();
+                    ctx
+                })
+        }
+
+        /// Every dot either context knows about plus its neighbours
+        fn probes(a: &SetCtx, b: &SetCtx) -> BTreeSet<Dot> {
+            let clock_dots = a
+                .clock
+                .iter()
+                .chain(b.clock.iter())
+                .map(|(&id, &n)| Dot(id, n));
+            a.dot_cloud
+                .iter()
+                .chain(b.dot_cloud.iter())
+                .copied()
+                .chain(clock_dots)
+                .flat_map(|Dot(id, n)| [Dot(id, n.saturating_sub(1)), Dot(id, n), Dot(id, n + 1)])
+                .collect()
+        }
+
+        #[test]
+        fn coalesces_runs() {
+            let id = ReplicaId(1);
+            let mut cloud = DotCloud::new();
+            for n in [5, 7, 6, 2, 3, 10] {
+                cloud.insert(Dot(id, n));
+            }
+
+            assert_eq!(cloud.len(), 6);
+            assert_eq!(cloud.run_count(), 3);
+            assert!(cloud.contains(Dot(id, 6)));
+            assert!(!cloud.contains(Dot(id, 4)));
+            assert!(!cloud.contains(Dot(id, 8)));
+        }
+
+        #[test]
+        fn deserializes_set_form() {
+            let set: BTreeSet<Dot> = [
+                Dot(ReplicaId(1), 3),
+                Dot(ReplicaId(1), 4),
+                Dot(ReplicaId(2), 9),
+            ]
+            .into();
+            let json = serde_json::to_string(&set).unwrap();
+            let cloud: DotCloud = serde_json::from_str(&json).unwrap();
+
+            assert_eq!(cloud, DotCloud::from(set));
+            assert_eq!(
+                serde_json::to_string(&cloud).unwrap(),
+                r#"["1:3..5","2:9"]"#
+            );
+        }
+
+        #[test]
+        fn rejects_last_sequence_number() {
+            assert!(serde_json::from_str::<DotCloud>(r#"["1:18446744073709551615"]"#).is_err());
+            assert!(serde_json::from_str::<VectorClock>(r#"{"1":18446744073709551615}"#).is_err());
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig{ ..Default::default()})]
+
+            #[test]
+            fn contains_equivalence(a in setctx_strategy()) {
+                let ctx = a.to_ctx();
+                for dot in probes(&a, &a) {
+                    assert_eq!(a.contains(dot), ctx.contains(dot), "{:?}", dot);
+                }
+            }
+
+            #[test]
+            fn compact_equivalence(clock in proptest::collection::btree_map((0..4u64).prop_map(ReplicaId::from), 0..20u64, 0..4), dot_cloud in sparse_set_strategy()) {
+                let mut set = SetCtx { clock, dot_cloud };
+                let mut ctx = set.to_ctx();
+                set.compact();
+                ctx.compact();
+
+                assert_eq!(set.to_ctx(), ctx);
+                assert_eq!(set.dot_cloud, ctx.dot_cloud.iter().collect::<BTreeSet<_>>());
+            }
+
+            #[test]
+            fn merge_equivalence(a in setctx_strategy(), b in setctx_strategy()) {
+                let merged = a.merge(&b);
+                let ctx = a.to_ctx().merge(&b.to_ctx());
+
+                assert_eq!(merged.to_ctx(), ctx);
+                for dot in probes(&a, &b) {
+                    assert_eq!(merged.contains(dot), ctx.contains(dot), "{:?}", dot);
+                }
+            }
+
+            #[test]
+            fn remove_equivalence(a in setctx_strategy()) {
+                let mut ctx = a.to_ctx();
+                // Dots start at 1, the clock can't represent removing dot 0
+                let probes = probes(&a, &a).into_iter().filter(|dot| dot.1 > 0).collect::<Vec<_>>();
+                for (i, dot) in probes.iter().enumerate() {
+                    if i % 3 == 0 {
+                        ctx.remove(*dot);
+                    }
+                }
+                for (i, dot) in probes.iter().enumerate() {
+                    assert_eq!(i % 3 != 0 && a.contains(*dot), ctx.contains(*dot), "{:?}", dot);
+                }
+            }
+
+            #[test]
+            fn serde_roundtrip(a in setctx_strategy()) {
+                let ctx = a.to_ctx();
+                let json = serde_json::to_string(&ctx.dot_cloud).unwrap();
+                let cloud: DotCloud = serde_json::from_str(&json).unwrap();
+
+                assert_eq!(cloud, ctx.dot_cloud);
+            }
         }
     }
 }
@@ -1,14 +1,57 @@
-use std::collections::{btree_map::Entry, BTreeMap};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::fmt;
 
+use crate::memory::{MemoryUsage, Usage};
 use crate::ReplicaId;
 
 /// Note that the deltas are in a GCounter struct for composability reasons
-#[derive(Debug, Clone, PartialEq)]
+///
+/// Counts only ever go up, so they are unsigned. What happens when one would go past `u64::MAX`
+/// is up to the [`OverflowPolicy`] passed to [`GCounter::increment_by_with`], the total returned
+/// by [`GCounter::value`] saturates.
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::gcounter")
+)]
 pub struct GCounter {
-    values: BTreeMap<ReplicaId, i64>,
+    values: BTreeMap<ReplicaId, u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     delta: Option<Box<GCounter>>,
 }
 
+/// What to do when a replica's count would overflow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Stop at `u64::MAX`
+    #[default]
+    Saturate,
+    /// Leave the counter alone and return [`CounterOverflow`]
+    Error,
+}
+
+/// Adding `by` to `replica`'s `count` doesn't fit in a `u64`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CounterOverflow {
+    pub replica: ReplicaId,
+    pub count: u64,
+    pub by: u64,
+}
+
+impl fmt::Display for CounterOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "incrementing {:?}'s count of {} by {} overflows",
+            self.replica, self.count, self.by
+        )
+    }
+}
+
+impl core::error::Error for CounterOverflow {}
+
 impl Default for GCounter {
     fn default() -> Self {
         Self {
@@ -18,56 +61,87 @@ impl Default for GCounter {
     }
 }
 
+impl MemoryUsage for GCounter {
+    fn memory_usage(&self) -> Usage {
+        Usage::btree::<ReplicaId, u64>(self.values.len()) + self.delta.memory_usage()
+    }
+}
+
 impl GCounter {
     pub fn deltas(&self) -> Option<&GCounter> {
         self.delta.as_deref()
     }
 
-    /// Compute value of the G-counter
-    pub fn value(&self) -> i64 {
-        self.values.values().fold(0, |acc, i| acc + i)
+    /// Compute value of the G-counter, saturating at `u64::MAX`
+    pub fn value(&self) -> u64 {
+        self.values
+            .values()
+            .fold(0u64, |acc, i| acc.saturating_add(*i))
     }
 
     /// Increment G-counter value for a given replica.
     pub fn increment(&mut self, replica: ReplicaId) {
-        *self.values.entry(replica).or_default() += 1;
-        *self
-            .delta
-            .get_or_insert_default()
+        self.increment_by(replica, 1)
+    }
+
+    /// Increment G-counter value for a given replica by `n`, saturating at `u64::MAX`.
+    pub fn increment_by(&mut self, replica: ReplicaId, n: u64) {
+        let _ = self.increment_by_with(replica, n, OverflowPolicy::Saturate);
+    }
+
+    /// Increment G-counter value for a given replica by `n`, returning the replica's new count.
+    pub fn increment_by_with(
+        &mut self,
+        replica: ReplicaId,
+        n: u64,
+        policy: OverflowPolicy,
+    ) -> Result<u64, CounterOverflow> {
+        let count = self.values.get(&replica).copied().unwrap_or_default();
+        let count = match (count.checked_add(n), policy) {
+            (Some(count), _) => count,
+            (None, OverflowPolicy::Saturate) => u64::MAX,
+            (None, OverflowPolicy::Error) => {
+                return Err(CounterOverflow {
+                    replica,
+                    count,
+                    by: n,
+                })
+            }
+        };
+
+        // Merges take the max of each replica's count, so the delta has to carry the whole count
+        // and not just what was added since the last split
+        self.values.insert(replica, count);
+        self.delta
+            .get_or_insert_with(Default::default)
             .values
-            .entry(replica)
-            .or_default() += 1;
+            .insert(replica, count);
+        Ok(count)
     }
 
     /// Merge two G-counters.
     pub fn merge_impl(a: &Self, b: &Self) -> Self {
-        let values = a
-            .values
-            .iter()
-            .fold(b.values.clone(), |mut acc, (&replica, &val)| {
-                match acc.entry(replica) {
-                    Entry::Vacant(entry) => {
-                        entry.insert(val);
-                    }
-                    Entry::Occupied(mut entry) => {
-                        entry.insert(val.max(*entry.get()));
-                    }
-                };
-                acc
-            });
-
-        let delta = match (&a.delta, &b.delta) {
-            (Some(x), Some(y)) => Some(Box::new(Self::merge_impl(&x, &y))),
-            (Some(x), None) => Some(x.clone()),
-            (None, Some(y)) => Some(y.clone()),
-            (None, None) => None,
-        };
-
-        Self { values, delta }
+        let mut merged = a.clone();
+        merged.merge_in_place(b);
+        merged
     }
 
     pub fn merge(&self, other: &Self) -> Self {
-        Self::merge_impl(&self, other)
+        Self::merge_impl(self, other)
+    }
+
+    /// Merge `other` into `self` without rebuilding the values map.
+    pub fn merge_in_place(&mut self, other: &Self) {
+        for (&replica, &val) in other.values.iter() {
+            let entry = self.values.entry(replica).or_insert(val);
+            *entry = val.max(*entry);
+        }
+
+        match (&mut self.delta, &other.delta) {
+            (Some(x), Some(y)) => x.merge_in_place(y),
+            (None, Some(y)) => self.delta = Some(y.clone()),
+            (_, None) => (),
+        }
     }
 
     /// Merge full-state G-counter with G-counter delta.
@@ -75,6 +149,16 @@ impl GCounter {
         Self::merge_impl(self, delta)
     }
 
+    /// Merge G-counter delta into `self` in place.
+    pub fn merge_delta_in_place(&mut self, delta: &GCounter) {
+        self.merge_in_place(delta)
+    }
+
+    /// Take the delta out of `self`, leaving it with an empty delta.
+    pub fn split_mut(&mut self) -> Option<Box<GCounter>> {
+        self.delta.take()
+    }
+
     /// Split G-counter into full-state G-counter with empty delta, and a delta itself.
     pub fn split(&self) -> (Self, Option<Box<GCounter>>) {
         (
@@ -101,13 +185,11 @@ impl GCounter {
         (map, deltas.expect("Expected deltas"))
     }
 
-    pub fn from_u64_map(map: BTreeMap<u64, u8>) -> Self {
+    /// Counts per replica, zeros are left out since they're the same as no count at all
+    pub fn from_u64_map(map: BTreeMap<u64, u64>) -> Self {
         let mut this = Self::default();
-        for (k, v) in map {
-            let v = v.clamp(1, u8::MAX);
-            for _ in 0..v {
-                this.increment(ReplicaId(k))
-            }
+        for (k, v) in map.into_iter().filter(|(_, v)| *v > 0) {
+            this.increment_by(k.into(), v)
         }
         this
     }
@@ -118,10 +200,78 @@ pub mod test {
 
     use proptest::{collection::btree_map, prelude::*};
 
-    use crate::delta_state::gcounter::GCounter;
+    use crate::delta_state::gcounter::{CounterOverflow, GCounter, OverflowPolicy};
 
     pub fn gcounter_strategy() -> impl Strategy<Value = GCounter> {
-        btree_map(any::<u64>(), any::<u8>(), 10).prop_map(GCounter::from_u64_map)
+        btree_map(any::<u64>(), 1..=u8::MAX as u64, 10).prop_map(GCounter::from_u64_map)
+    }
+
+    /// Few replicas with counts close to `u64::MAX`
+    fn near_max_strategy() -> impl Strategy<Value = GCounter> {
+        btree_map(0..4u64, u64::MAX - 8..=u64::MAX, 1..4).prop_map(GCounter::from_u64_map)
+    }
+
+    #[test]
+    fn increment_by() {
+        let mut a = GCounter::default();
+        a.increment(1.into());
+        a.increment_by(2.into(), 10);
+        assert_eq!(a.value(), 11);
+        assert_eq!(
+            a.increment_by_with(2.into(), 5, OverflowPolicy::Error),
+            Ok(15)
+        );
+        assert_eq!(a.value(), 16);
+    }
+
+    #[test]
+    fn overflow_policy() {
+        let mut a = GCounter::from_u64_map([(1, u64::MAX - 1)].into());
+
+        assert_eq!(
+            a.increment_by_with(1.into(), 2, OverflowPolicy::Error),
+            Err(CounterOverflow {
+                replica: 1.into(),
+                count: u64::MAX - 1,
+                by: 2
+            })
+        );
+        assert_eq!(a.value(), u64::MAX - 1);
+
+        a.increment_by(1.into(), 2);
+        assert_eq!(a.value(), u64::MAX);
+        a.increment(2.into());
+        assert_eq!(a.value(), u64::MAX);
+    }
+
+    /// A delta taken after an earlier split still carries the whole count, merging it into a peer
+    /// that saw the earlier state moves it forward
+    #[test]
+    fn delta_after_split() {
+        let mut a = GCounter::default();
+        a.increment_by(1.into(), 5);
+        let (mut a, first) = a.split_expect();
+        let b = GCounter::default().merge(&first);
+
+        a.increment(1.into());
+        let (_, second) = a.split_expect();
+        assert_eq!(b.merge_deltas(&second).value(), 6);
+    }
+
+    #[test]
+    fn serde_rejects_negative_counts() {
+        let json = r#"{"values":{"1":-1}}"#;
+        assert!(serde_json::from_str::<GCounter>(json).is_err());
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let a = GCounter::from_u64_map([(1, 2), (7, 1)].into());
+
+        let json = serde_json::to_string(&a).unwrap();
+        let b: GCounter = serde_json::from_str(&json).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(b.value(), 3);
     }
 
     proptest! {
@@ -151,6 +301,43 @@ pub mod test {
         fn idempotency(a in gcounter_strategy()) {
             assert_eq!(a, a.merge(&a))
         }
+
+        #[test]
+        fn merge_in_place(a in gcounter_strategy(), b in gcounter_strategy()) {
+            let mut ab = a.clone();
+            ab.merge_in_place(&b);
+
+            assert_eq!(a.merge(&b), ab)
+        }
+
+        #[test]
+        fn near_max_merges(a in near_max_strategy(), b in near_max_strategy(), c in near_max_strategy()) {
+            prop_assert_eq!(a.merge(&b), b.merge(&a));
+            prop_assert_eq!(a.merge(&b).merge(&c), a.merge(&b.merge(&c)));
+            prop_assert_eq!(a.merge(&a), a.clone());
+            prop_assert!(a.merge(&b).value() >= a.value().max(b.value()));
+        }
+
+        #[test]
+        fn near_max_increments(a in near_max_strategy(), replica in 0..4u64, n in 0..16u64) {
+            let mut saturated = a.clone();
+            saturated.increment_by(replica.into(), n);
+            let count = a.values.get(&replica.into()).copied().unwrap_or_default();
+            prop_assert_eq!(saturated.values[&replica.into()], count.saturating_add(n));
+            prop_assert_eq!(saturated.merge(&a), saturated.clone());
+
+            let mut checked = a.clone();
+            match checked.increment_by_with(replica.into(), n, OverflowPolicy::Error) {
+                Ok(new) => {
+                    prop_assert_eq!(Some(new), count.checked_add(n));
+                    prop_assert_eq!(checked, saturated);
+                }
+                Err(_) => {
+                    prop_assert_eq!(count.checked_add(n), None);
+                    prop_assert_eq!(checked, a);
+                }
+            }
+        }
     }
 
     mod deltas {
@@ -0,0 +1,524 @@
+//! Graphs, see "A comprehensive study of Convergent and Commutative Replicated Data Types"
+//! (https://hal.inria.fr/inria-00555588) for both.
+//!
+//! [`MonotoneDag`] only grows and stays acyclic however replicas interleave their changes, since
+//! an edge only ever goes where there already was a path. [`TwoPTwoPGraph`] keeps its vertices
+//! and edges in [`AWORSet`]s so both can be removed. A removal only drops what its replica had
+//! seen, so an edge added concurrently with the removal of one of its vertices outlives it, such
+//! edges are left out when the graph is read instead.
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+use crate::{ReplicaId, Value};
+
+use super::aworset::AWORSet;
+use super::dot::DotKernel;
+
+/// An edge going `from` one vertex `to` another
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde_derive::Serialize,
+    serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::graph")
+)]
+pub struct Edge<V: Value> {
+    pub from: V,
+    pub to: V,
+}
+
+impl<V: Value> Edge<V> {
+    pub fn new(from: V, to: V) -> Self {
+        Self { from, to }
+    }
+
+    pub fn touches(&self, vertex: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        self.from == *vertex || self.to == *vertex
+    }
+}
+
+impl<V: Value> Value for Edge<V> {}
+
+/// Add-only directed acyclic graph between a `start` and an `end` vertex.
+///
+/// Vertices are added between two vertices with a path from one to the other and edges only
+/// where there is a path already, so every edge goes the same way as the paths every replica
+/// has. Vertices have to be unique across replicas, e.g. by having the replica's id in them, the
+/// same vertex added between different vertices by two replicas can close a cycle.
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::graph")
+)]
+pub struct MonotoneDag<V: Clone + Ord + Value> {
+    vertices: BTreeSet<V>,
+    edges: BTreeSet<Edge<V>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delta: Option<Box<MonotoneDag<V>>>,
+}
+
+impl<V: Clone + Ord + Value> MonotoneDag<V> {
+    /// Every replica has to start out with the same `start` and `end`
+    pub fn new(start: V, end: V) -> Self {
+        let edge = Edge::new(start.clone(), end.clone());
+        Self {
+            vertices: [start, end].into(),
+            edges: [edge].into(),
+            delta: None,
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            vertices: BTreeSet::new(),
+            edges: BTreeSet::new(),
+            delta: None,
+        }
+    }
+
+    pub fn vertices(&self) -> &BTreeSet<V> {
+        &self.vertices
+    }
+
+    pub fn edges(&self) -> &BTreeSet<Edge<V>> {
+        &self.edges
+    }
+
+    pub fn contains_vertex(&self, vertex: &V) -> bool {
+        self.vertices.contains(vertex)
+    }
+
+    /// Whether `to` can be reached from `from` by at least one edge
+    pub fn has_path(&self, from: &V, to: &V) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut next = Vec::from([from]);
+        while let Some(vertex) = next.pop() {
+            let out = (self.edges.iter())
+                .filter(|edge| edge.from == *vertex)
+                .map(|edge| &edge.to);
+            for succ in out {
+                if succ == to {
+                    return true;
+                }
+                if seen.insert(succ) {
+                    next.push(succ);
+                }
+            }
+        }
+        false
+    }
+
+    /// Adds `vertex` with edges from `from` and to `to`. Does nothing and returns `false` if
+    /// `vertex` is already there or there's no path from `from` to `to`.
+    pub fn add_between(&mut self, from: &V, vertex: V, to: &V) -> bool {
+        if self.vertices.contains(&vertex) || !self.has_path(from, to) {
+            return false;
+        }
+        let edges = [
+            Edge::new(from.clone(), vertex.clone()),
+            Edge::new(vertex.clone(), to.clone()),
+        ];
+        let delta = self.delta.get_or_insert_with(|| Box::new(Self::empty()));
+        delta.vertices.insert(vertex.clone());
+        delta.edges.extend(edges.iter().cloned());
+        self.vertices.insert(vertex);
+        self.edges.extend(edges);
+        true
+    }
+
+    /// Adds an edge alongside a path from `from` to `to`. Does nothing and returns `false` if
+    /// there's no such path.
+    pub fn add_edge(&mut self, from: V, to: V) -> bool {
+        if !self.has_path(&from, &to) {
+            return false;
+        }
+        let edge = Edge::new(from, to);
+        let delta = self.delta.get_or_insert_with(|| Box::new(Self::empty()));
+        delta.edges.insert(edge.clone());
+        self.edges.insert(edge);
+        true
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut ret = self.clone();
+        ret.merge_in_place(other);
+        ret
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        self.vertices.extend(other.vertices.iter().cloned());
+        self.edges.extend(other.edges.iter().cloned());
+        match (&mut self.delta, &other.delta) {
+            (Some(a), Some(b)) => a.merge_in_place(b),
+            (None, Some(b)) => self.delta = Some(b.clone()),
+            (_, None) => (),
+        }
+    }
+
+    pub fn merge_delta(&mut self, delta: Self) {
+        self.vertices.extend(delta.vertices.iter().cloned());
+        self.edges.extend(delta.edges.iter().cloned());
+        match &mut self.delta {
+            Some(a) => a.merge_in_place(&delta),
+            None => self.delta = Some(Box::new(delta)),
+        }
+    }
+
+    pub fn split_mut(&mut self) -> Option<Self> {
+        self.delta.take().map(|delta| *delta)
+    }
+}
+
+/// What a [`TwoPTwoPGraph`] changed since its delta was last taken
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::graph")
+)]
+pub struct GraphDelta<V: Clone + Ord + Value> {
+    pub vertices: Option<DotKernel<V>>,
+    pub edges: Option<DotKernel<Edge<V>>>,
+}
+
+/// Directed graph where vertices and edges can be added and removed, with edges only between
+/// vertices that are there.
+///
+/// Adding an edge takes both its vertices to be there, and removing a vertex removes its edges
+/// along with it. Merges can't keep that up: a replica adding an edge doesn't see the vertex
+/// being removed somewhere else at the same time, so [`TwoPTwoPGraph::edges`] skips edges
+/// missing a vertex. Such an edge is back if its vertex is added again.
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::graph")
+)]
+pub struct TwoPTwoPGraph<V: Clone + Ord + Value> {
+    vertices: AWORSet<V>,
+    edges: AWORSet<Edge<V>>,
+}
+
+impl<V: Clone + Ord + Value> Default for TwoPTwoPGraph<V> {
+    fn default() -> Self {
+        Self {
+            vertices: Default::default(),
+            edges: Default::default(),
+        }
+    }
+}
+
+impl<V: Clone + Ord + Value> TwoPTwoPGraph<V> {
+    pub fn vertices(&self) -> BTreeSet<V> {
+        self.vertices.value()
+    }
+
+    /// Edges with both their vertices there
+    pub fn edges(&self) -> BTreeSet<Edge<V>> {
+        let vertices = self.vertices.values_ref();
+        (self.edges.iter())
+            .filter(|edge| vertices.contains(&edge.from) && vertices.contains(&edge.to))
+            .cloned()
+            .collect()
+    }
+
+    pub fn contains_vertex(&self, vertex: &V) -> bool {
+        self.vertices.contains(vertex)
+    }
+
+    pub fn contains_edge(&self, from: &V, to: &V) -> bool {
+        self.contains_vertex(from)
+            && self.contains_vertex(to)
+            && self.edges.contains(&Edge::new(from.clone(), to.clone()))
+    }
+
+    pub fn add_vertex(&mut self, replica: ReplicaId, vertex: V) {
+        self.vertices.add(replica, vertex);
+    }
+
+    /// Removes `vertex` along with its edges
+    pub fn remove_vertex(&mut self, vertex: &V) {
+        let edges = (self.edges.iter())
+            .filter(|edge| edge.touches(vertex))
+            .cloned()
+            .collect::<Vec<_>>();
+        for edge in edges {
+            self.edges.remove(&edge);
+        }
+        self.vertices.remove(vertex);
+    }
+
+    /// Does nothing and returns `false` if either vertex isn't there
+    pub fn add_edge(&mut self, replica: ReplicaId, from: V, to: V) -> bool {
+        if !self.contains_vertex(&from) || !self.contains_vertex(&to) {
+            return false;
+        }
+        self.edges.add(replica, Edge::new(from, to));
+        true
+    }
+
+    pub fn remove_edge(&mut self, from: &V, to: &V) {
+        self.edges.remove(&Edge::new(from.clone(), to.clone()));
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            vertices: self.vertices.merge(&other.vertices),
+            edges: self.edges.merge(&other.edges),
+        }
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        self.vertices.merge_in_place(&other.vertices);
+        self.edges.merge_in_place(&other.edges);
+    }
+
+    pub fn merge_delta(&mut self, delta: GraphDelta<V>) {
+        if let Some(vertices) = delta.vertices {
+            self.vertices.merge_delta(vertices);
+        }
+        if let Some(edges) = delta.edges {
+            self.edges.merge_delta(edges);
+        }
+    }
+
+    pub fn split_mut(&mut self) -> Option<GraphDelta<V>> {
+        let delta = GraphDelta {
+            vertices: self.vertices.split_mut(),
+            edges: self.edges.split_mut(),
+        };
+        (delta.vertices.is_some() || delta.edges.is_some()).then_some(delta)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ReplicaGenerator;
+
+    use super::{Edge, MonotoneDag, TwoPTwoPGraph};
+
+    #[test]
+    fn dag_adds_along_paths() {
+        let mut a = MonotoneDag::new(0u32, 100);
+        assert!(a.add_between(&0, 50, &100));
+        assert!(a.add_between(&50, 75, &100));
+        assert!(!a.add_between(&75, 60, &50));
+        assert!(!a.add_between(&0, 75, &100));
+        assert!(a.add_edge(0, 75));
+        assert!(!a.add_edge(75, 50));
+
+        let mut b = MonotoneDag::new(0u32, 100);
+        assert!(b.add_between(&0, 25, &100));
+        b.merge_delta(a.split_mut().unwrap());
+        a.merge_delta(b.split_mut().unwrap());
+        assert_eq!(a.vertices(), b.vertices());
+        assert_eq!(a.edges(), b.edges());
+        assert!(a.has_path(&25, &100) && a.has_path(&0, &75));
+        assert!(!a.has_path(&25, &50) && !a.has_path(&100, &0));
+    }
+
+    #[test]
+    fn graph_drops_edges_of_removed_vertices() {
+        let mut gen = ReplicaGenerator::new();
+        let (alice_id, bob_id) = (gen.gen(), gen.gen());
+        let mut alice = TwoPTwoPGraph::<u32>::default();
+        let mut bob = TwoPTwoPGraph::<u32>::default();
+
+        alice.add_vertex(alice_id, 1);
+        alice.add_vertex(alice_id, 2);
+        assert!(!alice.add_edge(alice_id, 1, 3));
+        assert!(alice.add_edge(alice_id, 1, 2));
+        bob.merge_delta(alice.split_mut().unwrap());
+
+        // Bob adds an edge to 2 while alice removes it
+        bob.add_vertex(bob_id, 3);
+        assert!(bob.add_edge(bob_id, 3, 2));
+        alice.remove_vertex(&2);
+        assert!(!alice.contains_edge(&1, &2));
+
+        alice.merge_delta(bob.split_mut().unwrap());
+        bob.merge_delta(alice.split_mut().unwrap());
+        for graph in [&alice, &bob] {
+            assert_eq!(graph.vertices(), [1, 3].into());
+            assert!(graph.edges().is_empty());
+        }
+
+        // Only the edge alice hadn't seen comes back with the vertex
+        bob.add_vertex(bob_id, 2);
+        alice.merge_delta(bob.split_mut().unwrap());
+        assert_eq!(alice.edges(), [Edge::new(3, 2)].into());
+        alice.remove_edge(&3, &2);
+        assert!(alice.edges().is_empty());
+    }
+
+    pub mod properties {
+        use std::collections::BTreeSet;
+
+        use proptest::{collection::vec, prelude::*};
+
+        use crate::{
+            delta_state::graph::{GraphDelta, MonotoneDag, TwoPTwoPGraph},
+            ReplicaId,
+        };
+
+        /// Something one of the replicas does, vertices and edges are picked by index among the
+        /// ones the replica has
+        #[derive(Debug, Clone)]
+        enum Op {
+            AddVertex(u8),
+            AddEdge(usize, usize),
+            RemoveVertex(usize),
+            RemoveEdge(usize),
+            /// Merges the delta buffer of another replica, picked by index
+            MergeDelta(usize),
+        }
+
+        fn history_strategy(replicas: usize) -> impl Strategy<Value = Vec<(usize, Op)>> {
+            let op = prop_oneof![
+                2 => (0..16u8).prop_map(Op::AddVertex),
+                3 => (any::<usize>(), any::<usize>()).prop_map(|(a, b)| Op::AddEdge(a, b)),
+                1 => any::<usize>().prop_map(Op::RemoveVertex),
+                1 => any::<usize>().prop_map(Op::RemoveEdge),
+                2 => (0..replicas).prop_map(Op::MergeDelta),
+            ];
+            vec((0..replicas, op), 0..60)
+        }
+
+        fn pick<T: Clone>(values: &BTreeSet<T>, n: usize) -> Option<T> {
+            let len = values.len();
+            (len > 0).then(|| values.iter().nth(n % len).unwrap().clone())
+        }
+
+        /// Every edge read has both of its vertices
+        fn assert_edges_have_vertices(graph: &TwoPTwoPGraph<u8>) {
+            let vertices = graph.vertices();
+            for edge in graph.edges() {
+                assert!(vertices.contains(&edge.from) && vertices.contains(&edge.to));
+            }
+        }
+
+        /// Replays `history` on `replicas` empty graphs, each one keeps every delta it made or
+        /// received in its buffer
+        fn replay(replicas: usize, history: Vec<(usize, Op)>) -> Vec<TwoPTwoPGraph<u8>> {
+            let mut graphs = vec![TwoPTwoPGraph::default(); replicas];
+            for (i, op) in history {
+                let replica = ReplicaId::from(i as u64);
+                let delta = match op {
+                    Op::MergeDelta(from) => Some(GraphDelta {
+                        vertices: graphs[from].vertices.delta.clone(),
+                        edges: graphs[from].edges.delta.clone(),
+                    }),
+                    _ => None,
+                };
+                let graph = &mut graphs[i];
+                match op {
+                    Op::AddVertex(vertex) => graph.add_vertex(replica, vertex),
+                    Op::AddEdge(a, b) => {
+                        let vertices = graph.vertices();
+                        if let (Some(a), Some(b)) = (pick(&vertices, a), pick(&vertices, b)) {
+                            assert!(graph.add_edge(replica, a, b));
+                        }
+                    }
+                    Op::RemoveVertex(n) => {
+                        if let Some(vertex) = pick(&graph.vertices(), n) {
+                            graph.remove_vertex(&vertex);
+                        }
+                    }
+                    Op::RemoveEdge(n) => {
+                        if let Some(edge) = pick(&graph.edges(), n) {
+                            graph.remove_edge(&edge.from, &edge.to);
+                        }
+                    }
+                    Op::MergeDelta(_) => graph.merge_delta(delta.unwrap()),
+                }
+                assert_edges_have_vertices(graph);
+            }
+            graphs
+        }
+
+        fn replicas_strategy() -> impl Strategy<Value = Vec<TwoPTwoPGraph<u8>>> {
+            history_strategy(3).prop_map(|history| replay(3, history))
+        }
+
+        /// Replicas adding vertices unique to them between random vertices they know of and
+        /// random shortcut edges
+        fn dags_strategy() -> impl Strategy<Value = Vec<MonotoneDag<u32>>> {
+            let op = (0..3usize, 0..3usize, any::<(usize, usize)>(), any::<bool>());
+            vec(op, 0..60).prop_map(|history| {
+                let (start, end) = (0, 1);
+                let mut dags = vec![MonotoneDag::new(start, end); 3];
+                for (n, (i, from, (a, b), shortcut)) in history.into_iter().enumerate() {
+                    if from != i {
+                        let delta = dags[from].clone();
+                        dags[i].merge_in_place(&delta);
+                    }
+                    let vertices = dags[i].vertices().clone();
+                    let (a, b) = (pick(&vertices, a).unwrap(), pick(&vertices, b).unwrap());
+                    if shortcut {
+                        dags[i].add_edge(a, b);
+                    } else {
+                        dags[i].add_between(&a, (i as u32 + 1) * 1000 + n as u32, &b);
+                    }
+                }
+                dags
+            })
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig{ ..Default::default()})]
+
+            #[test]
+            fn graph_commutativity(graphs in replicas_strategy()) {
+                let [a, b, _] = <[_; 3]>::try_from(graphs).unwrap();
+                assert_eq!(a.merge(&b), b.merge(&a));
+            }
+
+            #[test]
+            fn graph_associativity(graphs in replicas_strategy()) {
+                let [a, b, c] = <[_; 3]>::try_from(graphs).unwrap();
+                assert_eq!(a.merge(&b).merge(&c), a.merge(&b.merge(&c)));
+            }
+
+            #[test]
+            fn graph_idempotency(graphs in replicas_strategy()) {
+                let a = &graphs[0];
+                assert_eq!(&a.merge(a), a);
+            }
+
+            /// Merging everything with concurrent vertex and edge removals in any order ends up in
+            /// the same graph, with no edge missing a vertex
+            #[test]
+            fn graph_edges_need_vertices(graphs in replicas_strategy()) {
+                let [a, b, c] = <[_; 3]>::try_from(graphs).unwrap();
+                let abc = a.merge(&b).merge(&c);
+                let cba = c.merge(&b).merge(&a);
+                assert_edges_have_vertices(&abc);
+                assert_eq!(abc.vertices(), cba.vertices());
+                assert_eq!(abc.edges(), cba.edges());
+            }
+
+            #[test]
+            fn dag_stays_acyclic(dags in dags_strategy()) {
+                let merged = dags[0].merge(&dags[1]).merge(&dags[2]);
+                assert_eq!(merged.edges(), dags[2].merge(&dags[1]).merge(&dags[0]).edges());
+                for vertex in merged.vertices() {
+                    assert!(!merged.has_path(vertex, vertex));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Spreadsheet-like grid of cells, see [`Grid`].
+//!
+//! Rows and columns are [`ORArray`]s, which give each of them a [`Position`] that stays the same
+//! whatever is inserted or removed around it. Cells are keyed by the positions of their row and
+//! column rather than by their indices, so an edit made concurrently with rows or columns being
+//! inserted still lands in the cell it was made in. Every cell is an [`MVReg`], concurrent edits
+//! of a cell are all kept until a replica that saw them edits it again.
+//!
+//! Removing a row or a column hides its cells, edits made concurrently with the removal included.
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+
+use crate::{ReplicaId, Value};
+
+use super::dot::{Dot, DotKernel};
+use super::mvreg::MVReg;
+use super::orarray::{self, ORArray, Position};
+
+/// Where a cell is, by the positions of its row and column
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+pub struct Cell {
+    pub row: Position,
+    pub column: Position,
+}
+
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(bound(
+    serialize = "V: serde::Serialize",
+    deserialize = "V: serde::Deserialize<'de>"
+))]
+pub struct Grid<V: Clone + Ord + Value> {
+    rows: ORArray<()>,
+    columns: ORArray<()>,
+    #[serde(with = "entries")]
+    cells: BTreeMap<Cell, MVReg<V>>,
+}
+
+/// Changes of a [`Grid`] since its delta was last taken
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(bound(
+    serialize = "V: serde::Serialize",
+    deserialize = "V: serde::Deserialize<'de>"
+))]
+pub struct GridDelta<V: Clone + Ord + Value> {
+    pub rows: Option<orarray::Deltas<()>>,
+    pub columns: Option<orarray::Deltas<()>>,
+    #[serde(with = "entries")]
+    pub cells: BTreeMap<Cell, DotKernel<V>>,
+}
+
+impl<V: Clone + Ord + Value> Default for Grid<V> {
+    fn default() -> Self {
+        Self {
+            rows: Default::default(),
+            columns: Default::default(),
+            cells: Default::default(),
+        }
+    }
+}
+
+impl<V: Clone + Ord + Value> Default for GridDelta<V> {
+    fn default() -> Self {
+        Self {
+            rows: None,
+            columns: None,
+            cells: Default::default(),
+        }
+    }
+}
+
+impl<V: Clone + Ord + Value> GridDelta<V> {
+    /// See [`DotKernel::uncovered_dot`]
+    pub fn uncovered_dot(&self) -> Option<Dot> {
+        let mut axes = self.rows.iter().chain(&self.columns);
+        (axes.find_map(DotKernel::uncovered_dot))
+            .or_else(|| self.cells.values().find_map(DotKernel::uncovered_dot))
+    }
+}
+
+impl<V: Clone + Ord + Value> Grid<V> {
+    pub fn rows(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// Inserts an empty row so it ends up at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > rows`.
+    pub fn insert_row(&mut self, replica: ReplicaId, index: usize) {
+        self.rows.insert(replica, index, ());
+    }
+
+    /// Returns `false` if there's no row at `index`
+    pub fn remove_row(&mut self, index: usize) -> bool {
+        self.rows.remove(index).is_some()
+    }
+
+    /// Inserts an empty column so it ends up at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > columns`.
+    pub fn insert_column(&mut self, replica: ReplicaId, index: usize) {
+        self.columns.insert(replica, index, ());
+    }
+
+    /// Returns `false` if there's no column at `index`
+    pub fn remove_column(&mut self, index: usize) -> bool {
+        self.columns.remove(index).is_some()
+    }
+
+    /// Where the cell at `row` and `column` is, `None` if it is out of the grid
+    pub fn cell(&self, row: usize, column: usize) -> Option<Cell> {
+        Some(Cell {
+            row: self.rows.position(row)?.clone(),
+            column: self.columns.position(column)?.clone(),
+        })
+    }
+
+    /// Overwrites the cell at `row` and `column`, returns `false` if it is out of the grid
+    pub fn set(&mut self, replica: ReplicaId, row: usize, column: usize, value: V) -> bool {
+        let Some(cell) = self.cell(row, column) else {
+            return false;
+        };
+        self.cells.entry(cell).or_default().set(replica, value);
+        true
+    }
+
+    /// Values of the cell at `row` and `column`, more than one if it was edited concurrently and
+    /// none if it was never edited or is out of the grid
+    pub fn get(&self, row: usize, column: usize) -> BTreeSet<&V> {
+        (self.cell(row, column))
+            .and_then(|cell| self.cells.get(&cell))
+            .map_or_else(BTreeSet::new, MVReg::value)
+    }
+
+    /// Values of every cell row by row
+    pub fn values(&self) -> Vec<Vec<BTreeSet<&V>>> {
+        let columns = self.columns.positions().collect::<Vec<_>>();
+        (self.rows.positions())
+            .map(|row| {
+                (columns.iter())
+                    .map(|column| {
+                        let cell = Cell {
+                            row: row.clone(),
+                            column: (*column).clone(),
+                        };
+                        self.cells
+                            .get(&cell)
+                            .map_or_else(BTreeSet::new, MVReg::value)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut ret = self.clone();
+        ret.merge_in_place(other);
+        ret
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        self.rows.merge_in_place(&other.rows);
+        self.columns.merge_in_place(&other.columns);
+        for (cell, reg) in other.cells.iter() {
+            match self.cells.get_mut(cell) {
+                Some(ours) => *ours = ours.merge(reg),
+                None => {
+                    self.cells.insert(cell.clone(), reg.clone());
+                }
+            }
+        }
+    }
+
+    pub fn merge_delta(&mut self, delta: &GridDelta<V>) {
+        if let Some(rows) = &delta.rows {
+            self.rows.merge_delta_in_place(rows);
+        }
+        if let Some(columns) = &delta.columns {
+            self.columns.merge_delta_in_place(columns);
+        }
+        for (cell, kernel) in delta.cells.iter() {
+            let reg = self.cells.entry(cell.clone()).or_default();
+            reg.merge_delta(kernel.clone());
+        }
+    }
+
+    pub fn split_mut(&mut self) -> Option<GridDelta<V>> {
+        let cells = (self.cells.iter_mut())
+            .filter_map(|(cell, reg)| reg.split_mut().map(|delta| (cell.clone(), delta)))
+            .collect::<BTreeMap<_, _>>();
+        let delta = GridDelta {
+            rows: self.rows.split_mut(),
+            columns: self.columns.split_mut(),
+            cells,
+        };
+        let empty = delta.rows.is_none() && delta.columns.is_none() && delta.cells.is_empty();
+        (!empty).then_some(delta)
+    }
+}
+
+/// Maps keyed by cells as a list of entries, JSON only has string keys
+mod entries {
+    use alloc::collections::BTreeMap;
+    use alloc::vec::Vec;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<K, V, S>(map: &BTreeMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+    where
+        K: Ord + Deserialize<'de>,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let entries = Vec::<(K, V)>::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::collections::BTreeSet;
+
+    use crate::ReplicaGenerator;
+
+    use super::Grid;
+
+    fn table(grid: &Grid<String>) -> Vec<Vec<Vec<&str>>> {
+        (grid.values().into_iter())
+            .map(|row| {
+                (row.into_iter())
+                    .map(|cell| cell.into_iter().map(String::as_str).collect())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edits_follow_inserted_rows_and_columns() {
+        let mut gen = ReplicaGenerator::new();
+        let (a_id, b_id) = (gen.gen(), gen.gen());
+        let mut a = Grid::<String>::default();
+        a.insert_row(a_id, 0);
+        a.insert_row(a_id, 1);
+        a.insert_column(a_id, 0);
+        let mut b = Grid::default();
+        b.merge_delta(&a.split_mut().unwrap());
+
+        // Bob edits the second row while Alice inserts a row and a column in front of it
+        assert!(b.set(b_id, 1, 0, "bob".into()));
+        assert!(!b.set(b_id, 2, 0, "nowhere".into()));
+        a.insert_row(a_id, 0);
+        a.insert_column(a_id, 0);
+        assert!(a.set(a_id, 0, 0, "alice".into()));
+
+        let a_delta = a.split_mut().unwrap();
+        a.merge_delta(&b.split_mut().unwrap());
+        b.merge_delta(&a_delta);
+        assert_eq!(table(&a), table(&b));
+        assert_eq!(
+            table(&a),
+            [
+                [vec!["alice"], vec![]],
+                [vec![], vec![]],
+                [vec![], vec!["bob"]],
+            ]
+        );
+        assert_eq!(a.get(2, 1), BTreeSet::from([&"bob".to_string()]));
+    }
+
+    #[test]
+    fn concurrent_edits_and_removals() {
+        let mut gen = ReplicaGenerator::new();
+        let (a_id, b_id) = (gen.gen(), gen.gen());
+        let mut a = Grid::<String>::default();
+        a.insert_row(a_id, 0);
+        a.insert_row(a_id, 1);
+        a.insert_column(a_id, 0);
+        let mut b = Grid::default();
+        b.merge_delta(&a.split_mut().unwrap());
+
+        // Both edit the same cell, Bob edits the row Alice removes
+        a.set(a_id, 0, 0, "a".into());
+        b.set(b_id, 0, 0, "b".into());
+        b.set(b_id, 1, 0, "gone".into());
+        assert!(a.remove_row(1));
+        let merged = a.merge(&b);
+        let a_delta = a.split_mut().unwrap();
+        a.merge_delta(&b.split_mut().unwrap());
+        b.merge_delta(&a_delta);
+        for grid in [&a, &b, &merged] {
+            assert_eq!(table(grid), [[vec!["a", "b"]]]);
+        }
+
+        // Editing it again after seeing both settles it everywhere
+        b.set(b_id, 0, 0, "c".into());
+        a.merge_delta(&b.split_mut().unwrap());
+        assert_eq!(table(&a), [[vec!["c"]]]);
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let mut a = Grid::<String>::default();
+        a.insert_row(a_id, 0);
+        a.insert_column(a_id, 0);
+        a.set(a_id, 0, 0, "x".into());
+
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(serde_json::from_str::<Grid<String>>(&json).unwrap(), a);
+        let delta = a.split_mut().unwrap();
+        let json = serde_json::to_string(&delta).unwrap();
+        let mut b = Grid::default();
+        b.merge_delta(&serde_json::from_str(&json).unwrap());
+        assert_eq!(table(&a), table(&b));
+    }
+}
@@ -1,20 +1,28 @@
-use std::collections::BTreeSet;
-use std::fmt::Debug;
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
 
-#[derive(Debug, Clone)]
-pub struct GSet<T: Debug + Clone + Ord> {
+use crate::Value;
+
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::gset")
+)]
+pub struct GSet<T: Clone + Ord + Value> {
     values: BTreeSet<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     delta: Option<Box<GSet<T>>>,
 }
 
-impl<T: Debug + Clone + Ord> GSet<T> {
+impl<T: Clone + Ord + Value> GSet<T> {
     pub fn value(&self) -> &BTreeSet<T> {
         &self.values
     }
 
     pub fn add(&mut self, val: T) {
         self.values.insert(val.clone());
-        let deltas = self.delta.get_or_insert_default();
+        let deltas = self.delta.get_or_insert_with(Default::default);
         deltas.values.insert(val);
     }
 
@@ -52,7 +60,7 @@ impl<T: Debug + Clone + Ord> GSet<T> {
     }
 }
 
-impl<T: Debug + Clone + Ord> Default for GSet<T> {
+impl<T: Clone + Ord + Value> Default for GSet<T> {
     fn default() -> Self {
         Self {
             values: Default::default(),
@@ -60,3 +68,23 @@ impl<T: Debug + Clone + Ord> Default for GSet<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::GSet;
+
+    #[test]
+    fn serde_roundtrip() {
+        let mut a = GSet::<String>::default();
+        a.add("apple".into());
+        a.add("orange".into());
+
+        let json = serde_json::to_string(&a).unwrap();
+        let b: GSet<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(a, b);
+
+        let (a, _) = a.split();
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(json, r#"{"values":["apple","orange"]}"#);
+    }
+}
@@ -0,0 +1,682 @@
+//! Arbitrary JSON documents as nested delta CRDTs.
+//!
+//! Every node of the tree is a causal dot store sharing the document's single [`DotCtx`], the way
+//! the ORMap of "Delta State Replicated Data Types" (Almeida et al.) embeds its values:
+//!
+//! - objects map keys to child nodes, like an ORMap
+//! - arrays map dense [`Position`]s to child nodes, like [`ORArray`](super::orarray::ORArray)
+//! - scalars are kept with the dots of their assignments, like an [`MVReg`](super::mvreg::MVReg)
+//!
+//! A child exists as long as it holds at least one dot, so removing a subtree is just adding all
+//! of its dots to the context. Whether a node is an object, an array or a scalar is itself an
+//! assignment held with a dot. Concurrent assignments of different kinds are all kept and the one
+//! with the greatest dot is the one that is read, the same rule as for concurrent scalars.
+//!
+//! Removes are observed removes: an edit inside of an object that is concurrently removed brings
+//! the object back with only the edited fields in it.
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::{vec, vec::Vec};
+use core::fmt;
+
+use serde_json::{Map, Value as JsonValue};
+
+use crate::ReplicaId;
+
+use super::dot::{Dot, DotCtx};
+use super::orarray::Position;
+
+/// What a node was assigned to be
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub enum Assigned {
+    Scalar(JsonValue),
+    Object,
+    Array,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct Node {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    assigned: BTreeMap<Dot, Assigned>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    fields: BTreeMap<String, Node>,
+    /// Positions aren't strings so they can't be map keys in every format
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty", with = "elements")]
+    elements: BTreeMap<Position, Node>,
+}
+
+/// Changes made to a [`Json`] document, merged into other replicas with [`Json::merge_delta`]
+#[derive(Debug, Clone, PartialEq, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct JsonDelta {
+    root: Node,
+    ctx: DotCtx,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Json {
+    root: Node,
+    ctx: DotCtx,
+    delta: Option<JsonDelta>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// The pointer doesn't start with `/` and isn't empty
+    InvalidPointer(String),
+    /// Nothing is stored at the pointer
+    NotFound(String),
+    /// The pointer goes through a scalar, or indexes an object or keys an array
+    NotAContainer(String),
+    /// Array index past the end, or not a number
+    InvalidIndex(String),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::InvalidPointer(path) => write!(f, "invalid JSON pointer: {path:?}"),
+            PatchError::NotFound(path) => write!(f, "nothing stored at {path:?}"),
+            PatchError::NotAContainer(path) => {
+                write!(
+                    f,
+                    "{path:?} is not an object or array that can be traversed"
+                )
+            }
+            PatchError::InvalidIndex(path) => write!(f, "invalid array index in {path:?}"),
+        }
+    }
+}
+
+impl core::error::Error for PatchError {}
+
+enum Op {
+    Set(JsonValue),
+    Insert(usize, JsonValue),
+    Remove,
+}
+
+/// Hands out consecutive dots of one replica and remembers them for the delta's context
+struct Dots {
+    replica: ReplicaId,
+    next: u64,
+    used: Vec<Dot>,
+}
+
+impl Dots {
+    fn next(&mut self) -> Dot {
+        let dot = Dot(self.replica, self.next);
+        self.next += 1;
+        self.used.push(dot);
+        dot
+    }
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.assigned.is_empty() && self.fields.is_empty() && self.elements.is_empty()
+    }
+
+    /// The assignment that is read, the one with the greatest dot. A container whose assignment
+    /// was removed concurrently with an edit inside of it is still a container.
+    fn kind(&self) -> Option<&Assigned> {
+        const OBJECT: &Assigned = &Assigned::Object;
+        const ARRAY: &Assigned = &Assigned::Array;
+
+        match self.assigned.values().next_back() {
+            Some(assigned) => Some(assigned),
+            None if !self.fields.is_empty() => Some(OBJECT),
+            None if !self.elements.is_empty() => Some(ARRAY),
+            None => None,
+        }
+    }
+
+    fn collect_dots(&self, dots: &mut Vec<Dot>) {
+        dots.extend(self.assigned.keys().copied());
+        for child in self.fields.values().chain(self.elements.values()) {
+            child.collect_dots(dots);
+        }
+    }
+
+    fn join(&self, ctx: &DotCtx, other: &Node, other_ctx: &DotCtx) -> Node {
+        // A dot survives if both sides have it or the side missing it has never seen it
+        let assigned =
+            self.assigned
+                .iter()
+                .filter(|(dot, _)| other.assigned.contains_key(dot) || !other_ctx.contains(**dot))
+                .chain(
+                    other.assigned.iter().filter(|(dot, _)| {
+                        !self.assigned.contains_key(dot) && !ctx.contains(**dot)
+                    }),
+                )
+                .map(|(dot, assigned)| (*dot, assigned.clone()))
+                .collect();
+
+        Node {
+            assigned,
+            fields: join_children(&self.fields, ctx, &other.fields, other_ctx),
+            elements: join_children(&self.elements, ctx, &other.elements, other_ctx),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        match self.kind() {
+            None => JsonValue::Null,
+            Some(Assigned::Scalar(value)) => value.clone(),
+            Some(Assigned::Object) => JsonValue::Object(
+                self.fields
+                    .iter()
+                    .map(|(key, child)| (key.clone(), child.to_json()))
+                    .collect::<Map<_, _>>(),
+            ),
+            Some(Assigned::Array) => {
+                JsonValue::Array(self.elements.values().map(Node::to_json).collect())
+            }
+        }
+    }
+
+    fn build(value: &JsonValue, dots: &mut Dots) -> Node {
+        let mut node = Node::default();
+        match value {
+            JsonValue::Object(map) => {
+                node.assigned.insert(dots.next(), Assigned::Object);
+                node.fields = map
+                    .iter()
+                    .map(|(key, value)| (key.clone(), Node::build(value, dots)))
+                    .collect();
+            }
+            JsonValue::Array(values) => {
+                node.assigned.insert(dots.next(), Assigned::Array);
+                let mut last = None;
+                for value in values {
+                    let pos = Position::between(last.as_ref(), None, dots.next());
+                    node.elements.insert(pos.clone(), Node::build(value, dots));
+                    last = Some(pos);
+                }
+            }
+            scalar => {
+                node.assigned
+                    .insert(dots.next(), Assigned::Scalar(scalar.clone()));
+            }
+        }
+        node
+    }
+
+    /// Builds the part of the tree an operation changes. Dots dropped by the operation are pushed
+    /// to `removed`, a node that was never assigned gets created as the container the pointer
+    /// needs it to be.
+    fn patch(
+        node: Option<&Node>,
+        path: &[String],
+        op: Op,
+        dots: &mut Dots,
+        removed: &mut Vec<Dot>,
+        pointer: &str,
+    ) -> Result<Node, PatchError> {
+        let kind = node.and_then(Node::kind);
+
+        let Some((segment, rest)) = path.split_first() else {
+            return match op {
+                Op::Set(value) => {
+                    if let Some(node) = node {
+                        node.collect_dots(removed);
+                    }
+                    Ok(Node::build(&value, dots))
+                }
+                Op::Remove => match node {
+                    Some(node) if !node.is_empty() => {
+                        node.collect_dots(removed);
+                        Ok(Node::default())
+                    }
+                    _ => Err(PatchError::NotFound(pointer.to_string())),
+                },
+                Op::Insert(index, value) => {
+                    let mut patch = Node::default();
+                    let elements = match kind {
+                        Some(Assigned::Array) => &node.unwrap().elements,
+                        None => {
+                            patch.assigned.insert(dots.next(), Assigned::Array);
+                            &patch.elements
+                        }
+                        Some(_) => return Err(PatchError::NotAContainer(pointer.to_string())),
+                    };
+                    if index > elements.len() {
+                        return Err(PatchError::InvalidIndex(pointer.to_string()));
+                    }
+                    let left = index.checked_sub(1).and_then(|i| elements.keys().nth(i));
+                    let right = elements.keys().nth(index);
+                    let pos = Position::between(left, right, dots.next());
+                    patch.elements.insert(pos, Node::build(&value, dots));
+                    Ok(patch)
+                }
+            };
+        };
+
+        let mut patch = Node::default();
+        match kind {
+            Some(Assigned::Array) => {
+                let elements = &node.unwrap().elements;
+                let index = match segment.as_str() {
+                    "-" => elements.len(),
+                    index => index
+                        .parse::<usize>()
+                        .map_err(|_| PatchError::InvalidIndex(pointer.to_string()))?,
+                };
+                match elements.iter().nth(index) {
+                    Some((pos, child)) => {
+                        let child = Node::patch(Some(child), rest, op, dots, removed, pointer)?;
+                        patch.elements.insert(pos.clone(), child);
+                    }
+                    // Setting one past the end appends
+                    None if index == elements.len() && rest.is_empty() => match op {
+                        Op::Set(value) => {
+                            let last = elements.keys().next_back();
+                            let pos = Position::between(last, None, dots.next());
+                            patch.elements.insert(pos, Node::build(&value, dots));
+                        }
+                        _ => return Err(PatchError::NotFound(pointer.to_string())),
+                    },
+                    None => return Err(PatchError::InvalidIndex(pointer.to_string())),
+                }
+            }
+            Some(Assigned::Object) | None => {
+                if matches!(op, Op::Remove) && kind.is_none() {
+                    return Err(PatchError::NotFound(pointer.to_string()));
+                }
+                if kind.is_none() {
+                    patch.assigned.insert(dots.next(), Assigned::Object);
+                }
+                let child = node.and_then(|node| node.fields.get(segment));
+                let child = Node::patch(child, rest, op, dots, removed, pointer)?;
+                patch.fields.insert(segment.clone(), child);
+            }
+            Some(Assigned::Scalar(_)) => {
+                return Err(PatchError::NotAContainer(pointer.to_string()))
+            }
+        }
+        Ok(patch)
+    }
+
+    fn find(&self, path: &[String]) -> Option<&Node> {
+        let Some((segment, rest)) = path.split_first() else {
+            return Some(self);
+        };
+        let child = match self.kind()? {
+            Assigned::Object => self.fields.get(segment)?,
+            Assigned::Array => self.elements.values().nth(segment.parse().ok()?)?,
+            Assigned::Scalar(_) => return None,
+        };
+        child.find(rest)
+    }
+}
+
+fn join_children<K: Ord + Clone>(
+    children: &BTreeMap<K, Node>,
+    ctx: &DotCtx,
+    other: &BTreeMap<K, Node>,
+    other_ctx: &DotCtx,
+) -> BTreeMap<K, Node> {
+    let empty = Node::default();
+    children
+        .keys()
+        .chain(other.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter_map(|key| {
+            let child = children.get(key).unwrap_or(&empty);
+            let other_child = other.get(key).unwrap_or(&empty);
+            let joined = child.join(ctx, other_child, other_ctx);
+            (!joined.is_empty()).then(|| (key.clone(), joined))
+        })
+        .collect()
+}
+
+/// Splits a JSON pointer (RFC 6901) into its unescaped segments
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, PatchError> {
+    if pointer.is_empty() {
+        return Ok(vec![]);
+    }
+    let Some(rest) = pointer.strip_prefix('/') else {
+        return Err(PatchError::InvalidPointer(pointer.to_string()));
+    };
+    Ok(rest
+        .split('/')
+        .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+impl JsonDelta {
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut ret = self.clone();
+        ret.merge_in_place(other);
+        ret
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        self.root = self.root.join(&self.ctx, &other.root, &other.ctx);
+        self.ctx.merge_in_place(&other.ctx);
+    }
+}
+
+impl Json {
+    /// Document holding `value`, every node of it assigned by `replica`
+    pub fn from_json(replica: ReplicaId, value: &JsonValue) -> Self {
+        let mut json = Self::default();
+        json.set(replica, value.clone());
+        json
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        self.root.to_json()
+    }
+
+    /// Value at the JSON `pointer`, `None` if there is nothing there
+    pub fn get(&self, pointer: &str) -> Option<JsonValue> {
+        let path = parse_pointer(pointer).ok()?;
+        self.root
+            .find(&path)
+            .filter(|node| !node.is_empty())
+            .map(Node::to_json)
+    }
+
+    /// Replaces the whole document
+    pub fn set(&mut self, replica: ReplicaId, value: JsonValue) {
+        self.apply_patch(replica, "", value)
+            .expect("the root can always be replaced")
+    }
+
+    /// Sets the value at the JSON `pointer`, replacing whatever was there.
+    ///
+    /// Objects on the way that don't exist yet are created. Inside of an array the last segment
+    /// may be the length of the array or `-` to append.
+    pub fn apply_patch(
+        &mut self,
+        replica: ReplicaId,
+        pointer: &str,
+        value: JsonValue,
+    ) -> Result<(), PatchError> {
+        self.edit(replica, pointer, Op::Set(value))
+    }
+
+    /// Inserts `value` at `index` of the array at the JSON `pointer`, shifting everything after it
+    pub fn insert(
+        &mut self,
+        replica: ReplicaId,
+        pointer: &str,
+        index: usize,
+        value: JsonValue,
+    ) -> Result<(), PatchError> {
+        self.edit(replica, pointer, Op::Insert(index, value))
+    }
+
+    /// Removes the object field or array element at the JSON `pointer`
+    pub fn remove(&mut self, pointer: &str) -> Result<(), PatchError> {
+        // Removing never hands out dots, the replica doesn't matter
+        self.edit(ReplicaId(0), pointer, Op::Remove)
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut ret = self.clone();
+        ret.merge_in_place(other);
+        ret
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        match (&mut self.delta, &other.delta) {
+            (Some(a), Some(b)) => a.merge_in_place(b),
+            (None, Some(b)) => self.delta = Some(b.clone()),
+            (_, None) => (),
+        }
+        self.root = self.root.join(&self.ctx, &other.root, &other.ctx);
+        self.ctx.merge_in_place(&other.ctx);
+    }
+
+    pub fn merge_delta(&mut self, delta: &JsonDelta) {
+        self.root = self.root.join(&self.ctx, &delta.root, &delta.ctx);
+        self.ctx.merge_in_place(&delta.ctx);
+    }
+
+    pub fn split_mut(&mut self) -> Option<JsonDelta> {
+        self.delta.take()
+    }
+
+    fn edit(&mut self, replica: ReplicaId, pointer: &str, op: Op) -> Result<(), PatchError> {
+        let path = parse_pointer(pointer)?;
+        let mut dots = Dots {
+            replica,
+            next: self.ctx.clock.get(&replica).copied().unwrap_or(0) + 1,
+            used: vec![],
+        };
+        let mut removed = vec![];
+        let root = Node::patch(
+            Some(&self.root),
+            &path,
+            op,
+            &mut dots,
+            &mut removed,
+            pointer,
+        )?;
+
+        let mut ctx = DotCtx::default();
+        for dot in removed.into_iter().chain(dots.used) {
+            ctx.add(dot);
+        }
+        ctx.compact();
+        let delta = JsonDelta { root, ctx };
+
+        self.merge_delta(&delta);
+        match &mut self.delta {
+            Some(buffer) => buffer.merge_in_place(&delta),
+            None => self.delta = Some(delta),
+        }
+        Ok(())
+    }
+}
+
+mod elements {
+    use alloc::collections::BTreeMap;
+    use alloc::vec::Vec;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{Node, Position};
+
+    pub fn serialize<S: Serializer>(
+        elements: &BTreeMap<Position, Node>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(elements.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Position, Node>, D::Error> {
+        Ok(Vec::<(Position, Node)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use crate::ReplicaId;
+
+    use super::{Json, PatchError};
+
+    const A: ReplicaId = ReplicaId(1);
+    const B: ReplicaId = ReplicaId(2);
+
+    fn sync(from: &mut Json, to: &mut Json) {
+        if let Some(delta) = from.split_mut() {
+            to.merge_delta(&delta);
+        }
+    }
+
+    #[test]
+    fn apply_patch_and_to_json() {
+        let mut doc = Json::default();
+        doc.apply_patch(A, "/title", json!("groceries")).unwrap();
+        doc.apply_patch(A, "/items", json!(["milk", "eggs"]))
+            .unwrap();
+        doc.apply_patch(A, "/items/-", json!("bread")).unwrap();
+        doc.apply_patch(A, "/items/0", json!({"name": "oat milk"}))
+            .unwrap();
+        doc.insert(A, "/items", 1, json!(null)).unwrap();
+        doc.apply_patch(A, "/meta/owner/name", json!("zack"))
+            .unwrap();
+        doc.remove("/items/2").unwrap();
+
+        assert_eq!(
+            doc.to_json(),
+            json!({
+                "title": "groceries",
+                "items": [{"name": "oat milk"}, null, "bread"],
+                "meta": {"owner": {"name": "zack"}},
+            })
+        );
+        assert_eq!(doc.get("/items/0/name"), Some(json!("oat milk")));
+        assert_eq!(doc.get("/items/3"), None);
+    }
+
+    #[test]
+    fn invalid_patches() {
+        let mut doc = Json::from_json(A, &json!({"a": 1, "b": [1]}));
+
+        assert!(matches!(
+            doc.apply_patch(A, "a", json!(1)),
+            Err(PatchError::InvalidPointer(_))
+        ));
+        assert!(matches!(
+            doc.apply_patch(A, "/a/x", json!(1)),
+            Err(PatchError::NotAContainer(_))
+        ));
+        assert!(matches!(
+            doc.apply_patch(A, "/b/5", json!(1)),
+            Err(PatchError::InvalidIndex(_))
+        ));
+        assert!(matches!(doc.remove("/c"), Err(PatchError::NotFound(_))));
+        assert_eq!(doc.to_json(), json!({"a": 1, "b": [1]}));
+    }
+
+    #[test]
+    fn concurrent_edits() {
+        let mut a = Json::from_json(A, &json!({"list": [1, 2], "obj": {"x": 1}}));
+        let mut b = Json::default();
+        sync(&mut a, &mut b);
+
+        a.insert(A, "/list", 1, json!("a")).unwrap();
+        b.insert(B, "/list", 1, json!("b")).unwrap();
+        // Edits inside of a concurrently removed object are kept
+        a.remove("/obj").unwrap();
+        b.apply_patch(B, "/obj/y", json!(2)).unwrap();
+
+        sync(&mut a, &mut b);
+        sync(&mut b, &mut a);
+
+        assert_eq!(a.to_json(), b.to_json());
+        assert_eq!(a.get("/obj"), Some(json!({"y": 2})));
+        assert_eq!(a.get("/list").unwrap().as_array().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn concurrent_kinds() {
+        let mut a = Json::default();
+        let mut b = Json::default();
+        a.apply_patch(A, "/k", json!({"x": 1})).unwrap();
+        b.apply_patch(B, "/k", json!("scalar")).unwrap();
+
+        let ab = a.merge(&b);
+        let ba = b.merge(&a);
+        assert_eq!(ab.to_json(), ba.to_json());
+        // B's dot is the greatest
+        assert_eq!(ab.get("/k"), Some(json!("scalar")));
+
+        // Overwriting the key observes both assignments
+        let mut ab = ab;
+        ab.apply_patch(A, "/k", json!([])).unwrap();
+        assert_eq!(ab.merge(&b).get("/k"), Some(json!([])));
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let mut doc = Json::from_json(A, &json!({"a": [1, {"b": true}], "c": "d"}));
+        let delta = doc.split_mut().unwrap();
+
+        let encoded = serde_json::to_string(&delta).unwrap();
+        let decoded = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(delta, decoded);
+
+        let mut other = Json::default();
+        other.merge_delta(&decoded);
+        assert_eq!(other.to_json(), doc.to_json());
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Set(String, i64),
+        Insert(usize, i64),
+        Remove(String),
+        RemoveAt(usize),
+        Sync(usize),
+    }
+
+    fn op_strategy() -> impl Strategy<Value = (usize, Op)> {
+        let key = prop::sample::select(vec!["/a", "/b", "/o/x", "/o/y"]).prop_map(String::from);
+        (
+            0..3usize,
+            prop_oneof![
+                (key.clone(), any::<i64>()).prop_map(|(k, v)| Op::Set(k, v)),
+                (0..4usize, any::<i64>()).prop_map(|(i, v)| Op::Insert(i, v)),
+                key.prop_map(Op::Remove),
+                (0..4usize).prop_map(Op::RemoveAt),
+                (0..3usize).prop_map(Op::Sync),
+            ],
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn converges(ops in prop::collection::vec(op_strategy(), 0..40)) {
+            let mut replicas = vec![Json::default(), Json::default(), Json::default()];
+            let mut full = vec![Json::default(), Json::default(), Json::default()];
+
+            for (i, op) in ops {
+                let replica = ReplicaId(i as u128 + 1);
+                let doc = &mut replicas[i];
+                // Errors are expected for ops on missing paths and leave the doc untouched
+                let _ = match op {
+                    Op::Set(key, v) => doc.apply_patch(replica, &key, json!(v)),
+                    Op::Insert(idx, v) => doc.insert(replica, "/l", idx, json!(v)),
+                    Op::Remove(key) => doc.remove(&key),
+                    Op::RemoveAt(idx) => doc.remove(&format!("/l/{idx}")),
+                    Op::Sync(to) => {
+                        if let Some(delta) = doc.split_mut() {
+                            full[i].merge_delta(&delta);
+                            replicas[to].merge_delta(&delta);
+                        }
+                        Ok(())
+                    }
+                };
+            }
+
+            for (i, doc) in replicas.iter_mut().enumerate() {
+                if let Some(delta) = doc.split_mut() {
+                    full[i].merge_delta(&delta);
+                }
+            }
+            let merged = replicas.iter().fold(Json::default(), |acc, doc| acc.merge(doc));
+            let reversed = replicas.iter().rev().fold(Json::default(), |acc, doc| acc.merge(doc));
+            prop_assert_eq!(merged.to_json(), reversed.to_json());
+
+            // Replaying every delta gives the same document as merging the states
+            let mut from_deltas = Json::default();
+            for doc in full.iter() {
+                from_deltas.merge_in_place(doc);
+            }
+            prop_assert_eq!(from_deltas.to_json(), merged.to_json());
+            prop_assert_eq!(merged.merge(&merged).to_json(), merged.to_json());
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Last-writer-wins register and map ordered by [hybrid logical clock](crate::hlc) timestamps.
+//!
+//! Timestamps are totally ordered so every replica picks the same winner. Before writing over a
+//! value the writer's clock is moved past that value's timestamp, so a write always wins over
+//! everything its writer had seen, even when the writer's wall clock is behind.
+use alloc::collections::BTreeMap;
+
+use crate::hlc::{Clock, Hlc, HlcClock};
+use crate::Value;
+
+/// A value along with the timestamp it was written at
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::lww")
+)]
+pub struct Timestamped<V: Value> {
+    pub timestamp: Hlc,
+    pub value: V,
+}
+
+impl<V: Value + Clone> Timestamped<V> {
+    /// Keeps whichever of the two was written last
+    fn merge_in_place(&mut self, other: &Self) {
+        if other.timestamp > self.timestamp {
+            *self = other.clone();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::lww")
+)]
+pub struct HlcLwwRegister<V: Value> {
+    entry: Option<Timestamped<V>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delta: Option<Timestamped<V>>,
+}
+
+impl<V: Value> Default for HlcLwwRegister<V> {
+    fn default() -> Self {
+        Self {
+            entry: None,
+            delta: None,
+        }
+    }
+}
+
+impl<V: Value + Clone> HlcLwwRegister<V> {
+    pub fn value(&self) -> Option<&V> {
+        self.entry.as_ref().map(|entry| &entry.value)
+    }
+
+    pub fn timestamp(&self) -> Option<Hlc> {
+        self.entry.as_ref().map(|entry| entry.timestamp)
+    }
+
+    pub fn set<C: Clock>(&mut self, clock: &mut HlcClock<C>, value: V) {
+        let timestamp = match &self.entry {
+            Some(entry) => clock.update(&entry.timestamp),
+            None => clock.now(),
+        };
+        let entry = Timestamped { timestamp, value };
+        self.delta = Some(entry.clone());
+        self.entry = Some(entry);
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut ret = self.clone();
+        ret.merge_in_place(other);
+        ret
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        if let Some(entry) = &other.entry {
+            self.merge_delta(entry);
+        }
+        if let Some(other_delta) = &other.delta {
+            match &mut self.delta {
+                Some(delta) => delta.merge_in_place(other_delta),
+                None => self.delta = Some(other_delta.clone()),
+            }
+        }
+    }
+
+    pub fn merge_delta(&mut self, delta: &Timestamped<V>) {
+        match &mut self.entry {
+            Some(entry) => entry.merge_in_place(delta),
+            None => self.entry = Some(delta.clone()),
+        }
+    }
+
+    pub fn split_mut(&mut self) -> Option<Timestamped<V>> {
+        self.delta.take()
+    }
+}
+
+pub type Deltas<K, V> = BTreeMap<K, Timestamped<Option<V>>>;
+
+/// Map where each key is a [`HlcLwwRegister`]. Removing a key writes a tombstone so the removal
+/// is ordered like any other write, tombstones are kept forever.
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::lww")
+)]
+pub struct HlcLwwMap<K: Ord + Value, V: Value> {
+    entries: BTreeMap<K, Timestamped<Option<V>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delta: Option<BTreeMap<K, Timestamped<Option<V>>>>,
+}
+
+impl<K: Ord + Value, V: Value> Default for HlcLwwMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: Default::default(),
+            delta: None,
+        }
+    }
+}
+
+impl<K, V> HlcLwwMap<K, V>
+where
+    K: Ord + Clone + Value,
+    V: Clone + Value,
+{
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)?.value.as_ref()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, entry)| Some((key, entry.value.as_ref()?)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn insert<C: Clock>(&mut self, clock: &mut HlcClock<C>, key: K, value: V) {
+        self.write(clock, key, Some(value))
+    }
+
+    pub fn remove<C: Clock>(&mut self, clock: &mut HlcClock<C>, key: K) {
+        if self.get(&key).is_some() {
+            self.write(clock, key, None)
+        }
+    }
+
+    fn write<C: Clock>(&mut self, clock: &mut HlcClock<C>, key: K, value: Option<V>) {
+        let timestamp = match self.entries.get(&key) {
+            Some(entry) => clock.update(&entry.timestamp),
+            None => clock.now(),
+        };
+        let entry = Timestamped { timestamp, value };
+        self.delta
+            .get_or_insert_with(Default::default)
+            .insert(key.clone(), entry.clone());
+        self.entries.insert(key, entry);
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut ret = self.clone();
+        ret.merge_in_place(other);
+        ret
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        Self::merge_entries(&mut self.entries, &other.entries);
+        if let Some(other_delta) = &other.delta {
+            Self::merge_entries(self.delta.get_or_insert_with(Default::default), other_delta);
+        }
+    }
+
+    pub fn merge_delta(&mut self, delta: &Deltas<K, V>) {
+        Self::merge_entries(&mut self.entries, delta)
+    }
+
+    fn merge_entries(entries: &mut Deltas<K, V>, other: &Deltas<K, V>) {
+        for (key, entry) in other {
+            match entries.get_mut(key) {
+                Some(ours) => ours.merge_in_place(entry),
+                None => {
+                    entries.insert(key.clone(), entry.clone());
+                }
+            }
+        }
+    }
+
+    pub fn split_mut(&mut self) -> Option<Deltas<K, V>> {
+        self.delta.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use crate::hlc::{Hlc, HlcClock, ManualClock};
+    use crate::ReplicaId;
+
+    use super::{HlcLwwMap, HlcLwwRegister, Timestamped};
+
+    const A: ReplicaId = ReplicaId(1);
+    const B: ReplicaId = ReplicaId(2);
+
+    fn manual_clock(replica: ReplicaId) -> (HlcClock<ManualClock>, ManualClock) {
+        let time = ManualClock::default();
+        (HlcClock::with_clock(replica, time.clone()), time)
+    }
+
+    #[test]
+    fn skewed_clock_still_overwrites() {
+        let (mut a_clock, a_time) = manual_clock(A);
+        let (mut b_clock, b_time) = manual_clock(B);
+        a_time.set(10_000);
+        b_time.set(10);
+
+        let mut a = HlcLwwRegister::default();
+        a.set(&mut a_clock, "a".to_string());
+        let mut b = HlcLwwRegister::default();
+        b.merge_delta(&a.split_mut().unwrap());
+
+        // B's wall clock is way behind, yet its write happened after it saw A's
+        b.set(&mut b_clock, "b".to_string());
+        a.merge_delta(&b.split_mut().unwrap());
+
+        assert_eq!(a.value(), Some(&"b".to_string()));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn map_insert_remove() {
+        let (mut a_clock, a_time) = manual_clock(A);
+        let (mut b_clock, _) = manual_clock(B);
+        a_time.set(100);
+
+        let mut a = HlcLwwMap::<u32, u32>::default();
+        a.insert(&mut a_clock, 1, 10);
+        a.insert(&mut a_clock, 2, 20);
+        let mut b = HlcLwwMap::default();
+        b.merge_delta(&a.split_mut().unwrap());
+
+        b.remove(&mut b_clock, 1);
+        b.insert(&mut b_clock, 2, 21);
+        // Concurrent with B's removal, but A's clock is ahead
+        a.insert(&mut a_clock, 1, 11);
+
+        let a_delta = a.split_mut().unwrap();
+        a.merge_delta(&b.split_mut().unwrap());
+        b.merge_delta(&a_delta);
+
+        assert_eq!(a, b);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![(&1, &11), (&2, &21)]);
+    }
+
+    fn map_strategy() -> impl Strategy<Value = HlcLwwMap<u8, u8>> {
+        let timestamp = (0..5u64, 0..3u32, 0..3u64).prop_map(|(physical, logical, replica)| Hlc {
+            physical,
+            logical,
+            replica: replica.into(),
+        });
+        proptest::collection::btree_map(any::<u8>(), timestamp, 0..20).prop_map(|entries| {
+            // A timestamp is only ever used for a single write
+            let entries = entries
+                .into_iter()
+                .map(|(key, timestamp)| {
+                    let seed = key as u64 + timestamp.physical * 7 + timestamp.logical as u64;
+                    let value = (timestamp.logical > 0).then_some(seed as u8);
+                    (key, Timestamped { timestamp, value })
+                })
+                .collect();
+            HlcLwwMap {
+                entries,
+                delta: None,
+            }
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn commutativity(a in map_strategy(), b in map_strategy()) {
+            assert_eq!(a.merge(&b), b.merge(&a));
+        }
+
+        #[test]
+        fn associativity(a in map_strategy(), b in map_strategy(), c in map_strategy()) {
+            assert_eq!(a.merge(&b).merge(&c), a.merge(&b.merge(&c)));
+        }
+
+        #[test]
+        fn idempotency(a in map_strategy()) {
+            assert_eq!(a.merge(&a), a);
+        }
+    }
+}
@@ -1,16 +1,25 @@
-use std::collections::BTreeSet;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
 
+use crate::memory::{MemoryUsage, Usage};
 use crate::{ReplicaId, Value};
 
-use super::dot::DotKernel;
+use super::dot::{compact_delta_buffer, DeltaPolicy, DotKernel, VectorClock};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::mvreg")
+)]
 pub struct MVReg<V: Clone + Value> {
     pub(crate) core: DotKernel<V>,
+    // A plain `default` would have serde ask for `V: Default`
+    #[serde(default = "Option::default", skip_serializing_if = "Option::is_none")]
     pub(crate) delta: Option<DotKernel<V>>,
 }
 
-impl<V: Clone + Default + PartialEq + Value> Default for MVReg<V> {
+impl<V: Clone + PartialEq + Value> Default for MVReg<V> {
     fn default() -> Self {
         Self {
             core: Default::default(),
@@ -19,14 +28,22 @@ impl<V: Clone + Default + PartialEq + Value> Default for MVReg<V> {
     }
 }
 
-impl<V: Clone + std::fmt::Debug + PartialEq + Ord + Default + Value> MVReg<V> {
+impl<V: Clone + Value> MemoryUsage for MVReg<V> {
+    fn memory_usage(&self) -> Usage {
+        self.core.memory_usage() + self.delta.memory_usage()
+    }
+}
+
+impl<V: Clone + PartialEq + Ord + Value> MVReg<V> {
     pub fn value(&self) -> BTreeSet<&V> {
         self.core.values().collect()
     }
 
     pub fn set(&mut self, replica: ReplicaId, value: V) {
-        let delta = self.delta.get_or_insert_default();
-        self.core.remove_all();
+        let delta = self.delta.get_or_insert_with(Default::default);
+        // Through the delta too, or peers would keep the values this one overwrote
+        let dots = self.core.entries.keys().copied().collect::<Vec<_>>();
+        self.core.remove_dots(dots, delta);
         self.core.add(replica, value, delta);
     }
 
@@ -53,6 +70,21 @@ impl<V: Clone + std::fmt::Debug + PartialEq + Ord + Default + Value> MVReg<V> {
         self.delta = Some(delta);
     }
 
+    /// See [`AWORSet::compact_deltas`](super::aworset::AWORSet::compact_deltas)
+    pub fn compact_deltas(&mut self, acked: &VectorClock) {
+        compact_delta_buffer(&mut self.delta, acked, None);
+    }
+
+    /// See [`AWORSet::compact_deltas_with`](super::aworset::AWORSet::compact_deltas_with)
+    #[must_use]
+    pub fn compact_deltas_with(&mut self, acked: &VectorClock, policy: DeltaPolicy) -> bool {
+        compact_delta_buffer(&mut self.delta, acked, Some(policy))
+    }
+
+    pub fn split_mut(&mut self) -> Option<DotKernel<V>> {
+        self.delta.take()
+    }
+
     pub fn split(&self) -> (Self, Option<DotKernel<V>>) {
         (
             Self {
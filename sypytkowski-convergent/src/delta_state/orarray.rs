@@ -0,0 +1,644 @@
+//! Causal observed remove array.
+//!
+//! Elements aren't identified by their index, which shifts on every insert before them, but by a
+//! position identifier they get when inserted and keep forever. Positions are dense (Logoot/LSEQ style): between any two of them there
+//! is always room for another one, so concurrent inserts never have to move existing elements.
+//!
+//! A position is a path of [`Ident`]s compared lexicographically, where each ident is a digit plus
+//! the dot of the insert that created it. The dot makes positions generated concurrently by
+//! different replicas unique, and the last ident of every position is the one freshly created for
+//! it.
+//!
+//! The elements live in a [`DotKernel`] like the other delta CRDTs, so removes are observed
+//! removes and deltas are just kernels. Updating an element replaces the dots held at its position,
+//! concurrent updates of the same element are all kept until one observes the others, the one with
+//! the greatest dot is the one that is read.
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::{vec, vec::Vec};
+
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize};
+
+use crate::{ReplicaId, Value};
+
+use super::dot::{compact_delta_buffer, DeltaPolicy, Dot, DotKernel, DotRange, VectorClock};
+
+/// Digits at each level of a position are in `1..BASE`, 0 is reserved for [`Ident::MIN`]
+const BASE: u64 = 1 << 32;
+/// Largest gap left after a freshly generated digit, keeps appends from exhausting a level
+/// quickly while still leaving room for inserts in between
+const BOUNDARY: u64 = 1 << 10;
+
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde_derive::Serialize,
+    serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::orarray")
+)]
+pub struct Ident {
+    pub digit: u64,
+    pub dot: Dot,
+}
+
+impl Ident {
+    /// Stands in for the missing levels of a position when generating a new one. Generated
+    /// digits are always at least 1 so this sorts before every real ident.
+    const MIN: Ident = Ident {
+        digit: 0,
+        dot: Dot(ReplicaId(0), 0),
+    };
+}
+
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+    serde_derive::Serialize,
+    serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::orarray")
+)]
+pub struct Position(pub Vec<Ident>);
+
+impl Position {
+    /// Generates a position strictly between `left` and `right`, `None` meaning the start and the
+    /// end of the array respectively. The new position ends with an ident holding `dot`.
+    pub fn between(left: Option<&Position>, right: Option<&Position>, dot: Dot) -> Self {
+        let left = left.map_or(&[][..], |pos| pos.0.as_slice());
+        let mut right = right.map(|pos| pos.0.as_slice());
+        let mut path = vec![];
+
+        for level in 0.. {
+            let lo = left.get(level).copied().unwrap_or(Ident::MIN);
+            // `right` only bounds us while our path is still equal to its prefix. It can't run
+            // out before we are done since we'd be equal to it, and `left` is strictly smaller
+            let hi = right.map_or(BASE, |right| right[level].digit);
+
+            if hi - lo.digit > 1 {
+                let digit = lo.digit + (hi - lo.digit).min(BOUNDARY) / 2;
+                path.push(Ident { digit, dot });
+                break;
+            }
+
+            // No room at this level, follow `left` down a level
+            path.push(lo);
+            if right.is_some_and(|right| lo < right[level]) {
+                right = None;
+            }
+        }
+
+        Self(path)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::orarray")
+)]
+pub struct Elem<V: Value> {
+    pub pos: Position,
+    pub val: V,
+}
+
+impl<V: Value> Value for Elem<V> {}
+
+pub type Deltas<V> = DotKernel<Elem<V>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ORArray<V: Clone + Value> {
+    pub(crate) core: DotKernel<Elem<V>>,
+    pub(crate) delta: Option<DotKernel<Elem<V>>>,
+    /// Dots held at each position, derived from `core` to keep the elements in order
+    order: BTreeMap<Position, BTreeSet<Dot>>,
+}
+
+impl<V: Clone + PartialEq + Value> Default for ORArray<V> {
+    fn default() -> Self {
+        Self {
+            core: Default::default(),
+            delta: None,
+            order: Default::default(),
+        }
+    }
+}
+
+impl<V> ORArray<V>
+where
+    V: Clone + PartialEq + Value,
+{
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&V> {
+        let (_, dots) = self.order.iter().nth(index)?;
+        self.read(dots)
+    }
+
+    /// Position of the element at `index`, which stays the same wherever it ends up
+    pub fn position(&self, index: usize) -> Option<&Position> {
+        self.order.keys().nth(index)
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = &Position> + '_ {
+        self.order.keys()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &V> + '_ {
+        self.order.values().filter_map(|dots| self.read(dots))
+    }
+
+    pub fn values(&self) -> Vec<V> {
+        self.iter().cloned().collect()
+    }
+
+    /// Where the gap before `index` ends up once `delta` is merged, so a cursor sitting there can
+    /// follow remote edits. Has to be called before merging `delta`.
+    ///
+    /// The gap is anchored to the element on its left: elements inserted or removed before that
+    /// one shift it, anything concurrently inserted right at the gap ends up after it.
+    pub fn transform_index(&self, index: usize, delta: &Deltas<V>) -> usize {
+        let Some(anchor) = index
+            .min(self.len())
+            .checked_sub(1)
+            .and_then(|i| self.order.keys().nth(i))
+        else {
+            return 0;
+        };
+
+        // Positions that hold a dot we haven't seen yet once `delta` is merged
+        let added = delta
+            .entries
+            .iter()
+            .filter(|(dot, elem)| elem.pos <= *anchor && !self.core.ctx.contains(**dot))
+            .map(|(_, elem)| &elem.pos)
+            .collect::<BTreeSet<_>>();
+        let inserted = added
+            .iter()
+            .filter(|pos| !self.order.contains_key(**pos))
+            .count();
+        // Only gone once every dot held at the position is
+        let removed = self
+            .order
+            .range(..=anchor)
+            .filter(|(pos, dots)| {
+                !added.contains(pos)
+                    && dots
+                        .iter()
+                        .all(|dot| delta.ctx.contains(*dot) && !delta.entries.contains_key(dot))
+            })
+            .count();
+
+        index.min(self.len()) + inserted - removed
+    }
+
+    /// Inserts `value` so it ends up at `index`, shifting everything after it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`.
+    pub fn insert(&mut self, replica: ReplicaId, index: usize, value: V) {
+        assert!(
+            index <= self.len(),
+            "insertion index (is {index}) should be <= len (is {})",
+            self.len()
+        );
+        let left = index.checked_sub(1).and_then(|i| self.order.keys().nth(i));
+        let right = self.order.keys().nth(index);
+        // The dot `add` is about to hand out, so the position's last ident carries it
+        let dot = Dot(
+            replica,
+            self.core.ctx.clock.get(&replica).copied().unwrap_or(0) + 1,
+        );
+        let pos = Position::between(left, right, dot);
+
+        self.add(replica, pos, value);
+    }
+
+    pub fn push(&mut self, replica: ReplicaId, value: V) {
+        self.insert(replica, self.len(), value)
+    }
+
+    /// Replaces the value at `index`, the element keeps its position
+    pub fn update(&mut self, replica: ReplicaId, index: usize, value: V) {
+        let Some(pos) = self.order.keys().nth(index).cloned() else {
+            return;
+        };
+        self.remove_position(&pos);
+        self.add(replica, pos, value);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<V> {
+        let (pos, dots) = self.order.iter().nth(index)?;
+        let val = self.read(dots).cloned();
+        let pos = pos.clone();
+        self.remove_position(&pos);
+        val
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let delta = match (&self.delta, &other.delta) {
+            (Some(a), Some(b)) => Some(a.merge(b)),
+            (Some(a), None) => Some(a.clone()),
+            (None, Some(b)) => Some(b.clone()),
+            (None, None) => None,
+        };
+
+        Self::from_parts(self.core.merge(&other.core), delta)
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        match (&mut self.delta, &other.delta) {
+            (Some(a), Some(b)) => a.merge_in_place(b),
+            (None, Some(b)) => self.delta = Some(b.clone()),
+            (_, None) => (),
+        }
+        self.merge_kernel_in_place(&other.core);
+    }
+
+    pub fn merge_delta(&mut self, delta: Deltas<V>) {
+        self.merge_delta_in_place(&delta);
+    }
+
+    pub fn merge_delta_in_place(&mut self, delta: &Deltas<V>) {
+        match &mut self.delta {
+            Some(a) => a.merge_in_place(delta),
+            None => self.delta = Some(delta.clone()),
+        }
+        self.merge_kernel_in_place(delta);
+    }
+
+    /// See [`AWORSet::compact_deltas`](super::aworset::AWORSet::compact_deltas)
+    pub fn compact_deltas(&mut self, acked: &VectorClock) {
+        compact_delta_buffer(&mut self.delta, acked, None);
+    }
+
+    /// See [`AWORSet::compact_deltas_with`](super::aworset::AWORSet::compact_deltas_with)
+    #[must_use]
+    pub fn compact_deltas_with(&mut self, acked: &VectorClock, policy: DeltaPolicy) -> bool {
+        compact_delta_buffer(&mut self.delta, acked, Some(policy))
+    }
+
+    pub fn split_mut(&mut self) -> Option<Deltas<V>> {
+        self.delta.take()
+    }
+
+    pub fn split(mut self) -> (Self, Option<Deltas<V>>) {
+        let delta = self.delta.take();
+        (self, delta)
+    }
+
+    pub fn split_expect_deltas(self) -> (Self, Deltas<V>) {
+        let (array, delta) = self.split();
+        (array, delta.expect("Deltas should be defined."))
+    }
+
+    fn from_parts(core: DotKernel<Elem<V>>, delta: Option<DotKernel<Elem<V>>>) -> Self {
+        let mut order = BTreeMap::<Position, BTreeSet<Dot>>::new();
+        for (dot, elem) in core.entries.iter() {
+            order.entry(elem.pos.clone()).or_default().insert(*dot);
+        }
+        Self { core, delta, order }
+    }
+
+    fn read(&self, dots: &BTreeSet<Dot>) -> Option<&V> {
+        let dot = dots.iter().next_back()?;
+        self.core.entries.get(dot).map(|elem| &elem.val)
+    }
+
+    fn add(&mut self, replica: ReplicaId, pos: Position, val: V) {
+        let delta = self.delta.get_or_insert_with(Default::default);
+        let dot = self.core.add(
+            replica,
+            Elem {
+                pos: pos.clone(),
+                val,
+            },
+            delta,
+        );
+        self.order.entry(pos).or_default().insert(dot);
+    }
+
+    fn remove_position(&mut self, pos: &Position) {
+        if let Some(dots) = self.order.remove(pos) {
+            self.core
+                .remove_dots(dots, self.delta.get_or_insert_with(Default::default));
+        }
+    }
+
+    /// Merges `kernel` into the core and patches `order`, only the dots `kernel` knows about can
+    /// have been added or removed by merging it
+    fn merge_kernel_in_place(&mut self, kernel: &DotKernel<Elem<V>>) {
+        let touched = kernel
+            .ctx
+            .ranges()
+            .flat_map(|DotRange(id, range)| {
+                self.core
+                    .entries
+                    .range(Dot(id, range.start)..Dot(id, range.end))
+                    .map(|(dot, elem)| (*dot, elem.pos.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        self.core.merge_in_place(kernel);
+
+        let entries = &self.core.entries;
+        for (dot, pos) in touched {
+            if entries.contains_key(&dot) {
+                continue;
+            }
+            if let Some(dots) = self.order.get_mut(&pos) {
+                dots.remove(&dot);
+                if dots.is_empty() {
+                    self.order.remove(&pos);
+                }
+            }
+        }
+        for (dot, elem) in kernel.entries.iter() {
+            if entries.contains_key(dot) {
+                self.order.entry(elem.pos.clone()).or_default().insert(*dot);
+            }
+        }
+    }
+}
+
+impl<V> Serialize for ORArray<V>
+where
+    V: Clone + Value + Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut array = serializer.serialize_struct("ORArray", 2)?;
+        array.serialize_field("core", &self.core)?;
+        array.serialize_field("delta", &self.delta)?;
+        array.end()
+    }
+}
+
+impl<'de, V> Deserialize<'de> for ORArray<V>
+where
+    V: Clone + PartialEq + Value + Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        ORArrayDeserializer::<V>::deserialize(deserializer)
+            .map(|array| Self::from_parts(array.core, array.delta))
+    }
+}
+
+#[derive(serde_derive::Deserialize)]
+struct ORArrayDeserializer<V: Clone + Value> {
+    core: DotKernel<Elem<V>>,
+    // A plain `default` would have serde ask for `V: Default`
+    #[serde(default = "Option::default")]
+    delta: Option<DotKernel<Elem<V>>>,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ReplicaGenerator, ReplicaId};
+
+    use super::{Dot, ORArray, Position};
+
+    #[test]
+    fn positions_are_dense() {
+        let id = ReplicaId(0);
+        let mut counter = 0;
+        let mut dot = || {
+            counter += 1;
+            Dot(id, counter)
+        };
+
+        // Keep inserting right after the first element so every level runs out of room
+        let first = Position::between(None, None, dot());
+        let mut right = Position::between(Some(&first), None, dot());
+        for _ in 0..100 {
+            let pos = Position::between(Some(&first), Some(&right), dot());
+            assert!(
+                first < pos && pos < right,
+                "{first:?} < {pos:?} < {right:?}"
+            );
+            right = pos;
+        }
+
+        // Same thing at the front
+        let mut left = first.clone();
+        for _ in 0..100 {
+            let pos = Position::between(None, Some(&left), dot());
+            assert!(pos < left);
+            left = pos;
+        }
+    }
+
+    #[test]
+    fn insert_remove_update() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+
+        let mut a = ORArray::<u64>::default();
+        a.push(a_id, 1);
+        a.push(a_id, 3);
+        a.insert(a_id, 1, 2);
+        a.insert(a_id, 0, 0);
+        assert_eq!(a.values(), vec![0, 1, 2, 3]);
+
+        assert_eq!(a.remove(2), Some(2));
+        a.update(a_id, 0, 420);
+        assert_eq!(a.values(), vec![420, 1, 3]);
+        assert_eq!(a.get(2), Some(&3));
+        assert_eq!(a.get(3), None);
+    }
+
+    #[test]
+    fn concurrent_inserts_at_same_index() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = ORArray::<u64>::default();
+        a.push(a_id, 0);
+        a.push(a_id, 3);
+        let mut b = ORArray::<u64>::default();
+        b.merge_delta(a.split_mut().unwrap());
+
+        a.insert(a_id, 1, 1);
+        b.insert(b_id, 1, 2);
+        let a_deltas = a.split_mut().unwrap();
+        a.merge_delta(b.split_mut().unwrap());
+        b.merge_delta(a_deltas);
+
+        assert_eq!(a.values(), b.values());
+        assert_eq!(a.len(), 4);
+        assert_eq!(a.get(0), Some(&0));
+        assert_eq!(a.get(3), Some(&3));
+    }
+
+    #[test]
+    fn concurrent_update_and_remove() {
+        let mut gen = ReplicaGenerator::new();
+        let a_id = gen.gen();
+        let b_id = gen.gen();
+
+        let mut a = ORArray::<u64>::default();
+        a.push(a_id, 0);
+        let mut b = ORArray::<u64>::default();
+        b.merge_delta(a.split_mut().unwrap());
+
+        a.remove(0);
+        b.update(b_id, 0, 1);
+        let a_deltas = a.split_mut().unwrap();
+        a.merge_delta(b.split_mut().unwrap());
+        b.merge_delta(a_deltas);
+
+        // The update wasn't observed by the remove so it wins
+        assert_eq!(a.values(), vec![1]);
+        assert_eq!(b.values(), vec![1]);
+    }
+
+    mod properties {
+        use proptest::prelude::*;
+
+        use crate::{delta_state::orarray::ORArray, ReplicaId};
+
+        #[derive(Debug, Clone)]
+        enum Op {
+            Insert(usize, u16),
+            Update(usize, u16),
+            Remove(usize),
+            /// Send the accumulated delta to another replica
+            Sync(usize),
+        }
+
+        fn op_strategy() -> impl Strategy<Value = (usize, Op)> {
+            let op = prop_oneof![
+                4 => (any::<usize>(), any::<u16>()).prop_map(|(i, v)| Op::Insert(i, v)),
+                1 => (any::<usize>(), any::<u16>()).prop_map(|(i, v)| Op::Update(i, v)),
+                2 => any::<usize>().prop_map(Op::Remove),
+                2 => (0..3usize).prop_map(Op::Sync),
+            ];
+            (0..3usize, op)
+        }
+
+        /// Runs `ops` over 3 replicas, each of them keeping the delta of everything it has seen
+        fn run(ops: &[(usize, Op)]) -> Vec<ORArray<u16>> {
+            let mut replicas = vec![ORArray::<u16>::default(); 3];
+            for (r, op) in ops {
+                let id = ReplicaId(*r as u128);
+                let replica = &mut replicas[*r];
+                match *op {
+                    Op::Insert(i, v) => {
+                        let i = i % (replica.len() + 1);
+                        replica.insert(id, i, v);
+                        assert_eq!(replica.get(i), Some(&v));
+                    }
+                    Op::Update(i, v) if !replica.is_empty() => {
+                        let i = i % replica.len();
+                        replica.update(id, i, v);
+                        assert_eq!(replica.get(i), Some(&v));
+                    }
+                    Op::Remove(i) if !replica.is_empty() => {
+                        let len = replica.len();
+                        replica.remove(i % len);
+                        assert_eq!(replica.len(), len - 1);
+                    }
+                    Op::Sync(to) => {
+                        if let Some(delta) = replica.delta.clone() {
+                            replicas[to].merge_delta(delta);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            replicas
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig{ ..Default::default()})]
+
+            #[test]
+            fn converges(ops in proptest::collection::vec(op_strategy(), 0..100)) {
+                let replicas = run(&ops);
+
+                // Deltas are never split off so each one holds everything its replica has seen
+                let deltas = replicas.iter().filter_map(|r| r.delta.clone()).collect::<Vec<_>>();
+                let mut synced = replicas.clone();
+                for replica in synced.iter_mut() {
+                    for delta in deltas.iter() {
+                        replica.merge_delta_in_place(delta);
+                    }
+                }
+
+                let merged = replicas[0].merge(&replicas[1]).merge(&replicas[2]);
+                for replica in synced.iter() {
+                    assert_eq!(replica.values(), merged.values());
+                    assert_eq!(replica.core, merged.core);
+                    assert_eq!(replica.order, merged.order);
+                }
+            }
+
+            #[test]
+            fn merge_properties(ops in proptest::collection::vec(op_strategy(), 0..100)) {
+                let replicas = run(&ops);
+                let (a, b, c) = (&replicas[0], &replicas[1], &replicas[2]);
+
+                assert_eq!(a.merge(b), b.merge(a));
+                assert_eq!(a.merge(b).merge(c), a.merge(&b.merge(c)));
+                assert_eq!(a.merge(a), *a);
+
+                let mut ab = a.clone();
+                ab.merge_in_place(b);
+                assert_eq!(ab, a.merge(b));
+            }
+
+            #[test]
+            fn transform_index(ops in proptest::collection::vec(op_strategy(), 0..100)) {
+                let replicas = run(&ops);
+                let (a, b) = (&replicas[0], &replicas[1]);
+                let Some(delta) = b.delta.as_ref() else {
+                    return Ok(());
+                };
+
+                let mut merged = a.clone();
+                merged.merge_delta_in_place(delta);
+                for index in 0..=a.len() {
+                    // Everything up to the element left of the gap stays on the left of it
+                    let expected = match index.checked_sub(1) {
+                        Some(i) => {
+                            let anchor = a.order.keys().nth(i).unwrap();
+                            merged.order.range(..=anchor).count()
+                        }
+                        None => 0,
+                    };
+                    assert_eq!(a.transform_index(index, delta), expected);
+                }
+            }
+        }
+    }
+}
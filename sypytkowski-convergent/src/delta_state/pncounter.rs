@@ -1,18 +1,26 @@
+use crate::memory::{MemoryUsage, Usage};
 use crate::ReplicaId;
 
 use super::gcounter::GCounter;
 
 type Deltas = PNCounter;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::pncounter")
+)]
 pub struct PNCounter {
     inc: GCounter,
     dec: GCounter,
 }
 
 impl PNCounter {
+    /// Clamped to what fits in an `i64`
     pub fn value(&self) -> i64 {
-        self.inc.value() - self.dec.value()
+        let value = self.inc.value() as i128 - self.dec.value() as i128;
+        value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
     }
 
     pub fn increment(&mut self, replica: ReplicaId) {
@@ -30,9 +38,18 @@ impl PNCounter {
         }
     }
 
+    pub fn merge_in_place(&mut self, other: &Self) {
+        self.inc.merge_in_place(&other.inc);
+        self.dec.merge_in_place(&other.dec);
+    }
+
+    pub fn merge_delta_in_place(&mut self, delta: &Deltas) {
+        self.merge_in_place(delta)
+    }
+
     pub fn split(&self) -> (Self, Option<Deltas>) {
         let (inc, inc_deltas) = self.inc.split();
-        let (dec, dec_deltas) = self.inc.split();
+        let (dec, dec_deltas) = self.dec.split();
         let deltas = match (inc_deltas, dec_deltas) {
             (None, None) => None,
             (a, b) => Some(PNCounter {
@@ -44,6 +61,16 @@ impl PNCounter {
         (Self { inc, dec }, deltas)
     }
 
+    pub fn split_mut(&mut self) -> Option<Deltas> {
+        match (self.inc.split_mut(), self.dec.split_mut()) {
+            (None, None) => None,
+            (a, b) => Some(PNCounter {
+                inc: *a.unwrap_or_default(),
+                dec: *b.unwrap_or_default(),
+            }),
+        }
+    }
+
     pub fn split_expect(&self) -> (Self, Deltas) {
         let (counter, deltas) = self.split();
         (counter, deltas.expect("Expected deltas."))
@@ -63,6 +90,12 @@ impl Default for PNCounter {
     }
 }
 
+impl MemoryUsage for PNCounter {
+    fn memory_usage(&self) -> Usage {
+        self.inc.memory_usage() + self.dec.memory_usage()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use proptest::prelude::*;
@@ -102,6 +135,14 @@ mod test {
         fn idempotency(a in pncounter_strategy()) {
             assert_eq!(a, a.merge(&a))
         }
+
+        #[test]
+        fn merge_in_place(a in pncounter_strategy(), b in pncounter_strategy()) {
+            let mut ab = a.clone();
+            ab.merge_in_place(&b);
+
+            assert_eq!(a.merge(&b), ab)
+        }
     }
 
     mod deltas {
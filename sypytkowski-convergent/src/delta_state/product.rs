@@ -0,0 +1,193 @@
+//! Putting CRDTs side by side: merging each part on its own makes the whole a CRDT too.
+//!
+//! Tuples of [`Convergent`] types are [`Convergent`], [`Product`] is a pair that can also be a
+//! [`Value`] of the maps, and [`convergent_record!`](crate::convergent_record) declares a struct
+//! merged field by field. Values of a map made of them merge per field instead of one write
+//! replacing the whole value, say the position of a shape as last-writer-wins registers and its
+//! size growing with the largest one seen.
+use crate::Value;
+
+use super::convergent::Convergent;
+
+/// Two CRDTs merged side by side
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+    serde_derive::Serialize,
+    serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::product")
+)]
+pub struct Product<A, B> {
+    pub first: A,
+    pub second: B,
+}
+
+impl<A, B> Product<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+impl<A: Value, B: Value> Value for Product<A, B> {}
+
+impl<A: Convergent, B: Convergent> Convergent for Product<A, B> {
+    fn merge(&self, other: &Self) -> Self {
+        Self {
+            first: self.first.merge(&other.first),
+            second: self.second.merge(&other.second),
+        }
+    }
+}
+
+macro_rules! impl_convergent_tuple {
+    ($(($($t:ident $i:tt),+)),*) => ($(
+        impl<$($t: Convergent),+> Convergent for ($($t,)+) {
+            fn merge(&self, other: &Self) -> Self {
+                ($(self.$i.merge(&other.$i),)+)
+            }
+        }
+    )*)
+}
+
+impl_convergent_tuple!(
+    (A 0),
+    (A 0, B 1),
+    (A 0, B 1, C 2),
+    (A 0, B 1, C 2, D 3),
+    (A 0, B 1, C 2, D 3, E 4),
+    (A 0, B 1, C 2, D 3, E 4, F 5)
+);
+
+/// Declares a struct of CRDTs and implements [`Convergent`] for it by merging each field, for
+/// builds without the `derive` feature. Generic structs need the derive.
+///
+/// ```
+/// use sypytkowski_convergent::convergent_record;
+/// use sypytkowski_convergent::delta_state::convergent::Convergent;
+///
+/// convergent_record! {
+///     #[derive(Debug, Clone, PartialEq)]
+///     pub struct Size {
+///         pub width: f32,
+///         pub height: f32,
+///     }
+/// }
+///
+/// let a = Size { width: 2.0, height: 1.0 };
+/// let b = Size { width: 1.0, height: 3.0 };
+/// assert_eq!(a.merge(&b), Size { width: 2.0, height: 3.0 });
+/// ```
+#[macro_export]
+macro_rules! convergent_record {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field_vis:vis $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $ty,)*
+        }
+
+        impl $crate::delta_state::convergent::Convergent for $name {
+            fn merge(&self, other: &Self) -> Self {
+                Self {
+                    $($field: $crate::delta_state::convergent::Convergent::merge(
+                        &self.$field,
+                        &other.$field,
+                    ),)*
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use crate::delta_state::convergent::Convergent;
+    use crate::delta_state::lww::HlcLwwRegister;
+    use crate::hlc::{HlcClock, ManualClock};
+    use crate::state::awormap::AWORMap;
+    use crate::state::monotonic::MaxReg;
+    use crate::ReplicaId;
+
+    use super::Product;
+
+    const A: ReplicaId = ReplicaId(1);
+    const B: ReplicaId = ReplicaId(2);
+
+    convergent_record! {
+        /// A square whose position and size merge apart
+        #[derive(Debug, Clone, PartialEq, Default)]
+        struct Square {
+            x: HlcLwwRegister<f32>,
+            y: HlcLwwRegister<f32>,
+            /// Grows to the largest size seen
+            width: f32,
+            height: f32,
+        }
+    }
+
+    #[test]
+    fn records_merge_per_field() {
+        let mut a_clock = HlcClock::with_clock(A, ManualClock::new(10));
+        let mut b_clock = HlcClock::with_clock(B, ManualClock::new(20));
+        let mut square = Square::default();
+        square.x.set(&mut a_clock, 0.0);
+        square.y.set(&mut a_clock, 0.0);
+
+        // One moves the square while the other resizes it
+        let mut a = square.clone();
+        a.x.set(&mut a_clock, 5.0);
+        a.width = 2.0;
+        let mut b = square;
+        b.y.set(&mut b_clock, 7.0);
+        b.height = 3.0;
+
+        let merged = a.merge(&b);
+        assert_eq!(merged, b.merge(&a));
+        assert_eq!(merged.x.value(), Some(&5.0));
+        assert_eq!(merged.y.value(), Some(&7.0));
+        assert_eq!((merged.width, merged.height), (2.0, 3.0));
+    }
+
+    #[test]
+    fn products_as_map_values() {
+        let mut a = AWORMap::<u32, Product<MaxReg<u32>, MaxReg<u32>>>::default();
+        a.add(A, 1, Product::new(MaxReg::new(3), MaxReg::new(1)));
+        let mut b = a.clone();
+        a.add(A, 1, Product::new(MaxReg::new(5), MaxReg::new(1)));
+        b.add(B, 1, Product::new(MaxReg::new(3), MaxReg::new(4)));
+
+        let merged = a.merge(&b);
+        assert_eq!(merged, b.merge(&a));
+        let product = &merged.value()[&1];
+        assert_eq!((*product.first.value(), *product.second.value()), (5, 4));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig{ ..Default::default()})]
+
+        #[test]
+        fn tuples(a in any::<(u32, i64, u16)>(), b in any::<(u32, i64, u16)>(), c in any::<(u32, i64, u16)>()) {
+            prop_assert_eq!(a.merge(&b), b.merge(&a));
+            prop_assert_eq!(a.merge(&b).merge(&c), a.merge(&b.merge(&c)));
+            prop_assert_eq!(a.merge(&a), a);
+
+            let (pa, pb) = (Product::new(a.0, a.1), Product::new(b.0, b.1));
+            prop_assert_eq!(pa.merge(&pb), Product::new(a.0.max(b.0), a.1.max(b.1)));
+        }
+    }
+}
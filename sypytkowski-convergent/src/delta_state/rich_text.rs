@@ -0,0 +1,402 @@
+//! Text with formatting, in the style of [Peritext](https://www.inkandswitch.com/peritext/).
+//!
+//! Marks aren't stored on the characters but as [`Format`]s spanning two [`Anchor`]s, which sit
+//! right before or right after the position of a character. Positions never move, so a mark keeps
+//! covering the characters it was made over while text is inserted and deleted around them, and
+//! whether text typed at its boundary takes the mark is decided by the side its anchors sit on:
+//! bold and italic grow when typing at their end like in most editors, comments don't grow at all.
+//!
+//! Formats never go away, removing a mark is another format that wins over the ones before it.
+//! Every format carries a Lamport timestamp and for each character and mark the greatest one
+//! covering it decides, so concurrent formatting of overlapping ranges converges.
+//!
+//! Deleted characters leave no trace in [`Text`], an anchor whose character was deleted still
+//! sits at its position but text inserted at that gap later may land on either side of it.
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use crate::{ReplicaId, Value};
+
+use super::aworset::AWORSet;
+use super::dot::{Dot, DotKernel};
+use super::orarray::Position;
+use super::text::{self, Text};
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::rich_text")
+)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Mark {
+    Bold,
+    Italic,
+    /// Comment thread `id`, comments of different threads can overlap
+    Comment {
+        id: String,
+    },
+}
+
+impl Mark {
+    /// Whether text typed right at the end of the mark takes it too
+    fn expands(&self) -> bool {
+        matches!(self, Mark::Bold | Mark::Italic)
+    }
+}
+
+/// Boundary of a [`Format`]
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::rich_text")
+)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Anchor {
+    /// Right before the character at `pos`, after anything inserted between it and the one before
+    Before { pos: Position },
+    /// Right after the character at `pos`, before anything inserted between it and the one after
+    After { pos: Position },
+    /// After the last character, wherever it ends up
+    End,
+}
+
+impl Anchor {
+    /// Whether the anchor comes before the character at `pos`
+    fn precedes(&self, pos: &Position) -> bool {
+        match self {
+            Anchor::Before { pos: anchor } => anchor <= pos,
+            Anchor::After { pos: anchor } => anchor < pos,
+            Anchor::End => false,
+        }
+    }
+}
+
+/// `mark` added to or, if not `active`, removed from the characters between `start` and `end`
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::delta_state::rich_text")
+)]
+pub struct Format {
+    /// Lamport timestamp, ties are broken by `replica`
+    pub counter: u64,
+    pub replica: ReplicaId,
+    pub mark: Mark,
+    pub active: bool,
+    pub start: Anchor,
+    pub end: Anchor,
+}
+
+impl Value for Mark {}
+impl Value for Anchor {}
+impl Value for Format {}
+
+impl Format {
+    fn covers(&self, pos: &Position) -> bool {
+        self.start.precedes(pos) && !self.end.precedes(pos)
+    }
+}
+
+/// Run of characters with the same marks, see [`RichText::spans`]
+#[derive(Debug, Clone, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct Span {
+    pub text: String,
+    pub marks: BTreeSet<Mark>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct RichText {
+    text: Text,
+    formats: AWORSet<Format>,
+}
+
+/// Changes of a [`RichText`] since its delta was last taken
+#[derive(Debug, Clone, PartialEq, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct RichTextDelta {
+    pub text: Option<text::Deltas>,
+    pub formats: Option<DotKernel<Format>>,
+}
+
+impl RichTextDelta {
+    /// See [`DotKernel::uncovered_dot`]
+    pub fn uncovered_dot(&self) -> Option<Dot> {
+        (self.text.as_ref().and_then(DotKernel::uncovered_dot))
+            .or_else(|| self.formats.as_ref().and_then(DotKernel::uncovered_dot))
+    }
+}
+
+impl RichText {
+    /// Plain text without the marks
+    pub fn text(&self) -> &Text {
+        &self.text
+    }
+
+    pub fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// See [`Text::insert`], the inserted text takes the marks whose anchors surround it
+    pub fn insert(&mut self, replica: ReplicaId, index: usize, text: &str) {
+        self.text.insert(replica, index, text)
+    }
+
+    /// See [`Text::delete`]
+    pub fn delete(&mut self, range: Range<usize>) {
+        self.text.delete(range)
+    }
+
+    /// Adds `mark` to the characters in `range`, clamped to the end of the text
+    pub fn mark(&mut self, replica: ReplicaId, range: Range<usize>, mark: Mark) {
+        self.format(replica, range, mark, true)
+    }
+
+    /// Removes `mark` from the characters in `range`, clamped to the end of the text
+    pub fn unmark(&mut self, replica: ReplicaId, range: Range<usize>, mark: Mark) {
+        self.format(replica, range, mark, false)
+    }
+
+    fn format(&mut self, replica: ReplicaId, range: Range<usize>, mark: Mark, active: bool) {
+        let chars = &self.text.chars;
+        let end = range.end.min(chars.len());
+        let Some(first) = chars.position(range.start).filter(|_| range.start < end) else {
+            return;
+        };
+        let start = Anchor::Before { pos: first.clone() };
+        let end = match chars.position(end) {
+            // Text typed at the end lands between the last character and the next one
+            Some(next) if mark.expands() => Anchor::Before { pos: next.clone() },
+            None if mark.expands() => Anchor::End,
+            _ => Anchor::After {
+                pos: chars.position(end - 1).unwrap().clone(),
+            },
+        };
+        let counter = self.formats.iter().map(|f| f.counter).max().unwrap_or(0) + 1;
+        self.formats.add(
+            replica,
+            Format {
+                counter,
+                replica,
+                mark,
+                active,
+                start,
+                end,
+            },
+        );
+    }
+
+    /// Marks of the character at `index`
+    pub fn marks(&self, index: usize) -> BTreeSet<&Mark> {
+        let Some(pos) = self.text.chars.position(index) else {
+            return BTreeSet::new();
+        };
+        self.marks_at(pos)
+    }
+
+    fn marks_at(&self, pos: &Position) -> BTreeSet<&Mark> {
+        let mut latest = BTreeMap::<&Mark, &Format>::new();
+        for format in self.formats.iter().filter(|f| f.covers(pos)) {
+            let stamp = (format.counter, format.replica);
+            let current = latest.entry(&format.mark).or_insert(format);
+            if stamp > (current.counter, current.replica) {
+                *current = format;
+            }
+        }
+        (latest.into_iter())
+            .filter(|(_, format)| format.active)
+            .map(|(mark, _)| mark)
+            .collect()
+    }
+
+    /// The text split into runs of characters with the same marks, for rendering
+    pub fn spans(&self) -> Vec<Span> {
+        let chars = &self.text.chars;
+        let mut spans = Vec::<Span>::new();
+        for (pos, c) in chars.positions().zip(chars.iter()) {
+            let marks = self.marks_at(pos);
+            match spans.last_mut() {
+                Some(span) if span.marks.iter().eq(marks.iter().copied()) => span.text.push_str(c),
+                _ => spans.push(Span {
+                    text: c.clone(),
+                    marks: marks.into_iter().cloned().collect(),
+                }),
+            }
+        }
+        spans
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            text: self.text.merge(&other.text),
+            formats: self.formats.merge(&other.formats),
+        }
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        self.text.merge_in_place(&other.text);
+        self.formats.merge_in_place(&other.formats);
+    }
+
+    pub fn merge_delta(&mut self, delta: &RichTextDelta) {
+        if let Some(text) = &delta.text {
+            self.text.merge_delta(text);
+        }
+        if let Some(formats) = &delta.formats {
+            self.formats.merge_delta_in_place(formats);
+        }
+    }
+
+    pub fn split_mut(&mut self) -> Option<RichTextDelta> {
+        let delta = RichTextDelta {
+            text: self.text.split_mut(),
+            formats: self.formats.split_mut(),
+        };
+        (delta.text.is_some() || delta.formats.is_some()).then_some(delta)
+    }
+}
+
+impl fmt::Display for RichText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ReplicaId;
+
+    use super::{Mark, RichText};
+
+    const A: ReplicaId = ReplicaId(1);
+    const B: ReplicaId = ReplicaId(2);
+
+    fn comment() -> Mark {
+        Mark::Comment { id: "c1".into() }
+    }
+
+    fn runs(text: &RichText) -> Vec<(String, Vec<Mark>)> {
+        (text.spans().into_iter())
+            .map(|span| (span.text, span.marks.into_iter().collect()))
+            .collect()
+    }
+
+    fn sync(a: &mut RichText, b: &mut RichText) {
+        let a_delta = a.split_mut();
+        if let Some(delta) = b.split_mut() {
+            a.merge_delta(&delta);
+        }
+        if let Some(delta) = a_delta {
+            b.merge_delta(&delta);
+        }
+    }
+
+    #[test]
+    fn marks_and_spans() {
+        let mut text = RichText::default();
+        text.insert(A, 0, "hello world");
+        text.mark(A, 0..5, Mark::Bold);
+        text.mark(A, 3..8, Mark::Italic);
+        text.unmark(A, 4..5, Mark::Bold);
+        text.mark(A, 20..30, Mark::Bold);
+
+        assert_eq!(
+            runs(&text),
+            [
+                ("hel".into(), vec![Mark::Bold]),
+                ("l".into(), vec![Mark::Bold, Mark::Italic]),
+                ("o wo".into(), vec![Mark::Italic]),
+                ("rld".into(), vec![]),
+            ]
+        );
+        assert!(text.marks(100).is_empty());
+
+        // Typing at the end of a bold word keeps typing in bold, not at its start
+        text.mark(A, 6..11, Mark::Bold);
+        text.insert(A, 11, "!");
+        text.insert(A, 6, "_");
+        assert_eq!(
+            text.marks(12).into_iter().collect::<Vec<_>>(),
+            [&Mark::Bold]
+        );
+        assert!(!text.marks(6).contains(&Mark::Bold));
+    }
+
+    #[test]
+    fn concurrent_edits_at_boundaries() {
+        let mut a = RichText::default();
+        a.insert(A, 0, "one two");
+        let mut b = RichText::default();
+        sync(&mut a, &mut b);
+
+        a.mark(A, 0..3, Mark::Bold);
+        a.mark(A, 4..7, comment());
+        // Right before and after "one" and right before and after "two"
+        b.insert(B, 7, "2");
+        b.insert(B, 4, "[");
+        b.insert(B, 3, "1");
+        b.insert(B, 0, ">");
+        sync(&mut a, &mut b);
+
+        assert_eq!(a.to_string(), ">one1 [two2");
+        assert_eq!(runs(&a), runs(&b));
+        assert_eq!(
+            runs(&a),
+            [
+                (">".into(), vec![]),
+                ("one1".into(), vec![Mark::Bold]),
+                (" [".into(), vec![]),
+                ("two".into(), vec![comment()]),
+                ("2".into(), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn concurrent_formatting_converges() {
+        let mut a = RichText::default();
+        a.insert(A, 0, "abcdef");
+        let mut b = RichText::default();
+        sync(&mut a, &mut b);
+
+        a.mark(A, 0..4, Mark::Bold);
+        b.unmark(B, 2..6, Mark::Bold);
+        b.mark(B, 1..3, comment());
+        let merged = a.merge(&b);
+        sync(&mut a, &mut b);
+
+        for text in [&a, &b, &merged] {
+            assert_eq!(
+                runs(text),
+                [
+                    ("a".into(), vec![Mark::Bold]),
+                    ("b".into(), vec![Mark::Bold, comment()]),
+                    ("c".into(), vec![comment()]),
+                    ("def".into(), vec![]),
+                ]
+            );
+        }
+
+        // Formatting after seeing both wins over them
+        a.mark(A, 0..6, Mark::Bold);
+        sync(&mut a, &mut b);
+        assert!(b
+            .spans()
+            .iter()
+            .all(|span| span.marks.contains(&Mark::Bold)));
+    }
+}
@@ -0,0 +1,173 @@
+//! Collaborative plain text.
+//!
+//! Built on [`ORArray`] with one element per `char`, so every character keeps the position it was
+//! inserted at and concurrent edits interleave without moving anyone else's text. Characters are
+//! stored as one character strings since `char` isn't a type plugins can exchange.
+//!
+//! Editors also have to keep their users' cursors where they were while remote edits come in,
+//! [`Text::transform_position`] maps a local cursor over a delta before it is merged.
+use alloc::string::{String, ToString};
+use core::fmt;
+use core::ops::Range;
+
+use crate::ReplicaId;
+
+use super::dot::{DeltaPolicy, VectorClock};
+use super::orarray::{self, ORArray};
+
+pub type Deltas = orarray::Deltas<String>;
+
+#[derive(Debug, Clone, PartialEq, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct Text {
+    pub(crate) chars: ORArray<String>,
+}
+
+impl Text {
+    /// Length in `char`s, which is also what every index of this API counts
+    pub fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /// Inserts `text` so it starts at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`.
+    pub fn insert(&mut self, replica: ReplicaId, index: usize, text: &str) {
+        for (i, c) in text.chars().enumerate() {
+            self.chars.insert(replica, index + i, c.to_string());
+        }
+    }
+
+    /// Deletes the characters in `range`, clamped to the end of the text
+    pub fn delete(&mut self, range: Range<usize>) {
+        let end = range.end.min(self.len());
+        for _ in range.start.min(end)..end {
+            self.chars.remove(range.start);
+        }
+    }
+
+    /// Where the cursor at `pos` ends up once `delta` is merged. Has to be called before merging
+    /// `delta`.
+    ///
+    /// A cursor sticks to the character on its left, so text inserted concurrently right at the
+    /// cursor ends up after it.
+    pub fn transform_position(&self, pos: usize, delta: &Deltas) -> usize {
+        self.chars.transform_index(pos, delta)
+    }
+
+    /// [`Text::transform_position`] for both ends of a selection
+    pub fn transform_selection(&self, selection: Range<usize>, delta: &Deltas) -> Range<usize> {
+        self.transform_position(selection.start, delta)
+            ..self.transform_position(selection.end, delta)
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            chars: self.chars.merge(&other.chars),
+        }
+    }
+
+    pub fn merge_in_place(&mut self, other: &Self) {
+        self.chars.merge_in_place(&other.chars)
+    }
+
+    pub fn merge_delta(&mut self, delta: &Deltas) {
+        self.chars.merge_delta_in_place(delta)
+    }
+
+    /// See [`AWORSet::compact_deltas`](super::aworset::AWORSet::compact_deltas)
+    pub fn compact_deltas(&mut self, acked: &VectorClock) {
+        self.chars.compact_deltas(acked)
+    }
+
+    /// See [`AWORSet::compact_deltas_with`](super::aworset::AWORSet::compact_deltas_with)
+    #[must_use]
+    pub fn compact_deltas_with(&mut self, acked: &VectorClock, policy: DeltaPolicy) -> bool {
+        self.chars.compact_deltas_with(acked, policy)
+    }
+
+    pub fn split_mut(&mut self) -> Option<Deltas> {
+        self.chars.split_mut()
+    }
+}
+
+impl fmt::Display for Text {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.chars.iter() {
+            f.write_str(c)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ReplicaId;
+
+    use super::Text;
+
+    const A: ReplicaId = ReplicaId(1);
+    const B: ReplicaId = ReplicaId(2);
+
+    #[test]
+    fn insert_delete() {
+        let mut text = Text::default();
+        text.insert(A, 0, "hello world");
+        text.insert(A, 5, ",");
+        text.delete(6..12);
+        text.insert(A, 6, " there");
+        assert_eq!(text.to_string(), "hello, there");
+        assert_eq!(text.len(), 12);
+
+        text.delete(5..100);
+        assert_eq!(text.to_string(), "hello");
+    }
+
+    #[test]
+    fn concurrent_edits_keep_cursors() {
+        let mut a = Text::default();
+        a.insert(A, 0, "the fox");
+        let mut b = Text::default();
+        b.merge_delta(&a.split_mut().unwrap());
+
+        // A's cursor sits after "fox", their selection covers "fox"
+        let cursor = a.len();
+        let selection = 4..7;
+
+        b.insert(B, 3, " quick");
+        b.delete(0..3);
+        b.insert(B, b.len(), "es");
+        let delta = b.split_mut().unwrap();
+
+        let cursor = a.transform_position(cursor, &delta);
+        let selection = a.transform_selection(selection, &delta);
+        a.merge_delta(&delta);
+
+        assert_eq!(a.to_string(), " quick foxes");
+        // Text typed right at the cursor ends up after it
+        assert_eq!(cursor, 10);
+        assert_eq!(&a.to_string()[selection], "fox");
+    }
+
+    #[test]
+    fn cursor_in_deleted_text() {
+        let mut a = Text::default();
+        a.insert(A, 0, "abcdef");
+        let mut b = Text::default();
+        b.merge_delta(&a.split_mut().unwrap());
+
+        b.delete(1..5);
+        let delta = b.split_mut().unwrap();
+
+        // Cursors inside of the deleted range collapse to where it was
+        for pos in 1..=5 {
+            assert_eq!(a.transform_position(pos, &delta), 1);
+        }
+        assert_eq!(a.transform_position(6, &delta), 2);
+    }
+}
@@ -0,0 +1,357 @@
+//! Undo/redo of local operations.
+//!
+//! An operation on a kernel based CRDT adds some entries under fresh dots and removes others, so
+//! [`UndoManager::record`] diffs the kernel around a local operation to remember exactly that.
+//! Undoing removes the dots the operation added and adds the entries it removed back under new
+//! dots. Only dots this replica touched are involved, so concurrent changes from other replicas,
+//! even to the same element or key, survive the undo.
+//!
+//! Undo and redo are regular local operations: they end up in the CRDT's delta buffer like any
+//! other change and are returned on their own as well.
+use alloc::borrow::Cow;
+use alloc::collections::BTreeSet;
+use alloc::{vec, vec::Vec};
+
+use crate::{ReplicaId, Value};
+
+use super::awormap::{AWORMap, KeyVal};
+use super::aworset::AWORSet;
+use super::dot::{Dot, DotKernel};
+use super::mvreg::MVReg;
+
+/// CRDTs whose whole state lives in a single [`DotKernel`]
+pub trait Undoable {
+    type Entry: Clone + PartialEq + Value;
+
+    /// Borrowed when the CRDT keeps its entries in a kernel as they are
+    fn kernel(&self) -> Cow<'_, DotKernel<Self::Entry>>;
+
+    /// Merges a locally produced delta, recording it in the delta buffer
+    fn apply_local(&mut self, delta: &DotKernel<Self::Entry>);
+}
+
+impl<V> Undoable for AWORSet<V>
+where
+    V: Clone + PartialEq + Value,
+{
+    type Entry = V;
+
+    fn kernel(&self) -> Cow<'_, DotKernel<V>> {
+        Cow::Borrowed(&self.kernel)
+    }
+
+    fn apply_local(&mut self, delta: &DotKernel<V>) {
+        self.merge_delta_in_place(delta)
+    }
+}
+
+impl<V> Undoable for MVReg<V>
+where
+    V: Clone + PartialEq + Ord + Value,
+{
+    type Entry = V;
+
+    fn kernel(&self) -> Cow<'_, DotKernel<V>> {
+        Cow::Borrowed(&self.core)
+    }
+
+    fn apply_local(&mut self, delta: &DotKernel<V>) {
+        self.merge_delta(delta.clone())
+    }
+}
+
+impl<K, V> Undoable for AWORMap<K, V>
+where
+    K: Clone + PartialEq + Ord + Value,
+    V: Value + Clone + PartialEq,
+{
+    type Entry = KeyVal<K, V>;
+
+    /// Keys and values are kept apart, so this puts them together in a copy
+    fn kernel(&self) -> Cow<'_, DotKernel<KeyVal<K, V>>> {
+        Cow::Owned(self.fused_kernel())
+    }
+
+    fn apply_local(&mut self, delta: &DotKernel<KeyVal<K, V>>) {
+        self.merge_delta_in_place(delta)
+    }
+}
+
+/// What a local operation did to the kernel
+#[derive(Debug, Clone)]
+struct Change<E> {
+    added: Vec<Dot>,
+    removed: Vec<(Dot, E)>,
+}
+
+impl<E> Change<E> {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// Folds `next` into this change, as if both had been done by a single operation
+    fn extend(&mut self, next: Change<E>) {
+        let removed = next
+            .removed
+            .iter()
+            .map(|(dot, _)| *dot)
+            .collect::<BTreeSet<_>>();
+        // Whatever we added and `next` removed again doesn't need to be restored by an undo
+        let transient = self
+            .added
+            .iter()
+            .filter(|dot| removed.contains(dot))
+            .copied()
+            .collect::<BTreeSet<_>>();
+
+        self.added.retain(|dot| !transient.contains(dot));
+        self.added.extend(next.added);
+        self.removed.extend(
+            next.removed
+                .into_iter()
+                .filter(|(dot, _)| !transient.contains(dot)),
+        );
+    }
+}
+
+/// Undo and redo stacks of the local operations on one CRDT
+#[derive(Debug, Clone)]
+pub struct UndoManager<T: Undoable> {
+    undo: Vec<Change<T::Entry>>,
+    redo: Vec<Change<T::Entry>>,
+    limit: Option<usize>,
+    /// Changes recorded while grouping are folded into the top of the undo stack
+    grouping: bool,
+    group_started: bool,
+}
+
+impl<T: Undoable> Default for UndoManager<T> {
+    fn default() -> Self {
+        Self {
+            undo: vec![],
+            redo: vec![],
+            limit: None,
+            grouping: false,
+            group_started: false,
+        }
+    }
+}
+
+impl<T: Undoable> UndoManager<T> {
+    /// Only keeps the last `limit` operations around to be undone
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Default::default()
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Runs the local operation `op` on `crdt` and remembers how to undo it. Recording a new
+    /// operation drops everything that could be redone.
+    ///
+    /// Changes are found by comparing the kernel before and after `op`, so this clones the
+    /// kernel's entries.
+    pub fn record<R>(&mut self, crdt: &mut T, op: impl FnOnce(&mut T) -> R) -> R {
+        let before = crdt.kernel().into_owned().entries;
+        let ret = op(crdt);
+        let after = crdt.kernel();
+        let after = &after.entries;
+
+        let change = Change {
+            added: after
+                .keys()
+                .filter(|dot| !before.contains_key(dot))
+                .copied()
+                .collect(),
+            removed: before
+                .into_iter()
+                .filter(|(dot, _)| !after.contains_key(dot))
+                .collect(),
+        };
+        if change.is_empty() {
+            return ret;
+        }
+
+        self.redo.clear();
+        match self.undo.last_mut() {
+            Some(last) if self.grouping && self.group_started => last.extend(change),
+            _ => {
+                self.undo.push(change);
+                self.group_started = self.grouping;
+                if let Some(limit) = self.limit {
+                    let excess = self.undo.len().saturating_sub(limit);
+                    self.undo.drain(..excess);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Everything recorded until [`UndoManager::end_group`] is undone as a single operation,
+    /// e.g. all the moves of a drag
+    pub fn start_group(&mut self) {
+        self.grouping = true;
+        self.group_started = false;
+    }
+
+    pub fn end_group(&mut self) {
+        self.grouping = false;
+        self.group_started = false;
+    }
+
+    /// Reverts the last recorded operation, returns the delta of the undo or `None` if there was
+    /// nothing to undo
+    pub fn undo(&mut self, crdt: &mut T, replica: ReplicaId) -> Option<DotKernel<T::Entry>> {
+        let change = self.undo.pop()?;
+        let (delta, inverse) = Self::revert(crdt, replica, change);
+        self.redo.push(inverse);
+        self.group_started = false;
+        Some(delta)
+    }
+
+    /// Reverts the last undo, returns the delta of the redo or `None` if there was nothing to redo
+    pub fn redo(&mut self, crdt: &mut T, replica: ReplicaId) -> Option<DotKernel<T::Entry>> {
+        let change = self.redo.pop()?;
+        let (delta, inverse) = Self::revert(crdt, replica, change);
+        self.undo.push(inverse);
+        self.group_started = false;
+        Some(delta)
+    }
+
+    /// Applies the inverse of `change`, returning its delta and the change that inverts it in turn
+    fn revert(
+        crdt: &mut T,
+        replica: ReplicaId,
+        change: Change<T::Entry>,
+    ) -> (DotKernel<T::Entry>, Change<T::Entry>) {
+        let kernel = crdt.kernel();
+        let mut delta = DotKernel::<T::Entry> {
+            entries: Default::default(),
+            ctx: Default::default(),
+        };
+        let mut inverse = Change {
+            added: vec![],
+            removed: vec![],
+        };
+
+        // Dots someone else already removed stay removed and don't need restoring later
+        for dot in change.added {
+            if let Some(entry) = kernel.entries.get(&dot) {
+                delta.ctx.add(dot);
+                inverse.removed.push((dot, entry.clone()));
+            }
+        }
+
+        let next = kernel.ctx.clock.get(&replica).copied().unwrap_or(0) + 1;
+        for (n, (_, entry)) in (next..).zip(change.removed) {
+            let dot = Dot(replica, n);
+            delta.entries.insert(dot, entry);
+            delta.ctx.add(dot);
+            inverse.added.push(dot);
+        }
+        delta.ctx.compact();
+
+        crdt.apply_local(&delta);
+        (delta, inverse)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::delta_state::{awormap::AWORMap, aworset::AWORSet, mvreg::MVReg};
+    use crate::ReplicaId;
+
+    use super::UndoManager;
+
+    const A: ReplicaId = ReplicaId(1);
+    const B: ReplicaId = ReplicaId(2);
+
+    #[test]
+    fn undo_redo_set() {
+        let mut set = AWORSet::<u32>::default();
+        let mut undo = UndoManager::default();
+
+        undo.record(&mut set, |set| set.add(A, 1));
+        undo.record(&mut set, |set| set.add(A, 2));
+        undo.record(&mut set, |set| set.remove(&1));
+        assert_eq!(set.value(), [2].into());
+
+        undo.undo(&mut set, A).unwrap();
+        assert_eq!(set.value(), [1, 2].into());
+        undo.undo(&mut set, A).unwrap();
+        assert_eq!(set.value(), [1].into());
+        undo.redo(&mut set, A).unwrap();
+        assert_eq!(set.value(), [1, 2].into());
+
+        // A new operation drops the redo stack
+        undo.record(&mut set, |set| set.add(A, 3));
+        assert!(!undo.can_redo());
+        assert!(undo.redo(&mut set, A).is_none());
+    }
+
+    #[test]
+    fn undo_keeps_remote_changes() {
+        let mut a = AWORMap::<u32, u32>::default();
+        let mut b = AWORMap::<u32, u32>::default();
+        let mut undo = UndoManager::default();
+
+        undo.record(&mut a, |a| a.insert(A, 1, 10));
+        b.merge_delta(a.split_mut().unwrap());
+
+        // Concurrently to B writing key 2 and a conflicting value for key 1, A moves key 1
+        undo.record(&mut a, |a| a.insert(A, 1, 11));
+        b.insert(B, 1, 20);
+        b.insert(B, 2, 20);
+        a.merge_delta(b.split_mut().unwrap());
+
+        let delta = undo.undo(&mut a, A).unwrap();
+        b.merge_delta(a.split_mut().unwrap());
+
+        // A's 11 is gone and their 10 is back, B's values are untouched
+        assert_eq!(a.get_all(&1).copied().collect::<Vec<_>>(), vec![10, 20]);
+        assert_eq!(a.get(&2), Some(&20));
+        assert_eq!(a.keys.kernel.entries, b.keys.kernel.entries);
+        assert_eq!(delta.entries.len(), 1);
+    }
+
+    #[test]
+    fn undo_register() {
+        let mut reg = MVReg::<String>::default();
+        let mut undo = UndoManager::with_limit(1);
+
+        undo.record(&mut reg, |reg| reg.set(A, "a".into()));
+        undo.record(&mut reg, |reg| reg.set(A, "b".into()));
+
+        undo.undo(&mut reg, A).unwrap();
+        assert_eq!(reg.value(), [&"a".to_string()].into());
+        // Only the last operation was kept
+        assert!(undo.undo(&mut reg, A).is_none());
+    }
+
+    #[test]
+    fn groups() {
+        let mut map = AWORMap::<u32, u32>::default();
+        let mut undo = UndoManager::default();
+
+        undo.record(&mut map, |map| map.insert(A, 1, 0));
+        undo.start_group();
+        for x in 1..10 {
+            undo.record(&mut map, |map| map.insert(A, 1, x));
+        }
+        undo.end_group();
+        assert_eq!(map.get(&1), Some(&9));
+
+        undo.undo(&mut map, A).unwrap();
+        assert_eq!(map.get_all(&1).copied().collect::<Vec<_>>(), vec![0]);
+        undo.redo(&mut map, A).unwrap();
+        assert_eq!(map.get_all(&1).copied().collect::<Vec<_>>(), vec![9]);
+    }
+}
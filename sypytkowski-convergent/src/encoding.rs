@@ -0,0 +1,485 @@
+//! Compact, versioned binary encoding of the delta state CRDTs, for the wire and for stores.
+//!
+//! The serde impls turn replica ids and dots into strings so they survive JSON and JavaScript
+//! numbers, which is slow and makes the encoding several times larger than the state. Here every
+//! encoding starts with a format version byte followed by the value:
+//!
+//! - unsigned integers, replica ids and lengths are LEB128 varints, signed integers are zigzag
+//!   encoded first, `u8`s and `bool`s are a single byte and floats are little endian
+//! - strings are their length followed by their UTF-8 bytes, options a `0`/`1` tag
+//! - maps and sets are their length followed by their entries in key order, so equal states
+//!   encode to the same bytes
+//! - runs of dots and of retired replicas are their start and how many they hold
+//!
+//! [`decode`] reads the version byte and hands it to the [`Decode`] impls through the [`Reader`],
+//! so bytes written in an older format keep decoding after a new one is added.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The first format
+pub const V1: u8 = 1;
+/// Newest format [`decode`] understands
+pub const LATEST: u8 = V1;
+
+/// Encodes `value` in the [`V1`] format, version byte included
+pub fn encode_v1<T: Encode + ?Sized>(value: &T) -> Vec<u8> {
+    let mut out = Writer {
+        buf: Vec::new(),
+        version: V1,
+    };
+    out.buf.push(V1);
+    value.encode(&mut out);
+    out.buf
+}
+
+/// Decodes a value written by any of the `encode_*` functions, all of `bytes` has to be used
+pub fn decode<T: Decode>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let (&version, bytes) = bytes.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+    if version == 0 || version > LATEST {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let mut input = Reader { bytes, version };
+    let value = T::decode(&mut input)?;
+    match input.bytes.len() {
+        0 => Ok(value),
+        n => Err(DecodeError::TrailingBytes(n)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The version byte is for a format this build doesn't know
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    /// A varint longer than its type or a number that doesn't fit where it is read
+    VarintOverflow,
+    InvalidUtf8,
+    /// A `bool` or `Option` tag other than `0` or `1`
+    InvalidTag(u8),
+    /// Bytes left over after the value
+    TrailingBytes(usize),
+    /// Well formed but not a valid state
+    Invalid(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {version}")
+            }
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            DecodeError::VarintOverflow => write!(f, "integer out of range"),
+            DecodeError::InvalidUtf8 => write!(f, "string isn't valid UTF-8"),
+            DecodeError::InvalidTag(tag) => write!(f, "invalid tag {tag}"),
+            DecodeError::TrailingBytes(n) => write!(f, "{n} bytes left after the value"),
+            DecodeError::Invalid(reason) => write!(f, "invalid state: {reason}"),
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+/// Where values are encoded to, along with the format they are encoded in
+pub struct Writer {
+    buf: Vec<u8>,
+    version: u8,
+}
+
+impl Writer {
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn byte(&mut self, byte: u8) {
+        self.buf.push(byte);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn varint(&mut self, mut n: u128) {
+        while n >= 0x80 {
+            self.buf.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.buf.push(n as u8);
+    }
+
+    pub fn length(&mut self, len: usize) {
+        self.varint(len as u128)
+    }
+}
+
+/// What values are decoded from, along with the format they were encoded in
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    version: u8,
+}
+
+impl<'a> Reader<'a> {
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn byte(&mut self) -> Result<u8, DecodeError> {
+        let (&byte, rest) = self.bytes.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < n {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    pub fn varint(&mut self) -> Result<u128, DecodeError> {
+        let mut n = 0u128;
+        for shift in (0..128).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as u128;
+            if shift == 126 && bits > 0b11 {
+                return Err(DecodeError::VarintOverflow);
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(DecodeError::VarintOverflow)
+    }
+
+    /// A length, which can't be more than the bytes left since every element takes at least one
+    pub fn length(&mut self) -> Result<usize, DecodeError> {
+        let len = self.varint()?;
+        if len > self.bytes.len() as u128 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        Ok(len as usize)
+    }
+}
+
+pub trait Encode {
+    /// Appends `self` in the format of `out`
+    fn encode(&self, out: &mut Writer);
+}
+
+pub trait Decode: Sized {
+    /// Reads a value in the format of `input`
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError>;
+}
+
+macro_rules! impl_unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode(&self, out: &mut Writer) {
+                    out.varint(*self as u128)
+                }
+            }
+
+            impl Decode for $t {
+                fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+                    input.varint()?.try_into().map_err(|_| DecodeError::VarintOverflow)
+                }
+            }
+        )*
+    };
+}
+
+impl_unsigned!(u16, u32, u64, u128);
+
+macro_rules! impl_signed {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode(&self, out: &mut Writer) {
+                    let zigzag = ((*self << 1) ^ (*self >> (<$t>::BITS - 1))) as $u;
+                    zigzag.encode(out)
+                }
+            }
+
+            impl Decode for $t {
+                fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+                    let zigzag = <$u>::decode(input)?;
+                    Ok((zigzag >> 1) as $t ^ -((zigzag & 1) as $t))
+                }
+            }
+        )*
+    };
+}
+
+impl_signed!(i16 => u16, i32 => u32, i64 => u64);
+
+impl Encode for u8 {
+    fn encode(&self, out: &mut Writer) {
+        out.byte(*self)
+    }
+}
+
+impl Decode for u8 {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        input.byte()
+    }
+}
+
+impl Encode for i8 {
+    fn encode(&self, out: &mut Writer) {
+        out.byte(*self as u8)
+    }
+}
+
+impl Decode for i8 {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        input.byte().map(|byte| byte as i8)
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, out: &mut Writer) {
+        out.byte(*self as u8)
+    }
+}
+
+impl Decode for bool {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        match input.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+macro_rules! impl_float {
+    ($($t:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode(&self, out: &mut Writer) {
+                    out.bytes(&self.to_le_bytes())
+                }
+            }
+
+            impl Decode for $t {
+                fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+                    let bytes = input.bytes(core::mem::size_of::<$t>())?;
+                    Ok(<$t>::from_le_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+impl_float!(f32, f64);
+
+impl Encode for str {
+    fn encode(&self, out: &mut Writer) {
+        out.length(self.len());
+        out.bytes(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Writer) {
+        self.as_str().encode(out)
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let len = input.length()?;
+        let bytes = input.bytes(len)?;
+        core::str::from_utf8(bytes)
+            .map(String::from)
+            .map_err(|_| DecodeError::InvalidUtf8)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Writer) {
+        match self {
+            None => out.byte(0),
+            Some(value) => {
+                out.byte(1);
+                value.encode(out);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        match input.byte()? {
+            0 => Ok(None),
+            1 => T::decode(input).map(Some),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Debug;
+
+    use proptest::prelude::*;
+
+    use crate::delta_state::{
+        awormap::AWORMap,
+        aworset::{test::properties::aworset_strategy, AWORSet},
+        dot::{test::dotkernel_strategy, Dot, DotCloud, DotRange, VectorClock},
+    };
+    use crate::ReplicaId;
+
+    use super::{decode, encode_v1, Decode, DecodeError, Encode, LATEST, V1};
+
+    fn round_trip<T: Encode + Decode + PartialEq + Debug>(value: &T) -> T {
+        let bytes = encode_v1(value);
+        assert_eq!(bytes[0], V1);
+        let decoded = decode::<T>(&bytes).unwrap();
+        assert_eq!(&decoded, value);
+        // Every prefix is missing something
+        for len in 0..bytes.len() {
+            assert!(decode::<T>(&bytes[..len]).is_err());
+        }
+        decoded
+    }
+
+    #[test]
+    fn primitives() {
+        for n in [0, 1, 127, 128, 300, u64::MAX] {
+            round_trip(&n);
+        }
+        for n in [0, -1, 1, i64::MIN, i64::MAX] {
+            round_trip(&n);
+        }
+        round_trip(&u128::MAX);
+        round_trip(&-7i8);
+        round_trip(&1.5f64);
+        round_trip(&String::from("héllo"));
+        round_trip(&Some(true));
+        round_trip(&None::<u32>);
+
+        assert_eq!(encode_v1(&300u64), [V1, 0xac, 0x02]);
+        assert_eq!(encode_v1(&-2i64), [V1, 3]);
+        assert_eq!(
+            decode::<u16>(&encode_v1(&70_000u32)),
+            Err(DecodeError::VarintOverflow)
+        );
+        assert_eq!(decode::<bool>(&[V1, 2]), Err(DecodeError::InvalidTag(2)));
+        assert_eq!(
+            decode::<String>(&[V1, 1, 0xff]),
+            Err(DecodeError::InvalidUtf8)
+        );
+        // A length longer than what is left isn't trusted
+        assert_eq!(
+            decode::<String>(&[V1, 0xff, 0xff, 0xff, 0xff, 0x0f]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(decode::<u64>(&[]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(
+            decode::<u64>(&[0, 1]),
+            Err(DecodeError::UnsupportedVersion(0))
+        );
+        assert_eq!(
+            decode::<u64>(&[LATEST + 1, 1]),
+            Err(DecodeError::UnsupportedVersion(LATEST + 1))
+        );
+        assert_eq!(
+            decode::<u64>(&[V1, 1, 2]),
+            Err(DecodeError::TrailingBytes(1))
+        );
+    }
+
+    /// Bytes written by the first format have to keep decoding to the same state, whatever
+    /// formats are added later
+    #[test]
+    fn v1_fixtures() {
+        let mut clock = VectorClock::default();
+        clock.insert(ReplicaId::new(1), 3);
+        clock.insert(ReplicaId::new(300), 1);
+        clock.retire(ReplicaId::new(7));
+        let bytes = [V1, 2, 1, 3, 0xac, 0x02, 1, 1, 7, 1];
+        assert_eq!(encode_v1(&clock), bytes);
+        assert_eq!(decode::<VectorClock>(&bytes).unwrap(), clock);
+
+        let mut set = AWORSet::<String>::default();
+        set.add(ReplicaId::new(1), "a".into());
+        set.add(ReplicaId::new(2), "b".into());
+        set.remove(&"a".into());
+        let (set, _) = set.split();
+        #[rustfmt::skip]
+        let bytes = [
+            V1,
+            // ctx: clock {1: 1, 2: 1}, no retired replicas, empty cloud
+            2, 1, 1, 2, 1, 0, 0,
+            // entries: dot 2:1 -> "b"
+            1, 2, 1, 1, b'b',
+            // no delta
+            0,
+        ];
+        assert_eq!(encode_v1(&set), bytes);
+        assert_eq!(decode::<AWORSet<String>>(&bytes).unwrap(), set);
+    }
+
+    #[test]
+    fn dot_cloud() {
+        let mut cloud = DotCloud::new();
+        cloud.insert_range(DotRange(ReplicaId::new(1), 3..10));
+        cloud.insert_range(DotRange(ReplicaId::new(1), 20..21));
+        cloud.insert(Dot(ReplicaId::new(u128::MAX), u64::MAX - 1));
+        round_trip(&cloud);
+
+        // Runs that would go past `u64::MAX`
+        assert_eq!(
+            decode::<DotCloud>(&[
+                V1, 1, 1, 1, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 2
+            ]),
+            Err(DecodeError::Invalid("dot range out of range"))
+        );
+        // Empty runs
+        assert_eq!(
+            decode::<DotCloud>(&[V1, 1, 1, 1, 0, 0]),
+            Err(DecodeError::Invalid("empty dot range"))
+        );
+    }
+
+    #[test]
+    fn awormap() {
+        let mut map = AWORMap::<String, i64>::default();
+        let (a, b) = (ReplicaId::new(1), ReplicaId::new(2));
+        map.insert(a, "x".into(), -5);
+        map.insert(b, "y".into(), 10);
+        map.remove(&"x".into());
+
+        let decoded = round_trip(&map);
+        // Equality of maps only looks at keys
+        assert_eq!(decoded.get(&"y".into()), Some(&10));
+        assert_eq!(decoded.get(&"x".into()), None);
+        assert_eq!(decoded.len(), 1);
+    }
+
+    proptest! {
+        #[test]
+        fn kernels(kernel in dotkernel_strategy(any::<i32>())) {
+            let bytes = encode_v1(&kernel);
+            prop_assert_eq!(decode(&bytes), Ok(kernel.clone()));
+            // Smaller than JSON, which is what the binary encoding is for
+            prop_assert!(bytes.len() <= serde_json::to_vec(&kernel).unwrap().len());
+        }
+
+        #[test]
+        fn sets(set in aworset_strategy()) {
+            prop_assert_eq!(decode(&encode_v1(&set)), Ok(set));
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! Hybrid logical clocks, from "Logical Physical Clocks and Consistent Snapshots in Globally
+//! Distributed Databases" (https://cse.buffalo.edu/tech-reports/2014-04.pdf)
+//!
+//! A timestamp is the wall clock time in milliseconds plus a logical counter that breaks ties
+//! between events in the same millisecond. When a replica sees a timestamp from someone else it
+//! moves its own clock past it, so anything it does afterwards is ordered after what it saw even
+//! if its wall clock is behind. The replica id comes last and makes the order total, which is
+//! what last-writer-wins types need to pick the same winner everywhere.
+//!
+//! The wall clock is whatever [`Clock`] an [`HlcClock`] was given, tests hand it a
+//! [`ManualClock`] to decide what time it is.
+use alloc::sync::Arc;
+use core::fmt;
+#[cfg(target_has_atomic = "64")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ReplicaId;
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde_derive::Serialize,
+    serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::hlc")
+)]
+pub struct Hlc {
+    /// Milliseconds since the unix epoch
+    pub physical: u64,
+    pub logical: u32,
+    pub replica: ReplicaId,
+}
+
+impl Hlc {
+    /// The smallest timestamp `replica` can produce
+    pub fn zero(replica: ReplicaId) -> Self {
+        Self {
+            physical: 0,
+            logical: 0,
+            replica,
+        }
+    }
+
+    /// The smallest timestamp of `replica` after `self`
+    pub fn tick(self, replica: ReplicaId) -> Self {
+        match self.logical.checked_add(1) {
+            Some(logical) => Self {
+                physical: self.physical,
+                logical,
+                replica,
+            },
+            None => Self {
+                physical: self.physical + 1,
+                logical: 0,
+                replica,
+            },
+        }
+    }
+}
+
+/// Source of wall clock time in milliseconds since the unix epoch
+pub trait Clock: fmt::Debug {
+    fn now_millis(&self) -> u64;
+}
+
+/// Clones of an `Arc` read the same clock, so it can be shared between the clocks of replicas
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now_millis(&self) -> u64 {
+        (**self).now_millis()
+    }
+}
+
+/// [`Clock`] backed by [`std::time::SystemTime`], which isn't available in wasm or without
+/// `std` where the host has to provide the time instead
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that only moves when told to. Clones share the time, so a test can keep one and hand
+/// the others out.
+#[cfg(target_has_atomic = "64")]
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock(Arc<AtomicU64>);
+
+#[cfg(target_has_atomic = "64")]
+impl ManualClock {
+    pub fn new(millis: u64) -> Self {
+        Self(Arc::new(millis.into()))
+    }
+
+    pub fn set(&self, millis: u64) {
+        self.0.store(millis, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.0.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+#[cfg(target_has_atomic = "64")]
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Hands out the timestamps of one replica. Every timestamp is greater than the ones it handed
+/// out before and than every timestamp passed to [`HlcClock::update`], no matter what the wall
+/// clock does.
+#[derive(Debug, Clone)]
+pub struct HlcClock<C: Clock> {
+    last: Hlc,
+    clock: C,
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl HlcClock<SystemClock> {
+    pub fn new(replica: ReplicaId) -> Self {
+        Self::with_clock(replica, SystemClock)
+    }
+}
+
+impl<C: Clock> HlcClock<C> {
+    pub fn with_clock(replica: ReplicaId, clock: C) -> Self {
+        Self {
+            last: Hlc::zero(replica),
+            clock,
+        }
+    }
+
+    pub fn replica(&self) -> ReplicaId {
+        self.last.replica
+    }
+
+    /// The last timestamp handed out
+    pub fn last(&self) -> Hlc {
+        self.last
+    }
+
+    /// Timestamp for a local event
+    pub fn now(&mut self) -> Hlc {
+        let physical = self.clock.now_millis();
+        self.last = if physical > self.last.physical {
+            Hlc {
+                physical,
+                logical: 0,
+                replica: self.last.replica,
+            }
+        } else {
+            self.last.tick(self.last.replica)
+        };
+        self.last
+    }
+
+    /// Moves the clock past `remote`, returning the timestamp for receiving it. Everything
+    /// stamped afterwards is ordered after `remote`.
+    pub fn update(&mut self, remote: &Hlc) -> Hlc {
+        let physical = self.clock.now_millis();
+        let replica = self.last.replica;
+        let latest = self.last.physical.max(remote.physical);
+
+        self.last = if physical > latest {
+            Hlc {
+                physical,
+                logical: 0,
+                replica,
+            }
+        } else {
+            // Whichever of the two is ahead decides the logical part
+            let ahead =
+                if (self.last.physical, self.last.logical) >= (remote.physical, remote.logical) {
+                    self.last
+                } else {
+                    *remote
+                };
+            ahead.tick(replica)
+        };
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ReplicaId;
+
+    use super::{Hlc, HlcClock, ManualClock};
+
+    const A: ReplicaId = ReplicaId(1);
+    const B: ReplicaId = ReplicaId(2);
+
+    fn manual_clock(replica: ReplicaId) -> (HlcClock<ManualClock>, ManualClock) {
+        let time = ManualClock::default();
+        (HlcClock::with_clock(replica, time.clone()), time)
+    }
+
+    #[test]
+    fn monotonic_when_wall_clock_goes_back() {
+        let (mut clock, time) = manual_clock(A);
+        time.set(100);
+        let first = clock.now();
+        let second = clock.now();
+        time.set(50);
+        let third = clock.now();
+        time.set(200);
+        let fourth = clock.now();
+
+        assert!(first < second && second < third && third < fourth);
+        assert_eq!((second.physical, second.logical), (100, 1));
+        assert_eq!((third.physical, third.logical), (100, 2));
+        assert_eq!((fourth.physical, fourth.logical), (200, 0));
+    }
+
+    #[test]
+    fn update_moves_past_remote() {
+        let (mut a, a_time) = manual_clock(A);
+        let (mut b, b_time) = manual_clock(B);
+        // B's wall clock is far behind
+        a_time.set(1000);
+        b_time.set(10);
+
+        let remote = a.now();
+        let received = b.update(&remote);
+        assert!(received > remote);
+        assert!(b.now() > remote);
+
+        // Once B's wall clock catches up it takes over again
+        b_time.set(2000);
+        assert_eq!(
+            b.update(&remote),
+            Hlc {
+                physical: 2000,
+                logical: 0,
+                replica: B
+            }
+        );
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let (mut clock, time) = manual_clock(A);
+        time.set(42);
+        let hlc = clock.now();
+
+        let json = serde_json::to_string(&hlc).unwrap();
+        assert_eq!(json, r#"{"physical":42,"logical":0,"replica":"1"}"#);
+        assert_eq!(serde_json::from_str::<Hlc>(&json).unwrap(), hlc);
+    }
+}
@@ -0,0 +1,7 @@
+//! Bridges to other CRDT libraries, so replicas can sync with documents kept by them.
+//!
+//! The other libraries have their own semantics, so only what maps onto ours is carried over.
+//! Values cross over as JSON through their serde representation.
+
+#[cfg(feature = "automerge")]
+pub mod automerge;
@@ -0,0 +1,490 @@
+//! Syncing with [Automerge](https://automerge.org) documents.
+//!
+//! [`AutomergeBridge`] mirrors an [`AWORMap`] into the root map of an Automerge document and
+//! speaks the Automerge sync protocol for it, so Automerge peers see every key of the map as a
+//! key of their document and whatever they change in it is merged back in. Keys that are strings
+//! are used as is, other keys as their JSON. Values become Automerge objects, lists and scalars
+//! through their JSON and are read back the same way, Automerge text and counters are read as
+//! strings and numbers.
+//!
+//! Conflicts don't carry over: each side resolves its concurrent values its own way and the mirror
+//! only holds the value [`AWORMap::get`] picks. A value written by a peer that doesn't deserialize
+//! to `V` is left out of the map.
+//!
+//! [`export_document`] writes a whole [`Document`] into a new Automerge document, one field per
+//! root key. Counters become Automerge counters, registers the greatest of their values and grids
+//! lists of rows, each cell picked like a register.
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+
+use ::automerge::sync::{self, Message, ReadMessageError, SyncDoc};
+use ::automerge::transaction::Transactable;
+use ::automerge::{
+    AutoCommit, AutomergeError, ObjId, ObjType, Prop, ReadDoc, ScalarValue, Value as AmValue, ROOT,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Number, Value as JsonValue};
+
+use crate::delta_state::awormap::AWORMap;
+use crate::delta_state::document::{Crdt, Document};
+use crate::{ReplicaId, Value};
+
+#[derive(Debug)]
+pub enum InteropError {
+    Automerge(AutomergeError),
+    Message(ReadMessageError),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for InteropError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InteropError::Automerge(err) => write!(f, "automerge: {err}"),
+            InteropError::Message(err) => write!(f, "invalid sync message: {err}"),
+            InteropError::Json(err) => write!(f, "value doesn't convert to JSON: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for InteropError {}
+
+impl From<AutomergeError> for InteropError {
+    fn from(err: AutomergeError) -> Self {
+        InteropError::Automerge(err)
+    }
+}
+
+impl From<ReadMessageError> for InteropError {
+    fn from(err: ReadMessageError) -> Self {
+        InteropError::Message(err)
+    }
+}
+
+impl From<serde_json::Error> for InteropError {
+    fn from(err: serde_json::Error) -> Self {
+        InteropError::Json(err)
+    }
+}
+
+/// An Automerge document mirroring an [`AWORMap`], see the [module docs](self)
+pub struct AutomergeBridge {
+    doc: AutoCommit,
+    /// The root of the mirror as of the last export or sync, to tell what peers changed
+    seen: BTreeMap<String, JsonValue>,
+}
+
+impl Default for AutomergeBridge {
+    fn default() -> Self {
+        Self::new(AutoCommit::new())
+    }
+}
+
+impl AutomergeBridge {
+    /// Mirrors into `doc`, whatever it already holds is merged into the map with the next
+    /// [`AutomergeBridge::receive_sync_message`] or [`AutomergeBridge::ingest`]
+    pub fn new(doc: AutoCommit) -> Self {
+        Self {
+            doc,
+            seen: BTreeMap::new(),
+        }
+    }
+
+    pub fn doc(&mut self) -> &mut AutoCommit {
+        &mut self.doc
+    }
+
+    /// The mirror as an Automerge document
+    pub fn save(&mut self) -> Vec<u8> {
+        self.doc.save()
+    }
+
+    /// Writes the keys of `map` that changed since the last export or sync into the mirror as one
+    /// Automerge change
+    pub fn export<K, V>(&mut self, map: &AWORMap<K, V>) -> Result<(), InteropError>
+    where
+        K: Clone + PartialEq + Ord + Value + Serialize,
+        V: Clone + PartialEq + Value + Serialize,
+    {
+        let mut current = BTreeMap::new();
+        for (key, val) in map.iter() {
+            current.insert(key_to_string(key)?, serde_json::to_value(val)?);
+        }
+
+        for (key, val) in current.iter() {
+            if self.seen.get(key) != Some(val) {
+                write(&mut self.doc, &ROOT, key.as_str().into(), val)?;
+            }
+        }
+        for key in self.seen.keys().filter(|key| !current.contains_key(*key)) {
+            self.doc.delete(ROOT, key.as_str())?;
+        }
+        self.doc.commit();
+        self.seen = current;
+        Ok(())
+    }
+
+    /// Next message for the peer whose sync state is `state`, if it's missing anything
+    pub fn generate_sync_message(&mut self, state: &mut sync::State) -> Option<Vec<u8>> {
+        self.doc
+            .sync()
+            .generate_sync_message(state)
+            .map(Message::encode)
+    }
+
+    /// Applies a message from the peer whose sync state is `state` to the mirror and merges what
+    /// it changed into `map` as `replica`. Returns the keys whose values couldn't be read as `V`.
+    pub fn receive_sync_message<K, V>(
+        &mut self,
+        state: &mut sync::State,
+        message: &[u8],
+        map: &mut AWORMap<K, V>,
+        replica: ReplicaId,
+    ) -> Result<Vec<String>, InteropError>
+    where
+        K: Clone + PartialEq + Ord + Value + DeserializeOwned,
+        V: Clone + PartialEq + Value + DeserializeOwned,
+    {
+        let message = Message::decode(message)?;
+        self.doc.sync().receive_sync_message(state, message)?;
+        self.ingest(map, replica)
+    }
+
+    /// Merges whatever changed in the mirror since the last export or sync into `map` as
+    /// `replica`, for changes that came in some other way than the sync protocol. Returns the keys
+    /// whose values couldn't be read as `V`.
+    pub fn ingest<K, V>(
+        &mut self,
+        map: &mut AWORMap<K, V>,
+        replica: ReplicaId,
+    ) -> Result<Vec<String>, InteropError>
+    where
+        K: Clone + PartialEq + Ord + Value + DeserializeOwned,
+        V: Clone + PartialEq + Value + DeserializeOwned,
+    {
+        let mut now = BTreeMap::new();
+        for key in self.doc.keys(ROOT) {
+            let val = read(&self.doc, &ROOT, key.as_str().into())?;
+            now.insert(key, val);
+        }
+
+        let mut skipped = vec![];
+        for (key, val) in now.iter() {
+            if self.seen.get(key) == Some(val) {
+                continue;
+            }
+            match (
+                key_from_string::<K>(key),
+                serde_json::from_value(val.clone()),
+            ) {
+                (Some(k), Ok(v)) => map.insert(replica, k, v),
+                _ => skipped.push(key.clone()),
+            }
+        }
+        for key in self.seen.keys().filter(|key| !now.contains_key(*key)) {
+            if let Some(k) = key_from_string::<K>(key) {
+                map.remove(&k);
+            }
+        }
+        self.seen = now;
+        Ok(skipped)
+    }
+}
+
+/// `document` as a new Automerge document, see the [module docs](self)
+pub fn export_document<K, V>(document: &Document<K, V>) -> Result<AutoCommit, InteropError>
+where
+    K: Clone + PartialEq + Ord + Value + Serialize,
+    V: Clone + PartialEq + Ord + Value + Serialize,
+{
+    let mut doc = AutoCommit::new();
+    for (name, crdt) in document.fields() {
+        let prop: Prop = name.into();
+        match crdt {
+            Crdt::Counter(counter) => {
+                doc.put(ROOT, prop, ScalarValue::counter(counter.value()))?;
+            }
+            Crdt::Register(register) => {
+                let val = match register.value().into_iter().next_back() {
+                    Some(val) => serde_json::to_value(val)?,
+                    None => JsonValue::Null,
+                };
+                write(&mut doc, &ROOT, prop, &val)?;
+            }
+            Crdt::Set(set) => {
+                let vals = set
+                    .iter()
+                    .map(serde_json::to_value)
+                    .collect::<Result<_, _>>()?;
+                write(&mut doc, &ROOT, prop, &JsonValue::Array(vals))?;
+            }
+            Crdt::Map(map) => {
+                let mut obj = Map::new();
+                for (key, val) in map.iter() {
+                    obj.insert(key_to_string(key)?, serde_json::to_value(val)?);
+                }
+                write(&mut doc, &ROOT, prop, &JsonValue::Object(obj))?;
+            }
+            Crdt::List(list) => {
+                let vals = list
+                    .iter()
+                    .map(serde_json::to_value)
+                    .collect::<Result<_, _>>()?;
+                write(&mut doc, &ROOT, prop, &JsonValue::Array(vals))?;
+            }
+            Crdt::Grid(grid) => {
+                let mut rows = Vec::new();
+                for row in grid.values() {
+                    let cells = (row.into_iter())
+                        .map(|cell| match cell.into_iter().next_back() {
+                            Some(val) => serde_json::to_value(val),
+                            None => Ok(JsonValue::Null),
+                        })
+                        .collect::<Result<_, _>>()?;
+                    rows.push(JsonValue::Array(cells));
+                }
+                write(&mut doc, &ROOT, prop, &JsonValue::Array(rows))?;
+            }
+        }
+    }
+    doc.commit();
+    Ok(doc)
+}
+
+fn key_to_string<K: Serialize>(key: &K) -> Result<String, InteropError> {
+    Ok(match serde_json::to_value(key)? {
+        JsonValue::String(key) => key,
+        key => key.to_string(),
+    })
+}
+
+fn key_from_string<K: DeserializeOwned>(key: &str) -> Option<K> {
+    serde_json::from_value(JsonValue::String(key.into()))
+        .ok()
+        .or_else(|| serde_json::from_str(key).ok())
+}
+
+/// Puts `val` at `prop` of `obj`, inserting it if `obj` is a list
+fn write(
+    doc: &mut AutoCommit,
+    obj: &ObjId,
+    prop: Prop,
+    val: &JsonValue,
+) -> Result<(), InteropError> {
+    let insert = matches!(doc.object_type(obj)?, ObjType::List);
+    let scalar = match val {
+        JsonValue::Null => ScalarValue::Null,
+        JsonValue::Bool(b) => ScalarValue::Boolean(*b),
+        JsonValue::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(n), _) => ScalarValue::Int(n),
+            (None, Some(n)) => ScalarValue::Uint(n),
+            _ => ScalarValue::F64(n.as_f64().unwrap_or_default()),
+        },
+        JsonValue::String(s) => ScalarValue::Str(s.as_str().into()),
+        JsonValue::Array(vals) => {
+            let child = object(doc, obj, prop, ObjType::List, insert)?;
+            for (i, val) in vals.iter().enumerate() {
+                write(doc, &child, i.into(), val)?;
+            }
+            return Ok(());
+        }
+        JsonValue::Object(vals) => {
+            let child = object(doc, obj, prop, ObjType::Map, insert)?;
+            for (key, val) in vals {
+                write(doc, &child, key.as_str().into(), val)?;
+            }
+            return Ok(());
+        }
+    };
+    match (insert, prop) {
+        (true, Prop::Seq(i)) => doc.insert(obj, i, scalar)?,
+        (_, prop) => doc.put(obj, prop, scalar)?,
+    }
+    Ok(())
+}
+
+fn object(
+    doc: &mut AutoCommit,
+    obj: &ObjId,
+    prop: Prop,
+    kind: ObjType,
+    insert: bool,
+) -> Result<ObjId, InteropError> {
+    Ok(match (insert, prop) {
+        (true, Prop::Seq(i)) => doc.insert_object(obj, i, kind)?,
+        (_, prop) => doc.put_object(obj, prop, kind)?,
+    })
+}
+
+/// The value at `prop` of `obj` as JSON, text reads as a string and counters as numbers
+fn read(doc: &AutoCommit, obj: &ObjId, prop: Prop) -> Result<JsonValue, InteropError> {
+    let Some((val, id)) = doc.get(obj, prop)? else {
+        return Ok(JsonValue::Null);
+    };
+    Ok(match val {
+        AmValue::Object(ObjType::Map | ObjType::Table) => {
+            let mut vals = Map::new();
+            for key in doc.keys(&id) {
+                let val = read(doc, &id, key.as_str().into())?;
+                vals.insert(key, val);
+            }
+            JsonValue::Object(vals)
+        }
+        AmValue::Object(ObjType::List) => {
+            let vals = (0..doc.length(&id))
+                .map(|i| read(doc, &id, i.into()))
+                .collect::<Result<_, _>>()?;
+            JsonValue::Array(vals)
+        }
+        AmValue::Object(ObjType::Text) => JsonValue::String(doc.text(&id)?),
+        AmValue::Scalar(scalar) => match scalar.as_ref() {
+            ScalarValue::Str(s) => JsonValue::String(s.to_string()),
+            ScalarValue::Int(n) | ScalarValue::Timestamp(n) => (*n).into(),
+            ScalarValue::Uint(n) => (*n).into(),
+            ScalarValue::F64(n) => Number::from_f64(*n).map_or(JsonValue::Null, JsonValue::Number),
+            ScalarValue::Counter(n) => i64::from(n).into(),
+            ScalarValue::Boolean(b) => JsonValue::Bool(*b),
+            ScalarValue::Bytes(bytes) => bytes.clone().into(),
+            ScalarValue::Unknown { .. } | ScalarValue::Null => JsonValue::Null,
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use ::automerge::sync::{self, SyncDoc};
+    use ::automerge::transaction::Transactable;
+    use ::automerge::{AutoCommit, ObjType, ReadDoc, ScalarValue, ROOT};
+
+    use crate::delta_state::awormap::AWORMap;
+    use crate::delta_state::document::Document;
+    use crate::ReplicaId;
+
+    use super::{export_document, read, AutomergeBridge};
+
+    /// Syncs the bridge with an Automerge peer until neither has anything to send, returns the
+    /// keys that were skipped
+    fn sync(
+        bridge: &mut AutomergeBridge,
+        peer: &mut AutoCommit,
+        map: &mut AWORMap<String, i64>,
+    ) -> Vec<String> {
+        let (mut ours, mut theirs) = (sync::State::new(), sync::State::new());
+        let mut skipped = vec![];
+        loop {
+            let to_peer = bridge.generate_sync_message(&mut ours);
+            if let Some(msg) = &to_peer {
+                let msg = sync::Message::decode(msg).unwrap();
+                peer.sync().receive_sync_message(&mut theirs, msg).unwrap();
+            }
+            let to_us = peer.sync().generate_sync_message(&mut theirs);
+            if let Some(msg) = &to_us {
+                skipped.extend(
+                    bridge
+                        .receive_sync_message(
+                            &mut ours,
+                            &msg.clone().encode(),
+                            map,
+                            ReplicaId::from(2),
+                        )
+                        .unwrap(),
+                );
+            }
+            if to_peer.is_none() && to_us.is_none() {
+                return skipped;
+            }
+        }
+    }
+
+    fn int(doc: &AutoCommit, key: &str) -> Option<i64> {
+        read(doc, &ROOT, key.into()).unwrap().as_i64()
+    }
+
+    #[test]
+    fn syncs_both_ways() {
+        let a = ReplicaId::from(1);
+        let mut map = AWORMap::<String, i64>::default();
+        map.insert(a, "x".into(), 1);
+        map.insert(a, "y".into(), 2);
+
+        let mut bridge = AutomergeBridge::default();
+        bridge.export(&map).unwrap();
+
+        let mut peer = AutoCommit::new();
+        // Can't be an i64 so it stays out of the map
+        peer.put(ROOT, "title", "squares").unwrap();
+        peer.commit();
+        assert_eq!(sync(&mut bridge, &mut peer, &mut map), vec!["title"]);
+        assert_eq!((int(&peer, "x"), int(&peer, "y")), (Some(1), Some(2)));
+        assert!(!map.contains_key(&"title".to_string()));
+
+        peer.put(ROOT, "x", 10).unwrap();
+        peer.delete(ROOT, "y").unwrap();
+        peer.put(ROOT, "z", ScalarValue::counter(3)).unwrap();
+        peer.commit();
+        sync(&mut bridge, &mut peer, &mut map);
+        assert_eq!(map.get(&"x".to_string()), Some(&10));
+        assert!(!map.contains_key(&"y".to_string()));
+        assert_eq!(map.get(&"z".to_string()), Some(&3));
+
+        map.remove(&"x".to_string());
+        map.insert(a, "w".into(), 4);
+        bridge.export(&map).unwrap();
+        sync(&mut bridge, &mut peer, &mut map);
+        assert_eq!((int(&peer, "x"), int(&peer, "w")), (None, Some(4)));
+
+        // A document saved from the bridge loads in Automerge
+        let saved = AutoCommit::load(&bridge.save()).unwrap();
+        assert_eq!(int(&saved, "w"), Some(4));
+    }
+
+    #[test]
+    fn exports_documents() {
+        let a = ReplicaId::from(1);
+        let mut document = Document::<String, String>::default();
+        document.counter("likes").unwrap().increment(a);
+        document.counter("likes").unwrap().increment(a);
+        document.register("title").unwrap().set(a, "squares".into());
+        document
+            .map("colors")
+            .unwrap()
+            .insert(a, "a".into(), "red".into());
+        document.list("log").unwrap().push(a, "created".into());
+        document.set("tags").unwrap().add(a, "demo".into());
+        let table = document.grid("table").unwrap();
+        table.insert_row(a, 0);
+        table.insert_column(a, 0);
+        table.insert_column(a, 1);
+        table.set(a, 0, 1, "x".into());
+
+        let doc = export_document(&document).unwrap();
+        let (likes, _) = doc.get(ROOT, "likes").unwrap().unwrap();
+        assert!(matches!(likes.to_scalar(), Some(ScalarValue::Counter(_))));
+        assert_eq!(
+            read(&doc, &ROOT, "likes".into()).unwrap(),
+            serde_json::json!(2)
+        );
+        assert_eq!(
+            read(&doc, &ROOT, "title".into()).unwrap(),
+            serde_json::json!("squares")
+        );
+        assert_eq!(
+            read(&doc, &ROOT, "colors".into()).unwrap(),
+            serde_json::json!({"a": "red"})
+        );
+        assert_eq!(
+            read(&doc, &ROOT, "log".into()).unwrap(),
+            serde_json::json!(["created"])
+        );
+        assert_eq!(
+            read(&doc, &ROOT, "table".into()).unwrap(),
+            serde_json::json!([[null, "x"]])
+        );
+        assert_eq!(
+            read(&doc, &ROOT, "tags".into()).unwrap(),
+            serde_json::json!(["demo"])
+        );
+        let (_, colors) = doc.get(ROOT, "colors").unwrap().unwrap();
+        assert_eq!(doc.object_type(&colors).unwrap(), ObjType::Map);
+    }
+}
@@ -1,37 +1,157 @@
-#![feature(option_get_or_insert_default)]
-#![feature(btree_drain_filter)]
+//! Delta state and state based CRDTs.
+//!
+//! Without the default `std` feature the crate is `no_std` and only needs `alloc`, which leaves
+//! out the system clock and the `HashMap` views.
+#![cfg_attr(not(feature = "std"), no_std)]
 
-use std::str::FromStr;
+extern crate alloc;
 
-use fp_bindgen::prelude::Serializable;
+use alloc::string::String;
+use core::fmt;
+use core::str::FromStr;
 
+use encoding::{Decode, DecodeError, Encode, Reader, Writer};
+
+#[cfg(feature = "wasm")]
+use fp_bindgen::prelude::{Serializable, Type, TypeIdent, TypeMap};
+
+pub mod checksum;
 pub mod delta_state;
+pub mod encoding;
+pub mod hlc;
+pub mod interop;
+pub mod memory;
 pub mod state;
 
-#[derive(
-    Copy,
-    Clone,
-    Debug,
-    PartialEq,
-    Eq,
-    PartialOrd,
-    Ord,
-    Default,
-    fp_bindgen::prelude::Serializable,
-    serde_derive::Serialize,
-    serde_derive::Deserialize,
-)]
-#[fp(rust_plugin_module = "sypytkowski_convergent")]
-pub struct ReplicaId(u64);
+/// Identifies a replica.
+///
+/// 128 bits so replicas can pick their own id at random without coordinating, see
+/// [`ReplicaIdProvider`]. Serialized as a decimal string since neither JS numbers nor msgpack
+/// integers can hold 128 bits, integers are still accepted when deserializing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ReplicaId(u128);
+
+impl ReplicaId {
+    pub fn new(id: u128) -> Self {
+        Self(id)
+    }
+
+    pub fn as_u128(&self) -> u128 {
+        self.0
+    }
+
+    /// A random (v4 UUID) id, not available in wasm or on bare metal where the host should mint
+    /// ids instead
+    #[cfg(not(any(target_arch = "wasm32", target_os = "none")))]
+    pub fn random() -> Self {
+        uuid::Uuid::new_v4().into()
+    }
+}
 
 impl From<u64> for ReplicaId {
     fn from(val: u64) -> Self {
-        Self(val)
+        Self(val as u128)
+    }
+}
+
+impl From<uuid::Uuid> for ReplicaId {
+    fn from(uuid: uuid::Uuid) -> Self {
+        Self(uuid.as_u128())
+    }
+}
+
+impl From<ReplicaId> for uuid::Uuid {
+    fn from(ReplicaId(id): ReplicaId) -> Self {
+        uuid::Uuid::from_u128(id)
+    }
+}
+
+impl FromStr for ReplicaId {
+    type Err = core::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+impl fmt::Display for ReplicaId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl serde::Serialize for ReplicaId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+struct ReplicaIdVisitor;
+impl<'de> serde::de::Visitor<'de> for ReplicaIdVisitor {
+    type Value = ReplicaId;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "ReplicaId")
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_u128<E: serde::de::Error>(self, v: u128) -> Result<Self::Value, E> {
+        Ok(ReplicaId(v))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.parse().map_err(E::custom)
     }
 }
 
+impl<'de> serde::Deserialize<'de> for ReplicaId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ReplicaIdVisitor)
+    }
+}
+
+impl Encode for ReplicaId {
+    fn encode(&self, out: &mut Writer) {
+        self.0.encode(out)
+    }
+}
+
+impl Decode for ReplicaId {
+    fn decode(input: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        u128::decode(input).map(Self)
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Serializable for ReplicaId {
+    fn ident() -> TypeIdent {
+        TypeIdent::from("ReplicaId")
+    }
+
+    /// There is no 128 bit primitive, to plugins it is the string it is serialized as
+    fn ty() -> Type {
+        Type::from_item(
+            r#"#[fp(rust_plugin_module = "sypytkowski_convergent")] pub struct ReplicaId(String);"#,
+        )
+    }
+
+    fn collect_types(types: &mut TypeMap) {
+        types.entry(Self::ident()).or_insert_with(Self::ty);
+        String::collect_types(types);
+    }
+}
+
+/// Mints ids for new replicas
+pub trait ReplicaIdProvider {
+    fn next_id(&mut self) -> ReplicaId;
+}
+
+/// Hands out sequential ids, only safe when a single party assigns every replica's id
 pub struct ReplicaGenerator {
-    count: u64,
+    count: u128,
 }
 
 impl ReplicaGenerator {
@@ -46,7 +166,31 @@ impl ReplicaGenerator {
     }
 }
 
+impl ReplicaIdProvider for ReplicaGenerator {
+    fn next_id(&mut self) -> ReplicaId {
+        self.gen()
+    }
+}
+
+/// Random ids for replicas that pick their own, collisions are as unlikely as for v4 UUIDs
+#[cfg(not(any(target_arch = "wasm32", target_os = "none")))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomReplicaIds;
+
+#[cfg(not(any(target_arch = "wasm32", target_os = "none")))]
+impl ReplicaIdProvider for RandomReplicaIds {
+    fn next_id(&mut self) -> ReplicaId {
+        ReplicaId::random()
+    }
+}
+
+/// What CRDTs can hold, with the `wasm` feature values also have to cross over to plugins. Only
+/// then do they need to be `Serializable`, so types that should work either way derive it with
+/// `#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]`.
+#[cfg(feature = "wasm")]
 pub trait Value: fp_bindgen::prelude::Serializable {}
+#[cfg(not(feature = "wasm"))]
+pub trait Value: 'static {}
 
 macro_rules! impl_value {
     ($($t:ty),*) => {
@@ -57,3 +201,34 @@ macro_rules! impl_value {
 }
 
 impl_value!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64, String, bool);
+
+impl Value for () {}
+
+impl<T: Value> Value for Option<T> {}
+
+#[cfg(test)]
+mod test {
+    use crate::delta_state::dot::{Dot, VectorClock};
+    use crate::{RandomReplicaIds, ReplicaId, ReplicaIdProvider};
+
+    #[test]
+    fn replica_id_serde() {
+        let id = RandomReplicaIds.next_id();
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", id.as_u128()));
+        assert_eq!(serde_json::from_str::<ReplicaId>(&json).unwrap(), id);
+        assert_eq!(uuid::Uuid::from(id).as_u128(), id.as_u128());
+
+        // Ids serialized before they were 128 bits
+        assert_eq!(serde_json::from_str::<ReplicaId>("5").unwrap(), 5u64.into());
+
+        let dot = Dot(id, 3);
+        let json = serde_json::to_string(&dot).unwrap();
+        assert_eq!(serde_json::from_str::<Dot>(&json).unwrap(), dot);
+
+        let mut clock = VectorClock::default();
+        clock.insert(id, 3);
+        let json = serde_json::to_string(&clock).unwrap();
+        assert_eq!(serde_json::from_str::<VectorClock>(&json).unwrap(), clock);
+    }
+}
@@ -0,0 +1,102 @@
+//! How much the CRDTs hold on to, for deciding when compacting them is worth it.
+//!
+//! Sizes are estimates: every entry of a map or set counts its own size plus a share of the B-tree
+//! node it sits in, nodes being about two thirds full on average. What values allocate
+//! themselves, like the contents of a `String`, isn't counted.
+use alloc::boxed::Box;
+use core::mem::size_of;
+use core::ops::{Add, AddAssign};
+
+/// Entries kept and the heap they take up, see [`MemoryUsage`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub entries: usize,
+    pub heap_bytes: usize,
+}
+
+impl Usage {
+    /// `len` entries of a B-tree map from `K` to `V`, or a set of `K` with `V = ()`
+    pub fn btree<K, V>(len: usize) -> Self {
+        Self {
+            entries: len,
+            heap_bytes: len * (size_of::<K>() + size_of::<V>()) * 3 / 2,
+        }
+    }
+
+    /// `len` entries of a `Vec<T>`, the spare capacity isn't counted
+    pub fn vec<T>(len: usize) -> Self {
+        Self {
+            entries: len,
+            heap_bytes: len * size_of::<T>(),
+        }
+    }
+}
+
+impl Add for Usage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            entries: self.entries + other.entries,
+            heap_bytes: self.heap_bytes + other.heap_bytes,
+        }
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl core::iter::Sum for Usage {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+/// What a CRDT keeps, its pending delta and metadata included
+pub trait MemoryUsage {
+    fn memory_usage(&self) -> Usage;
+}
+
+impl<T: MemoryUsage> MemoryUsage for Option<T> {
+    fn memory_usage(&self) -> Usage {
+        self.as_ref().map(T::memory_usage).unwrap_or_default()
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for Box<T> {
+    fn memory_usage(&self) -> Usage {
+        let boxed = Usage {
+            entries: 0,
+            heap_bytes: size_of::<T>(),
+        };
+        boxed + T::memory_usage(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::delta_state::aworset::AWORSet;
+    use crate::ReplicaId;
+
+    use super::{MemoryUsage, Usage};
+
+    #[test]
+    fn acked_deltas_stop_counting() {
+        let mut set = AWORSet::<i32>::default();
+        for value in 0..3 {
+            set.add(ReplicaId::new(1), value);
+        }
+        // The entries and the clock of the replica, once in the set and once in the delta
+        let usage = set.memory_usage();
+        assert_eq!(usage.entries, 8);
+
+        let acked = set.kernel.ctx.clock.clone();
+        set.compact_deltas(&acked);
+        assert_eq!(set.memory_usage().entries, 4);
+        assert!(set.memory_usage().heap_bytes < usage.heap_bytes);
+        assert_eq!(AWORSet::<i32>::default().memory_usage(), Usage::default());
+    }
+}
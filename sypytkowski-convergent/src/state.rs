@@ -1,6 +1,6 @@
 pub mod awormap;
 pub mod grow_counter;
-pub mod list;
+pub mod monotonic;
 pub mod or_set;
 pub mod pn_counter;
 pub mod vector_clock;
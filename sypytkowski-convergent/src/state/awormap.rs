@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use alloc::collections::BTreeMap;
 
 use crate::{
     delta_state::{aworset::AWORSet, convergent::Convergent},
@@ -6,17 +6,12 @@ use crate::{
 };
 
 #[derive(Clone, Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
-pub struct AWORMap<
-    K: Clone + PartialEq + Default + std::fmt::Debug + std::cmp::Ord + Value,
-    V: Value,
-> {
+pub struct AWORMap<K: Clone + PartialEq + core::cmp::Ord + Value, V: Value> {
     pub(crate) keys: AWORSet<K>,
     pub(crate) entries: BTreeMap<K, V>,
 }
 
-impl<K: Clone + PartialEq + Default + std::fmt::Debug + std::cmp::Ord + Value, V: Value> Default
-    for AWORMap<K, V>
-{
+impl<K: Clone + PartialEq + core::cmp::Ord + Value, V: Value> Default for AWORMap<K, V> {
     fn default() -> Self {
         Self {
             keys: Default::default(),
@@ -25,11 +20,7 @@ impl<K: Clone + PartialEq + Default + std::fmt::Debug + std::cmp::Ord + Value, V
     }
 }
 
-impl<
-        K: Clone + PartialEq + Default + std::fmt::Debug + std::cmp::Ord + Value,
-        V: Convergent + Clone + std::fmt::Debug + Value,
-    > AWORMap<K, V>
-{
+impl<K: Clone + PartialEq + core::cmp::Ord + Value, V: Convergent + Clone + Value> AWORMap<K, V> {
     pub fn value(&self) -> &BTreeMap<K, V> {
         &self.entries
     }
@@ -127,7 +118,7 @@ mod test {
             for map in awormaps.iter_mut() {
                 // Above will delete keys so prune them from entries as well
                 let keys = map.keys.value();
-                map.entries.drain_filter(|k, _| !keys.contains(k));
+                map.entries.retain(|k, _| keys.contains(k));
             }
         }
 
@@ -1,16 +1,37 @@
-use std::{
-    collections::{btree_map::Entry, BTreeMap},
-    ops::Deref,
-};
+use alloc::collections::{btree_map::Entry, BTreeMap};
+use core::ops::Deref;
 
+use crate::memory::{MemoryUsage, Usage};
 use crate::ReplicaId;
 
+/// Numbers a [`GrowCounter`] can count with. Only unsigned ones, a count never goes down.
+pub trait Count: Copy + Ord + Default {
+    const ONE: Self;
+
+    /// Counts stop at the largest number rather than wrapping around to a smaller one
+    fn saturating_add(self, other: Self) -> Self;
+}
+
+macro_rules! impl_count {
+    ($($ty:ty),*) => {
+        $(impl Count for $ty {
+            const ONE: Self = 1;
+
+            fn saturating_add(self, other: Self) -> Self {
+                <$ty>::saturating_add(self, other)
+            }
+        })*
+    };
+}
+
+impl_count!(u8, u16, u32, u64, u128, usize);
+
 #[derive(Clone, Debug, PartialEq)]
-pub struct GrowCounter {
-    map: BTreeMap<ReplicaId, i64>,
+pub struct GrowCounter<N = u64> {
+    map: BTreeMap<ReplicaId, N>,
 }
 
-impl GrowCounter {
+impl<N: Count> GrowCounter<N> {
     pub fn new() -> Self {
         Self {
             map: Default::default(),
@@ -19,22 +40,30 @@ impl GrowCounter {
 
     pub fn from_iter<T>(iter: T) -> Self
     where
-        T: IntoIterator<Item = (ReplicaId, i64)>,
+        T: IntoIterator<Item = (ReplicaId, N)>,
     {
         Self {
-            map: BTreeMap::<ReplicaId, i64>::from_iter(iter),
+            map: BTreeMap::<ReplicaId, N>::from_iter(iter),
         }
     }
 
-    pub fn value(&self) -> i64 {
-        self.map.values().fold(0, |acc, val| acc + val)
+    pub fn value(&self) -> N {
+        (self.map.values()).fold(N::default(), |acc, &count| acc.saturating_add(count))
+    }
+
+    /// Adds one to `replica`'s count, returns the new count
+    pub fn increment(&mut self, replica: ReplicaId) -> N {
+        self.increment_by(replica, N::ONE)
     }
 
-    pub fn increment(&mut self, replica: ReplicaId) -> i64 {
-        *self.map.entry(replica).or_insert(0)
+    /// Adds `n` to `replica`'s count, returns the new count
+    pub fn increment_by(&mut self, replica: ReplicaId, n: N) -> N {
+        let count = self.map.entry(replica).or_default();
+        *count = count.saturating_add(n);
+        *count
     }
 
-    pub fn merge(&self, other: &Self) -> GrowCounter {
+    pub fn merge(&self, other: &Self) -> Self {
         self.map
             .iter()
             .fold(other.clone(), |mut acc, (&key, &value)| {
@@ -51,22 +80,22 @@ impl GrowCounter {
             })
     }
 
-    pub fn from_u64_map(map: BTreeMap<u64, i64>) -> Self {
+    pub fn from_u64_map(map: BTreeMap<u64, N>) -> Self {
         Self {
-            map: map.into_iter().map(|(k, v)| (ReplicaId(k), v)).collect(),
+            map: map.into_iter().map(|(k, v)| (k.into(), v)).collect(),
         }
     }
 }
 
-impl Deref for GrowCounter {
-    type Target = BTreeMap<ReplicaId, i64>;
+impl<N> Deref for GrowCounter<N> {
+    type Target = BTreeMap<ReplicaId, N>;
 
     fn deref(&self) -> &Self::Target {
         &self.map
     }
 }
 
-impl Default for GrowCounter {
+impl<N> Default for GrowCounter<N> {
     fn default() -> Self {
         Self {
             map: Default::default(),
@@ -74,6 +103,12 @@ impl Default for GrowCounter {
     }
 }
 
+impl<N> MemoryUsage for GrowCounter<N> {
+    fn memory_usage(&self) -> Usage {
+        Usage::btree::<ReplicaId, N>(self.map.len())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::BTreeMap;
@@ -81,10 +116,63 @@ mod test {
     use proptest::prelude::*;
 
     use crate::state::grow_counter::GrowCounter;
+    use crate::ReplicaId;
+
+    #[test]
+    fn increment() {
+        let (a, b) = (ReplicaId::from(0), ReplicaId::from(1));
+        let mut counter = GrowCounter::<u64>::new();
+        assert_eq!(counter.value(), 0);
+
+        assert_eq!(counter.increment(a), 1);
+        assert_eq!(counter.increment(a), 2);
+        assert_eq!(counter.increment(b), 1);
+        assert_eq!(counter.value(), 3);
+
+        assert_eq!(counter.increment_by(b, 10), 11);
+        assert_eq!(counter.value(), 13);
+        assert_eq!(counter.get(&a), Some(&2));
+    }
+
+    #[test]
+    fn saturates_at_the_largest_count() {
+        let (a, b) = (ReplicaId::from(0), ReplicaId::from(1));
+        let mut counter = GrowCounter::<u8>::new();
+        assert_eq!(counter.increment_by(a, u8::MAX - 1), u8::MAX - 1);
+        assert_eq!(counter.increment(a), u8::MAX);
+        assert_eq!(counter.increment(a), u8::MAX);
+        counter.increment(b);
+        assert_eq!(counter.value(), u8::MAX);
+    }
 
     proptest! {
         #[test]
-        fn commutativity(mut a: BTreeMap<u64, i64>, mut b: BTreeMap<u64, i64>) {
+        fn increments_add_up(increments in proptest::collection::vec((0..4u64, 0..1000u32), 0..50)) {
+            let mut counter = GrowCounter::<u32>::new();
+            let mut total = 0;
+            for (replica, n) in increments {
+                let before = counter.get(&replica.into()).copied().unwrap_or_default();
+                prop_assert_eq!(counter.increment_by(replica.into(), n), before + n);
+                total += n;
+                prop_assert_eq!(counter.value(), total);
+            }
+        }
+
+        /// Merging an incremented counter into the one it came from picks up the increment
+        #[test]
+        fn merge_keeps_increments(a: BTreeMap<u64, u16>, replica in 0..8u64) {
+            let a = GrowCounter::<u32>::from_u64_map(
+                a.into_iter().map(|(k, v)| (k % 8, v as u32)).collect()
+            );
+            let mut b = a.clone();
+            b.increment(replica.into());
+
+            prop_assert_eq!(a.merge(&b).value(), a.value() + 1);
+            prop_assert_eq!(b.merge(&a), b);
+        }
+
+        #[test]
+        fn commutativity(mut a: BTreeMap<u64, u64>, mut b: BTreeMap<u64, u64>) {
             let a = GrowCounter::from_u64_map(a);
             let b = GrowCounter::from_u64_map(b);
 
@@ -95,7 +183,7 @@ mod test {
         }
 
         #[test]
-        fn associativity(mut a: BTreeMap<u64, i64>, mut b: BTreeMap<u64, i64>, mut c: BTreeMap<u64, i64>) {
+        fn associativity(mut a: BTreeMap<u64, u64>, mut b: BTreeMap<u64, u64>, mut c: BTreeMap<u64, u64>) {
             let a = GrowCounter::from_u64_map(a);
             let b = GrowCounter::from_u64_map(b);
             let c = GrowCounter::from_u64_map(c);
@@ -107,7 +195,7 @@ mod test {
         }
 
         #[test]
-        fn idempotency(mut a: BTreeMap<u64, i64>) {
+        fn idempotency(mut a: BTreeMap<u64, u64>) {
             let a = GrowCounter::from_u64_map(a);
             let result = a.merge(&a);
             assert_eq!(a, result)
@@ -0,0 +1,245 @@
+//! Registers whose value only ever moves one way, so merging two of them is picking the one
+//! further along. They implement [`Convergent`] and can be the values of an
+//! [`AWORMap`](super::awormap::AWORMap) to get a high score or a lowest price per key.
+use crate::{delta_state::convergent::Convergent, Value};
+
+/// Keeps the greatest value it was set to
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+    serde_derive::Serialize,
+    serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::state::monotonic")
+)]
+pub struct MaxReg<T> {
+    value: T,
+}
+
+/// Keeps the smallest value it was set to
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+    serde_derive::Serialize,
+    serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::state::monotonic")
+)]
+pub struct MinReg<T> {
+    value: T,
+}
+
+/// A value that advances by steps, like the stage of an order or the version of a schema.
+///
+/// Each step is a function of the current value that has to return something at least as great,
+/// [`Monotonic::update`] refuses steps that would go back since other replicas may already have
+/// moved past the value it goes back to.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+    serde_derive::Serialize,
+    serde_derive::Deserialize,
+)]
+#[cfg_attr(feature = "wasm", derive(fp_bindgen::prelude::Serializable))]
+#[cfg_attr(
+    feature = "wasm",
+    fp(rust_plugin_module = "sypytkowski_convergent::state::monotonic")
+)]
+pub struct Monotonic<T> {
+    value: T,
+}
+
+impl<T: Value> Value for MaxReg<T> {}
+impl<T: Value> Value for MinReg<T> {}
+impl<T: Value> Value for Monotonic<T> {}
+
+impl<T: Ord> MaxReg<T> {
+    pub fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// Does nothing unless `value` is greater than the current one
+    pub fn set(&mut self, value: T) {
+        if value > self.value {
+            self.value = value;
+        }
+    }
+}
+
+impl<T: Ord> MinReg<T> {
+    pub fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// Does nothing unless `value` is smaller than the current one
+    pub fn set(&mut self, value: T) {
+        if value < self.value {
+            self.value = value;
+        }
+    }
+}
+
+impl<T: Ord> Monotonic<T> {
+    pub fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// Replaces the value with what `step` makes of it, returns `false` and leaves the value
+    /// alone if that is less than the current value
+    pub fn update(&mut self, step: impl FnOnce(&T) -> T) -> bool {
+        let next = step(&self.value);
+        if next < self.value {
+            return false;
+        }
+        self.value = next;
+        true
+    }
+}
+
+impl<T: Ord + Clone> Convergent for MaxReg<T> {
+    fn merge(&self, other: &Self) -> Self {
+        self.max(other).clone()
+    }
+}
+
+impl<T: Ord + Clone> Convergent for MinReg<T> {
+    fn merge(&self, other: &Self) -> Self {
+        self.min(other).clone()
+    }
+}
+
+impl<T: Ord + Clone> Convergent for Monotonic<T> {
+    fn merge(&self, other: &Self) -> Self {
+        self.max(other).clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use crate::delta_state::convergent::Convergent;
+    use crate::state::awormap::AWORMap;
+    use crate::ReplicaGenerator;
+
+    use super::{MaxReg, MinReg, Monotonic};
+
+    #[test]
+    fn registers() {
+        let mut max = MaxReg::new(3);
+        max.set(1);
+        max.set(5);
+        assert_eq!(*max.value(), 5);
+        let mut min = MinReg::new(3);
+        min.set(5);
+        min.set(1);
+        assert_eq!(*min.value(), 1);
+
+        let mut stage = Monotonic::new(0u32);
+        assert!(stage.update(|stage| stage + 1));
+        assert!(!stage.update(|stage| stage - 1));
+        assert_eq!(*stage.value(), 1);
+        assert_eq!(stage.merge(&Monotonic::new(3)), Monotonic::new(3));
+    }
+
+    #[test]
+    fn map_values() {
+        let mut gen = ReplicaGenerator::new();
+        let (a_id, b_id) = (gen.gen(), gen.gen());
+        let mut a = AWORMap::<String, MaxReg<u32>>::default();
+        a.add(a_id, "alice".into(), MaxReg::new(10));
+        let mut b = a.clone();
+        b.add(b_id, "alice".into(), MaxReg::new(7));
+        a.add(a_id, "alice".into(), MaxReg::new(12));
+        b.add(b_id, "bob".into(), MaxReg::new(3));
+
+        let merged = a.merge(&b);
+        assert_eq!(merged, b.merge(&a));
+        let scores = (merged.value().iter())
+            .map(|(name, score)| (name.as_str(), *score.value()))
+            .collect::<Vec<_>>();
+        assert_eq!(scores, [("alice", 12), ("bob", 3)]);
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let reg = MinReg::new(4u8);
+        let json = serde_json::to_string(&reg).unwrap();
+        assert_eq!(json, r#"{"value":4}"#);
+        assert_eq!(serde_json::from_str::<MinReg<u8>>(&json).unwrap(), reg);
+    }
+
+    /// Checks the merge of registers made with `new` from `a`, `b` and `c` is a semilattice
+    fn check_merge<R: Convergent + PartialEq + core::fmt::Debug>(
+        new: impl Fn(u32) -> R,
+        (a, b, c): (u32, u32, u32),
+    ) {
+        let (a, b, c) = (new(a), new(b), new(c));
+        assert_eq!(a.merge(&b), b.merge(&a));
+        assert_eq!(a.merge(&b).merge(&c), a.merge(&b.merge(&c)));
+        assert_eq!(a.merge(&a), a);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig{ ..Default::default()})]
+
+        #[test]
+        fn max_reg(values in any::<(u32, u32, u32)>()) {
+            check_merge(MaxReg::new, values);
+        }
+
+        #[test]
+        fn min_reg(values in any::<(u32, u32, u32)>()) {
+            check_merge(MinReg::new, values);
+        }
+
+        #[test]
+        fn monotonic(values in any::<(u32, u32, u32)>(), steps in prop::collection::vec(any::<u32>(), 0..10)) {
+            check_merge(Monotonic::new, values);
+
+            // Whatever the steps, the value never goes back
+            let mut reg = Monotonic::new(values.0);
+            for step in steps {
+                let before = *reg.value();
+                reg.update(|_| step);
+                prop_assert!(*reg.value() >= before);
+            }
+        }
+    }
+}
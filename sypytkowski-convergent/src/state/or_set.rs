@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use alloc::collections::BTreeMap;
 
 use crate::ReplicaId;
 
@@ -160,8 +160,8 @@ mod test {
         let val = orset.value();
         assert!(val.contains_key(&420));
         let clock = val.get(&420).expect("Clock should be defined");
-        assert_eq!(clock.get(&alice), Some(&0));
-        assert_eq!(clock.get(&bob), Some(&0));
+        assert_eq!(clock.get(&alice), Some(&1));
+        assert_eq!(clock.get(&bob), Some(&1));
     }
 
     #[test]
@@ -204,7 +204,7 @@ mod test {
         assert!(!val.contains_key(&420));
 
         let clock = val.get(&69).expect("Clock should be defined");
-        assert_eq!(clock.get(&alice), Some(&0));
+        assert_eq!(clock.get(&alice), Some(&1));
     }
 
     #[test]
@@ -1,9 +1,10 @@
-use std::{
+use core::{
     cmp::Ordering,
     ops::{Deref, DerefMut},
 };
 
 use super::grow_counter::GrowCounter;
+use crate::memory::{MemoryUsage, Usage};
 
 #[derive(Clone, Debug)]
 pub struct VectorClock(GrowCounter);
@@ -45,6 +46,12 @@ impl Default for VectorClock {
     }
 }
 
+impl MemoryUsage for VectorClock {
+    fn memory_usage(&self) -> Usage {
+        self.0.memory_usage()
+    }
+}
+
 impl Deref for VectorClock {
     type Target = GrowCounter;
 
@@ -66,7 +73,7 @@ impl PartialEq for VectorClock {
 }
 
 impl PartialOrd for VectorClock {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
         Self::partial_ord_impl(self, other)
     }
 }
@@ -113,6 +120,21 @@ mod test {
         assert!(b == a);
     }
 
+    #[test]
+    fn increment_moves_forward() {
+        let a = VectorClock::new(GrowCounter::from_iter([(0.into(), 3), (1.into(), 1)]));
+
+        let mut b = a.clone();
+        b.increment(1.into());
+        assert!(a < b);
+        assert_eq!(b.get(&1.into()), Some(&2));
+
+        let mut c = a.clone();
+        c.increment(2.into());
+        assert_eq!(b.partial_cmp(&c), None);
+        assert!(b < b.merge(&c) && c < b.merge(&c));
+    }
+
     #[test]
     fn cmp_concurrent() {
         let a = VectorClock::new(GrowCounter::from_iter([